@@ -4,9 +4,13 @@ CREATE EXTENSION IF NOT EXISTS timescaledb;
 CREATE TABLE IF NOT EXISTS bibimbap (
   time   TIMESTAMPTZ NOT NULL,
   device TEXT        NOT NULL,
-  data   JSONB       NOT NULL
+  data   JSONB       NOT NULL,
+  tenant TEXT
 );
 
+-- Tenant column, for tables created before it was added
+ALTER TABLE bibimbap ADD COLUMN IF NOT EXISTS tenant TEXT;
+
 SELECT public.create_hypertable('bibimbap', 'time',
   partitioning_column => 'device',
   number_partitions => 4,
@@ -14,3 +18,161 @@ SELECT public.create_hypertable('bibimbap', 'time',
 
 CREATE INDEX IF NOT EXISTS bibimbap_time_idx ON bibimbap (time DESC);
 CREATE INDEX IF NOT EXISTS bibimbap_device_idx ON bibimbap (device);
+CREATE INDEX IF NOT EXISTS bibimbap_tenant_time_idx ON bibimbap (tenant, time DESC);
+
+-- Normalized layout written alongside bibimbap by data-db --schema-mode=dual, or instead of it
+-- by --schema-mode=columns
+CREATE TABLE IF NOT EXISTS bibimbap_measurements (
+  time                       TIMESTAMPTZ      NOT NULL,
+  device                     TEXT             NOT NULL,
+  stream                     TEXT             NOT NULL,
+  phase                      TEXT             NOT NULL,
+  tenant                     TEXT,
+  rms_voltage                DOUBLE PRECISION,
+  dc_offset_voltage          DOUBLE PRECISION,
+  rms_current                DOUBLE PRECISION,
+  dc_offset_current          DOUBLE PRECISION,
+  real_power                 DOUBLE PRECISION,
+  apparent_power             DOUBLE PRECISION,
+  reactive_power             DOUBLE PRECISION,
+  power_factor               DOUBLE PRECISION,
+  three_phase_real_power     DOUBLE PRECISION,
+  three_phase_reactive_power DOUBLE PRECISION,
+  crest_factor_voltage       DOUBLE PRECISION,
+  thd_voltage                DOUBLE PRECISION,
+  crest_factor_current       DOUBLE PRECISION,
+  thd_current                DOUBLE PRECISION
+);
+
+-- Tenant and waveform statistics columns, for tables created before they were added
+ALTER TABLE bibimbap_measurements
+  ADD COLUMN IF NOT EXISTS tenant TEXT,
+  ADD COLUMN IF NOT EXISTS crest_factor_voltage DOUBLE PRECISION,
+  ADD COLUMN IF NOT EXISTS thd_voltage DOUBLE PRECISION,
+  ADD COLUMN IF NOT EXISTS crest_factor_current DOUBLE PRECISION,
+  ADD COLUMN IF NOT EXISTS thd_current DOUBLE PRECISION;
+
+SELECT public.create_hypertable('bibimbap_measurements', 'time',
+  partitioning_column => 'device',
+  number_partitions => 4,
+  if_not_exists => TRUE);
+
+CREATE INDEX IF NOT EXISTS bibimbap_measurements_stream_time_idx
+  ON bibimbap_measurements (stream, time DESC);
+
+-- Full-rate rows around capture events (data-db --capture), in the layout of bibimbap
+CREATE TABLE IF NOT EXISTS bibimbap_highres (
+  time   TIMESTAMPTZ NOT NULL,
+  device TEXT        NOT NULL,
+  data   JSONB       NOT NULL,
+  tenant TEXT
+);
+
+SELECT public.create_hypertable('bibimbap_highres', 'time',
+  partitioning_column => 'device',
+  number_partitions => 4,
+  if_not_exists => TRUE);
+
+CREATE INDEX IF NOT EXISTS bibimbap_highres_time_idx ON bibimbap_highres (time DESC);
+
+-- What started each capture
+CREATE TABLE IF NOT EXISTS bibimbap_events (
+  time      TIMESTAMPTZ      NOT NULL,
+  device    TEXT             NOT NULL,
+  tenant    TEXT,
+  trigger   TEXT             NOT NULL,
+  stream    TEXT             NOT NULL,
+  phase     TEXT,
+  value     DOUBLE PRECISION NOT NULL,
+  threshold DOUBLE PRECISION NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS bibimbap_events_time_idx ON bibimbap_events (time DESC);
+
+-- Streams registry, filled by data-db and the exporter as new calculation names show up
+CREATE TABLE IF NOT EXISTS streams (
+  device        TEXT        NOT NULL,
+  tenant        TEXT,
+  stream        TEXT        NOT NULL,
+  first_seen    TIMESTAMPTZ NOT NULL,
+  first_seen_by TEXT        NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS streams_key
+  ON streams (device, (coalesce(tenant, '')), stream);
+
+-- Per-minute and per-15-minute aggregates, maintained by data-db --tiering
+CREATE TABLE IF NOT EXISTS bibimbap_rollup_1m (
+  bucket             TIMESTAMPTZ      NOT NULL,
+  device             TEXT             NOT NULL,
+  tenant             TEXT,
+  stream             TEXT             NOT NULL,
+  phase              TEXT             NOT NULL,
+  samples            BIGINT           NOT NULL,
+  real_power_avg     DOUBLE PRECISION,
+  real_power_min     DOUBLE PRECISION,
+  real_power_max     DOUBLE PRECISION,
+  reactive_power_avg DOUBLE PRECISION,
+  reactive_power_min DOUBLE PRECISION,
+  reactive_power_max DOUBLE PRECISION,
+  apparent_power_avg DOUBLE PRECISION,
+  apparent_power_min DOUBLE PRECISION,
+  apparent_power_max DOUBLE PRECISION,
+  power_factor_avg   DOUBLE PRECISION,
+  power_factor_min   DOUBLE PRECISION,
+  power_factor_max   DOUBLE PRECISION,
+  rms_voltage_avg    DOUBLE PRECISION,
+  rms_voltage_min    DOUBLE PRECISION,
+  rms_voltage_max    DOUBLE PRECISION,
+  rms_current_avg    DOUBLE PRECISION,
+  rms_current_min    DOUBLE PRECISION,
+  rms_current_max    DOUBLE PRECISION
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS bibimbap_rollup_1m_key
+  ON bibimbap_rollup_1m (bucket, device, (coalesce(tenant, '')), stream, phase);
+
+CREATE TABLE IF NOT EXISTS bibimbap_rollup_15m (
+  bucket             TIMESTAMPTZ      NOT NULL,
+  device             TEXT             NOT NULL,
+  tenant             TEXT,
+  stream             TEXT             NOT NULL,
+  phase              TEXT             NOT NULL,
+  samples            BIGINT           NOT NULL,
+  real_power_avg     DOUBLE PRECISION,
+  real_power_min     DOUBLE PRECISION,
+  real_power_max     DOUBLE PRECISION,
+  reactive_power_avg DOUBLE PRECISION,
+  reactive_power_min DOUBLE PRECISION,
+  reactive_power_max DOUBLE PRECISION,
+  apparent_power_avg DOUBLE PRECISION,
+  apparent_power_min DOUBLE PRECISION,
+  apparent_power_max DOUBLE PRECISION,
+  power_factor_avg   DOUBLE PRECISION,
+  power_factor_min   DOUBLE PRECISION,
+  power_factor_max   DOUBLE PRECISION,
+  rms_voltage_avg    DOUBLE PRECISION,
+  rms_voltage_min    DOUBLE PRECISION,
+  rms_voltage_max    DOUBLE PRECISION,
+  rms_current_avg    DOUBLE PRECISION,
+  rms_current_min    DOUBLE PRECISION,
+  rms_current_max    DOUBLE PRECISION
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS bibimbap_rollup_15m_key
+  ON bibimbap_rollup_15m (bucket, device, (coalesce(tenant, '')), stream, phase);
+
+-- How far each rollup tier has got
+CREATE TABLE IF NOT EXISTS bibimbap_tiering (
+  tier         TEXT        PRIMARY KEY,
+  rolled_up_to TIMESTAMPTZ NOT NULL
+);
+
+-- Tables data-db --route writes streams to, rolled up and pruned along with bibimbap;
+-- retention_seconds is null for --full-rate-retention
+CREATE TABLE IF NOT EXISTS bibimbap_routes (
+  table_name        TEXT        PRIMARY KEY,
+  patterns          TEXT[]      NOT NULL,
+  retention_seconds BIGINT,
+  updated_at        TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,316 @@
+//! Reassembles frames that a publisher splits across several ZeroMQ messages (one per stream,
+//! or one per phase) back into one `CompositeJoinedCalculations` per instant.
+//!
+//! Fragments are grouped by their provenance: the sequence number when the publisher sends
+//! one, otherwise the UTC timestamp. A frame is complete once every expected stream has both
+//! phases; one still incomplete after the assembly window is emitted partial or dropped.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    time::{Duration, Instant, SystemTime},
+};
+
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, CompositeJoinedCalculations,
+    CompositeJoinedCalculationsWrapper, CompositeTwoPhaseCalculations, Harmonics,
+};
+
+/// Frames waiting at once; past this the oldest is timed out early.
+const MAX_PENDING: usize = 4096;
+
+/// Keys of recently emitted frames remembered, so stragglers are recognised as late.
+const EMITTED_HISTORY: usize = 4096;
+
+/// What happens to a frame still incomplete when its window ends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimeoutPolicy {
+    /// Pass on whatever arrived
+    #[default]
+    EmitPartial,
+    /// Discard it
+    Drop,
+}
+
+#[derive(Clone, Debug)]
+pub struct AssemblyConfig {
+    /// How long to wait for the rest of a frame after its first fragment
+    pub window: Duration,
+    pub on_timeout: TimeoutPolicy,
+    /// Streams a complete frame has. When empty, every stream seen so far is expected, so
+    /// frames can be emitted early until each stream has shown up once.
+    pub expected_streams: BTreeSet<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Key {
+    Sequence(u64),
+    Time(i64, i32),
+}
+
+fn key_of(calcs: &CompositeTwoPhaseCalculations) -> Option<Key> {
+    let provenance = [calcs.phase_a, calcs.phase_b, calcs.phase_c]
+        .into_iter()
+        .flatten()
+        .find_map(|phase| phase.provenance)?;
+    if let Some(sequence) = provenance.generic_sequence_number {
+        return Some(Key::Sequence(sequence));
+    }
+    provenance
+        .utc_time
+        .map(|time| Key::Time(time.seconds, time.nanos))
+}
+
+/// An assembled (or passed through) frame.
+#[derive(Debug)]
+pub struct Frame {
+    pub joined: CompositeJoinedCalculations,
+    /// When its first fragment was pushed
+    pub first_received: SystemTime,
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    /// Every expected stream and phase arrived, or the message could not be keyed and was
+    /// passed on as is
+    Complete(Frame),
+    /// The window ended with pieces missing, under `TimeoutPolicy::EmitPartial`
+    Partial { frame: Frame, missing: Vec<String> },
+    /// The window ended with pieces missing, under `TimeoutPolicy::Drop`
+    Dropped { missing: Vec<String> },
+    /// A fragment for a frame that was already emitted; it is discarded
+    Late,
+}
+
+struct Pending {
+    deadline: Instant,
+    first_received: SystemTime,
+    streams: BTreeMap<String, CompositeTwoPhaseCalculations>,
+    /// The device of each stream, from the first fragment that names one
+    devices: BTreeMap<String, String>,
+    /// The harmonic content of each stream, phase by phase like its calculations
+    harmonics: BTreeMap<String, Harmonics>,
+}
+
+impl Pending {
+    fn merge(
+        &mut self,
+        name: String,
+        calcs: CompositeTwoPhaseCalculations,
+        device: Option<&str>,
+        harmonics: Option<Harmonics>,
+    ) {
+        if let Some(device) = device {
+            self.devices
+                .entry(name.clone())
+                .or_insert_with(|| device.to_string());
+        }
+        if let Some(harmonics) = harmonics {
+            let existing = self.harmonics.entry(name.clone()).or_default();
+            existing.phase_a = existing.phase_a.take().or(harmonics.phase_a);
+            existing.phase_b = existing.phase_b.take().or(harmonics.phase_b);
+            existing.phase_c = existing.phase_c.take().or(harmonics.phase_c);
+        }
+        let existing = self.streams.entry(name).or_default();
+        // The first copy of a phase wins; repeats are ignored
+        existing.phase_a = existing.phase_a.or(calcs.phase_a);
+        existing.phase_b = existing.phase_b.or(calcs.phase_b);
+        existing.phase_c = existing.phase_c.or(calcs.phase_c);
+    }
+
+    fn missing(&self, expected: &BTreeSet<String>) -> Vec<String> {
+        let mut missing = Vec::new();
+        for stream in expected {
+            match self.streams.get(stream) {
+                None => missing.push(stream.clone()),
+                Some(calcs) => {
+                    if calcs.phase_a.is_none() {
+                        missing.push(format!("{stream}:a"));
+                    }
+                    if calcs.phase_b.is_none() {
+                        missing.push(format!("{stream}:b"));
+                    }
+                }
+            }
+        }
+        missing
+    }
+
+    fn into_frame(mut self) -> Frame {
+        Frame {
+            joined: CompositeJoinedCalculations {
+                calculations: self
+                    .streams
+                    .into_iter()
+                    .map(|(name, calcs)| CompositeJoinedCalculationsWrapper {
+                        device_id: self.devices.remove(&name),
+                        harmonics: self.harmonics.remove(&name),
+                        calculation_name: Some(name),
+                        data_product: Some(DataProduct::Calculations(calcs)),
+                    })
+                    .collect(),
+            },
+            first_received: self.first_received,
+        }
+    }
+}
+
+pub struct Assembler {
+    config: AssemblyConfig,
+    /// Streams seen so far, used as the expected set when none is configured
+    known: BTreeSet<String>,
+    pending: BTreeMap<Key, Pending>,
+    emitted: VecDeque<Key>,
+    emitted_set: HashSet<Key>,
+}
+
+impl Assembler {
+    pub fn new(config: AssemblyConfig) -> Self {
+        Self {
+            config,
+            known: BTreeSet::new(),
+            pending: BTreeMap::new(),
+            emitted: VecDeque::new(),
+            emitted_set: HashSet::new(),
+        }
+    }
+
+    fn expected(&self) -> &BTreeSet<String> {
+        if self.config.expected_streams.is_empty() {
+            &self.known
+        } else {
+            &self.config.expected_streams
+        }
+    }
+
+    fn remember_emitted(&mut self, key: Key) {
+        if self.emitted.len() >= EMITTED_HISTORY {
+            if let Some(oldest) = self.emitted.pop_front() {
+                self.emitted_set.remove(&oldest);
+            }
+        }
+        self.emitted.push_back(key);
+        self.emitted_set.insert(key);
+    }
+
+    fn finish(&mut self, key: Key) -> Option<Outcome> {
+        let pending = self.pending.remove(&key)?;
+        self.remember_emitted(key);
+        let missing = pending.missing(self.expected());
+        Some(if missing.is_empty() {
+            Outcome::Complete(pending.into_frame())
+        } else {
+            match self.config.on_timeout {
+                TimeoutPolicy::EmitPartial => Outcome::Partial {
+                    frame: pending.into_frame(),
+                    missing,
+                },
+                TimeoutPolicy::Drop => Outcome::Dropped { missing },
+            }
+        })
+    }
+
+    /// Adds a received message and returns the frames it completes. Data products other than
+    /// two-phase calculations, and calculations without provenance, are passed straight on.
+    pub fn push(&mut self, joined: CompositeJoinedCalculations, now: Instant) -> Vec<Outcome> {
+        let received = SystemTime::now();
+        let mut outcomes = Vec::new();
+        let mut passthrough = Vec::new();
+        let mut touched = BTreeSet::new();
+
+        for wrapper in joined.calculations {
+            let (Some(name), Some(DataProduct::Calculations(calcs))) =
+                (&wrapper.calculation_name, &wrapper.data_product)
+            else {
+                passthrough.push(wrapper);
+                continue;
+            };
+            let Some(key) = key_of(calcs) else {
+                passthrough.push(wrapper);
+                continue;
+            };
+            if self.emitted_set.contains(&key) {
+                outcomes.push(Outcome::Late);
+                continue;
+            }
+
+            self.known.insert(name.clone());
+            let deadline = now + self.config.window;
+            self.pending
+                .entry(key)
+                .or_insert_with(|| Pending {
+                    deadline,
+                    first_received: received,
+                    streams: BTreeMap::new(),
+                    devices: BTreeMap::new(),
+                    harmonics: BTreeMap::new(),
+                })
+                .merge(
+                    name.clone(),
+                    *calcs,
+                    wrapper.device_id.as_deref(),
+                    wrapper.harmonics.clone(),
+                );
+            touched.insert(key);
+        }
+
+        if !passthrough.is_empty() {
+            outcomes.push(Outcome::Complete(Frame {
+                joined: CompositeJoinedCalculations {
+                    calculations: passthrough,
+                },
+                first_received: received,
+            }));
+        }
+
+        for key in touched {
+            let complete = self
+                .pending
+                .get(&key)
+                .is_some_and(|pending| pending.missing(self.expected()).is_empty());
+            if complete {
+                outcomes.extend(self.finish(key));
+            }
+        }
+
+        while self.pending.len() > MAX_PENDING {
+            let Some(oldest) = self
+                .pending
+                .iter()
+                .min_by_key(|(_, pending)| pending.deadline)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            outcomes.extend(self.finish(oldest));
+        }
+        outcomes
+    }
+
+    /// Times out every frame whose window has ended.
+    pub fn expire(&mut self, now: Instant) -> Vec<Outcome> {
+        let expired: Vec<Key> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|key| self.finish(key))
+            .collect()
+    }
+
+    /// Ends every pending frame as if its window were over, e.g. on shutdown.
+    pub fn drain(&mut self) -> Vec<Outcome> {
+        let pending: Vec<Key> = self.pending.keys().copied().collect();
+        pending
+            .into_iter()
+            .filter_map(|key| self.finish(key))
+            .collect()
+    }
+
+    /// When `expire` next has something to do.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.deadline).min()
+    }
+}
@@ -0,0 +1,159 @@
+//! Checks each stream's provenance sequence numbers, so frames lost on the way, sent twice or
+//! delivered out of order show up instead of silently thinning or doubling the data. data-db
+//! and the exporter share it so their counters agree on what went missing.
+//!
+//! Numbers skipped over are remembered for `REORDER_WINDOW` frames: one arriving in that time
+//! is out of order, one arriving again a duplicate. A number further behind than that means
+//! the publisher started counting again, as after a restart, and the stream starts over from
+//! it. Streams without sequence numbers are never checked.
+
+use std::collections::{BTreeSet, HashMap};
+
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeTwoPhaseCalculations;
+
+/// How many frames behind the newest a number may be and still be told apart as late or
+/// duplicate: 17 seconds at 60 frames a second.
+pub const REORDER_WINDOW: u64 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sequence {
+    /// The next number, the stream's first, or a frame without one
+    InSequence,
+    /// Ahead of the next number: this many frames before it are missing
+    Gap { missing: u64 },
+    /// A number that was skipped over earlier, arriving after all
+    OutOfOrder,
+    /// A number already seen
+    Duplicate,
+    /// So far behind the newest that the publisher must have started counting again
+    Restarted,
+}
+
+impl Sequence {
+    /// The `kind` label of an out-of-order counter, for frames that came in behind the newest.
+    pub fn out_of_order(self) -> Option<&'static str> {
+        match self {
+            Self::OutOfOrder => Some("late"),
+            Self::Duplicate => Some("duplicate"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct StreamSequence {
+    newest: u64,
+    /// Skipped numbers within `REORDER_WINDOW` of `newest`
+    missing: BTreeSet<u64>,
+}
+
+/// The newest sequence number of each stream, and the ones it skipped.
+#[derive(Clone, Debug, Default)]
+pub struct SequenceTracker {
+    streams: HashMap<String, StreamSequence>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks one stream's calculations by their sequence number.
+    pub fn check(&mut self, stream: &str, calcs: &CompositeTwoPhaseCalculations) -> Sequence {
+        match sequence_number(calcs) {
+            Some(number) => self.check_number(stream, number),
+            None => Sequence::InSequence,
+        }
+    }
+
+    pub fn check_number(&mut self, stream: &str, number: u64) -> Sequence {
+        let Some(state) = self.streams.get_mut(stream) else {
+            self.streams.insert(
+                stream.to_string(),
+                StreamSequence {
+                    newest: number,
+                    missing: BTreeSet::new(),
+                },
+            );
+            return Sequence::InSequence;
+        };
+
+        if number > state.newest {
+            let missing = number - state.newest - 1;
+            let oldest = number.saturating_sub(REORDER_WINDOW);
+            state.missing.extend(state.newest.saturating_add(1).max(oldest)..number);
+            state.missing = state.missing.split_off(&oldest);
+            state.newest = number;
+            return match missing {
+                0 => Sequence::InSequence,
+                missing => Sequence::Gap { missing },
+            };
+        }
+        if state.newest - number > REORDER_WINDOW {
+            state.newest = number;
+            state.missing.clear();
+            return Sequence::Restarted;
+        }
+        if state.missing.remove(&number) {
+            Sequence::OutOfOrder
+        } else {
+            Sequence::Duplicate
+        }
+    }
+}
+
+/// The first sequence number among the phases; publishers give every phase of a frame the
+/// same one.
+pub fn sequence_number(calcs: &CompositeTwoPhaseCalculations) -> Option<u64> {
+    [calcs.phase_a, calcs.phase_b, calcs.phase_c]
+        .into_iter()
+        .flatten()
+        .find_map(|phase| phase.provenance?.generic_sequence_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_count_the_frames_skipped() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.check_number("feeder", 10), Sequence::InSequence);
+        assert_eq!(tracker.check_number("feeder", 11), Sequence::InSequence);
+        assert_eq!(tracker.check_number("feeder", 15), Sequence::Gap { missing: 3 });
+        assert_eq!(tracker.check_number("other", 500), Sequence::InSequence);
+    }
+
+    #[test]
+    fn skipped_numbers_arrive_late_once_and_then_duplicate() {
+        let mut tracker = SequenceTracker::new();
+        tracker.check_number("feeder", 1);
+        tracker.check_number("feeder", 4);
+        assert_eq!(tracker.check_number("feeder", 3), Sequence::OutOfOrder);
+        assert_eq!(tracker.check_number("feeder", 3), Sequence::Duplicate);
+        assert_eq!(tracker.check_number("feeder", 4), Sequence::Duplicate);
+        assert_eq!(tracker.check_number("feeder", 1), Sequence::Duplicate);
+        assert_eq!(tracker.check_number("feeder", 2), Sequence::OutOfOrder);
+        assert_eq!(tracker.check_number("feeder", 5), Sequence::InSequence);
+    }
+
+    #[test]
+    fn falling_far_behind_is_a_restart() {
+        let mut tracker = SequenceTracker::new();
+        tracker.check_number("feeder", 5000);
+        assert_eq!(tracker.check_number("feeder", 0), Sequence::Restarted);
+        assert_eq!(tracker.check_number("feeder", 1), Sequence::InSequence);
+    }
+
+    #[test]
+    fn only_recent_gaps_are_remembered() {
+        let mut tracker = SequenceTracker::new();
+        tracker.check_number("feeder", 0);
+        assert_eq!(
+            tracker.check_number("feeder", 1_000_000),
+            Sequence::Gap { missing: 999_999 }
+        );
+        assert_eq!(tracker.streams["feeder"].missing.len(), REORDER_WINDOW as usize);
+        assert_eq!(tracker.check_number("feeder", 999_999), Sequence::OutOfOrder);
+    }
+}
@@ -0,0 +1,292 @@
+//! `/healthz` and `/readyz`, served next to each service's `/metrics` so Kubernetes can tell a
+//! service that is up from one that is actually moving data.
+//!
+//! Both report the same: whether each source (the ZeroMQ subscription, say) is connected, how
+//! long ago it last delivered a message, and how each check (a database ping, say) went.
+//! /healthz answers 200 for as long as the service answers at all, as restarting it would not
+//! bring a publisher back. /readyz answers 503 while a source is disconnected or has been quiet
+//! for longer than --ready-max-message-age, or a check fails or takes longer than
+//! --ready-check-timeout, so a stalled pipeline drops out of its Service and shows up as not
+//! ready in `kubectl get pods`.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, clap::Args)]
+pub struct HealthArgs {
+    /// /readyz fails once a source has delivered nothing for this long, counted from startup
+    /// until its first message; 0 turns the check off, for feeds that go quiet at times
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    pub ready_max_message_age: Duration,
+    /// /readyz fails when a check, such as the database ping, takes longer than this
+    #[arg(long, default_value = "2s", value_parser = humantime::parse_duration)]
+    pub ready_check_timeout: Duration,
+}
+
+type CheckResult = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+type Check = Arc<dyn Fn() -> CheckResult + Send + Sync>;
+
+/// What /healthz and /readyz report on. Cheap to clone; every clone reports to the same place.
+#[derive(Clone)]
+pub struct Health(Arc<Inner>);
+
+struct Inner {
+    args: HealthArgs,
+    started: Instant,
+    sources: Mutex<Vec<(String, Source)>>,
+    checks: Mutex<Vec<(&'static str, Check)>>,
+}
+
+/// One source's handle for reporting its connection and messages.
+#[derive(Clone)]
+pub struct Source(Arc<SourceState>);
+
+struct SourceState {
+    started: Instant,
+    connected: AtomicBool,
+    received: AtomicBool,
+    /// Since `started`: when the last message came in, or the source was added before that
+    last_message_ms: AtomicU64,
+}
+
+impl Source {
+    pub fn connected(&self) {
+        self.0.connected.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.0.connected.store(false, Ordering::Relaxed);
+    }
+
+    pub fn message(&self) {
+        let elapsed = self.0.started.elapsed().as_millis() as u64;
+        self.0.last_message_ms.store(elapsed, Ordering::Relaxed);
+        self.0.received.store(true, Ordering::Relaxed);
+    }
+
+    /// How long the source has been quiet at `now`, and whether it ever delivered anything.
+    fn quiet(&self, now: Instant) -> (Duration, bool) {
+        let last =
+            self.0.started + Duration::from_millis(self.0.last_message_ms.load(Ordering::Relaxed));
+        (
+            now.saturating_duration_since(last),
+            self.0.received.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub ready: bool,
+    /// Why not, when not
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+    pub sources: BTreeMap<String, SourceReport>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<&'static str, CheckReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SourceReport {
+    pub connected: bool,
+    /// Left out before the first message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_message_age_seconds: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Health {
+    pub fn new(args: HealthArgs) -> Self {
+        Self(Arc::new(Inner {
+            args,
+            started: Instant::now(),
+            sources: Mutex::default(),
+            checks: Mutex::default(),
+        }))
+    }
+
+    /// Adds a source, which counts as disconnected until it reports otherwise.
+    pub fn source(&self, name: impl Into<String>) -> Source {
+        let started = self.0.started;
+        let source = Source(Arc::new(SourceState {
+            started,
+            connected: AtomicBool::new(false),
+            received: AtomicBool::new(false),
+            last_message_ms: AtomicU64::new(started.elapsed().as_millis() as u64),
+        }));
+        let mut sources = self.0.sources.lock().expect("health poisoned");
+        sources.push((name.into(), source.clone()));
+        source
+    }
+
+    /// Adds a check run on every request, such as pinging the database.
+    pub fn check<F, Fut, E>(&self, name: &'static str, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display,
+    {
+        let check: Check = Arc::new(move || {
+            let result = check();
+            Box::pin(async move { result.await.map_err(|err| err.to_string()) })
+        });
+        let mut checks = self.0.checks.lock().expect("health poisoned");
+        checks.push((name, check));
+    }
+
+    pub async fn report(&self) -> Report {
+        self.report_at(Instant::now()).await
+    }
+
+    async fn report_at(&self, now: Instant) -> Report {
+        let max_age = self.0.args.ready_max_message_age;
+        let mut problems = Vec::new();
+        let sources = self.0.sources.lock().expect("health poisoned").clone();
+        let sources = sources
+            .into_iter()
+            .map(|(name, source)| {
+                let connected = source.0.connected.load(Ordering::Relaxed);
+                let (quiet, received) = source.quiet(now);
+                if !connected {
+                    problems.push(format!("{name} is not connected"));
+                } else if !max_age.is_zero() && quiet > max_age {
+                    problems.push(match received {
+                        true => format!("{name} has had no message for {}s", quiet.as_secs()),
+                        false => format!("{name} has had no message yet"),
+                    });
+                }
+                let report = SourceReport {
+                    connected,
+                    last_message_age_seconds: received.then_some(quiet.as_secs_f64()),
+                };
+                (name, report)
+            })
+            .collect();
+
+        let checks = self.0.checks.lock().expect("health poisoned").clone();
+        let mut reports = BTreeMap::new();
+        for (name, check) in checks {
+            let error = match tokio::time::timeout(self.0.args.ready_check_timeout, check()).await {
+                Ok(Ok(())) => None,
+                Ok(Err(err)) => Some(err),
+                Err(_) => Some(format!(
+                    "took longer than {}",
+                    humantime::format_duration(self.0.args.ready_check_timeout)
+                )),
+            };
+            if let Some(error) = &error {
+                problems.push(format!("{name}: {error}"));
+            }
+            let report = CheckReport {
+                ok: error.is_none(),
+                error,
+            };
+            reports.insert(name, report);
+        }
+
+        Report {
+            ready: problems.is_empty(),
+            problems,
+            sources,
+            checks: reports,
+        }
+    }
+
+    /// `/healthz` and `/readyz`, to merge into the service's router.
+    pub fn router<S: Clone + Send + Sync + 'static>(&self) -> Router<S> {
+        Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .with_state(self.clone())
+    }
+}
+
+async fn healthz(State(health): State<Health>) -> Json<Report> {
+    Json(health.report().await)
+}
+
+async fn readyz(State(health): State<Health>) -> (StatusCode, Json<Report>) {
+    let report = health.report().await;
+    let status = match report.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_max_age(max_age_secs: u64) -> Health {
+        Health::new(HealthArgs {
+            ready_max_message_age: Duration::from_secs(max_age_secs),
+            ready_check_timeout: Duration::from_millis(50),
+        })
+    }
+
+    #[tokio::test]
+    async fn sources_must_be_connected_and_recent() {
+        let health = with_max_age(30);
+        let zmq = health.source("zmq");
+        let now = Instant::now();
+        let report = health.report_at(now).await;
+        assert!(!report.ready);
+        assert_eq!(report.problems, ["zmq is not connected"]);
+
+        zmq.connected();
+        assert!(health.report_at(now).await.ready);
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            health.report_at(later).await.problems,
+            ["zmq has had no message yet"]
+        );
+
+        zmq.message();
+        let report = health.report_at(Instant::now()).await;
+        assert!(report.ready);
+        assert!(report.sources["zmq"].last_message_age_seconds.is_some());
+        assert!(!health.report_at(later + Duration::from_secs(1)).await.ready);
+
+        let quiet_feed = with_max_age(0);
+        quiet_feed.source("zmq").connected();
+        assert!(quiet_feed.report_at(later).await.ready);
+    }
+
+    #[tokio::test]
+    async fn failing_and_slow_checks_are_not_ready() {
+        let health = with_max_age(30);
+        health.check("database", || async { Ok::<_, String>(()) });
+        assert!(health.report().await.ready);
+
+        health.check("broker", || async { Err("connection refused") });
+        health.check("slow", || async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok::<_, String>(())
+        });
+        let report = health.report().await;
+        assert!(!report.ready);
+        assert!(report.checks["database"].ok);
+        assert_eq!(
+            report.problems,
+            ["broker: connection refused", "slow: took longer than 50ms"]
+        );
+    }
+}
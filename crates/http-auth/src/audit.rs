@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use sqlx::{postgres::PgPoolOptions, Executor, Pool, Postgres};
+
+use crate::Principal;
+
+const TABLE: &str = "CREATE TABLE IF NOT EXISTS audit_log (
+  time      TIMESTAMPTZ NOT NULL DEFAULT now(),
+  service   TEXT        NOT NULL,
+  principal TEXT        NOT NULL,
+  role      TEXT        NOT NULL,
+  action    TEXT        NOT NULL,
+  detail    JSONB       NOT NULL
+)";
+
+/// For tables created before principals could be scoped to a tenant.
+const UPGRADE: &str = "ALTER TABLE audit_log ADD COLUMN IF NOT EXISTS tenant TEXT";
+
+pub struct AuditLog {
+    service: String,
+    pool: Option<Pool<Postgres>>,
+}
+
+impl AuditLog {
+    pub async fn connect(service: &str, database_url: Option<&str>) -> Result<Self> {
+        let pool = match database_url {
+            Some(url) => {
+                // Lazy so a database outage doesn't keep the service from starting; rows
+                // that can't be written are still in the service log.
+                let pool = PgPoolOptions::new()
+                    .max_connections(2)
+                    .connect_lazy(url)
+                    .context("Invalid audit database URL")?;
+                for statement in [TABLE, UPGRADE] {
+                    if let Err(err) = pool.execute(statement).await {
+                        log::error!("Could not create audit_log table: {err}");
+                    }
+                }
+                Some(pool)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            service: service.to_string(),
+            pool,
+        })
+    }
+
+    pub async fn record(&self, principal: &Principal, action: &str, detail: serde_json::Value) {
+        log::info!(
+            target: "audit",
+            "{} ({}{}) {action}: {detail}",
+            principal.name,
+            principal.role.as_str(),
+            principal
+                .tenant
+                .as_ref()
+                .map(|tenant| format!("@{tenant}"))
+                .unwrap_or_default()
+        );
+
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        let result = sqlx::query(
+            "INSERT INTO audit_log (service, principal, role, tenant, action, detail) VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(&self.service)
+        .bind(&principal.name)
+        .bind(principal.role.as_str())
+        .bind(&principal.tenant)
+        .bind(action)
+        .bind(&detail)
+        .execute(pool)
+        .await;
+
+        if let Err(err) = result {
+            log::error!("Could not write audit record for {action}: {err}");
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! API key / JWT authentication with read and admin roles, shared by the HTTP surfaces of
+//! the services, plus an audit log of admin actions.
+//!
+//! Credentials are sent as `Authorization: Bearer <key or token>` or `X-Api-Key: <key>`.
+//! Safe methods (GET, HEAD, OPTIONS) need the read role, everything else needs admin.
+//! When no credentials are configured at all, reads are anonymous and admin requests are
+//! refused, so a control endpoint is never left open by omission.
+//!
+//! Credentials can be scoped to a tenant (a customer site). A scoped credential is only
+//! accepted by services running for that tenant; unscoped ones are for operators and work
+//! everywhere.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::audit::AuditLog;
+
+mod audit;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Read => "read",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "read" => Ok(Role::Read),
+            "admin" => Ok(Role::Admin),
+            _ => Err(anyhow!("Unknown role '{value}', expected read or admin")),
+        }
+    }
+}
+
+/// Who made a request. Inserted into the request extensions of every authorised request,
+/// so handlers can take `Extension<Principal>` to audit what they did.
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+    /// The only tenant this principal may access; None for operators
+    pub tenant: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AuthSettings {
+    /// One key per line as `name:role:key`, or `name:role@tenant:key` for a key scoped to
+    /// one tenant; blank lines and `#` comments are ignored
+    pub api_keys_file: Option<PathBuf>,
+    /// HS256 secret for JWTs carrying `sub`, `role` and `exp` claims, and optionally `tenant`
+    pub jwt_secret: Option<String>,
+    /// Postgres connection string for the `audit_log` table. Admin actions are always
+    /// logged; with this set they are also recorded in the database.
+    pub audit_database_url: Option<String>,
+    /// The tenant this service instance serves. Credentials scoped to another tenant are
+    /// refused, as are all scoped credentials when this is unset.
+    pub tenant: Option<String>,
+}
+
+struct ApiKey {
+    name: String,
+    role: Role,
+    tenant: Option<String>,
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    role: Role,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+struct Inner {
+    keys: Vec<ApiKey>,
+    jwt: Option<DecodingKey>,
+    tenant: Option<String>,
+    audit: AuditLog,
+}
+
+#[derive(Clone)]
+pub struct Auth {
+    inner: Arc<Inner>,
+}
+
+type Rejection = (StatusCode, String);
+
+impl Auth {
+    pub async fn load(service: &str, settings: &AuthSettings) -> Result<Self> {
+        let keys = match &settings.api_keys_file {
+            Some(path) => read_keys(path)?,
+            None => Vec::new(),
+        };
+        let jwt = settings
+            .jwt_secret
+            .as_ref()
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+        let audit = AuditLog::connect(service, settings.audit_database_url.as_deref()).await?;
+
+        if keys.is_empty() && jwt.is_none() {
+            log::warn!(
+                "{service}: no API keys or JWT secret configured, admin endpoints are disabled"
+            );
+        } else {
+            log::info!(
+                "{service}: {} API keys loaded, JWT {}",
+                keys.len(),
+                if jwt.is_some() { "enabled" } else { "disabled" }
+            );
+        }
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                keys,
+                jwt,
+                tenant: settings.tenant.clone(),
+                audit,
+            }),
+        })
+    }
+
+    /// Requires credentials on every route added to `router` so far.
+    pub fn protect<S>(&self, router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        router.route_layer(middleware::from_fn_with_state(self.clone(), authorize))
+    }
+
+    /// Records an admin action. Failing to write the audit row is logged, not returned:
+    /// by the time this is called the action has already happened.
+    pub async fn audit(&self, principal: &Principal, action: &str, detail: serde_json::Value) {
+        self.inner.audit.record(principal, action, detail).await;
+    }
+
+    /// Whether `principal` may access this service's tenant.
+    fn admits(&self, principal: &Principal) -> bool {
+        match &principal.tenant {
+            None => true,
+            Some(tenant) => self.inner.tenant.as_ref() == Some(tenant),
+        }
+    }
+
+    fn is_configured(&self) -> bool {
+        !self.inner.keys.is_empty() || self.inner.jwt.is_some()
+    }
+
+    fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, Rejection> {
+        if !self.is_configured() {
+            return Ok(Principal {
+                name: "anonymous".to_string(),
+                role: Role::Read,
+                tenant: self.inner.tenant.clone(),
+            });
+        }
+
+        let credential = credential(headers).ok_or((
+            StatusCode::UNAUTHORIZED,
+            "Missing API key or bearer token".to_string(),
+        ))?;
+
+        // Check every key so timing doesn't reveal which one was close
+        let mut matched = None;
+        for key in &self.inner.keys {
+            if bool::from(key.key.as_bytes().ct_eq(credential.as_bytes())) {
+                matched = Some(Principal {
+                    name: key.name.clone(),
+                    role: key.role,
+                    tenant: key.tenant.clone(),
+                });
+            }
+        }
+        if let Some(principal) = matched {
+            return Ok(principal);
+        }
+
+        if let Some(decoding_key) = &self.inner.jwt {
+            if let Ok(token) = jsonwebtoken::decode::<Claims>(
+                credential,
+                decoding_key,
+                &Validation::new(Algorithm::HS256),
+            ) {
+                return Ok(Principal {
+                    name: token.claims.sub,
+                    role: token.claims.role,
+                    tenant: token.claims.tenant,
+                });
+            }
+        }
+
+        Err((StatusCode::UNAUTHORIZED, "Invalid credentials".to_string()))
+    }
+}
+
+async fn authorize(State(auth): State<Auth>, mut request: Request, next: Next) -> Response {
+    let required = match *request.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => Role::Read,
+        _ => Role::Admin,
+    };
+
+    let principal = match auth.authenticate(request.headers()) {
+        Ok(principal) => principal,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    if !auth.admits(&principal) {
+        log::warn!(
+            "Denied {} {} for {} of another tenant",
+            request.method(),
+            request.uri().path(),
+            principal.name
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            "Credentials are for another tenant".to_string(),
+        )
+            .into_response();
+    }
+
+    if principal.role < required {
+        let message = if auth.is_configured() {
+            format!(
+                "{} requires the {} role",
+                request.uri().path(),
+                required.as_str()
+            )
+        } else {
+            "Admin endpoints are disabled until API keys or a JWT secret are configured".to_string()
+        };
+        log::warn!(
+            "Denied {} {} for {}",
+            request.method(),
+            request.uri().path(),
+            principal.name
+        );
+        return (StatusCode::FORBIDDEN, message).into_response();
+    }
+
+    request.extensions_mut().insert(principal);
+    next.run(request).await
+}
+
+fn credential(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key") {
+        return key.to_str().ok();
+    }
+    headers
+        .get(axum::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn read_keys(path: &Path) -> Result<Vec<ApiKey>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read API keys from {}", path.display()))?;
+
+    contents
+        .lines()
+        .enumerate()
+        .map(|(number, line)| (number + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let mut parts = line.splitn(3, ':');
+            let (Some(name), Some(role), Some(key)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(anyhow!(
+                    "{}:{number}: expected name:role:key",
+                    path.display()
+                ));
+            };
+            if key.is_empty() {
+                return Err(anyhow!("{}:{number}: empty key", path.display()));
+            }
+            let (role, tenant) = match role.split_once('@') {
+                Some((_, "")) => {
+                    return Err(anyhow!("{}:{number}: empty tenant", path.display()));
+                }
+                Some((role, tenant)) => (role, Some(tenant.to_string())),
+                None => (role, None),
+            };
+            Ok(ApiKey {
+                name: name.to_string(),
+                role: role
+                    .parse()
+                    .with_context(|| format!("{}:{number}", path.display()))?,
+                tenant,
+                key: key.to_string(),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,352 @@
+//! The streams of a frame as the services work with them. Every protobuf field is optional on
+//! the wire, so a stream is checked once, where it is decoded: one without a name or
+//! calculations is skipped with the reason, and a value the publisher left out of one that is
+//! kept becomes NaN and is listed in `Stream::missing`, for the services to count. Everything
+//! after that works on plain numbers.
+//!
+//! Validation rejects single streams, never a whole frame. A stream keeps its raw
+//! calculations as well, for the code that reads fields beyond these.
+
+pub mod time_sync;
+
+use std::fmt;
+
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, CompositeCalculations,
+    CompositeJoinedCalculations, CompositeJoinedCalculationsWrapper, CompositeTwoPhaseCalculations,
+    Provenance,
+};
+
+/// One phase of a stream, NaN where the publisher left a value out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhaseMeasurements {
+    pub provenance: Option<Provenance>,
+    pub rms_voltage: f64,
+    pub dc_offset_voltage: f64,
+    pub rms_current: f64,
+    pub dc_offset_current: f64,
+    pub real_power: f64,
+    pub apparent_power: f64,
+    pub reactive_power: f64,
+    pub power_factor: f64,
+    /// Extended waveform statistics are only sent by some publishers, so they are never
+    /// counted as missing
+    pub crest_factor_voltage: Option<f64>,
+    pub thd_voltage: Option<f64>,
+    pub crest_factor_current: Option<f64>,
+    pub thd_current: Option<f64>,
+}
+
+impl PhaseMeasurements {
+    pub const MISSING: Self = Self {
+        provenance: None,
+        rms_voltage: f64::NAN,
+        dc_offset_voltage: f64::NAN,
+        rms_current: f64::NAN,
+        dc_offset_current: f64::NAN,
+        real_power: f64::NAN,
+        apparent_power: f64::NAN,
+        reactive_power: f64::NAN,
+        power_factor: f64::NAN,
+        crest_factor_voltage: None,
+        thd_voltage: None,
+        crest_factor_current: None,
+        thd_current: None,
+    };
+
+    /// `calcs` as phase field `phase`, adding the path of every value left out to `missing`.
+    /// Values inside a missing message are not listed again.
+    fn decode(phase: &str, calcs: CompositeCalculations, missing: &mut Vec<String>) -> Self {
+        let mut values = Self {
+            provenance: calcs.provenance,
+            ..Self::MISSING
+        };
+
+        let path = [phase, "voltage_waveform_calculations_v"];
+        if let Some(voltage) = present(missing, &path, calcs.voltage_waveform_calculations_v) {
+            values.rms_voltage = required(missing, &path, "rms", voltage.rms);
+            values.dc_offset_voltage = required(missing, &path, "dc_offset", voltage.dc_offset);
+            values.crest_factor_voltage = voltage.crest_factor.map(f64::from);
+            values.thd_voltage = voltage.thd_percent.map(f64::from);
+        }
+
+        let path = [phase, "current_waveform_calculations_a"];
+        if let Some(current) = present(missing, &path, calcs.current_waveform_calculations_a) {
+            values.rms_current = required(missing, &path, "rms", current.rms);
+            values.dc_offset_current = required(missing, &path, "dc_offset", current.dc_offset);
+            values.crest_factor_current = current.crest_factor.map(f64::from);
+            values.thd_current = current.thd_percent.map(f64::from);
+        }
+
+        let path = [phase, "power_calculations"];
+        if let Some(power) = present(missing, &path, calcs.power_calculations) {
+            values.real_power = required(missing, &path, "real_power_w", power.real_power_w);
+            values.apparent_power =
+                required(missing, &path, "apparent_power_va", power.apparent_power_va);
+            values.reactive_power = required(
+                missing,
+                &path,
+                "reactive_power_var",
+                power.reactive_power_var,
+            );
+            values.power_factor = required(missing, &path, "power_factor", power.power_factor);
+        }
+        values
+    }
+}
+
+fn present<T>(missing: &mut Vec<String>, path: &[&str], value: Option<T>) -> Option<T> {
+    if value.is_none() {
+        missing.push(path.join("."));
+    }
+    value
+}
+
+fn required(missing: &mut Vec<String>, message: &[&str], name: &str, value: Option<f32>) -> f64 {
+    match value {
+        Some(value) => value as f64,
+        None => {
+            missing.push(format!("{}.{name}", message.join(".")));
+            f64::NAN
+        }
+    }
+}
+
+/// Without listing what is missing, e.g. for stored samples that were counted when they
+/// first arrived.
+impl From<CompositeCalculations> for PhaseMeasurements {
+    fn from(calcs: CompositeCalculations) -> Self {
+        Self::decode("", calcs, &mut Vec::new())
+    }
+}
+
+/// One stream of a frame.
+#[derive(Clone, Debug)]
+pub struct Stream {
+    pub name: String,
+    /// All NaN if the publisher left the phase out
+    pub phase_a: PhaseMeasurements,
+    pub phase_b: PhaseMeasurements,
+    /// Only three-phase meters send it, so two-phase ones aren't missing it
+    pub phase_c: Option<PhaseMeasurements>,
+    /// Paths of the required fields left out, e.g. `phase_b.power_calculations.power_factor`
+    pub missing: Vec<String>,
+    pub calculations: CompositeTwoPhaseCalculations,
+}
+
+impl Stream {
+    pub fn new(name: String, calculations: CompositeTwoPhaseCalculations) -> Self {
+        let mut missing = Vec::new();
+        let mut phase = |phase: &str, calcs: Option<CompositeCalculations>| match present(
+            &mut missing,
+            &[phase],
+            calcs,
+        ) {
+            Some(calcs) => PhaseMeasurements::decode(phase, calcs, &mut missing),
+            None => PhaseMeasurements::MISSING,
+        };
+        let phase_a = phase("phase_a", calculations.phase_a);
+        let phase_b = phase("phase_b", calculations.phase_b);
+        let phase_c = calculations
+            .phase_c
+            .map(|calcs| phase("phase_c", Some(calcs)));
+        Self {
+            name,
+            phase_a,
+            phase_b,
+            phase_c,
+            missing,
+            calculations,
+        }
+    }
+
+    /// Phases A, B and C, each `None` if the publisher left it out.
+    pub fn phases(&self) -> [Option<&PhaseMeasurements>; 3] {
+        [
+            self.calculations.phase_a.and(Some(&self.phase_a)),
+            self.calculations.phase_b.and(Some(&self.phase_b)),
+            self.phase_c.as_ref(),
+        ]
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Why a stream entry of a frame was left out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Skipped {
+    NoName,
+    NoDataProduct,
+    /// A data product other than calculations, which isn't malformed, just not a stream
+    NotCalculations,
+}
+
+impl Skipped {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::NoName => "no calculation_name",
+            Self::NoDataProduct => "no data_product",
+            Self::NotCalculations => "not calculations",
+        }
+    }
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+impl std::error::Error for Skipped {}
+
+impl TryFrom<&CompositeJoinedCalculationsWrapper> for Stream {
+    type Error = Skipped;
+
+    fn try_from(wrapper: &CompositeJoinedCalculationsWrapper) -> Result<Self, Skipped> {
+        let name = wrapper.calculation_name.clone().ok_or(Skipped::NoName)?;
+        match &wrapper.data_product {
+            Some(DataProduct::Calculations(calculations)) => Ok(Self::new(name, *calculations)),
+            Some(_) => Err(Skipped::NotCalculations),
+            None => Err(Skipped::NoDataProduct),
+        }
+    }
+}
+
+/// The streams of one frame, in the order they were sent.
+#[derive(Clone, Debug, Default)]
+pub struct StreamFrame {
+    pub streams: Vec<Stream>,
+    pub skipped: Vec<Skipped>,
+}
+
+impl From<&CompositeJoinedCalculations> for StreamFrame {
+    fn from(joined: &CompositeJoinedCalculations) -> Self {
+        let mut frame = Self::default();
+        for wrapper in &joined.calculations {
+            match Stream::try_from(wrapper) {
+                Ok(stream) => frame.streams.push(stream),
+                Err(skipped) => frame.skipped.push(skipped),
+            }
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf_rs::utilidata::karman::bibimbap::v1::{PowerCalculations, WaveformCalculations};
+
+    use super::*;
+
+    fn phase(real: f32) -> CompositeCalculations {
+        CompositeCalculations {
+            provenance: None,
+            voltage_waveform_calculations_v: Some(WaveformCalculations {
+                rms: Some(120.0),
+                dc_offset: Some(0.5),
+                ..Default::default()
+            }),
+            current_waveform_calculations_a: Some(WaveformCalculations {
+                rms: Some(real / 120.0),
+                dc_offset: Some(0.0),
+                thd_percent: Some(3.0),
+                ..Default::default()
+            }),
+            power_calculations: Some(PowerCalculations {
+                real_power_w: Some(real),
+                reactive_power_var: Some(real / 10.0),
+                apparent_power_va: Some(real),
+                power_factor: Some(1.0),
+            }),
+        }
+    }
+
+    fn wrapper(
+        name: Option<&str>,
+        calcs: CompositeTwoPhaseCalculations,
+    ) -> CompositeJoinedCalculationsWrapper {
+        CompositeJoinedCalculationsWrapper {
+            calculation_name: name.map(str::to_string),
+            data_product: Some(DataProduct::Calculations(calcs)),
+            device_id: None,
+            harmonics: None,
+        }
+    }
+
+    #[test]
+    fn missing_values_are_nan_and_listed() {
+        let mut partial = phase(1200.0);
+        partial.power_calculations.as_mut().unwrap().power_factor = None;
+        partial.voltage_waveform_calculations_v = None;
+        let stream = Stream::new(
+            "feeder-1".to_string(),
+            CompositeTwoPhaseCalculations {
+                phase_a: Some(phase(1200.0)),
+                phase_b: Some(partial),
+                phase_c: None,
+            },
+        );
+        assert_eq!(stream.phase_a.real_power, 1200.0);
+        assert_eq!(stream.phase_a.thd_current, Some(3.0));
+        assert_eq!(stream.phase_a.crest_factor_current, None);
+        assert!(stream.phase_b.power_factor.is_nan());
+        assert!(stream.phase_b.rms_voltage.is_nan());
+        assert_eq!(stream.phase_b.real_power, 1200.0);
+        assert_eq!(
+            stream.missing,
+            [
+                "phase_b.voltage_waveform_calculations_v",
+                "phase_b.power_calculations.power_factor"
+            ]
+        );
+        assert!(stream.phases()[2].is_none());
+
+        let stream = Stream::new(
+            "feeder-2".to_string(),
+            CompositeTwoPhaseCalculations {
+                phase_a: None,
+                phase_b: Some(phase(10.0)),
+                phase_c: Some(phase(20.0)),
+            },
+        );
+        assert_eq!(stream.missing, ["phase_a"]);
+        assert!(stream.phase_a.real_power.is_nan());
+        let [a, b, c] = stream.phases();
+        assert!(a.is_none());
+        assert_eq!(b.unwrap().real_power, 10.0);
+        assert_eq!(c.unwrap().real_power, 20.0);
+    }
+
+    #[test]
+    fn unusable_entries_are_skipped_not_the_frame() {
+        let calcs = CompositeTwoPhaseCalculations {
+            phase_a: Some(phase(1.0)),
+            phase_b: Some(phase(2.0)),
+            phase_c: None,
+        };
+        let joined = CompositeJoinedCalculations {
+            calculations: vec![
+                wrapper(Some("feeder-1"), calcs),
+                wrapper(None, calcs),
+                CompositeJoinedCalculationsWrapper {
+                    calculation_name: Some("feeder-2".to_string()),
+                    data_product: None,
+                    device_id: None,
+                    harmonics: None,
+                },
+                wrapper(Some("feeder-3"), calcs),
+            ],
+        };
+        let frame = StreamFrame::from(&joined);
+        let names: Vec<_> = frame
+            .streams
+            .iter()
+            .map(|stream| stream.name.as_str())
+            .collect();
+        assert_eq!(names, ["feeder-1", "feeder-3"]);
+        assert!(frame.streams.iter().all(Stream::is_complete));
+        assert_eq!(frame.skipped, [Skipped::NoName, Skipped::NoDataProduct]);
+    }
+}
@@ -0,0 +1,77 @@
+//! The publisher's clock status, judged and named the same way everywhere it is exported or
+//! stored.
+
+use std::time::Duration;
+
+use protobuf_rs::utilidata::karman::bibimbap::v1::{time_sync::Source, TimeSync};
+
+/// Every clock source, in protobuf order.
+pub const SOURCES: [Source; 5] = [
+    Source::Unspecified,
+    Source::FreeRunning,
+    Source::Ntp,
+    Source::Ptp,
+    Source::Gps,
+];
+
+/// How `source` is spelled in metric labels, stored rows and datasets.
+pub fn source_label(source: Source) -> &'static str {
+    match source {
+        Source::Unspecified => "unspecified",
+        Source::FreeRunning => "free_running",
+        Source::Ntp => "ntp",
+        Source::Ptp => "ptp",
+        Source::Gps => "gps",
+    }
+}
+
+/// The source `label` names, as `source_label` spells it; unspecified for anything else.
+pub fn source_from_label(label: &str) -> Source {
+    SOURCES
+        .into_iter()
+        .find(|source| source_label(*source) == label)
+        .unwrap_or(Source::Unspecified)
+}
+
+/// Timestamps are trusted when the clock is locked to a real reference and neither its
+/// offset nor its error bound (when reported) exceeds `max_offset`.
+pub fn is_trusted(sync: &TimeSync, max_offset: Duration) -> bool {
+    let max_offset_ns = max_offset.as_nanos() as u64;
+    !matches!(sync.source(), Source::FreeRunning)
+        && sync.locked()
+        && sync
+            .offset_ns
+            .is_none_or(|offset| offset.unsigned_abs() <= max_offset_ns)
+        && sync.max_error_ns.is_none_or(|error| error <= max_offset_ns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted(source: Source, locked: bool, offset_ns: Option<i64>) -> bool {
+        let sync = TimeSync {
+            source: Some(source as i32),
+            locked: Some(locked),
+            offset_ns,
+            max_error_ns: None,
+        };
+        is_trusted(&sync, Duration::from_millis(1))
+    }
+
+    #[test]
+    fn only_a_locked_clock_within_the_offset_is_trusted() {
+        assert!(trusted(Source::Gps, true, Some(-999_000)));
+        assert!(!trusted(Source::Gps, true, Some(2_000_000)));
+        assert!(!trusted(Source::Ntp, false, None));
+        assert!(!trusted(Source::FreeRunning, true, None));
+    }
+
+    #[test]
+    fn labels_name_their_source() {
+        for source in SOURCES {
+            assert_eq!(source_from_label(source_label(source)), source);
+        }
+        assert_eq!(source_from_label("sundial"), Source::Unspecified);
+    }
+}
@@ -0,0 +1,155 @@
+//! What happens to calculations whose provenance timestamp is older than one already seen for
+//! the same stream, as after network reordering, a publisher resending, or a replay restarted
+//! part way through. data-db and the exporter share it so their rows, rollups and counters
+//! agree on which of them count.
+//!
+//! Under `correct`, calculations late by no more than the lateness window are accepted and
+//! whatever aggregates them is corrected to include them; later ones are rejected. Under
+//! `reject`, everything out of order is rejected. Either way the late ones are counted.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeTwoPhaseCalculations;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LatePolicy {
+    /// Accept calculations late by up to the lateness window, and correct aggregates for them
+    #[default]
+    Correct,
+    /// Drop every calculation older than one already seen for its stream
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LatenessConfig {
+    pub policy: LatePolicy,
+    /// How late a calculation may be and still be accepted under `Correct`
+    pub window: Duration,
+}
+
+impl LatenessConfig {
+    /// How far back aggregates have to be revisited, or None if they never are.
+    pub fn correction_window(&self) -> Option<Duration> {
+        match self.policy {
+            LatePolicy::Correct => Some(self.window),
+            LatePolicy::Reject => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arrival {
+    /// No older than anything seen for the stream, or without a timestamp to tell
+    InOrder,
+    /// Out of order, but within the lateness window
+    Accepted,
+    Rejected,
+}
+
+impl Arrival {
+    /// The `outcome` label of a late-calculations counter; None for `InOrder`.
+    pub fn outcome(self) -> Option<&'static str> {
+        match self {
+            Self::InOrder => None,
+            Self::Accepted => Some("accepted"),
+            Self::Rejected => Some("rejected"),
+        }
+    }
+}
+
+/// The newest provenance timestamp seen for each stream.
+#[derive(Clone)]
+pub struct OrderTracker {
+    config: LatenessConfig,
+    newest: HashMap<String, SystemTime>,
+}
+
+impl OrderTracker {
+    pub fn new(config: LatenessConfig) -> Self {
+        Self {
+            config,
+            newest: HashMap::new(),
+        }
+    }
+
+    /// Classifies one stream's calculations by their provenance timestamp.
+    pub fn check(&mut self, stream: &str, calcs: &CompositeTwoPhaseCalculations) -> Arrival {
+        match provenance_time(calcs) {
+            Some(time) => self.check_time(stream, time),
+            None => Arrival::InOrder,
+        }
+    }
+
+    pub fn check_time(&mut self, stream: &str, time: SystemTime) -> Arrival {
+        let Some(newest) = self.newest.get_mut(stream) else {
+            self.newest.insert(stream.to_string(), time);
+            return Arrival::InOrder;
+        };
+        let Ok(late) = newest.duration_since(time) else {
+            *newest = time;
+            return Arrival::InOrder;
+        };
+        if late.is_zero() {
+            return Arrival::InOrder;
+        }
+        match self.config.policy {
+            LatePolicy::Correct if late <= self.config.window => Arrival::Accepted,
+            _ => Arrival::Rejected,
+        }
+    }
+}
+
+/// The latest of the phases' UTC timestamps.
+pub fn provenance_time(calcs: &CompositeTwoPhaseCalculations) -> Option<SystemTime> {
+    [calcs.phase_a, calcs.phase_b, calcs.phase_c]
+        .into_iter()
+        .flatten()
+        .filter_map(|phase| phase.provenance?.utc_time)
+        .map(|time| {
+            let nanos = Duration::from_nanos(time.nanos.max(0) as u64);
+            match u64::try_from(time.seconds) {
+                Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs) + nanos,
+                Err(_) => UNIX_EPOCH - Duration::from_secs(time.seconds.unsigned_abs()) + nanos,
+            }
+        })
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(policy: LatePolicy) -> OrderTracker {
+        OrderTracker::new(LatenessConfig {
+            policy,
+            window: Duration::from_secs(10),
+        })
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn late_within_the_window_is_accepted_when_correcting() {
+        let mut tracker = tracker(LatePolicy::Correct);
+        assert_eq!(tracker.check_time("feeder", at(100)), Arrival::InOrder);
+        assert_eq!(tracker.check_time("feeder", at(100)), Arrival::InOrder);
+        assert_eq!(tracker.check_time("feeder", at(95)), Arrival::Accepted);
+        assert_eq!(tracker.check_time("feeder", at(89)), Arrival::Rejected);
+        // Accepting a late one doesn't move the stream back
+        assert_eq!(tracker.check_time("feeder", at(96)), Arrival::Accepted);
+        assert_eq!(tracker.check_time("feeder", at(101)), Arrival::InOrder);
+    }
+
+    #[test]
+    fn anything_out_of_order_is_rejected_when_rejecting() {
+        let mut tracker = tracker(LatePolicy::Reject);
+        assert_eq!(tracker.check_time("feeder", at(100)), Arrival::InOrder);
+        assert_eq!(tracker.check_time("feeder", at(99)), Arrival::Rejected);
+        assert_eq!(tracker.check_time("other", at(50)), Arrival::InOrder);
+    }
+}
@@ -0,0 +1,144 @@
+//! Log output shared by every binary: `--log-format text` for reading, or `json` for log
+//! pipelines, one object per line. The `log` macros the code has always used go through the
+//! same output, so nothing needs rewriting to show up in either.
+//!
+//! A JSON line carries the spans it was logged in. Services handle each frame in a `frame`
+//! span naming its stream and sequence number, with a span under it for each stage the service
+//! has: `decode` for decoding the message, `transform` for turning it into what the service
+//! writes or sends on, `sink` for writing or sending it. The exporter, whose metric updates
+//! are what it does with a frame, has only `sink`. Where several messages are assembled into
+//! one frame, each is decoded in a `frame` span of its own and the assembled frame handled in
+//! another, so the lines about one frame are picked out, and followed from one service to the
+//! next, by those two fields rather than by a single span.
+
+use std::io::IsTerminal;
+
+use clap::ValueEnum;
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, CompositeJoinedCalculations,
+};
+use tracing::field::Empty;
+use tracing::Span;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the spans it was logged in
+    Json,
+}
+
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct LogArgs {
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// The least severe level logged (error, warn, info, debug or trace), or a filter in
+    /// RUST_LOG's syntax such as `info,sqlx=warn`. RUST_LOG applies without it.
+    #[arg(long, value_parser = parse_filter)]
+    pub log_level: Option<String>,
+}
+
+impl LogArgs {
+    /// LOG_FORMAT and LOG_LEVEL, for binaries configured through the environment.
+    pub fn from_env() -> Result<Self, String> {
+        let log_format = match std::env::var("LOG_FORMAT") {
+            Ok(format) => LogFormat::from_str(&format, true)
+                .map_err(|_| format!("Invalid LOG_FORMAT {format:?}: expected text or json"))?,
+            Err(_) => LogFormat::Text,
+        };
+        let log_level = match std::env::var("LOG_LEVEL") {
+            Ok(level) if !level.is_empty() => {
+                Some(parse_filter(&level).map_err(|err| format!("Invalid LOG_LEVEL: {err}"))?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            log_format,
+            log_level,
+        })
+    }
+}
+
+fn parse_filter(filter: &str) -> Result<String, String> {
+    EnvFilter::try_new(filter)
+        .map(|_| filter.to_string())
+        .map_err(|err| err.to_string())
+}
+
+/// Sends logs to stderr as `args` say, filtered by --log-level, then RUST_LOG, then `default`.
+pub fn init(args: &LogArgs, default: &str) {
+    let filter = match &args.log_level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default)),
+    };
+    let registry = tracing_subscriber::registry().with(filter);
+    match args.log_format {
+        LogFormat::Text => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(std::io::stderr().is_terminal())
+                    .with_writer(std::io::stderr),
+            )
+            .init(),
+        LogFormat::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(false)
+                    .with_span_list(true)
+                    .with_writer(std::io::stderr),
+            )
+            .init(),
+    }
+}
+
+/// The span to handle a message in before it is decoded, with a `decode` span under it for
+/// the decoding; `record_frame` names it once the message decodes.
+pub fn undecoded_frame_span() -> Span {
+    tracing::info_span!("frame", stream = Empty, sequence = Empty)
+}
+
+/// Names a span from `undecoded_frame_span` after `joined`'s first stream with calculations.
+/// A frame's streams normally share their sequence number; frames without calculations have
+/// neither.
+pub fn record_frame(span: &Span, joined: &CompositeJoinedCalculations) {
+    let first = joined
+        .calculations
+        .iter()
+        .find_map(|wrapper| match &wrapper.data_product {
+            Some(DataProduct::Calculations(calcs)) => Some((
+                wrapper.calculation_name.as_deref(),
+                frame_sequence::sequence_number(calcs),
+            )),
+            _ => None,
+        });
+    let (stream, sequence) = first.unwrap_or_default();
+    if let Some(stream) = stream {
+        span.record("stream", stream);
+    }
+    if let Some(sequence) = sequence {
+        span.record("sequence", sequence);
+    }
+}
+
+/// The span to handle `joined` in, once it is decoded, named as `record_frame` does.
+pub fn frame_span(joined: &CompositeJoinedCalculations) -> Span {
+    let span = undecoded_frame_span();
+    record_frame(&span, joined);
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_and_filters_are_accepted_and_anything_else_is_not() {
+        assert!(parse_filter("debug").is_ok());
+        assert!(parse_filter("info,sqlx=warn").is_ok());
+        assert!(parse_filter("sqlx=loud").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Quantities derived from one stream's phases: its total real, reactive and apparent power,
+//! power factor, voltage and current imbalance, and an estimate of its neutral current.
+//! data-db stores them with each stream and the exporter exports them as gauges.
+//!
+//! These are totals over a stream's own phases. The `three_phase_*` values both services
+//! had before are something else, each phase summed over every stream in a frame, and are
+//! kept as they were.
+//!
+//! A value the publisher left out adds nothing to a total, and a total nothing was sent for
+//! is None rather than 0. Imbalance and neutral current need every phase's value. Two-phase
+//! meters are taken to be on split-phase services, whose legs are 180° apart, and three-phase
+//! meters on wye services, whose phases are 120° apart.
+
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeCalculations, CompositeTwoPhaseCalculations,
+};
+
+/// One phase's measurements; None where the publisher left them out.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Phase {
+    pub rms_voltage: Option<f64>,
+    pub rms_current: Option<f64>,
+    pub real_power: Option<f64>,
+    pub reactive_power: Option<f64>,
+}
+
+impl From<&CompositeCalculations> for Phase {
+    fn from(calcs: &CompositeCalculations) -> Self {
+        let voltage = calcs.voltage_waveform_calculations_v.unwrap_or_default();
+        let current = calcs.current_waveform_calculations_a.unwrap_or_default();
+        let power = calcs.power_calculations.unwrap_or_default();
+        Self {
+            rms_voltage: voltage.rms.map(f64::from),
+            rms_current: current.rms.map(f64::from),
+            real_power: power.real_power_w.map(f64::from),
+            reactive_power: power.reactive_power_var.map(f64::from),
+        }
+    }
+}
+
+/// A stream's phases in order. Phase C only counts from three-phase meters, while phase A or B
+/// missing from a partial frame counts as a phase that sent nothing.
+pub fn phases(calcs: &CompositeTwoPhaseCalculations) -> Vec<Phase> {
+    let phase =
+        |calcs: Option<CompositeCalculations>| calcs.as_ref().map(Phase::from).unwrap_or_default();
+    let mut phases = vec![phase(calcs.phase_a), phase(calcs.phase_b)];
+    phases.extend(calcs.phase_c.map(|phase_c| phase(Some(phase_c))));
+    phases
+}
+
+/// A stream's quantities over all its phases.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    /// Watts
+    pub real_power: Option<f64>,
+    /// var
+    pub reactive_power: Option<f64>,
+    /// VA, from the total real and reactive power (IEEE 1459 vector apparent power)
+    pub apparent_power: Option<f64>,
+    /// Negative while the stream exports real power
+    pub power_factor: Option<f64>,
+    pub voltage_imbalance_percent: Option<f64>,
+    pub current_imbalance_percent: Option<f64>,
+    /// Amperes
+    pub neutral_current: Option<f64>,
+}
+
+/// Summarizes a stream's phases, in phase order.
+pub fn summarize(phases: &[Phase]) -> Summary {
+    let real_power = total(phases.iter().map(|phase| phase.real_power));
+    let reactive_power = total(phases.iter().map(|phase| phase.reactive_power));
+    let apparent_power = real_power
+        .zip(reactive_power)
+        .map(|(real, reactive)| apparent_power(real, reactive));
+    let every = |value: fn(&Phase) -> Option<f64>| -> Option<Vec<f64>> {
+        phases.iter().map(value).collect()
+    };
+    let voltages = every(|phase| phase.rms_voltage);
+    let currents = every(|phase| phase.rms_current);
+    Summary {
+        real_power,
+        reactive_power,
+        apparent_power,
+        power_factor: real_power
+            .zip(apparent_power)
+            .and_then(|(real, apparent)| power_factor(real, apparent)),
+        voltage_imbalance_percent: voltages.as_deref().and_then(imbalance_percent),
+        current_imbalance_percent: currents.as_deref().and_then(imbalance_percent),
+        neutral_current: currents.as_deref().and_then(neutral_current),
+    }
+}
+
+/// The sum of the values that were sent; None when none were.
+pub fn total(values: impl IntoIterator<Item = Option<f64>>) -> Option<f64> {
+    values
+        .into_iter()
+        .flatten()
+        .filter(|value| !value.is_nan())
+        .fold(None, |sum, value| Some(sum.unwrap_or(0.0) + value))
+}
+
+pub fn apparent_power(real: f64, reactive: f64) -> f64 {
+    real.hypot(reactive)
+}
+
+/// Real over apparent power; None without apparent power to divide by.
+pub fn power_factor(real: f64, apparent: f64) -> Option<f64> {
+    if !apparent.is_finite() || apparent <= 0.0 {
+        return None;
+    }
+    Some((real / apparent).clamp(-1.0, 1.0))
+}
+
+/// Percent imbalance as defined by NEMA MG-1 14.35: the largest deviation from the average of
+/// the phases, as a percent of the average. None for fewer than two phases or a zero average.
+pub fn imbalance_percent(values: &[f64]) -> Option<f64> {
+    if values.len() < 2 {
+        return None;
+    }
+    let average = values.iter().sum::<f64>() / values.len() as f64;
+    if !average.is_finite() || average <= 0.0 {
+        return None;
+    }
+    let max_deviation = values
+        .iter()
+        .map(|value| (value - average).abs())
+        .fold(0.0, f64::max);
+    Some(max_deviation / average * 100.0)
+}
+
+/// The neutral current of a split-phase (two currents) or wye (three currents) service,
+/// from the rms phase currents alone. Assumes the currents are as far apart as the voltages,
+/// i.e. unity power factor on every phase, and leaves out the triplen harmonics that add up
+/// in the neutral, so treat it as an estimate.
+pub fn neutral_current(currents: &[f64]) -> Option<f64> {
+    match *currents {
+        [a, b] => Some((a - b).abs()),
+        [a, b, c] => Some(
+            (a * a + b * b + c * c - a * b - b * c - c * a)
+                .max(0.0)
+                .sqrt(),
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phase(volts: f64, amps: f64, watts: f64, var: f64) -> Phase {
+        Phase {
+            rms_voltage: Some(volts),
+            rms_current: Some(amps),
+            real_power: Some(watts),
+            reactive_power: Some(var),
+        }
+    }
+
+    fn close(a: Option<f64>, b: f64) -> bool {
+        a.is_some_and(|a| (a - b).abs() < 1e-9)
+    }
+
+    #[test]
+    fn totals_power_over_the_streams_own_phases() {
+        let summary = summarize(&[
+            phase(120.0, 10.0, 1000.0, 300.0),
+            phase(120.0, 10.0, 1000.0, 300.0),
+            phase(120.0, 10.0, 1000.0, 300.0),
+        ]);
+        assert_eq!(summary.real_power, Some(3000.0));
+        assert_eq!(summary.reactive_power, Some(900.0));
+        assert!(close(summary.apparent_power, 3000f64.hypot(900.0)));
+        assert!(close(summary.power_factor, 3000.0 / 3000f64.hypot(900.0)));
+        // Balanced phases cancel out in the neutral
+        assert_eq!(summary.voltage_imbalance_percent, Some(0.0));
+        assert_eq!(summary.current_imbalance_percent, Some(0.0));
+        assert_eq!(summary.neutral_current, Some(0.0));
+    }
+
+    #[test]
+    fn values_left_out_add_nothing() {
+        let mut partial = phase(120.0, 10.0, 1000.0, 300.0);
+        partial.real_power = None;
+        partial.rms_current = None;
+        let summary = summarize(&[phase(120.0, 10.0, 1000.0, 300.0), partial]);
+        assert_eq!(summary.real_power, Some(1000.0));
+        assert_eq!(summary.reactive_power, Some(600.0));
+        // Needs every phase's current
+        assert_eq!(summary.current_imbalance_percent, None);
+        assert_eq!(summary.neutral_current, None);
+
+        assert_eq!(summarize(&[Phase::default()]), Summary::default());
+    }
+
+    #[test]
+    fn exporting_power_has_a_negative_power_factor() {
+        assert_eq!(power_factor(-800.0, 1000.0), Some(-0.8));
+        assert_eq!(power_factor(0.0, 0.0), None);
+    }
+
+    #[test]
+    fn imbalance_is_the_largest_deviation_from_the_average() {
+        // Average 100, phase C 10 below it
+        assert!(close(imbalance_percent(&[105.0, 105.0, 90.0]), 10.0));
+        assert_eq!(imbalance_percent(&[120.0]), None);
+        assert_eq!(imbalance_percent(&[0.0, 0.0]), None);
+    }
+
+    #[test]
+    fn neutral_current_follows_the_service_type() {
+        // Split-phase legs subtract
+        assert_eq!(neutral_current(&[30.0, 10.0]), Some(20.0));
+        // One loaded wye phase returns all its current through the neutral
+        assert_eq!(neutral_current(&[10.0, 0.0, 0.0]), Some(10.0));
+        assert!(close(neutral_current(&[20.0, 10.0, 10.0]), 10.0));
+        assert_eq!(neutral_current(&[10.0]), None);
+    }
+}
@@ -0,0 +1,459 @@
+//! One configuration file for data-db, data-exporter, data-replay, data-forwarder and
+//! data-stream, passed to each with `--config PATH`.
+//!
+//! The file is TOML, or YAML when it ends in `.yaml` or `.yml`, with a table per section:
+//!
+//! ```toml
+//! [zmq]
+//! endpoint = "tcp://data-replay:5557"
+//! topic = "site1/"
+//!
+//! [database]
+//! url = "postgres://karman@timescale/karman"
+//!
+//! [prometheus]
+//! port = 9100
+//!
+//! [db]              # data-db's own flags, by long name
+//! batch_size = 120
+//!
+//! [exporter]        # data-exporter's
+//! stale_after = "5s"
+//!
+//! [forwarder]       # data-forwarder's
+//! mqtt_host = "mosquitto"
+//!
+//! [stream]          # data-stream's
+//! grpc_port = 50051
+//!
+//! [replay]          # data-replay's environment variables, lower case
+//! file = "/datasets/sample1-b200-no-powercap.csv"
+//! rate_hz = 60
+//! ```
+//!
+//! The shared sections (`zmq`, `database`, `prometheus`) mean what each service makes of them,
+//! e.g. `zmq.endpoint` is data-db's `--zmq-endpoint` and the exporter's `--source`, while
+//! data-replay, which binds rather than connects, only takes `zmq.topic`. A service ignores
+//! the sections of the others, so one file can serve the whole pipeline.
+//!
+//! Settings are layered: the file is overridden by the environment variables a service reads,
+//! which are overridden by the command line. Repeatable flags are the exception: a list in the
+//! file and the same flag on the command line add up. Unknown sections, keys and flags are
+//! errors that say where they are in the file.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Command, Parser};
+use serde_json::Value;
+
+/// Sections shared between the services, and the keys they may hold.
+const SHARED_SECTIONS: &[(&str, &[&str])] = &[
+    ("zmq", &["endpoint", "topic"]),
+    ("database", &["url"]),
+    ("prometheus", &["port"]),
+];
+
+/// Sections holding one service's own settings.
+const SERVICE_SECTIONS: &[&str] = &["db", "exporter", "forwarder", "replay", "stream"];
+
+/// Where a service takes a shared setting: `zmq.endpoint` from `--source`, say.
+pub struct Shared {
+    pub section: &'static str,
+    pub key: &'static str,
+    /// The long flag name, or the environment variable for data-replay
+    pub setting: &'static str,
+}
+
+/// A loaded configuration file.
+pub struct ConfigFile {
+    path: PathBuf,
+    sections: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+/// One setting taken from the file.
+struct Setting<'a> {
+    /// Flag or environment variable name
+    name: String,
+    value: &'a Value,
+    /// Where it is in the file, e.g. `[zmq] endpoint`
+    origin: String,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let yaml = path
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml");
+        let document: Value = if yaml {
+            serde_yaml::from_str(&text)
+                .with_context(|| format!("{} is not valid YAML", path.display()))?
+        } else {
+            toml::from_str(&text)
+                .with_context(|| format!("{} is not valid TOML", path.display()))?
+        };
+        Self::from_document(path, document)
+    }
+
+    fn from_document(path: &Path, document: Value) -> Result<Self> {
+        let Value::Object(document) = document else {
+            bail!("{} should hold a table of sections", path.display());
+        };
+        let mut sections = BTreeMap::new();
+        for (section, settings) in document {
+            let Value::Object(settings) = settings else {
+                bail!("[{section}] should be a table of settings");
+            };
+            sections.insert(section, settings.into_iter().collect());
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            sections,
+        })
+    }
+
+    /// The file named by `--config PATH` or `--config=PATH` on the command line, if any.
+    pub fn from_args(args: &[OsString]) -> Result<Option<Self>> {
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            let Some(arg) = arg.to_str() else {
+                continue;
+            };
+            if arg == "--" {
+                break;
+            }
+            let path = if arg == "--config" {
+                args.next()
+                    .ok_or_else(|| anyhow!("--config needs a path"))?
+                    .into()
+            } else if let Some(path) = arg.strip_prefix("--config=") {
+                PathBuf::from(path)
+            } else {
+                continue;
+            };
+            return Self::load(&path).map(Some);
+        }
+        Ok(None)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The settings for the service whose own section is `section`, named with `name` (flag
+    /// or environment variable). Errors on anything in the file no service understands.
+    fn settings(
+        &self,
+        section: &str,
+        shared: &[Shared],
+        name: impl Fn(&str) -> String,
+    ) -> Result<Vec<Setting<'_>>> {
+        let mut settings = Vec::new();
+        for (section_name, values) in &self.sections {
+            let known = SHARED_SECTIONS
+                .iter()
+                .find(|(shared, _)| shared == section_name);
+            if let Some((_, keys)) = known {
+                for (key, value) in values {
+                    if !keys.contains(&key.as_str()) {
+                        bail!(
+                            "Unknown setting {key} in [{section_name}]; expected one of {}",
+                            keys.join(", ")
+                        );
+                    }
+                    // Shared settings this service has no use for are left to the others
+                    if let Some(shared) = shared
+                        .iter()
+                        .find(|shared| shared.section == section_name && shared.key == key)
+                    {
+                        settings.push(Setting {
+                            name: shared.setting.to_string(),
+                            value,
+                            origin: format!("[{section_name}] {key}"),
+                        });
+                    }
+                }
+            } else if section_name == section {
+                for (key, value) in values {
+                    settings.push(Setting {
+                        name: name(key),
+                        value,
+                        origin: format!("[{section_name}] {key}"),
+                    });
+                }
+            } else if !SERVICE_SECTIONS.contains(&section_name.as_str()) {
+                let mut expected: Vec<&str> = SHARED_SECTIONS.iter().map(|(s, _)| *s).collect();
+                expected.extend(SERVICE_SECTIONS);
+                bail!(
+                    "Unknown section [{section_name}]; expected one of {}",
+                    expected.join(", ")
+                );
+            }
+        }
+        Ok(settings)
+    }
+
+    /// The file's settings as command-line flags for `command`, leaving out those whose
+    /// environment variable is set, as that takes precedence.
+    pub fn flags(
+        &self,
+        command: &Command,
+        section: &str,
+        shared: &[Shared],
+    ) -> Result<Vec<OsString>> {
+        let mut flags = Vec::new();
+        for setting in self.settings(section, shared, |key| key.replace('_', "-"))? {
+            let origin = &setting.origin;
+            let Some(arg) = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(setting.name.as_str()))
+            else {
+                bail!(
+                    "{origin}: --{} is not a {} flag",
+                    setting.name,
+                    command.get_name()
+                );
+            };
+            if arg
+                .get_env()
+                .is_some_and(|env| std::env::var_os(env).is_some())
+            {
+                continue;
+            }
+            let values = match setting.value {
+                Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                let long = &setting.name;
+                match value {
+                    // Switches take no value: true is the bare flag, false leaves it off
+                    Value::Bool(on) if !arg.get_action().takes_values() => {
+                        if *on {
+                            flags.push(format!("--{long}").into());
+                        }
+                    }
+                    Value::Bool(_) | Value::Number(_) | Value::String(_) => {
+                        flags.push(format!("--{long}={}", scalar(value)).into());
+                    }
+                    _ => bail!("{origin}: expected a string, number, boolean or list of them"),
+                }
+            }
+        }
+        Ok(flags)
+    }
+
+    /// Sets the environment variables named by the file that aren't set already. For
+    /// services configured through the environment; call before starting any threads.
+    pub fn apply_to_env(&self, section: &str, shared: &[Shared], known: &[&str]) -> Result<()> {
+        for setting in self.settings(section, shared, |key| key.to_uppercase().replace('-', "_"))? {
+            let origin = &setting.origin;
+            if !known.contains(&setting.name.as_str()) {
+                bail!("{origin}: {} is not a setting here", setting.name);
+            }
+            let value = match setting.value {
+                Value::Bool(_) | Value::Number(_) | Value::String(_) => scalar(setting.value),
+                _ => bail!("{origin}: expected a string, number or boolean"),
+            };
+            if std::env::var_os(&setting.name).is_none() {
+                std::env::set_var(&setting.name, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Parses the command line of a clap service, with the settings of the `--config` file (if
+/// any) in front of it so flags given on the command line win. Exits with a usage error if
+/// either is invalid.
+pub fn parse<P: Parser>(section: &str, shared: &[Shared]) -> P {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let config = match ConfigFile::from_args(&args) {
+        Ok(Some(config)) => config,
+        Ok(None) => return P::parse_from(args),
+        Err(err) => exit_with(&err),
+    };
+
+    let command = P::command().args_override_self(true);
+    let from_file = match config.flags(&command, section, shared) {
+        Ok(flags) => flags,
+        Err(err) => exit_with(&err.context(format!("In {}", config.path().display()))),
+    };
+    let mut merged = args[..1].to_vec();
+    merged.extend(from_file.iter().cloned());
+    merged.extend(args[1..].iter().cloned());
+
+    let parsed = command
+        .try_get_matches_from(merged)
+        .and_then(|mut matches| P::from_arg_matches_mut(&mut matches));
+    match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            let mut note = format!("\nSettings read from {}:", config.path().display());
+            for flag in &from_file {
+                write!(note, " {}", flag.to_string_lossy()).expect("String write");
+            }
+            let _ = err.print();
+            eprintln!("{note}");
+            std::process::exit(err.exit_code());
+        }
+    }
+}
+
+/// A tenant's publishers put `<tenant>/` in front of their topics, so with a tenant the topic
+/// defaults to that prefix and anything outside it is refused.
+pub fn tenant_topic(tenant: Option<&str>, topic: &str) -> Result<String> {
+    let Some(tenant) = tenant else {
+        return Ok(topic.to_string());
+    };
+    let prefix = format!("{tenant}/");
+    if topic.is_empty() {
+        return Ok(prefix);
+    }
+    if !topic.starts_with(&prefix) {
+        bail!("Topic '{topic}' is outside tenant {tenant}'s prefix '{prefix}'");
+    }
+    Ok(topic.to_string())
+}
+
+fn exit_with(err: &anyhow::Error) -> ! {
+    eprintln!("error: {err:#}");
+    std::process::exit(2);
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    const SHARED: &[Shared] = &[
+        Shared {
+            section: "zmq",
+            key: "endpoint",
+            setting: "source",
+        },
+        Shared {
+            section: "prometheus",
+            key: "port",
+            setting: "prometheus-port",
+        },
+    ];
+
+    #[derive(Parser, Debug)]
+    #[command(name = "data-exporter")]
+    struct Args {
+        #[arg(long)]
+        source: Vec<String>,
+        #[arg(long)]
+        prometheus_port: u16,
+        #[arg(long)]
+        sample_counters: bool,
+        #[arg(long, default_value = "5m")]
+        lateness_window: String,
+    }
+
+    fn config(toml: &str) -> ConfigFile {
+        ConfigFile::from_document(Path::new("test.toml"), toml::from_str(toml).unwrap()).unwrap()
+    }
+
+    fn flags(toml: &str) -> Result<Vec<String>> {
+        let flags = config(toml).flags(&Args::command(), "exporter", SHARED)?;
+        Ok(flags
+            .into_iter()
+            .map(|flag| flag.into_string().unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn shared_and_own_settings_become_flags() {
+        let flags = flags(
+            r#"
+            [zmq]
+            endpoint = ["tcp://a:5557", "tcp://b:5557"]
+            topic = "ignored/"
+            [prometheus]
+            port = 9100
+            [exporter]
+            sample_counters = true
+            lateness_window = "1m"
+            [db]
+            batch_size = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            flags,
+            [
+                "--lateness-window=1m",
+                "--sample-counters",
+                "--prometheus-port=9100",
+                "--source=tcp://a:5557",
+                "--source=tcp://b:5557",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_false_switch_is_left_off() {
+        assert!(flags("[exporter]\nsample_counters = false")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_file() {
+        let mut args = vec!["data-exporter".to_string()];
+        args.extend(
+            flags("[prometheus]\nport = 9100\n[exporter]\nlateness_window = \"1m\"").unwrap(),
+        );
+        args.push("--prometheus-port=9200".to_string());
+        let matches = Args::command()
+            .args_override_self(true)
+            .try_get_matches_from(args)
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        assert_eq!(args.prometheus_port, 9200);
+        assert_eq!(args.lateness_window, "1m");
+    }
+
+    #[test]
+    fn mistakes_say_where_they_are() {
+        let err = flags("[zmq]\nendpont = \"tcp://a:5557\"").unwrap_err();
+        assert!(err.to_string().contains("endpont in [zmq]"), "{err}");
+        let err = flags("[exporter]\nwindow = 5").unwrap_err();
+        assert!(err.to_string().contains("[exporter] window"), "{err}");
+        let err = flags("[exproter]\nwindow = 5").unwrap_err();
+        assert!(err.to_string().contains("[exproter]"), "{err}");
+        let err = flags("[exporter]\nlateness_window = { minutes = 5 }").unwrap_err();
+        assert!(err.to_string().contains("expected a string"), "{err}");
+    }
+
+    #[test]
+    fn a_tenant_keeps_the_topic_inside_its_prefix() {
+        assert_eq!(tenant_topic(None, "").unwrap(), "");
+        assert_eq!(tenant_topic(None, "site1/").unwrap(), "site1/");
+        assert_eq!(tenant_topic(Some("acme"), "").unwrap(), "acme/");
+        assert_eq!(
+            tenant_topic(Some("acme"), "acme/feeder-3").unwrap(),
+            "acme/feeder-3"
+        );
+        let err = tenant_topic(Some("acme"), "site1/").unwrap_err();
+        assert!(err.to_string().contains("outside tenant acme"), "{err}");
+        assert!(tenant_topic(Some("acme"), "acmesite/").is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Stopping on SIGTERM and SIGINT, shared by the services so that a container restart lets
+//! them finish what they were doing instead of being killed mid-write.
+//!
+//! The first signal only requests shutdown: each service notices through `Shutdown`, stops
+//! taking new work, flushes what it holds and returns from `main`. A second SIGINT exits
+//! immediately, for when flushing hangs; further SIGTERMs are ignored, as Kubernetes follows
+//! up with SIGKILL once the grace period is over.
+
+use std::future::Future;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Exit status after a second SIGINT cut the shutdown short, as a shell reports SIGINT.
+const FORCED_EXIT_STATUS: i32 = 130;
+
+/// Cloneable view of whether shutdown has been requested.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Starts listening for SIGTERM and SIGINT. Call once, from inside the runtime.
+    pub fn install() -> std::io::Result<Self> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let (sender, receiver) = watch::channel(false);
+
+        tokio::spawn(async move {
+            let name = tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+            };
+            log::info!("Received {name}, shutting down");
+            sender.send_replace(true);
+
+            loop {
+                tokio::select! {
+                    _ = terminate.recv() => log::info!("Received SIGTERM, already shutting down"),
+                    _ = interrupt.recv() => break,
+                }
+            }
+            log::warn!("Received SIGINT again, exiting without finishing the shutdown");
+            std::process::exit(FORCED_EXIT_STATUS);
+        });
+        Ok(Self(receiver))
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown has been requested. Cancel safe, and independent of `self`, so
+    /// it can be handed to `axum::serve(..).with_graceful_shutdown`.
+    pub fn requested(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut receiver = self.0.clone();
+        async move {
+            if receiver.wait_for(|requested| *requested).await.is_err() {
+                // The signal task is gone without requesting shutdown; nothing will ever come
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! The site total: a virtual stream holding the real, reactive and apparent power of a site's
+//! feeder streams summed per frame and phase, since nearly every dashboard starts from total
+//! site load. data-db stores it as one more stream in each row, and the exporter exports it
+//! under the same gauges as any other stream.
+//!
+//! Its members are the configured feeder streams, or every stream in the frame when none are
+//! configured. A member missing from a frame is counted by the services; the total is then
+//! either the sum of the members that are there or left out of that frame. A member that sent
+//! a phase without its power adds nothing to that phase. Phase C is only summed when some
+//! member is a three-phase meter.
+
+use std::collections::BTreeSet;
+
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeCalculations, CompositeTwoPhaseCalculations, Provenance,
+};
+
+/// What happens to the total of a frame that is missing members.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MissingMembers {
+    /// Sum the members that are there
+    #[default]
+    Partial,
+    /// Leave the total out of the frame
+    Skip,
+}
+
+#[derive(Clone, Debug)]
+pub struct SiteTotalConfig {
+    /// Name of the virtual stream
+    pub stream: String,
+    /// Streams summed; empty for every stream in the frame
+    pub members: BTreeSet<String>,
+    pub on_missing: MissingMembers,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Power {
+    pub real: f64,
+    pub reactive: f64,
+    pub apparent: f64,
+}
+
+impl Power {
+    fn add(&mut self, calcs: Option<CompositeCalculations>) {
+        let Some(power) = calcs.and_then(|calcs| calcs.power_calculations) else {
+            return;
+        };
+        self.real += power.real_power_w.unwrap_or_default() as f64;
+        self.reactive += power.reactive_power_var.unwrap_or_default() as f64;
+        self.apparent += power.apparent_power_va.unwrap_or_default() as f64;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Total {
+    pub phase_a: Power,
+    pub phase_b: Power,
+    /// None unless a member sent phase C
+    pub phase_c: Option<Power>,
+    /// The first member's, so the total is timestamped like the frame it came from
+    pub provenance: Option<Provenance>,
+    /// Configured members that weren't in the frame
+    pub missing: Vec<String>,
+}
+
+impl SiteTotalConfig {
+    /// Sums one frame's members, given as (stream, calculations). None when none of them
+    /// is there.
+    pub fn sum<'a>(
+        &self,
+        streams: impl IntoIterator<Item = (&'a str, &'a CompositeTwoPhaseCalculations)>,
+    ) -> Option<Total> {
+        let mut total = Total {
+            phase_a: Power::default(),
+            phase_b: Power::default(),
+            phase_c: None,
+            provenance: None,
+            missing: Vec::new(),
+        };
+        let mut seen = BTreeSet::new();
+        for (stream, calcs) in streams {
+            let member = if self.members.is_empty() {
+                stream != self.stream
+            } else {
+                self.members.contains(stream)
+            };
+            if !member || !seen.insert(stream) {
+                continue;
+            }
+            total.phase_a.add(calcs.phase_a);
+            total.phase_b.add(calcs.phase_b);
+            if calcs.phase_c.is_some() {
+                total.phase_c.get_or_insert_default().add(calcs.phase_c);
+            }
+            if total.provenance.is_none() {
+                total.provenance = [calcs.phase_a, calcs.phase_b, calcs.phase_c]
+                    .into_iter()
+                    .flatten()
+                    .find_map(|phase| phase.provenance);
+            }
+        }
+        if seen.is_empty() {
+            return None;
+        }
+        total.missing = self
+            .members
+            .iter()
+            .filter(|member| !seen.contains(member.as_str()))
+            .cloned()
+            .collect();
+        Some(total)
+    }
+
+    /// Whether `total` goes out, given the members it is missing.
+    pub fn emits(&self, total: &Total) -> bool {
+        total.missing.is_empty() || self.on_missing == MissingMembers::Partial
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf_rs::utilidata::karman::bibimbap::v1::PowerCalculations;
+
+    use super::*;
+
+    fn calcs(real: f32) -> CompositeTwoPhaseCalculations {
+        let phase = CompositeCalculations {
+            power_calculations: Some(PowerCalculations {
+                real_power_w: Some(real),
+                reactive_power_var: Some(real / 10.0),
+                apparent_power_va: Some(real),
+                power_factor: Some(1.0),
+            }),
+            ..Default::default()
+        };
+        CompositeTwoPhaseCalculations {
+            phase_a: Some(phase),
+            phase_b: None,
+            phase_c: None,
+        }
+    }
+
+    fn config(members: &[&str], on_missing: MissingMembers) -> SiteTotalConfig {
+        SiteTotalConfig {
+            stream: "site/total".to_string(),
+            members: members.iter().map(|member| member.to_string()).collect(),
+            on_missing,
+        }
+    }
+
+    #[test]
+    fn sums_every_stream_but_itself_without_members() {
+        let (feeder1, feeder2, stale) = (calcs(100.0), calcs(250.0), calcs(1e6));
+        let frame = [
+            ("feeder1", &feeder1),
+            ("feeder2", &feeder2),
+            ("site/total", &stale),
+        ];
+
+        let total = config(&[], MissingMembers::Partial).sum(frame).unwrap();
+        assert_eq!(total.phase_a.real, 350.0);
+        assert_eq!(total.phase_a.reactive, 35.0);
+        assert_eq!(total.phase_b, Power::default());
+        assert_eq!(total.phase_c, None);
+        assert!(total.missing.is_empty());
+    }
+
+    #[test]
+    fn phase_c_is_summed_over_the_members_that_send_it() {
+        let two_phase = calcs(100.0);
+        let mut three_phase = calcs(250.0);
+        three_phase.phase_c = three_phase.phase_a;
+        let frame = [("feeder1", &two_phase), ("feeder2", &three_phase)];
+
+        let total = config(&[], MissingMembers::Partial).sum(frame).unwrap();
+        assert_eq!(total.phase_a.real, 350.0);
+        assert_eq!(total.phase_c.map(|power| power.real), Some(250.0));
+    }
+
+    #[test]
+    fn missing_members_are_reported_and_decide_whether_it_emits() {
+        let (feeder1, solar) = (calcs(100.0), calcs(40.0));
+        let frame = [("feeder1", &feeder1), ("solar", &solar)];
+
+        let partial = config(&["feeder1", "feeder2"], MissingMembers::Partial);
+        let total = partial.sum(frame).unwrap();
+        assert_eq!(total.phase_a.real, 100.0);
+        assert_eq!(total.missing, ["feeder2"]);
+        assert!(partial.emits(&total));
+
+        let skip = config(&["feeder1", "feeder2"], MissingMembers::Skip);
+        assert!(!skip.emits(&skip.sum(frame).unwrap()));
+        assert_eq!(skip.sum([("solar", &solar)]), None);
+    }
+}
@@ -0,0 +1,128 @@
+//! The layout of data-db's `data` column, chosen with its --json-schema-version. Every row records the
+//! version it was written in as `schema_version`, so queries keep working on a table that
+//! holds rows from both (rows from before versioning have none, and are version 1):
+//!
+//! - 1: each stream's entry keyed by its calculation name, as data-db has always written it.
+//!   A stream called `schema_version` would be shadowed by the version.
+//! - 2: `{"schema_version": 2, "streams": [{"stream": "threephase/feeder-1", ...}, ...]}`, one
+//!   flat entry per stream with its name as a field, so new fields can't collide with stream
+//!   names and streams unnest with `jsonb_array_elements(data->'streams')`.
+//!
+//! Everything up to data-db's writer works on the streams keyed by name; the layout is applied
+//! as rows are written. Readers get back to that with `streams` in Rust, or
+//! `streams_lateral` in SQL.
+
+use serde_json::{Map, Value};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchemaVersion {
+    /// Streams keyed by calculation name
+    #[default]
+    #[value(name = "1")]
+    V1,
+    /// A `streams` array of entries naming their stream
+    #[value(name = "2")]
+    V2,
+}
+
+impl SchemaVersion {
+    pub fn number(self) -> u64 {
+        match self {
+            SchemaVersion::V1 => 1,
+            SchemaVersion::V2 => 2,
+        }
+    }
+
+    /// The stored document for a row's `data`, which holds its streams keyed by name.
+    pub fn document(self, streams: &Value) -> Value {
+        let Value::Object(streams) = streams else {
+            return streams.clone();
+        };
+        let mut document = Map::new();
+        match self {
+            SchemaVersion::V1 => document.extend(streams.clone()),
+            SchemaVersion::V2 => {
+                let entries = streams
+                    .iter()
+                    .map(|(stream, entry)| {
+                        let mut entry = entry.clone();
+                        if let Value::Object(fields) = &mut entry {
+                            fields.insert("stream".to_string(), stream.clone().into());
+                        }
+                        entry
+                    })
+                    .collect();
+                document.insert("streams".to_string(), Value::Array(entries));
+            }
+        }
+        document.insert("schema_version".to_string(), self.number().into());
+        Value::Object(document)
+    }
+}
+
+/// A lateral join unnesting the streams of `{alias}.data` into `s(key, value)`, the stream's
+/// name and its entry, whichever version each row was written in.
+pub fn streams_lateral(alias: &str) -> String {
+    let version = format!("coalesce(({alias}.data->>'schema_version')::int, 1)");
+    format!(
+        "CROSS JOIN LATERAL (
+             SELECT key, value FROM jsonb_each(CASE WHEN {version} = 1 THEN {alias}.data END)
+             WHERE key <> 'schema_version'
+             UNION ALL
+             SELECT value->>'stream', value
+             FROM jsonb_array_elements(CASE WHEN {version} = 2 THEN {alias}.data->'streams' END)
+         ) AS s(key, value)"
+    )
+}
+
+/// The streams of a stored document keyed by name, whichever version it was written in: the
+/// reverse of `SchemaVersion::document`. Entries of a version 2 document without a stream
+/// name are left out.
+pub fn streams(document: Value) -> Map<String, Value> {
+    let Value::Object(mut document) = document else {
+        return Map::new();
+    };
+    let version = document
+        .remove("schema_version")
+        .and_then(|version| version.as_u64());
+    if version != Some(2) {
+        return document;
+    }
+    let Some(Value::Array(entries)) = document.remove("streams") else {
+        return Map::new();
+    };
+    entries
+        .into_iter()
+        .filter_map(|entry| {
+            let Value::Object(mut fields) = entry else {
+                return None;
+            };
+            match fields.remove("stream") {
+                Some(Value::String(stream)) => Some((stream, Value::Object(fields))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn either_version_reads_back_as_the_streams_it_was_written_from() {
+        let streams = json!({
+            "threephase/feeder-1": {"phase_a": {"rms_voltage": 120.0}},
+            "threephase/feeder-2": {"phase_a": {"rms_voltage": 121.0}},
+        });
+        for version in [SchemaVersion::V1, SchemaVersion::V2] {
+            let document = version.document(&streams);
+            assert_eq!(document["schema_version"], version.number());
+            assert_eq!(Value::Object(super::streams(document)), streams);
+        }
+        // Rows from before versioning
+        assert_eq!(Value::Object(super::streams(streams.clone())), streams);
+    }
+}
@@ -0,0 +1,230 @@
+//! Notices calculation names (streams) that have never been seen before and provisions them:
+//! a row in the `streams` registry table and, optionally, a POST to a webhook, so inventory
+//! systems and dashboards learn about new circuits without anyone telling them.
+//!
+//! data-db and the exporter both report what they see. With the registry table the webhook
+//! fires once per stream, for whichever service inserts the row first; without it, once per
+//! stream per process start.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{postgres::PgPoolOptions, Executor, Pool, Postgres};
+use tokio::sync::mpsc;
+
+/// The `streams` table and its key, also created by `data-db --init-schema`.
+pub const SCHEMA: &[&str] = &[TABLE, KEY];
+
+const TABLE: &str = "CREATE TABLE IF NOT EXISTS streams (
+  device        TEXT        NOT NULL,
+  tenant        TEXT,
+  stream        TEXT        NOT NULL,
+  first_seen    TIMESTAMPTZ NOT NULL,
+  first_seen_by TEXT        NOT NULL
+)";
+
+const KEY: &str =
+    "CREATE UNIQUE INDEX IF NOT EXISTS streams_key ON streams (device, (coalesce(tenant, '')), stream)";
+
+const WEBHOOK_ATTEMPTS: u32 = 4;
+
+#[derive(Clone, Debug, Default)]
+pub struct RegistrySettings {
+    /// Postgres holding the `streams` table, normally data-db's
+    pub database_url: Option<String>,
+    /// Receives a JSON `Discovered` for every new stream
+    pub webhook_url: Option<String>,
+    /// The `device` streams are registered under; data-db writes rows as `bibimbap`
+    pub device: String,
+    pub tenant: Option<String>,
+}
+
+/// The webhook payload.
+#[derive(Clone, Debug, Serialize)]
+pub struct Discovered {
+    pub device: String,
+    pub tenant: Option<String>,
+    pub stream: String,
+    pub first_seen: DateTime<Utc>,
+    /// Which service noticed it
+    pub first_seen_by: String,
+}
+
+/// Cheap to clone; `observe` only takes a lock and checks a set, the provisioning happens on
+/// a background task.
+#[derive(Clone)]
+pub struct Registry {
+    known: Arc<Mutex<HashSet<String>>>,
+    discovered: mpsc::UnboundedSender<String>,
+}
+
+impl Registry {
+    /// Creates the registry table if needed and loads the streams it already has. Returns None
+    /// when neither a database nor a webhook is configured.
+    pub async fn start(service: &str, settings: &RegistrySettings) -> Result<Option<Self>> {
+        if settings.database_url.is_none() && settings.webhook_url.is_none() {
+            return Ok(None);
+        }
+
+        let pool = match &settings.database_url {
+            Some(url) => Some(connect(url).await?),
+            None => None,
+        };
+        let known = match &pool {
+            Some(pool) => registered(pool, settings).await?,
+            None => HashSet::new(),
+        };
+        log::info!(
+            "{service}: {} streams already registered for {}",
+            known.len(),
+            settings.device
+        );
+
+        let known = Arc::new(Mutex::new(known));
+        let (discovered, receiver) = mpsc::unbounded_channel();
+        let worker = Worker {
+            service: service.to_string(),
+            settings: settings.clone(),
+            pool,
+            http: reqwest::Client::new(),
+            known: known.clone(),
+        };
+        tokio::spawn(worker.run(receiver));
+
+        Ok(Some(Self { known, discovered }))
+    }
+
+    /// Provisions `stream` if it hasn't been seen before.
+    pub fn observe(&self, stream: &str) {
+        let mut known = self.known.lock().unwrap();
+        if known.contains(stream) {
+            return;
+        }
+        known.insert(stream.to_string());
+        // Only fails once the worker has gone, and then there is nobody left to tell
+        self.discovered.send(stream.to_string()).ok();
+    }
+}
+
+async fn connect(url: &str) -> Result<Pool<Postgres>> {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(url)
+        .await
+        .context("Could not connect to the stream registry database")?;
+    // Service roles usually can't create tables, so only try when --init-schema hasn't
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass('streams') IS NOT NULL")
+        .fetch_one(&pool)
+        .await
+        .context("Could not look for the streams table")?;
+    if !exists {
+        for statement in SCHEMA {
+            pool.execute(*statement)
+                .await
+                .context("Could not create the streams table")?;
+        }
+    }
+    Ok(pool)
+}
+
+async fn registered(pool: &Pool<Postgres>, settings: &RegistrySettings) -> Result<HashSet<String>> {
+    let streams: Vec<String> = sqlx::query_scalar(
+        "SELECT stream FROM streams WHERE device = $1 AND tenant IS NOT DISTINCT FROM $2",
+    )
+    .bind(&settings.device)
+    .bind(&settings.tenant)
+    .fetch_all(pool)
+    .await
+    .context("Could not load registered streams")?;
+    Ok(streams.into_iter().collect())
+}
+
+struct Worker {
+    service: String,
+    settings: RegistrySettings,
+    pool: Option<Pool<Postgres>>,
+    http: reqwest::Client,
+    known: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Worker {
+    async fn run(self, mut discovered: mpsc::UnboundedReceiver<String>) {
+        while let Some(stream) = discovered.recv().await {
+            let event = Discovered {
+                device: self.settings.device.clone(),
+                tenant: self.settings.tenant.clone(),
+                stream,
+                first_seen: Utc::now(),
+                first_seen_by: self.service.clone(),
+            };
+            match self.register(&event).await {
+                Ok(true) => self.notify(&event).await,
+                Ok(false) => {
+                    log::debug!("{} was already registered by another service", event.stream)
+                }
+                Err(err) => {
+                    log::error!("Could not register stream {}: {err:#}", event.stream);
+                    // Forget it, so the next message on the stream tries again
+                    self.known.lock().unwrap().remove(&event.stream);
+                }
+            }
+        }
+    }
+
+    /// Whether this service is the first to see the stream.
+    async fn register(&self, event: &Discovered) -> Result<bool> {
+        let Some(pool) = &self.pool else {
+            return Ok(true);
+        };
+        let inserted = sqlx::query(
+            "INSERT INTO streams (device, tenant, stream, first_seen, first_seen_by) \
+                 VALUES ($1, $2, $3, $4, $5) \
+                 ON CONFLICT (device, (coalesce(tenant, '')), stream) DO NOTHING",
+        )
+        .bind(&event.device)
+        .bind(&event.tenant)
+        .bind(&event.stream)
+        .bind(event.first_seen)
+        .bind(&event.first_seen_by)
+        .execute(pool)
+        .await
+        .context("Could not register stream")?
+        .rows_affected();
+        Ok(inserted > 0)
+    }
+
+    /// Posts to the webhook, retrying with backoff. The stream is registered by now, so a
+    /// webhook that stays down is only logged.
+    async fn notify(&self, event: &Discovered) {
+        log::info!("New stream {} on {}", event.stream, event.device);
+        let Some(url) = &self.settings.webhook_url else {
+            return;
+        };
+
+        let mut backoff = Duration::from_secs(1);
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            let result = self
+                .http
+                .post(url)
+                .json(event)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            match result {
+                Ok(_) => return,
+                Err(err) if attempt < WEBHOOK_ATTEMPTS => {
+                    log::warn!("Webhook {url} failed (attempt {attempt}): {err}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) => {
+                    log::error!("Gave up telling {url} about {}: {err}", event.stream);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! ZeroMQ endpoints as given on the command line or in the environment. zeromq-rs speaks
+//! `tcp://HOST:PORT` and `ipc://PATH`, the latter for a publisher and subscribers on the same
+//! host, which skips the network stack. `inproc://` only reaches sockets in the same process
+//! and zeromq-rs has no such transport, so it is refused up front with a pointer to `ipc://`
+//! instead of failing later in `connect` or `bind`.
+
+use std::fmt;
+
+/// The transports the services can use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Ipc,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EndpointError {
+    /// No `scheme://`
+    NoScheme(String),
+    Inproc(String),
+    Unsupported {
+        endpoint: String,
+        scheme: String,
+    },
+    /// `tcp://` without `HOST:PORT`, or `ipc://` without a path
+    NoAddress(String),
+    BadPort(String),
+}
+
+impl fmt::Display for EndpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoScheme(endpoint) => write!(
+                f,
+                "'{endpoint}' has no transport, expected tcp://HOST:PORT or ipc://PATH"
+            ),
+            Self::Inproc(endpoint) => write!(
+                f,
+                "'{endpoint}': inproc:// only reaches sockets in the same process and isn't \
+                 supported; use ipc://PATH between processes on one host"
+            ),
+            Self::Unsupported { endpoint, scheme } => write!(
+                f,
+                "'{endpoint}': unsupported transport {scheme}://, expected tcp:// or ipc://"
+            ),
+            Self::NoAddress(endpoint) => write!(
+                f,
+                "'{endpoint}' has no address, expected tcp://HOST:PORT or ipc://PATH"
+            ),
+            Self::BadPort(endpoint) => write!(
+                f,
+                "'{endpoint}' needs a port number (or * when binding) after the host"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EndpointError {}
+
+/// Checks a full endpoint URI, returning its transport.
+pub fn endpoint_transport(endpoint: &str) -> Result<Transport, EndpointError> {
+    let Some((scheme, address)) = endpoint.split_once("://") else {
+        return Err(EndpointError::NoScheme(endpoint.to_string()));
+    };
+    match scheme {
+        "tcp" => {
+            let Some((host, port)) = address.rsplit_once(':') else {
+                return Err(EndpointError::NoAddress(endpoint.to_string()));
+            };
+            if host.is_empty() {
+                return Err(EndpointError::NoAddress(endpoint.to_string()));
+            }
+            if port != "*" && port.parse::<u16>().is_err() {
+                return Err(EndpointError::BadPort(endpoint.to_string()));
+            }
+            Ok(Transport::Tcp)
+        }
+        "ipc" if address.is_empty() => Err(EndpointError::NoAddress(endpoint.to_string())),
+        "ipc" => Ok(Transport::Ipc),
+        "inproc" => Err(EndpointError::Inproc(endpoint.to_string())),
+        _ => Err(EndpointError::Unsupported {
+            endpoint: endpoint.to_string(),
+            scheme: scheme.to_string(),
+        }),
+    }
+}
+
+/// `endpoint` if it is one the services can use, as a clap value parser.
+pub fn parse_endpoint(endpoint: &str) -> Result<String, EndpointError> {
+    endpoint_transport(endpoint)?;
+    Ok(endpoint.to_string())
+}
+
+/// Like `parse_endpoint`, also taking a bare `HOST:PORT` as TCP, for flags that predate full
+/// URIs.
+pub fn parse_endpoint_or_tcp(endpoint: &str) -> Result<String, EndpointError> {
+    if endpoint.contains("://") {
+        return parse_endpoint(endpoint);
+    }
+    parse_endpoint(&format!("tcp://{endpoint}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_and_ipc_are_accepted() {
+        assert_eq!(endpoint_transport("tcp://karman:5557"), Ok(Transport::Tcp));
+        assert_eq!(endpoint_transport("tcp://0.0.0.0:*"), Ok(Transport::Tcp));
+        assert_eq!(endpoint_transport("tcp://[::1]:5557"), Ok(Transport::Tcp));
+        assert_eq!(
+            endpoint_transport("ipc:///run/karman/feed.sock"),
+            Ok(Transport::Ipc)
+        );
+        assert_eq!(
+            parse_endpoint_or_tcp("127.0.0.1:5557").unwrap(),
+            "tcp://127.0.0.1:5557"
+        );
+        assert_eq!(
+            parse_endpoint_or_tcp("ipc:///tmp/feed").unwrap(),
+            "ipc:///tmp/feed"
+        );
+    }
+
+    #[test]
+    fn other_endpoints_are_refused_with_the_reason() {
+        let err = |endpoint| endpoint_transport(endpoint).unwrap_err();
+        assert!(matches!(err("inproc://feed"), EndpointError::Inproc(_)));
+        assert!(matches!(
+            err("udp://karman:5557"),
+            EndpointError::Unsupported { .. }
+        ));
+        assert!(matches!(err("karman:5557"), EndpointError::NoScheme(_)));
+        assert!(matches!(err("tcp://karman"), EndpointError::NoAddress(_)));
+        assert!(matches!(err("tcp://:5557"), EndpointError::NoAddress(_)));
+        assert!(matches!(
+            err("tcp://karman:http"),
+            EndpointError::BadPort(_)
+        ));
+        assert!(matches!(err("ipc://"), EndpointError::NoAddress(_)));
+        assert!(err("inproc://feed").to_string().contains("ipc://"));
+    }
+}
@@ -0,0 +1,341 @@
+//! Subscriber-side plumbing shared by the services that consume the calculation feed.
+//!
+//! zeromq-rs has no receive high-water mark: a subscriber that falls behind simply stops
+//! reading its TCP connection, and messages are then lost at the publisher where nobody can
+//! count them. `BufferedSubscriber` reads the socket on its own task into a queue bounded by
+//! a high-water mark, so a slow consumer drops messages here, where the queue depth and every
+//! drop are exported as metrics. The reader can run on a dedicated, pinned and prioritised
+//! thread; see `ReaderThread`.
+//!
+//! `SubscriberStream` puts the whole subscription together, from connecting to decoded
+//! frames, so every consumer strips topics and handles multipart messages the same way, and
+//! gets it back after it fails with the same backoff; see `reconnect_with_backoff`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use prometheus::core::Collector;
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntGauge};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+use zeromq::{Socket, SocketRecv, SubSocket, ZmqError, ZmqMessage, ZmqResult};
+
+pub use crate::endpoint::{
+    endpoint_transport, parse_endpoint, parse_endpoint_or_tcp, EndpointError, Transport,
+};
+pub use crate::reader_thread::{CpuSet, ReaderThread, ReaderThreadArgs};
+pub use crate::reconnect::{Backoff, ReconnectArgs};
+pub use crate::subscriber::{
+    split_topic, Frame, Rejected, SubscriberConfig, SubscriberStream, TopicError,
+};
+
+mod endpoint;
+mod reader_thread;
+mod reconnect;
+mod subscriber;
+
+/// Which message gives way when the queue is at its high-water mark.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// Discard the message just received, as a ZeroMQ socket at its HWM does
+    #[default]
+    DropNewest,
+    /// Discard the oldest queued message, favouring fresh data over complete data
+    DropOldest,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HwmConfig {
+    /// Messages queued before the overflow policy applies; 0 means unbounded, as for
+    /// ZMQ_RCVHWM
+    pub hwm: usize,
+    pub on_full: OverflowPolicy,
+}
+
+/// Receive-queue metrics. Register once per process and share with every subscriber.
+pub struct ReceiveMetrics {
+    pub received: IntCounter,
+    pub dropped: IntCounter,
+    pub rejected: IntCounter,
+    /// The rejected messages whose payload was not a frame
+    pub decode_failures: IntCounter,
+    /// The rejected messages without a payload behind their topic
+    pub frameless: IntCounter,
+    /// The rejected messages whose topic was not the subscribed one
+    pub topic_mismatches: IntCounter,
+    pub reconnect_attempts: IntCounter,
+    pub queue_depth: IntGauge,
+    pub hwm: IntGauge,
+    pub message_size: Histogram,
+    pub decode_duration: Histogram,
+}
+
+impl ReceiveMetrics {
+    /// Registers the metrics with the default registry, names prefixed with `prefix` (e.g.
+    /// `data_db_`).
+    pub fn register(prefix: &str) -> prometheus::Result<Self> {
+        Self::register_with(prometheus::default_registry(), prefix)
+    }
+
+    /// Like `register`, with `registry` instead of the default one.
+    pub fn register_with(
+        registry: &prometheus::Registry,
+        prefix: &str,
+    ) -> prometheus::Result<Self> {
+        let received = IntCounter::new(
+            format!("{prefix}zmq_received_messages_total"),
+            "Messages read from the ZeroMQ subscription",
+        )?;
+        let dropped = IntCounter::new(
+            format!("{prefix}zmq_dropped_messages_total"),
+            "Messages discarded because the receive queue was at its high-water mark",
+        )?;
+        let rejected = IntCounter::new(
+            format!("{prefix}zmq_rejected_messages_total"),
+            "Messages skipped because they were not a topic and a decodable frame",
+        )?;
+        let decode_failures = IntCounter::new(
+            format!("{prefix}zmq_decode_failures_total"),
+            "Messages rejected because their payload did not decode as a frame",
+        )?;
+        let frameless = IntCounter::new(
+            format!("{prefix}zmq_frameless_messages_total"),
+            "Messages rejected because they had no payload after the topic, or too many parts",
+        )?;
+        let topic_mismatches = IntCounter::new(
+            format!("{prefix}zmq_topic_mismatches_total"),
+            "Messages rejected because they did not start with the subscribed topic",
+        )?;
+        let reconnect_attempts = IntCounter::new(
+            format!("{prefix}zmq_reconnect_attempts_total"),
+            "Attempts to reconnect to the publisher after the subscription failed",
+        )?;
+        let message_size = Histogram::with_opts(
+            HistogramOpts::new(
+                format!("{prefix}zmq_message_size_bytes"),
+                "Size of each message's payload, without its topic",
+            )
+            .buckets(prometheus::exponential_buckets(64.0, 2.0, 14)?),
+        )?;
+        let decode_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                format!("{prefix}zmq_decode_duration_seconds"),
+                "Time taken to decode each message's protobuf payload",
+            )
+            .buckets(prometheus::exponential_buckets(1e-6, 4.0, 10)?),
+        )?;
+        let queue_depth = IntGauge::new(
+            format!("{prefix}zmq_receive_queue_depth"),
+            "Messages received but not yet processed",
+        )?;
+        let hwm = IntGauge::new(
+            format!("{prefix}zmq_receive_hwm"),
+            "Receive queue high-water mark (0 for unbounded)",
+        )?;
+        registry.register(Box::new(received.clone()))?;
+        registry.register(Box::new(dropped.clone()))?;
+        registry.register(Box::new(rejected.clone()))?;
+        registry.register(Box::new(decode_failures.clone()))?;
+        registry.register(Box::new(frameless.clone()))?;
+        registry.register(Box::new(topic_mismatches.clone()))?;
+        registry.register(Box::new(reconnect_attempts.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(hwm.clone()))?;
+        registry.register(Box::new(message_size.clone()))?;
+        registry.register(Box::new(decode_duration.clone()))?;
+        Ok(Self {
+            received,
+            dropped,
+            rejected,
+            decode_failures,
+            frameless,
+            topic_mismatches,
+            reconnect_attempts,
+            queue_depth,
+            hwm,
+            message_size,
+            decode_duration,
+        })
+    }
+
+    /// For describing the metrics, e.g. in a service's metric catalog.
+    pub fn collectors(&self) -> [&dyn Collector; 11] {
+        [
+            &self.received,
+            &self.dropped,
+            &self.rejected,
+            &self.decode_failures,
+            &self.frameless,
+            &self.topic_mismatches,
+            &self.reconnect_attempts,
+            &self.queue_depth,
+            &self.hwm,
+            &self.message_size,
+            &self.decode_duration,
+        ]
+    }
+}
+
+struct Shared {
+    queue: Mutex<VecDeque<ZmqMessage>>,
+    /// Set once the socket fails; handed to the consumer after the queue drains
+    error: Mutex<Option<ZmqError>>,
+    ready: Notify,
+    /// Tells the reader to close the socket and stop
+    stop: Notify,
+}
+
+/// A subscription read ahead into a bounded queue.
+pub struct BufferedSubscriber {
+    shared: Arc<Shared>,
+    metrics: &'static ReceiveMetrics,
+    reader: JoinHandle<()>,
+}
+
+impl BufferedSubscriber {
+    /// Starts reading `socket`, which should already be connected and subscribed.
+    pub fn new(socket: SubSocket, config: HwmConfig, metrics: &'static ReceiveMetrics) -> Self {
+        let shared = Self::shared(config, metrics);
+        let reader = tokio::spawn(read(socket, shared.clone(), config, metrics));
+        Self {
+            shared,
+            metrics,
+            reader,
+        }
+    }
+
+    /// Like `new`, but reads on a thread of its own, with its own single-threaded runtime,
+    /// when `thread` pins or prioritises it. Errors if the thread can't be started.
+    pub fn with_reader_thread(
+        socket: SubSocket,
+        config: HwmConfig,
+        thread: &ReaderThread,
+        metrics: &'static ReceiveMetrics,
+    ) -> std::io::Result<Self> {
+        if !thread.is_dedicated() {
+            return Ok(Self::new(socket, config, metrics));
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let shared = Self::shared(config, metrics);
+        // Dropped when the reader returns or is aborted, which ends the thread
+        let (running, stopped) = oneshot::channel::<()>();
+        let reader = runtime.spawn({
+            let shared = shared.clone();
+            async move {
+                read(socket, shared, config, metrics).await;
+                drop(running);
+            }
+        });
+        let thread = thread.clone();
+        std::thread::Builder::new()
+            .name("zmq-reader".to_string())
+            .spawn(move || {
+                thread.apply();
+                runtime.block_on(async {
+                    let _ = stopped.await;
+                });
+            })?;
+        Ok(Self {
+            shared,
+            metrics,
+            reader,
+        })
+    }
+
+    fn shared(config: HwmConfig, metrics: &'static ReceiveMetrics) -> Arc<Shared> {
+        metrics.hwm.set(config.hwm as i64);
+        metrics.queue_depth.set(0);
+        Arc::new(Shared {
+            queue: Mutex::new(VecDeque::new()),
+            error: Mutex::new(None),
+            ready: Notify::new(),
+            stop: Notify::new(),
+        })
+    }
+
+    /// The next queued message, or the error that stopped the socket once every message
+    /// before it has been handed out. Cancel safe.
+    pub async fn recv(&mut self) -> ZmqResult<ZmqMessage> {
+        loop {
+            if let Some(message) = self.shared.queue.lock().unwrap().pop_front() {
+                self.metrics.queue_depth.dec();
+                return Ok(message);
+            }
+            if let Some(err) = self.shared.error.lock().unwrap().take() {
+                return Err(err);
+            }
+            if self.reader.is_finished() {
+                return Err(ZmqError::NoMessage);
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+
+    /// Stops reading and closes the socket, disconnecting from the publisher. Messages still
+    /// queued are discarded.
+    pub async fn close(mut self) {
+        self.shared.stop.notify_one();
+        if let Err(err) = (&mut self.reader).await {
+            log::warn!("Subscription reader failed while closing: {err}");
+        }
+    }
+}
+
+impl Drop for BufferedSubscriber {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read(
+    mut socket: SubSocket,
+    shared: Arc<Shared>,
+    config: HwmConfig,
+    metrics: &'static ReceiveMetrics,
+) {
+    loop {
+        let received = tokio::select! {
+            received = socket.recv() => received,
+            _ = shared.stop.notified() => {
+                for err in socket.close().await {
+                    log::warn!("Error closing subscription: {err}");
+                }
+                return;
+            }
+        };
+        let message = match received {
+            Ok(message) => message,
+            Err(err) => {
+                *shared.error.lock().unwrap() = Some(err);
+                shared.ready.notify_one();
+                return;
+            }
+        };
+        metrics.received.inc();
+
+        {
+            let mut queue = shared.queue.lock().unwrap();
+            if config.hwm > 0 && queue.len() >= config.hwm {
+                metrics.dropped.inc();
+                if metrics.dropped.get() == 1 {
+                    log::warn!(
+                        "Receive queue reached its high-water mark of {}, dropping messages",
+                        config.hwm
+                    );
+                }
+                match config.on_full {
+                    OverflowPolicy::DropNewest => continue,
+                    OverflowPolicy::DropOldest => {
+                        queue.pop_front();
+                        metrics.queue_depth.dec();
+                    }
+                }
+            }
+            queue.push_back(message);
+            metrics.queue_depth.inc();
+        }
+        shared.ready.notify_one();
+    }
+}
@@ -0,0 +1,134 @@
+//! Scheduling for the thread that reads the subscription. By default the reader is a task on
+//! the service's runtime, competing with database writes and HTTP handlers; on an edge box that
+//! shares its CPUs with the measurement stack it can instead get a thread of its own, pinned to
+//! chosen CPUs and/or run under SCHED_FIFO, so bursts elsewhere don't back up the socket.
+//!
+//! The runtime's I/O driver still delivers readiness for the socket; the pinned thread is where
+//! frames are read, decoded and queued.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct ReaderThreadArgs {
+    /// Read the subscription on a dedicated thread running SCHED_FIFO at this priority (1-99),
+    /// ahead of everything else on its CPUs. Needs CAP_SYS_NICE; without it a warning is logged.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=99))]
+    pub zmq_reader_priority: Option<u8>,
+    /// Read the subscription on a dedicated thread pinned to these CPUs, e.g. 3 or 2-3
+    #[arg(long)]
+    pub zmq_reader_cpus: Option<CpuSet>,
+}
+
+impl ReaderThreadArgs {
+    pub fn reader_thread(&self) -> ReaderThread {
+        ReaderThread {
+            realtime_priority: self.zmq_reader_priority,
+            cpus: self.zmq_reader_cpus.clone(),
+        }
+    }
+}
+
+/// CPUs a thread may run on, written as a list of numbers and ranges, e.g. `2,3` or `2-3`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuSet(Vec<usize>);
+
+impl FromStr for CpuSet {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid CPU list '{value}', expected e.g. 2,3 or 2-3");
+        let mut cpus = Vec::new();
+        for part in value.split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (first, last),
+                None => (part, part),
+            };
+            let first: usize = first.trim().parse().map_err(|_| invalid())?;
+            let last: usize = last.trim().parse().map_err(|_| invalid())?;
+            if first > last {
+                return Err(invalid());
+            }
+            cpus.extend(first..=last);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        if let Some(&cpu) = cpus.iter().find(|&&cpu| cpu >= libc::CPU_SETSIZE as usize) {
+            return Err(format!(
+                "CPU {cpu} is beyond what the kernel interface can address"
+            ));
+        }
+        Ok(Self(cpus))
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpus: Vec<String> = self.0.iter().map(usize::to_string).collect();
+        f.write_str(&cpus.join(","))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReaderThread {
+    /// SCHED_FIFO priority, 1 (lowest) to 99
+    pub realtime_priority: Option<u8>,
+    pub cpus: Option<CpuSet>,
+}
+
+impl ReaderThread {
+    /// Whether the reader needs a thread of its own.
+    pub(crate) fn is_dedicated(&self) -> bool {
+        self.realtime_priority.is_some() || self.cpus.is_some()
+    }
+
+    /// Applies the settings to the calling thread. Failing to is logged rather than fatal: the
+    /// service still works, only without the guarantees, e.g. when the container lacks
+    /// CAP_SYS_NICE.
+    pub(crate) fn apply(&self) {
+        if let Some(cpus) = &self.cpus {
+            match set_affinity(&cpus.0) {
+                Ok(()) => log::info!("Subscription reader pinned to CPUs {cpus}"),
+                Err(err) => {
+                    log::warn!("Could not pin the subscription reader to CPUs {cpus}: {err}")
+                }
+            }
+        }
+        if let Some(priority) = self.realtime_priority {
+            match set_fifo_priority(priority) {
+                Ok(()) => {
+                    log::info!("Subscription reader running SCHED_FIFO at priority {priority}")
+                }
+                Err(err) => log::warn!(
+                    "Could not run the subscription reader SCHED_FIFO at priority {priority} \
+                     (it needs CAP_SYS_NICE): {err}"
+                ),
+            }
+        }
+    }
+}
+
+fn set_affinity(cpus: &[usize]) -> std::io::Result<()> {
+    // SAFETY: cpu_set_t is plain data, and pid 0 is the calling thread
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn set_fifo_priority(priority: u8) -> std::io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority.into(),
+    };
+    // SAFETY: pid 0 is the calling thread
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! Getting the subscription back after it failed: a randomised exponential backoff between
+//! attempts, so that instances which lost the same publisher don't all dial it at the same
+//! moment, and the flags that configure it.
+
+use std::time::Duration;
+
+use rand::Rng;
+use shutdown::Shutdown;
+
+use crate::SubscriberStream;
+
+/// Delays between attempts to reconnect to a peer after losing it.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    /// Ceiling for the first attempt, doubled after every failed one
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// How long to wait before attempt `attempt` (counting from 0): somewhere between half
+    /// and all of its ceiling, so instances that lost the same peer don't all dial it at the
+    /// same moment.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[derive(Clone, Copy, Debug, clap::Args)]
+pub struct ReconnectArgs {
+    /// Longest wait before the first attempt to reconnect after the subscription failed,
+    /// doubled after every failed attempt. Each wait is randomly shortened by up to half.
+    #[arg(long, default_value = "500ms", value_parser = humantime::parse_duration)]
+    pub zmq_reconnect_backoff: Duration,
+    /// Cap on the wait between reconnect attempts
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    pub zmq_reconnect_max_backoff: Duration,
+}
+
+impl ReconnectArgs {
+    pub fn backoff(&self) -> Backoff {
+        Backoff {
+            initial: self.zmq_reconnect_backoff,
+            max: self.zmq_reconnect_max_backoff,
+        }
+    }
+}
+
+impl SubscriberStream {
+    /// Dials the publisher again after `next` failed, backing off between attempts until one
+    /// succeeds (`true`) or shutdown is requested (`false`).
+    pub async fn reconnect_with_backoff(&mut self, backoff: Backoff, shutdown: &Shutdown) -> bool {
+        let mut attempt = 0;
+        loop {
+            let delay = backoff.delay(attempt);
+            log::warn!("Reconnecting to the publisher in {delay:?}");
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.requested() => return false,
+            }
+
+            let reconnected = tokio::select! {
+                reconnected = self.reconnect() => reconnected,
+                _ = shutdown.requested() => return false,
+            };
+            match reconnected {
+                Ok(()) => {
+                    log::info!(
+                        "Reconnected to the publisher after {} attempts",
+                        attempt + 1
+                    );
+                    return true;
+                }
+                Err(err) => log::error!("Could not reconnect to the publisher: {err}"),
+            }
+            attempt = attempt.saturating_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_cap_and_are_shortened_by_at_most_half() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        };
+        for (attempt, ceiling) in [(0, 500), (1, 1000), (3, 4000), (6, 30_000), (40, 30_000)] {
+            let ceiling = Duration::from_millis(ceiling);
+            let delay = backoff.delay(attempt);
+            assert!(
+                ceiling / 2 <= delay && delay <= ceiling,
+                "{attempt}: {delay:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! The calculation feed as decoded frames: connecting and subscribing, the read-ahead queue,
+//! taking the topic off each message and decoding what is left.
+//!
+//! Publishers send a frame either as a single ZeroMQ frame, the topic followed directly by
+//! the protobuf payload, or as a two-part message with the topic in a frame of its own. Both
+//! are accepted, once the topic is checked against the subscribed one; anything else is
+//! counted as rejected and skipped rather than handed to prost. `split_topic` does the same
+//! for services that read their socket themselves.
+
+use std::fmt;
+use std::time::{Instant, SystemTime};
+
+use prost::Message;
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeJoinedCalculations;
+use tracing::Span;
+use zeromq::{Socket, SubSocket, ZmqMessage, ZmqResult};
+
+use crate::{BufferedSubscriber, HwmConfig, ReaderThread, ReceiveMetrics};
+
+#[derive(Clone, Debug)]
+pub struct SubscriberConfig {
+    /// e.g. `tcp://127.0.0.1:5555`
+    pub endpoint: String,
+    /// Topic prefix to subscribe to; empty for every message
+    pub topic: String,
+    pub hwm: HwmConfig,
+    pub reader_thread: ReaderThread,
+}
+
+/// One decoded message and when it was handed out.
+pub struct Frame {
+    pub joined: CompositeJoinedCalculations,
+    pub received: SystemTime,
+    /// The whole topic, when it came in a frame of its own; a single-frame message doesn't
+    /// say where its topic ends
+    pub topic: Option<String>,
+    /// The `frame` span it was decoded in, named after it
+    pub span: Span,
+}
+
+/// A message `next` skipped, as handed to `on_rejected`.
+pub struct Rejected {
+    /// The message's parts joined back together, topic first, as a single-frame publisher
+    /// would have sent it
+    pub message: Vec<u8>,
+    pub received: SystemTime,
+    pub reason: String,
+}
+
+/// A subscription to the calculation feed that yields decoded frames.
+pub struct SubscriberStream {
+    config: SubscriberConfig,
+    metrics: &'static ReceiveMetrics,
+    subscription: BufferedSubscriber,
+    on_rejected: Option<Box<dyn FnMut(Rejected) + Send>>,
+}
+
+impl SubscriberStream {
+    /// Connects and subscribes. Connecting waits for the publisher to come up.
+    pub async fn connect(
+        config: SubscriberConfig,
+        metrics: &'static ReceiveMetrics,
+    ) -> ZmqResult<Self> {
+        let subscription = subscribe(&config, metrics).await?;
+        Ok(Self {
+            config,
+            metrics,
+            subscription,
+            on_rejected: None,
+        })
+    }
+
+    /// Hands every message `next` skips from now on to `handler`, e.g. for a dead-letter
+    /// queue. It runs inside `next`, so it should be quick.
+    pub fn on_rejected(&mut self, handler: impl FnMut(Rejected) + Send + 'static) {
+        self.on_rejected = Some(Box::new(handler));
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.config.topic
+    }
+
+    /// The next frame that decodes, or the error that stopped the socket. Cancel safe.
+    pub async fn next(&mut self) -> ZmqResult<Frame> {
+        loop {
+            let message = self.subscription.recv().await?;
+            let received = SystemTime::now();
+            let payload = match split_topic(&message, self.config.topic.as_bytes()) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    self.metrics.rejected.inc();
+                    match err {
+                        TopicError::Mismatch => self.metrics.topic_mismatches.inc(),
+                        TopicError::Parts(_) => self.metrics.frameless.inc(),
+                    }
+                    log::error!("Could not decode incoming message: {err}");
+                    self.reject(&message, received, err.to_string());
+                    continue;
+                }
+            };
+            self.metrics.message_size.observe(payload.len() as f64);
+            let span = logging::undecoded_frame_span();
+            let started = Instant::now();
+            let decoded = tracing::info_span!(parent: &span, "decode")
+                .in_scope(|| CompositeJoinedCalculations::decode(payload));
+            self.metrics
+                .decode_duration
+                .observe(started.elapsed().as_secs_f64());
+            match decoded {
+                Ok(joined) => {
+                    logging::record_frame(&span, &joined);
+                    return Ok(Frame {
+                        joined,
+                        received,
+                        topic: topic(&message),
+                        span,
+                    });
+                }
+                Err(err) => {
+                    self.metrics.rejected.inc();
+                    self.metrics.decode_failures.inc();
+                    log::error!("Could not decode incoming message: {err}");
+                    self.reject(&message, received, err.to_string());
+                }
+            }
+        }
+    }
+
+    fn reject(&mut self, message: &ZmqMessage, received: SystemTime, reason: String) {
+        if let Some(handler) = &mut self.on_rejected {
+            handler(Rejected {
+                message: message
+                    .iter()
+                    .flat_map(|frame| frame.iter().copied())
+                    .collect(),
+                received,
+                reason,
+            });
+        }
+    }
+
+    /// Dials the publisher again with a fresh socket, e.g. after `next` failed. Messages still
+    /// queued from the old connection are discarded. See also `reconnect_with_backoff`.
+    pub async fn reconnect(&mut self) -> ZmqResult<()> {
+        self.metrics.reconnect_attempts.inc();
+        let subscription = subscribe(&self.config, self.metrics).await?;
+        std::mem::replace(&mut self.subscription, subscription)
+            .close()
+            .await;
+        Ok(())
+    }
+
+    /// Closes the socket, disconnecting from the publisher.
+    pub async fn close(self) {
+        self.subscription.close().await;
+    }
+}
+
+async fn subscribe(
+    config: &SubscriberConfig,
+    metrics: &'static ReceiveMetrics,
+) -> ZmqResult<BufferedSubscriber> {
+    let mut socket = SubSocket::new();
+    log::info!("Connecting to {}", config.endpoint);
+    socket.connect(&config.endpoint).await?;
+    socket.subscribe(&config.topic).await?;
+    log::info!(
+        "Connected to {}, subscribed to topic '{}'",
+        config.endpoint,
+        config.topic
+    );
+    Ok(BufferedSubscriber::with_reader_thread(
+        socket,
+        config.hwm,
+        &config.reader_thread,
+        metrics,
+    )?)
+}
+
+/// Why a message has no payload to decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopicError {
+    /// It doesn't start with the subscribed topic, so where the payload starts is unknown
+    Mismatch,
+    /// Neither one frame nor a topic frame and a payload
+    Parts(usize),
+}
+
+impl TopicError {
+    /// For a metric label.
+    pub fn label(self) -> &'static str {
+        match self {
+            TopicError::Mismatch => "topic_mismatch",
+            TopicError::Parts(_) => "frameless",
+        }
+    }
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicError::Mismatch => f.write_str("message does not start with the subscribed topic"),
+            TopicError::Parts(frames) => write!(f, "expected 1 or 2 frames, got {frames}"),
+        }
+    }
+}
+
+impl std::error::Error for TopicError {}
+
+/// The protobuf payload of `message`, without its topic: what follows `topic` in a
+/// single-frame message, or the second frame of a two-part one whose first starts with
+/// `topic`.
+pub fn split_topic<'a>(message: &'a ZmqMessage, topic: &[u8]) -> Result<&'a [u8], TopicError> {
+    match message.len() {
+        1 => {
+            let frame = message.get(0).expect("a frame");
+            frame.strip_prefix(topic).ok_or(TopicError::Mismatch)
+        }
+        2 => {
+            let (envelope, frame) = (message.get(0).expect("a frame"), message.get(1));
+            if !envelope.starts_with(topic) {
+                return Err(TopicError::Mismatch);
+            }
+            Ok(&frame.expect("a second frame")[..])
+        }
+        frames => Err(TopicError::Parts(frames)),
+    }
+}
+
+/// The topic frame of a two-part message.
+fn topic(message: &ZmqMessage) -> Option<String> {
+    if message.len() != 2 {
+        return None;
+    }
+    let envelope = message.get(0)?;
+    Some(String::from_utf8_lossy(envelope).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(frames: &[&[u8]]) -> ZmqMessage {
+        let frames: Vec<bytes::Bytes> = frames
+            .iter()
+            .map(|frame| bytes::Bytes::copy_from_slice(frame))
+            .collect();
+        ZmqMessage::try_from(frames).unwrap()
+    }
+
+    #[test]
+    fn single_frame_has_its_topic_cut_off() {
+        let message = message(&[b"site1/payload"]);
+        assert_eq!(split_topic(&message, b"site1/").unwrap(), b"payload");
+        assert_eq!(split_topic(&message, b"").unwrap(), b"site1/payload");
+        assert!(split_topic(&message, b"site2/").is_err());
+    }
+
+    #[test]
+    fn topic_may_come_in_a_frame_of_its_own() {
+        let message = message(&[b"site1/", b"payload"]);
+        assert_eq!(split_topic(&message, b"site1/").unwrap(), b"payload");
+        assert_eq!(split_topic(&message, b"").unwrap(), b"payload");
+        assert!(split_topic(&message, b"site2/").is_err());
+    }
+
+    #[test]
+    fn only_a_topic_frame_tells_the_whole_topic() {
+        let two_part = message(&[b"site1/feeder-3", b"payload"]);
+        assert_eq!(topic(&two_part).as_deref(), Some("site1/feeder-3"));
+        assert_eq!(topic(&message(&[b"site1/feeder-3payload"])), None);
+    }
+
+    #[test]
+    fn a_short_frame_is_rejected_rather_than_sliced() {
+        assert_eq!(
+            split_topic(&message(&[b"site"]), b"site1/"),
+            Err(TopicError::Mismatch)
+        );
+        assert_eq!(
+            split_topic(&message(&[b"a", b"b", b"c"]), b""),
+            Err(TopicError::Parts(3))
+        );
+    }
+}
@@ -0,0 +1,28 @@
+use std::io::Result;
+
+const PROTOS: &[&str] = &[
+    "proto/utilidata/karman/bibimbap/v1/bibimbap.proto",
+    "proto/utilidata/karman/bibimbap/v1/stream.proto",
+];
+
+fn main() -> Result<()> {
+    // Use the vendored protoc so builds don't depend on a system install.
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("No vendored protoc for this host");
+        std::env::set_var("PROTOC", protoc);
+    }
+
+    for proto in PROTOS {
+        println!("cargo:rerun-if-changed={proto}");
+    }
+
+    // Services only get a client and server with the grpc feature; otherwise prost skips them
+    #[cfg(feature = "grpc")]
+    return tonic_prost_build::configure()
+        // Services pick their own transport features; connect with Channel yourself
+        .build_transport(false)
+        .compile_protos(PROTOS, &["proto"]);
+
+    #[cfg(not(feature = "grpc"))]
+    prost_build::Config::new().compile_protos(PROTOS, &["proto"])
+}
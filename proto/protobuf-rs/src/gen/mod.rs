@@ -1,13 +1,13 @@
-// @generated
 pub mod utilidata {
     pub mod karman {
         pub mod bibimbap {
             #[cfg(feature = "utilidata-karman-bibimbap-v1")]
-            // @@protoc_insertion_point(attribute:utilidata.karman.bibimbap.v1)
+            // DataProduct's variants are the messages themselves, as prost generates them;
+            // boxing Calculations would change every match on it
+            #[allow(clippy::large_enum_variant)]
             pub mod v1 {
-                include!("utilidata.karman.bibimbap.v1.rs");
-                // @@protoc_insertion_point(utilidata.karman.bibimbap.v1)
+                include!(concat!(env!("OUT_DIR"), "/utilidata.karman.bibimbap.v1.rs"));
             }
         }
     }
-}
\ No newline at end of file
+}
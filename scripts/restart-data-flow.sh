@@ -76,7 +76,7 @@ echo ""
 
 echo "✅ Data flow restarted successfully!"
 echo ""
-echo "⏳ Data will publish in ~75 seconds (waiting for subscribers to connect)..."
+echo "⏳ Data will publish once subscribers connect (up to ~75 seconds by default)..."
 echo "📊 Then data will flow for a few minutes (varies by scenario)."
 echo ""
 echo "Available scenarios:"
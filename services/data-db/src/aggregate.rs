@@ -0,0 +1,369 @@
+//! `--aggregate-interval`: one row per device and interval instead of one per frame. Each
+//! value in a stream's entry is replaced by its mean over the interval, under its usual key so
+//! queries, the storage manager and the daily reports read aggregated rows like full-rate ones,
+//! with `<key>_min` and `<key>_max` next to it. Flags such as `trusted` only stay true if they
+//! were in every frame, text keeps its latest value, and each stream gets `samples`, the number
+//! of frames it was in. bibimbap_measurements only has room for the means.
+//!
+//! Rows are stamped with the start of their interval, counted from the Unix epoch. An interval
+//! is written once a row from past its end (and --assembly-window, since assembled frames are
+//! stamped with when their first fragment arrived) comes in, and on shutdown. Without the
+//! durable queue it is also written once that much time has passed without one; with it,
+//! frames are only committed once their interval has been written, so after a restart or a
+//! failed write the interval is summarized from the start.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::{Map, Value};
+
+use crate::metrics::AGGREGATED_ROWS;
+use crate::writer::{Measurement, Row};
+
+#[derive(Clone, Copy, Debug)]
+pub struct AggregateConfig {
+    pub interval: Duration,
+    /// How long after an interval ends its rows may still come in
+    pub settle: Duration,
+}
+
+/// The intervals still open, by device.
+pub struct Aggregator {
+    interval: TimeDelta,
+    settle: TimeDelta,
+    open: HashMap<String, Interval>,
+}
+
+struct Interval {
+    start: DateTime<Utc>,
+    /// Durable queue offset of the interval's first frame
+    offset: u64,
+    tenant: Option<String>,
+    rows: u64,
+    streams: BTreeMap<String, Summary>,
+    samples: BTreeMap<String, u64>,
+    measurements: BTreeMap<(String, &'static str), [Mean; MEASUREMENT_VALUES]>,
+}
+
+impl Aggregator {
+    pub fn new(config: AggregateConfig) -> Self {
+        let delta = |duration| TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX);
+        Self {
+            interval: delta(config.interval).max(TimeDelta::milliseconds(1)),
+            settle: delta(config.settle),
+            open: HashMap::new(),
+        }
+    }
+
+    /// Adds a row to its device's interval, returning the intervals it closes. `offset` is
+    /// where the row's frame starts in the durable queue, 0 without one.
+    pub fn push(&mut self, row: Row, offset: u64) -> Vec<Row> {
+        let mut closed = self.expire(row.time);
+        let start = self.start_of(row.time);
+        if let Some(interval) = self.open.get(&row.device)
+            && interval.start != start
+        {
+            // The device's previous interval, or rows stamped earlier than the open one
+            // (re-read from the durable queue), which get an interval of their own
+            let previous = self.open.remove(&row.device).expect("open interval");
+            closed.push(previous.finish(&row.device));
+        }
+        self.open
+            .entry(row.device.clone())
+            .or_insert_with(|| Interval::new(start, offset, row.tenant.clone()))
+            .add(row);
+        closed
+    }
+
+    /// Closes the intervals whose rows have all come in by `now`.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<Row> {
+        let done: Vec<String> = self
+            .open
+            .iter()
+            .filter(|(_, interval)| interval.start + self.interval + self.settle <= now)
+            .map(|(device, _)| device.clone())
+            .collect();
+        self.close(done)
+    }
+
+    /// Closes every open interval, e.g. on shutdown.
+    pub fn drain(&mut self) -> Vec<Row> {
+        let devices = self.open.keys().cloned().collect();
+        self.close(devices)
+    }
+
+    /// Forgets the open intervals, whose frames the durable queue is about to read again.
+    pub fn clear(&mut self) {
+        self.open.clear();
+    }
+
+    /// How far the durable queue may be committed after reading up to `read`: not past the
+    /// first frame of an interval that hasn't been written yet.
+    pub fn committable(&self, read: u64) -> u64 {
+        self.open
+            .values()
+            .map(|interval| interval.offset)
+            .fold(read, u64::min)
+    }
+
+    fn close(&mut self, devices: Vec<String>) -> Vec<Row> {
+        let mut closed: Vec<Row> = devices
+            .into_iter()
+            .filter_map(|device| Some(self.open.remove(&device)?.finish(&device)))
+            .collect();
+        closed.sort_by_key(|row| row.time);
+        closed
+    }
+
+    fn start_of(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let interval = self.interval.num_nanoseconds().unwrap_or(i64::MAX);
+        let nanos = time.timestamp_nanos_opt().unwrap_or_default();
+        DateTime::from_timestamp_nanos(nanos - nanos.rem_euclid(interval))
+    }
+}
+
+impl Interval {
+    fn new(start: DateTime<Utc>, offset: u64, tenant: Option<String>) -> Self {
+        Self {
+            start,
+            offset,
+            tenant,
+            rows: 0,
+            streams: BTreeMap::new(),
+            samples: BTreeMap::new(),
+            measurements: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, row: Row) {
+        self.rows += 1;
+        if let Value::Object(streams) = &row.data {
+            for (stream, calculation) in streams {
+                *self.samples.entry(stream.clone()).or_default() += 1;
+                match self.streams.get_mut(stream) {
+                    Some(summary) => summary.add(calculation),
+                    None => {
+                        self.streams
+                            .insert(stream.clone(), Summary::of(calculation));
+                    }
+                }
+            }
+        }
+        for measurement in &row.measurements {
+            let means = self
+                .measurements
+                .entry((measurement.stream.clone(), measurement.phase))
+                .or_insert_with(|| [Mean::default(); MEASUREMENT_VALUES]);
+            for (mean, value) in means.iter_mut().zip(measurement_values(measurement)) {
+                mean.add(value);
+            }
+        }
+    }
+
+    fn finish(self, device: &str) -> Row {
+        AGGREGATED_ROWS.inc_by(self.rows);
+        let mut data = Map::new();
+        for (stream, summary) in self.streams {
+            let mut calculation = summary.finish();
+            if let Value::Object(calculation) = &mut calculation {
+                let samples = self.samples.get(&stream).copied().unwrap_or_default();
+                calculation.insert("samples".to_string(), samples.into());
+            }
+            data.insert(stream, calculation);
+        }
+        Row {
+            time: self.start,
+            device: device.to_string(),
+            tenant: self.tenant,
+            data: Value::Object(data),
+            measurements: self
+                .measurements
+                .into_iter()
+                .map(|((stream, phase), means)| {
+                    measurement(stream, phase, means.map(|mean| mean.value()))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// One value of a stream's entry over the interval.
+enum Summary {
+    Object(BTreeMap<String, Summary>),
+    Number {
+        min: f64,
+        max: f64,
+        sum: f64,
+        count: u64,
+    },
+    /// Whether it was true in every row
+    Flag(bool),
+    /// Text, and nulls until a number comes along
+    Latest(Value),
+}
+
+impl Summary {
+    fn of(value: &Value) -> Self {
+        match value {
+            Value::Object(fields) => Summary::Object(
+                fields
+                    .iter()
+                    .map(|(key, value)| (key.clone(), Summary::of(value)))
+                    .collect(),
+            ),
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                Summary::Number {
+                    min: number,
+                    max: number,
+                    sum: number,
+                    count: 1,
+                }
+            }
+            Value::Bool(flag) => Summary::Flag(*flag),
+            other => Summary::Latest(other.clone()),
+        }
+    }
+
+    fn add(&mut self, value: &Value) {
+        match (&mut *self, value) {
+            (Summary::Object(fields), Value::Object(values)) => {
+                for (key, value) in values {
+                    match fields.get_mut(key) {
+                        Some(summary) => summary.add(value),
+                        None => {
+                            fields.insert(key.clone(), Summary::of(value));
+                        }
+                    }
+                }
+            }
+            (
+                Summary::Number {
+                    min,
+                    max,
+                    sum,
+                    count,
+                },
+                Value::Number(number),
+            ) => {
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                *min = min.min(number);
+                *max = max.max(number);
+                *sum += number;
+                *count += 1;
+            }
+            // A value left out of some rows is summarized over the others
+            (Summary::Number { .. }, Value::Null) => {}
+            (Summary::Flag(all), Value::Bool(flag)) => *all &= flag,
+            (summary, value) => *summary = Summary::of(value),
+        }
+    }
+
+    fn finish(self) -> Value {
+        match self {
+            Summary::Object(fields) => {
+                let mut object = Map::new();
+                for (key, summary) in fields {
+                    if let Summary::Number { min, max, .. } = summary {
+                        object.insert(format!("{key}_min"), number(min));
+                        object.insert(format!("{key}_max"), number(max));
+                    }
+                    object.insert(key, summary.finish());
+                }
+                Value::Object(object)
+            }
+            Summary::Number { sum, count, .. } => number(sum / count as f64),
+            Summary::Flag(all) => Value::Bool(all),
+            Summary::Latest(value) => value,
+        }
+    }
+}
+
+/// NaN and infinities are stored as null, as serializing them would.
+fn number(value: f64) -> Value {
+    serde_json::Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+const MEASUREMENT_VALUES: usize = 14;
+
+#[derive(Clone, Copy, Default)]
+struct Mean {
+    sum: f64,
+    count: u64,
+}
+
+impl Mean {
+    /// Values left out (None or NaN) don't count.
+    fn add(&mut self, value: Option<f64>) {
+        if let Some(value) = value.filter(|value| !value.is_nan()) {
+            self.sum += value;
+            self.count += 1;
+        }
+    }
+
+    fn value(self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+fn measurement_values(m: &Measurement) -> [Option<f64>; MEASUREMENT_VALUES] {
+    [
+        Some(m.rms_voltage),
+        Some(m.dc_offset_voltage),
+        Some(m.rms_current),
+        Some(m.dc_offset_current),
+        Some(m.real_power),
+        Some(m.apparent_power),
+        Some(m.reactive_power),
+        Some(m.power_factor),
+        Some(m.three_phase_real_power),
+        Some(m.three_phase_reactive_power),
+        m.crest_factor_voltage,
+        m.thd_voltage,
+        m.crest_factor_current,
+        m.thd_current,
+    ]
+}
+
+fn measurement(
+    stream: String,
+    phase: &'static str,
+    values: [Option<f64>; MEASUREMENT_VALUES],
+) -> Measurement {
+    let [
+        rms_voltage,
+        dc_offset_voltage,
+        rms_current,
+        dc_offset_current,
+        real_power,
+        apparent_power,
+        reactive_power,
+        power_factor,
+        three_phase_real_power,
+        three_phase_reactive_power,
+        crest_factor_voltage,
+        thd_voltage,
+        crest_factor_current,
+        thd_current,
+    ] = values;
+    let required = |value: Option<f64>| value.unwrap_or(f64::NAN);
+    Measurement {
+        stream,
+        phase,
+        rms_voltage: required(rms_voltage),
+        dc_offset_voltage: required(dc_offset_voltage),
+        rms_current: required(rms_current),
+        dc_offset_current: required(dc_offset_current),
+        real_power: required(real_power),
+        apparent_power: required(apparent_power),
+        reactive_power: required(reactive_power),
+        power_factor: required(power_factor),
+        three_phase_real_power: required(three_phase_real_power),
+        three_phase_reactive_power: required(three_phase_reactive_power),
+        crest_factor_voltage,
+        thd_voltage,
+        crest_factor_current,
+        thd_current,
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use frame_assembly::{Assembler, AssemblyConfig, Outcome};
+use health::Source;
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeJoinedCalculations, composite_joined_calculations_wrapper::DataProduct,
+};
+use shutdown::Shutdown;
+use zmq_ingest::{Backoff, SubscriberStream};
+
+use crate::device::DeviceConfig;
+use crate::metrics::ASSEMBLED_FRAMES;
+
+/// A frame as it goes on to the durable queue or the decoder.
+pub struct Received {
+    pub joined: CompositeJoinedCalculations,
+    pub received: DateTime<Utc>,
+}
+
+/// Hands out frames as they arrive, or, with an assembly window, once the fragments of each
+/// instant have been joined back together.
+pub struct Receiver {
+    subscription: SubscriberStream,
+    assembler: Option<Assembler>,
+    backoff: Backoff,
+    devices: DeviceConfig,
+    health: Source,
+}
+
+impl Receiver {
+    pub fn new(
+        subscription: SubscriberStream,
+        assembly: Option<AssemblyConfig>,
+        backoff: Backoff,
+        devices: DeviceConfig,
+        health: Source,
+    ) -> Self {
+        health.connected();
+        Self {
+            subscription,
+            assembler: assembly.map(Assembler::new),
+            backoff,
+            devices,
+            health,
+        }
+    }
+
+    /// Waits for the next message or assembly deadline. Often returns nothing while a frame
+    /// is still being assembled.
+    pub async fn next(&mut self) -> Result<Vec<Received>> {
+        let deadline = self.assembler.as_ref().and_then(Assembler::next_deadline);
+        let mut frame = tokio::select! {
+            frame = self.subscription.next() => frame
+                .inspect_err(|_| self.health.disconnected())
+                .context("Unable to receive message")?,
+            _ = sleep_until_or_forever(deadline) => {
+                let Some(assembler) = self.assembler.as_mut() else {
+                    return Ok(Vec::new());
+                };
+                let outcomes = assembler.expire(Instant::now());
+                return Ok(self.settle(outcomes));
+            }
+        };
+
+        self.health.message();
+        let topic = frame.topic.as_deref().unwrap_or(self.subscription.topic());
+        self.devices.stamp(&mut frame.joined, topic);
+
+        let Some(assembler) = self.assembler.as_mut() else {
+            return Ok(vec![Received {
+                joined: frame.joined,
+                received: frame.received.into(),
+            }]);
+        };
+        let outcomes = assembler.push(frame.joined, Instant::now());
+        Ok(self.settle(outcomes))
+    }
+
+    pub fn topic(&self) -> &str {
+        self.subscription.topic()
+    }
+
+    /// Dials the publisher again after `next` failed, backing off between attempts until one
+    /// succeeds (`true`) or shutdown is requested (`false`). Frames still being assembled are
+    /// kept.
+    pub async fn reconnect(&mut self, shutdown: &Shutdown) -> bool {
+        let reconnected = self
+            .subscription
+            .reconnect_with_backoff(self.backoff, shutdown)
+            .await;
+        if reconnected {
+            self.health.connected();
+        }
+        reconnected
+    }
+
+    /// Closes the subscription, handing out the frames that were still being assembled.
+    pub async fn close(mut self) -> Vec<Received> {
+        let outcomes = self
+            .assembler
+            .as_mut()
+            .map(Assembler::drain)
+            .unwrap_or_default();
+        let frames = self.settle(outcomes);
+        self.subscription.close().await;
+        frames
+    }
+
+    fn settle(&self, outcomes: Vec<Outcome>) -> Vec<Received> {
+        let mut frames = Vec::new();
+        for outcome in outcomes {
+            let (label, frame) = match outcome {
+                Outcome::Complete(frame) => ("complete", Some(frame)),
+                Outcome::Partial { frame, missing } => {
+                    log::debug!("Assembly window ended without {}", missing.join(", "));
+                    ("partial", Some(frame))
+                }
+                Outcome::Dropped { missing } => {
+                    log::debug!("Dropped a frame without {}", missing.join(", "));
+                    ("dropped", None)
+                }
+                Outcome::Late => ("late", None),
+            };
+            ASSEMBLED_FRAMES.with_label_values(&[label]).inc();
+
+            let Some(mut frame) = frame else {
+                continue;
+            };
+            // Rows need phases A and B of a stream, so a stream only half assembled is left out
+            frame.joined.calculations.retain(|wrapper| {
+                !matches!(
+                    &wrapper.data_product,
+                    Some(DataProduct::Calculations(calcs))
+                        if calcs.phase_a.is_none() || calcs.phase_b.is_none()
+                )
+            });
+            if frame.joined.calculations.is_empty() {
+                continue;
+            }
+
+            frames.push(Received {
+                joined: frame.joined,
+                received: frame.first_received.into(),
+            });
+        }
+        frames
+    }
+}
+
+async fn sleep_until_or_forever(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
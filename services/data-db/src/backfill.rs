@@ -0,0 +1,275 @@
+//! `data-db [FLAGS] backfill FILE...`: historical captures in data-replay's dataset format
+//! (CSV, JSON lines or Parquet, by extension) written straight into the table, without
+//! replaying them through ZeroMQ in real time.
+//!
+//! Rows go through the same transformation as frames received live: each timestamp becomes
+//! one row per file, its streams named `threephase/<stream_name>` as data-replay publishes
+//! them and stamped with that timestamp, under --device and --tenant. Data-replay's
+//! duplication of a missing phase_b is kept too, so a backfilled day reads the same as a
+//! replayed one. The table has no key to deduplicate on; backfilling a file twice stores its
+//! rows twice.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use karman_types::time_sync;
+use parquet::file::reader::SerializedFileReader;
+use parquet::record::{Field, Row as ParquetRow};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeCalculations, CompositeJoinedCalculations, CompositeJoinedCalculationsWrapper,
+    CompositeTwoPhaseCalculations, PowerCalculations, Provenance, TimeSync, WaveformCalculations,
+    composite_joined_calculations_wrapper::DataProduct,
+};
+use serde::Deserialize;
+
+use crate::pool::PoolConfig;
+use crate::writer::{BatchConfig, BatchWriter, Row};
+use crate::{Args, into_calculations, measurements, schema};
+
+#[derive(clap::Args, Clone)]
+pub struct BackfillArgs {
+    /// Dataset files, written in the order given
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+}
+
+/// One stream and phase at one timestamp, with the columns data-replay reads.
+#[derive(Deserialize)]
+struct DatasetRow {
+    time: i64, // Milliseconds since epoch
+    stream_name: String,
+    phase: String,
+    rms_voltage: f32,
+    dc_offset_voltage: f32,
+    rms_current: f32,
+    dc_offset_current: f32,
+    real_power: f32,
+    apparent_power: f32,
+    reactive_power: f32,
+    power_factor: f32,
+    sequence_number: Option<u64>,
+    time_source: Option<String>,
+    clock_locked: Option<bool>,
+    clock_offset_ns: Option<i64>,
+    clock_max_error_ns: Option<u64>,
+    crest_factor_voltage: Option<f32>,
+    thd_percent_voltage: Option<f32>,
+    crest_factor_current: Option<f32>,
+    thd_percent_current: Option<f32>,
+}
+
+type Rows = Box<dyn Iterator<Item = Result<DatasetRow>>>;
+
+pub async fn run(args: &Args, backfill: &BackfillArgs) -> Result<()> {
+    let Some(config) = args.pool()? else {
+        bail!("backfill needs --connection-string");
+    };
+    let pool = PoolConfig {
+        max_connections: 1,
+        ..config
+    }
+    .connect_once()
+    .await
+    .context("Could not connect to database")?;
+    schema::migrate(&pool, args.schema_mode).await?;
+
+    let mut writer = BatchWriter::new(
+        pool.clone(),
+        BatchConfig {
+            // Nothing else arrives meanwhile, so rows the database can't take yet stop the
+            // backfill rather than pile up
+            max_buffered_rows: 0,
+            ..args.batch_config()
+        },
+    );
+    for path in &backfill.files {
+        let rows = backfill_file(args, path, &mut writer)
+            .await
+            .with_context(|| format!("Could not backfill {}", path.display()))?;
+        if !writer.flush().await {
+            bail!("Could not write every row of {}", path.display());
+        }
+        log::info!("Backfilled {rows} rows from {}", path.display());
+    }
+    pool.close().await;
+    Ok(())
+}
+
+/// Pushes a row per timestamp of `path` to `writer`, returning how many.
+async fn backfill_file(args: &Args, path: &Path, writer: &mut BatchWriter) -> Result<u64> {
+    // Ordered by stream name, as data-replay encodes its frames
+    let mut frame: BTreeMap<String, [Option<DatasetRow>; 3]> = BTreeMap::new();
+    let mut frame_time = None;
+    let mut frames = 0;
+    for row in read_rows(path)? {
+        let row = row?;
+        if let Some(time) = frame_time.filter(|time| *time != row.time && !frame.is_empty()) {
+            writer.push(into_row(args, time, &frame, frames)?);
+            writer.flush_if_full().await;
+            frames += 1;
+            frame.clear();
+        }
+        frame_time = Some(row.time);
+
+        let phases = frame
+            .entry(row.stream_name.clone())
+            .or_insert([None, None, None]);
+        match row.phase.as_str() {
+            "phase_a" => phases[0] = Some(row),
+            "phase_b" => phases[1] = Some(row),
+            "phase_c" => phases[2] = Some(row),
+            _ => log::warn!("Unknown phase: {}", row.phase),
+        }
+    }
+    if let Some(time) = frame_time.filter(|_| !frame.is_empty()) {
+        writer.push(into_row(args, time, &frame, frames)?);
+        frames += 1;
+    }
+    Ok(frames)
+}
+
+fn into_row(
+    args: &Args,
+    time_ms: i64,
+    frame: &BTreeMap<String, [Option<DatasetRow>; 3]>,
+    sequence: u64,
+) -> Result<Row> {
+    let Some(time) = DateTime::<Utc>::from_timestamp_millis(time_ms) else {
+        bail!("Row time {time_ms} is out of range");
+    };
+    let calculations = into_calculations(&joined(frame, time, sequence), args.max_clock_offset);
+    Ok(Row {
+        time,
+        device: args.device.clone(),
+        tenant: args.tenant.clone(),
+        measurements: measurements(&calculations, args.schema_mode),
+        data: serde_json::to_value(&calculations).expect("Could not serialize"),
+    })
+}
+
+/// The frame data-replay would publish for these rows, stamped with `time`.
+fn joined(
+    frame: &BTreeMap<String, [Option<DatasetRow>; 3]>,
+    time: DateTime<Utc>,
+    sequence: u64,
+) -> CompositeJoinedCalculations {
+    let calculations = frame
+        .iter()
+        .filter_map(|(stream_name, [phase_a, phase_b, phase_c])| {
+            let row_a = phase_a.as_ref()?;
+            let composite = CompositeTwoPhaseCalculations {
+                phase_a: Some(composite(row_a, time, sequence)),
+                phase_b: Some(composite(phase_b.as_ref().unwrap_or(row_a), time, sequence)),
+                phase_c: phase_c.as_ref().map(|row| composite(row, time, sequence)),
+            };
+            Some(CompositeJoinedCalculationsWrapper {
+                calculation_name: Some(format!("threephase/{stream_name}")),
+                data_product: Some(DataProduct::Calculations(composite)),
+                device_id: None,
+                harmonics: None,
+            })
+        })
+        .collect();
+    CompositeJoinedCalculations { calculations }
+}
+
+fn composite(row: &DatasetRow, time: DateTime<Utc>, sequence: u64) -> CompositeCalculations {
+    CompositeCalculations {
+        provenance: Some(Provenance {
+            utc_time: Some(prost_types::Timestamp {
+                seconds: time.timestamp(),
+                nanos: time.timestamp_subsec_nanos() as i32,
+            }),
+            generic_sequence_number: row.sequence_number.or(Some(sequence)),
+            time_sync: time_sync(row),
+        }),
+        voltage_waveform_calculations_v: Some(WaveformCalculations {
+            rms: Some(row.rms_voltage),
+            dc_offset: Some(row.dc_offset_voltage),
+            crest_factor: row.crest_factor_voltage,
+            thd_percent: row.thd_percent_voltage,
+        }),
+        current_waveform_calculations_a: Some(WaveformCalculations {
+            rms: Some(row.rms_current),
+            dc_offset: Some(row.dc_offset_current),
+            crest_factor: row.crest_factor_current,
+            thd_percent: row.thd_percent_current,
+        }),
+        power_calculations: Some(PowerCalculations {
+            real_power_w: Some(row.real_power),
+            apparent_power_va: Some(row.apparent_power),
+            reactive_power_var: Some(row.reactive_power),
+            power_factor: Some(row.power_factor),
+        }),
+    }
+}
+
+fn time_sync(row: &DatasetRow) -> Option<TimeSync> {
+    if row.time_source.is_none()
+        && row.clock_locked.is_none()
+        && row.clock_offset_ns.is_none()
+        && row.clock_max_error_ns.is_none()
+    {
+        return None;
+    }
+    let source = time_sync::source_from_label(row.time_source.as_deref().unwrap_or_default());
+    Some(TimeSync {
+        source: Some(source as i32),
+        locked: row.clock_locked,
+        offset_ns: row.clock_offset_ns,
+        max_error_ns: row.clock_max_error_ns,
+    })
+}
+
+/// The rows of a dataset file, in file order. Like data-replay, reads anything that isn't
+/// JSON lines or Parquet as CSV.
+fn read_rows(path: &Path) -> Result<Rows> {
+    let file = File::open(path).context("Could not open dataset file")?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "jsonl" | "ndjson" => Ok(Box::new(
+            BufReader::new(file)
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+                .map(|(number, line)| {
+                    let line = line.context("Could not read dataset file")?;
+                    serde_json::from_str(&line)
+                        .with_context(|| format!("Failed to parse JSON line {}", number + 1))
+                }),
+        )),
+        "parquet" => {
+            let reader = SerializedFileReader::new(file).context("Could not read Parquet file")?;
+            Ok(Box::new(reader.into_iter().map(|row| {
+                parquet_row(row.context("Failed to read Parquet row")?)
+            })))
+        }
+        _ => Ok(Box::new(
+            csv::Reader::from_reader(file)
+                .into_deserialize()
+                .map(|row| row.context("Failed to parse CSV row")),
+        )),
+    }
+}
+
+/// Maps a Parquet row onto the dataset columns by name.
+fn parquet_row(row: ParquetRow) -> Result<DatasetRow> {
+    let mut object = serde_json::Map::new();
+    for (name, field) in row.get_column_iter() {
+        let value = match field {
+            Field::TimestampMillis(ms) => (*ms).into(),
+            Field::TimestampMicros(us) => us.div_euclid(1000).into(),
+            field => field.to_json_value(),
+        };
+        object.insert(name.clone(), value);
+    }
+    serde_json::from_value(object.into()).context("Failed to parse Parquet row")
+}
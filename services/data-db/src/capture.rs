@@ -0,0 +1,266 @@
+//! Event-triggered capture: the regular tables get one row per `store_interval`, while the
+//! last `pre_roll` of full-rate rows is kept in memory (the flight recorder). When a trigger
+//! fires, that history, and everything up to `post_roll` after the trigger last held, goes to
+//! `bibimbap_highres`, and the trigger itself to `bibimbap_events`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+
+use crate::metrics::CAPTURE_EVENTS;
+use crate::writer::{BatchWriter, Row};
+
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    /// Spacing of the rows kept in the regular tables
+    pub store_interval: Duration,
+    pub pre_roll: Duration,
+    pub post_roll: Duration,
+    pub triggers: Triggers,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Triggers {
+    /// Any phase's RMS voltage below this many volts
+    pub sag_below_volts: Option<f64>,
+    /// A stream's real power, all phases (C when sent) together, above this many watts
+    pub power_cap_watts: Option<f64>,
+    /// A stream's real power changing by more than this percent from one frame to the next
+    pub step_change_percent: Option<f64>,
+}
+
+impl Triggers {
+    pub fn is_empty(&self) -> bool {
+        self.sag_below_volts.is_none()
+            && self.power_cap_watts.is_none()
+            && self.step_change_percent.is_none()
+    }
+}
+
+/// A trigger condition on one stream (and phase, for voltage).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Condition {
+    trigger: &'static str,
+    stream: String,
+    phase: Option<&'static str>,
+}
+
+struct Detection {
+    condition: Condition,
+    value: f64,
+    threshold: f64,
+}
+
+pub struct Capture {
+    config: CaptureConfig,
+    pool: Pool<Postgres>,
+    highres: BatchWriter,
+    recorder: VecDeque<Row>,
+    /// Set while capturing: rows up to this time go to the high-res table
+    capturing_until: Option<DateTime<Utc>>,
+    last_stored: Option<DateTime<Utc>>,
+    /// Conditions that held on the previous frame, so each episode is one event
+    active: HashSet<Condition>,
+    last_power: HashMap<String, f64>,
+}
+
+impl Capture {
+    /// `highres` should write to `bibimbap_highres`.
+    pub fn new(config: CaptureConfig, pool: Pool<Postgres>, highres: BatchWriter) -> Self {
+        Self {
+            config,
+            pool,
+            highres,
+            recorder: VecDeque::new(),
+            capturing_until: None,
+            last_stored: None,
+            active: HashSet::new(),
+            last_power: HashMap::new(),
+        }
+    }
+
+    /// Runs the triggers over a full-rate row. Returns it again when it is also due in the
+    /// regular, downsampled tables.
+    pub async fn process(&mut self, row: Row) -> Option<Row> {
+        let detections = self.detect(&row);
+
+        let mut active = HashSet::new();
+        for detection in detections {
+            if !self.active.contains(&detection.condition) {
+                self.record_event(&row, &detection).await;
+            }
+            active.insert(detection.condition);
+        }
+
+        if !active.is_empty() {
+            if self.capturing_until.is_none() {
+                log::info!("Capturing from {} rows of pre-roll", self.recorder.len());
+                for row in self.recorder.drain(..) {
+                    self.highres.push(row);
+                }
+            }
+            let until = row.time + self.config.post_roll;
+            self.capturing_until = Some(self.capturing_until.map_or(until, |t| t.max(until)));
+        }
+        self.active = active;
+
+        let capturing = self.capturing_until.is_some_and(|until| row.time <= until);
+        if !capturing && self.capturing_until.take().is_some() {
+            log::info!("Capture ended");
+        }
+
+        // Time going backwards (the durable queue re-reading after a failed write) stores
+        // rows again rather than skipping them
+        let due = self.last_stored.is_none_or(|last| {
+            (row.time - last)
+                .to_std()
+                .map_or(true, |elapsed| elapsed >= self.config.store_interval)
+        });
+        if due {
+            self.last_stored = Some(row.time);
+        }
+        let full_rate = Row {
+            time: row.time,
+            device: row.device.clone(),
+            tenant: row.tenant.clone(),
+            data: row.data.clone(),
+            measurements: Vec::new(),
+        };
+        if capturing {
+            self.highres.push(full_rate);
+            if self.highres.is_full() {
+                self.highres.flush().await;
+            }
+        } else {
+            self.remember(full_rate);
+        }
+
+        due.then_some(row)
+    }
+
+    /// Writes buffered high-res rows.
+    pub async fn flush(&mut self) {
+        self.highres.flush().await;
+    }
+
+    fn remember(&mut self, row: Row) {
+        let horizon = row.time - self.config.pre_roll;
+        self.recorder.push_back(row);
+        while self
+            .recorder
+            .front()
+            .is_some_and(|oldest| oldest.time < horizon)
+        {
+            self.recorder.pop_front();
+        }
+    }
+
+    fn detect(&mut self, row: &Row) -> Vec<Detection> {
+        let triggers = &self.config.triggers;
+        let mut detections = Vec::new();
+        let Some(streams) = row.data.as_object() else {
+            return detections;
+        };
+
+        for (stream, calculation) in streams {
+            let field = |phase: &str, name: &str| calculation[phase][name].as_f64();
+
+            if let Some(threshold) = triggers.sag_below_volts {
+                for (phase, key) in [("a", "phase_a"), ("b", "phase_b"), ("c", "phase_c")] {
+                    if let Some(volts) = field(key, "rms_voltage").filter(|v| *v < threshold) {
+                        detections.push(Detection {
+                            condition: Condition {
+                                trigger: "sag",
+                                stream: stream.clone(),
+                                phase: Some(phase),
+                            },
+                            value: volts,
+                            threshold,
+                        });
+                    }
+                }
+            }
+
+            let (Some(a), Some(b)) = (
+                field("phase_a", "real_power"),
+                field("phase_b", "real_power"),
+            ) else {
+                continue;
+            };
+            // Three-phase meters add phase C to the stream's power
+            let power = a + b + field("phase_c", "real_power").unwrap_or_default();
+
+            if let Some(threshold) = triggers.power_cap_watts.filter(|cap| power > *cap) {
+                detections.push(Detection {
+                    condition: Condition {
+                        trigger: "power_cap",
+                        stream: stream.clone(),
+                        phase: None,
+                    },
+                    value: power,
+                    threshold,
+                });
+            }
+
+            let previous = self.last_power.insert(stream.clone(), power);
+            if let (Some(threshold), Some(previous)) = (triggers.step_change_percent, previous) {
+                // Relative to at least 1 W, so an idle stream's noise isn't a huge step
+                let step = (power - previous).abs() / previous.abs().max(1.0) * 100.0;
+                if step > threshold {
+                    detections.push(Detection {
+                        condition: Condition {
+                            trigger: "step_change",
+                            stream: stream.clone(),
+                            phase: None,
+                        },
+                        value: step,
+                        threshold,
+                    });
+                }
+            }
+        }
+        detections
+    }
+
+    async fn record_event(&self, row: &Row, detection: &Detection) {
+        let condition = &detection.condition;
+        log::info!(
+            "{} on {}{}: {:.2} against {:.2}",
+            condition.trigger,
+            condition.stream,
+            condition
+                .phase
+                .map(|p| format!(" phase {p}"))
+                .unwrap_or_default(),
+            detection.value,
+            detection.threshold
+        );
+        CAPTURE_EVENTS.with_label_values(&[condition.trigger]).inc();
+        if let Err(err) = insert_event(&self.pool, row, detection).await {
+            log::error!("{err:#}");
+        }
+    }
+}
+
+async fn insert_event(pool: &Pool<Postgres>, row: &Row, detection: &Detection) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO bibimbap_events \
+         (time, device, tenant, trigger, stream, phase, value, threshold) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+    )
+    .bind(row.time)
+    .bind(&row.device)
+    .bind(&row.tenant)
+    .bind(detection.condition.trigger)
+    .bind(&detection.condition.stream)
+    .bind(detection.condition.phase)
+    .bind(detection.value)
+    .bind(detection.threshold)
+    .execute(pool)
+    .await
+    .context("Could not record capture event")?;
+    Ok(())
+}
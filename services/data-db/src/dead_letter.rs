@@ -0,0 +1,282 @@
+//! Spool directory for messages data-db could not store, kept so they can be diagnosed and
+//! replayed instead of only being logged.
+//!
+//! Every dead letter is a pair of files sharing a name: the payload, and a `.json` file with
+//! the context (what failed, the error, when it arrived). Messages that did not decode are
+//! kept as they came off the socket, topic followed by the protobuf payload (`.pb`), so they
+//! can be published again as they are. Rows the database refused are kept as JSON lines of
+//! `time`, `device`, `tenant` and `data` (`.jsonl`), ready to be inserted once the cause is
+//! fixed. Names start with the time the message arrived, so they sort oldest first; the
+//! oldest are deleted once the spool is over its size limit or past its maximum age.
+//!
+//! A table would be easier to query, but the database is often the thing that failed.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::metrics::{DEAD_LETTER_BYTES, DEAD_LETTER_ERRORS, DEAD_LETTERS, DEAD_LETTERS_PRUNED};
+use crate::writer::Row;
+
+const CONTEXT_EXTENSION: &str = "json";
+
+#[derive(Clone, Debug)]
+pub struct DeadLetterConfig {
+    pub directory: PathBuf,
+    /// Oldest dead letters are deleted once the spool holds more than this
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// A message that was not a topic and a decodable frame
+    Decode,
+    /// Rows the database refused outright
+    Insert,
+}
+
+impl Kind {
+    fn label(self) -> &'static str {
+        match self {
+            Kind::Decode => "decode",
+            Kind::Insert => "insert",
+        }
+    }
+
+    fn payload_extension(self) -> &'static str {
+        match self {
+            Kind::Decode => "pb",
+            Kind::Insert => "jsonl",
+        }
+    }
+}
+
+/// What went wrong, stored next to the payload.
+#[derive(Serialize)]
+struct Details<'a> {
+    kind: &'static str,
+    /// RFC 3339; for rows, when the first one arrived
+    received: String,
+    error: &'a str,
+    /// The subscription's topic prefix
+    topic: &'a str,
+    payload_file: &'a str,
+    payload_bytes: usize,
+    /// Rows in the payload, for `insert`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rows: Option<usize>,
+}
+
+struct Entry {
+    stem: String,
+    bytes: u64,
+    written: SystemTime,
+}
+
+struct Spool {
+    config: DeadLetterConfig,
+    /// Oldest first
+    entries: VecDeque<Entry>,
+    bytes: u64,
+    /// Tells apart dead letters that arrived in the same microsecond
+    sequence: u64,
+}
+
+/// The dead-letter spool, shared by the subscriber and the writers. Writing blocks on the
+/// filesystem; dead letters are rare enough for that not to matter.
+#[derive(Clone)]
+pub struct DeadLetters {
+    topic: String,
+    spool: Arc<Mutex<Spool>>,
+}
+
+impl DeadLetters {
+    /// Opens (or creates) the spool and applies the retention limits to what is already in
+    /// it.
+    pub fn open(config: DeadLetterConfig, topic: &str) -> Result<Self> {
+        let directory = &config.directory;
+        fs::create_dir_all(directory)
+            .with_context(|| format!("Could not create {}", directory.display()))?;
+        let entries = existing(directory)?;
+        let bytes = entries.iter().map(|entry| entry.bytes).sum();
+        let mut spool = Spool {
+            config,
+            entries,
+            bytes,
+            sequence: 0,
+        };
+        spool.prune();
+        if !spool.entries.is_empty() {
+            log::warn!(
+                "{} dead letters ({} bytes) in {}",
+                spool.entries.len(),
+                spool.bytes,
+                spool.config.directory.display()
+            );
+        }
+        Ok(Self {
+            topic: topic.to_string(),
+            spool: Arc::new(Mutex::new(spool)),
+        })
+    }
+
+    /// Keeps a message that did not decode; `message` still carries its topic.
+    pub fn message(&self, message: &[u8], received: DateTime<Utc>, error: &str) {
+        self.write(Kind::Decode, message, 1, received, error);
+    }
+
+    /// Keeps rows the database refused.
+    pub fn rows(&self, rows: &[Row], error: &str) {
+        let Some(first) = rows.first() else {
+            return;
+        };
+        let mut payload = Vec::new();
+        for row in rows {
+            let line = serde_json::json!({
+                "time": row.time.to_rfc3339_opts(SecondsFormat::Micros, true),
+                "device": row.device,
+                "tenant": row.tenant,
+                "data": row.data,
+            });
+            serde_json::to_writer(&mut payload, &line).expect("Could not serialize");
+            payload.push(b'\n');
+        }
+        self.write(Kind::Insert, &payload, rows.len(), first.time, error);
+    }
+
+    /// Counts the dead letter and writes it to the spool. A failure to write is logged and
+    /// counted, but otherwise changes nothing: the message was lost either way.
+    fn write(&self, kind: Kind, payload: &[u8], rows: usize, received: DateTime<Utc>, error: &str) {
+        DEAD_LETTERS.with_label_values(&[kind.label()]).inc();
+        let mut spool = self.spool.lock().unwrap();
+        let written = tokio::task::block_in_place(|| {
+            spool.write(kind, payload, rows, received, error, &self.topic)
+        });
+        if let Err(err) = written {
+            DEAD_LETTER_ERRORS.inc();
+            log::error!("Could not write dead letter: {err:#}");
+        }
+    }
+}
+
+impl Spool {
+    fn write(
+        &mut self,
+        kind: Kind,
+        payload: &[u8],
+        rows: usize,
+        received: DateTime<Utc>,
+        error: &str,
+        topic: &str,
+    ) -> Result<()> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let stem = format!(
+            "{}-{:06}-{}",
+            received.format("%Y%m%dT%H%M%S%.6fZ"),
+            self.sequence % 1_000_000,
+            kind.label()
+        );
+        let payload_file = format!("{stem}.{}", kind.payload_extension());
+        let context = serde_json::to_vec_pretty(&Details {
+            kind: kind.label(),
+            received: received.to_rfc3339_opts(SecondsFormat::Micros, true),
+            error,
+            topic,
+            payload_file: &payload_file,
+            payload_bytes: payload.len(),
+            rows: (kind == Kind::Insert).then_some(rows),
+        })?;
+
+        let directory = &self.config.directory;
+        fs::write(directory.join(&payload_file), payload)
+            .with_context(|| format!("Could not write {payload_file}"))?;
+        // The context goes last, so a dead letter with one is complete
+        fs::write(
+            directory.join(format!("{stem}.{CONTEXT_EXTENSION}")),
+            &context,
+        )
+        .with_context(|| format!("Could not write context for {payload_file}"))?;
+
+        let bytes = (payload.len() + context.len()) as u64;
+        self.entries.push_back(Entry {
+            stem,
+            bytes,
+            written: SystemTime::now(),
+        });
+        self.bytes += bytes;
+        self.prune();
+        Ok(())
+    }
+
+    /// Deletes the oldest dead letters until the spool is within its limits.
+    fn prune(&mut self) {
+        let now = SystemTime::now();
+        while let Some(oldest) = self.entries.front() {
+            let expired = now
+                .duration_since(oldest.written)
+                .is_ok_and(|age| age > self.config.max_age);
+            if !expired && self.bytes <= self.config.max_bytes {
+                break;
+            }
+            let oldest = self.entries.pop_front().expect("an entry");
+            if let Err(err) = remove(&self.config.directory, &oldest.stem) {
+                log::warn!("Could not delete dead letter {}: {err:#}", oldest.stem);
+            }
+            self.bytes -= oldest.bytes;
+            DEAD_LETTERS_PRUNED.inc();
+        }
+        DEAD_LETTER_BYTES.set(self.bytes as i64);
+    }
+}
+
+/// The dead letters already in `directory`, oldest first. Files are grouped by name, so a
+/// payload whose context was never written is still counted and pruned.
+fn existing(directory: &Path) -> Result<VecDeque<Entry>> {
+    let mut entries: BTreeMap<String, Entry> = BTreeMap::new();
+    for file in fs::read_dir(directory)
+        .with_context(|| format!("Could not list {}", directory.display()))?
+    {
+        let file = file?;
+        let path = file.path();
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let written = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        let entry = entries.entry(stem.to_string()).or_insert_with(|| Entry {
+            stem: stem.to_string(),
+            bytes: 0,
+            written,
+        });
+        entry.bytes += metadata.len();
+        entry.written = entry.written.min(written);
+    }
+    Ok(entries.into_values().collect())
+}
+
+fn remove(directory: &Path, stem: &str) -> Result<()> {
+    for extension in [
+        CONTEXT_EXTENSION,
+        Kind::Decode.payload_extension(),
+        Kind::Insert.payload_extension(),
+    ] {
+        match fs::remove_file(directory.join(format!("{stem}.{extension}"))) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
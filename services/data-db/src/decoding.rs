@@ -0,0 +1,42 @@
+//! Counting what publishers leave out of frames. Every field is optional on the wire;
+//! karman-types decodes a stream once, and a field that is left out is stored as NaN (null in
+//! the JSON column) and counted in `data_db_missing_fields_total`, instead of taking the
+//! service down.
+
+use karman_types::Stream;
+
+use crate::metrics::{INCOMPLETE_CALCULATIONS, MISSING_FIELDS};
+
+/// Counts the fields `stream` was missing, and the stream as partial if there were any.
+pub fn count(stream: &Stream) {
+    if stream.is_complete() {
+        return;
+    }
+    INCOMPLETE_CALCULATIONS
+        .with_label_values(&["partial"])
+        .inc();
+    for field in &stream.missing {
+        let counter = MISSING_FIELDS.with_label_values(&[field]);
+        counter.inc();
+        // Every frame from a publisher that never sends the field would otherwise log
+        if counter.get() == 1 {
+            log::warn!(
+                "Frame without a required field, storing NaN: stream={} field={field} \
+                 (further ones are only counted in data_db_missing_fields_total)",
+                stream.name
+            );
+        }
+    }
+}
+
+/// Counts a stream entry that can't be stored at all, e.g. one without a name.
+pub fn skipped(reason: &str) {
+    let counter = INCOMPLETE_CALCULATIONS.with_label_values(&["skipped"]);
+    counter.inc();
+    if counter.get() == 1 {
+        log::warn!(
+            "Skipping a stream in a frame: reason={reason:?} \
+             (further ones are only counted in data_db_incomplete_calculations_total)"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! `/schema`: the shapes data-db produces, for integrators building against them without
+//! reading the source. The JSON Schema of the `data` column is generated from the types that
+//! are serialized into it, and the metric catalog from the registered collectors.
+
+use std::collections::HashMap;
+
+use axum::Json;
+use prometheus::core::Collector;
+use prometheus::proto::MetricType;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::Calculation;
+use crate::metrics;
+use stored_document::SchemaVersion;
+
+// Only described, never built: rows are laid out from their JSON by `SchemaVersion::document`
+
+/// `--json-schema-version 1`
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+struct DocumentV1 {
+    /// 1; rows written before the version was recorded have none
+    schema_version: u64,
+    #[serde(flatten)]
+    streams: HashMap<String, Calculation>,
+}
+
+/// `--json-schema-version 2`
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+struct DocumentV2 {
+    /// 2
+    schema_version: u64,
+    streams: Vec<Entry>,
+}
+
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+struct Entry {
+    /// The calculation name
+    stream: String,
+    #[serde(flatten)]
+    calculation: Calculation,
+}
+
+#[derive(Serialize)]
+struct MetricDescription {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    help: String,
+    labels: Vec<String>,
+}
+
+/// Describes every metric family `collectors` export, sorted by name, whether or not it has
+/// any series yet.
+fn describe_metrics(collectors: &[&dyn Collector]) -> Vec<MetricDescription> {
+    let mut metrics = Vec::new();
+    for collector in collectors {
+        let families = collector.collect();
+        for desc in collector.desc() {
+            let kind = families
+                .iter()
+                .find(|family| family.get_name() == desc.fq_name)
+                .map_or("untyped", |family| match family.get_field_type() {
+                    MetricType::COUNTER => "counter",
+                    MetricType::GAUGE => "gauge",
+                    MetricType::SUMMARY => "summary",
+                    MetricType::HISTOGRAM => "histogram",
+                    MetricType::UNTYPED => "untyped",
+                });
+            metrics.push(MetricDescription {
+                name: desc.fq_name.clone(),
+                kind,
+                help: desc.help.clone(),
+                labels: desc.variable_labels.clone(),
+            });
+        }
+    }
+    metrics.sort_by(|a, b| a.name.cmp(&b.name));
+    metrics
+}
+
+/// JSON Schema of the `data` column of `bibimbap` (and `bibimbap_highres`), as `version`
+/// lays it out.
+fn document(version: SchemaVersion) -> Value {
+    let (mut schema, layout) = match version {
+        SchemaVersion::V1 => (
+            schemars::schema_for!(DocumentV1),
+            "One row's streams, keyed by calculation name.",
+        ),
+        SchemaVersion::V2 => (
+            schemars::schema_for!(DocumentV2),
+            "One row's streams, each entry naming its stream.",
+        ),
+    };
+    schema.insert("title".to_string(), "bibimbap.data".into());
+    schema.insert(
+        "description".to_string(),
+        format!("{layout} The row's time, device and tenant are columns of their own.").into(),
+    );
+    schema.to_value()
+}
+
+#[derive(Serialize)]
+struct Description {
+    document: Value,
+    metrics: Vec<MetricDescription>,
+}
+
+pub async fn handler(version: SchemaVersion) -> Json<impl Serialize> {
+    Json(Description {
+        document: document(version),
+        metrics: describe_metrics(&metrics::collectors()),
+    })
+}
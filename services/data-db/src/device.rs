@@ -0,0 +1,63 @@
+//! Which device a frame's row is written under, so one data-db can take in several meters or
+//! feeders on the same feed and queries can tell them apart.
+//!
+//! The device comes from the message's topic or from the `device_id` the publisher puts next
+//! to each stream's calculations, and `--device-map` renames what either says. A topic is only
+//! known while the message is being received, so with `--device-from topic` it is written into
+//! the streams' `device_id` there; that way it survives frame assembly and the durable queue
+//! like a publisher's own would.
+
+use std::collections::HashMap;
+
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeJoinedCalculations;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DeviceSource {
+    /// Every row is written under --device
+    Fixed,
+    /// The last part of the message's topic, e.g. feeder-3 for site1/feeder-3. Publishers
+    /// that send the topic in the same frame as the payload only tell the subscribed topic.
+    Topic,
+    /// The device_id the publisher sends with each stream
+    Provenance,
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceConfig {
+    pub source: DeviceSource,
+    /// For frames that don't name a device, and every frame with `DeviceSource::Fixed`
+    pub default: String,
+    /// Device names by what the topic or provenance calls them; others are kept as they are
+    pub map: HashMap<String, String>,
+}
+
+impl DeviceConfig {
+    /// Names the device after `topic` in every stream of the frame, with
+    /// `DeviceSource::Topic`.
+    pub fn stamp(&self, joined: &mut CompositeJoinedCalculations, topic: &str) {
+        if self.source != DeviceSource::Topic {
+            return;
+        }
+        let Some(device) = topic.split('/').rev().find(|part| !part.is_empty()) else {
+            return;
+        };
+        for wrapper in &mut joined.calculations {
+            wrapper.device_id = Some(device.to_string());
+        }
+    }
+
+    /// The device the frame's row is written under: the first one its streams name.
+    pub fn device(&self, joined: &CompositeJoinedCalculations) -> String {
+        if self.source == DeviceSource::Fixed {
+            return self.default.clone();
+        }
+        let named = joined
+            .calculations
+            .iter()
+            .find_map(|wrapper| wrapper.device_id.as_deref());
+        match named {
+            Some(id) => self.map.get(id).cloned().unwrap_or_else(|| id.to_string()),
+            None => self.default.clone(),
+        }
+    }
+}
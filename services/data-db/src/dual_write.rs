@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres, Row};
+use stored_document::streams_lateral;
+
+use crate::metrics::{DUAL_WRITE_CHECKED_ROWS, DUAL_WRITE_DISCREPANCIES};
+
+/// Columns of `bibimbap_measurements` that mirror a key of the JSONB phase buckets.
+const FIELDS: &[&str] = &[
+    "rms_voltage",
+    "dc_offset_voltage",
+    "rms_current",
+    "dc_offset_current",
+    "real_power",
+    "apparent_power",
+    "reactive_power",
+    "power_factor",
+    "three_phase_real_power",
+    "three_phase_reactive_power",
+];
+
+/// Nullable columns for statistics not every publisher sends; the JSONB bucket omits the key.
+const OPTIONAL_FIELDS: &[&str] = &[
+    "crest_factor_voltage",
+    "thd_voltage",
+    "crest_factor_current",
+    "thd_current",
+];
+
+/// How far behind "now" the checked window ends, on top of the flush interval, so rows still
+/// being retried aren't reported as missing.
+const SETTLE: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct Comparison {
+    /// Stream/phase entries in the JSONB table
+    legacy: i64,
+    /// Entries in the JSONB table with no normalized row
+    missing: i64,
+    /// Normalized rows with no JSONB entry
+    extra: i64,
+    /// Entries present in both whose values disagree
+    different: i64,
+}
+
+fn comparison_query() -> String {
+    let key = "n.time = l.time AND n.device = l.device AND n.tenant IS NOT DISTINCT FROM l.tenant \
+               AND n.stream = l.stream AND n.phase = l.phase";
+    // serde_json writes NaN as null, so compare nulls in the JSON as NaN
+    let differs = FIELDS
+        .iter()
+        .map(|field| {
+            format!("coalesce((l.bucket->>'{field}')::float8, 'NaN') IS DISTINCT FROM n.{field}")
+        })
+        .chain(OPTIONAL_FIELDS.iter().map(|field| {
+            format!(
+                "coalesce((l.bucket->>'{field}')::float8, 'NaN') \
+                 IS DISTINCT FROM coalesce(n.{field}, 'NaN')"
+            )
+        }))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+    let streams = streams_lateral("b");
+
+    format!(
+        "WITH legacy AS (
+            SELECT b.time, b.device, b.tenant, s.key AS stream, p.phase, p.bucket
+            FROM bibimbap b
+            {streams}
+            CROSS JOIN LATERAL (VALUES
+                ('a', s.value->'phase_a'), ('b', s.value->'phase_b'), ('c', s.value->'phase_c')
+            ) AS p(phase, bucket)
+            WHERE b.time >= $1 AND b.time < $2 AND p.bucket IS NOT NULL
+        ),
+        normalized AS (
+            SELECT * FROM bibimbap_measurements WHERE time >= $1 AND time < $2
+        )
+        SELECT
+            (SELECT count(*) FROM legacy) AS legacy,
+            (SELECT count(*) FROM legacy l
+                WHERE NOT EXISTS (SELECT 1 FROM normalized n WHERE {key})) AS missing,
+            (SELECT count(*) FROM normalized n
+                WHERE NOT EXISTS (SELECT 1 FROM legacy l WHERE {key})) AS extra,
+            (SELECT count(*) FROM legacy l JOIN normalized n ON {key}
+                WHERE {differs}) AS different"
+    )
+}
+
+async fn compare(
+    pool: &Pool<Postgres>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Comparison> {
+    let row = sqlx::query(&comparison_query())
+        .bind(from)
+        .bind(to)
+        .fetch_one(pool)
+        .await
+        .context("Could not compare the JSONB and normalized tables")?;
+
+    Ok(Comparison {
+        legacy: row.get("legacy"),
+        missing: row.get("missing"),
+        extra: row.get("extra"),
+        different: row.get("different"),
+    })
+}
+
+/// Compares everything written since startup, one window at a time, and reports rows the two
+/// layouts disagree on.
+pub async fn check_periodically(
+    pool: Pool<Postgres>,
+    interval: Duration,
+    flush_interval: Duration,
+) {
+    let lag = chrono::Duration::from_std(flush_interval + SETTLE).unwrap_or_default();
+    let mut from = Utc::now();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let to = Utc::now() - lag;
+        if to <= from {
+            continue;
+        }
+
+        let comparison = match compare(&pool, from, to).await {
+            Ok(comparison) => comparison,
+            Err(err) => {
+                log::error!("{err:#}");
+                continue;
+            }
+        };
+
+        DUAL_WRITE_CHECKED_ROWS.inc_by(comparison.legacy as u64);
+        for (kind, count) in [
+            ("missing", comparison.missing),
+            ("extra", comparison.extra),
+            ("different", comparison.different),
+        ] {
+            DUAL_WRITE_DISCREPANCIES
+                .with_label_values(&[kind])
+                .inc_by(count as u64);
+        }
+
+        if comparison.missing + comparison.extra + comparison.different > 0 {
+            log::warn!("Dual-write check {from} to {to} found discrepancies: {comparison:?}");
+        } else {
+            log::info!(
+                "Dual-write check {from} to {to}: {} entries match",
+                comparison.legacy
+            );
+        }
+        from = to;
+    }
+}
@@ -0,0 +1,282 @@
+//! `--sink influx`: rows written to an InfluxDB 2 bucket as line protocol, through the
+//! `/api/v2/write` HTTP API with a token, for sites that run InfluxDB instead of Postgres.
+//!
+//! Every row becomes a point per stream and phase in --influx-measurement, tagged with
+//! `device`, `stream`, `phase` (a, b or c) and `tenant` when there is one, with the phase's
+//! numbers as fields. The stream's own values (its power totals, clock status and the like)
+//! go in one more point without a `phase` tag, nested ones as `<key>_<field>`. Values left out
+//! are left out of the point, and text isn't written. Points are stamped with the row's time
+//! in nanoseconds, so aggregated rows arrive with their `_min` and `_max` fields like any
+//! other.
+//!
+//! Rows are buffered and written --batch-size at a time, and at least every
+//! --flush-interval. A write that fails on the network, with a 5xx or with a 429 is retried
+//! --insert-max-retries times with backoff; after that the points are counted as failed and
+//! the next flush reports it, so the durable queue sends those frames again. Points InfluxDB
+//! refuses are counted as failed and dropped: sending them again would only be refused again.
+
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+
+use crate::metrics::{INFLUX_LINES, INFLUX_RETRIES};
+use crate::writer::Row;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct InfluxConfig {
+    /// e.g. http://influxdb:8086
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: Option<String>,
+    pub measurement: String,
+    /// Rows to buffer before writing them
+    pub batch_size: usize,
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+    pub timeout: Duration,
+}
+
+pub struct InfluxSink {
+    client: reqwest::Client,
+    write_url: String,
+    config: InfluxConfig,
+    lines: String,
+    points: u64,
+    rows: usize,
+    /// A write failed since the last flush reported
+    failed: bool,
+}
+
+enum Failure {
+    /// Worth sending again
+    Transient(String),
+    Refused(String),
+}
+
+impl InfluxSink {
+    pub fn new(config: InfluxConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("Could not set up the InfluxDB client")?;
+        let write_url = format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=ns",
+            config.url.trim_end_matches('/'),
+            query_escape(&config.org),
+            query_escape(&config.bucket),
+        );
+        Ok(Self {
+            client,
+            write_url,
+            config,
+            lines: String::new(),
+            points: 0,
+            rows: 0,
+            failed: false,
+        })
+    }
+
+    pub fn push(&mut self, row: &Row) {
+        self.points += write_points(&mut self.lines, &self.config.measurement, row);
+        self.rows += 1;
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.rows >= self.config.batch_size
+    }
+
+    pub async fn flush_if_full(&mut self) {
+        if self.is_full() {
+            self.write().await;
+        }
+    }
+
+    /// Writes what is buffered. False if some of it, or of what was written since the last
+    /// flush, couldn't be written for now; points InfluxDB refuses for good are dropped with
+    /// an error logged, as sending them again would only fail the same way.
+    pub async fn flush(&mut self) -> bool {
+        self.write().await;
+        !std::mem::take(&mut self.failed)
+    }
+
+    async fn write(&mut self) {
+        self.rows = 0;
+        let points = std::mem::take(&mut self.points);
+        let lines = std::mem::take(&mut self.lines);
+        if lines.is_empty() {
+            return;
+        }
+
+        let mut attempt = 0;
+        loop {
+            let reason = match self.send(lines.clone()).await {
+                Ok(()) => {
+                    INFLUX_LINES.with_label_values(&["written"]).inc_by(points);
+                    return;
+                }
+                Err(Failure::Transient(reason)) if attempt < self.config.max_retries => reason,
+                Err(Failure::Transient(reason)) => {
+                    log::error!(
+                        "Could not write {points} points to InfluxDB after {} attempts: {reason}",
+                        attempt + 1
+                    );
+                    INFLUX_LINES.with_label_values(&["failed"]).inc_by(points);
+                    self.failed = true;
+                    return;
+                }
+                Err(Failure::Refused(reason)) => {
+                    log::error!("Dropping {points} points InfluxDB refused: {reason}");
+                    INFLUX_LINES.with_label_values(&["failed"]).inc_by(points);
+                    return;
+                }
+            };
+            let backoff = self
+                .config
+                .retry_backoff
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(MAX_BACKOFF);
+            log::warn!(
+                "Transient error writing {points} points to InfluxDB, retrying in {backoff:?}: {reason}"
+            );
+            INFLUX_RETRIES.inc();
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send(&self, lines: String) -> Result<(), Failure> {
+        let mut request = self
+            .client
+            .post(&self.write_url)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(lines);
+        if let Some(token) = &self.config.token {
+            request = request.header("Authorization", format!("Token {token}"));
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|err| Failure::Transient(err.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let reason = format!("{status}: {}", response.text().await.unwrap_or_default());
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Transient(reason))
+        } else {
+            Err(Failure::Refused(reason))
+        }
+    }
+}
+
+/// Appends `row`'s points to `lines`, returning how many.
+fn write_points(lines: &mut String, measurement: &str, row: &Row) -> u64 {
+    let Value::Object(streams) = &row.data else {
+        return 0;
+    };
+    let Some(time) = row.time.timestamp_nanos_opt() else {
+        return 0;
+    };
+    let mut tags = vec![("device", row.device.as_str())];
+    if let Some(tenant) = &row.tenant {
+        tags.push(("tenant", tenant));
+    }
+
+    let mut points = 0;
+    for (stream, entry) in streams {
+        let Value::Object(entry) = entry else {
+            continue;
+        };
+        let mut stream_tags = tags.clone();
+        stream_tags.push(("stream", stream));
+
+        let mut fields = Map::new();
+        for (key, value) in entry {
+            match (key.strip_prefix("phase_"), value) {
+                (Some(phase), Value::Object(values)) => {
+                    let mut phase_tags = stream_tags.clone();
+                    phase_tags.push(("phase", phase));
+                    points += write_point(lines, measurement, &phase_tags, values, time) as u64;
+                }
+                (None, Value::Object(values)) => {
+                    for (field, value) in values {
+                        fields.insert(format!("{key}_{field}"), value.clone());
+                    }
+                }
+                _ => {
+                    fields.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        points += write_point(lines, measurement, &stream_tags, &fields, time) as u64;
+    }
+    points
+}
+
+/// One point with the numbers and flags of `fields`; nothing if there are none.
+fn write_point(
+    lines: &mut String,
+    measurement: &str,
+    tags: &[(&str, &str)],
+    fields: &Map<String, Value>,
+    time: i64,
+) -> bool {
+    let mut written = String::new();
+    for (key, value) in fields {
+        let value = match value {
+            Value::Number(number) => match number.as_f64() {
+                Some(number) if number.is_finite() => number.to_string(),
+                _ => continue,
+            },
+            Value::Bool(flag) => flag.to_string(),
+            _ => continue,
+        };
+        let separator = if written.is_empty() { "" } else { "," };
+        let _ = write!(written, "{separator}{}={value}", escape(key, ",= "));
+    }
+    if written.is_empty() {
+        return false;
+    }
+
+    lines.push_str(&escape(measurement, ", "));
+    for (key, value) in tags {
+        if !value.is_empty() {
+            let _ = write!(lines, ",{}={}", escape(key, ",= "), escape(value, ",= "));
+        }
+    }
+    let _ = writeln!(lines, " {written} {time}");
+    true
+}
+
+/// Backslash-escapes `special` and backslashes, as line protocol wants in names and tags.
+fn escape(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn query_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                escaped.push(byte as char)
+            }
+            _ => {
+                let _ = write!(escaped, "%{byte:02X}");
+            }
+        }
+    }
+    escaped
+}
@@ -0,0 +1,71 @@
+//! `--sink kafka`: every decoded frame as records on a Kafka topic, one per stream and keyed by
+//! its name, so a stream's records stay in order on one partition. JSON records carry the
+//! stream's entry of the `data` column (site total included) with the row's time, device and
+//! tenant; protobuf records a CompositeJoinedCalculations with only that stream, as it came
+//! off the feed. Kafka gets every frame --late-data-policy lets through, at full rate:
+//! --capture and --aggregate-interval only thin out what goes to Postgres.
+//!
+//! librdkafka buffers and retries on its own thread. What it gives up on is counted in
+//! data_db_kafka_records_total{outcome="failed"}, and what doesn't fit in its buffer in
+//! outcome="dropped". With Postgres as a sink too, that's all that happens, so an outage of
+//! one sink doesn't make the durable queue write the other's rows again; with Kafka alone, a
+//! flush that loses records rewinds the queue like a failed insert.
+//!
+//! The producer needs librdkafka, so it is only built with the `kafka` cargo feature, which
+//! the data-db image turns on. Without it, `--sink kafka` is refused at startup.
+
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RecordFormat {
+    /// The stream's calculations as data-db stores them, with time, device and tenant
+    #[default]
+    Json,
+    /// A CompositeJoinedCalculations holding only the stream
+    Protobuf,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+pub struct KafkaConfig {
+    /// bootstrap.servers
+    pub brokers: String,
+    pub topic: String,
+    pub format: RecordFormat,
+    /// Further librdkafka producer properties
+    pub properties: Vec<(String, String)>,
+    pub flush_timeout: Duration,
+}
+
+#[cfg(feature = "kafka")]
+mod producer;
+
+#[cfg(feature = "kafka")]
+pub use producer::KafkaSink;
+
+/// Stands in for the producer in builds without the `kafka` feature, where it can't be
+/// created.
+#[cfg(not(feature = "kafka"))]
+pub enum KafkaSink {}
+
+#[cfg(not(feature = "kafka"))]
+impl KafkaSink {
+    pub fn new(_: KafkaConfig) -> anyhow::Result<Self> {
+        anyhow::bail!("--sink kafka needs data-db built with the kafka feature")
+    }
+
+    pub fn send<C: serde::Serialize>(
+        &self,
+        _: &protobuf_rs::utilidata::karman::bibimbap::v1::CompositeJoinedCalculations,
+        _: &std::collections::HashMap<String, C>,
+        _: chrono::DateTime<chrono::Utc>,
+        _: &str,
+        _: Option<&str>,
+    ) {
+        match *self {}
+    }
+
+    pub async fn flush(&self) -> bool {
+        match *self {}
+    }
+}
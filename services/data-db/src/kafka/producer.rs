@@ -0,0 +1,146 @@
+//! The librdkafka producer behind `--sink kafka`, built with the `kafka` feature.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use prost::Message;
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeJoinedCalculations;
+use rdkafka::ClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
+use serde::Serialize;
+
+use super::{KafkaConfig, RecordFormat};
+use crate::metrics::KAFKA_RECORDS;
+
+pub struct KafkaSink {
+    producer: ThreadedProducer<Deliveries>,
+    topic: String,
+    format: RecordFormat,
+    flush_timeout: Duration,
+}
+
+/// Counts delivery reports as librdkafka hands them over.
+#[derive(Default)]
+struct Deliveries {
+    /// Records lost since the last flush
+    failed: AtomicU64,
+}
+
+impl ClientContext for Deliveries {}
+
+impl ProducerContext for Deliveries {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        match result {
+            Ok(_) => KAFKA_RECORDS.with_label_values(&["delivered"]).inc(),
+            Err((err, _)) => {
+                KAFKA_RECORDS.with_label_values(&["failed"]).inc();
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                log::warn!("Kafka did not take a record: {err}");
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a, C> {
+    time: DateTime<Utc>,
+    device: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    stream: &'a str,
+    #[serde(flatten)]
+    calculation: &'a C,
+}
+
+impl KafkaSink {
+    /// Creates the producer, which connects to the brokers in the background.
+    pub fn new(config: KafkaConfig) -> Result<Self> {
+        let mut client = ClientConfig::new();
+        client.set("bootstrap.servers", &config.brokers);
+        for (key, value) in &config.properties {
+            client.set(key, value);
+        }
+        let producer = client
+            .create_with_context(Deliveries::default())
+            .context("Could not create the Kafka producer")?;
+        log::info!(
+            "Producing to Kafka topic {} on {}",
+            config.topic,
+            config.brokers
+        );
+        Ok(Self {
+            producer,
+            topic: config.topic,
+            format: config.format,
+            flush_timeout: config.flush_timeout,
+        })
+    }
+
+    /// Queues a record for every stream of a frame: `frame`'s streams for protobuf records,
+    /// `streams` (the row's `data` entries) for JSON ones.
+    pub fn send<C: Serialize>(
+        &self,
+        frame: &CompositeJoinedCalculations,
+        streams: &HashMap<String, C>,
+        time: DateTime<Utc>,
+        device: &str,
+        tenant: Option<&str>,
+    ) {
+        match self.format {
+            RecordFormat::Json => {
+                for (stream, calculation) in streams {
+                    let record = JsonRecord {
+                        time,
+                        device,
+                        tenant,
+                        stream,
+                        calculation,
+                    };
+                    let payload = serde_json::to_vec(&record).expect("Could not serialize");
+                    self.produce(stream, &payload);
+                }
+            }
+            RecordFormat::Protobuf => {
+                for wrapper in &frame.calculations {
+                    let Some(stream) = &wrapper.calculation_name else {
+                        continue;
+                    };
+                    let payload = CompositeJoinedCalculations {
+                        calculations: vec![wrapper.clone()],
+                    }
+                    .encode_to_vec();
+                    self.produce(stream, &payload);
+                }
+            }
+        }
+    }
+
+    fn produce(&self, key: &str, payload: &[u8]) {
+        let record = BaseRecord::to(&self.topic).key(key).payload(payload);
+        if let Err((err, _)) = self.producer.send(record) {
+            KAFKA_RECORDS.with_label_values(&["dropped"]).inc();
+            self.producer
+                .context()
+                .failed
+                .fetch_add(1, Ordering::Relaxed);
+            log::warn!("Could not queue a Kafka record for {key}: {err}");
+        }
+    }
+
+    /// Waits for Kafka to acknowledge what was queued; false if anything since the last flush
+    /// was lost or is still outstanding after --kafka-flush-timeout.
+    pub async fn flush(&self) -> bool {
+        let flushed = tokio::task::block_in_place(|| self.producer.flush(self.flush_timeout));
+        if let Err(err) = &flushed {
+            log::warn!("Kafka flush failed: {err}");
+        }
+        let failed = self.producer.context().failed.swap(0, Ordering::Relaxed);
+        flushed.is_ok() && failed == 0
+    }
+}
@@ -1,148 +1,411 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Utc};
 use clap::Parser;
+use frame_assembly::{AssemblyConfig, TimeoutPolicy};
+use frame_sequence::{Sequence, SequenceTracker};
+use health::{Health, HealthArgs};
+use karman_types::{PhaseMeasurements, Skipped, StreamFrame, time_sync};
+use late_data::{Arrival, LatePolicy, LatenessConfig, OrderTracker, provenance_time};
+use logging::LogArgs;
 use prost::Message;
 use protobuf_rs::utilidata::karman::bibimbap::v1::{
-    CompositeJoinedCalculations, composite_joined_calculations_wrapper::DataProduct,
+    CompositeJoinedCalculations, TimeSync, composite_joined_calculations_wrapper::DataProduct,
 };
+use schemars::JsonSchema;
 use serde::Serialize;
-use sqlx::{Pool, Postgres, postgres::PgPoolOptions, query};
-use zeromq::{Socket, SocketRecv, SubSocket};
+use service_config::{Shared, tenant_topic};
+use shutdown::Shutdown;
+use site_total::{MissingMembers, Power, SiteTotalConfig};
+use sqlx::{Pool, Postgres};
+use stream_registry::{Registry, RegistrySettings};
+use tracing::{Instrument, Span};
+use zmq_ingest::{
+    Backoff, HwmConfig, OverflowPolicy, ReaderThreadArgs, ReconnectArgs, SubscriberConfig,
+    SubscriberStream, parse_endpoint,
+};
+
+use crate::aggregate::{AggregateConfig, Aggregator};
+use crate::assembly::{Received, Receiver};
+use crate::backfill::BackfillArgs;
+use crate::capture::{Capture, CaptureConfig, Triggers};
+use crate::dead_letter::{DeadLetterConfig, DeadLetters};
+use crate::device::{DeviceConfig, DeviceSource};
+use crate::influx::{InfluxConfig, InfluxSink};
+use crate::kafka::{KafkaConfig, KafkaSink, RecordFormat};
+use crate::metrics::{
+    FRAMES_DROPPED, FRAMES_OUT_OF_ORDER, LATE_CALCULATIONS, QUEUE_REDELIVERIES,
+    SITE_TOTAL_MISSING_MEMBERS, ZMQ_RECEIVE,
+};
+use crate::pool::PoolConfig;
+use crate::queue::{QueueLimit, QueueReader, QueueWriter};
+use crate::reports::ReportConfig;
+use crate::routing::{Route, RoutedWriter, Routes};
+use crate::row_time::{RowTime, RowTimeConfig};
+use crate::schema::SchemaMode;
+use crate::sink::{Sink, Sinks};
+use crate::stdout::{StdoutConfig, StdoutSink};
+use crate::tiering::TieringConfig;
+use crate::writer::{BatchConfig, BatchWriter, Measurement, Row, WriteMethod};
+use stored_document::SchemaVersion;
 
-#[derive(Serialize)]
+mod aggregate;
+mod assembly;
+mod backfill;
+mod capture;
+mod dead_letter;
+mod decoding;
+mod describe;
+mod device;
+mod dual_write;
+mod influx;
+mod kafka;
+mod metrics;
+mod pool;
+mod queue;
+mod reports;
+mod routing;
+mod row_time;
+mod schema;
+mod sink;
+mod stdout;
+mod tiering;
+mod writer;
+
+/// One stream's entry in the `data` column, keyed by its calculation name.
+#[derive(Serialize, JsonSchema)]
 struct Calculation {
     phase_a: Bucket,
     phase_b: Bucket,
+    /// Only from three-phase meters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase_c: Option<Bucket>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power: Option<StreamPower>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_sync: Option<TimeSyncStatus>,
+    /// When the publisher took the stream's values (the latest of its phases' timestamps);
+    /// the row's time is when data-db received them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    provenance_time: Option<DateTime<Utc>>,
+    /// False when the frame didn't follow the stream's previous one: frames before it were
+    /// lost, it arrived late, or the publisher started counting again. Left out for streams
+    /// without sequence numbers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_sequence: Option<bool>,
+}
+
+/// The stream's totals over its own phases, unlike `three_phase_*`, which sum one phase over
+/// every stream in the row. Values that can't be derived from what was sent are left out.
+#[derive(Serialize, JsonSchema)]
+struct StreamPower {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    real_power: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reactive_power: Option<f64>,
+    /// From the total real and reactive power
+    #[serde(skip_serializing_if = "Option::is_none")]
+    apparent_power: Option<f64>,
+    /// Negative while the stream exports real power
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power_factor: Option<f64>,
+    /// NEMA MG-1: largest deviation from the phase average, as a percent of the average
+    #[serde(skip_serializing_if = "Option::is_none")]
+    voltage_imbalance_percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_imbalance_percent: Option<f64>,
+    /// Estimated from the rms phase currents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    neutral_current: Option<f64>,
 }
 
-#[derive(Serialize)]
+impl StreamPower {
+    /// None when nothing could be derived.
+    fn of(phases: &[power_calc::Phase]) -> Option<Self> {
+        let summary = power_calc::summarize(phases);
+        (summary != power_calc::Summary::default()).then_some(Self {
+            real_power: summary.real_power,
+            reactive_power: summary.reactive_power,
+            apparent_power: summary.apparent_power,
+            power_factor: summary.power_factor,
+            voltage_imbalance_percent: summary.voltage_imbalance_percent,
+            current_imbalance_percent: summary.current_imbalance_percent,
+            neutral_current: summary.neutral_current,
+        })
+    }
+}
+
+/// The publisher's clock status, stored so analyses can exclude periods where `trusted` is
+/// false.
+#[derive(Serialize, JsonSchema)]
+struct TimeSyncStatus {
+    /// unspecified, free_running, ntp, ptp or gps
+    source: &'static str,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset_ns: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_error_ns: Option<u64>,
+    trusted: bool,
+}
+
+impl TimeSyncStatus {
+    fn new(sync: &TimeSync, max_clock_offset: Duration) -> Self {
+        Self {
+            source: time_sync::source_label(sync.source()),
+            locked: sync.locked(),
+            offset_ns: sync.offset_ns,
+            max_error_ns: sync.max_error_ns,
+            trusted: time_sync::is_trusted(sync, max_clock_offset),
+        }
+    }
+}
+
+/// One phase's values. Values the publisher left out are stored as null.
+#[derive(Serialize, JsonSchema)]
 struct Bucket {
+    #[schemars(with = "Option<f64>", required)]
     rms_current: f64,
+    #[schemars(with = "Option<f64>", required)]
     rms_voltage: f64,
+    #[schemars(with = "Option<f64>", required)]
     dc_offset_voltage: f64,
+    #[schemars(with = "Option<f64>", required)]
     dc_offset_current: f64,
+    #[schemars(with = "Option<f64>", required)]
     real_power: f64,
+    #[schemars(with = "Option<f64>", required)]
     apparent_power: f64,
+    #[schemars(with = "Option<f64>", required)]
     reactive_power: f64,
+    #[schemars(with = "Option<f64>", required)]
     power_factor: f64,
+    /// Sum over every stream in the frame that sent its power
     three_phase_real_power: f64,
     three_phase_reactive_power: f64,
+    /// Extended waveform statistics, only sent by some publishers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crest_factor_voltage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thd_voltage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crest_factor_current: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thd_current: Option<f64>,
 }
 
-fn into_json(value: CompositeJoinedCalculations) -> serde_json::Value {
-    let mut outside = HashMap::new();
+fn into_calculations(
+    value: &CompositeJoinedCalculations,
+    max_clock_offset: Duration,
+) -> HashMap<String, Calculation> {
+    let frame = StreamFrame::from(value);
+    for skipped in frame.skipped {
+        // Not malformed, just not something data-db stores
+        if skipped != Skipped::NotCalculations {
+            decoding::skipped(skipped.reason());
+        }
+    }
+    let streams: Vec<_> = frame
+        .streams
+        .into_iter()
+        .map(|stream| {
+            decoding::count(&stream);
+            let calc = stream.calculations;
+            let power = StreamPower::of(&power_calc::phases(&calc));
+            let time_sync = [calc.phase_a, calc.phase_b, calc.phase_c]
+                .iter()
+                .flatten()
+                .find_map(|phase| phase.provenance?.time_sync)
+                .map(|sync| TimeSyncStatus::new(&sync, max_clock_offset));
+            let provenance = provenance_time(&calc).map(DateTime::<Utc>::from);
+            (stream, power, time_sync, provenance)
+        })
+        .collect();
 
-    // let's do some calculations!!!!
+    // A stream whose power is missing adds nothing, rather than making the sums NaN
+    let known = |value: f64| if value.is_nan() { 0.0 } else { value };
     let mut real_power_three_phase_a = 0.0;
     let mut reactive_power_three_phase_a = 0.0;
     let mut real_power_three_phase_b = 0.0;
     let mut reactive_power_three_phase_b = 0.0;
-    for j in value.calculations.iter() {
-        let DataProduct::Calculations(calc) = j.data_product.as_ref().unwrap() else {
-            continue;
-        };
-        real_power_three_phase_a += calc
-            .phase_a
-            .unwrap()
-            .power_calculations
-            .unwrap()
-            .real_power_w() as f64;
-
-        reactive_power_three_phase_a += calc
-            .phase_a
-            .unwrap()
-            .power_calculations
-            .unwrap()
-            .reactive_power_var() as f64;
-
-        real_power_three_phase_b += calc
-            .phase_b
-            .unwrap()
-            .power_calculations
-            .unwrap()
-            .real_power_w() as f64;
-
-        reactive_power_three_phase_b += calc
-            .phase_b
-            .unwrap()
-            .power_calculations
-            .unwrap()
-            .reactive_power_var() as f64;
-    }
-
-    for joined in value.calculations.iter() {
-        let DataProduct::Calculations(calc) = joined.data_product.as_ref().unwrap() else {
-            continue;
-        };
+    let mut real_power_three_phase_c = 0.0;
+    let mut reactive_power_three_phase_c = 0.0;
+    for (stream, _, _, _) in &streams {
+        real_power_three_phase_a += known(stream.phase_a.real_power);
+        reactive_power_three_phase_a += known(stream.phase_a.reactive_power);
+        real_power_three_phase_b += known(stream.phase_b.real_power);
+        reactive_power_three_phase_b += known(stream.phase_b.reactive_power);
+        if let Some(phase_c) = &stream.phase_c {
+            real_power_three_phase_c += known(phase_c.real_power);
+            reactive_power_three_phase_c += known(phase_c.reactive_power);
+        }
+    }
 
-        let pa = calc.phase_a.unwrap();
-        let pb = calc.phase_b.unwrap();
-
-        let calculation = Calculation {
-            phase_a: Bucket {
-                rms_current: pa.current_waveform_calculations_a.as_ref().unwrap().rms() as f64,
-                rms_voltage: pa.voltage_waveform_calculations_v.as_ref().unwrap().rms() as f64,
-                dc_offset_voltage: pa
-                    .voltage_waveform_calculations_v
-                    .as_ref()
-                    .unwrap()
-                    .dc_offset() as f64,
-                dc_offset_current: pa
-                    .current_waveform_calculations_a
-                    .as_ref()
-                    .unwrap()
-                    .dc_offset() as f64,
-                real_power: pa.power_calculations.as_ref().unwrap().real_power_w() as f64,
-                apparent_power: pa.power_calculations.as_ref().unwrap().apparent_power_va() as f64,
-                reactive_power: pa.power_calculations.as_ref().unwrap().reactive_power_var() as f64,
-                power_factor: pa.power_calculations.as_ref().unwrap().power_factor() as f64,
-                three_phase_reactive_power: reactive_power_three_phase_a,
-                three_phase_real_power: real_power_three_phase_a,
-            },
-            phase_b: Bucket {
-                rms_current: pb.current_waveform_calculations_a.as_ref().unwrap().rms() as f64,
-                rms_voltage: pb.voltage_waveform_calculations_v.as_ref().unwrap().rms() as f64,
-                dc_offset_voltage: pb
-                    .voltage_waveform_calculations_v
-                    .as_ref()
-                    .unwrap()
-                    .dc_offset() as f64,
-                dc_offset_current: pb
-                    .current_waveform_calculations_a
-                    .as_ref()
-                    .unwrap()
-                    .dc_offset() as f64,
-                real_power: pb.power_calculations.as_ref().unwrap().real_power_w() as f64,
-                apparent_power: pb.power_calculations.as_ref().unwrap().apparent_power_va() as f64,
-                reactive_power: pb.power_calculations.as_ref().unwrap().reactive_power_var() as f64,
-                power_factor: pb.power_calculations.as_ref().unwrap().power_factor() as f64,
-                three_phase_reactive_power: reactive_power_three_phase_b,
-                three_phase_real_power: real_power_three_phase_b,
-            },
-        };
+    streams
+        .into_iter()
+        .map(|(stream, power, time_sync, provenance)| {
+            let calculation = Calculation {
+                phase_a: Bucket::new(
+                    stream.phase_a,
+                    real_power_three_phase_a,
+                    reactive_power_three_phase_a,
+                ),
+                phase_b: Bucket::new(
+                    stream.phase_b,
+                    real_power_three_phase_b,
+                    reactive_power_three_phase_b,
+                ),
+                phase_c: stream.phase_c.map(|phase_c| {
+                    Bucket::new(
+                        phase_c,
+                        real_power_three_phase_c,
+                        reactive_power_three_phase_c,
+                    )
+                }),
+                power,
+                time_sync,
+                provenance_time: provenance,
+                in_sequence: None,
+            };
+            (stream.name, calculation)
+        })
+        .collect()
+}
 
-        outside.insert(
-            joined.calculation_name.as_ref().unwrap().clone(),
-            calculation,
-        );
+/// The normalized layout of `calculations`, when `schema_mode` writes it.
+fn measurements(
+    calculations: &HashMap<String, Calculation>,
+    schema_mode: SchemaMode,
+) -> Vec<Measurement> {
+    match schema_mode {
+        SchemaMode::Json => Vec::new(),
+        SchemaMode::Dual | SchemaMode::Columns => calculations
+            .iter()
+            .flat_map(|(stream, calculation)| {
+                [
+                    Some(calculation.phase_a.measurement(stream, "a")),
+                    Some(calculation.phase_b.measurement(stream, "b")),
+                    calculation
+                        .phase_c
+                        .as_ref()
+                        .map(|phase_c| phase_c.measurement(stream, "c")),
+                ]
+            })
+            .flatten()
+            .collect(),
     }
-    serde_json::to_value(&outside).expect("Could not serialize")
 }
 
-async fn prepare_subscribe(endpoint: &str) -> Result<SubSocket> {
-    let mut subsocket = SubSocket::new();
-    log::info!("about to bind to socket {}", endpoint);
-    subsocket
-        .connect(endpoint)
-        .await
-        .context("Could not bind to socket")?;
+/// The site total of a frame as one more stream, with only its power quantities. Counts the
+/// members it is missing.
+fn site_total(
+    config: &SiteTotalConfig,
+    joined: &CompositeJoinedCalculations,
+) -> Option<Calculation> {
+    let streams = joined.calculations.iter().filter_map(|wrapper| {
+        match (&wrapper.calculation_name, &wrapper.data_product) {
+            (Some(name), Some(DataProduct::Calculations(calcs))) => Some((name.as_str(), calcs)),
+            _ => None,
+        }
+    });
+    let total = config.sum(streams)?;
+    for member in &total.missing {
+        SITE_TOTAL_MISSING_MEMBERS
+            .with_label_values(&[member])
+            .inc();
+    }
+    if !config.emits(&total) {
+        return None;
+    }
 
-    log::info!("Bound to socket {}", endpoint);
-    Ok(subsocket)
+    let bucket = |power: Power| {
+        let values = PhaseMeasurements {
+            real_power: power.real,
+            reactive_power: power.reactive,
+            apparent_power: power.apparent,
+            ..PhaseMeasurements::MISSING
+        };
+        Bucket::new(values, power.real, power.reactive)
+    };
+    let phase = |power: Power| power_calc::Phase {
+        real_power: Some(power.real),
+        reactive_power: Some(power.reactive),
+        ..Default::default()
+    };
+    let phases: Vec<_> = [Some(total.phase_a), Some(total.phase_b), total.phase_c]
+        .into_iter()
+        .flatten()
+        .map(phase)
+        .collect();
+    Some(Calculation {
+        phase_a: bucket(total.phase_a),
+        phase_b: bucket(total.phase_b),
+        phase_c: total.phase_c.map(bucket),
+        power: StreamPower::of(&phases),
+        time_sync: None,
+        provenance_time: None,
+        in_sequence: None,
+    })
+}
+
+impl Bucket {
+    fn new(
+        values: PhaseMeasurements,
+        three_phase_real_power: f64,
+        three_phase_reactive_power: f64,
+    ) -> Self {
+        Self {
+            rms_current: values.rms_current,
+            rms_voltage: values.rms_voltage,
+            dc_offset_voltage: values.dc_offset_voltage,
+            dc_offset_current: values.dc_offset_current,
+            real_power: values.real_power,
+            apparent_power: values.apparent_power,
+            reactive_power: values.reactive_power,
+            power_factor: values.power_factor,
+            three_phase_real_power,
+            three_phase_reactive_power,
+            crest_factor_voltage: values.crest_factor_voltage,
+            thd_voltage: values.thd_voltage,
+            crest_factor_current: values.crest_factor_current,
+            thd_current: values.thd_current,
+        }
+    }
+
+    fn measurement(&self, stream: &str, phase: &'static str) -> Measurement {
+        Measurement {
+            stream: stream.to_string(),
+            phase,
+            rms_voltage: self.rms_voltage,
+            dc_offset_voltage: self.dc_offset_voltage,
+            rms_current: self.rms_current,
+            dc_offset_current: self.dc_offset_current,
+            real_power: self.real_power,
+            apparent_power: self.apparent_power,
+            reactive_power: self.reactive_power,
+            power_factor: self.power_factor,
+            three_phase_real_power: self.three_phase_real_power,
+            three_phase_reactive_power: self.three_phase_reactive_power,
+            crest_factor_voltage: self.crest_factor_voltage,
+            thd_voltage: self.thd_voltage,
+            crest_factor_current: self.crest_factor_current,
+            thd_current: self.thd_current,
+        }
+    }
 }
 
-async fn listen(args: Args, pool: Pool<Postgres>) {
+/// Writes until shutdown is requested, then flushes what it holds. Reconnects whenever the
+/// subscription fails; errors once it can no longer write.
+async fn listen(
+    args: Args,
+    pool: Option<Pool<Postgres>>,
+    registry: Option<Registry>,
+    routes: Routes,
+    health: Health,
+    shutdown: &Shutdown,
+) -> Result<()> {
     let endpoint = match args.resolve_endpoint() {
         Ok(endpoint) => endpoint,
         Err(err) => {
@@ -151,76 +414,912 @@ async fn listen(args: Args, pool: Pool<Postgres>) {
         }
     };
 
-    let mut subscription = match prepare_subscribe(&endpoint).await {
-        Ok(sock) => sock,
+    let config = SubscriberConfig {
+        endpoint,
+        topic: args.zmq_topic.clone(),
+        hwm: args.hwm(),
+        reader_thread: args.reader_thread.reader_thread(),
+    };
+    // Connecting waits for the publisher to come up
+    let subscribed = tokio::select! {
+        subscribed = SubscriberStream::connect(config, &ZMQ_RECEIVE) => subscribed,
+        _ = shutdown.requested() => return Ok(()),
+    };
+    let mut subscription = match subscribed {
+        Ok(subscription) => subscription,
         Err(err) => {
             log::error!("Could not prepare subscription: {err:#?}");
             std::process::exit(255);
         }
     };
+    let dead_letters = match args.dead_letters() {
+        Some(config) => match DeadLetters::open(config, &args.zmq_topic) {
+            Ok(dead_letters) => Some(dead_letters),
+            Err(err) => {
+                log::error!("Could not open dead-letter spool: {err:#}");
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+    if let Some(dead_letters) = dead_letters.clone() {
+        subscription.on_rejected(move |rejected| {
+            dead_letters.message(
+                &rejected.message,
+                rejected.received.into(),
+                &rejected.reason,
+            );
+        });
+    }
+    let receiver = Receiver::new(
+        subscription,
+        args.assembly(),
+        args.reconnect.backoff(),
+        args.devices(),
+        health.source("zmq"),
+    );
+    let capture = args.capture().zip(pool.clone()).map(|(config, pool)| {
+        if config.triggers.is_empty() {
+            log::warn!("--capture without triggers only downsamples");
+        }
+        let highres = BatchConfig {
+            table: "bibimbap_highres".to_string(),
+            schema_mode: SchemaMode::Json,
+            max_buffered_rows: 0,
+            ..args.batch_config()
+        };
+        Capture::new(
+            config,
+            pool.clone(),
+            BatchWriter::new(pool.clone(), highres),
+        )
+    });
+    let kafka = match args.kafka() {
+        Some(config) => match KafkaSink::new(config) {
+            Ok(kafka) => Some(Arc::new(kafka)),
+            Err(err) => {
+                log::error!("{err:#}");
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+    let postgres = pool
+        .filter(|_| args.sinks.contains(&Sink::Postgres))
+        .map(|pool| {
+            RoutedWriter::new(routes, |table| {
+                let config = BatchConfig {
+                    table,
+                    ..args.batch_config()
+                };
+                BatchWriter::new(pool.clone(), config).with_dead_letters(dead_letters.clone())
+            })
+        });
+    let influx = match args.influx().map(InfluxSink::new) {
+        Some(Ok(influx)) => Some(influx),
+        Some(Err(err)) => {
+            log::error!("{err:#}");
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    let writer = Sinks::new(
+        postgres,
+        influx,
+        kafka.clone(),
+        args.stdout().map(StdoutSink::new),
+        args.flush_interval,
+    );
+    let stages = Stages {
+        capture,
+        aggregator: args.aggregate().map(Aggregator::new),
+    };
+    match &args.durable_queue_dir {
+        Some(dir) => {
+            let (queue_writer, queue_reader) =
+                match queue::open(dir, args.queue_segment_bytes(), args.queue_limit()) {
+                    Ok(queue) => queue,
+                    Err(err) => {
+                        log::error!("Could not open durable queue: {err:#}");
+                        std::process::exit(2);
+                    }
+                };
+            let receiving =
+                tokio::spawn(receive_into_queue(receiver, queue_writer, shutdown.clone()));
+            write_from_queue(
+                queue_reader,
+                writer,
+                stages,
+                args.decoder(registry, dead_letters, kafka),
+                shutdown,
+            )
+            .await?;
+            receiving.await.context("Durable queue receiver failed")
+        }
+        None => {
+            let decoder = args.decoder(registry, dead_letters, kafka);
+            write_direct(receiver, writer, stages, decoder, shutdown).await
+        }
+    }
+}
 
-    subscription
-        .subscribe(&args.zmq_topic.clone())
-        .await
-        .expect("Could not subscribe");
-    loop {
-        let incoming = match subscription.recv().await {
-            Ok(message) => message,
+/// Turns raw frames into rows.
+struct Decoder {
+    /// What queued frames start with
+    topic: Vec<u8>,
+    tenant: Option<String>,
+    devices: DeviceConfig,
+    max_clock_offset: Duration,
+    row_time: RowTimeConfig,
+    schema_mode: SchemaMode,
+    registry: Option<Registry>,
+    site_total: Option<SiteTotalConfig>,
+    order: OrderTracker,
+    /// `order` as of the last durable queue commit, for going back to on a rewind
+    committed_order: OrderTracker,
+    sequence: SequenceTracker,
+    /// `sequence` as of the last durable queue commit
+    committed_sequence: SequenceTracker,
+    dead_letters: Option<DeadLetters>,
+    kafka: Option<Arc<KafkaSink>>,
+}
+
+impl Decoder {
+    /// Decodes a frame from the durable queue (still carrying its topic prefix) in a `decode`
+    /// span under `span`, naming `span` after it.
+    fn queued_frame(
+        &mut self,
+        span: &Span,
+        frame: &[u8],
+        received: DateTime<Utc>,
+    ) -> Option<CompositeJoinedCalculations> {
+        // Frames queued under a different --zmq-topic can't be told apart from their payload
+        let Some(buf) = frame.strip_prefix(self.topic.as_slice()) else {
+            let reason = "queued frame does not start with the topic";
+            log::error!("{reason}, skipping it");
+            if let Some(dead_letters) = &self.dead_letters {
+                dead_letters.message(frame, received, reason);
+            }
+            return None;
+        };
+
+        let decoded = tracing::info_span!(parent: span, "decode")
+            .in_scope(|| CompositeJoinedCalculations::decode(buf));
+        match decoded {
+            Ok(joined) => {
+                logging::record_frame(span, &joined);
+                Some(joined)
+            }
             Err(err) => {
-                log::error!("Unable to receive message: {err:#?}");
-                std::process::exit(255);
+                log::error!("Could not decode queued frame: {err:#?}");
+                if let Some(dead_letters) = &self.dead_letters {
+                    dead_letters.message(frame, received, &err.to_string());
+                }
+                None
+            }
+        }
+    }
+
+    /// Turns a frame into a row stamped with when it arrived, leaving out duplicate streams and
+    /// the ones --late-data-policy rejects.
+    fn row(&mut self, mut joined: CompositeJoinedCalculations, received: DateTime<Utc>) -> Row {
+        let device = self.devices.device(&joined);
+        let multi_device = self.devices.source != DeviceSource::Fixed;
+        let order = &mut self.order;
+        let sequence = &mut self.sequence;
+        let mut in_sequence = HashMap::new();
+        joined.calculations.retain(|wrapper| {
+            let (Some(stream), Some(DataProduct::Calculations(calcs))) =
+                (&wrapper.calculation_name, &wrapper.data_product)
+            else {
+                return true;
+            };
+            // Devices may well have streams of the same name
+            let key = if multi_device {
+                format!("{device}/{stream}")
+            } else {
+                stream.clone()
+            };
+            if let Some(number) = frame_sequence::sequence_number(calcs) {
+                let checked = sequence.check_number(&key, number);
+                match checked {
+                    Sequence::Gap { missing } => {
+                        FRAMES_DROPPED.with_label_values(&[stream]).inc_by(missing);
+                        log::debug!("{stream} skipped {missing} frames before {number}");
+                    }
+                    Sequence::Restarted => {
+                        log::info!("{stream} started counting again at {number}");
+                    }
+                    _ => {}
+                }
+                if let Some(kind) = checked.out_of_order() {
+                    FRAMES_OUT_OF_ORDER.with_label_values(&[stream, kind]).inc();
+                    log::debug!("{stream} frame {number} arrived out of order: {kind}");
+                }
+                if checked == Sequence::Duplicate {
+                    return false;
+                }
+                in_sequence.insert(stream.clone(), checked == Sequence::InSequence);
+            }
+            let arrival = order.check(&key, calcs);
+            if let Some(outcome) = arrival.outcome() {
+                LATE_CALCULATIONS.with_label_values(&[outcome]).inc();
+                log::debug!("{stream} arrived out of order: {outcome}");
             }
+            arrival != Arrival::Rejected
+        });
+        let site_total = self
+            .site_total
+            .as_ref()
+            .and_then(|config| Some((&config.stream, site_total(config, &joined)?)));
+        let mut calculations = into_calculations(&joined, self.max_clock_offset);
+        for (stream, in_sequence) in in_sequence {
+            if let Some(calculation) = calculations.get_mut(&stream) {
+                calculation.in_sequence = Some(in_sequence);
+            }
+        }
+        if let Some((stream, total)) = site_total {
+            calculations.insert(stream.clone(), total);
+        }
+        if let Some(kafka) = &self.kafka {
+            kafka.send(
+                &joined,
+                &calculations,
+                received,
+                &device,
+                self.tenant.as_deref(),
+            );
+        }
+        if let Some(registry) = &self.registry {
+            for stream in calculations.keys() {
+                registry.observe(stream);
+            }
+        }
+        let measurements = measurements(&calculations, self.schema_mode);
+
+        let provenance = calculations
+            .values()
+            .filter_map(|calc| calc.provenance_time);
+        Row {
+            time: self.row_time.time(received, provenance),
+            device,
+            tenant: self.tenant.clone(),
+            data: serde_json::to_value(&calculations).expect("Could not serialize"),
+            measurements,
+        }
+    }
+
+    /// Rows up to here are committed to the durable queue.
+    fn commit_order(&mut self) {
+        self.committed_order = self.order.clone();
+        self.committed_sequence = self.sequence.clone();
+    }
+
+    /// The durable queue went back to its last commit, so frames seen since then are about
+    /// to come round again and mustn't count as late or duplicate.
+    fn rewind_order(&mut self) {
+        self.order = self.committed_order.clone();
+        self.sequence = self.committed_sequence.clone();
+    }
+}
+
+/// What rows go through on their way to the writer, when turned on. --capture and
+/// --aggregate-interval don't go together.
+struct Stages {
+    capture: Option<Capture>,
+    aggregator: Option<Aggregator>,
+}
+
+impl Stages {
+    /// Hands a row to the writer. `offset` is where its frame starts in the durable queue.
+    async fn store(&mut self, row: Row, offset: u64, writer: &mut Sinks) {
+        if let Some(aggregator) = &mut self.aggregator {
+            for row in aggregator.push(row, offset) {
+                writer.push(row);
+            }
+            return;
+        }
+        let row = match &mut self.capture {
+            Some(capture) => capture.process(row).await,
+            None => Some(row),
         };
+        if let Some(row) = row {
+            writer.push(row);
+        }
+    }
 
-        let as_vec = incoming.into_vec();
+    /// Passes on what is due by now: high-res captures, and the intervals that have ended.
+    async fn flush(&mut self, writer: &mut Sinks) {
+        if let Some(capture) = &mut self.capture {
+            capture.flush().await;
+        }
+        if let Some(aggregator) = &mut self.aggregator {
+            for row in aggregator.expire(Utc::now()) {
+                writer.push(row);
+            }
+        }
+    }
 
-        let Some(frame) = as_vec.first() else {
-            log::error!("Weird frameless message");
-            continue;
+    /// Passes on everything held, on shutdown.
+    async fn drain(&mut self, writer: &mut Sinks) {
+        if let Some(capture) = &mut self.capture {
+            capture.flush().await;
+        }
+        if let Some(aggregator) = &mut self.aggregator {
+            for row in aggregator.drain() {
+                writer.push(row);
+            }
+        }
+    }
+}
+
+async fn write_direct(
+    mut receiver: Receiver,
+    mut writer: Sinks,
+    mut stages: Stages,
+    mut decoder: Decoder,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let mut flush_timer = tokio::time::interval(writer.flush_interval());
+    loop {
+        let received = tokio::select! {
+            received = receiver.next() => received,
+            _ = flush_timer.tick() => {
+                stages.flush(&mut writer).await;
+                writer.flush().await;
+                continue;
+            }
+            _ = shutdown.requested() => break,
         };
 
-        let buf = &frame[args.zmq_topic.len()..];
+        let frames = match received {
+            Ok(frames) => frames,
+            Err(err) => {
+                log::error!("Subscription failed: {err:#}");
+                // Write what is buffered rather than hold it through the outage
+                stages.flush(&mut writer).await;
+                writer.flush().await;
+                if !receiver.reconnect(shutdown).await {
+                    break;
+                }
+                continue;
+            }
+        };
+        write_frames(frames, &mut decoder, &mut writer, &mut stages).await;
+    }
+
+    let frames = receiver.close().await;
+    write_frames(frames, &mut decoder, &mut writer, &mut stages).await;
+    stages.drain(&mut writer).await;
+    if !writer.flush().await {
+        bail!("Could not write the last rows before shutting down");
+    }
+    Ok(())
+}
+
+async fn write_frames(
+    frames: Vec<Received>,
+    decoder: &mut Decoder,
+    writer: &mut Sinks,
+    stages: &mut Stages,
+) {
+    for received in frames {
+        store_frame(
+            logging::frame_span(&received.joined),
+            received.joined,
+            received.received,
+            0,
+            decoder,
+            stages,
+            writer,
+        )
+        .await;
+        writer.flush_if_full().await;
+    }
+}
 
-        let joined = match CompositeJoinedCalculations::decode(buf) {
-            Ok(joined) => joined,
+/// Turns a frame into a row and hands it to the writer, in its `frame` span.
+async fn store_frame(
+    frame: Span,
+    joined: CompositeJoinedCalculations,
+    received: DateTime<Utc>,
+    offset: u64,
+    decoder: &mut Decoder,
+    stages: &mut Stages,
+    writer: &mut Sinks,
+) {
+    let row = frame
+        .in_scope(|| tracing::info_span!("transform").in_scope(|| decoder.row(joined, received)));
+    stages
+        .store(row, offset, writer)
+        .instrument(tracing::info_span!(parent: &frame, "sink"))
+        .await;
+}
+
+/// Subscriber half of at-least-once mode: frames go to disk before anything else looks at
+/// them.
+async fn receive_into_queue(mut receiver: Receiver, mut queue: QueueWriter, shutdown: Shutdown) {
+    let topic = receiver.topic().as_bytes().to_vec();
+    loop {
+        let received = tokio::select! {
+            received = receiver.next() => received,
+            _ = shutdown.requested() => break,
+        };
+        let frames = match received {
+            Ok(frames) => frames,
             Err(err) => {
-                log::error!("Could not decode incoming message: {err:#?}");
+                log::error!("Subscription failed: {err:#}");
+                if !receiver.reconnect(&shutdown).await {
+                    break;
+                }
                 continue;
             }
         };
+        append_frames(frames, &topic, &mut queue);
+    }
 
-        let as_json = into_json(joined);
+    let frames = receiver.close().await;
+    append_frames(frames, &topic, &mut queue);
+}
 
-        if let Err(err) = query("INSERT INTO bibimbap (time, device, data) VALUES ($1, $2, $3)")
-            .bind(chrono::Utc::now())
-            .bind("bibimbap")
-            .bind(as_json)
-            .execute(&pool)
-            .await
-        {
-            log::error!("Could not write to table: {err:#?}");
+/// Queued frames keep the topic in front of the protobuf payload, as they arrived before
+/// frames were decoded on receipt.
+fn append_frames(frames: Vec<Received>, topic: &[u8], queue: &mut QueueWriter) {
+    for received in frames {
+        let mut frame = topic.to_vec();
+        frame.extend_from_slice(&received.joined.encode_to_vec());
+        if let Err(err) = tokio::task::block_in_place(|| queue.append(received.received, &frame)) {
+            log::error!("{err:#}");
+            std::process::exit(255);
         }
     }
 }
 
+/// Database half of at-least-once mode: the committed offset only moves past frames whose
+/// rows were written, and a failed write rewinds to re-read everything since the last commit.
+/// High-res captures are best effort: a failed one is not retried. Aggregated intervals are
+/// only written once a later frame closes them, so a replayed backlog is summarized the same
+/// way it was received. On shutdown, frames not yet read stay in the queue for the next start.
+async fn write_from_queue(
+    mut queue: QueueReader,
+    mut writer: Sinks,
+    mut stages: Stages,
+    mut decoder: Decoder,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let mut flush_timer = tokio::time::interval(writer.flush_interval());
+    let mut pending = None;
+    loop {
+        let frame = tokio::select! {
+            frame = queue.next() => frame,
+            _ = flush_timer.tick() => {
+                if let Some(capture) = stages.capture.as_mut() {
+                    capture.flush().await;
+                }
+                flush_and_commit(&mut queue, &mut writer, &mut stages, &mut decoder, &mut pending)
+                    .await?;
+                continue;
+            }
+            _ = shutdown.requested() => break,
+        };
+
+        let frame = frame.context("Could not read from durable queue")?;
+        pending = Some(frame.next);
+        let span = logging::undecoded_frame_span();
+        if let Some(joined) = decoder.queued_frame(&span, &frame.payload, frame.received) {
+            store_frame(
+                span,
+                joined,
+                frame.received,
+                frame.offset,
+                &mut decoder,
+                &mut stages,
+                &mut writer,
+            )
+            .await;
+        }
+        if writer.is_full() {
+            flush_and_commit(
+                &mut queue,
+                &mut writer,
+                &mut stages,
+                &mut decoder,
+                &mut pending,
+            )
+            .await?;
+        }
+    }
+
+    stages.drain(&mut writer).await;
+    flush_and_commit(
+        &mut queue,
+        &mut writer,
+        &mut stages,
+        &mut decoder,
+        &mut pending,
+    )
+    .await
+}
+
+async fn flush_and_commit(
+    queue: &mut QueueReader,
+    writer: &mut Sinks,
+    stages: &mut Stages,
+    decoder: &mut Decoder,
+    pending: &mut Option<u64>,
+) -> Result<()> {
+    let Some(read) = *pending else {
+        return Ok(());
+    };
+
+    if writer.flush().await {
+        // Frames of intervals still open are read again after a restart
+        let offset = match &stages.aggregator {
+            Some(aggregator) => aggregator.committable(read),
+            None => read,
+        };
+        tokio::task::block_in_place(|| queue.commit(offset))
+            .context("Could not commit durable queue offset")?;
+        // Otherwise frames read again after a rewind would count as late
+        if offset == read {
+            decoder.commit_order();
+        }
+    } else {
+        log::warn!("Write failed, re-reading durable queue from the last committed offset");
+        QUEUE_REDELIVERIES.inc();
+        queue.rewind();
+        decoder.rewind_order();
+        if let Some(aggregator) = &mut stages.aggregator {
+            aggregator.clear();
+        }
+        tokio::time::sleep(writer.flush_interval()).await;
+    }
+    *pending = None;
+    Ok(())
+}
+
+/// How the shared sections of a --config file map onto data-db's flags.
+const SHARED_SETTINGS: &[Shared] = &[
+    Shared {
+        section: "zmq",
+        key: "endpoint",
+        setting: "zmq-endpoint",
+    },
+    Shared {
+        section: "zmq",
+        key: "topic",
+        setting: "zmq-topic",
+    },
+    Shared {
+        section: "database",
+        key: "url",
+        setting: "connection-string",
+    },
+    Shared {
+        section: "prometheus",
+        key: "port",
+        setting: "prometheus-port",
+    },
+];
+
 #[derive(Parser, Clone)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML or YAML file of settings shared with the rest of the pipeline, plus data-db's
+    /// own flags under [db]. Flags given on the command line take precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Needed to write to Postgres (--sink postgres, the default) and for everything that
+    /// reads or manages the tables
     #[arg(long)]
-    connection_string: String,
+    connection_string: Option<String>,
+    /// Connections the pool keeps open to Postgres at most
+    #[arg(long, default_value_t = 5)]
+    db_max_connections: u32,
+    /// Longest wait for a database connection, whether opening one or waiting for one of the
+    /// pool's to free up
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    db_connect_timeout: Duration,
+    /// Cap on how long any one statement may run, migrations included (Postgres'
+    /// statement_timeout). Unlimited without it, unless the server or role sets one.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    db_statement_timeout: Option<Duration>,
+    /// Times to retry connecting at startup while Postgres is unreachable or still starting
+    /// up, so data-db can start before it does. 0 exits on the first failure.
+    #[arg(long, default_value_t = 10)]
+    db_connect_retries: u32,
+    /// Longest wait before the first startup retry, doubled after every failed one. Each wait
+    /// is randomly shortened by up to half.
+    #[arg(long, default_value = "500ms", value_parser = humantime::parse_duration)]
+    db_connect_backoff: Duration,
+    /// Cap on the wait between startup retries
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    db_connect_max_backoff: Duration,
+    /// tcp://HOST:PORT, or ipc://PATH for a publisher on the same host; overrides
+    /// --zmq-host and --zmq-port
     #[arg(long)]
     zmq_endpoint: Option<String>,
     #[arg(long, default_value = "127.0.0.1")]
     zmq_host: String,
     #[arg(long)]
     zmq_port: Option<u16>,
-    #[arg(long)]
+    #[arg(long, default_value = "")]
     zmq_topic: String,
+    /// Messages read ahead of the writer before --zmq-overflow-policy applies (0 for no
+    /// limit). Drops are counted in data_db_zmq_dropped_messages_total.
+    #[arg(long, default_value_t = 1000)]
+    zmq_rcvhwm: usize,
+    /// Which message to discard when the receive queue is full
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropNewest)]
+    zmq_overflow_policy: OverflowPolicy,
+    #[command(flatten)]
+    reader_thread: ReaderThreadArgs,
+    #[command(flatten)]
+    reconnect: ReconnectArgs,
+    /// Create the database, table, indexes and grants, then exit
+    #[arg(long)]
+    init_schema: bool,
+    /// With --init-schema: role granted SELECT and INSERT on the table (repeatable)
+    #[arg(long)]
+    grant_writer: Vec<String>,
+    /// With --init-schema: role granted SELECT on the table (repeatable)
+    #[arg(long)]
+    grant_reader: Vec<String>,
+    /// With --init-schema: role that may also update and delete rows, as --tiering needs
+    /// (repeatable)
+    #[arg(long)]
+    grant_storage_manager: Vec<String>,
+    /// With --init-schema: role granted SELECT on one tenant's rows only, as ROLE=TENANT
+    /// (repeatable). Enables row-level security on the tables.
+    #[arg(long, value_parser = parse_tenant_reader)]
+    grant_tenant_reader: Vec<(String, String)>,
+    /// Customer site this instance writes for. Rows are tagged with it, and the topic must
+    /// start with `<tenant>/` (the default topic then).
+    #[arg(long)]
+    tenant: Option<String>,
+    /// Where the device column comes from, for feeds carrying several meters or feeders
+    #[arg(long, value_enum, default_value_t = DeviceSource::Fixed)]
+    device_from: DeviceSource,
+    /// Device rows are written under when --device-from finds none. The stream registry and
+    /// daily reports use it too.
+    #[arg(long, default_value = "bibimbap")]
+    device: String,
+    /// Renames a device found by --device-from, as NAME=DEVICE (repeatable)
+    #[arg(long = "device-map", value_parser = parse_device_mapping)]
+    device_map: Vec<(String, String)>,
+    /// Where decoded data goes (repeatable): the Postgres tables, an InfluxDB bucket, a Kafka
+    /// topic, stdout, or several. With Postgres or InfluxDB and Kafka, only the former hold
+    /// back the durable queue; Kafka's losses are counted instead.
+    #[arg(long = "sink", value_enum, default_values_t = [Sink::Postgres])]
+    sinks: Vec<Sink>,
+    /// Write streams whose calculation name matches REGEX to TABLE instead of bibimbap, as
+    /// REGEX=TABLE, e.g. '^threephase/lab/=bibimbap_lab' (repeatable; the first match wins).
+    /// Tables are created on startup and recorded in bibimbap_routes for the storage manager.
+    #[arg(long = "route", value_parser = routing::parse_route)]
+    routes: Vec<Route>,
+    /// How long the storage manager keeps a routed table's rows, as TABLE=DURATION
+    /// (repeatable); --full-rate-retention for tables without one
+    #[arg(long = "route-retention", value_parser = parse_route_retention)]
+    route_retention: Vec<(String, Duration)>,
+    /// With --sink kafka: bootstrap servers, e.g. kafka-0:9092,kafka-1:9092
+    #[arg(long)]
+    kafka_brokers: Option<String>,
+    #[arg(long, default_value = "karman.calculations")]
+    kafka_topic: String,
+    /// Records are keyed by stream name either way
+    #[arg(long, value_enum, default_value_t = RecordFormat::Json)]
+    kafka_format: RecordFormat,
+    /// A librdkafka producer property as KEY=VALUE (repeatable), e.g. security.protocol=ssl
+    /// or compression.type=lz4
+    #[arg(long = "kafka-property", value_parser = parse_kafka_property)]
+    kafka_properties: Vec<(String, String)>,
+    /// How long a flush waits for Kafka to acknowledge what was produced
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    kafka_flush_timeout: Duration,
+    /// With --sink influx: the InfluxDB 2 server, e.g. http://influxdb:8086
+    #[arg(long)]
+    influx_url: Option<String>,
+    #[arg(long)]
+    influx_org: Option<String>,
+    #[arg(long)]
+    influx_bucket: Option<String>,
+    /// API token with write access to the bucket
+    #[arg(long, env = "INFLUX_TOKEN", hide_env_values = true)]
+    influx_token: Option<String>,
+    /// Measurement every point is written to; stream and phase are tags
+    #[arg(long, default_value = "bibimbap")]
+    influx_measurement: String,
+    /// How long a write to InfluxDB may take before it is retried
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    influx_timeout: Duration,
+    /// Decode and print the rows that would be written (--sink stdout) without touching the
+    /// database: every other sink and --connection-string are ignored
+    #[arg(long)]
+    dry_run: bool,
+    /// With --sink stdout: indent each row over several lines
+    #[arg(long)]
+    stdout_pretty: bool,
+    /// With --sink stdout: print at most this many rows a second, leaving the rest out
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    stdout_max_rows_per_second: Option<u32>,
+    /// Serve Prometheus metrics (insert and retry counters), /healthz and /readyz on this port
+    #[arg(long)]
+    prometheus_port: Option<u16>,
+    #[command(flatten)]
+    health: HealthArgs,
+    #[command(flatten)]
+    log: LogArgs,
+    /// Rows to buffer before writing them in one multi-row INSERT (or one write to InfluxDB)
+    #[arg(long, default_value_t = 60)]
+    batch_size: usize,
+    /// Write buffered rows at least this often
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    flush_interval: Duration,
+    /// Split batches into INSERT or COPY statements of at most this many rows
+    #[arg(long, default_value_t = 1000)]
+    max_rows_per_statement: usize,
+    /// Retries for a statement, or a write to InfluxDB, that failed with a transient error
+    #[arg(long, default_value_t = 5)]
+    insert_max_retries: u32,
+    /// Initial retry delay, doubled on every attempt (capped at 10s)
+    #[arg(long, default_value = "100ms", value_parser = humantime::parse_duration)]
+    insert_retry_backoff: Duration,
+    /// Write batches with multi-row INSERT statements or with COPY
+    #[arg(long, value_enum, default_value_t = WriteMethod::Insert)]
+    write_method: WriteMethod,
+    /// Rows kept in memory while the database is unavailable. Once this many are waiting,
+    /// the subscription is not read until they are written, and --zmq-overflow-policy
+    /// decides what gets lost. 0 drops rows that could not be written instead. Not used with
+    /// --durable-queue-dir, which keeps them on disk.
+    #[arg(long, default_value_t = 18000)]
+    max_buffered_rows: usize,
+    /// At-least-once mode: persist every frame in this directory before writing it, and
+    /// resume from the last committed frame after a restart. While the database is down,
+    /// frames are kept here and written in order once it is back.
+    #[arg(long)]
+    durable_queue_dir: Option<PathBuf>,
+    /// Start a new durable queue segment once the current one reaches this size
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    durable_queue_segment_bytes: u64,
+    /// Frames the durable queue holds for the database, in bytes, before
+    /// --durable-queue-overflow-policy applies (0 for no limit). Segments are kept to at most
+    /// an eighth of this. Losses are counted in data_db_queue_dropped_bytes_total.
+    #[arg(long, default_value_t = 0)]
+    durable_queue_max_bytes: u64,
+    /// Which frames to lose when the durable queue is full: new ones, or the oldest segment
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropOldest)]
+    durable_queue_overflow_policy: OverflowPolicy,
+    /// Keep messages that do not decode, and rows the database refuses, in this directory
+    /// instead of only logging them. Counted in data_db_dead_letters_total.
+    #[arg(long)]
+    dead_letter_dir: Option<PathBuf>,
+    /// Delete the oldest dead letters once the spool holds more than this
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    dead_letter_max_bytes: u64,
+    /// Delete dead letters older than this
+    #[arg(long, default_value = "7days", value_parser = humantime::parse_duration)]
+    dead_letter_max_age: Duration,
+    /// Write the legacy JSONB table, the typed bibimbap_measurements table, or both while
+    /// migrating. The typed table is created or brought up to date on startup.
+    #[arg(long, value_enum, default_value_t = SchemaMode::Json)]
+    schema_mode: SchemaMode,
+    /// Layout of the JSONB `data` column: 1 keys each stream's entry by name, 2 lists them in
+    /// a `streams` array. Every row records its version as `schema_version`.
+    #[arg(long, value_enum, default_value_t = SchemaVersion::V1)]
+    json_schema_version: SchemaVersion,
+    /// With --schema-mode dual: how often to compare the two tables
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    dual_write_check_interval: Duration,
+    /// Rows are flagged `trusted: false` when the publisher's clock reports an offset or
+    /// error bound larger than this, or is not locked to its time source
+    #[arg(long, default_value = "1ms", value_parser = humantime::parse_duration)]
+    max_clock_offset: Duration,
+    /// The time rows are stored under: when data-db received the frame, or the earliest or
+    /// latest provenance timestamp of its streams, falling back to arrival without one
+    #[arg(long, value_enum, default_value_t = RowTime::Arrival)]
+    row_time: RowTime,
+    /// With a provenance --row-time: store rows whose provenance timestamp is further than
+    /// this from arrival at their arrival time instead
+    #[arg(long, value_parser = humantime::parse_duration)]
+    row_time_max_skew: Option<Duration>,
+    /// Reassemble frames whose streams or phases arrive in separate messages, waiting up to
+    /// this long after the first fragment. Rows are stamped with when that fragment arrived.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    assembly_window: Option<Duration>,
+    /// What to do with a frame still incomplete when its assembly window ends
+    #[arg(long, value_enum, default_value_t = TimeoutPolicy::EmitPartial)]
+    assembly_timeout_policy: TimeoutPolicy,
+    /// A stream every assembled frame should have (repeatable); defaults to every stream seen
+    #[arg(long = "assembly-stream")]
+    assembly_streams: Vec<String>,
+    /// Record every new stream (calculation name) in the `streams` table the first time it
+    /// is seen
+    #[arg(long)]
+    stream_registry: bool,
+    /// Event-triggered capture: keep only one row per --capture-store-interval in the regular
+    /// tables, and the full-rate rows around every trigger in bibimbap_highres
+    #[arg(long)]
+    capture: bool,
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    capture_store_interval: Duration,
+    /// Full-rate history kept in memory and written out when a trigger fires
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    capture_pre_roll: Duration,
+    /// How long capturing continues after a trigger last held
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    capture_post_roll: Duration,
+    /// Capture trigger: any phase's RMS voltage below this
+    #[arg(long)]
+    sag_below_volts: Option<f64>,
+    /// Capture trigger: a stream's real power (all phases, C when sent) above this
+    #[arg(long)]
+    power_cap_watts: Option<f64>,
+    /// Capture trigger: a stream's real power changing by more than this percent between
+    /// consecutive frames
+    #[arg(long)]
+    step_change_percent: Option<f64>,
+    /// Write one row per device and interval with each value's mean, min and max instead of
+    /// one per frame, e.g. 1s. Summarized frames are counted in data_db_aggregated_rows_total.
+    #[arg(long, value_parser = humantime::parse_duration, conflicts_with = "capture")]
+    aggregate_interval: Option<Duration>,
+    /// POST a JSON description of every new stream to this http:// URL. With
+    /// --stream-registry, only for streams no other service registered first.
+    #[arg(long)]
+    stream_webhook: Option<String>,
+    /// Add a virtual stream to every row with the real, reactive and apparent power of the
+    /// --site-total-member streams summed per phase (of every stream when none are given)
+    #[arg(long)]
+    site_total: bool,
+    #[arg(long, default_value = "site/total")]
+    site_total_stream: String,
+    /// A feeder stream summed into the site total (repeatable)
+    #[arg(long = "site-total-member")]
+    site_total_members: Vec<String>,
+    /// What to do with the site total of a frame missing a --site-total-member. Missing
+    /// members are counted in data_db_site_total_missing_members_total either way.
+    #[arg(long, value_enum, default_value_t = MissingMembers::Partial)]
+    site_total_missing: MissingMembers,
+    /// Storage manager: roll full-rate rows up into bibimbap_rollup_1m and
+    /// bibimbap_rollup_15m, and prune each tier past its retention. bibimbap_highres and
+    /// bibimbap_events are left alone.
+    #[arg(long)]
+    tiering: bool,
+    /// Full-rate rows (bibimbap, bibimbap_measurements) kept this long
+    #[arg(long, default_value = "30days", value_parser = humantime::parse_duration)]
+    full_rate_retention: Duration,
+    /// 1-minute rollups kept this long; 15-minute rollups are kept indefinitely
+    #[arg(long, default_value = "12months", value_parser = humantime::parse_duration)]
+    minute_rollup_retention: Duration,
+    /// How often the storage manager rolls up and prunes
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    tiering_interval: Duration,
+    /// Write a summary workbook (xlsx) of every UTC day to this directory, once the storage
+    /// manager (--tiering, here or on another instance) has rolled the day up
+    #[arg(long)]
+    daily_report_dir: Option<PathBuf>,
+    /// What to do with a stream's calculations when their provenance timestamp is older than
+    /// one already stored for it. Counted in data_db_late_calculations_total either way.
+    #[arg(long, value_enum, default_value_t = LatePolicy::Correct)]
+    late_data_policy: LatePolicy,
+    /// With --late-data-policy correct: how late calculations may be and still be stored, and
+    /// how far back the storage manager re-aggregates 1-minute rollups for rows stored late
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    lateness_window: Duration,
+}
+
+#[derive(clap::Subcommand, Clone)]
+enum Command {
+    /// Write data-replay datasets straight into the table instead of subscribing, each row
+    /// under its own time, then exit. Takes the Postgres, schema and batch flags given before
+    /// it.
+    Backfill(BackfillArgs),
 }
 
 impl Args {
     fn resolve_endpoint(&self) -> Result<String> {
         if let Some(endpoint) = &self.zmq_endpoint {
-            return Ok(endpoint.clone());
+            return parse_endpoint(endpoint).map_err(|err| anyhow!("--zmq-endpoint {err}"));
         }
 
         let Some(port) = self.zmq_port else {
@@ -231,19 +1330,447 @@ impl Args {
 
         Ok(format!("tcp://{}:{}", self.zmq_host, port))
     }
+
+    fn decoder(
+        &self,
+        registry: Option<Registry>,
+        dead_letters: Option<DeadLetters>,
+        kafka: Option<Arc<KafkaSink>>,
+    ) -> Decoder {
+        Decoder {
+            topic: self.zmq_topic.as_bytes().to_vec(),
+            tenant: self.tenant.clone(),
+            devices: self.devices(),
+            max_clock_offset: self.max_clock_offset,
+            row_time: RowTimeConfig {
+                source: self.row_time,
+                max_skew: self.row_time_max_skew,
+            },
+            schema_mode: self.schema_mode,
+            registry,
+            site_total: self.site_total.then(|| SiteTotalConfig {
+                stream: self.site_total_stream.clone(),
+                members: self.site_total_members.iter().cloned().collect(),
+                on_missing: self.site_total_missing,
+            }),
+            order: OrderTracker::new(self.lateness()),
+            committed_order: OrderTracker::new(self.lateness()),
+            sequence: SequenceTracker::new(),
+            committed_sequence: SequenceTracker::new(),
+            dead_letters,
+            kafka,
+        }
+    }
+
+    /// Small enough that dropping the oldest segment only loses a fraction of the queue.
+    fn queue_segment_bytes(&self) -> u64 {
+        match self.durable_queue_max_bytes {
+            0 => self.durable_queue_segment_bytes,
+            max => self.durable_queue_segment_bytes.min(max / 8).max(1),
+        }
+    }
+
+    fn queue_limit(&self) -> QueueLimit {
+        QueueLimit {
+            max_bytes: self.durable_queue_max_bytes,
+            on_full: self.durable_queue_overflow_policy,
+        }
+    }
+
+    fn dead_letters(&self) -> Option<DeadLetterConfig> {
+        Some(DeadLetterConfig {
+            directory: self.dead_letter_dir.clone()?,
+            max_bytes: self.dead_letter_max_bytes,
+            max_age: self.dead_letter_max_age,
+        })
+    }
+
+    fn devices(&self) -> DeviceConfig {
+        DeviceConfig {
+            source: self.device_from,
+            default: self.device.clone(),
+            map: self.device_map.iter().cloned().collect(),
+        }
+    }
+
+    fn stream_registry(&self) -> RegistrySettings {
+        RegistrySettings {
+            database_url: self
+                .stream_registry
+                .then(|| self.connection_string.clone())
+                .flatten(),
+            webhook_url: self.stream_webhook.clone(),
+            device: self.device.clone(),
+            tenant: self.tenant.clone(),
+        }
+    }
+
+    fn hwm(&self) -> HwmConfig {
+        HwmConfig {
+            hwm: self.zmq_rcvhwm,
+            on_full: self.zmq_overflow_policy,
+        }
+    }
+
+    /// None without --connection-string.
+    fn pool(&self) -> Result<Option<PoolConfig>> {
+        let Some(connection_string) = &self.connection_string else {
+            return Ok(None);
+        };
+        Ok(Some(PoolConfig {
+            connect_options: connection_string
+                .parse()
+                .context("Invalid --connection-string")?,
+            max_connections: self.db_max_connections,
+            connect_timeout: self.db_connect_timeout,
+            statement_timeout: self.db_statement_timeout,
+            retries: self.db_connect_retries,
+            backoff: Backoff {
+                initial: self.db_connect_backoff,
+                max: self.db_connect_max_backoff,
+            },
+        }))
+    }
+
+    fn assembly(&self) -> Option<AssemblyConfig> {
+        Some(AssemblyConfig {
+            window: self.assembly_window?,
+            on_timeout: self.assembly_timeout_policy,
+            expected_streams: self.assembly_streams.iter().cloned().collect(),
+        })
+    }
+
+    fn capture(&self) -> Option<CaptureConfig> {
+        if !self.capture {
+            return None;
+        }
+        Some(CaptureConfig {
+            store_interval: self.capture_store_interval,
+            pre_roll: self.capture_pre_roll,
+            post_roll: self.capture_post_roll,
+            triggers: Triggers {
+                sag_below_volts: self.sag_below_volts,
+                power_cap_watts: self.power_cap_watts,
+                step_change_percent: self.step_change_percent,
+            },
+        })
+    }
+
+    fn aggregate(&self) -> Option<AggregateConfig> {
+        Some(AggregateConfig {
+            interval: self.aggregate_interval?,
+            settle: self.assembly_window.unwrap_or_default(),
+        })
+    }
+
+    fn kafka(&self) -> Option<KafkaConfig> {
+        if !self.sinks.contains(&Sink::Kafka) {
+            return None;
+        }
+        Some(KafkaConfig {
+            brokers: self.kafka_brokers.clone()?,
+            topic: self.kafka_topic.clone(),
+            format: self.kafka_format,
+            properties: self.kafka_properties.clone(),
+            flush_timeout: self.kafka_flush_timeout,
+        })
+    }
+
+    fn influx(&self) -> Option<InfluxConfig> {
+        if !self.sinks.contains(&Sink::Influx) {
+            return None;
+        }
+        Some(InfluxConfig {
+            url: self.influx_url.clone()?,
+            org: self.influx_org.clone()?,
+            bucket: self.influx_bucket.clone()?,
+            token: self.influx_token.clone(),
+            measurement: self.influx_measurement.clone(),
+            batch_size: self.batch_size.max(1),
+            max_retries: self.insert_max_retries,
+            retry_backoff: self.insert_retry_backoff,
+            timeout: self.influx_timeout,
+        })
+    }
+
+    fn stdout(&self) -> Option<StdoutConfig> {
+        self.sinks.contains(&Sink::Stdout).then_some(StdoutConfig {
+            pretty: self.stdout_pretty,
+            max_rows_per_second: self.stdout_max_rows_per_second,
+            schema_version: self.json_schema_version,
+        })
+    }
+
+    /// What needs flags the command line can't require on its own.
+    fn check_sinks(&self) -> Result<()> {
+        if self.sinks.contains(&Sink::Kafka) && self.kafka_brokers.is_none() {
+            bail!("--sink kafka needs --kafka-brokers");
+        }
+        if self.sinks.contains(&Sink::Kafka) && !cfg!(feature = "kafka") {
+            bail!("--sink kafka needs data-db built with the kafka feature");
+        }
+        if self.sinks.contains(&Sink::Influx) {
+            let needed = [
+                (self.influx_url.is_none(), "--influx-url"),
+                (self.influx_org.is_none(), "--influx-org"),
+                (self.influx_bucket.is_none(), "--influx-bucket"),
+            ];
+            if let Some((_, flag)) = needed.iter().find(|(missing, _)| *missing) {
+                bail!("--sink influx needs {flag}");
+            }
+        }
+        if self.capture && !self.sinks.contains(&Sink::Postgres) {
+            bail!("--capture needs --sink postgres");
+        }
+        if !self.routes.is_empty() {
+            if !self.sinks.contains(&Sink::Postgres) {
+                bail!("--route needs --sink postgres");
+            }
+            // Routes split the JSONB rows; bibimbap_measurements has no table per route
+            if self.schema_mode == SchemaMode::Columns {
+                bail!("--route needs --schema-mode json or dual");
+            }
+        }
+        let needs_database = [
+            (self.sinks.contains(&Sink::Postgres), "--sink postgres"),
+            (self.init_schema, "--init-schema"),
+            (self.tiering, "--tiering"),
+            (self.daily_report_dir.is_some(), "--daily-report-dir"),
+            (self.stream_registry, "--stream-registry"),
+            (self.schema_mode == SchemaMode::Dual, "--schema-mode dual"),
+        ];
+        if self.connection_string.is_none()
+            && let Some((_, flag)) = needs_database.iter().find(|(needed, _)| *needed)
+        {
+            bail!("{flag} needs --connection-string");
+        }
+        Ok(())
+    }
+
+    fn routes(&self) -> Result<Routes> {
+        Routes::new(self.routes.clone(), self.route_retention.clone())
+    }
+
+    fn tiering(&self) -> Option<TieringConfig> {
+        if !self.tiering {
+            return None;
+        }
+        Some(TieringConfig {
+            full_rate_retention: self.full_rate_retention,
+            minute_rollup_retention: self.minute_rollup_retention,
+            interval: self.tiering_interval,
+            correction_window: self.lateness().correction_window(),
+            schema_mode: self.schema_mode,
+        })
+    }
+
+    fn daily_reports(&self) -> Option<ReportConfig> {
+        Some(ReportConfig {
+            directory: self.daily_report_dir.clone()?,
+            device: self.device.clone(),
+            tenant: self.tenant.clone(),
+        })
+    }
+
+    fn lateness(&self) -> LatenessConfig {
+        LatenessConfig {
+            policy: self.late_data_policy,
+            window: self.lateness_window,
+        }
+    }
+
+    fn batch_config(&self) -> BatchConfig {
+        BatchConfig {
+            table: "bibimbap".to_string(),
+            batch_size: self.batch_size.max(1),
+            flush_interval: self.flush_interval,
+            max_rows_per_statement: self.max_rows_per_statement,
+            max_retries: self.insert_max_retries,
+            retry_backoff: self.insert_retry_backoff,
+            schema_mode: self.schema_mode,
+            schema_version: self.json_schema_version,
+            method: self.write_method,
+            // A failed write rewinds the durable queue instead
+            max_buffered_rows: if self.durable_queue_dir.is_some() {
+                0
+            } else {
+                self.max_buffered_rows
+            },
+        }
+    }
+}
+
+fn parse_tenant_reader(value: &str) -> Result<(String, String)> {
+    match value.split_once('=') {
+        Some((role, tenant)) if !role.is_empty() && !tenant.is_empty() => {
+            Ok((role.to_string(), tenant.to_string()))
+        }
+        _ => Err(anyhow!("expected ROLE=TENANT")),
+    }
+}
+
+fn parse_kafka_property(value: &str) -> Result<(String, String)> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(anyhow!("expected KEY=VALUE")),
+    }
+}
+
+fn parse_route_retention(value: &str) -> Result<(String, Duration)> {
+    match value.split_once('=') {
+        Some((table, retention)) if !table.is_empty() => {
+            Ok((table.to_string(), humantime::parse_duration(retention)?))
+        }
+        _ => Err(anyhow!("expected TABLE=DURATION")),
+    }
+}
+
+fn parse_device_mapping(value: &str) -> Result<(String, String)> {
+    match value.split_once('=') {
+        Some((name, device)) if !name.is_empty() && !device.is_empty() => {
+            Ok((name.to_string(), device.to_string()))
+        }
+        _ => Err(anyhow!("expected NAME=DEVICE")),
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    let mut args: Args = service_config::parse("db", SHARED_SETTINGS);
+    logging::init(&args.log, "error");
+    args.zmq_topic = match tenant_topic(args.tenant.as_deref(), &args.zmq_topic) {
+        Ok(topic) => topic,
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(2);
+        }
+    };
+    if args.dry_run {
+        args.sinks = vec![Sink::Stdout];
+        args.connection_string = None;
+    }
+    if let Err(err) = args.check_sinks() {
+        log::error!("{err}");
+        std::process::exit(2);
+    }
+    let routes = match args.routes() {
+        Ok(routes) => routes,
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(2);
+        }
+    };
+
+    if args.init_schema {
+        let grants = schema::Grants {
+            writers: args.grant_writer.clone(),
+            readers: args.grant_reader.clone(),
+            storage_managers: args.grant_storage_manager.clone(),
+            tenant_readers: args.grant_tenant_reader.clone(),
+        };
+        let connection_string = args.connection_string.as_deref().expect("checked above");
+        if let Err(err) = schema::init(connection_string, &grants, &routes.tables()).await {
+            log::error!("Could not initialise schema: {err:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let args = Args::parse();
+    if let Some(Command::Backfill(backfill)) = &args.command {
+        if let Err(err) = backfill::run(&args, backfill).await {
+            log::error!("Backfill failed: {err:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let pool = PgPoolOptions::new()
-        .max_connections(5) // tune for your workload
-        .connect(&args.connection_string.clone())
-        .await
-        .expect("Could not connect to database");
+    let shutdown = match Shutdown::install() {
+        Ok(shutdown) => shutdown,
+        Err(err) => {
+            log::error!("Could not install signal handlers: {err:#}");
+            std::process::exit(1);
+        }
+    };
+
+    // Without --connection-string, the other sinks are the only ones and nothing reads the
+    // tables
+    let pool = match args.pool() {
+        Ok(Some(config)) => match config.connect(&shutdown).await {
+            Ok(Some(pool)) => Some(pool),
+            Ok(None) => return,
+            Err(err) => {
+                log::error!("{err:#}");
+                std::process::exit(1);
+            }
+        },
+        Ok(None) => None,
+        Err(err) => {
+            log::error!("{err:#}");
+            std::process::exit(1);
+        }
+    };
+
+    let health = Health::new(args.health);
+    if let Some(pool) = pool
+        .clone()
+        .filter(|_| args.sinks.contains(&Sink::Postgres))
+    {
+        health.check("database", move || {
+            let pool = pool.clone();
+            async move { sqlx::query("SELECT 1").execute(&pool).await.map(|_| ()) }
+        });
+    }
+    if let Some(port) = args.prometheus_port {
+        tokio::spawn(metrics::serve(
+            port,
+            health.clone(),
+            args.json_schema_version,
+        ));
+    }
 
-    listen(args, pool).await;
+    if let Some(pool) = &pool {
+        if let Err(err) = schema::migrate(pool, args.schema_mode).await {
+            log::error!("Could not migrate the schema: {err:#}");
+            std::process::exit(1);
+        }
+        if args.sinks.contains(&Sink::Postgres)
+            && let Err(err) = schema::migrate_routes(pool, &routes.tables()).await
+        {
+            log::error!("Could not create the routed tables: {err:#}");
+            std::process::exit(1);
+        }
+
+        if args.schema_mode == SchemaMode::Dual {
+            tokio::spawn(dual_write::check_periodically(
+                pool.clone(),
+                args.dual_write_check_interval,
+                args.flush_interval,
+            ));
+        }
+
+        if let Some(config) = args.tiering() {
+            tokio::spawn(tiering::manage(pool.clone(), config, args.flush_interval));
+        }
+
+        if let Some(config) = args.daily_reports() {
+            tokio::spawn(reports::write_daily(pool.clone(), config));
+        }
+    }
+
+    let registry = match Registry::start("data-db", &args.stream_registry()).await {
+        Ok(registry) => registry,
+        Err(err) => {
+            log::error!("Could not start the stream registry: {err:#}");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = listen(args, pool.clone(), registry, routes, health, &shutdown).await {
+        log::error!("{err:#}");
+        std::process::exit(255);
+    }
+    if let Some(pool) = pool {
+        pool.close().await;
+    }
+    log::info!("Shut down cleanly");
 }
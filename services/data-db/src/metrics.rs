@@ -0,0 +1,399 @@
+use std::sync::LazyLock;
+
+use axum::{
+    Router,
+    http::{HeaderMap, StatusCode},
+    routing::get,
+};
+use health::Health;
+use prometheus::core::Collector;
+use prometheus::{
+    Counter, Encoder, Gauge, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use zmq_ingest::ReceiveMetrics;
+
+use crate::describe;
+use stored_document::SchemaVersion;
+
+pub static ROWS_WRITTEN: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_rows_written_total",
+        "Rows successfully inserted into the database"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static INSERT_RETRIES: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_insert_retries_total",
+        "Insert statements retried after a transient error"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static INSERT_FAILED_ROWS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_insert_failed_rows_total",
+        "Rows not written after a permanent error or exhausting retries"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static BUFFERED_ROWS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "data_db_buffered_rows",
+        "Rows held in memory after the database was unavailable, waiting for another attempt",
+        &["table"]
+    )
+    .expect("Unable to register gauge vec")
+});
+
+pub static INTAKE_PAUSED_SECONDS: LazyLock<Counter> = LazyLock::new(|| {
+    prometheus::register_counter!(
+        "data_db_intake_paused_seconds_total",
+        "Time spent not reading the subscription because --max-buffered-rows were waiting"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static QUEUE_BACKLOG_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    prometheus::register_int_gauge!(
+        "data_db_queue_backlog_bytes",
+        "Bytes in the durable queue not yet committed to the database"
+    )
+    .expect("Unable to register gauge")
+});
+
+pub static QUEUE_REDELIVERIES: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_queue_redeliveries_total",
+        "Times the durable queue was rewound to its committed offset after a failed write"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static QUEUE_DROPPED_BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_queue_dropped_bytes_total",
+        "Bytes of frames lost because the durable queue was at --durable-queue-max-bytes"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static KAFKA_RECORDS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_kafka_records_total",
+        "Records for --sink kafka: delivered, failed (given up on by librdkafka) or dropped (its buffer was full)",
+        &["outcome"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static INFLUX_LINES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_influx_lines_total",
+        "Line protocol points for --sink influx: written, or failed (given up on or refused by InfluxDB)",
+        &["outcome"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static INFLUX_RETRIES: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_influx_retries_total",
+        "Writes to InfluxDB sent again after a network error, 5xx or 429"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static DEAD_LETTERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_dead_letters_total",
+        "Messages that did not decode (decode) and rows the database refused (insert), \
+         kept in the dead-letter spool",
+        &["kind"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static DEAD_LETTER_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    prometheus::register_int_gauge!("data_db_dead_letter_bytes", "Size of the dead-letter spool")
+        .expect("Unable to register gauge")
+});
+
+pub static DEAD_LETTERS_PRUNED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_dead_letters_pruned_total",
+        "Dead letters deleted for being past --dead-letter-max-age or over --dead-letter-max-bytes"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static DEAD_LETTER_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_dead_letter_errors_total",
+        "Dead letters that could not be written to the spool"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static DUAL_WRITE_CHECKED_ROWS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_dual_write_checked_total",
+        "Stream/phase entries compared between the JSONB and normalized tables"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static DUAL_WRITE_DISCREPANCIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_dual_write_discrepancies_total",
+        "Entries where the JSONB and normalized tables disagree: missing, extra or different",
+        &["kind"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static ASSEMBLED_FRAMES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_frame_assembly_total",
+        "Frames by how their assembly ended: complete, partial, dropped or late",
+        &["outcome"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static MISSING_FIELDS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_missing_fields_total",
+        "Required fields left out of a frame and stored as NaN, by field path",
+        &["field"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static INCOMPLETE_CALCULATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_incomplete_calculations_total",
+        "Streams in a frame that were stored with missing fields (partial) or not at all (skipped)",
+        &["outcome"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static PROVENANCE_SKEW: LazyLock<Gauge> = LazyLock::new(|| {
+    prometheus::register_gauge!(
+        "data_db_provenance_skew_seconds",
+        "Arrival time minus the provenance time of the last frame that had one; negative when \
+         the publisher's clock is ahead"
+    )
+    .expect("Unable to register gauge")
+});
+
+pub static ROW_TIME_FALLBACKS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_row_time_fallbacks_total",
+        "Rows stored at their arrival time instead of their provenance time, by reason: \
+         no_provenance or skew",
+        &["reason"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static LATE_CALCULATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_late_calculations_total",
+        "Streams older than one already stored for them, by outcome: accepted or rejected",
+        &["outcome"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static FRAMES_DROPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_frames_dropped_total",
+        "Frames missing from a stream's sequence numbers, by stream",
+        &["stream"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static FRAMES_OUT_OF_ORDER: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_frames_out_of_order_total",
+        "Frames behind their stream's newest sequence number, by kind: late, or duplicate and dropped",
+        &["stream", "kind"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static SITE_TOTAL_MISSING_MEMBERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_site_total_missing_members_total",
+        "Frames whose site total was missing a member stream, by member",
+        &["stream"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static CAPTURE_EVENTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_capture_events_total",
+        "Events that triggered a high-resolution capture, by trigger",
+        &["trigger"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static AGGREGATED_ROWS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_aggregated_rows_total",
+        "Rows summarized into --aggregate-interval rows, which are counted in rows_written_total"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static TIERING_ROLLED_UP_TO: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "data_db_tiering_rolled_up_to_seconds",
+        "Unix time up to which full-rate rows (tier 1m) or 1-minute rollups (15m) are rolled up",
+        &["tier"]
+    )
+    .expect("Unable to register gauge vec")
+});
+
+pub static TIERING_ROLLUP_ROWS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_db_tiering_rollup_rows_total",
+        "Rows written to a rollup tier",
+        &["tier"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static TIERING_PRUNED_TO: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "data_db_tiering_pruned_to_seconds",
+        "Unix time before which a table was last pruned (hypertables keep the chunk containing it)",
+        &["table"]
+    )
+    .expect("Unable to register gauge vec")
+});
+
+pub static TIERING_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_tiering_errors_total",
+        "Storage manager runs that failed; the next run picks up where it stopped"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static DAILY_REPORTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_daily_reports_total",
+        "Daily summary workbooks written"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static DAILY_REPORT_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_db_daily_report_errors_total",
+        "Daily summary runs that failed; the next run retries the days still missing"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static ZMQ_RECEIVE: LazyLock<ReceiveMetrics> = LazyLock::new(|| {
+    ReceiveMetrics::register("data_db_").expect("Unable to register receive queue metrics")
+});
+
+/// Every metric data-db exports, registering any not used yet.
+pub fn collectors() -> Vec<&'static dyn Collector> {
+    let mut collectors: Vec<&'static dyn Collector> = vec![
+        &*ROWS_WRITTEN,
+        &*INSERT_RETRIES,
+        &*INSERT_FAILED_ROWS,
+        &*BUFFERED_ROWS,
+        &*INTAKE_PAUSED_SECONDS,
+        &*QUEUE_BACKLOG_BYTES,
+        &*QUEUE_REDELIVERIES,
+        &*QUEUE_DROPPED_BYTES,
+        &*KAFKA_RECORDS,
+        &*INFLUX_LINES,
+        &*INFLUX_RETRIES,
+        &*DEAD_LETTERS,
+        &*DEAD_LETTER_BYTES,
+        &*DEAD_LETTERS_PRUNED,
+        &*DEAD_LETTER_ERRORS,
+        &*DUAL_WRITE_CHECKED_ROWS,
+        &*DUAL_WRITE_DISCREPANCIES,
+        &*ASSEMBLED_FRAMES,
+        &*MISSING_FIELDS,
+        &*INCOMPLETE_CALCULATIONS,
+        &*LATE_CALCULATIONS,
+        &*PROVENANCE_SKEW,
+        &*ROW_TIME_FALLBACKS,
+        &*FRAMES_DROPPED,
+        &*FRAMES_OUT_OF_ORDER,
+        &*SITE_TOTAL_MISSING_MEMBERS,
+        &*CAPTURE_EVENTS,
+        &*AGGREGATED_ROWS,
+        &*TIERING_ROLLED_UP_TO,
+        &*TIERING_ROLLUP_ROWS,
+        &*TIERING_PRUNED_TO,
+        &*TIERING_ERRORS,
+        &*DAILY_REPORTS,
+        &*DAILY_REPORT_ERRORS,
+    ];
+    collectors.extend(ZMQ_RECEIVE.collectors());
+    collectors
+}
+
+async fn metrics_handler() -> (StatusCode, HeaderMap, String) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {:?}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            HeaderMap::new(),
+            String::new(),
+        );
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "Content-Type",
+        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+    );
+
+    match String::from_utf8(buffer) {
+        Ok(body) => (StatusCode::OK, headers, body),
+        Err(e) => {
+            log::error!("Failed to convert metrics to UTF8: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                String::new(),
+            )
+        }
+    }
+}
+
+pub async fn serve(port: u16, health: Health, schema_version: SchemaVersion) {
+    let addr = format!("0.0.0.0:{}", port);
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/schema", get(move || describe::handler(schema_version)))
+        .merge(health.router());
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .expect("Could not bind prometheus server");
+    log::info!("data-db: Prometheus metrics server listening on {}", addr);
+
+    axum::serve(listener, app)
+        .await
+        .expect("Metrics server failed");
+}
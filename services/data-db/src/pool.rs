@@ -0,0 +1,77 @@
+//! The Postgres connection pool, and waiting for the database at startup: data-db brought up
+//! alongside Postgres (in docker-compose, say) keeps trying until it accepts connections
+//! rather than exiting on the first refusal.
+
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use shutdown::Shutdown;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{Pool, Postgres};
+use zmq_ingest::Backoff;
+
+use crate::writer::is_transient;
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    pub connect_options: PgConnectOptions,
+    pub max_connections: u32,
+    /// Longest wait for a connection, whether opening one or for one of the pool's to free up
+    pub connect_timeout: Duration,
+    /// Cap on every statement, migrations included; Postgres' own setting without it
+    pub statement_timeout: Option<Duration>,
+    /// Attempts after the first failed one before giving up
+    pub retries: u32,
+    pub backoff: Backoff,
+}
+
+impl PoolConfig {
+    /// One attempt at opening the pool, which opens its first connection to check it.
+    pub async fn connect_once(&self) -> Result<Pool<Postgres>, sqlx::Error> {
+        let mut options = self.connect_options.clone();
+        if let Some(timeout) = self.statement_timeout {
+            options = options.options([("statement_timeout", timeout.as_millis().to_string())]);
+        }
+        PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .acquire_timeout(self.connect_timeout)
+            .connect_with(options)
+            .await
+    }
+
+    /// Opens the pool, trying again with backoff while the database is unreachable or still
+    /// starting up, up to `retries` times. Errors that waiting won't fix, such as a rejected
+    /// password, fail at once. None when shutdown is requested first.
+    pub async fn connect(&self, shutdown: &Shutdown) -> Result<Option<Pool<Postgres>>> {
+        let mut attempt = 0;
+        loop {
+            let connected = tokio::select! {
+                connected = self.connect_once() => connected,
+                _ = shutdown.requested() => return Ok(None),
+            };
+            let err = match connected {
+                Ok(pool) => {
+                    if attempt > 0 {
+                        log::info!("Connected to the database after {attempt} retries");
+                    }
+                    return Ok(Some(pool));
+                }
+                Err(err) => err,
+            };
+            if !is_transient(&err) {
+                bail!("Could not connect to database: {err}");
+            }
+            if attempt >= self.retries {
+                bail!("Could not connect to database after {attempt} retries: {err}");
+            }
+
+            let delay = self.backoff.delay(attempt);
+            log::warn!("Could not connect to database ({err}); retrying in {delay:?}");
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.requested() => return Ok(None),
+            }
+            attempt += 1;
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! On-disk frame queue for at-least-once delivery.
+//!
+//! The subscriber appends every raw frame (with the time it arrived) to a log of segment files
+//! before anything else happens to it; the database side reads from the log and only moves the
+//! committed offset forward once the rows built from those frames are in the table. After a
+//! crash on either side, reading resumes from the committed offset, so frames are written at
+//! least once: anything in flight when the process died is written again. Rows the table
+//! refuses outright are committed past (dead-lettered, or dropped and counted), since reading
+//! them again would only stop everything behind them.
+//!
+//! While the database is down the queue keeps growing, up to its size limit if it has one.
+//! Past the limit it either drops new frames or discards its oldest uncommitted segment to make
+//! room, so the outage loses the oldest data instead of the newest.
+//!
+//! Segments are named after the offset of their first record and deleted once everything in
+//! them has been committed (or discarded). Each record is `len: u32, crc32: u32, received_micros: i64,
+//! payload`, little endian, with the CRC covering the timestamp and payload.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use tokio::sync::Notify;
+use zmq_ingest::OverflowPolicy;
+
+use crate::metrics::{QUEUE_BACKLOG_BYTES, QUEUE_DROPPED_BYTES};
+
+const HEADER_LEN: u64 = 16;
+const SEGMENT_EXTENSION: &str = "log";
+const COMMITTED_FILE: &str = "committed";
+
+pub struct Frame {
+    /// Offset where this frame starts
+    pub offset: u64,
+    /// Offset just past this frame; commit this once the frame has been written
+    pub next: u64,
+    pub received: DateTime<Utc>,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueLimit {
+    /// Uncommitted bytes the queue may hold; 0 for no limit
+    pub max_bytes: u64,
+    /// Drop the frame being appended, or discard the oldest segment to make room for it
+    pub on_full: OverflowPolicy,
+}
+
+/// Offsets the writer needs from the reader and the other way round.
+struct Shared {
+    committed: AtomicU64,
+    /// Everything before this was discarded by the writer to stay within the limit; the
+    /// reader skips it and commits past it
+    discard_before: AtomicU64,
+    notify: Notify,
+}
+
+pub struct QueueWriter {
+    dir: PathBuf,
+    segment_bytes: u64,
+    limit: QueueLimit,
+    segment_start: u64,
+    file: File,
+    position: u64,
+    /// Frames are being dropped for want of room
+    dropping: bool,
+    shared: Arc<Shared>,
+}
+
+pub struct QueueReader {
+    dir: PathBuf,
+    segment: Option<(u64, File)>,
+    position: u64,
+    committed: u64,
+    shared: Arc<Shared>,
+}
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("{start:020}.{SEGMENT_EXTENSION}"))
+}
+
+/// Start offsets of the segments in the queue directory, oldest first.
+fn segments(dir: &Path) -> Result<Vec<u64>> {
+    let mut starts = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Could not list {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION)
+            && let Some(start) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+        {
+            starts.push(start);
+        }
+    }
+    starts.sort_unstable();
+    Ok(starts)
+}
+
+fn checksum(received_micros: i64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&received_micros.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+enum ReadOutcome {
+    Frame(Frame),
+    /// Nothing (or only part of a record) past this point yet
+    End,
+    Corrupt,
+}
+
+/// Reads the record at `local` (an offset within the segment starting at `segment_start`).
+fn read_record(file: &mut File, segment_start: u64, local: u64) -> Result<ReadOutcome> {
+    let available = file.metadata()?.len().saturating_sub(local);
+    if available < HEADER_LEN {
+        return Ok(ReadOutcome::End);
+    }
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    file.seek(SeekFrom::Start(local))?;
+    file.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as u64;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let received_micros = i64::from_le_bytes(header[8..16].try_into().unwrap());
+    if available < HEADER_LEN + len {
+        return Ok(ReadOutcome::End);
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    file.read_exact(&mut payload)?;
+    if checksum(received_micros, &payload) != crc {
+        return Ok(ReadOutcome::Corrupt);
+    }
+    let Some(received) = DateTime::from_timestamp_micros(received_micros) else {
+        return Ok(ReadOutcome::Corrupt);
+    };
+
+    Ok(ReadOutcome::Frame(Frame {
+        offset: segment_start + local,
+        next: segment_start + local + HEADER_LEN + len,
+        received,
+        payload,
+    }))
+}
+
+fn read_committed(dir: &Path) -> Result<Option<u64>> {
+    match fs::read_to_string(dir.join(COMMITTED_FILE)) {
+        Ok(contents) => contents
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow!("Corrupt committed offset file in {}", dir.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).context("Could not read committed offset"),
+    }
+}
+
+/// Opens (or creates) the queue in `dir`. A record torn by a crash mid-append is cut off the
+/// end of the newest segment; everything after the committed offset will be read again.
+pub fn open(
+    dir: &Path,
+    segment_bytes: u64,
+    limit: QueueLimit,
+) -> Result<(QueueWriter, QueueReader)> {
+    fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+
+    let starts = segments(dir)?;
+    let committed = read_committed(dir)?;
+    let segment_start = match starts.last() {
+        Some(start) => *start,
+        None => committed.unwrap_or(0),
+    };
+
+    let path = segment_path(dir, segment_start);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+
+    let mut valid = 0;
+    while let ReadOutcome::Frame(frame) = read_record(&mut file, segment_start, valid)? {
+        valid = frame.next - segment_start;
+    }
+    if file.metadata()?.len() > valid {
+        log::warn!(
+            "Truncating torn record at offset {} in {}",
+            segment_start + valid,
+            path.display()
+        );
+        file.set_len(valid)?;
+        file.sync_all()?;
+    }
+
+    let position = segment_start + valid;
+    let oldest = starts.first().copied().unwrap_or(segment_start);
+    let committed = committed.unwrap_or(oldest).clamp(oldest, position);
+    if committed < position {
+        log::info!(
+            "Durable queue has {} uncommitted bytes, writing them again",
+            position - committed
+        );
+    }
+    QUEUE_BACKLOG_BYTES.set((position - committed) as i64);
+
+    let shared = Arc::new(Shared {
+        committed: AtomicU64::new(committed),
+        discard_before: AtomicU64::new(committed),
+        notify: Notify::new(),
+    });
+    Ok((
+        QueueWriter {
+            dir: dir.to_path_buf(),
+            segment_bytes,
+            limit,
+            segment_start,
+            file,
+            position,
+            dropping: false,
+            shared: shared.clone(),
+        },
+        QueueReader {
+            dir: dir.to_path_buf(),
+            segment: None,
+            position: committed,
+            committed,
+            shared,
+        },
+    ))
+}
+
+impl QueueWriter {
+    /// Appends a frame and syncs it to disk, unless the queue is full and the frame is
+    /// dropped. Blocks on the fsync.
+    pub fn append(&mut self, received: DateTime<Utc>, payload: &[u8]) -> Result<()> {
+        if self.position - self.segment_start >= self.segment_bytes {
+            self.roll()?;
+        }
+        let record_len = HEADER_LEN + payload.len() as u64;
+        if !self.make_room(record_len)? {
+            QUEUE_DROPPED_BYTES.inc_by(record_len);
+            if !self.dropping {
+                log::warn!(
+                    "Durable queue is at its limit of {} bytes, dropping new frames",
+                    self.limit.max_bytes
+                );
+                self.dropping = true;
+            }
+            return Ok(());
+        }
+        if self.dropping {
+            log::info!("Durable queue has room again, no longer dropping frames");
+            self.dropping = false;
+        }
+
+        let received_micros = received.timestamp_micros();
+        let mut record = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(received_micros, payload).to_le_bytes());
+        record.extend_from_slice(&received_micros.to_le_bytes());
+        record.extend_from_slice(payload);
+
+        self.file
+            .write_all(&record)
+            .context("Could not append to durable queue")?;
+        self.file
+            .sync_data()
+            .context("Could not sync durable queue")?;
+        self.position += record.len() as u64;
+        QUEUE_BACKLOG_BYTES.add(record.len() as i64);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    /// Whether a record of `record_len` fits within the limit, discarding the oldest
+    /// segments first if that is the policy. The segment being appended to is never
+    /// discarded, so a frame that still doesn't fit is dropped either way.
+    fn make_room(&mut self, record_len: u64) -> Result<bool> {
+        if self.limit.max_bytes == 0 {
+            return Ok(true);
+        }
+        loop {
+            let start = self
+                .shared
+                .committed
+                .load(Ordering::Acquire)
+                .max(self.shared.discard_before.load(Ordering::Acquire));
+            if self.position + record_len - start <= self.limit.max_bytes {
+                return Ok(true);
+            }
+            if self.limit.on_full == OverflowPolicy::DropNewest {
+                return Ok(false);
+            }
+            let Some(next) = segments(&self.dir)?
+                .into_iter()
+                .find(|next| *next > start && *next <= self.segment_start)
+            else {
+                return Ok(false);
+            };
+            log::warn!(
+                "Durable queue is at its limit of {} bytes, discarding {} bytes of the oldest \
+                 frames",
+                self.limit.max_bytes,
+                next - start
+            );
+            QUEUE_DROPPED_BYTES.inc_by(next - start);
+            self.shared.discard_before.store(next, Ordering::Release);
+            // The reader deletes the segment once it sees the new offset
+            self.shared.notify.notify_one();
+        }
+    }
+
+    fn roll(&mut self) -> Result<()> {
+        let path = segment_path(&self.dir, self.position);
+        self.file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Could not create {}", path.display()))?;
+        File::open(&self.dir)?.sync_all()?;
+        self.segment_start = self.position;
+        Ok(())
+    }
+}
+
+impl QueueReader {
+    /// Waits for the next frame after the read position.
+    pub async fn next(&mut self) -> Result<Frame> {
+        loop {
+            if let Some(frame) = self.try_next()? {
+                return Ok(frame);
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<Frame>> {
+        self.skip_discarded()?;
+        loop {
+            if self.segment.is_none() {
+                let Some(start) = self.segment_containing(self.position)? else {
+                    return Ok(None);
+                };
+                let path = segment_path(&self.dir, start);
+                let file = File::open(&path)
+                    .with_context(|| format!("Could not open {}", path.display()))?;
+                self.segment = Some((start, file));
+            }
+            let Some((start, file)) = &mut self.segment else {
+                unreachable!();
+            };
+            let start = *start;
+
+            match read_record(file, start, self.position - start)? {
+                ReadOutcome::Frame(frame) => {
+                    self.position = frame.next;
+                    return Ok(Some(frame));
+                }
+                ReadOutcome::Corrupt => {
+                    return Err(anyhow!(
+                        "Corrupt record at offset {} in the durable queue",
+                        self.position
+                    ));
+                }
+                ReadOutcome::End => {
+                    // The writer rolls to a segment named after exactly this offset
+                    if segment_path(&self.dir, self.position).exists() && start != self.position {
+                        self.segment = None;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Moves past the frames the writer discarded, committing them so their segments are
+    /// deleted.
+    fn skip_discarded(&mut self) -> Result<()> {
+        let discard_before = self.shared.discard_before.load(Ordering::Acquire);
+        if discard_before <= self.committed {
+            return Ok(());
+        }
+        if self.position < discard_before {
+            self.position = discard_before;
+            self.segment = None;
+        }
+        self.commit(discard_before)
+    }
+
+    fn segment_containing(&self, offset: u64) -> Result<Option<u64>> {
+        Ok(segments(&self.dir)?
+            .into_iter()
+            .take_while(|start| *start <= offset)
+            .last())
+    }
+
+    /// Records that everything before `offset` has been written, and deletes segments that
+    /// are now fully consumed.
+    pub fn commit(&mut self, offset: u64) -> Result<()> {
+        if offset <= self.committed {
+            return Ok(());
+        }
+
+        let temp = self.dir.join(format!("{COMMITTED_FILE}.tmp"));
+        let mut file = File::create(&temp).context("Could not write committed offset")?;
+        file.write_all(offset.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, self.dir.join(COMMITTED_FILE))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        QUEUE_BACKLOG_BYTES.sub((offset - self.committed) as i64);
+        self.committed = offset;
+        self.shared.committed.store(offset, Ordering::Release);
+
+        let starts = segments(&self.dir)?;
+        for pair in starts.windows(2) {
+            if pair[1] <= offset {
+                fs::remove_file(segment_path(&self.dir, pair[0]))
+                    .context("Could not delete consumed segment")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the read position back to the committed offset so uncommitted frames are read
+    /// again.
+    pub fn rewind(&mut self) {
+        self.position = self.committed;
+        self.segment = None;
+    }
+}
@@ -0,0 +1,303 @@
+//! Daily summary workbooks, for utility engineers who exchange spreadsheets rather than query
+//! the database: one xlsx per UTC day, with a Summary sheet of energy, peaks, power factor and
+//! voltage range per stream and phase, and an Excursions sheet counting the capture triggers.
+//!
+//! They are built from the 15-minute rollups and `bibimbap_events`, so a day is only written
+//! once the storage manager (`--tiering`, here or on another instance) has rolled all of it up.
+//! Days whose workbook already exists are skipped, which makes a restart carry on where the
+//! last run stopped; the last `BACKFILL_DAYS` are looked at on every run.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_xlsxwriter::{Format, Workbook, Worksheet};
+use sqlx::{Pool, Postgres, Row};
+
+use crate::metrics::{DAILY_REPORT_ERRORS, DAILY_REPORTS};
+use crate::tiering;
+
+/// How often finished days are looked for.
+const CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Days before yesterday that are still written if their workbook is missing.
+const BACKFILL_DAYS: i64 = 7;
+
+/// Hours each 15-minute rollup covers, for turning its average power into energy.
+const ROLLUP_HOURS: f64 = 0.25;
+
+#[derive(Clone, Debug)]
+pub struct ReportConfig {
+    pub directory: PathBuf,
+    /// `device` the rows were written under
+    pub device: String,
+    pub tenant: Option<String>,
+}
+
+/// One stream and phase over a day. Quantities the publisher never sent are None.
+struct Summary {
+    stream: String,
+    phase: String,
+    /// From the 15-minute average real power; quarter hours without rows add nothing
+    energy_kwh: Option<f64>,
+    peak_real_power: Option<f64>,
+    peak_apparent_power: Option<f64>,
+    power_factor_min: Option<f64>,
+    power_factor_avg: Option<f64>,
+    power_factor_max: Option<f64>,
+    rms_voltage_min: Option<f64>,
+    rms_voltage_max: Option<f64>,
+}
+
+/// Capture triggers of one kind on one stream (and phase, for voltage) over a day.
+struct Excursions {
+    stream: String,
+    phase: Option<String>,
+    trigger: String,
+    count: i64,
+    min_value: f64,
+    max_value: f64,
+}
+
+impl ReportConfig {
+    fn path(&self, day: NaiveDate) -> PathBuf {
+        let name = match &self.tenant {
+            Some(tenant) => format!("{tenant}-daily-summary-{day}.xlsx"),
+            None => format!("daily-summary-{day}.xlsx"),
+        };
+        self.directory.join(name)
+    }
+}
+
+async fn summaries(
+    pool: &Pool<Postgres>,
+    config: &ReportConfig,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<Summary>> {
+    let rows = sqlx::query(
+        "SELECT stream, phase,
+             sum(real_power_avg) * $5 / 1000 AS energy_kwh,
+             max(real_power_max) AS peak_real_power,
+             max(apparent_power_max) AS peak_apparent_power,
+             min(power_factor_min) AS power_factor_min,
+             sum(power_factor_avg * samples)
+                 / nullif(sum(samples) FILTER (WHERE power_factor_avg IS NOT NULL), 0)
+                 AS power_factor_avg,
+             max(power_factor_max) AS power_factor_max,
+             min(rms_voltage_min) AS rms_voltage_min,
+             max(rms_voltage_max) AS rms_voltage_max
+         FROM bibimbap_rollup_15m
+         WHERE bucket >= $1 AND bucket < $2 AND device = $3 AND tenant IS NOT DISTINCT FROM $4
+         GROUP BY stream, phase
+         ORDER BY stream, phase",
+    )
+    .bind(from)
+    .bind(to)
+    .bind(&config.device)
+    .bind(&config.tenant)
+    .bind(ROLLUP_HOURS)
+    .fetch_all(pool)
+    .await
+    .context("Could not summarise the 15-minute rollups")?;
+
+    Ok(rows
+        .iter()
+        .map(|row| Summary {
+            stream: row.get("stream"),
+            phase: row.get("phase"),
+            energy_kwh: row.get("energy_kwh"),
+            peak_real_power: row.get("peak_real_power"),
+            peak_apparent_power: row.get("peak_apparent_power"),
+            power_factor_min: row.get("power_factor_min"),
+            power_factor_avg: row.get("power_factor_avg"),
+            power_factor_max: row.get("power_factor_max"),
+            rms_voltage_min: row.get("rms_voltage_min"),
+            rms_voltage_max: row.get("rms_voltage_max"),
+        })
+        .collect())
+}
+
+async fn excursions(
+    pool: &Pool<Postgres>,
+    config: &ReportConfig,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<Excursions>> {
+    let rows = sqlx::query(
+        "SELECT stream, phase, trigger, count(*) AS count,
+             min(value) AS min_value, max(value) AS max_value
+         FROM bibimbap_events
+         WHERE time >= $1 AND time < $2 AND device = $3 AND tenant IS NOT DISTINCT FROM $4
+         GROUP BY stream, phase, trigger
+         ORDER BY stream, phase, trigger",
+    )
+    .bind(from)
+    .bind(to)
+    .bind(&config.device)
+    .bind(&config.tenant)
+    .fetch_all(pool)
+    .await
+    .context("Could not count capture events")?;
+
+    Ok(rows
+        .iter()
+        .map(|row| Excursions {
+            stream: row.get("stream"),
+            phase: row.get("phase"),
+            trigger: row.get("trigger"),
+            count: row.get("count"),
+            min_value: row.get("min_value"),
+            max_value: row.get("max_value"),
+        })
+        .collect())
+}
+
+fn header(sheet: &mut Worksheet, columns: &[&str]) -> Result<()> {
+    let bold = Format::new().set_bold();
+    for (col, title) in columns.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, *title, &bold)?;
+        sheet.set_column_width(col as u16, title.len().max(10) as f64 + 2.0)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    Ok(())
+}
+
+/// Leaves the cell empty for a quantity that wasn't sent, rather than writing 0.
+fn number(sheet: &mut Worksheet, row: u32, col: u16, value: Option<f64>) -> Result<()> {
+    if let Some(value) = value.filter(|value| value.is_finite()) {
+        sheet.write_number(row, col, value)?;
+    }
+    Ok(())
+}
+
+fn workbook(summaries: &[Summary], excursions: &[Excursions]) -> Result<Vec<u8>> {
+    let mut workbook = Workbook::new();
+
+    let sheet = workbook.add_worksheet().set_name("Summary")?;
+    header(
+        sheet,
+        &[
+            "Stream",
+            "Phase",
+            "Energy (kWh)",
+            "Peak real power (W)",
+            "Peak apparent power (VA)",
+            "Min power factor",
+            "Avg power factor",
+            "Max power factor",
+            "Min RMS voltage (V)",
+            "Max RMS voltage (V)",
+        ],
+    )?;
+    for (summary, row) in summaries.iter().zip(1..) {
+        sheet.write_string(row, 0, &summary.stream)?;
+        sheet.write_string(row, 1, &summary.phase)?;
+        for (value, col) in [
+            summary.energy_kwh,
+            summary.peak_real_power,
+            summary.peak_apparent_power,
+            summary.power_factor_min,
+            summary.power_factor_avg,
+            summary.power_factor_max,
+            summary.rms_voltage_min,
+            summary.rms_voltage_max,
+        ]
+        .into_iter()
+        .zip(2..)
+        {
+            number(sheet, row, col, value)?;
+        }
+    }
+
+    let sheet = workbook.add_worksheet().set_name("Excursions")?;
+    header(
+        sheet,
+        &[
+            "Stream",
+            "Phase",
+            "Trigger",
+            "Count",
+            "Min value",
+            "Max value",
+        ],
+    )?;
+    for (excursions, row) in excursions.iter().zip(1..) {
+        sheet.write_string(row, 0, &excursions.stream)?;
+        // Power cap and step change triggers cover all phases (C when sent)
+        sheet.write_string(row, 1, excursions.phase.as_deref().unwrap_or("all"))?;
+        sheet.write_string(row, 2, &excursions.trigger)?;
+        sheet.write_number(row, 3, excursions.count as f64)?;
+        sheet.write_number(row, 4, excursions.min_value)?;
+        sheet.write_number(row, 5, excursions.max_value)?;
+    }
+
+    workbook
+        .save_to_buffer()
+        .context("Could not build the workbook")
+}
+
+/// Writes `day`'s workbook, unless there was nothing stored that day. Returns whether it did.
+async fn report(pool: &Pool<Postgres>, config: &ReportConfig, day: NaiveDate) -> Result<bool> {
+    let from = day.and_time(chrono::NaiveTime::MIN).and_utc();
+    let to = from + chrono::Duration::days(1);
+    let summaries = summaries(pool, config, from, to).await?;
+    let excursions = excursions(pool, config, from, to).await?;
+    if summaries.is_empty() && excursions.is_empty() {
+        return Ok(false);
+    }
+
+    let path = config.path(day);
+    // Written aside and renamed, so a half-written file never counts as done
+    let partial = path.with_extension("xlsx.partial");
+    tokio::fs::write(&partial, workbook(&summaries, &excursions)?)
+        .await
+        .with_context(|| format!("Could not write {}", partial.display()))?;
+    tokio::fs::rename(&partial, &path)
+        .await
+        .with_context(|| format!("Could not move {} into place", path.display()))?;
+    log::info!("Wrote the daily summary for {day} to {}", path.display());
+    Ok(true)
+}
+
+async fn run(pool: &Pool<Postgres>, config: &ReportConfig) -> Result<()> {
+    let Some(rolled_up_to) = tiering::quarter_hours_rolled_up_to(pool).await? else {
+        return Ok(());
+    };
+    let yesterday = Utc::now().date_naive() - chrono::Duration::days(1);
+    for back in (0..=BACKFILL_DAYS).rev() {
+        let day = yesterday - chrono::Duration::days(back);
+        let end = (day + chrono::Duration::days(1))
+            .and_time(chrono::NaiveTime::MIN)
+            .and_utc();
+        if end > rolled_up_to || tokio::fs::try_exists(config.path(day)).await? {
+            continue;
+        }
+        if report(pool, config, day).await? {
+            DAILY_REPORTS.inc();
+        }
+    }
+    Ok(())
+}
+
+/// Writes the workbooks of finished days every `CHECK_INTERVAL`, starting straight away.
+pub async fn write_daily(pool: Pool<Postgres>, config: ReportConfig) {
+    if let Err(err) = tokio::fs::create_dir_all(&config.directory).await {
+        log::error!(
+            "Not writing daily summaries: could not create {}: {err}",
+            config.directory.display()
+        );
+        return;
+    }
+    log::info!("Writing daily summaries to {}", config.directory.display());
+
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = run(&pool, &config).await {
+            DAILY_REPORT_ERRORS.inc();
+            log::error!("Daily summaries: {err:#}");
+        }
+    }
+}
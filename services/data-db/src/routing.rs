@@ -0,0 +1,211 @@
+//! Per-stream tables (`--route`): streams whose calculation name matches a route's pattern are
+//! written to its table instead of `bibimbap`, so feeders kept for different lengths of time
+//! can be pruned separately. A frame with streams for several tables becomes a row in each,
+//! with the same time, device and tenant. Routes are tried in order and the first match wins;
+//! streams matching none stay in `bibimbap`.
+//!
+//! Routed tables have the columns of `bibimbap` and are created on startup. Each is recorded
+//! in `bibimbap_routes` with its patterns and retention, where the storage manager finds it to
+//! roll it up with `bibimbap` and prune it past its own retention. A table stays there after
+//! its routes are removed, so what it holds is still pruned.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use crate::writer::{BatchWriter, Row};
+
+/// The default table, which routes may name too.
+pub const DEFAULT_TABLE: &str = "bibimbap";
+
+/// Tables data-db keeps for itself, which no route may write to.
+const RESERVED: &[&str] = &[
+    "bibimbap_measurements",
+    "bibimbap_highres",
+    "bibimbap_events",
+    "bibimbap_rollup_1m",
+    "bibimbap_rollup_15m",
+    "bibimbap_tiering",
+    "bibimbap_routes",
+    "streams",
+];
+
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub pattern: Regex,
+    pub table: String,
+}
+
+/// `REGEX=TABLE`; the pattern may contain `=` itself, the table can't.
+pub fn parse_route(value: &str) -> Result<Route> {
+    let Some((pattern, table)) = value.rsplit_once('=') else {
+        bail!("expected REGEX=TABLE");
+    };
+    check_table(table)?;
+    Ok(Route {
+        pattern: Regex::new(pattern)?,
+        table: table.to_string(),
+    })
+}
+
+/// A plain lowercase identifier, so it needs no quoting in SQL.
+fn check_table(table: &str) -> Result<()> {
+    let valid = table.len() <= 63
+        && table.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && table
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        bail!("table '{table}' must be lowercase letters, digits and underscores");
+    }
+    if RESERVED.contains(&table) {
+        bail!("table '{table}' is data-db's own");
+    }
+    Ok(())
+}
+
+/// One routed table, as recorded in `bibimbap_routes`.
+#[derive(Clone, Debug)]
+pub struct RoutedTable {
+    pub table: String,
+    pub patterns: Vec<String>,
+    /// None: the storage manager's --full-rate-retention
+    pub retention: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Routes {
+    routes: Vec<Route>,
+    retention: HashMap<String, Duration>,
+}
+
+impl Routes {
+    /// Fails for a retention given for a table no route writes to.
+    pub fn new(routes: Vec<Route>, retention: Vec<(String, Duration)>) -> Result<Self> {
+        let retention: HashMap<_, _> = retention.into_iter().collect();
+        for table in retention.keys() {
+            if !routes.iter().any(|route| &route.table == table) {
+                bail!("--route-retention for {table}, which no --route writes to");
+            }
+        }
+        Ok(Self { routes, retention })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// The table a stream's calculations go to.
+    pub fn table(&self, stream: &str) -> &str {
+        self.routes
+            .iter()
+            .find(|route| route.pattern.is_match(stream))
+            .map_or(DEFAULT_TABLE, |route| route.table.as_str())
+    }
+
+    /// Every table routes write to other than `bibimbap`, in the order first named.
+    pub fn tables(&self) -> Vec<RoutedTable> {
+        let mut tables: Vec<RoutedTable> = Vec::new();
+        for route in &self.routes {
+            if route.table == DEFAULT_TABLE {
+                continue;
+            }
+            match tables.iter_mut().find(|table| table.table == route.table) {
+                Some(table) => table.patterns.push(route.pattern.to_string()),
+                None => tables.push(RoutedTable {
+                    table: route.table.clone(),
+                    patterns: vec![route.pattern.to_string()],
+                    retention: self.retention.get(&route.table).copied(),
+                }),
+            }
+        }
+        tables
+    }
+
+    /// Splits a row into one per table its streams go to.
+    fn split(&self, row: Row) -> Vec<(&str, Row)> {
+        let data = match row.data {
+            Value::Object(data) => data,
+            data => return vec![(DEFAULT_TABLE, Row { data, ..row })],
+        };
+        let mut tables: Vec<(&str, Map<String, Value>)> = Vec::new();
+        for (stream, calculation) in data {
+            let table = self.table(&stream);
+            match tables.iter_mut().find(|(name, _)| *name == table) {
+                Some((_, data)) => {
+                    data.insert(stream, calculation);
+                }
+                None => tables.push((table, Map::from_iter([(stream, calculation)]))),
+            }
+        }
+
+        let mut measurements = row.measurements;
+        tables
+            .into_iter()
+            .map(|(table, data)| {
+                let (ours, rest) = std::mem::take(&mut measurements)
+                    .into_iter()
+                    .partition(|measurement| data.contains_key(&measurement.stream));
+                measurements = rest;
+                let row = Row {
+                    time: row.time,
+                    device: row.device.clone(),
+                    tenant: row.tenant.clone(),
+                    data: Value::Object(data),
+                    measurements: ours,
+                };
+                (table, row)
+            })
+            .collect()
+    }
+}
+
+/// The Postgres sink: `bibimbap`'s writer, and one for each routed table.
+pub struct RoutedWriter {
+    routes: Routes,
+    writers: HashMap<String, BatchWriter>,
+}
+
+impl RoutedWriter {
+    /// `writer` makes the writer for a table.
+    pub fn new(routes: Routes, writer: impl Fn(String) -> BatchWriter) -> Self {
+        let writers = std::iter::once(DEFAULT_TABLE.to_string())
+            .chain(routes.tables().into_iter().map(|table| table.table))
+            .map(|table| (table.clone(), writer(table)))
+            .collect();
+        Self { routes, writers }
+    }
+
+    pub fn push(&mut self, row: Row) {
+        let rows = match self.routes.is_empty() {
+            true => vec![(DEFAULT_TABLE, row)],
+            false => self.routes.split(row),
+        };
+        for (table, row) in rows {
+            let writer = self.writers.get_mut(table);
+            writer.expect("a writer for every table").push(row);
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.writers.values().any(BatchWriter::is_full)
+    }
+
+    pub async fn flush_if_full(&mut self) {
+        for writer in self.writers.values_mut() {
+            writer.flush_if_full().await;
+        }
+    }
+
+    /// Flushes every table, true if all of them took their rows.
+    pub async fn flush(&mut self) -> bool {
+        let mut written = true;
+        for writer in self.writers.values_mut() {
+            written &= writer.flush().await;
+        }
+        written
+    }
+}
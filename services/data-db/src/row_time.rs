@@ -0,0 +1,74 @@
+//! The time a row is stored under. By default that is when data-db received the frame, which
+//! misplaces frames that sat in a buffer or are being replayed. --row-time provenance-earliest
+//! or provenance-latest stores the publisher's own UTC timestamp instead, across the row's
+//! streams. Rows without one, or whose timestamp is further than --row-time-max-skew from
+//! arrival, fall back to the arrival time.
+//!
+//! However rows are timed, `data_db_provenance_skew_seconds` tracks how far behind arrival the
+//! provenance timestamps are.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::metrics::{PROVENANCE_SKEW, ROW_TIME_FALLBACKS};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RowTime {
+    /// When data-db received the frame
+    #[default]
+    Arrival,
+    /// The earliest provenance timestamp of the row's streams
+    ProvenanceEarliest,
+    /// The latest provenance timestamp of the row's streams
+    ProvenanceLatest,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RowTimeConfig {
+    pub source: RowTime,
+    /// Provenance timestamps further than this from arrival, either way, aren't trusted
+    pub max_skew: Option<Duration>,
+}
+
+impl RowTimeConfig {
+    /// The time to store a row under, given when it arrived and its streams' provenance times.
+    pub fn time(
+        &self,
+        received: DateTime<Utc>,
+        provenance: impl IntoIterator<Item = DateTime<Utc>>,
+    ) -> DateTime<Utc> {
+        let mut provenance = provenance.into_iter();
+        let Some(first) = provenance.next() else {
+            if self.source != RowTime::Arrival {
+                ROW_TIME_FALLBACKS
+                    .with_label_values(&["no_provenance"])
+                    .inc();
+            }
+            return received;
+        };
+        let (earliest, latest) = provenance.fold((first, first), |(earliest, latest), time| {
+            (earliest.min(time), latest.max(time))
+        });
+        let chosen = match self.source {
+            RowTime::ProvenanceEarliest => earliest,
+            RowTime::Arrival | RowTime::ProvenanceLatest => latest,
+        };
+        let skew = received - chosen;
+        PROVENANCE_SKEW.set(skew.num_milliseconds() as f64 / 1000.0);
+
+        if self.source == RowTime::Arrival {
+            return received;
+        }
+        if let Some(max_skew) = self.max_skew
+            && skew.abs().to_std().is_ok_and(|skew| skew > max_skew)
+        {
+            ROW_TIME_FALLBACKS.with_label_values(&["skew"]).inc();
+            log::debug!(
+                "Provenance time {chosen} is {skew} from arrival, storing the row at {received}"
+            );
+            return received;
+        }
+        chosen
+    }
+}
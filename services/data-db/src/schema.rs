@@ -0,0 +1,531 @@
+use anyhow::{Context, Result};
+use sqlx::{Connection, Executor, PgConnection, Pool, Postgres, postgres::PgConnectOptions};
+
+use crate::routing::RoutedTable;
+
+// Keep in sync with charts/karman-lab/files/timescale-init.sql
+const TABLE: &str = "CREATE TABLE IF NOT EXISTS bibimbap (
+  time   TIMESTAMPTZ NOT NULL,
+  device TEXT        NOT NULL,
+  data   JSONB       NOT NULL,
+  tenant TEXT
+)";
+
+const HYPERTABLE: &str = "SELECT public.create_hypertable('bibimbap', 'time',
+  partitioning_column => 'device',
+  number_partitions => 4,
+  if_not_exists => TRUE)";
+
+/// Normalized layout: one typed row per stream and phase, written alongside the JSONB table in
+/// `--schema-mode dual`.
+const MEASUREMENTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS bibimbap_measurements (
+  time                       TIMESTAMPTZ      NOT NULL,
+  device                     TEXT             NOT NULL,
+  stream                     TEXT             NOT NULL,
+  phase                      TEXT             NOT NULL,
+  tenant                     TEXT,
+  rms_voltage                DOUBLE PRECISION,
+  dc_offset_voltage          DOUBLE PRECISION,
+  rms_current                DOUBLE PRECISION,
+  dc_offset_current          DOUBLE PRECISION,
+  real_power                 DOUBLE PRECISION,
+  apparent_power             DOUBLE PRECISION,
+  reactive_power             DOUBLE PRECISION,
+  power_factor               DOUBLE PRECISION,
+  three_phase_real_power     DOUBLE PRECISION,
+  three_phase_reactive_power DOUBLE PRECISION,
+  crest_factor_voltage       DOUBLE PRECISION,
+  thd_voltage                DOUBLE PRECISION,
+  crest_factor_current       DOUBLE PRECISION,
+  thd_current                DOUBLE PRECISION
+)";
+
+const MEASUREMENTS_HYPERTABLE: &str =
+    "SELECT public.create_hypertable('bibimbap_measurements', 'time',
+  partitioning_column => 'device',
+  number_partitions => 4,
+  if_not_exists => TRUE)";
+
+/// Full-rate rows around capture events, in the layout of `bibimbap`.
+const HIGHRES_TABLE: &str = "CREATE TABLE IF NOT EXISTS bibimbap_highres (
+  time   TIMESTAMPTZ NOT NULL,
+  device TEXT        NOT NULL,
+  data   JSONB       NOT NULL,
+  tenant TEXT
+)";
+
+const HIGHRES_HYPERTABLE: &str = "SELECT public.create_hypertable('bibimbap_highres', 'time',
+  partitioning_column => 'device',
+  number_partitions => 4,
+  if_not_exists => TRUE)";
+
+/// What started each capture; the captured rows are the ones in `bibimbap_highres` around
+/// `time`.
+const EVENTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS bibimbap_events (
+  time      TIMESTAMPTZ      NOT NULL,
+  device    TEXT             NOT NULL,
+  tenant    TEXT,
+  trigger   TEXT             NOT NULL,
+  stream    TEXT             NOT NULL,
+  phase     TEXT,
+  value     DOUBLE PRECISION NOT NULL,
+  threshold DOUBLE PRECISION NOT NULL
+)";
+
+/// Per-minute and per-15-minute aggregates of every stream and phase, maintained by the
+/// storage manager (`--tiering`). `samples` weights the averages when rolling up further.
+const ROLLUP_COLUMNS: &str = "(
+  bucket             TIMESTAMPTZ      NOT NULL,
+  device             TEXT             NOT NULL,
+  tenant             TEXT,
+  stream             TEXT             NOT NULL,
+  phase              TEXT             NOT NULL,
+  samples            BIGINT           NOT NULL,
+  real_power_avg     DOUBLE PRECISION,
+  real_power_min     DOUBLE PRECISION,
+  real_power_max     DOUBLE PRECISION,
+  reactive_power_avg DOUBLE PRECISION,
+  reactive_power_min DOUBLE PRECISION,
+  reactive_power_max DOUBLE PRECISION,
+  apparent_power_avg DOUBLE PRECISION,
+  apparent_power_min DOUBLE PRECISION,
+  apparent_power_max DOUBLE PRECISION,
+  power_factor_avg   DOUBLE PRECISION,
+  power_factor_min   DOUBLE PRECISION,
+  power_factor_max   DOUBLE PRECISION,
+  rms_voltage_avg    DOUBLE PRECISION,
+  rms_voltage_min    DOUBLE PRECISION,
+  rms_voltage_max    DOUBLE PRECISION,
+  rms_current_avg    DOUBLE PRECISION,
+  rms_current_min    DOUBLE PRECISION,
+  rms_current_max    DOUBLE PRECISION
+)";
+
+const ROLLUP_TABLES: &[&str] = &["bibimbap_rollup_1m", "bibimbap_rollup_15m"];
+
+/// How far each rollup tier has got: everything before `rolled_up_to` has been aggregated.
+const TIERING_TABLE: &str = "CREATE TABLE IF NOT EXISTS bibimbap_tiering (
+  tier         TEXT        PRIMARY KEY,
+  rolled_up_to TIMESTAMPTZ NOT NULL
+)";
+
+/// Tables `--route` writes to, which the storage manager rolls up and prunes along with
+/// `bibimbap`. `retention_seconds` is null for --full-rate-retention.
+const ROUTES_TABLE: &str = "CREATE TABLE IF NOT EXISTS bibimbap_routes (
+  table_name        TEXT        PRIMARY KEY,
+  patterns          TEXT[]      NOT NULL,
+  retention_seconds BIGINT,
+  updated_at        TIMESTAMPTZ NOT NULL
+)";
+
+/// Columns added after the tables were first released, for tables created by an older data-db.
+const UPGRADE: &str = "ALTER TABLE bibimbap ADD COLUMN IF NOT EXISTS tenant TEXT";
+
+const MEASUREMENTS_UPGRADE: &str = "ALTER TABLE bibimbap_measurements
+  ADD COLUMN IF NOT EXISTS tenant TEXT,
+  ADD COLUMN IF NOT EXISTS crest_factor_voltage DOUBLE PRECISION,
+  ADD COLUMN IF NOT EXISTS thd_voltage DOUBLE PRECISION,
+  ADD COLUMN IF NOT EXISTS crest_factor_current DOUBLE PRECISION,
+  ADD COLUMN IF NOT EXISTS thd_current DOUBLE PRECISION";
+
+const MEASUREMENTS_INDEX: &str = "CREATE INDEX IF NOT EXISTS bibimbap_measurements_stream_time_idx \
+     ON bibimbap_measurements (stream, time DESC)";
+
+const INDEXES: &[&str] = &[
+    "CREATE INDEX IF NOT EXISTS bibimbap_time_idx ON bibimbap (time DESC)",
+    "CREATE INDEX IF NOT EXISTS bibimbap_device_idx ON bibimbap (device)",
+    "CREATE INDEX IF NOT EXISTS bibimbap_tenant_time_idx ON bibimbap (tenant, time DESC)",
+    MEASUREMENTS_INDEX,
+    "CREATE INDEX IF NOT EXISTS bibimbap_highres_time_idx ON bibimbap_highres (time DESC)",
+    "CREATE INDEX IF NOT EXISTS bibimbap_events_time_idx ON bibimbap_events (time DESC)",
+];
+
+const TABLES: &[&str] = &[
+    "bibimbap",
+    "bibimbap_measurements",
+    "bibimbap_highres",
+    "bibimbap_events",
+    "streams",
+    "bibimbap_rollup_1m",
+    "bibimbap_rollup_15m",
+];
+
+/// Which layouts data-db writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchemaMode {
+    /// The legacy JSONB `bibimbap` table only
+    Json,
+    /// Both `bibimbap` and `bibimbap_measurements`, in one transaction, for migrating between
+    /// them
+    Dual,
+    /// The typed `bibimbap_measurements` table only: one row per stream and phase, one column
+    /// per measurement
+    Columns,
+}
+
+/// Brings `bibimbap_measurements` up to date when data-db starts in a mode that writes it: the
+/// table (a hypertable when TimescaleDB is installed), columns added since, and its index. For
+/// databases set up by an older `--init-schema`, or before the normalized table existed.
+pub async fn migrate(pool: &Pool<Postgres>, mode: SchemaMode) -> Result<()> {
+    if mode == SchemaMode::Json {
+        return Ok(());
+    }
+    let timescale: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb')",
+    )
+    .fetch_one(pool)
+    .await
+    .context("Could not check for TimescaleDB")?;
+
+    pool.execute(MEASUREMENTS_TABLE)
+        .await
+        .context("Could not create bibimbap_measurements")?;
+    if timescale {
+        pool.execute(MEASUREMENTS_HYPERTABLE)
+            .await
+            .context("Could not make bibimbap_measurements a hypertable")?;
+    }
+    pool.execute(MEASUREMENTS_UPGRADE)
+        .await
+        .context("Could not add new columns to bibimbap_measurements")?;
+    pool.execute(MEASUREMENTS_INDEX)
+        .await
+        .context("Could not index bibimbap_measurements")?;
+    Ok(())
+}
+
+/// Creates the tables routes write to (as hypertables when TimescaleDB is installed) and
+/// records them with their retention in `bibimbap_routes`. Runs on every start, as
+/// [`migrate`] does, since routes are added without running --init-schema again.
+pub async fn migrate_routes(pool: &Pool<Postgres>, tables: &[RoutedTable]) -> Result<()> {
+    if tables.is_empty() {
+        return Ok(());
+    }
+    let timescale: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb')",
+    )
+    .fetch_one(pool)
+    .await
+    .context("Could not check for TimescaleDB")?;
+
+    let mut conn = pool.acquire().await.context("Could not connect")?;
+    create_routed_tables(&mut conn, tables, timescale).await?;
+    for table in tables {
+        sqlx::query(
+            "INSERT INTO bibimbap_routes (table_name, patterns, retention_seconds, updated_at)
+             VALUES ($1, $2, $3, now())
+             ON CONFLICT (table_name) DO UPDATE SET patterns = EXCLUDED.patterns,
+               retention_seconds = EXCLUDED.retention_seconds, updated_at = now()",
+        )
+        .bind(&table.table)
+        .bind(&table.patterns)
+        .bind(table.retention.map(|retention| retention.as_secs() as i64))
+        .execute(&mut *conn)
+        .await
+        .with_context(|| format!("Could not record the route to {}", table.table))?;
+        log::info!(
+            "Streams matching {} go to {}",
+            table.patterns.join(", "),
+            table.table
+        );
+    }
+    Ok(())
+}
+
+/// `bibimbap_routes` and every routed table with its indexes, in the layout of `bibimbap`.
+async fn create_routed_tables(
+    conn: &mut PgConnection,
+    tables: &[RoutedTable],
+    timescale: bool,
+) -> Result<()> {
+    conn.execute(ROUTES_TABLE)
+        .await
+        .context("Could not create bibimbap_routes")?;
+    for RoutedTable { table, .. } in tables {
+        let mut statements = vec![format!(
+            "CREATE TABLE IF NOT EXISTS {table} (LIKE bibimbap INCLUDING DEFAULTS)"
+        )];
+        if timescale {
+            statements.push(format!(
+                "SELECT public.create_hypertable('{table}', 'time',
+                   partitioning_column => 'device',
+                   number_partitions => 4,
+                   if_not_exists => TRUE)"
+            ));
+        }
+        statements.extend([
+            format!("CREATE INDEX IF NOT EXISTS {table}_time_idx ON {table} (time DESC)"),
+            format!(
+                "CREATE INDEX IF NOT EXISTS {table}_tenant_time_idx ON {table} (tenant, time DESC)"
+            ),
+        ]);
+        for statement in statements {
+            conn.execute(statement.as_str())
+                .await
+                .with_context(|| format!("Could not create {table}"))?;
+        }
+    }
+    Ok(())
+}
+
+pub struct Grants {
+    /// Roles that get SELECT and INSERT on the table (e.g. the data-db service account)
+    pub writers: Vec<String>,
+    /// Roles that only get SELECT (e.g. Grafana)
+    pub readers: Vec<String>,
+    /// Roles that may also update and delete, as the storage manager does (normally the
+    /// data-db service account when it runs with --tiering)
+    pub storage_managers: Vec<String>,
+    /// Roles that only get SELECT on one tenant's rows, as (role, tenant). Turns on row-level
+    /// security, under which every role above sees all rows and these see their tenant's.
+    pub tenant_readers: Vec<(String, String)>,
+}
+
+/// Creates everything data-db needs from scratch: the database named in the connection
+/// string, the bibimbap, bibimbap_measurements and bibimbap_highres tables (as hypertables
+/// when TimescaleDB is available), bibimbap_events, the rollup tables, the tables of `routes`,
+/// their indexes, the stream registry, and the requested role grants and tenant policies. Safe
+/// to run repeatedly.
+pub async fn init(connection_string: &str, grants: &Grants, routes: &[RoutedTable]) -> Result<()> {
+    let options: PgConnectOptions = connection_string
+        .parse()
+        .context("Invalid connection string")?;
+    let database = options
+        .get_database()
+        .context("Connection string does not name a database")?
+        .to_string();
+
+    create_database(&options, &database).await?;
+
+    let mut conn = PgConnection::connect_with(&options)
+        .await
+        .context("Could not connect to database")?;
+
+    let timescale = match conn
+        .execute("CREATE EXTENSION IF NOT EXISTS timescaledb")
+        .await
+    {
+        Ok(_) => {
+            for (table, hypertable) in [
+                (TABLE, HYPERTABLE),
+                (MEASUREMENTS_TABLE, MEASUREMENTS_HYPERTABLE),
+                (HIGHRES_TABLE, HIGHRES_HYPERTABLE),
+            ] {
+                conn.execute(table)
+                    .await
+                    .context("Could not create table")?;
+                conn.execute(hypertable)
+                    .await
+                    .context("Could not create hypertable")?;
+            }
+            true
+        }
+        Err(err) => {
+            log::warn!("TimescaleDB unavailable, creating plain tables instead: {err}");
+            for table in [TABLE, MEASUREMENTS_TABLE, HIGHRES_TABLE] {
+                conn.execute(table)
+                    .await
+                    .context("Could not create table")?;
+            }
+            false
+        }
+    };
+
+    conn.execute(EVENTS_TABLE)
+        .await
+        .context("Could not create table")?;
+
+    for table in ROLLUP_TABLES {
+        conn.execute(format!("CREATE TABLE IF NOT EXISTS {table} {ROLLUP_COLUMNS}").as_str())
+            .await
+            .context("Could not create rollup table")?;
+        // The key the storage manager upserts on
+        conn.execute(
+            format!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS {table}_key \
+                 ON {table} (bucket, device, (coalesce(tenant, '')), stream, phase)"
+            )
+            .as_str(),
+        )
+        .await
+        .context("Could not create rollup key")?;
+    }
+    conn.execute(TIERING_TABLE)
+        .await
+        .context("Could not create table")?;
+    create_routed_tables(&mut conn, routes, timescale).await?;
+
+    for upgrade in [UPGRADE, MEASUREMENTS_UPGRADE] {
+        conn.execute(upgrade)
+            .await
+            .with_context(|| format!("Could not add new columns: {upgrade}"))?;
+    }
+
+    for index in INDEXES {
+        conn.execute(*index)
+            .await
+            .with_context(|| format!("Could not create index: {index}"))?;
+    }
+
+    for statement in stream_registry::SCHEMA {
+        conn.execute(*statement)
+            .await
+            .context("Could not create the streams table")?;
+    }
+
+    let tables: Vec<&str> = TABLES
+        .iter()
+        .copied()
+        .chain(routes.iter().map(|routed| routed.table.as_str()))
+        .collect();
+    let database = quote_ident(&database);
+    for (role, privileges) in grants
+        .writers
+        .iter()
+        .map(|role| (role, "SELECT, INSERT"))
+        .chain(
+            grants
+                .storage_managers
+                .iter()
+                .map(|role| (role, "SELECT, INSERT, UPDATE, DELETE")),
+        )
+        .chain(grants.readers.iter().map(|role| (role, "SELECT")))
+        .chain(
+            grants
+                .tenant_readers
+                .iter()
+                .map(|(role, _)| (role, "SELECT")),
+        )
+    {
+        let role = quote_ident(role);
+        let tables = tables.join(", ");
+        for statement in [
+            format!("GRANT CONNECT ON DATABASE {database} TO {role}"),
+            format!("GRANT USAGE ON SCHEMA public TO {role}"),
+            format!("GRANT {privileges} ON {tables} TO {role}"),
+        ] {
+            conn.execute(statement.as_str())
+                .await
+                .with_context(|| format!("Could not apply grant: {statement}"))?;
+        }
+        log::info!("Granted {privileges} on {tables} to {role}");
+    }
+    for role in &grants.storage_managers {
+        let statement = format!(
+            "GRANT SELECT, INSERT, UPDATE ON bibimbap_tiering TO {}",
+            quote_ident(role)
+        );
+        conn.execute(statement.as_str())
+            .await
+            .with_context(|| format!("Could not apply grant: {statement}"))?;
+    }
+    // Writers record their routes on startup; readers may want to know where a stream went
+    for (role, privileges) in grants
+        .writers
+        .iter()
+        .chain(&grants.storage_managers)
+        .map(|role| (role, "SELECT, INSERT, UPDATE"))
+        .chain(grants.readers.iter().map(|role| (role, "SELECT")))
+    {
+        let statement = format!(
+            "GRANT {privileges} ON bibimbap_routes TO {}",
+            quote_ident(role)
+        );
+        conn.execute(statement.as_str())
+            .await
+            .with_context(|| format!("Could not apply grant: {statement}"))?;
+    }
+
+    if !grants.tenant_readers.is_empty() {
+        isolate_tenants(&mut conn, grants, &tables).await?;
+    }
+
+    conn.close().await.ok();
+    log::info!("Schema initialised in database {database}");
+    Ok(())
+}
+
+/// Enables row-level security with a policy per granted role: writers, storage managers and
+/// readers see everything, tenant readers only rows with their tenant. The table owner (normally the role
+/// running --init-schema) bypasses the policies.
+async fn isolate_tenants(conn: &mut PgConnection, grants: &Grants, tables: &[&str]) -> Result<()> {
+    let policies = grants
+        .writers
+        .iter()
+        .chain(&grants.storage_managers)
+        .map(|role| (role, "ALL", "true".to_string()))
+        .chain(
+            grants
+                .readers
+                .iter()
+                .map(|role| (role, "SELECT", "true".to_string())),
+        )
+        .chain(grants.tenant_readers.iter().map(|(role, tenant)| {
+            (
+                role,
+                "SELECT",
+                format!("tenant = {}", quote_literal(tenant)),
+            )
+        }));
+
+    for (role, command, condition) in policies {
+        for table in tables {
+            let policy = quote_ident(&format!("{table}_{role}"));
+            let role = quote_ident(role);
+            let check = if command == "ALL" {
+                format!(" WITH CHECK ({condition})")
+            } else {
+                String::new()
+            };
+            for statement in [
+                format!("ALTER TABLE {table} ENABLE ROW LEVEL SECURITY"),
+                format!("DROP POLICY IF EXISTS {policy} ON {table}"),
+                format!(
+                    "CREATE POLICY {policy} ON {table} FOR {command} TO {role} \
+                     USING ({condition}){check}"
+                ),
+            ] {
+                conn.execute(statement.as_str())
+                    .await
+                    .with_context(|| format!("Could not apply policy: {statement}"))?;
+            }
+        }
+        log::info!("Row-level security policy for {role}: {condition}");
+    }
+    Ok(())
+}
+
+/// CREATE DATABASE can't run inside the target database (it may not exist yet), so this goes
+/// through the `postgres` maintenance database on the same server.
+async fn create_database(options: &PgConnectOptions, database: &str) -> Result<()> {
+    let mut conn = PgConnection::connect_with(&options.clone().database("postgres"))
+        .await
+        .context("Could not connect to the postgres maintenance database")?;
+
+    let exists: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pg_database WHERE datname = $1)")
+            .bind(database)
+            .fetch_one(&mut conn)
+            .await
+            .context("Could not check for database")?;
+
+    if exists {
+        log::info!("Database {database} already exists");
+    } else {
+        conn.execute(format!("CREATE DATABASE {}", quote_ident(database)).as_str())
+            .await
+            .context("Could not create database")?;
+        log::info!("Created database {database}");
+    }
+
+    conn.close().await.ok();
+    Ok(())
+}
+
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
@@ -0,0 +1,100 @@
+//! Where decoded data goes (`--sink`, repeatable): the Postgres tables, InfluxDB, Kafka,
+//! stdout, or any of them together.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::influx::InfluxSink;
+use crate::kafka::KafkaSink;
+use crate::routing::RoutedWriter;
+use crate::stdout::StdoutSink;
+use crate::writer::Row;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Sink {
+    /// The bibimbap tables, through --connection-string
+    Postgres,
+    /// A record per stream on --kafka-topic
+    Kafka,
+    /// Line protocol points in --influx-bucket
+    Influx,
+    /// The rows as JSON lines, for checking a publisher without a database
+    Stdout,
+}
+
+/// The sinks the write loops flush. The decoder hands Kafka every frame as it decodes it, so
+/// rows only go on to Postgres, InfluxDB and stdout.
+pub struct Sinks {
+    postgres: Option<RoutedWriter>,
+    influx: Option<InfluxSink>,
+    kafka: Option<Arc<KafkaSink>>,
+    stdout: Option<StdoutSink>,
+    flush_interval: Duration,
+}
+
+impl Sinks {
+    pub fn new(
+        postgres: Option<RoutedWriter>,
+        influx: Option<InfluxSink>,
+        kafka: Option<Arc<KafkaSink>>,
+        stdout: Option<StdoutSink>,
+        flush_interval: Duration,
+    ) -> Self {
+        Self {
+            postgres,
+            influx,
+            kafka,
+            stdout,
+            flush_interval,
+        }
+    }
+
+    pub fn push(&mut self, row: Row) {
+        if let Some(stdout) = &mut self.stdout {
+            stdout.print(&row);
+        }
+        if let Some(influx) = &mut self.influx {
+            influx.push(&row);
+        }
+        if let Some(writer) = &mut self.postgres {
+            writer.push(row);
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.postgres.as_ref().is_some_and(RoutedWriter::is_full)
+            || self.influx.as_ref().is_some_and(InfluxSink::is_full)
+    }
+
+    pub async fn flush_if_full(&mut self) {
+        if let Some(influx) = &mut self.influx {
+            influx.flush_if_full().await;
+        }
+        if let Some(writer) = &mut self.postgres {
+            writer.flush_if_full().await;
+        }
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    /// Flushes every sink. True if what was handed over so far is stored: by Postgres and
+    /// InfluxDB, whichever are sinks, as Kafka's losses are only counted then, and by Kafka
+    /// otherwise. Stdout is printed as rows are pushed.
+    pub async fn flush(&mut self) -> bool {
+        let kafka = match &self.kafka {
+            Some(kafka) => kafka.flush().await,
+            None => true,
+        };
+        let influx = match &mut self.influx {
+            Some(influx) => Some(influx.flush().await),
+            None => None,
+        };
+        match (&mut self.postgres, influx) {
+            (Some(writer), influx) => writer.flush().await && influx.unwrap_or(true),
+            (None, Some(influx)) => influx,
+            (None, None) => kafka,
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! `--sink stdout`: every row data-db would write to Postgres, printed as one JSON object per
+//! line with its time, device, tenant and `data` in the --json-schema-version layout, so a new
+//! publisher can be checked before there is a database. `--dry-run` makes it the only sink.
+//! Logs go to stderr, so stdout holds nothing but rows.
+//!
+//! `--stdout-max-rows-per-second` keeps a full-rate feed readable; rows over the limit are left
+//! out and how many is logged once the next second starts.
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::writer::Row;
+use stored_document::SchemaVersion;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutConfig {
+    /// Indented over several lines instead of one object per line
+    pub pretty: bool,
+    pub max_rows_per_second: Option<u32>,
+    pub schema_version: SchemaVersion,
+}
+
+#[derive(Serialize)]
+struct Printed<'a> {
+    time: DateTime<Utc>,
+    device: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    data: serde_json::Value,
+}
+
+pub struct StdoutSink {
+    config: StdoutConfig,
+    second_started: Instant,
+    printed: u32,
+    /// Left out over the limit this second
+    dropped: u64,
+}
+
+impl StdoutSink {
+    pub fn new(config: StdoutConfig) -> Self {
+        Self {
+            config,
+            second_started: Instant::now(),
+            printed: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn print(&mut self, row: &Row) {
+        if let Some(limit) = self.config.max_rows_per_second {
+            if self.second_started.elapsed() >= Duration::from_secs(1) {
+                if self.dropped > 0 {
+                    log::info!(
+                        "Left {} rows out of stdout over --stdout-max-rows-per-second",
+                        self.dropped
+                    );
+                }
+                self.second_started = Instant::now();
+                self.printed = 0;
+                self.dropped = 0;
+            }
+            if self.printed >= limit {
+                self.dropped += 1;
+                return;
+            }
+            self.printed += 1;
+        }
+
+        let printed = Printed {
+            time: row.time,
+            device: &row.device,
+            tenant: row.tenant.as_deref(),
+            data: self.config.schema_version.document(&row.data),
+        };
+        let mut stdout = std::io::stdout().lock();
+        let written = if self.config.pretty {
+            serde_json::to_writer_pretty(&mut stdout, &printed)
+        } else {
+            serde_json::to_writer(&mut stdout, &printed)
+        };
+        if let Err(err) = written
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(stdout))
+        {
+            log::warn!("Could not print a row: {err}");
+        }
+    }
+}
@@ -0,0 +1,515 @@
+//! The storage manager: full-rate rows are rolled up into `bibimbap_rollup_1m`, and those into
+//! `bibimbap_rollup_15m`. Each tier is pruned once it is past its retention, but never before
+//! it has been rolled up into the next. The 15-minute tier is kept indefinitely.
+//!
+//! Rollups only cover complete buckets, and how far each tier has got is kept in
+//! `bibimbap_tiering`, so a restart carries on where the last run stopped. Under
+//! `--late-data-policy correct`, every run also aggregates the buckets within the lateness
+//! window behind that point again, so rows stored after their bucket was rolled up (accepted
+//! late, redelivered from the durable queue, retried) are included.
+//!
+//! Tables recorded in `bibimbap_routes` are rolled up together with `bibimbap`, and each is
+//! pruned past its own retention, or --full-rate-retention when it has none.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres, Transaction};
+use stored_document::streams_lateral;
+
+use crate::metrics::{
+    TIERING_ERRORS, TIERING_PRUNED_TO, TIERING_ROLLED_UP_TO, TIERING_ROLLUP_ROWS,
+};
+use crate::schema::SchemaMode;
+
+/// Keys of the JSONB phase buckets (and columns of `bibimbap_measurements`) that are
+/// aggregated, as `<key>_avg`, `_min` and `_max`.
+const FIELDS: &[&str] = &[
+    "real_power",
+    "reactive_power",
+    "apparent_power",
+    "power_factor",
+    "rms_voltage",
+    "rms_current",
+];
+
+/// Full-rate tables, pruned together once they have been rolled up.
+const FULL_RATE_TABLES: &[&str] = &["bibimbap", "bibimbap_measurements"];
+
+/// How far behind "now" the last rolled-up minute ends, on top of the flush interval, so rows
+/// still being retried make it into their bucket.
+const SETTLE: Duration = Duration::from_secs(60);
+
+/// Buckets aggregated per transaction, so catching up on a large backlog makes steady progress.
+const BUCKETS_PER_STEP: i64 = 60;
+
+/// Advisory lock held for each step, so data-db instances sharing a database take turns.
+const LOCK: i64 = 0x6269_6269_7469_6572;
+
+#[derive(Clone, Debug)]
+pub struct TieringConfig {
+    pub full_rate_retention: Duration,
+    pub minute_rollup_retention: Duration,
+    /// How often rollups and pruning run
+    pub interval: Duration,
+    /// How far behind its watermark each tier is re-aggregated on every run, if at all
+    pub correction_window: Option<Duration>,
+    /// Which full-rate table the 1-minute rollups are read from
+    pub schema_mode: SchemaMode,
+}
+
+struct Tier {
+    name: &'static str,
+    table: &'static str,
+    width_secs: i64,
+    source: Source,
+}
+
+enum Source {
+    /// Full-rate rows in the JSONB `bibimbap` table and the tables routed streams go to
+    Json,
+    /// Full-rate rows in `bibimbap_measurements`
+    Columns,
+    Rollup(&'static Tier),
+}
+
+const MINUTE: Tier = Tier {
+    name: "1m",
+    table: "bibimbap_rollup_1m",
+    width_secs: 60,
+    source: Source::Json,
+};
+
+/// The same tier, for data-db writing only the typed table (`--schema-mode columns`).
+const MINUTE_FROM_COLUMNS: Tier = Tier {
+    source: Source::Columns,
+    ..MINUTE
+};
+
+const QUARTER_HOUR: Tier = Tier {
+    name: "15m",
+    table: "bibimbap_rollup_15m",
+    width_secs: 900,
+    source: Source::Rollup(&MINUTE),
+};
+
+enum Step {
+    /// Rolled up to here, and there may be more
+    Advanced(DateTime<Utc>),
+    Done,
+    /// Another instance holds the lock
+    Busy,
+}
+
+impl Tier {
+    fn floor(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let secs = time.timestamp().div_euclid(self.width_secs) * self.width_secs;
+        DateTime::from_timestamp(secs, 0).unwrap_or(time)
+    }
+
+    /// Aggregates `[$1, $2)` of the source into this tier. `json` is where JSONB rows are read
+    /// from, as made by `json_source`.
+    fn rollup_query(&self, json: &str) -> String {
+        let columns: Vec<String> = FIELDS
+            .iter()
+            .flat_map(|field| ["avg", "min", "max"].map(|stat| format!("{field}_{stat}")))
+            .collect();
+        let updates = columns
+            .iter()
+            .map(|column| format!("{column} = EXCLUDED.{column}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let columns = columns.join(", ");
+        let bucket = |column: &str| {
+            format!(
+                "date_bin('{} seconds', {column}, TIMESTAMPTZ 'epoch')",
+                self.width_secs
+            )
+        };
+
+        let select = match self.source {
+            Source::Json => {
+                // serde_json writes NaN as null, which the aggregates skip
+                let aggregates = FIELDS
+                    .iter()
+                    .map(|field| {
+                        let value = format!("(p.bucket->>'{field}')::float8");
+                        format!("avg({value}), min({value}), max({value})")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(
+                    "SELECT {}, b.device, b.tenant, s.key, p.phase, count(*), {aggregates}
+                     FROM {json} b
+                     {}
+                     CROSS JOIN LATERAL (VALUES
+                         ('a', s.value->'phase_a'), ('b', s.value->'phase_b'), ('c', s.value->'phase_c')
+                     ) AS p(phase, bucket)
+                     -- Only three-phase meters have a phase_c
+                     WHERE b.time >= $1 AND b.time < $2 AND p.bucket IS NOT NULL
+                     GROUP BY 1, 2, 3, 4, 5",
+                    bucket("b.time"),
+                    streams_lateral("b")
+                )
+            }
+            Source::Columns => {
+                // Measurements the publisher left out are NaN, which the aggregates would spread
+                let aggregates = FIELDS
+                    .iter()
+                    .map(|field| {
+                        let value = format!("nullif({field}, 'NaN')");
+                        format!("avg({value}), min({value}), max({value})")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(
+                    "SELECT {}, device, tenant, stream, phase, count(*), {aggregates}
+                     FROM bibimbap_measurements
+                     WHERE time >= $1 AND time < $2
+                     GROUP BY 1, 2, 3, 4, 5",
+                    bucket("time")
+                )
+            }
+            Source::Rollup(source) => {
+                let aggregates = FIELDS
+                    .iter()
+                    .map(|field| {
+                        format!(
+                            "sum({field}_avg * samples) \
+                             / nullif(sum(samples) FILTER (WHERE {field}_avg IS NOT NULL), 0), \
+                             min({field}_min), max({field}_max)"
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(
+                    "SELECT {}, device, tenant, stream, phase, sum(samples), {aggregates}
+                     FROM {}
+                     WHERE bucket >= $1 AND bucket < $2
+                     GROUP BY 1, 2, 3, 4, 5",
+                    bucket("bucket"),
+                    source.table
+                )
+            }
+        };
+
+        format!(
+            "INSERT INTO {} (bucket, device, tenant, stream, phase, samples, {columns})
+             {select}
+             ON CONFLICT (bucket, device, (coalesce(tenant, '')), stream, phase)
+             DO UPDATE SET samples = EXCLUDED.samples, {updates}",
+            self.table
+        )
+    }
+
+    /// Where rolling up starts when this tier never has: the oldest source row.
+    async fn oldest_source(
+        &self,
+        pool: &Pool<Postgres>,
+        json: &str,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let query = match self.source {
+            Source::Json => format!("SELECT min(time) FROM {json} b"),
+            Source::Columns => "SELECT min(time) FROM bibimbap_measurements".to_string(),
+            Source::Rollup(source) => format!("SELECT min(bucket) FROM {}", source.table),
+        };
+        sqlx::query_scalar(&query)
+            .fetch_one(pool)
+            .await
+            .with_context(|| format!("Could not find where to start the {} rollup", self.name))
+    }
+
+    async fn aggregate(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        json: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<u64> {
+        let rows = sqlx::query(&self.rollup_query(json))
+            .bind(from)
+            .bind(to)
+            .execute(&mut **tx)
+            .await
+            .with_context(|| format!("Could not roll up {from} to {to} into {}", self.table))?
+            .rows_affected();
+        Ok(rows)
+    }
+
+    /// Aggregates the buckets within `window` behind the watermark again, leaving the
+    /// watermark where it is.
+    async fn revisit(&self, pool: &Pool<Postgres>, json: &str, window: Duration) -> Result<()> {
+        let mut tx = pool
+            .begin()
+            .await
+            .context("Could not start a transaction")?;
+        if !try_lock(&mut tx).await? {
+            return Ok(());
+        }
+        let Some(to) = watermark(&mut *tx, self).await? else {
+            return Ok(());
+        };
+        let from = self.floor(to - chrono::Duration::from_std(window)?);
+        if from >= to {
+            return Ok(());
+        }
+
+        let rows = self.aggregate(&mut tx, json, from, to).await?;
+        tx.commit().await.context("Could not commit rollup")?;
+
+        TIERING_ROLLUP_ROWS
+            .with_label_values(&[self.name])
+            .inc_by(rows);
+        log::debug!(
+            "Rolled up {from} to {to} into {} again: {rows} rows",
+            self.table
+        );
+        Ok(())
+    }
+
+    /// Rolls up to `limit` at most `BUCKETS_PER_STEP` buckets, in one transaction with the
+    /// watermark.
+    async fn step(&self, pool: &Pool<Postgres>, json: &str, limit: DateTime<Utc>) -> Result<Step> {
+        let mut tx = pool
+            .begin()
+            .await
+            .context("Could not start a transaction")?;
+        if !try_lock(&mut tx).await? {
+            return Ok(Step::Busy);
+        }
+
+        let from = match watermark(&mut *tx, self).await? {
+            Some(from) => from,
+            None => match self.oldest_source(pool, json).await? {
+                Some(oldest) => self.floor(oldest),
+                None => return Ok(Step::Done),
+            },
+        };
+        let limit = self.floor(limit);
+        if from >= limit {
+            return Ok(Step::Done);
+        }
+        let to = limit.min(from + chrono::Duration::seconds(self.width_secs * BUCKETS_PER_STEP));
+
+        let rows = self.aggregate(&mut tx, json, from, to).await?;
+        sqlx::query(
+            "INSERT INTO bibimbap_tiering (tier, rolled_up_to) VALUES ($1, $2)
+             ON CONFLICT (tier) DO UPDATE SET rolled_up_to = EXCLUDED.rolled_up_to",
+        )
+        .bind(self.name)
+        .bind(to)
+        .execute(&mut *tx)
+        .await
+        .context("Could not record rollup progress")?;
+        tx.commit().await.context("Could not commit rollup")?;
+
+        TIERING_ROLLUP_ROWS
+            .with_label_values(&[self.name])
+            .inc_by(rows);
+        TIERING_ROLLED_UP_TO
+            .with_label_values(&[self.name])
+            .set(to.timestamp());
+        log::debug!("Rolled up {from} to {to} into {}: {rows} rows", self.table);
+        Ok(Step::Advanced(to))
+    }
+
+    /// Rolls up everything complete before `limit`. Returns how far the tier has got, or None
+    /// if it hasn't started (or another instance is busy with it).
+    async fn catch_up(
+        &self,
+        pool: &Pool<Postgres>,
+        json: &str,
+        limit: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let mut reached = None;
+        loop {
+            match self.step(pool, json, limit).await? {
+                Step::Advanced(to) => reached = Some(to),
+                Step::Done => return Ok(reached.or(watermark(pool, self).await?)),
+                Step::Busy => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Takes the storage manager lock until `tx` ends; false if another instance holds it.
+async fn try_lock(tx: &mut Transaction<'_, Postgres>) -> Result<bool> {
+    sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)")
+        .bind(LOCK)
+        .fetch_one(&mut **tx)
+        .await
+        .context("Could not take the storage manager lock")
+}
+
+async fn watermark<'e>(
+    executor: impl sqlx::PgExecutor<'e>,
+    tier: &Tier,
+) -> Result<Option<DateTime<Utc>>> {
+    sqlx::query_scalar("SELECT rolled_up_to FROM bibimbap_tiering WHERE tier = $1")
+        .bind(tier.name)
+        .fetch_optional(executor)
+        .await
+        .context("Could not read rollup progress")
+}
+
+/// How far the 15-minute rollups have got, for what reads them.
+pub async fn quarter_hours_rolled_up_to(pool: &Pool<Postgres>) -> Result<Option<DateTime<Utc>>> {
+    watermark(pool, &QUARTER_HOUR).await
+}
+
+/// The tables routed streams go to, with their retention if they have their own.
+async fn routed_tables(pool: &Pool<Postgres>) -> Result<Vec<(String, Option<Duration>)>> {
+    let recorded: bool = sqlx::query_scalar("SELECT to_regclass('bibimbap_routes') IS NOT NULL")
+        .fetch_one(pool)
+        .await
+        .context("Could not look for bibimbap_routes")?;
+    if !recorded {
+        return Ok(Vec::new());
+    }
+    let tables: Vec<(String, Option<i64>)> =
+        sqlx::query_as("SELECT table_name, retention_seconds FROM bibimbap_routes")
+            .fetch_all(pool)
+            .await
+            .context("Could not read bibimbap_routes")?;
+    Ok(tables
+        .into_iter()
+        .map(|(table, retention)| {
+            let retention = retention.map(|secs| Duration::from_secs(secs.max(0) as u64));
+            (table, retention)
+        })
+        .collect())
+}
+
+/// `bibimbap`, or all of the JSONB full-rate tables as one when streams are routed.
+fn json_source(routed: &[(String, Option<Duration>)]) -> String {
+    if routed.is_empty() {
+        return "bibimbap".to_string();
+    }
+    let selects = std::iter::once("bibimbap")
+        .chain(routed.iter().map(|(table, _)| table.as_str()))
+        .map(|table| format!("SELECT time, device, tenant, data FROM {table}"))
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ");
+    format!("({selects})")
+}
+
+async fn hypertables(pool: &Pool<Postgres>) -> Result<HashSet<String>> {
+    let timescale: bool =
+        sqlx::query_scalar("SELECT to_regclass('timescaledb_information.hypertables') IS NOT NULL")
+            .fetch_one(pool)
+            .await
+            .context("Could not look for TimescaleDB")?;
+    if !timescale {
+        return Ok(HashSet::new());
+    }
+    let tables: Vec<String> =
+        sqlx::query_scalar("SELECT hypertable_name::text FROM timescaledb_information.hypertables")
+            .fetch_all(pool)
+            .await
+            .context("Could not list hypertables")?;
+    Ok(tables.into_iter().collect())
+}
+
+/// Removes rows from before `cutoff`: whole chunks of a hypertable, which frees their disk
+/// space at once, otherwise with a DELETE.
+async fn prune(
+    pool: &Pool<Postgres>,
+    hypertables: &HashSet<String>,
+    table: &str,
+    column: &str,
+    cutoff: DateTime<Utc>,
+) -> Result<()> {
+    if hypertables.contains(table) {
+        sqlx::query("SELECT public.drop_chunks($1::regclass, older_than => $2)")
+            .bind(table)
+            .bind(cutoff)
+            .execute(pool)
+            .await
+            .with_context(|| format!("Could not drop chunks of {table}"))?;
+    } else {
+        let deleted = sqlx::query(&format!("DELETE FROM {table} WHERE {column} < $1"))
+            .bind(cutoff)
+            .execute(pool)
+            .await
+            .with_context(|| format!("Could not prune {table}"))?
+            .rows_affected();
+        if deleted > 0 {
+            log::info!("Pruned {deleted} rows of {table} from before {cutoff}");
+        }
+    }
+    TIERING_PRUNED_TO
+        .with_label_values(&[table])
+        .set(cutoff.timestamp());
+    Ok(())
+}
+
+async fn run(
+    pool: &Pool<Postgres>,
+    config: &TieringConfig,
+    hypertables: &HashSet<String>,
+    settle: Duration,
+) -> Result<()> {
+    let now = Utc::now();
+    let routed = routed_tables(pool).await?;
+    let json = json_source(&routed);
+    let minute = match config.schema_mode {
+        SchemaMode::Json | SchemaMode::Dual => &MINUTE,
+        SchemaMode::Columns => &MINUTE_FROM_COLUMNS,
+    };
+    if let Some(window) = config.correction_window {
+        minute.revisit(pool, &json, window).await?;
+    }
+    let Some(minutes) = minute
+        .catch_up(pool, &json, now - chrono::Duration::from_std(settle)?)
+        .await?
+    else {
+        return Ok(());
+    };
+    if let Some(window) = config.correction_window {
+        QUARTER_HOUR.revisit(pool, &json, window).await?;
+    }
+    let quarters = QUARTER_HOUR.catch_up(pool, &json, minutes).await?;
+
+    let cutoff = minutes.min(now - chrono::Duration::from_std(config.full_rate_retention)?);
+    for table in FULL_RATE_TABLES {
+        prune(pool, hypertables, table, "time", cutoff).await?;
+    }
+    for (table, retention) in &routed {
+        let retention = retention.unwrap_or(config.full_rate_retention);
+        let cutoff = minutes.min(now - chrono::Duration::from_std(retention)?);
+        prune(pool, hypertables, table, "time", cutoff).await?;
+    }
+    if let Some(quarters) = quarters {
+        let cutoff =
+            quarters.min(now - chrono::Duration::from_std(config.minute_rollup_retention)?);
+        prune(pool, hypertables, MINUTE.table, "bucket", cutoff).await?;
+    }
+    Ok(())
+}
+
+/// Rolls up and prunes every `config.interval`, starting straight away.
+pub async fn manage(pool: Pool<Postgres>, config: TieringConfig, flush_interval: Duration) {
+    let hypertables = match hypertables(&pool).await {
+        Ok(hypertables) => hypertables,
+        Err(err) => {
+            log::warn!("Pruning with DELETE: {err:#}");
+            HashSet::new()
+        }
+    };
+    log::info!(
+        "Storage manager: full rate for {}, 1-minute rollups for {}, 15-minute rollups indefinitely",
+        humantime::format_duration(config.full_rate_retention),
+        humantime::format_duration(config.minute_rollup_retention)
+    );
+
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = run(&pool, &config, &hypertables, flush_interval + SETTLE).await {
+            TIERING_ERRORS.inc();
+            log::error!("Storage manager: {err:#}");
+        }
+    }
+}
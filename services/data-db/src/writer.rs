@@ -0,0 +1,464 @@
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::{Executor, PgConnection, Pool, Postgres, QueryBuilder};
+
+use crate::dead_letter::DeadLetters;
+use crate::metrics::{
+    BUFFERED_ROWS, INSERT_FAILED_ROWS, INSERT_RETRIES, INTAKE_PAUSED_SECONDS, ROWS_WRITTEN,
+};
+use crate::schema::SchemaMode;
+use stored_document::SchemaVersion;
+
+pub struct Row {
+    pub time: DateTime<Utc>,
+    pub device: String,
+    /// The customer site the row belongs to, when data-db runs for one
+    pub tenant: Option<String>,
+    /// The row's streams keyed by name, stored in the --json-schema-version layout
+    pub data: serde_json::Value,
+    /// The same values in the normalized layout; only filled in when it is being written
+    pub measurements: Vec<Measurement>,
+}
+
+/// One stream and phase of a row, as stored in `bibimbap_measurements`.
+pub struct Measurement {
+    pub stream: String,
+    pub phase: &'static str,
+    pub rms_voltage: f64,
+    pub dc_offset_voltage: f64,
+    pub rms_current: f64,
+    pub dc_offset_current: f64,
+    pub real_power: f64,
+    pub apparent_power: f64,
+    pub reactive_power: f64,
+    pub power_factor: f64,
+    pub three_phase_real_power: f64,
+    pub three_phase_reactive_power: f64,
+    pub crest_factor_voltage: Option<f64>,
+    pub thd_voltage: Option<f64>,
+    pub crest_factor_current: Option<f64>,
+    pub thd_current: Option<f64>,
+}
+
+/// How batches reach the table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WriteMethod {
+    /// Multi-row `INSERT ... VALUES`, which works through any Postgres-speaking proxy
+    #[default]
+    Insert,
+    /// `COPY ... FROM STDIN`: less parsing and no bind parameter limit, but not every
+    /// transaction-pooling proxy passes it through
+    Copy,
+}
+
+#[derive(Clone, Debug)]
+pub struct BatchConfig {
+    /// `bibimbap`, or a table with the same columns
+    pub table: String,
+    /// Flush once this many rows are buffered.
+    pub batch_size: usize,
+    /// Flush at least this often, even if the batch isn't full.
+    pub flush_interval: Duration,
+    /// Upper bound on rows per INSERT or COPY statement; larger batches are split into
+    /// chunks that are written (and retried) independently.
+    pub max_rows_per_statement: usize,
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+    pub schema_mode: SchemaMode,
+    /// Layout of the `data` column
+    pub schema_version: SchemaVersion,
+    pub method: WriteMethod,
+    /// Rows held for another attempt while the database is unavailable. Once this many are
+    /// waiting, `flush_if_full` stops returning until they are written. 0 discards rows that
+    /// could not be written instead.
+    pub max_buffered_rows: usize,
+}
+
+/// Buffers rows and writes them in multi-row statements. Rows that ran out of retries on a
+/// transient error stay buffered for the next flush (up to `max_buffered_rows`); rows the
+/// database rejected outright are dropped, or kept as dead letters when there is a spool.
+pub struct BatchWriter {
+    pool: Pool<Postgres>,
+    config: BatchConfig,
+    dead_letters: Option<DeadLetters>,
+    rows: Vec<Row>,
+    /// Leading rows of `rows` kept from a failed flush, not counted towards a full batch
+    held: usize,
+}
+
+enum Written {
+    Yes,
+    /// Permanent error, but the rows are in the dead-letter spool
+    DeadLettered,
+    /// Permanent error and no spool: the rows are dropped, as writing them again would only
+    /// fail the same way
+    Rejected,
+    /// Still failing after the retries with no room to hold the rows: the durable queue's to
+    /// redeliver, or gone without one
+    GaveUp,
+    /// Still failing after the retries; worth another attempt later
+    Unavailable,
+}
+
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Postgres allows at most 65535 bind parameters per statement; normalized rows take 19 each.
+const MAX_MEASUREMENTS_PER_STATEMENT: usize = 3400;
+
+impl BatchWriter {
+    pub fn new(pool: Pool<Postgres>, config: BatchConfig) -> Self {
+        Self {
+            rows: Vec::with_capacity(config.batch_size),
+            held: 0,
+            pool,
+            config,
+            dead_letters: None,
+        }
+    }
+
+    /// Keeps rows the database refuses in `dead_letters` instead of dropping them.
+    pub fn with_dead_letters(mut self, dead_letters: Option<DeadLetters>) -> Self {
+        self.dead_letters = dead_letters;
+        self
+    }
+
+    pub fn push(&mut self, row: Row) {
+        self.rows.push(row);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.rows.len() - self.held >= self.config.batch_size
+    }
+
+    fn is_backlogged(&self) -> bool {
+        self.config.max_buffered_rows > 0 && self.held >= self.config.max_buffered_rows
+    }
+
+    /// Flushes a full batch. While `max_buffered_rows` are waiting for the database, keeps
+    /// retrying instead of returning, so the caller stops reading and the receive queue's
+    /// high-water mark takes over.
+    pub async fn flush_if_full(&mut self) {
+        if self.is_full() {
+            self.flush().await;
+        }
+        if !self.is_backlogged() {
+            return;
+        }
+
+        log::warn!(
+            "{} rows waiting for the database, pausing intake",
+            self.rows.len()
+        );
+        let paused = Instant::now();
+        while self.is_backlogged() {
+            tokio::time::sleep(self.config.flush_interval).await;
+            self.flush().await;
+        }
+        INTAKE_PAUSED_SECONDS.inc_by(paused.elapsed().as_secs_f64());
+        log::info!("Database caught up, resuming intake");
+    }
+
+    /// Writes the buffered rows. False when some couldn't be written for now and are worth
+    /// delivering again; rows the table refuses for good are dead-lettered, or dropped with
+    /// an error logged and counted in data_db_insert_failed_rows_total, and count as done.
+    pub async fn flush(&mut self) -> bool {
+        if self.rows.is_empty() {
+            return true;
+        }
+
+        let mut rows = std::mem::take(&mut self.rows);
+        let chunk_size = self.config.max_rows_per_statement.max(1);
+        let mut written = true;
+        let mut held = Vec::new();
+        while !rows.is_empty() {
+            let chunk: Vec<Row> = rows.drain(..chunk_size.min(rows.len())).collect();
+            match self.write_chunk_with_retry(&chunk).await {
+                Written::Yes | Written::DeadLettered | Written::Rejected => {}
+                Written::GaveUp => written = false,
+                Written::Unavailable => {
+                    written = false;
+                    held.extend(chunk);
+                }
+            }
+        }
+        self.held = held.len();
+        self.rows = held;
+        self.rows.reserve(self.config.batch_size);
+        BUFFERED_ROWS
+            .with_label_values(&[&self.config.table])
+            .set(self.held as i64);
+        written
+    }
+
+    async fn write_chunk_with_retry(&self, chunk: &[Row]) -> Written {
+        let mut attempt = 0;
+        loop {
+            let err = match self.write_chunk(chunk).await {
+                Ok(()) => {
+                    ROWS_WRITTEN.inc_by(chunk.len() as u64);
+                    return Written::Yes;
+                }
+                Err(err) => err,
+            };
+
+            let transient = is_transient(&err);
+            if !transient || attempt >= self.config.max_retries {
+                log::error!(
+                    "Could not write {} rows to table after {} attempts: {err:#?}",
+                    chunk.len(),
+                    attempt + 1
+                );
+                if transient && self.config.max_buffered_rows > 0 {
+                    return Written::Unavailable;
+                }
+                INSERT_FAILED_ROWS.inc_by(chunk.len() as u64);
+                // Rows given up on after a transient error are the durable queue's to
+                // redeliver, or were meant to be dropped (--max-buffered-rows 0)
+                if transient {
+                    return Written::GaveUp;
+                }
+                if let Some(dead_letters) = &self.dead_letters {
+                    dead_letters.rows(chunk, &format!("{err:#}"));
+                    return Written::DeadLettered;
+                }
+                // Redelivering them would fail again, and hold up everything behind them
+                log::error!("Dropping {} rows the table refused", chunk.len());
+                return Written::Rejected;
+            }
+
+            let backoff = self
+                .config
+                .retry_backoff
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(MAX_BACKOFF);
+            log::warn!(
+                "Transient error writing {} rows, retrying in {backoff:?}: {err}",
+                chunk.len()
+            );
+            INSERT_RETRIES.inc();
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    async fn write_chunk(&self, chunk: &[Row]) -> Result<(), sqlx::Error> {
+        let table = self.config.table.as_str();
+        let version = self.config.schema_version;
+        match (self.config.schema_mode, self.config.method) {
+            (SchemaMode::Json, WriteMethod::Insert) => {
+                insert_rows(&self.pool, table, version, chunk).await
+            }
+            (SchemaMode::Json, WriteMethod::Copy) => {
+                copy_rows(&mut *self.pool.acquire().await?, table, version, chunk).await
+            }
+            // Both layouts commit or fail together, so the comparison checker only ever sees
+            // real differences
+            (SchemaMode::Dual, method) => {
+                let mut tx = self.pool.begin().await?;
+                let measurements = measurements(chunk);
+                match method {
+                    WriteMethod::Insert => {
+                        insert_rows(&mut *tx, table, version, chunk).await?;
+                        for measurements in measurements.chunks(MAX_MEASUREMENTS_PER_STATEMENT) {
+                            insert_measurements(&mut *tx, measurements).await?;
+                        }
+                    }
+                    WriteMethod::Copy => {
+                        copy_rows(&mut tx, table, version, chunk).await?;
+                        copy_measurements(&mut tx, &measurements).await?;
+                    }
+                }
+                tx.commit().await
+            }
+            (SchemaMode::Columns, WriteMethod::Insert) => {
+                let measurements = measurements(chunk);
+                let mut tx = self.pool.begin().await?;
+                for measurements in measurements.chunks(MAX_MEASUREMENTS_PER_STATEMENT) {
+                    insert_measurements(&mut *tx, measurements).await?;
+                }
+                tx.commit().await
+            }
+            (SchemaMode::Columns, WriteMethod::Copy) => {
+                copy_measurements(&mut *self.pool.acquire().await?, &measurements(chunk)).await
+            }
+        }
+    }
+}
+
+/// Each row's normalized measurements, paired with the row they belong to.
+fn measurements(chunk: &[Row]) -> Vec<(&Row, &Measurement)> {
+    chunk
+        .iter()
+        .flat_map(|row| row.measurements.iter().map(move |m| (row, m)))
+        .collect()
+}
+
+async fn insert_rows<'c, E>(
+    executor: E,
+    table: &str,
+    version: SchemaVersion,
+    chunk: &[Row],
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    let mut builder =
+        QueryBuilder::<Postgres>::new(format!("INSERT INTO {table} (time, device, tenant, data) "));
+    builder.push_values(chunk, |mut b, row| {
+        b.push_bind(row.time)
+            .push_bind(&row.device)
+            .push_bind(&row.tenant)
+            .push_bind(version.document(&row.data));
+    });
+    builder.build().execute(executor).await?;
+    Ok(())
+}
+
+async fn insert_measurements<'c, E>(
+    executor: E,
+    measurements: &[(&Row, &Measurement)],
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    let mut builder = QueryBuilder::<Postgres>::new(
+        "INSERT INTO bibimbap_measurements (time, device, tenant, stream, phase, rms_voltage, \
+         dc_offset_voltage, rms_current, dc_offset_current, real_power, apparent_power, \
+         reactive_power, power_factor, three_phase_real_power, three_phase_reactive_power, \
+         crest_factor_voltage, thd_voltage, crest_factor_current, thd_current) ",
+    );
+    builder.push_values(measurements, |mut b, (row, m)| {
+        b.push_bind(row.time)
+            .push_bind(&row.device)
+            .push_bind(&row.tenant)
+            .push_bind(&m.stream)
+            .push_bind(m.phase)
+            .push_bind(m.rms_voltage)
+            .push_bind(m.dc_offset_voltage)
+            .push_bind(m.rms_current)
+            .push_bind(m.dc_offset_current)
+            .push_bind(m.real_power)
+            .push_bind(m.apparent_power)
+            .push_bind(m.reactive_power)
+            .push_bind(m.power_factor)
+            .push_bind(m.three_phase_real_power)
+            .push_bind(m.three_phase_reactive_power)
+            .push_bind(m.crest_factor_voltage)
+            .push_bind(m.thd_voltage)
+            .push_bind(m.crest_factor_current)
+            .push_bind(m.thd_current);
+    });
+    builder.build().execute(executor).await?;
+    Ok(())
+}
+
+async fn copy_rows(
+    conn: &mut PgConnection,
+    table: &str,
+    version: SchemaVersion,
+    chunk: &[Row],
+) -> Result<(), sqlx::Error> {
+    let mut csv = String::new();
+    for row in chunk {
+        csv_record(
+            &mut csv,
+            [
+                Some(row.time.to_rfc3339_opts(SecondsFormat::Micros, true)),
+                Some(row.device.clone()),
+                row.tenant.clone(),
+                Some(version.document(&row.data).to_string()),
+            ],
+        );
+    }
+    copy(
+        conn,
+        &format!("COPY {table} (time, device, tenant, data) FROM STDIN (FORMAT csv)"),
+        csv,
+    )
+    .await
+}
+
+async fn copy_measurements(
+    conn: &mut PgConnection,
+    measurements: &[(&Row, &Measurement)],
+) -> Result<(), sqlx::Error> {
+    let mut csv = String::new();
+    for (row, m) in measurements {
+        let number = |value: f64| Some(value.to_string());
+        csv_record(
+            &mut csv,
+            [
+                Some(row.time.to_rfc3339_opts(SecondsFormat::Micros, true)),
+                Some(row.device.clone()),
+                row.tenant.clone(),
+                Some(m.stream.clone()),
+                Some(m.phase.to_string()),
+                number(m.rms_voltage),
+                number(m.dc_offset_voltage),
+                number(m.rms_current),
+                number(m.dc_offset_current),
+                number(m.real_power),
+                number(m.apparent_power),
+                number(m.reactive_power),
+                number(m.power_factor),
+                number(m.three_phase_real_power),
+                number(m.three_phase_reactive_power),
+                m.crest_factor_voltage.and_then(number),
+                m.thd_voltage.and_then(number),
+                m.crest_factor_current.and_then(number),
+                m.thd_current.and_then(number),
+            ],
+        );
+    }
+    copy(
+        conn,
+        "COPY bibimbap_measurements (time, device, tenant, stream, phase, rms_voltage, \
+         dc_offset_voltage, rms_current, dc_offset_current, real_power, apparent_power, \
+         reactive_power, power_factor, three_phase_real_power, three_phase_reactive_power, \
+         crest_factor_voltage, thd_voltage, crest_factor_current, thd_current) \
+         FROM STDIN (FORMAT csv)",
+        csv,
+    )
+    .await
+}
+
+async fn copy(conn: &mut PgConnection, statement: &str, csv: String) -> Result<(), sqlx::Error> {
+    let mut copy = conn.copy_in_raw(statement).await?;
+    copy.send(csv.into_bytes()).await?;
+    copy.finish().await?;
+    Ok(())
+}
+
+/// Appends a CSV line: every value quoted, `None` left as the bare empty field COPY reads as
+/// NULL.
+fn csv_record<const N: usize>(csv: &mut String, fields: [Option<String>; N]) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            csv.push(',');
+        }
+        if let Some(field) = field {
+            write!(csv, "\"{}\"", field.replace('"', "\"\"")).expect("String write");
+        }
+    }
+    csv.push('\n');
+}
+
+/// Errors worth retrying: dropped connections, pool exhaustion, and the SQLSTATEs Postgres
+/// (or a proxy in front of it) uses for conditions that clear up on their own.
+pub fn is_transient(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::Protocol(_) => true,
+        sqlx::Error::Database(db_err) => db_err.code().is_some_and(|code| {
+            // 08xxx connection exceptions, serialization failure, deadlock,
+            // too many connections, admin/crash shutdown, cannot connect now
+            code.starts_with("08")
+                || matches!(
+                    code.as_ref(),
+                    "40001" | "40P01" | "53300" | "57P01" | "57P02" | "57P03"
+                )
+        }),
+        _ => false,
+    }
+}
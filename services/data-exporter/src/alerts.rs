@@ -0,0 +1,541 @@
+//! Threshold alerts evaluated at the edge (--alert-rules), for remote sites without an
+//! Alertmanager. Rules come from a TOML file:
+//!
+//! ```toml
+//! webhook = "http://alert-relay:8080/hook"  # for every rule without its own; http:// only
+//!
+//! [[rule]]
+//! name = "voltage-sag"
+//! metric = "rms_voltage"
+//! stream = "threephase/*"   # glob, every stream if left out
+//! comparison = "below"      # or "above"
+//! threshold = 0.95
+//! per_unit = true           # of --nominal-voltage; rms_voltage only
+//! for = "5s"                # how long it must hold before firing, 0s by default
+//! ```
+//!
+//! Every message checks each phase of the streams a rule matches. Once its value has been
+//! past the threshold for `for`, the alert fires: `alert_active` reads 1 and the webhook gets
+//! a `firing` notification. The first value back on the right side resolves it, with a
+//! `resolved` one. Values the publisher left out don't change anything, and maintenance
+//! windows pause the rules of the streams they cover.
+//!
+//! Webhooks are posted from their own task, so a slow receiver never holds up the metrics.
+//! A post that fails on the network, with a 5xx or with a 429 is tried again a few times;
+//! notifications that find the queue full are dropped, and both are counted in
+//! `alert_notifications_total`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use karman_types::PhaseMeasurements;
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec, Opts};
+use serde::{Deserialize, Serialize};
+use shutdown::Shutdown;
+use tokio::sync::mpsc;
+
+use crate::metric_filter::glob_match;
+
+/// Notifications waiting to be posted at once; past this they are dropped.
+const QUEUE: usize = 1024;
+const ATTEMPTS: u32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which alerts are firing, and what became of their notifications.
+pub struct Gauges {
+    pub active: GaugeVec,
+    pub notifications: IntCounterVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let active = GaugeVec::new(
+            Opts::new(
+                "alert_active",
+                "1 while the --alert-rules rule is firing for the stream and phase, 0 once resolved",
+            ),
+            &["rule", "device", "stream", "phase"],
+        )?;
+        registry.register(Box::new(active.clone()))?;
+        let notifications = IntCounterVec::new(
+            Opts::new(
+                "alert_notifications_total",
+                "Alert webhooks: sent, refused by the receiver, failed after every attempt, or dropped with the queue full",
+            ),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(notifications.clone()))?;
+        Ok(Self {
+            active,
+            notifications,
+        })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 2] {
+        [&self.active, &self.notifications]
+    }
+}
+
+/// The phase values a rule can watch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    RmsVoltage,
+    DcOffsetVoltage,
+    RmsCurrent,
+    DcOffsetCurrent,
+    RealPower,
+    ApparentPower,
+    ReactivePower,
+    PowerFactor,
+    CrestFactorVoltage,
+    ThdVoltage,
+    CrestFactorCurrent,
+    ThdCurrent,
+}
+
+impl Metric {
+    /// NaN when the publisher left it out.
+    fn value(self, phase: &PhaseMeasurements) -> f64 {
+        match self {
+            Self::RmsVoltage => phase.rms_voltage,
+            Self::DcOffsetVoltage => phase.dc_offset_voltage,
+            Self::RmsCurrent => phase.rms_current,
+            Self::DcOffsetCurrent => phase.dc_offset_current,
+            Self::RealPower => phase.real_power,
+            Self::ApparentPower => phase.apparent_power,
+            Self::ReactivePower => phase.reactive_power,
+            Self::PowerFactor => phase.power_factor,
+            Self::CrestFactorVoltage => phase.crest_factor_voltage.unwrap_or(f64::NAN),
+            Self::ThdVoltage => phase.thd_voltage.unwrap_or(f64::NAN),
+            Self::CrestFactorCurrent => phase.crest_factor_current.unwrap_or(f64::NAN),
+            Self::ThdCurrent => phase.thd_current.unwrap_or(f64::NAN),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Comparison {
+    Above,
+    Below,
+}
+
+impl Comparison {
+    fn breached(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Self::Above => value > threshold,
+            Self::Below => value < threshold,
+        }
+    }
+}
+
+fn parse_duration<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    humantime::parse_duration(&text).map_err(serde::de::Error::custom)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub metric: Metric,
+    #[serde(default = "every_stream")]
+    pub stream: String,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    #[serde(default)]
+    pub per_unit: bool,
+    #[serde(default, rename = "for", deserialize_with = "parse_duration")]
+    pub hold: Duration,
+    /// Overrides the file's `webhook`
+    pub webhook: Option<String>,
+}
+
+fn every_stream() -> String {
+    "*".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    webhook: Option<String>,
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+/// What a webhook receives.
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    pub status: &'static str,
+    pub rule: String,
+    pub device: String,
+    pub stream: String,
+    pub phase: &'static str,
+    pub metric: Metric,
+    pub comparison: Comparison,
+    /// In the metric's unit, i.e. already multiplied out for `per_unit` rules
+    pub threshold: f64,
+    pub value: f64,
+    /// When the value first went past the threshold
+    pub since: DateTime<Utc>,
+    #[serde(skip)]
+    webhook: Option<String>,
+}
+
+/// The rules, shared by every listener, and where their notifications go.
+#[derive(Clone, Default)]
+pub struct Alerts {
+    rules: Arc<Vec<Rule>>,
+    nominal_voltage: f64,
+    notifications: Option<mpsc::Sender<Notification>>,
+}
+
+/// Posts the notifications the listeners queue; see `Notifier::run`.
+pub struct Notifier {
+    queue: mpsc::Receiver<Notification>,
+}
+
+impl Alerts {
+    /// Loads the rules in `file`, if given, and the notifier to run for their webhooks.
+    pub fn load(file: Option<&Path>, nominal_voltage: f64) -> Result<(Self, Option<Notifier>)> {
+        let Some(file) = file else {
+            return Ok((Self::default(), None));
+        };
+        let contents = fs::read_to_string(file)
+            .with_context(|| format!("Could not read alert rules {}", file.display()))?;
+        let mut rules: RulesFile = toml::from_str(&contents)
+            .with_context(|| format!("Invalid alert rules in {}", file.display()))?;
+        let mut names = HashSet::new();
+        for rule in &mut rules.rules {
+            if rule.per_unit && rule.metric != Metric::RmsVoltage {
+                bail!(
+                    "Alert rule {} in {}: per_unit only applies to rms_voltage",
+                    rule.name,
+                    file.display()
+                );
+            }
+            if !names.insert(rule.name.clone()) {
+                bail!(
+                    "Alert rule {} in {} is defined twice",
+                    rule.name,
+                    file.display()
+                );
+            }
+            rule.webhook = rule.webhook.take().or_else(|| rules.webhook.clone());
+            if let Some(webhook) = rule
+                .webhook
+                .as_deref()
+                .filter(|url| !url.starts_with("http://"))
+            {
+                bail!(
+                    "Alert rule {} in {}: webhook {webhook} isn't plain http://",
+                    rule.name,
+                    file.display()
+                );
+            }
+        }
+        log::info!(
+            "Loaded {} alert rules from {}",
+            rules.rules.len(),
+            file.display()
+        );
+
+        let (sender, queue) = mpsc::channel(QUEUE);
+        let alerts = Self {
+            rules: Arc::new(rules.rules),
+            nominal_voltage,
+            notifications: Some(sender),
+        };
+        Ok((alerts, Some(Notifier { queue })))
+    }
+
+    fn threshold(&self, rule: &Rule) -> f64 {
+        if rule.per_unit {
+            rule.threshold * self.nominal_voltage
+        } else {
+            rule.threshold
+        }
+    }
+}
+
+struct Condition {
+    since: DateTime<Utc>,
+    started: Instant,
+    firing: bool,
+}
+
+/// One listener's view of the rules: which conditions hold, by rule, device, stream and
+/// phase.
+pub struct AlertTracker {
+    alerts: Alerts,
+    conditions: HashMap<(usize, String, String, &'static str), Condition>,
+}
+
+impl AlertTracker {
+    pub fn new(alerts: Alerts) -> Self {
+        Self {
+            alerts,
+            conditions: HashMap::new(),
+        }
+    }
+
+    /// Checks the latest values of `stream`'s phases against every rule that matches it, and
+    /// queues the notifications for alerts that fire or resolve.
+    pub fn check(
+        &mut self,
+        gauges: &Gauges,
+        device: &str,
+        stream: &str,
+        phases: [Option<PhaseMeasurements>; 3],
+        now: Instant,
+    ) {
+        for notification in self.evaluate(gauges, device, stream, phases, now) {
+            self.notify(gauges, notification);
+        }
+    }
+
+    /// Forgets what hadn't fired yet for `stream`, e.g. while it is in maintenance; alerts
+    /// already firing stay so until a value resolves them.
+    pub fn pause(&mut self, device: &str, stream: &str) {
+        self.conditions
+            .retain(|(_, d, s, _), condition| condition.firing || d != device || s != stream);
+    }
+
+    fn evaluate(
+        &mut self,
+        gauges: &Gauges,
+        device: &str,
+        stream: &str,
+        phases: [Option<PhaseMeasurements>; 3],
+        now: Instant,
+    ) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        let rules = Arc::clone(&self.alerts.rules);
+        for (index, rule) in rules.iter().enumerate() {
+            if !glob_match(&rule.stream, stream) {
+                continue;
+            }
+            let threshold = self.alerts.threshold(rule);
+            for (phase, values) in ["a", "b", "c"].into_iter().zip(phases) {
+                let Some(value) = values
+                    .map(|values| rule.metric.value(&values))
+                    .filter(|value| !value.is_nan())
+                else {
+                    continue;
+                };
+                let key = (index, device.to_string(), stream.to_string(), phase);
+                let notification = |status, since| Notification {
+                    status,
+                    rule: rule.name.clone(),
+                    device: device.to_string(),
+                    stream: stream.to_string(),
+                    phase,
+                    metric: rule.metric,
+                    comparison: rule.comparison,
+                    threshold,
+                    value,
+                    since,
+                    webhook: rule.webhook.clone(),
+                };
+
+                if !rule.comparison.breached(value, threshold) {
+                    if let Some(condition) = self.conditions.remove(&key) {
+                        if condition.firing {
+                            gauges
+                                .active
+                                .with_label_values(&[&rule.name, device, stream, phase])
+                                .set(0.0);
+                            notifications.push(notification("resolved", condition.since));
+                        }
+                    }
+                    continue;
+                }
+                let condition = self.conditions.entry(key).or_insert_with(|| Condition {
+                    since: Utc::now(),
+                    started: now,
+                    firing: false,
+                });
+                if !condition.firing && now.duration_since(condition.started) >= rule.hold {
+                    condition.firing = true;
+                    gauges
+                        .active
+                        .with_label_values(&[&rule.name, device, stream, phase])
+                        .set(1.0);
+                    notifications.push(notification("firing", condition.since));
+                }
+            }
+        }
+        notifications
+    }
+
+    fn notify(&self, gauges: &Gauges, notification: Notification) {
+        log::warn!(
+            "Alert {} {} for {}/{} phase {}: {:?} {} {:?} {}",
+            notification.rule,
+            notification.status,
+            notification.device,
+            notification.stream,
+            notification.phase,
+            notification.metric,
+            notification.value,
+            notification.comparison,
+            notification.threshold
+        );
+        let Some(queue) = &self.alerts.notifications else {
+            return;
+        };
+        if notification.webhook.is_some() && queue.try_send(notification).is_err() {
+            gauges.notifications.with_label_values(&["dropped"]).inc();
+        }
+    }
+}
+
+enum Failure {
+    /// Worth sending again
+    Transient(String),
+    Refused(String),
+}
+
+async fn post(client: &reqwest::Client, notification: &Notification) -> Result<(), Failure> {
+    let Some(url) = &notification.webhook else {
+        return Ok(());
+    };
+    let body = serde_json::to_vec(notification).expect("A notification is always valid JSON");
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| Failure::Transient(err.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let reason = format!("{status}: {}", response.text().await.unwrap_or_default());
+    if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        Err(Failure::Transient(reason))
+    } else {
+        Err(Failure::Refused(reason))
+    }
+}
+
+impl Notifier {
+    /// Posts every queued notification to its rule's webhook until shutdown is requested.
+    pub async fn run(mut self, gauges: &Gauges, shutdown: Shutdown) {
+        let client = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+            Ok(client) => client,
+            Err(err) => {
+                log::error!("Not sending alert webhooks: {err}");
+                return;
+            }
+        };
+        loop {
+            let notification = tokio::select! {
+                notification = self.queue.recv() => notification,
+                _ = shutdown.requested() => return,
+            };
+            let Some(notification) = notification else {
+                return;
+            };
+            let mut backoff = Duration::from_millis(500);
+            for attempt in 1..=ATTEMPTS {
+                let reason = match post(&client, &notification).await {
+                    Ok(()) => {
+                        gauges.notifications.with_label_values(&["sent"]).inc();
+                        break;
+                    }
+                    Err(Failure::Refused(reason)) => {
+                        log::error!("Alert webhook refused {}: {reason}", notification.rule);
+                        gauges.notifications.with_label_values(&["refused"]).inc();
+                        break;
+                    }
+                    Err(Failure::Transient(reason)) => reason,
+                };
+                if attempt == ATTEMPTS {
+                    log::error!(
+                        "Could not send alert {} after {ATTEMPTS} attempts: {reason}",
+                        notification.rule
+                    );
+                    gauges.notifications.with_label_values(&["failed"]).inc();
+                    break;
+                }
+                log::warn!("Alert webhook failed, retrying in {backoff:?}: {reason}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phase(rms_voltage: f64) -> Option<PhaseMeasurements> {
+        Some(PhaseMeasurements {
+            rms_voltage,
+            ..PhaseMeasurements::MISSING
+        })
+    }
+
+    #[test]
+    fn sags_fire_after_holding_and_resolve_on_recovery() {
+        let rules: RulesFile = toml::from_str(
+            r#"
+            [[rule]]
+            name = "sag"
+            metric = "rms_voltage"
+            stream = "feeder-*"
+            comparison = "below"
+            threshold = 0.95
+            per_unit = true
+            for = "5s"
+            "#,
+        )
+        .unwrap();
+        let mut tracker = AlertTracker::new(Alerts {
+            rules: Arc::new(rules.rules),
+            nominal_voltage: 120.0,
+            notifications: None,
+        });
+        let gauges = Gauges::register(&prometheus::Registry::new()).unwrap();
+        let start = Instant::now();
+        let mut check = |stream, volts, seconds| {
+            let phases = [phase(volts), phase(120.0), None];
+            let now = start + Duration::from_secs(seconds);
+            tracker
+                .evaluate(&gauges, "lab", stream, phases, now)
+                .into_iter()
+                .map(|notification| (notification.status, notification.phase))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(check("feeder-1", 110.0, 0), []);
+        assert_eq!(check("feeder-1", 110.0, 4), []);
+        assert_eq!(check("feeder-1", 111.0, 5), [("firing", "a")]);
+        assert_eq!(check("feeder-1", 112.0, 6), []);
+        // Left out values don't resolve it
+        assert_eq!(check("feeder-1", f64::NAN, 7), []);
+        assert_eq!(check("feeder-1", 119.0, 8), [("resolved", "a")]);
+
+        // A dip shorter than the hold time never fires
+        assert_eq!(check("feeder-1", 100.0, 10), []);
+        assert_eq!(check("feeder-1", 120.0, 12), []);
+        assert_eq!(check("feeder-1", 100.0, 13), []);
+        assert_eq!(check("feeder-1", 100.0, 17), []);
+        // Other streams aren't watched
+        assert_eq!(check("site/total", 0.0, 0), []);
+        assert_eq!(check("site/total", 0.0, 60), []);
+    }
+}
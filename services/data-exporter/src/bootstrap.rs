@@ -0,0 +1,221 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeCalculations, CompositeTwoPhaseCalculations, PowerCalculations, Provenance,
+    WaveformCalculations,
+};
+use serde::Deserialize;
+use sqlx::{postgres::PgConnection, Connection, Row};
+
+/// Rows are read a slice of time at a time so a day of history never sits in memory at once.
+const CHUNK: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Debug)]
+pub struct BootstrapConfig {
+    pub database_url: String,
+    /// data-db's `device` column
+    pub device: String,
+    /// Only rows data-db wrote for this tenant (or untagged rows, without one)
+    pub tenant: Option<String>,
+    pub window: Duration,
+}
+
+/// One phase as data-db stores it in the `bibimbap` JSONB column. NaN, including a value the
+/// publisher left out, is stored as null and leaves the window as it was.
+#[derive(Deserialize)]
+struct StoredBucket {
+    rms_current: Option<f32>,
+    rms_voltage: Option<f32>,
+    dc_offset_voltage: Option<f32>,
+    dc_offset_current: Option<f32>,
+    real_power: Option<f32>,
+    apparent_power: Option<f32>,
+    reactive_power: Option<f32>,
+    power_factor: Option<f32>,
+    three_phase_real_power: Option<f32>,
+    three_phase_reactive_power: Option<f32>,
+    crest_factor_voltage: Option<f32>,
+    thd_voltage: Option<f32>,
+    crest_factor_current: Option<f32>,
+    thd_current: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct StoredCalculation {
+    phase_a: StoredBucket,
+    phase_b: StoredBucket,
+    /// Only stored for three-phase meters
+    #[serde(default)]
+    phase_c: Option<StoredBucket>,
+}
+
+impl StoredBucket {
+    fn into_calculations(self, utc_time: prost_types::Timestamp) -> CompositeCalculations {
+        CompositeCalculations {
+            provenance: Some(Provenance {
+                utc_time: Some(utc_time),
+                generic_sequence_number: None,
+                time_sync: None,
+            }),
+            voltage_waveform_calculations_v: Some(WaveformCalculations {
+                rms: self.rms_voltage,
+                dc_offset: self.dc_offset_voltage,
+                crest_factor: self.crest_factor_voltage,
+                thd_percent: self.thd_voltage,
+            }),
+            current_waveform_calculations_a: Some(WaveformCalculations {
+                rms: self.rms_current,
+                dc_offset: self.dc_offset_current,
+                crest_factor: self.crest_factor_current,
+                thd_percent: self.thd_current,
+            }),
+            power_calculations: Some(PowerCalculations {
+                real_power_w: self.real_power,
+                apparent_power_va: self.apparent_power,
+                reactive_power_var: self.reactive_power,
+                power_factor: self.power_factor,
+            }),
+        }
+    }
+}
+
+/// One stored message, rebuilt into the shape the listener receives.
+pub struct Sample {
+    /// How long before the bootstrap started this was recorded
+    pub age: Duration,
+    pub streams: Vec<(String, CompositeTwoPhaseCalculations)>,
+    /// Three-phase (real, reactive) sums for phase a and b, and c if a stream had it
+    pub three_phase: Vec<(f32, f32)>,
+}
+
+/// `data` is the row's document, in whichever layout data-db wrote it.
+fn sample(time: DateTime<Utc>, now: DateTime<Utc>, data: serde_json::Value) -> Result<Sample> {
+    let calculations: HashMap<String, StoredCalculation> =
+        serde_json::from_value(stored_document::streams(data).into())
+            .context("Unexpected row layout")?;
+    let utc_time = prost_types::Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    };
+
+    let mut three_phase = vec![(0.0, 0.0); 2];
+    let streams = calculations
+        .into_iter()
+        .map(|(stream, calc)| {
+            // Every stream carries the same sums, so any of them will do
+            let buckets = [
+                Some(&calc.phase_a),
+                Some(&calc.phase_b),
+                calc.phase_c.as_ref(),
+            ];
+            for (index, bucket) in buckets.into_iter().enumerate() {
+                let Some(bucket) = bucket else {
+                    continue;
+                };
+                if index == three_phase.len() {
+                    three_phase.push((0.0, 0.0));
+                }
+                three_phase[index] = (
+                    bucket.three_phase_real_power.unwrap_or_default(),
+                    bucket.three_phase_reactive_power.unwrap_or_default(),
+                );
+            }
+            let calcs = CompositeTwoPhaseCalculations {
+                phase_a: Some(calc.phase_a.into_calculations(utc_time)),
+                phase_b: Some(calc.phase_b.into_calculations(utc_time)),
+                phase_c: calc
+                    .phase_c
+                    .map(|phase_c| phase_c.into_calculations(utc_time)),
+            };
+            (stream, calcs)
+        })
+        .collect();
+
+    Ok(Sample {
+        age: (now - time).to_std().unwrap_or_default(),
+        streams,
+        three_phase,
+    })
+}
+
+/// Reads the last `config.window` of data-db's rows, oldest first, and hands each to `apply`.
+/// Returns how many rows were applied.
+pub async fn replay(config: &BootstrapConfig, mut apply: impl FnMut(Sample)) -> Result<usize> {
+    let mut conn = PgConnection::connect(&config.database_url)
+        .await
+        .context("Could not connect to the bootstrap database")?;
+
+    let now = Utc::now();
+    let chunk = chrono::Duration::from_std(CHUNK).expect("chunk fits");
+    let mut from = now - chrono::Duration::from_std(config.window).unwrap_or(chunk);
+    let mut applied = 0;
+    while from < now {
+        let to = (from + chunk).min(now);
+        let rows = sqlx::query(
+            "SELECT time, data FROM bibimbap \
+             WHERE device = $1 AND tenant IS NOT DISTINCT FROM $2 \
+             AND time >= $3 AND time < $4 ORDER BY time",
+        )
+        .bind(&config.device)
+        .bind(&config.tenant)
+        .bind(from)
+        .bind(to)
+        .fetch_all(&mut conn)
+        .await
+        .context("Could not read history for the bootstrap")?;
+
+        for row in rows {
+            let time: DateTime<Utc> = row.get("time");
+            match sample(time, now, row.get("data")) {
+                Ok(sample) => {
+                    apply(sample);
+                    applied += 1;
+                }
+                Err(err) => log::warn!("Skipping bootstrap row at {time}: {err:#}"),
+            }
+        }
+        from = to;
+    }
+
+    conn.close().await.ok();
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use stored_document::SchemaVersion;
+
+    use super::*;
+
+    #[test]
+    fn rows_of_either_layout_are_read() {
+        let phase =
+            |rms_voltage: f32| json!({"rms_voltage": rms_voltage, "three_phase_real_power": 900.0});
+        let streams = json!({
+            "threephase/feeder-1": {"phase_a": phase(120.0), "phase_b": phase(121.0)},
+            "threephase/feeder-2": {
+                "phase_a": phase(240.0), "phase_b": phase(241.0), "phase_c": phase(242.0)
+            },
+        });
+        let now = Utc::now();
+        let time = now - chrono::Duration::seconds(5);
+
+        for version in [SchemaVersion::V1, SchemaVersion::V2] {
+            let sample = sample(time, now, version.document(&streams)).unwrap();
+            let mut streams = sample.streams;
+            streams.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(streams.len(), 2, "{version:?}");
+            assert_eq!(streams[0].0, "threephase/feeder-1");
+            let rms = |phase: &Option<CompositeCalculations>| {
+                phase.unwrap().voltage_waveform_calculations_v.unwrap().rms
+            };
+            assert_eq!(rms(&streams[0].1.phase_b), Some(121.0));
+            assert_eq!(rms(&streams[1].1.phase_c), Some(242.0));
+            assert_eq!(sample.three_phase, vec![(900.0, 0.0); 3]);
+            assert_eq!(sample.age, Duration::from_secs(5));
+        }
+    }
+}
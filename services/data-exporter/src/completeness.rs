@@ -0,0 +1,226 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, Opts};
+
+use crate::maintenance::{self, Maintenance};
+
+/// Default length of the rolling window the peak/trough/average and completeness gauges
+/// summarise.
+pub const DEFAULT_WINDOW_SECONDS: f64 = 5.0;
+
+/// How many messages each stream delivered against how many it should have.
+pub struct Gauges {
+    pub expected_rate: GaugeVec,
+    pub completeness: GaugeVec,
+    pub underdelivering: GaugeVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let expected_rate = GaugeVec::new(
+            Opts::new(
+                "stream_expected_rate_hertz",
+                "Configured expected message rate",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(expected_rate.clone()))?;
+        let completeness = GaugeVec::new(
+            Opts::new(
+                "stream_completeness_ratio",
+                "Messages received vs expected over the window",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(completeness.clone()))?;
+        let underdelivering = GaugeVec::new(
+            Opts::new(
+                "stream_underdelivering",
+                "1 when completeness is below the configured threshold",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(underdelivering.clone()))?;
+        Ok(Self {
+            expected_rate,
+            completeness,
+            underdelivering,
+        })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 3] {
+        [
+            &self.expected_rate,
+            &self.completeness,
+            &self.underdelivering,
+        ]
+    }
+}
+
+/// Parses `--window-seconds`
+pub fn parse_window_seconds(value: &str) -> Result<f64, String> {
+    let seconds: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid window '{value}'"))?;
+    if !(seconds > 0.0 && seconds.is_finite()) {
+        return Err(format!(
+            "window must be a positive number of seconds, got '{value}'"
+        ));
+    }
+    Ok(seconds)
+}
+
+/// Parses `--default-expected-rate`
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid rate '{value}'"))?;
+    if !(rate > 0.0 && rate.is_finite()) {
+        return Err(format!(
+            "rate must be a positive number of hertz, got '{value}'"
+        ));
+    }
+    Ok(rate)
+}
+
+/// Parses `--expected-rate stream=hz`
+pub fn parse_expected_rate(value: &str) -> Result<(String, f64), String> {
+    let (stream, rate) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected STREAM=HZ, got '{value}'"))?;
+    let rate = parse_rate(rate).map_err(|err| format!("{err} for stream '{stream}'"))?;
+    Ok((stream.to_string(), rate))
+}
+
+#[derive(Clone, Debug)]
+pub struct ExpectedRates {
+    pub default_hz: f64,
+    pub per_stream: HashMap<String, f64>,
+}
+
+impl ExpectedRates {
+    pub fn for_stream(&self, stream: &str) -> f64 {
+        self.per_stream
+            .get(stream)
+            .copied()
+            .unwrap_or(self.default_hz)
+    }
+}
+
+/// Counts arrivals per stream over the window and compares them with the expected rate.
+pub struct CompletenessTracker {
+    rates: ExpectedRates,
+    window: Duration,
+    threshold: f64,
+    arrivals: HashMap<String, VecDeque<Instant>>,
+    /// When maintenance ended for streams whose window hasn't filled up since
+    resumed: HashMap<String, Instant>,
+}
+
+impl CompletenessTracker {
+    pub fn new(rates: ExpectedRates, window: Duration, threshold: f64) -> Self {
+        // Explicitly configured streams are tracked from the start, so one that never
+        // shows up is reported as underdelivering rather than missing.
+        let arrivals = rates
+            .per_stream
+            .keys()
+            .map(|stream| (stream.clone(), VecDeque::new()))
+            .collect();
+
+        Self {
+            rates,
+            window,
+            threshold,
+            arrivals,
+            resumed: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, stream: &str) {
+        self.arrivals
+            .entry(stream.to_string())
+            .or_default()
+            .push_back(Instant::now());
+    }
+
+    /// Streams under maintenance keep their last gauge values, and their window restarts
+    /// empty when it ends, so planned outages don't read as underdelivery.
+    pub fn update(
+        &mut self,
+        gauges: &Gauges,
+        device: &str,
+        maintenance: &Maintenance,
+        maintenance_gauges: &maintenance::Gauges,
+    ) {
+        let window = self.window;
+        let now = Instant::now();
+
+        for (stream, arrivals) in self.arrivals.iter_mut() {
+            if maintenance.check(maintenance_gauges, device, stream) {
+                arrivals.clear();
+                self.resumed.insert(stream.clone(), now);
+                continue;
+            }
+
+            while arrivals
+                .front()
+                .is_some_and(|arrival| now.duration_since(*arrival) > window)
+            {
+                arrivals.pop_front();
+            }
+
+            let covered = match self.resumed.get(stream) {
+                Some(resumed) if now.duration_since(*resumed) < window => {
+                    now.duration_since(*resumed)
+                }
+                Some(_) => {
+                    self.resumed.remove(stream);
+                    window
+                }
+                None => window,
+            };
+            if covered.is_zero() {
+                continue;
+            }
+
+            let expected_hz = self.rates.for_stream(stream);
+            let ratio = arrivals.len() as f64 / (expected_hz * covered.as_secs_f64());
+
+            gauges
+                .expected_rate
+                .with_label_values(&[device, stream])
+                .set(expected_hz);
+            gauges
+                .completeness
+                .with_label_values(&[device, stream])
+                .set(ratio);
+            gauges
+                .underdelivering
+                .with_label_values(&[device, stream])
+                .set(if ratio < self.threshold { 1.0 } else { 0.0 });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_must_be_positive() {
+        assert_eq!(parse_rate("60"), Ok(60.0));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("NaN").is_err());
+        assert_eq!(
+            parse_expected_rate("feeder=30"),
+            Ok(("feeder".to_string(), 30.0))
+        );
+        assert!(parse_expected_rate("feeder=0").is_err());
+    }
+}
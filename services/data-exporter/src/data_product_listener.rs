@@ -1,480 +1,1377 @@
 use std::{
     collections::{HashMap, VecDeque},
-    sync::LazyLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{Context, Result};
-use prost::Message;
+use frame_assembly::{Assembler, Outcome};
+use frame_sequence::{sequence_number, Sequence, SequenceTracker};
+use karman_types::{PhaseMeasurements, Stream};
+use late_data::{provenance_time, Arrival, OrderTracker};
+use prometheus::{core::Collector, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts};
 use protobuf_rs::utilidata::karman::bibimbap::v1::{
     composite_joined_calculations_wrapper::DataProduct, CompositeCalculations,
-    CompositeJoinedCalculations, CompositeTwoPhaseCalculations,
+    CompositeJoinedCalculations, Provenance,
+};
+use shutdown::Shutdown;
+use site_total::{SiteTotalConfig, Total};
+use stream_registry::Registry;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use zmq_ingest::{
+    parse_endpoint_or_tcp, EndpointError, ReceiveMetrics, SubscriberConfig, SubscriberStream,
 };
-use zeromq::{Socket, SocketRecv, SubSocket};
 
-use crate::Args;
+use crate::alerts::{self, AlertTracker, Alerts};
+use crate::bootstrap::{self, BootstrapConfig, Sample};
+use crate::completeness::{self, CompletenessTracker};
+use crate::data_products;
+use crate::deadband::{self, Deadband};
+use crate::decoding;
+use crate::energy::{self, EnergyMeters};
+use crate::harmonics;
+use crate::imbalance::{self, UnbalanceThresholds};
+use crate::maintenance::{self, Maintenance};
+use crate::metric_names::UnitGaugeVec;
+use crate::openmetrics::{Exemplar, Exemplars};
+use crate::remote_write;
+use crate::sample_counters;
+use crate::staleness::{self, StaleAction, StalenessTracker};
+use crate::stream_labels::{self, StreamLabels};
+use crate::stream_power;
+use crate::streams::{Phase, Streams};
+use crate::time_sync;
+use crate::voltage_bands::{self, VoltageBands};
+use crate::{Args, Subscription};
+
+const LABELS: &[&str] = &["device", "stream", "phase"];
+
+/// `phase` label values, in the order of the phases in a message. Phase C only comes from
+/// three-phase meters.
+const PHASES: [&str; 3] = ["a", "b", "c"];
+
+/// One quantity's window: its latest, peak, trough and average, and when the peak and trough
+/// occurred. The timestamps have their unit in the name already, so they're registered the same
+/// way whatever --metric-names says.
+struct WindowGauges {
+    latest: UnitGaugeVec,
+    peak: UnitGaugeVec,
+    trough: UnitGaugeVec,
+    average: UnitGaugeVec,
+    peak_time: UnitGaugeVec,
+    trough_time: UnitGaugeVec,
+}
 
-// Ideally you'd use a macro for this kind of thing tbh
+impl WindowGauges {
+    /// `help` describes the latest, peak, trough and average gauges, in that order.
+    fn register(
+        registry: &prometheus::Registry,
+        name: &str,
+        unit: &str,
+        help: [&str; 4],
+    ) -> prometheus::Result<Self> {
+        let [latest, peak, trough, average] = help;
+        let gauge = |stat: &str, help: &str| {
+            UnitGaugeVec::register(registry, &format!("{name}_{stat}"), unit, help, LABELS)
+        };
+        let timestamp = |extreme: &str| {
+            UnitGaugeVec::register_as_is(
+                registry,
+                &format!("{name}_{extreme}_timestamp_seconds"),
+                &format!("Unix time of the most recent sample at {name}_{extreme}"),
+                LABELS,
+            )
+        };
+        Ok(Self {
+            latest: gauge("latest", latest)?,
+            peak: gauge("peak", peak)?,
+            trough: gauge("trough", trough)?,
+            average: gauge("average", average)?,
+            peak_time: timestamp("peak")?,
+            trough_time: timestamp("trough")?,
+        })
+    }
 
-macro_rules! build_gauge {
-    ($variable_name:ident, $name:expr, $description:expr) => {
-        static $variable_name: LazyLock<prometheus::GaugeVec> = LazyLock::new(|| {
-            prometheus::register_gauge_vec!($name, $description, &["stream", "phase"],)
-                .expect("Unable to register gauge vec")
-        });
-    };
-}
+    fn collectors(&self) -> impl Iterator<Item = &dyn Collector> {
+        self.gauges().flat_map(UnitGaugeVec::collectors)
+    }
 
-// active power
-build_gauge!(
-    ACTIVE_POWER_LATEST_GAUGE,
-    "active_power_latest",
-    "Most recent watts"
-);
-
-build_gauge!(ACTIVE_POWER_PEAK_GAUGE, "active_power_peak", "peak watts");
-build_gauge!(
-    ACTIVE_POWER_TROUGH_GAUGE,
-    "active_power_trough",
-    "trough active power"
-);
-build_gauge!(
-    ACTIVE_POWER_AVERAGE_GAUGE,
-    "active_power_average",
-    "average active power"
-);
-
-// power factor
-build_gauge!(
-    POWER_FACTOR_LATEST_GAUGE,
-    "power_factor_latest",
-    "Most recent"
-);
-
-build_gauge!(POWER_FACTOR_PEAK_GAUGE, "power_factor_peak", "peak");
-build_gauge!(
-    POWER_FACTOR_TROUGH_GAUGE,
-    "power_factor_trough",
-    "trough power factor"
-);
-build_gauge!(
-    POWER_FACTOR_AVERAGE_GAUGE,
-    "power_factor_average",
-    "average power factor"
-);
-
-// dc offset current
-build_gauge!(
-    DC_OFFSET_CURRENT_LATEST_GAUGE,
-    "dc_offset_current_latest",
-    "Most recent"
-);
-
-build_gauge!(
-    DC_OFFSET_CURRENT_PEAK_GAUGE,
-    "dc_offset_current_peak",
-    "peak"
-);
-build_gauge!(
-    DC_OFFSET_CURRENT_TROUGH_GAUGE,
-    "dc_offset_current_trough",
-    "trough"
-);
-build_gauge!(
-    DC_OFFSET_CURRENT_AVERAGE_GAUGE,
-    "dc_offset_current_average",
-    "average"
-);
-
-// dc offset voltage
-build_gauge!(
-    DC_OFFSET_VOLTAGE_LATEST_GAUGE,
-    "dc_offset_voltage_latest",
-    "Most recent"
-);
-
-build_gauge!(
-    DC_OFFSET_VOLTAGE_PEAK_GAUGE,
-    "dc_offset_voltage_peak",
-    "peak"
-);
-build_gauge!(
-    DC_OFFSET_VOLTAGE_TROUGH_GAUGE,
-    "dc_offset_voltage_trough",
-    "trough"
-);
-build_gauge!(
-    DC_OFFSET_VOLTAGE_AVERAGE_GAUGE,
-    "dc_offset_voltage_average",
-    "average"
-);
-
-// reactive power
-build_gauge!(
-    REACTIVE_POWER_LATEST_GAUGE,
-    "reactive_power_latest",
-    "Most recent watts"
-);
-
-build_gauge!(
-    REACTIVE_POWER_PEAK_GAUGE,
-    "reactive_power_peak",
-    "peak watts"
-);
-build_gauge!(
-    REACTIVE_POWER_TROUGH_GAUGE,
-    "reactive_power_trough",
-    "trough active power"
-);
-build_gauge!(
-    REACTIVE_POWER_AVERAGE_GAUGE,
-    "reactive_power_average",
-    "average active power"
-);
-
-// rms current
-build_gauge!(
-    RMS_CURRENT_LATEST_GAUGE,
-    "rms_current_latest",
-    "Most recent"
-);
-
-build_gauge!(RMS_CURRENT_PEAK_GAUGE, "rms_current_peak", "peak");
-build_gauge!(RMS_CURRENT_TROUGH_GAUGE, "rms_current_trough", "trough");
-build_gauge!(RMS_CURRENT_AVERAGE_GAUGE, "rms_current_average", "average");
-
-// rms voltage
-build_gauge!(
-    RMS_VOLTAGE_LATEST_GAUGE,
-    "rms_voltage_latest",
-    "Most recent"
-);
-
-build_gauge!(RMS_VOLTAGE_PEAK_GAUGE, "rms_voltage_peak", "peak");
-build_gauge!(RMS_VOLTAGE_TROUGH_GAUGE, "rms_voltage_trough", "trough");
-build_gauge!(RMS_VOLTAGE_AVERAGE_GAUGE, "rms_voltage_average", "average");
-
-// real_power
-build_gauge!(REAL_POWER_LATEST_GAUGE, "real_power_latest", "Most recent");
-
-build_gauge!(REAL_POWER_PEAK_GAUGE, "real_power_peak", "peak");
-build_gauge!(REAL_POWER_TROUGH_GAUGE, "real_power_trough", "trough");
-build_gauge!(REAL_POWER_AVERAGE_GAUGE, "real_power_average", "average");
-
-//    real_power: Bucket,
-build_gauge!(
-    APPARENT_POWER_LATEST_GAUGE,
-    "apparent_power_latest",
-    "Most recent"
-);
-
-build_gauge!(APPARENT_POWER_PEAK_GAUGE, "apparent_power_peak", "peak");
-build_gauge!(
-    APPARENT_POWER_TROUGH_GAUGE,
-    "apparent_power_trough",
-    "trough"
-);
-build_gauge!(
-    APPARENT_POWER_AVERAGE_GAUGE,
-    "apparent_power_average",
-    "average"
-);
-
-build_gauge!(
-    REAL_POWER_THREE_PHASE_LATEST_GAUGE,
-    "real_power_three_phase_latest",
-    "real power three phase peak"
-);
-build_gauge!(
-    REAL_POWER_THREE_PHASE_TROUGH_GAUGE,
-    "real_power_three_phase_trough",
-    "real power three phase trough"
-);
-build_gauge!(
-    REAL_POWER_THREE_PHASE_AVERAGE_GAUGE,
-    "real_power_three_phase_average",
-    "real power three phase average"
-);
-build_gauge!(
-    REAL_POWER_THREE_PHASE_PEAK_GAUGE,
-    "real_power_three_phase_peak",
-    "real power three phase peak"
-);
-
-build_gauge!(
-    REACTIVE_POWER_THREE_PHASE_LATEST_GAUGE,
-    "reactive_power_three_phase_latest",
-    "reactive power three phase peak"
-);
-build_gauge!(
-    REACTIVE_POWER_THREE_PHASE_TROUGH_GAUGE,
-    "reactive_power_three_phase_trough",
-    "reactive power three phase trough"
-);
-build_gauge!(
-    REACTIVE_POWER_THREE_PHASE_AVERAGE_GAUGE,
-    "reactive_power_three_phase_average",
-    "reactive power three phase average"
-);
-build_gauge!(
-    REACTIVE_POWER_THREE_PHASE_PEAK_GAUGE,
-    "reactive_power_three_phase_peak",
-    "reactive power three phase peak"
-);
-
-pub async fn prepare_subscribe(config: Args) -> Result<SubSocket> {
-    let endpoint = format!("tcp://{}", config.source);
-    let mut subsocket = SubSocket::new();
-    log::info!("about to bind to socket {}", endpoint);
-    subsocket.connect(&endpoint).await?;
-    log::info!("connected to remote {}", endpoint);
-    Ok(subsocket)
-}
-
-pub async fn listen(config: Args) -> Result<()> {
-    let mut subscription = prepare_subscribe(config.clone()).await?;
-
-    let mut measurements = AllMeasurements::new();
-    let mut three_phase = AllThreePhase::default();
-
-    subscription
-        .subscribe(&config.zmq_subscription.clone())
-        .await
-        .context("Could not subscribe")?;
-    log::info!("Subscribed to topic: '{}'", config.zmq_subscription);
+    fn gauges(&self) -> impl Iterator<Item = &UnitGaugeVec> {
+        [
+            &self.latest,
+            &self.peak,
+            &self.trough,
+            &self.average,
+            &self.peak_time,
+            &self.trough_time,
+        ]
+        .into_iter()
+    }
 
-    // ZeroMQ "slow joiner" workaround: give subscription time to propagate
-    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-    log::info!("Subscription ready, waiting for messages...");
+    fn set(&self, labels: &[&str], bucket: &Bucket) {
+        self.latest.with_label_values(labels).set(bucket.latest());
+        self.peak.with_label_values(labels).set(bucket.peak());
+        self.trough.with_label_values(labels).set(bucket.trough());
+        self.average.with_label_values(labels).set(bucket.average());
+        self.peak_time
+            .with_label_values(labels)
+            .set(bucket.peak_time());
+        self.trough_time
+            .with_label_values(labels)
+            .set(bucket.trough_time());
+    }
 
-    let mut msg_count = 0;
-    loop {
-        let incoming = subscription.recv().await?;
-        msg_count += 1;
-        if msg_count % 100 == 0 {
-            log::info!("Received {} messages so far", msg_count);
+    fn clear(&self, labels: &[&str], action: StaleAction) {
+        for gauge in self.gauges() {
+            match action {
+                StaleAction::Keep => {}
+                StaleAction::Reset => gauge.with_label_values(labels).set(f64::NAN),
+                StaleAction::Drop => gauge.remove_label_values(labels),
+            }
         }
+    }
+}
 
-        let as_vec = incoming.into_vec();
+/// The spread of one quantity's window, for the quantities dashboards chart percentiles of.
+struct DistributionGauges {
+    p50: UnitGaugeVec,
+    p95: UnitGaugeVec,
+    p99: UnitGaugeVec,
+    stddev: UnitGaugeVec,
+}
 
-        let Some(frame) = as_vec.first() else {
-            log::error!("Weird frameless message");
-            continue;
+impl DistributionGauges {
+    fn register(
+        registry: &prometheus::Registry,
+        name: &str,
+        unit: &str,
+    ) -> prometheus::Result<Self> {
+        let gauge = |stat: &str, help: &str| {
+            UnitGaugeVec::register(registry, &format!("{name}_{stat}"), unit, help, LABELS)
         };
+        Ok(Self {
+            p50: gauge("p50", "Median over the window")?,
+            p95: gauge("p95", "95th percentile over the window")?,
+            p99: gauge("p99", "99th percentile over the window")?,
+            stddev: gauge("stddev", "Population standard deviation over the window")?,
+        })
+    }
 
-        let buf = &frame[config.zmq_subscription.len()..];
+    fn collectors(&self) -> impl Iterator<Item = &dyn Collector> {
+        [&self.p50, &self.p95, &self.p99, &self.stddev]
+            .into_iter()
+            .flat_map(UnitGaugeVec::collectors)
+    }
 
-        let joined = match CompositeJoinedCalculations::decode(buf) {
-            Ok(joined) => joined,
-            Err(err) => {
-                log::error!("Could not decode incoming message: {err:#?}");
-                continue;
+    fn set(&self, labels: &[&str], bucket: &Bucket) {
+        let [p50, p95, p99] = bucket.percentiles([50.0, 95.0, 99.0]);
+        self.p50.with_label_values(labels).set(p50);
+        self.p95.with_label_values(labels).set(p95);
+        self.p99.with_label_values(labels).set(p99);
+        self.stddev.with_label_values(labels).set(bucket.stddev());
+    }
+
+    fn clear(&self, labels: &[&str], action: StaleAction) {
+        for gauge in [&self.p50, &self.p95, &self.p99, &self.stddev] {
+            match action {
+                StaleAction::Keep => {}
+                StaleAction::Reset => gauge.with_label_values(labels).set(f64::NAN),
+                StaleAction::Drop => gauge.remove_label_values(labels),
             }
+        }
+    }
+}
+
+/// Every per-stream and three-phase gauge, registered once per process (with the default
+/// registry, which /metrics serves) or per test.
+pub struct Gauges {
+    active_power: WindowGauges,
+    power_factor: WindowGauges,
+    dc_offset_current: WindowGauges,
+    dc_offset_voltage: WindowGauges,
+    reactive_power: WindowGauges,
+    rms_current: WindowGauges,
+    rms_voltage: WindowGauges,
+    real_power: WindowGauges,
+    apparent_power: WindowGauges,
+    real_power_three_phase: WindowGauges,
+    reactive_power_three_phase: WindowGauges,
+    // waveform statistics, only exported for streams whose publisher sends them
+    crest_factor_voltage: WindowGauges,
+    thd_voltage: WindowGauges,
+    crest_factor_current: WindowGauges,
+    thd_current: WindowGauges,
+    real_power_distribution: DistributionGauges,
+    rms_current_distribution: DistributionGauges,
+    rms_voltage_distribution: DistributionGauges,
+    assembly: IntCounterVec,
+    late: IntCounterVec,
+    frames_dropped: IntCounterVec,
+    frames_out_of_order: IntCounterVec,
+    site_total_missing: IntCounterVec,
+    latency: HistogramVec,
+    /// The frames behind `latency`, for OpenMetrics scrapes
+    pub latency_exemplars: Exemplars,
+    /// Frames handed from a subscription's `FrameReceiver` to its metric updates
+    frame_channel_depth: IntGaugeVec,
+    frame_channel_dropped: IntCounterVec,
+    /// The subscriptions' receive queues, shared by all of them
+    receive: ReceiveMetrics,
+    decoding: decoding::Counters,
+    energy: energy::Counters,
+    stream_power: stream_power::Gauges,
+    staleness: staleness::Gauges,
+    stream_labels: stream_labels::Gauges,
+    completeness: completeness::Gauges,
+    deadband: deadband::Counters,
+    imbalance: imbalance::Gauges,
+    time_sync: time_sync::Gauges,
+    voltage_bands: voltage_bands::Gauges,
+    data_products: data_products::Gauges,
+    /// Also counts the notifications posted by the alerts' `Notifier`
+    pub alerts: alerts::Gauges,
+    maintenance: maintenance::Gauges,
+    /// Counted by the remote_write task
+    pub remote_write: remote_write::Counters,
+    /// Left out of `collectors` since they are only exported with --harmonics
+    pub harmonics: harmonics::Gauges,
+    /// Left out of `collectors` since they are only exported with --sample-counters
+    pub sample_counters: sample_counters::Counters,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let window = |name, unit, help| WindowGauges::register(registry, name, unit, help);
+        let distribution = |name, unit| DistributionGauges::register(registry, name, unit);
+        let assembly = IntCounterVec::new(
+            Opts::new(
+                "frame_assembly_total",
+                "Frames by how their assembly ended: complete, partial, dropped or late",
+            ),
+            &["device", "outcome"],
+        )?;
+        registry.register(Box::new(assembly.clone()))?;
+        let late = IntCounterVec::new(
+            Opts::new(
+                "late_calculations_total",
+                "Streams older than one already seen for them, by outcome: accepted or rejected",
+            ),
+            &["device", "outcome"],
+        )?;
+        registry.register(Box::new(late.clone()))?;
+        let frames_dropped = IntCounterVec::new(
+            Opts::new(
+                "frames_dropped_total",
+                "Frames missing from a stream's sequence numbers",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(frames_dropped.clone()))?;
+        let frames_out_of_order = IntCounterVec::new(
+            Opts::new(
+                "frames_out_of_order_total",
+                "Frames behind their stream's newest sequence number, by kind: late, or duplicate and skipped",
+            ),
+            &["device", "stream", "kind"],
+        )?;
+        registry.register(Box::new(frames_out_of_order.clone()))?;
+        let site_total_missing = IntCounterVec::new(
+            Opts::new(
+                "site_total_missing_members_total",
+                "Frames whose site total was missing a member stream, by member",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(site_total_missing.clone()))?;
+        let latency_buckets = prometheus::exponential_buckets(0.001, 2.0, 16)?;
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "message_latency_seconds",
+                "Time from a message's newest provenance timestamp to its receipt here",
+            )
+            .buckets(latency_buckets.clone()),
+            &["device"],
+        )?;
+        registry.register(Box::new(latency.clone()))?;
+        let latency_exemplars = Exemplars::new("message_latency_seconds", latency_buckets);
+        let frame_channel_depth = IntGaugeVec::new(
+            Opts::new(
+                "frame_channel_depth",
+                "Frames received and waiting for the metric updates",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(frame_channel_depth.clone()))?;
+        let frame_channel_dropped = IntCounterVec::new(
+            Opts::new(
+                "frame_channel_dropped_total",
+                "Frames dropped because the metric updates were --frame-channel-capacity frames behind",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(frame_channel_dropped.clone()))?;
+
+        Ok(Self {
+            active_power: window(
+                "active_power",
+                "watts",
+                [
+                    "Most recent watts",
+                    "peak watts",
+                    "trough active power",
+                    "average active power",
+                ],
+            )?,
+            power_factor: window(
+                "power_factor",
+                "ratio",
+                [
+                    "Most recent",
+                    "peak",
+                    "trough power factor",
+                    "average power factor",
+                ],
+            )?,
+            dc_offset_current: window(
+                "dc_offset_current",
+                "amperes",
+                ["Most recent", "peak", "trough", "average"],
+            )?,
+            dc_offset_voltage: window(
+                "dc_offset_voltage",
+                "volts",
+                ["Most recent", "peak", "trough", "average"],
+            )?,
+            reactive_power: window(
+                "reactive_power",
+                "volt_amperes_reactive",
+                [
+                    "Most recent watts",
+                    "peak watts",
+                    "trough active power",
+                    "average active power",
+                ],
+            )?,
+            rms_current: window(
+                "rms_current",
+                "amperes",
+                ["Most recent", "peak", "trough", "average"],
+            )?,
+            rms_voltage: window(
+                "rms_voltage",
+                "volts",
+                ["Most recent", "peak", "trough", "average"],
+            )?,
+            real_power: window(
+                "real_power",
+                "watts",
+                ["Most recent", "peak", "trough", "average"],
+            )?,
+            apparent_power: window(
+                "apparent_power",
+                "volt_amperes",
+                ["Most recent", "peak", "trough", "average"],
+            )?,
+            real_power_three_phase: window(
+                "real_power_three_phase",
+                "watts",
+                [
+                    "real power three phase peak",
+                    "real power three phase peak",
+                    "real power three phase trough",
+                    "real power three phase average",
+                ],
+            )?,
+            reactive_power_three_phase: window(
+                "reactive_power_three_phase",
+                "volt_amperes_reactive",
+                [
+                    "reactive power three phase peak",
+                    "reactive power three phase peak",
+                    "reactive power three phase trough",
+                    "reactive power three phase average",
+                ],
+            )?,
+            crest_factor_voltage: window(
+                "crest_factor_voltage",
+                "ratio",
+                [
+                    "Most recent voltage crest factor",
+                    "peak voltage crest factor",
+                    "trough voltage crest factor",
+                    "average voltage crest factor",
+                ],
+            )?,
+            thd_voltage: window(
+                "thd_voltage",
+                "percent",
+                [
+                    "Most recent voltage total harmonic distortion",
+                    "peak voltage total harmonic distortion",
+                    "trough voltage total harmonic distortion",
+                    "average voltage total harmonic distortion",
+                ],
+            )?,
+            crest_factor_current: window(
+                "crest_factor_current",
+                "ratio",
+                [
+                    "Most recent current crest factor",
+                    "peak current crest factor",
+                    "trough current crest factor",
+                    "average current crest factor",
+                ],
+            )?,
+            thd_current: window(
+                "thd_current",
+                "percent",
+                [
+                    "Most recent current total harmonic distortion",
+                    "peak current total harmonic distortion",
+                    "trough current total harmonic distortion",
+                    "average current total harmonic distortion",
+                ],
+            )?,
+            real_power_distribution: distribution("real_power", "watts")?,
+            rms_current_distribution: distribution("rms_current", "amperes")?,
+            rms_voltage_distribution: distribution("rms_voltage", "volts")?,
+            assembly,
+            late,
+            frames_dropped,
+            frames_out_of_order,
+            site_total_missing,
+            latency,
+            latency_exemplars,
+            frame_channel_depth,
+            frame_channel_dropped,
+            receive: ReceiveMetrics::register_with(registry, "")?,
+            decoding: decoding::Counters::register(registry)?,
+            energy: energy::Counters::register(registry)?,
+            stream_power: stream_power::Gauges::register(registry)?,
+            staleness: staleness::Gauges::register(registry)?,
+            stream_labels: stream_labels::Gauges::register(registry)?,
+            completeness: completeness::Gauges::register(registry)?,
+            deadband: deadband::Counters::register(registry)?,
+            imbalance: imbalance::Gauges::register(registry)?,
+            time_sync: time_sync::Gauges::register(registry)?,
+            voltage_bands: voltage_bands::Gauges::register(registry)?,
+            data_products: data_products::Gauges::register(registry)?,
+            alerts: alerts::Gauges::register(registry)?,
+            maintenance: maintenance::Gauges::register(registry)?,
+            remote_write: remote_write::Counters::register(registry)?,
+            harmonics: harmonics::Gauges::register(registry)?,
+            sample_counters: sample_counters::Counters::register(registry)?,
+        })
+    }
+
+    /// Every gauge and counter registered here, for the metric catalog on /schema.
+    pub fn collectors(&self) -> Vec<&dyn Collector> {
+        let windows = [
+            &self.active_power,
+            &self.power_factor,
+            &self.dc_offset_current,
+            &self.dc_offset_voltage,
+            &self.reactive_power,
+            &self.rms_current,
+            &self.rms_voltage,
+            &self.real_power,
+            &self.apparent_power,
+            &self.real_power_three_phase,
+            &self.reactive_power_three_phase,
+            &self.crest_factor_voltage,
+            &self.thd_voltage,
+            &self.crest_factor_current,
+            &self.thd_current,
+        ];
+        let mut collectors: Vec<&dyn Collector> = windows
+            .into_iter()
+            .flat_map(WindowGauges::collectors)
+            .collect();
+        for distribution in [
+            &self.real_power_distribution,
+            &self.rms_current_distribution,
+            &self.rms_voltage_distribution,
+        ] {
+            collectors.extend(distribution.collectors());
+        }
+        collectors.push(&self.assembly);
+        collectors.push(&self.late);
+        collectors.push(&self.frames_dropped);
+        collectors.push(&self.frames_out_of_order);
+        collectors.push(&self.site_total_missing);
+        collectors.push(&self.latency);
+        collectors.push(&self.frame_channel_depth);
+        collectors.push(&self.frame_channel_dropped);
+        collectors.extend(self.receive.collectors());
+        collectors.extend(self.decoding.collectors());
+        collectors.extend(self.energy.collectors());
+        collectors.extend(self.stream_power.collectors());
+        collectors.extend(self.staleness.collectors());
+        collectors.extend(self.stream_labels.collectors());
+        collectors.extend(self.completeness.collectors());
+        collectors.extend(self.deadband.collectors());
+        collectors.extend(self.imbalance.collectors());
+        collectors.extend(self.time_sync.collectors());
+        collectors.extend(self.voltage_bands.collectors());
+        collectors.extend(self.data_products.collectors());
+        collectors.extend(self.alerts.collectors());
+        collectors.extend(self.maintenance.collectors());
+        collectors.extend(self.remote_write.collectors());
+        collectors
+    }
+
+    /// Records how long `joined` took to get here from its publisher. Messages without a
+    /// timestamp aren't recorded; a publisher clock running ahead of ours records 0.
+    fn observe_latency(
+        &self,
+        device: &str,
+        joined: &CompositeJoinedCalculations,
+        received: SystemTime,
+    ) {
+        let newest = joined
+            .calculations
+            .iter()
+            .filter_map(|composite| match &composite.data_product {
+                Some(DataProduct::Calculations(calcs)) => {
+                    Some((provenance_time(calcs)?, sequence_number(calcs)))
+                }
+                _ => None,
+            })
+            .max_by_key(|(time, _)| *time);
+        if let Some((newest, sequence)) = newest {
+            let latency = received
+                .duration_since(newest)
+                .unwrap_or_default()
+                .as_secs_f64();
+            self.latency.with_label_values(&[device]).observe(latency);
+            self.latency_exemplars.record(
+                device,
+                Exemplar {
+                    sequence,
+                    value: latency,
+                    time: newest,
+                },
+            );
+        }
+    }
+}
+
+/// Counts assembly outcomes and keeps the frames that go on to the gauges.
+fn settle(
+    gauges: &Gauges,
+    device: &str,
+    outcomes: Vec<Outcome>,
+) -> Vec<CompositeJoinedCalculations> {
+    let mut frames = Vec::new();
+    for outcome in outcomes {
+        let label = match outcome {
+            Outcome::Complete(frame) => {
+                frames.push(frame.joined);
+                "complete"
+            }
+            Outcome::Partial { frame, missing } => {
+                log::debug!("Assembly window ended without {}", missing.join(", "));
+                frames.push(frame.joined);
+                "partial"
+            }
+            Outcome::Dropped { missing } => {
+                log::debug!("Dropped a frame without {}", missing.join(", "));
+                "dropped"
+            }
+            Outcome::Late => "late",
         };
+        gauges.assembly.with_label_values(&[device, label]).inc();
+    }
+    frames
+}
+
+async fn sleep_until_or_forever(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// State every listener shares with the HTTP server, cloned into each one.
+#[derive(Clone, Default)]
+pub struct Handles {
+    pub maintenance: Maintenance,
+    pub alerts: Alerts,
+    pub registry: Option<Registry>,
+    pub streams: Streams,
+    pub labels: StreamLabels,
+}
 
-        let mut three_phase_active_a = 0.0;
-        let mut three_phase_reactive_a = 0.0;
-        let mut three_phase_active_b = 0.0;
-        let mut three_phase_reactive_b = 0.0;
+/// Everything between a decoded frame and the gauges: the rolling windows, deadbands,
+/// completeness and voltage band tracking. Kept apart from the socket so tests can feed it
+/// frames directly.
+struct Exporter<'a> {
+    gauges: &'a Gauges,
+    device: String,
+    topic: String,
+    sample_counters: bool,
+    /// Highest harmonic order exported, with --harmonics
+    harmonics: Option<u32>,
+    max_clock_offset: Duration,
+    maintenance: Maintenance,
+    alerts: AlertTracker,
+    registry: Option<Registry>,
+    streams: Streams,
+    labels: StreamLabels,
+    window: Duration,
+    completeness: CompletenessTracker,
+    staleness: StalenessTracker,
+    energy: EnergyMeters,
+    three_phase: AllThreePhase,
+    measurements: AllMeasurements,
+    deadband: Deadband,
+    voltage_bands: VoltageBands,
+    order: OrderTracker,
+    sequence: SequenceTracker,
+    site_total: Option<(SiteTotalConfig, SiteTotalWindows)>,
+}
+
+impl<'a> Exporter<'a> {
+    fn new(
+        config: &Args,
+        subscription: &Subscription,
+        gauges: &'a Gauges,
+        handles: Handles,
+    ) -> Self {
+        let Handles {
+            maintenance,
+            alerts,
+            registry,
+            streams,
+            labels,
+        } = handles;
+        let window = config.window();
+        let site_total = config
+            .site_total()
+            .map(|site| (site, SiteTotalWindows::new(window)));
+        Self {
+            gauges,
+            device: subscription.device.clone(),
+            topic: subscription.topic.clone(),
+            sample_counters: config.sample_counters,
+            harmonics: config.harmonics(),
+            max_clock_offset: config.max_clock_offset,
+            maintenance,
+            alerts: AlertTracker::new(alerts),
+            registry,
+            streams,
+            labels,
+            window,
+            completeness: CompletenessTracker::new(
+                config.expected_rates(),
+                window,
+                config.underdelivery_threshold,
+            ),
+            staleness: StalenessTracker::new(config.staleness()),
+            energy: EnergyMeters::new(config.energy()),
+            three_phase: AllThreePhase::new(window),
+            measurements: AllMeasurements::new(window, config.unbalance_thresholds()),
+            deadband: Deadband::new(config.deadband()),
+            voltage_bands: VoltageBands::new(
+                config.nominal_voltage,
+                config.voltage_measurement_point,
+                config.voltage_band_windows.clone(),
+            ),
+            order: OrderTracker::new(config.lateness()),
+            sequence: SequenceTracker::new(),
+            site_total,
+        }
+    }
 
-        for composite in joined.calculations.into_iter() {
-            measurements.apply(
-                composite.calculation_name.clone().unwrap().as_str(),
-                composite.data_product.clone().unwrap(),
+    /// Pre-fills the windows from rows data-db stored.
+    async fn bootstrap(&mut self, bootstrap: &BootstrapConfig) {
+        let loaded = bootstrap::replay(bootstrap, |sample| self.apply_stored(sample)).await;
+        match loaded {
+            Ok(rows) => {
+                log::info!("Bootstrapped windows from {rows} stored rows");
+                self.voltage_bands
+                    .update(&self.gauges.voltage_bands, &self.device);
+            }
+            Err(err) => log::warn!("Starting with empty windows: {err:#}"),
+        }
+    }
+
+    fn apply_stored(&mut self, sample: Sample) {
+        let device = &self.device;
+        // Only the voltage bands have windows longer than a few seconds
+        let recent = sample.age <= self.window;
+        for (stream, calcs) in sample.streams {
+            let stream = self.labels.label(&stream);
+            let phases = [calcs.phase_a, calcs.phase_b, calcs.phase_c];
+            for (phase, calcs) in PHASES.into_iter().zip(phases) {
+                let rms = calcs.and_then(|c| c.voltage_waveform_calculations_v?.rms);
+                if let Some(rms) = rms {
+                    self.voltage_bands
+                        .record_at(&stream, phase, rms as f64, sample.age);
+                }
+            }
+            if recent {
+                self.measurements.apply(
+                    &stream,
+                    phases.map(|phase| phase.map(PhaseMeasurements::from)),
+                );
+                self.measurements.update(self.gauges, device, &stream);
+            }
+        }
+        if recent {
+            self.three_phase.apply_and_update(
+                self.gauges,
+                device,
+                self.topic.clone(),
+                &sample.three_phase,
             );
-            measurements.update(&composite.calculation_name());
+        }
+    }
+
+    /// Gauges that are evaluated on a timer, so a stream that stops entirely still shows up.
+    fn tick(&mut self) {
+        self.completeness.update(
+            &self.gauges.completeness,
+            &self.device,
+            &self.maintenance,
+            &self.gauges.maintenance,
+        );
+        self.voltage_bands
+            .update(&self.gauges.voltage_bands, &self.device);
+        self.labels.update(&self.gauges.stream_labels, &self.device);
+        let action = self.staleness.action();
+        let stale = self
+            .staleness
+            .update(&self.gauges.staleness, &self.device, &self.maintenance);
+        for (stream, phase) in stale {
+            self.measurements
+                .clear(self.gauges, &self.device, &stream, phase, action);
+            // Otherwise a stream coming back with the values it stopped at would stay hidden
+            // until the keepalive
+            self.deadband.forget(&stream);
+        }
+    }
+
+    fn process(&mut self, joined: CompositeJoinedCalculations) {
+        let device = self.device.as_str();
+        let mut in_order = Vec::new();
+        // (active, reactive) per phase; phase C counts once any stream in the message sent it
+        let mut three_phase = [(0.0, 0.0); 3];
+        let mut has_phase_c = false;
+
+        for mut composite in joined.calculations.into_iter() {
+            // Everything below sees the label; only the registry gets the name as sent
+            let name = composite.calculation_name.take();
+            composite.calculation_name = name
+                .as_deref()
+                .map(|name| self.labels.seen(&self.gauges.stream_labels, device, name));
+            data_products::count(
+                &self.gauges.data_products,
+                device,
+                composite.data_product.as_ref(),
+            );
+            if composite.calculation_name.is_none() || composite.data_product.is_none() {
+                let reason = match composite.calculation_name {
+                    None => "no calculation_name",
+                    Some(_) => "no data_product",
+                };
+                self.gauges.decoding.skipped(device, reason);
+                continue;
+            }
+            if let Some(DataProduct::Calculations(calcs)) = &composite.data_product {
+                let stream = composite.calculation_name();
+                let sequence = self.sequence.check(stream, calcs);
+                if let Sequence::Gap { missing } = sequence {
+                    self.gauges
+                        .frames_dropped
+                        .with_label_values(&[device, stream])
+                        .inc_by(missing);
+                }
+                if let Some(kind) = sequence.out_of_order() {
+                    self.gauges
+                        .frames_out_of_order
+                        .with_label_values(&[device, stream, kind])
+                        .inc();
+                }
+                // Already counted and applied the first time round
+                if sequence == Sequence::Duplicate {
+                    continue;
+                }
+            }
+            let arrival = match &composite.data_product {
+                Some(DataProduct::Calculations(calcs)) => {
+                    self.order.check(composite.calculation_name(), calcs)
+                }
+                _ => Arrival::InOrder,
+            };
+            if let Some(outcome) = arrival.outcome() {
+                self.gauges.late.with_label_values(&[device, outcome]).inc();
+            }
+            match (arrival, &composite.data_product) {
+                (Arrival::InOrder, _) => {}
+                (Arrival::Rejected, _) => continue,
+                // Accepted late ones still count towards the running totals and the delivery
+                // rate, but would only push the windows and latest values back in time
+                (Arrival::Accepted, Some(DataProduct::Calculations(calcs))) => {
+                    if self.sample_counters {
+                        sample_counters::record(
+                            &self.gauges.sample_counters,
+                            device,
+                            composite.calculation_name(),
+                            calcs,
+                        );
+                    }
+                    self.completeness.record(composite.calculation_name());
+                    continue;
+                }
+                (Arrival::Accepted, _) => continue,
+            }
+            if let Some(DataProduct::Calculations(calcs)) = &composite.data_product {
+                in_order.push((composite.calculation_name().to_string(), *calcs));
+                let phases = [calcs.phase_a, calcs.phase_b, calcs.phase_c];
+                for (phase, calcs) in PHASES.into_iter().zip(phases) {
+                    let Some(calcs) = calcs else {
+                        continue;
+                    };
+                    self.staleness.record(composite.calculation_name(), phase);
+                    self.energy.record(
+                        &self.gauges.energy,
+                        device,
+                        composite.calculation_name(),
+                        phase,
+                        &calcs,
+                    );
+                }
+            }
+            let phases = match &composite.data_product {
+                Some(DataProduct::Calculations(calcs)) => {
+                    let stream = Stream::new(composite.calculation_name().to_string(), *calcs);
+                    self.gauges.decoding.count(device, &stream);
+                    Some(stream.phases().map(|phase| phase.copied()))
+                }
+                _ => None,
+            };
+            if let Some(phases) = phases {
+                let phases = PHASES
+                    .into_iter()
+                    .zip(phases)
+                    .filter_map(|(phase, values)| Some((phase, Phase::from(values?))))
+                    .collect();
+                self.streams
+                    .record(device, composite.calculation_name(), phases);
+            }
+
+            // Deadbanding only holds back the per-stream gauges; completeness, voltage bands
+            // and the three-phase sums below still see every message.
+            let forward = match &composite.data_product {
+                Some(DataProduct::Calculations(calcs)) => self.deadband.pass(
+                    &self.gauges.deadband,
+                    device,
+                    composite.calculation_name(),
+                    calcs,
+                ),
+                _ => true,
+            };
+            if forward {
+                if let Some(phases) = phases {
+                    self.measurements
+                        .apply(composite.calculation_name(), phases);
+                }
+                self.measurements
+                    .update(self.gauges, device, composite.calculation_name());
+                if let Some(DataProduct::Calculations(calcs)) = &composite.data_product {
+                    self.gauges
+                        .stream_power
+                        .update(device, composite.calculation_name(), calcs);
+                }
+                if let Some(DataProduct::Waveform(waveform)) = &composite.data_product {
+                    data_products::record(
+                        &self.gauges.data_products,
+                        device,
+                        composite.calculation_name(),
+                        waveform,
+                    );
+                }
+                if let Some((max_order, harmonics)) =
+                    self.harmonics.zip(composite.harmonics.as_ref())
+                {
+                    harmonics::record(
+                        &self.gauges.harmonics,
+                        device,
+                        composite.calculation_name(),
+                        harmonics,
+                        max_order,
+                    );
+                }
+            }
+            self.completeness.record(composite.calculation_name());
+            if let Some((registry, name)) = self.registry.as_ref().zip(name.as_deref()) {
+                registry.observe(name);
+            }
+            // Planned outages and switching would otherwise skew the power quality stats
+            let in_maintenance = self
+                .maintenance
+                .is_active(device, composite.calculation_name());
+            if let Some(sync) = composite.data_product.as_ref().and_then(time_sync::of) {
+                time_sync::record(
+                    &self.gauges.time_sync,
+                    device,
+                    composite.calculation_name(),
+                    &sync,
+                    self.max_clock_offset,
+                );
+            }
+            if in_maintenance {
+                self.alerts.pause(device, composite.calculation_name());
+            } else if let Some(phases) = phases {
+                self.alerts.check(
+                    &self.gauges.alerts,
+                    device,
+                    composite.calculation_name(),
+                    phases,
+                    Instant::now(),
+                );
+            }
+            if let Some(DataProduct::Calculations(calcs)) =
+                composite.data_product.as_ref().filter(|_| !in_maintenance)
+            {
+                let phases = [calcs.phase_a, calcs.phase_b, calcs.phase_c];
+                for (phase, calcs) in PHASES.into_iter().zip(phases) {
+                    // Reading 0 V for a value left out would look like an outage
+                    let rms = calcs.and_then(|c| c.voltage_waveform_calculations_v?.rms);
+                    if let Some(rms) = rms {
+                        self.voltage_bands
+                            .record(composite.calculation_name(), phase, rms as f64);
+                    }
+                }
+            }
+
+            if self.sample_counters {
+                if let Some(DataProduct::Calculations(calcs)) = &composite.data_product {
+                    sample_counters::record(
+                        &self.gauges.sample_counters,
+                        device,
+                        composite.calculation_name(),
+                        calcs,
+                    );
+                }
+            }
 
             let Some(DataProduct::Calculations(calcs)) = composite.data_product else {
                 continue;
             };
-            three_phase_active_a += calcs
-                .phase_a
-                .unwrap()
-                .power_calculations
-                .unwrap()
-                .real_power_w();
-            three_phase_reactive_a += calcs
-                .phase_a
-                .unwrap()
-                .power_calculations
-                .unwrap()
-                .reactive_power_var();
-            three_phase_active_b += calcs
-                .phase_b
-                .unwrap()
-                .power_calculations
-                .unwrap()
-                .real_power_w();
-            three_phase_reactive_b += calcs
-                .phase_b
-                .unwrap()
-                .power_calculations
-                .unwrap()
-                .reactive_power_var();
+            // A partial frame may be missing a phase, which then adds nothing
+            let power = |phase: Option<CompositeCalculations>| {
+                phase
+                    .and_then(|phase| phase.power_calculations)
+                    .unwrap_or_default()
+            };
+            has_phase_c |= calcs.phase_c.is_some();
+            let phases = [calcs.phase_a, calcs.phase_b, calcs.phase_c];
+            for ((active, reactive), phase) in three_phase.iter_mut().zip(phases) {
+                *active += power(phase).real_power_w();
+                *reactive += power(phase).reactive_power_var();
+            }
+        }
+        let three_phase = &three_phase[..if has_phase_c { 3 } else { 2 }];
+
+        if self.sample_counters {
+            sample_counters::record_three_phase(
+                &self.gauges.sample_counters,
+                device,
+                &self.topic,
+                three_phase,
+            );
+        }
+
+        if let Some((site, windows)) = &mut self.site_total {
+            let streams = in_order.iter().map(|(name, calcs)| (name.as_str(), calcs));
+            if let Some(total) = site.sum(streams) {
+                for member in &total.missing {
+                    self.gauges
+                        .site_total_missing
+                        .with_label_values(&[device, member])
+                        .inc();
+                }
+                if site.emits(&total) {
+                    windows.apply(&total, sample_time(total.provenance));
+                    windows.update(self.gauges, device, &site.stream);
+                }
+            }
         }
 
         // Okay, this is a little hacky
-        three_phase.apply_and_update(
-            config.zmq_subscription.clone(),
-            three_phase_active_a,
-            three_phase_reactive_a,
-            three_phase_active_b,
-            three_phase_reactive_b,
-        );
+        self.three_phase
+            .apply_and_update(self.gauges, device, self.topic.clone(), three_phase);
+    }
+}
+
+/// A --source: `HOST:PORT` for TCP, or a full tcp:// or ipc:// endpoint. Kept as given,
+/// since it is also the default `device` label.
+pub fn parse_source(value: &str) -> Result<String, EndpointError> {
+    parse_endpoint_or_tcp(value)?;
+    Ok(value.to_string())
+}
+
+/// Exports one subscription until shutdown is requested (`Ok`) or it can't be reconnected
+/// (`Err`). A `FrameReceiver` task reads and assembles the frames and hands them over a
+/// bounded channel to the metric updates here, so a burst of messages or an update held up
+/// by a scrape never stalls reading the socket; frames that find the channel full are
+/// dropped and counted in frame_channel_dropped_total.
+pub async fn listen(
+    config: &Args,
+    subscription: &Subscription,
+    bootstrap: Option<&BootstrapConfig>,
+    handles: Handles,
+    gauges: &'static Gauges,
+    health: &health::Source,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let device = subscription.device.as_str();
+    let mut exporter = Exporter::new(config, subscription, gauges, handles);
+
+    if let Some(bootstrap) = bootstrap {
+        exporter.bootstrap(bootstrap).await;
+    }
+
+    let source = subscription.source.as_str();
+    let subscriber = SubscriberConfig {
+        endpoint: parse_endpoint_or_tcp(source)?,
+        topic: subscription.topic.clone(),
+        hwm: config.hwm(),
+        reader_thread: config.reader_thread.reader_thread(),
+    };
+    // Connecting waits for the publisher to come up
+    let subscriber = tokio::select! {
+        subscriber = SubscriberStream::connect(subscriber, &gauges.receive) => {
+            subscriber.context("Could not subscribe")?
+        }
+        _ = shutdown.requested() => return Ok(()),
+    };
+
+    // ZeroMQ "slow joiner" workaround: give subscription time to propagate
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    log::info!("Subscription to {source} ready, waiting for messages...");
+    health.connected();
+
+    let (sender, mut frames) = mpsc::channel(config.frame_channel_capacity);
+    let receiving = tokio::spawn(
+        FrameReceiver {
+            subscriber,
+            assembler: config.assembly().map(Assembler::new),
+            frames: sender,
+            gauges,
+            device: device.to_string(),
+            source: source.to_string(),
+            health: health.clone(),
+            shutdown: shutdown.clone(),
+        }
+        .run(),
+    );
+
+    let depth = gauges.frame_channel_depth.with_label_values(&[device]);
+    // Completeness is evaluated on a timer so a stream that stops entirely still shows up
+    let mut completeness_timer = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            joined = frames.recv() => {
+                // The receiver has stopped, and everything it handed over is exported
+                let Some(joined) = joined else {
+                    break;
+                };
+                depth.set(frames.len() as i64);
+                let frame = logging::frame_span(&joined);
+                frame.in_scope(|| {
+                    tracing::info_span!("sink").in_scope(|| exporter.process(joined))
+                });
+            }
+            _ = completeness_timer.tick() => exporter.tick(),
+        }
+    }
+
+    receiving.await.context("Frame receiver panicked")?
+}
+
+/// Reads one subscription, reconnecting in place, and passes its (assembled) frames on until
+/// shutdown. Dropping the channel's sender on return tells the metric updates it is done.
+struct FrameReceiver {
+    subscriber: SubscriberStream,
+    assembler: Option<Assembler>,
+    frames: mpsc::Sender<CompositeJoinedCalculations>,
+    gauges: &'static Gauges,
+    device: String,
+    source: String,
+    health: health::Source,
+    shutdown: Shutdown,
+}
+
+impl FrameReceiver {
+    async fn run(mut self) -> Result<()> {
+        let (gauges, device, source) = (self.gauges, self.device.as_str(), self.source.as_str());
+        let mut msg_count = 0;
+        'receiving: loop {
+            let assembly_deadline = self.assembler.as_ref().and_then(Assembler::next_deadline);
+            let frames = tokio::select! {
+                frame = self.subscriber.next() => {
+                    let joined = match frame {
+                        Ok(frame) => {
+                            self.health.message();
+                            gauges.observe_latency(device, &frame.joined, frame.received);
+                            frame.joined
+                        }
+                        // Reconnecting in place keeps the windows filled so far
+                        Err(err) => {
+                            self.health.disconnected();
+                            log::error!("Subscription to {source} failed, reconnecting in 5s: {err}");
+                            tokio::select! {
+                                _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                                _ = self.shutdown.requested() => break,
+                            }
+                            tokio::select! {
+                                reconnected = self.subscriber.reconnect() => reconnected?,
+                                _ = self.shutdown.requested() => break,
+                            }
+                            self.health.connected();
+                            continue;
+                        }
+                    };
+                    msg_count += 1;
+                    if msg_count % 100 == 0 {
+                        log::info!("Received {} messages from {source} so far", msg_count);
+                    }
+
+                    match self.assembler.as_mut() {
+                        Some(assembler) => settle(gauges, device, assembler.push(joined, Instant::now())),
+                        None => vec![joined],
+                    }
+                }
+                _ = sleep_until_or_forever(assembly_deadline) => {
+                    let Some(assembler) = self.assembler.as_mut() else {
+                        continue;
+                    };
+                    settle(gauges, device, assembler.expire(Instant::now()))
+                }
+                _ = self.shutdown.requested() => break,
+            };
+
+            for joined in frames {
+                match self.frames.try_send(joined) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        gauges
+                            .frame_channel_dropped
+                            .with_label_values(&[device])
+                            .inc();
+                    }
+                    Err(TrySendError::Closed(_)) => break 'receiving,
+                }
+            }
+            let depth = self.frames.max_capacity() - self.frames.capacity();
+            gauges
+                .frame_channel_depth
+                .with_label_values(&[device])
+                .set(depth as i64);
+        }
+
+        self.subscriber.close().await;
+        Ok(())
     }
 }
 
-#[derive(Default)]
 struct AllThreePhase {
+    window: Duration,
     map: HashMap<String, ThreePhaseMeasurements>,
 }
 
 impl AllThreePhase {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            map: HashMap::default(),
+        }
+    }
+
+    /// `sums` are (real, reactive) for phases a and b, and c from three-phase meters.
     fn apply_and_update(
         &mut self,
+        gauges: &Gauges,
+        device: &str,
         name: String,
-        real_a: f32,
-        reactive_a: f32,
-        real_b: f32,
-        reactive_b: f32,
+        sums: &[(f32, f32)],
     ) {
         let measurements = self
             .map
             .entry(name.to_string())
-            .or_insert(ThreePhaseMeasurements::default());
+            .or_insert_with(|| ThreePhaseMeasurements::with_window(self.window));
 
-        measurements.apply(real_a, reactive_a, real_b, reactive_b);
+        measurements.apply(sums);
 
-        measurements.update(&name);
+        measurements.update(gauges, device, &name);
     }
 }
 
-#[derive(Default)]
 struct ThreePhaseMeasurements {
-    three_phase_real_a: Bucket,
-    three_phase_real_b: Bucket,
-    three_phase_reactive_a: Bucket,
-    three_phase_reactive_b: Bucket,
+    window: Duration,
+    /// (real, reactive) windows per phase, in `PHASES` order; phase C's from the first message
+    /// that had it
+    phases: Vec<(Bucket, Bucket)>,
 }
 
 impl ThreePhaseMeasurements {
-    fn apply(&mut self, real_a: f32, reactive_a: f32, real_b: f32, reactive_b: f32) {
-        self.three_phase_real_a.apply(real_a as f64);
-        self.three_phase_real_b.apply(real_b as f64);
-        self.three_phase_reactive_a.apply(reactive_a as f64);
-        self.three_phase_reactive_b.apply(reactive_b as f64);
-    }
-
-    fn update(&mut self, label: &str) {
-        REAL_POWER_THREE_PHASE_AVERAGE_GAUGE
-            .with_label_values(&[label, "a"])
-            .set(self.three_phase_real_a.average());
-        REAL_POWER_THREE_PHASE_LATEST_GAUGE
-            .with_label_values(&[label, "a"])
-            .set(self.three_phase_real_a.latest());
-        REAL_POWER_THREE_PHASE_TROUGH_GAUGE
-            .with_label_values(&[label, "a"])
-            .set(self.three_phase_real_a.trough());
-        REAL_POWER_THREE_PHASE_PEAK_GAUGE
-            .with_label_values(&[label, "a"])
-            .set(self.three_phase_real_a.peak());
-
-        REAL_POWER_THREE_PHASE_AVERAGE_GAUGE
-            .with_label_values(&[label, "b"])
-            .set(self.three_phase_real_b.average());
-        REAL_POWER_THREE_PHASE_LATEST_GAUGE
-            .with_label_values(&[label, "b"])
-            .set(self.three_phase_real_b.latest());
-        REAL_POWER_THREE_PHASE_TROUGH_GAUGE
-            .with_label_values(&[label, "b"])
-            .set(self.three_phase_real_b.trough());
-        REAL_POWER_THREE_PHASE_PEAK_GAUGE
-            .with_label_values(&[label, "b"])
-            .set(self.three_phase_real_b.peak());
-
-        REACTIVE_POWER_THREE_PHASE_AVERAGE_GAUGE
-            .with_label_values(&[label, "a"])
-            .set(self.three_phase_reactive_a.average());
-        REACTIVE_POWER_THREE_PHASE_LATEST_GAUGE
-            .with_label_values(&[label, "a"])
-            .set(self.three_phase_reactive_a.latest());
-        REACTIVE_POWER_THREE_PHASE_TROUGH_GAUGE
-            .with_label_values(&[label, "a"])
-            .set(self.three_phase_reactive_a.trough());
-        REACTIVE_POWER_THREE_PHASE_PEAK_GAUGE
-            .with_label_values(&[label, "a"])
-            .set(self.three_phase_reactive_a.peak());
-
-        REACTIVE_POWER_THREE_PHASE_AVERAGE_GAUGE
-            .with_label_values(&[label, "b"])
-            .set(self.three_phase_reactive_b.average());
-        REACTIVE_POWER_THREE_PHASE_LATEST_GAUGE
-            .with_label_values(&[label, "b"])
-            .set(self.three_phase_reactive_b.latest());
-        REACTIVE_POWER_THREE_PHASE_TROUGH_GAUGE
-            .with_label_values(&[label, "b"])
-            .set(self.three_phase_reactive_b.trough());
-        REACTIVE_POWER_THREE_PHASE_PEAK_GAUGE
-            .with_label_values(&[label, "b"])
-            .set(self.three_phase_reactive_b.peak())
+    fn with_window(window: Duration) -> Self {
+        Self {
+            window,
+            phases: Vec::with_capacity(PHASES.len()),
+        }
+    }
+
+    fn apply(&mut self, sums: &[(f32, f32)]) {
+        // The sums span every stream in the message, so they're stamped with when it arrived
+        let at = sample_time(None);
+        for (index, (real, reactive)) in sums.iter().enumerate() {
+            if index == self.phases.len() {
+                let window = self.window;
+                self.phases
+                    .push((Bucket::with_window(window), Bucket::with_window(window)));
+            }
+            let (real_bucket, reactive_bucket) = &mut self.phases[index];
+            real_bucket.apply(*real as f64, at);
+            reactive_bucket.apply(*reactive as f64, at);
+        }
+    }
+
+    fn update(&mut self, gauges: &Gauges, device: &str, label: &str) {
+        for (phase, (real, reactive)) in PHASES.into_iter().zip(&self.phases) {
+            let labels = [device, label, phase];
+            gauges.real_power_three_phase.set(&labels, real);
+            gauges.reactive_power_three_phase.set(&labels, reactive);
+        }
     }
 }
 
 // This structure mirrors the protobuf
 // There's probably a better way of doing this.
 struct AllMeasurements {
+    window: Duration,
+    thresholds: UnbalanceThresholds,
     data: HashMap<String, ConjoinedMeasurements>,
 }
 
 impl AllMeasurements {
-    fn new() -> Self {
+    fn new(window: Duration, thresholds: UnbalanceThresholds) -> Self {
         Self {
+            window,
+            thresholds,
             data: HashMap::default(),
         }
     }
 
-    fn apply(&mut self, name: &str, product: DataProduct) {
-        let DataProduct::Calculations(calcs) = product else {
-            return;
-        };
+    fn apply(&mut self, name: &str, phases: [Option<PhaseMeasurements>; 3]) {
+        if !self.data.contains_key(name) {
+            let measurements = ConjoinedMeasurements::with_window(self.window);
+            self.data.insert(name.to_string(), measurements);
+        }
 
-        let measurements = self
-            .data
-            .entry(name.to_string())
-            .or_insert(ConjoinedMeasurements::default());
+        self.data.get_mut(name).unwrap().apply(phases);
+    }
 
-        measurements.apply(calcs);
+    /// Empties a stale phase's windows and applies `action` to its gauges. Its next sample
+    /// starts the windows over.
+    fn clear(
+        &mut self,
+        gauges: &Gauges,
+        device: &str,
+        name: &str,
+        phase: &str,
+        action: StaleAction,
+    ) {
+        if let Some(measurements) = self.data.get_mut(name) {
+            measurements.clear(gauges, device, name, phase, action);
+        }
     }
 
-    fn update(&mut self, name: &str) {
+    fn update(&mut self, gauges: &Gauges, device: &str, name: &str) {
         let Some(measurements) = self.data.get(name) else {
-            self.data
-                .insert(name.to_string(), ConjoinedMeasurements::default());
+            let measurements = ConjoinedMeasurements::with_window(self.window);
+            self.data.insert(name.to_string(), measurements);
             return;
         };
 
-        measurements.update(name);
+        measurements.update(gauges, device, name);
+        let phases: Vec<_> = measurements.phases().map(|(_, phase)| phase).collect();
+        imbalance::update(
+            &gauges.imbalance,
+            device,
+            name,
+            &phases
+                .iter()
+                .map(|phase| phase.rms_voltage.average())
+                .collect::<Vec<_>>(),
+            &phases
+                .iter()
+                .map(|phase| phase.rms_current.average())
+                .collect::<Vec<_>>(),
+            &self.thresholds,
+        );
     }
 }
 
-#[derive(Default)]
 struct ConjoinedMeasurements {
-    phase_a: MeasurementBuckets,
-    phase_b: MeasurementBuckets,
+    window: Duration,
+    /// In `PHASES` order, from each phase's first sample. Two-phase meters never send phase C,
+    /// so they don't export empty series for it, and a stale phase starts over when it returns.
+    phases: [Option<MeasurementBuckets>; 3],
 }
 
 impl ConjoinedMeasurements {
-    fn apply(&mut self, calcs: CompositeTwoPhaseCalculations) {
-        self.phase_a.apply(calcs.phase_a.unwrap());
-        self.phase_b.apply(calcs.phase_b.unwrap());
+    fn with_window(window: Duration) -> Self {
+        Self {
+            window,
+            phases: [None, None, None],
+        }
+    }
+
+    fn phases(&self) -> impl Iterator<Item = (&'static str, &MeasurementBuckets)> {
+        PHASES
+            .into_iter()
+            .zip(&self.phases)
+            .filter_map(|(phase, buckets)| Some((phase, buckets.as_ref()?)))
+    }
+
+    fn apply(&mut self, phases: [Option<PhaseMeasurements>; 3]) {
+        let window = self.window;
+        // Usually only missing from partially assembled frames
+        for (buckets, values) in self.phases.iter_mut().zip(phases) {
+            if let Some(values) = values {
+                buckets
+                    .get_or_insert_with(|| MeasurementBuckets::with_window(window))
+                    .apply(values);
+            }
+        }
     }
 
-    fn update(&self, name: &str) {
-        self.phase_a.update(name, "a");
-        self.phase_b.update(name, "b");
+    fn update(&self, gauges: &Gauges, device: &str, name: &str) {
+        for (phase, measurements) in self.phases() {
+            measurements.update(gauges, device, name, phase);
+        }
+    }
+
+    fn clear(
+        &mut self,
+        gauges: &Gauges,
+        device: &str,
+        name: &str,
+        phase: &str,
+        action: StaleAction,
+    ) {
+        let Some(index) = PHASES.iter().position(|known| *known == phase) else {
+            return;
+        };
+        if let Some(measurements) = self.phases[index].take() {
+            measurements.clear(gauges, &[device, name, phase], action);
+        }
     }
 }
 
-#[derive(Default)]
 struct MeasurementBuckets {
     real_power: Bucket,
     rms_current: Bucket,
@@ -485,175 +1382,247 @@ struct MeasurementBuckets {
     power_factor: Bucket,
     dc_offset_current: Bucket,
     dc_offset_voltage: Bucket,
+    crest_factor_voltage: Bucket,
+    thd_voltage: Bucket,
+    crest_factor_current: Bucket,
+    thd_current: Bucket,
 }
 
 impl MeasurementBuckets {
-    fn apply(&mut self, calcs: CompositeCalculations) {
-        self.dc_offset_current
-            .apply(calcs.current_waveform_calculations_a.unwrap().dc_offset() as f64);
-        self.rms_current
-            .apply(calcs.current_waveform_calculations_a.unwrap().rms() as f64);
-        self.dc_offset_voltage
-            .apply(calcs.voltage_waveform_calculations_v.unwrap().dc_offset() as f64);
-        self.rms_voltage
-            .apply(calcs.voltage_waveform_calculations_v.unwrap().rms() as f64);
-
-        self.apparent_power
-            .apply(calcs.power_calculations.unwrap().apparent_power_va() as f64);
-
-        self.power_factor
-            .apply(calcs.power_calculations.unwrap().power_factor() as f64);
-
-        self.reactive_power
-            .apply(calcs.power_calculations.unwrap().reactive_power_var() as f64);
-
-        self.real_power
-            .apply(calcs.power_calculations.unwrap().real_power_w() as f64);
-        self.active_power
-            .apply(calcs.power_calculations.unwrap().real_power_w() as f64);
-    }
-
-    fn update(&self, stream: &str, phase: &str) {
-        ACTIVE_POWER_AVERAGE_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.active_power.average());
-        ACTIVE_POWER_LATEST_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.active_power.latest());
-        ACTIVE_POWER_PEAK_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.active_power.peak());
-        ACTIVE_POWER_TROUGH_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.active_power.trough());
-
-        REAL_POWER_AVERAGE_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.real_power.average());
-        REAL_POWER_LATEST_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.real_power.latest());
-        REAL_POWER_PEAK_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.real_power.peak());
-        REAL_POWER_TROUGH_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.real_power.trough());
-
-        RMS_CURRENT_AVERAGE_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.rms_current.average());
-        RMS_CURRENT_LATEST_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.rms_current.latest());
-        RMS_CURRENT_PEAK_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.rms_current.peak());
-        RMS_CURRENT_TROUGH_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.rms_current.trough());
-
-        RMS_VOLTAGE_AVERAGE_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.rms_voltage.average());
-        RMS_VOLTAGE_LATEST_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.rms_voltage.latest());
-        RMS_VOLTAGE_PEAK_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.rms_voltage.peak());
-        RMS_VOLTAGE_TROUGH_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.rms_voltage.trough());
-
-        APPARENT_POWER_AVERAGE_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.apparent_power.average());
-        APPARENT_POWER_LATEST_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.apparent_power.latest());
-        APPARENT_POWER_PEAK_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.apparent_power.peak());
-        APPARENT_POWER_TROUGH_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.apparent_power.trough());
-
-        REACTIVE_POWER_AVERAGE_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.reactive_power.average());
-        REACTIVE_POWER_LATEST_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.reactive_power.latest());
-        REACTIVE_POWER_PEAK_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.reactive_power.peak());
-        REACTIVE_POWER_TROUGH_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.reactive_power.trough());
-
-        POWER_FACTOR_AVERAGE_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.power_factor.average());
-        POWER_FACTOR_LATEST_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.power_factor.latest());
-        POWER_FACTOR_PEAK_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.power_factor.peak());
-        POWER_FACTOR_TROUGH_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.power_factor.trough());
-
-        DC_OFFSET_CURRENT_AVERAGE_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.dc_offset_current.average());
-        DC_OFFSET_CURRENT_LATEST_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.dc_offset_current.latest());
-        DC_OFFSET_CURRENT_PEAK_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.dc_offset_current.peak());
-        DC_OFFSET_CURRENT_TROUGH_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.dc_offset_current.trough());
-
-        DC_OFFSET_VOLTAGE_AVERAGE_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.dc_offset_voltage.average());
-        DC_OFFSET_VOLTAGE_LATEST_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.dc_offset_voltage.latest());
-        DC_OFFSET_VOLTAGE_PEAK_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.dc_offset_voltage.peak());
-        DC_OFFSET_VOLTAGE_TROUGH_GAUGE
-            .with_label_values(&[stream, phase])
-            .set(self.dc_offset_voltage.trough());
+    fn with_window(window: Duration) -> Self {
+        Self {
+            real_power: Bucket::with_window(window),
+            rms_current: Bucket::with_window(window),
+            rms_voltage: Bucket::with_window(window),
+            apparent_power: Bucket::with_window(window),
+            active_power: Bucket::with_window(window),
+            reactive_power: Bucket::with_window(window),
+            power_factor: Bucket::with_window(window),
+            dc_offset_current: Bucket::with_window(window),
+            dc_offset_voltage: Bucket::with_window(window),
+            crest_factor_voltage: Bucket::with_window(window),
+            thd_voltage: Bucket::with_window(window),
+            crest_factor_current: Bucket::with_window(window),
+            thd_current: Bucket::with_window(window),
+        }
+    }
+
+    /// Values the publisher left out leave their bucket as it was.
+    fn apply(&mut self, values: PhaseMeasurements) {
+        let at = sample_time(values.provenance);
+        let known = |value: f64| (!value.is_nan()).then_some(value);
+        for (bucket, value) in [
+            (&mut self.dc_offset_current, known(values.dc_offset_current)),
+            (&mut self.rms_current, known(values.rms_current)),
+            (&mut self.dc_offset_voltage, known(values.dc_offset_voltage)),
+            (&mut self.rms_voltage, known(values.rms_voltage)),
+            (&mut self.apparent_power, known(values.apparent_power)),
+            (&mut self.power_factor, known(values.power_factor)),
+            (&mut self.reactive_power, known(values.reactive_power)),
+            (&mut self.real_power, known(values.real_power)),
+            (&mut self.active_power, known(values.real_power)),
+            (&mut self.crest_factor_voltage, values.crest_factor_voltage),
+            (&mut self.thd_voltage, values.thd_voltage),
+            (&mut self.crest_factor_current, values.crest_factor_current),
+            (&mut self.thd_current, values.thd_current),
+        ] {
+            if let Some(value) = value {
+                bucket.apply(value, at);
+            }
+        }
+    }
+
+    /// The window gauges this phase exports, with the bucket behind each.
+    fn windows<'a>(
+        &'a self,
+        gauges: &'a Gauges,
+    ) -> impl Iterator<Item = (&'a WindowGauges, &'a Bucket)> {
+        let extended = [
+            (&gauges.crest_factor_voltage, &self.crest_factor_voltage),
+            (&gauges.thd_voltage, &self.thd_voltage),
+            (&gauges.crest_factor_current, &self.crest_factor_current),
+            (&gauges.thd_current, &self.thd_current),
+        ];
+        [
+            (&gauges.active_power, &self.active_power),
+            (&gauges.real_power, &self.real_power),
+            (&gauges.rms_current, &self.rms_current),
+            (&gauges.rms_voltage, &self.rms_voltage),
+            (&gauges.apparent_power, &self.apparent_power),
+            (&gauges.reactive_power, &self.reactive_power),
+            (&gauges.power_factor, &self.power_factor),
+            (&gauges.dc_offset_current, &self.dc_offset_current),
+            (&gauges.dc_offset_voltage, &self.dc_offset_voltage),
+        ]
+        .into_iter()
+        // Publishers without extended statistics never fill these, so don't export
+        // series that would only ever read the empty-window defaults
+        .chain(
+            extended
+                .into_iter()
+                .filter(|(_, bucket)| !bucket.is_empty()),
+        )
+    }
+
+    fn distributions<'a>(
+        &'a self,
+        gauges: &'a Gauges,
+    ) -> [(&'a DistributionGauges, &'a Bucket); 3] {
+        [
+            (&gauges.real_power_distribution, &self.real_power),
+            (&gauges.rms_current_distribution, &self.rms_current),
+            (&gauges.rms_voltage_distribution, &self.rms_voltage),
+        ]
+    }
+
+    fn update(&self, gauges: &Gauges, device: &str, stream: &str, phase: &str) {
+        let labels = [device, stream, phase];
+        for (window, bucket) in self.windows(gauges) {
+            window.set(&labels, bucket);
+        }
+        for (distribution, bucket) in self.distributions(gauges) {
+            distribution.set(&labels, bucket);
+        }
+    }
+
+    fn clear(&self, gauges: &Gauges, labels: &[&str], action: StaleAction) {
+        for (window, _) in self.windows(gauges) {
+            window.clear(labels, action);
+        }
+        for (distribution, _) in self.distributions(gauges) {
+            distribution.clear(labels, action);
+        }
     }
 }
 
-pub struct Bucket {
-    values: VecDeque<f64>,
+/// The site total's windows. Only its power is known, so only the power gauges are set for it.
+struct SiteTotalWindows {
+    window: Duration,
+    phase_a: PowerWindows,
+    phase_b: PowerWindows,
+    /// Once a member has sent phase C
+    phase_c: Option<PowerWindows>,
 }
 
-impl Default for Bucket {
-    fn default() -> Self {
-        Bucket {
-            // assuming 60hz samples
-            // TODO: learn this.
-            values: VecDeque::with_capacity(60 * 5),
+struct PowerWindows {
+    real: Bucket,
+    reactive: Bucket,
+    apparent: Bucket,
+}
+
+impl PowerWindows {
+    fn new(window: Duration) -> Self {
+        Self {
+            real: Bucket::with_window(window),
+            reactive: Bucket::with_window(window),
+            apparent: Bucket::with_window(window),
         }
     }
 }
 
+impl SiteTotalWindows {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            phase_a: PowerWindows::new(window),
+            phase_b: PowerWindows::new(window),
+            phase_c: None,
+        }
+    }
+
+    fn apply(&mut self, total: &Total, at: f64) {
+        let window = self.window;
+        let phase_c = total.phase_c.as_ref().map(|power| {
+            let windows = self
+                .phase_c
+                .get_or_insert_with(|| PowerWindows::new(window));
+            (windows, power)
+        });
+        let phases = [
+            Some((&mut self.phase_a, &total.phase_a)),
+            Some((&mut self.phase_b, &total.phase_b)),
+            phase_c,
+        ];
+        for (windows, power) in phases.into_iter().flatten() {
+            windows.real.apply(power.real, at);
+            windows.reactive.apply(power.reactive, at);
+            windows.apparent.apply(power.apparent, at);
+        }
+    }
+
+    fn update(&self, gauges: &Gauges, device: &str, stream: &str) {
+        let phases = [
+            Some(&self.phase_a),
+            Some(&self.phase_b),
+            self.phase_c.as_ref(),
+        ];
+        for (phase, windows) in PHASES.into_iter().zip(phases) {
+            let Some(windows) = windows else {
+                continue;
+            };
+            let labels = [device, stream, phase];
+            gauges.real_power.set(&labels, &windows.real);
+            gauges.real_power_distribution.set(&labels, &windows.real);
+            gauges.active_power.set(&labels, &windows.real);
+            gauges.reactive_power.set(&labels, &windows.reactive);
+            gauges.apparent_power.set(&labels, &windows.apparent);
+        }
+    }
+}
+
+/// When a sample was taken, as Unix time: the publisher's timestamp when it sent one, otherwise
+/// the time it was received.
+fn sample_time(provenance: Option<Provenance>) -> f64 {
+    match provenance.and_then(|p| p.utc_time) {
+        Some(time) => time.seconds as f64 + time.nanos as f64 / 1e9,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+    }
+}
+
+/// Samples a window holds at most, whatever their spacing.
+const MAX_WINDOW_SAMPLES: usize = 100_000;
+
+/// The samples of the last `window` seconds, up to MAX_WINDOW_SAMPLES of them.
+pub struct Bucket {
+    window: f64,
+    values: VecDeque<f64>,
+    /// Unix time of each entry in `values`
+    times: VecDeque<f64>,
+}
+
 impl Bucket {
-    fn apply(&mut self, val: f64) {
-        if self.values.len() == self.values.capacity() {
-            self.values.pop_front();
+    fn with_window(window: Duration) -> Self {
+        Bucket {
+            window: window.as_secs_f64(),
+            values: VecDeque::new(),
+            times: VecDeque::new(),
         }
+    }
 
+    /// Evicts every sample taken `window` or more before `at`, and the oldest beyond
+    /// MAX_WINDOW_SAMPLES. A sample from before the newest one means the clock stepped back,
+    /// and the window starts over from it: going by `at`, nothing would be evicted until the
+    /// clock caught up again.
+    fn apply(&mut self, val: f64, at: f64) {
+        if self.times.back().is_some_and(|newest| at < *newest) {
+            self.values.clear();
+            self.times.clear();
+        }
         self.values.push_back(val);
+        self.times.push_back(at);
+
+        while self.values.len() > MAX_WINDOW_SAMPLES
+            || self
+                .times
+                .front()
+                .is_some_and(|oldest| at - oldest >= self.window)
+        {
+            self.values.pop_front();
+            self.times.pop_front();
+        }
     }
 
     fn peak(&self) -> f64 {
@@ -667,9 +1636,873 @@ impl Bucket {
             .fold(f64::MAX, |acc, v| if *v < acc { *v } else { acc })
     }
     fn average(&self) -> f64 {
-        self.values.iter().map(|v| *v).sum::<f64>() / self.values.len() as f64
+        self.values.iter().sum::<f64>() / self.values.len() as f64
+    }
+    /// Nearest-rank percentiles (0 to 100) from one sorted copy of the window; NaN when empty.
+    fn percentiles<const N: usize>(&self, percents: [f64; N]) -> [f64; N] {
+        let mut sorted: Vec<f64> = self.values.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        percents.map(|percent| {
+            let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+            sorted
+                .get(rank.saturating_sub(1))
+                .copied()
+                .unwrap_or(f64::NAN)
+        })
+    }
+    fn stddev(&self) -> f64 {
+        let average = self.average();
+        let variance = self
+            .values
+            .iter()
+            .map(|v| (v - average).powi(2))
+            .sum::<f64>()
+            / self.values.len() as f64;
+        variance.sqrt()
+    }
+    fn peak_time(&self) -> f64 {
+        self.time_of(self.peak())
+    }
+    fn trough_time(&self) -> f64 {
+        self.time_of(self.trough())
+    }
+    /// When `value` was last seen in the window; 0 when it wasn't.
+    fn time_of(&self, value: f64) -> f64 {
+        self.values
+            .iter()
+            .zip(&self.times)
+            .rev()
+            .find(|(v, _)| **v == value)
+            .map_or(0.0, |(_, at)| *at)
+    }
+    fn is_empty(&self) -> bool {
+        self.values.is_empty()
     }
     fn latest(&self) -> f64 {
-        self.values.iter().map(|v| *v).last().unwrap_or_default()
+        self.values.back().copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use prometheus::proto::MetricType;
+    use prometheus::Registry as MetricsRegistry;
+    use protobuf_rs::utilidata::karman::bibimbap::v1::{
+        time_sync::Source, CompositeJoinedCalculationsWrapper, CompositeTwoPhaseCalculations,
+        PowerCalculations, TimeSync, WaveformCalculations,
+    };
+
+    use super::*;
+
+    const TOPIC: &str = "cycle-aligned";
+
+    fn args(extra: &[&str]) -> Args {
+        let required = [
+            "data-exporter",
+            "--source",
+            "127.0.0.1:5557",
+            "--prometheus-port",
+            "0",
+            "--zmq-subscription",
+            TOPIC,
+            "--device",
+            "test",
+        ];
+        Args::parse_from(required.iter().chain(extra))
+    }
+
+    /// A phase measured at Unix time `at`, with everything derived from its real power.
+    fn phase(real: f32, at: i64) -> CompositeCalculations {
+        CompositeCalculations {
+            provenance: Some(Provenance {
+                utc_time: Some(prost_types::Timestamp {
+                    seconds: at,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            }),
+            voltage_waveform_calculations_v: Some(WaveformCalculations {
+                rms: Some(120.0),
+                dc_offset: Some(0.5),
+                ..Default::default()
+            }),
+            current_waveform_calculations_a: Some(WaveformCalculations {
+                rms: Some(real / 120.0),
+                dc_offset: Some(0.0),
+                ..Default::default()
+            }),
+            power_calculations: Some(PowerCalculations {
+                real_power_w: Some(real),
+                reactive_power_var: Some(real / 10.0),
+                apparent_power_va: Some(real),
+                power_factor: Some(1.0),
+            }),
+        }
+    }
+
+    fn frame(
+        streams: &[(&str, CompositeCalculations, CompositeCalculations)],
+    ) -> CompositeJoinedCalculations {
+        CompositeJoinedCalculations {
+            calculations: streams
+                .iter()
+                .map(
+                    |(name, phase_a, phase_b)| CompositeJoinedCalculationsWrapper {
+                        calculation_name: Some(name.to_string()),
+                        data_product: Some(DataProduct::Calculations(
+                            CompositeTwoPhaseCalculations {
+                                phase_a: Some(*phase_a),
+                                phase_b: Some(*phase_b),
+                                phase_c: None,
+                            },
+                        )),
+                        device_id: None,
+                        harmonics: None,
+                    },
+                )
+                .collect(),
+        }
+    }
+
+    /// The value of `name` for one stream and phase, if it has been exported.
+    fn gauge(registry: &MetricsRegistry, name: &str, stream: &str, phase: &str) -> Option<f64> {
+        let family = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == name)?;
+        family
+            .get_metric()
+            .iter()
+            .find(|metric| {
+                let label = |key: &str| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .find(|label| label.get_name() == key)
+                        .map(|label| label.get_value().to_string())
+                };
+                label("device").as_deref() == Some("test")
+                    && label("stream").as_deref() == Some(stream)
+                    && label("phase").as_deref() == Some(phase)
+            })
+            .map(|metric| metric.get_gauge().get_value())
+    }
+
+    /// The value of counter `name` for one stream and phase, and direction if given; 0 if it
+    /// hasn't been exported.
+    fn counter(
+        registry: &MetricsRegistry,
+        name: &str,
+        phase: &str,
+        direction: Option<&str>,
+    ) -> f64 {
+        let Some(family) = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == name)
+        else {
+            return 0.0;
+        };
+        family
+            .get_metric()
+            .iter()
+            .find(|metric| {
+                let label = |key: &str| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .find(|label| label.get_name() == key)
+                        .map(|label| label.get_value().to_string())
+                };
+                label("phase").as_deref() == Some(phase)
+                    && label("direction").as_deref() == direction
+            })
+            .map_or(0.0, |metric| metric.get_counter().get_value())
+    }
+
+    fn exporter<'a>(gauges: &'a Gauges, extra: &[&str]) -> Exporter<'a> {
+        let args = args(extra);
+        let subscription = &args.subscriptions().unwrap()[0];
+        let handles = Handles {
+            labels: StreamLabels::new(args.stream_labels()),
+            ..Handles::default()
+        };
+        Exporter::new(&args, subscription, gauges, handles)
+    }
+
+    #[test]
+    fn every_topic_is_subscribed_on_every_source_and_labelled_with_it() {
+        let subscription = |source: &str, topic: &str| Subscription {
+            source: source.to_string(),
+            topic: topic.to_string(),
+            device: source.to_string(),
+        };
+        let site = Args::parse_from([
+            "data-exporter",
+            "--source",
+            "10.0.0.5:5557,10.0.0.6:5557",
+            "--source",
+            "10.0.0.7:5557",
+            "--prometheus-port",
+            "0",
+            "--zmq-subscription",
+            "cycle-aligned",
+            "--zmq-subscription",
+            "events",
+        ]);
+        assert_eq!(
+            site.subscriptions().unwrap(),
+            [
+                subscription("10.0.0.5:5557", "cycle-aligned"),
+                subscription("10.0.0.5:5557", "events"),
+                subscription("10.0.0.6:5557", "cycle-aligned"),
+                subscription("10.0.0.6:5557", "events"),
+                subscription("10.0.0.7:5557", "cycle-aligned"),
+                subscription("10.0.0.7:5557", "events"),
+            ]
+        );
+
+        // --device can only stand in for a single source
+        assert_eq!(args(&[]).subscriptions().unwrap()[0].device, "test");
+        let mut several = args(&["--source", "10.0.0.6:5557"]);
+        assert!(several.subscriptions().is_err());
+        several.device = None;
+        assert_eq!(several.subscriptions().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn latest_peak_trough_and_average_per_phase() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &[]);
+
+        exporter.process(frame(&[("feeder", phase(100.0, 1000), phase(50.0, 1000))]));
+        exporter.process(frame(&[("feeder", phase(300.0, 1001), phase(70.0, 1001))]));
+
+        let value = |name, phase| gauge(&registry, name, "feeder", phase);
+        assert_eq!(value("real_power_latest", "a"), Some(300.0));
+        assert_eq!(value("real_power_peak", "a"), Some(300.0));
+        assert_eq!(value("real_power_trough", "a"), Some(100.0));
+        assert_eq!(value("real_power_average", "a"), Some(200.0));
+        assert_eq!(
+            value("real_power_peak_timestamp_seconds", "a"),
+            Some(1001.0)
+        );
+        assert_eq!(
+            value("real_power_trough_timestamp_seconds", "a"),
+            Some(1000.0)
+        );
+        assert_eq!(value("active_power_latest", "a"), Some(300.0));
+        assert_eq!(value("reactive_power_average", "a"), Some(20.0));
+        assert_eq!(value("real_power_average", "b"), Some(60.0));
+        assert_eq!(value("rms_voltage_latest", "b"), Some(120.0));
+        assert_eq!(value("dc_offset_voltage_latest", "b"), Some(0.5));
+    }
+
+    #[test]
+    fn three_phase_sums_every_stream_in_a_frame() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &[]);
+
+        exporter.process(frame(&[
+            ("feeder-1", phase(100.0, 1000), phase(10.0, 1000)),
+            ("feeder-2", phase(250.0, 1000), phase(20.0, 1000)),
+        ]));
+
+        let value = |name, phase| gauge(&registry, name, TOPIC, phase);
+        assert_eq!(value("real_power_three_phase_latest", "a"), Some(350.0));
+        assert_eq!(value("real_power_three_phase_latest", "b"), Some(30.0));
+        assert_eq!(value("reactive_power_three_phase_latest", "a"), Some(35.0));
+        assert_eq!(value("reactive_power_three_phase_latest", "b"), Some(3.0));
+    }
+
+    #[test]
+    fn stream_power_totals_each_streams_own_phases() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &[]);
+
+        exporter.process(frame(&[
+            ("feeder-1", phase(100.0, 1000), phase(10.0, 1000)),
+            ("feeder-2", phase(250.0, 1000), phase(20.0, 1000)),
+        ]));
+
+        let value = |name: &str, stream: &str| {
+            let family = registry
+                .gather()
+                .into_iter()
+                .find(|family| family.get_name() == name)?;
+            family
+                .get_metric()
+                .iter()
+                .find(|metric| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .any(|label| label.get_name() == "stream" && label.get_value() == stream)
+                })
+                .map(|metric| metric.get_gauge().get_value())
+        };
+        let close = |a: Option<f64>, b: f64| a.is_some_and(|a| (a - b).abs() < 1e-4);
+        assert_eq!(value("stream_real_power", "feeder-1"), Some(110.0));
+        assert_eq!(value("stream_real_power", "feeder-2"), Some(270.0));
+        assert!(close(value("stream_reactive_power", "feeder-2"), 27.0));
+        assert!(close(
+            value("stream_power_factor", "feeder-2"),
+            10.0 / 101f64.sqrt()
+        ));
+        // Split-phase legs of 250 / 120 and 20 / 120 A
+        assert!(close(
+            value("neutral_current_estimate", "feeder-2"),
+            230.0 / 120.0
+        ));
+    }
+
+    #[test]
+    fn energy_is_integrated_between_samples_and_split_by_direction() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &["--energy-max-gap", "1h"]);
+
+        // Half an hour apart
+        exporter.process(frame(&[(
+            "feeder",
+            phase(1000.0, 1000),
+            phase(1000.0, 1000),
+        )]));
+        exporter.process(frame(&[(
+            "feeder",
+            phase(1000.0, 2800),
+            phase(-1000.0, 2800),
+        )]));
+        exporter.process(frame(&[(
+            "feeder",
+            phase(3000.0, 4600),
+            phase(-1000.0, 4600),
+        )]));
+
+        let energy = |name, phase, direction| counter(&registry, name, phase, Some(direction));
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(energy("energy_kwh_total", "a", "import"), 1.5));
+        assert!(close(energy("energy_kwh_total", "a", "export"), 0.0));
+        assert!(close(
+            energy("reactive_energy_kvarh_total", "a", "import"),
+            0.15
+        ));
+        // Crossing zero halfway through the first half hour
+        assert!(close(energy("energy_kwh_total", "b", "import"), 0.125));
+        assert!(close(energy("energy_kwh_total", "b", "export"), 0.625));
+    }
+
+    #[test]
+    fn energy_gaps_follow_the_gap_policy() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut skipping = exporter(&gauges, &[]);
+        skipping.process(frame(&[("feeder", phase(1000.0, 1000), phase(0.0, 1000))]));
+        skipping.process(frame(&[("feeder", phase(1000.0, 4600), phase(0.0, 4600))]));
+        assert_eq!(
+            counter(&registry, "energy_kwh_total", "a", Some("import")),
+            0.0
+        );
+        assert_eq!(
+            counter(&registry, "energy_gap_seconds_total", "a", None),
+            3600.0
+        );
+
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut holding = exporter(&gauges, &["--energy-gap-policy", "hold"]);
+        holding.process(frame(&[("feeder", phase(1000.0, 1000), phase(0.0, 1000))]));
+        holding.process(frame(&[("feeder", phase(3000.0, 4600), phase(0.0, 4600))]));
+        // Repeated and older timestamps add nothing
+        holding.process(frame(&[("feeder", phase(3000.0, 4600), phase(0.0, 4600))]));
+        let energy = counter(&registry, "energy_kwh_total", "a", Some("import"));
+        assert!((energy - 1.0).abs() < 1e-9, "{energy}");
+    }
+
+    #[test]
+    fn phase_c_is_exported_only_for_three_phase_meters() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &[]);
+
+        let mut joined = frame(&[
+            ("meter", phase(100.0, 1000), phase(10.0, 1000)),
+            ("feeder", phase(200.0, 1000), phase(20.0, 1000)),
+        ]);
+        if let Some(DataProduct::Calculations(calcs)) = &mut joined.calculations[0].data_product {
+            calcs.phase_c = Some(phase(40.0, 1000));
+        }
+        exporter.process(joined);
+
+        assert_eq!(
+            gauge(&registry, "real_power_latest", "meter", "c"),
+            Some(40.0)
+        );
+        assert_eq!(
+            gauge(&registry, "rms_voltage_latest", "meter", "c"),
+            Some(120.0)
+        );
+        assert_eq!(gauge(&registry, "real_power_latest", "feeder", "c"), None);
+        assert_eq!(
+            gauge(&registry, "real_power_three_phase_latest", TOPIC, "c"),
+            Some(40.0)
+        );
+        assert_eq!(
+            gauge(&registry, "real_power_three_phase_latest", TOPIC, "a"),
+            Some(300.0)
+        );
+    }
+
+    #[test]
+    fn stale_phases_are_dropped_until_they_report_again() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(
+            &gauges,
+            &["--stale-after", "20ms", "--stale-action", "drop"],
+        );
+
+        exporter.process(frame(&[("feeder", phase(100.0, 1000), phase(50.0, 1000))]));
+        std::thread::sleep(Duration::from_millis(30));
+        exporter.tick();
+
+        let value = |name, phase| gauge(&registry, name, "feeder", phase);
+        assert_eq!(value("real_power_latest", "a"), None);
+        assert_eq!(value("rms_voltage_p50", "b"), None);
+
+        // The windows start over rather than picking up where they stopped
+        exporter.process(frame(&[("feeder", phase(300.0, 1001), phase(70.0, 1001))]));
+        assert_eq!(value("real_power_latest", "a"), Some(300.0));
+        assert_eq!(value("real_power_trough", "a"), Some(300.0));
+    }
+
+    #[test]
+    fn latest_calculations_are_kept_for_the_streams_api() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &[]);
+
+        exporter.process(frame(&[("feeder", phase(100.0, 1000), phase(50.0, 1000))]));
+        exporter.process(frame(&[("feeder", phase(300.0, 1001), phase(70.0, 1001))]));
+
+        let summaries = exporter.streams.summaries();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].phases, ["a", "b"]);
+        let latest = exporter.streams.latest("feeder");
+        let phase_a = &latest[0].phases["a"];
+        assert_eq!(phase_a.real_power, Some(300.0));
+        assert_eq!(phase_a.time.map(|time| time.timestamp()), Some(1001));
+        assert!(exporter.streams.latest("other").is_empty());
+    }
+
+    #[test]
+    fn series_are_labelled_with_the_rewritten_stream_name() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(
+            &gauges,
+            &[
+                "--stream-label-rewrite",
+                "^threephase/=",
+                "--sanitize-stream-labels",
+            ],
+        );
+
+        let name = "threephase/feeder 1 (east)";
+        exporter.process(frame(&[(name, phase(100.0, 1000), phase(50.0, 1000))]));
+
+        let value = |stream| gauge(&registry, "real_power_latest", stream, "a");
+        assert_eq!(value("feeder_1_east"), Some(100.0));
+        assert_eq!(value(name), None);
+        assert_eq!(exporter.streams.latest("feeder_1_east").len(), 1);
+        let mappings = exporter.labels.mappings();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].stream, name);
+        assert_eq!(mappings[0].label, "feeder_1_east");
+        assert!(mappings[0].active);
+    }
+
+    #[test]
+    fn window_holds_the_last_window_seconds_of_samples() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &["--window-seconds", "3"]);
+
+        // One sample a second, then a burst: the window is about time, not count
+        for (at, real) in [(1000, 1000.0), (1001, 1.0), (1002, 2.0), (1003, 3.0)] {
+            exporter.process(frame(&[("feeder", phase(real, at), phase(real, at))]));
+        }
+        for real in [4.0, 5.0, 6.0, 7.0] {
+            exporter.process(frame(&[("feeder", phase(real, 1003), phase(real, 1003))]));
+        }
+
+        let value = |name| gauge(&registry, name, "feeder", "a");
+        assert_eq!(value("real_power_peak"), Some(7.0));
+        assert_eq!(value("real_power_trough"), Some(1.0));
+        assert_eq!(value("real_power_average"), Some(4.0));
+    }
+
+    #[test]
+    fn window_starts_over_when_the_clock_steps_back() {
+        let mut bucket = Bucket::with_window(Duration::from_secs(3));
+        for (at, value) in [(1000.0, 10.0), (1001.0, 11.0), (1002.0, 12.0)] {
+            bucket.apply(value, at);
+        }
+        bucket.apply(1.0, 500.0);
+        bucket.apply(2.0, 501.0);
+
+        assert_eq!(bucket.values, [1.0, 2.0]);
+        assert_eq!(bucket.peak(), 2.0);
+    }
+
+    #[test]
+    fn window_holds_at_most_max_window_samples() {
+        let mut bucket = Bucket::with_window(Duration::from_secs(3));
+        for n in 0..MAX_WINDOW_SAMPLES + 10 {
+            bucket.apply(n as f64, 1000.0);
+        }
+
+        assert_eq!(bucket.values.len(), MAX_WINDOW_SAMPLES);
+        assert_eq!(bucket.trough(), 10.0);
+    }
+
+    #[test]
+    fn clock_status_goes_to_the_registry_the_gauges_were_registered_with() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &[]);
+
+        let synced = |at, locked| {
+            let mut phase = phase(100.0, at);
+            phase.provenance.as_mut().unwrap().time_sync = Some(TimeSync {
+                source: Some(Source::Gps as i32),
+                locked: Some(locked),
+                offset_ns: Some(2_000),
+                max_error_ns: None,
+            });
+            phase
+        };
+        exporter.process(frame(&[("feeder", synced(1000, true), synced(1000, true))]));
+        exporter.process(frame(&[(
+            "feeder",
+            synced(1001, false),
+            synced(1001, false),
+        )]));
+
+        let value = |name: &str| {
+            let family = registry
+                .gather()
+                .into_iter()
+                .find(|family| family.get_name() == name)?;
+            let metric = &family.get_metric()[0];
+            Some(match family.get_field_type() {
+                MetricType::COUNTER => metric.get_counter().get_value(),
+                _ => metric.get_gauge().get_value(),
+            })
+        };
+        assert_eq!(value("time_sync_offset_seconds"), Some(2e-6));
+        assert_eq!(value("timestamps_trusted"), Some(0.0));
+        assert_eq!(value("untrusted_timestamp_frames_total"), Some(1.0));
+        assert!(prometheus::gather()
+            .iter()
+            .all(|family| family.get_name() != "timestamps_trusted"));
+    }
+
+    #[test]
+    fn exporters_with_their_own_registries_keep_their_own_sample_counters() {
+        let sample_count = |registry: &MetricsRegistry| {
+            registry
+                .gather()
+                .into_iter()
+                .find(|family| family.get_name() == "rms_voltage")
+                .map(|family| family.get_metric()[0].get_summary().get_sample_count())
+        };
+        let (first, second) = (MetricsRegistry::new(), MetricsRegistry::new());
+        let first_gauges = Gauges::register(&first).unwrap();
+        let second_gauges = Gauges::register(&second).unwrap();
+        let mut first_exporter = exporter(&first_gauges, &["--sample-counters"]);
+        let mut second_exporter = exporter(&second_gauges, &["--sample-counters"]);
+
+        first_exporter.process(frame(&[("feeder", phase(100.0, 1000), phase(100.0, 1000))]));
+        first_exporter.process(frame(&[("feeder", phase(100.0, 1001), phase(100.0, 1001))]));
+        second_exporter.process(frame(&[("feeder", phase(100.0, 1000), phase(100.0, 1000))]));
+
+        assert_eq!(sample_count(&first), Some(2));
+        assert_eq!(sample_count(&second), Some(1));
+    }
+
+    #[test]
+    fn percentiles_and_stddev_cover_the_window() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &["--window-seconds", "1000"]);
+
+        for (at, real) in (1..=100).map(|n| (1000 + n, n as f32)) {
+            exporter.process(frame(&[("feeder", phase(real, at), phase(real, at))]));
+        }
+
+        let value = |name| gauge(&registry, name, "feeder", "a");
+        assert_eq!(value("real_power_p50"), Some(50.0));
+        assert_eq!(value("real_power_p95"), Some(95.0));
+        assert_eq!(value("real_power_p99"), Some(99.0));
+        let stddev = value("real_power_stddev").unwrap();
+        assert!((stddev - 28.866).abs() < 1e-3, "{stddev}");
+        // Voltage is constant in this fixture
+        assert_eq!(value("rms_voltage_stddev"), Some(0.0));
+        assert_eq!(value("rms_current_p99"), Some((99.0f32 / 120.0) as f64));
+    }
+
+    #[test]
+    fn latency_is_measured_from_the_newest_provenance_timestamp() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+
+        let joined = frame(&[
+            ("feeder1", phase(100.0, 1000), phase(100.0, 1000)),
+            ("feeder2", phase(100.0, 1002), phase(100.0, 1002)),
+        ]);
+        let received = UNIX_EPOCH + Duration::from_millis(1_002_250);
+        gauges.observe_latency("test", &joined, received);
+        // Ahead of our clock, e.g. a publisher whose clock runs fast
+        gauges.observe_latency("test", &joined, UNIX_EPOCH + Duration::from_secs(1001));
+
+        let histogram = gauges.latency.with_label_values(&["test"]);
+        assert_eq!(histogram.get_sample_count(), 2);
+        assert_eq!(histogram.get_sample_sum(), 0.25);
+    }
+
+    #[test]
+    fn extended_statistics_only_exported_when_sent() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &[]);
+
+        exporter.process(frame(&[("plain", phase(100.0, 1000), phase(100.0, 1000))]));
+        let mut extended = phase(100.0, 1000);
+        if let Some(voltage) = extended.voltage_waveform_calculations_v.as_mut() {
+            voltage.crest_factor = Some(1.5);
+            voltage.thd_percent = Some(2.5);
+        }
+        exporter.process(frame(&[("extended", extended, extended)]));
+
+        assert_eq!(gauge(&registry, "thd_voltage_latest", "plain", "a"), None);
+        assert_eq!(
+            gauge(&registry, "crest_factor_voltage_latest", "extended", "a"),
+            Some(1.5)
+        );
+        assert_eq!(
+            gauge(&registry, "thd_voltage_average", "extended", "b"),
+            Some(2.5)
+        );
+        assert_eq!(
+            gauge(&registry, "thd_current_latest", "extended", "a"),
+            None
+        );
+    }
+
+    #[test]
+    fn missing_fields_are_counted_and_leave_gauges_unchanged() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &[]);
+
+        exporter.process(frame(&[("feeder", phase(100.0, 1000), phase(50.0, 1000))]));
+        let mut without_power = phase(70.0, 1001);
+        without_power.power_calculations = None;
+        let mut without_rms = phase(300.0, 1001);
+        if let Some(voltage) = without_rms.voltage_waveform_calculations_v.as_mut() {
+            voltage.rms = None;
+        }
+        let mut partial = frame(&[("feeder", without_rms, without_power)]);
+        partial
+            .calculations
+            .push(CompositeJoinedCalculationsWrapper {
+                calculation_name: None,
+                data_product: None,
+                device_id: None,
+                harmonics: None,
+            });
+        exporter.process(partial);
+
+        let value = |name, phase| gauge(&registry, name, "feeder", phase);
+        assert_eq!(value("real_power_latest", "a"), Some(300.0));
+        assert_eq!(value("rms_voltage_latest", "a"), Some(120.0));
+        assert_eq!(value("rms_voltage_average", "a"), Some(120.0));
+        assert_eq!(value("real_power_latest", "b"), Some(50.0));
+        assert_eq!(
+            value("rms_current_latest", "b"),
+            Some(f64::from(70.0f32 / 120.0))
+        );
+
+        let missing = |field: &str| {
+            gauges
+                .decoding
+                .missing_fields
+                .with_label_values(&["test", field])
+                .get()
+        };
+        assert_eq!(missing("phase_b.power_calculations"), 1);
+        assert_eq!(missing("phase_a.voltage_waveform_calculations_v.rms"), 1);
+        assert_eq!(missing("phase_b.power_calculations.real_power_w"), 0);
+        let incomplete = |outcome: &str| {
+            gauges
+                .decoding
+                .incomplete
+                .with_label_values(&["test", outcome])
+                .get()
+        };
+        assert_eq!(incomplete("partial"), 1);
+        assert_eq!(incomplete("skipped"), 1);
+    }
+
+    #[test]
+    fn late_calculations_leave_the_gauges_alone() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &["--lateness-window", "10s"]);
+
+        exporter.process(frame(&[("feeder", phase(100.0, 1000), phase(100.0, 1000))]));
+        exporter.process(frame(&[("feeder", phase(300.0, 1001), phase(300.0, 1001))]));
+        exporter.process(frame(&[("feeder", phase(900.0, 995), phase(900.0, 995))]));
+        exporter.process(frame(&[("feeder", phase(900.0, 980), phase(900.0, 980))]));
+
+        assert_eq!(
+            gauge(&registry, "real_power_latest", "feeder", "a"),
+            Some(300.0)
+        );
+        assert_eq!(
+            gauge(&registry, "real_power_peak", "feeder", "a"),
+            Some(300.0)
+        );
+        let count = |outcome: &str| gauges.late.with_label_values(&["test", outcome]).get();
+        assert_eq!(count("accepted"), 1);
+        assert_eq!(count("rejected"), 1);
+    }
+
+    #[test]
+    fn sequence_numbers_count_lost_and_repeated_frames() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &[]);
+        let numbered = |real, number| {
+            let mut calcs = phase(real, 1000 + number as i64);
+            if let Some(provenance) = calcs.provenance.as_mut() {
+                provenance.generic_sequence_number = Some(number);
+            }
+            frame(&[("feeder", calcs, calcs)])
+        };
+
+        exporter.process(numbered(100.0, 1));
+        exporter.process(numbered(200.0, 4));
+        exporter.process(numbered(900.0, 4));
+        exporter.process(numbered(300.0, 5));
+
+        assert_eq!(
+            gauges
+                .frames_dropped
+                .with_label_values(&["test", "feeder"])
+                .get(),
+            2
+        );
+        let out_of_order = |kind: &str| {
+            gauges
+                .frames_out_of_order
+                .with_label_values(&["test", "feeder", kind])
+                .get()
+        };
+        assert_eq!(out_of_order("duplicate"), 1);
+        assert_eq!(out_of_order("late"), 0);
+        assert_eq!(
+            gauge(&registry, "real_power_peak", "feeder", "a"),
+            Some(300.0)
+        );
+    }
+
+    #[test]
+    fn reject_policy_rejects_anything_out_of_order() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(&gauges, &["--late-data-policy", "reject"]);
+
+        exporter.process(frame(&[("feeder", phase(100.0, 1000), phase(100.0, 1000))]));
+        exporter.process(frame(&[("feeder", phase(900.0, 999), phase(900.0, 999))]));
+        exporter.process(frame(&[("other", phase(50.0, 900), phase(50.0, 900))]));
+
+        let count = |outcome: &str| gauges.late.with_label_values(&["test", outcome]).get();
+        assert_eq!(count("rejected"), 1);
+        assert_eq!(count("accepted"), 0);
+        assert_eq!(
+            gauge(&registry, "real_power_latest", "other", "a"),
+            Some(50.0)
+        );
+    }
+
+    #[test]
+    fn site_total_sums_its_members_and_counts_missing_ones() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+        let mut exporter = exporter(
+            &gauges,
+            &[
+                "--site-total",
+                "--site-total-member",
+                "feeder-1",
+                "--site-total-member",
+                "feeder-2",
+            ],
+        );
+
+        exporter.process(frame(&[
+            ("feeder-1", phase(100.0, 1000), phase(10.0, 1000)),
+            ("feeder-2", phase(250.0, 1000), phase(20.0, 1000)),
+            ("solar", phase(40.0, 1000), phase(40.0, 1000)),
+        ]));
+        let value = |name, phase| gauge(&registry, name, "site/total", phase);
+        assert_eq!(value("real_power_latest", "a"), Some(350.0));
+        assert_eq!(value("reactive_power_latest", "b"), Some(3.0));
+        assert_eq!(value("rms_voltage_latest", "a"), None);
+
+        exporter.process(frame(&[(
+            "feeder-1",
+            phase(100.0, 1001),
+            phase(10.0, 1001),
+        )]));
+        assert_eq!(value("real_power_latest", "a"), Some(100.0));
+        let missing = |member: &str| {
+            gauges
+                .site_total_missing
+                .with_label_values(&["test", member])
+                .get()
+        };
+        assert_eq!(missing("feeder-2"), 1);
+        assert_eq!(missing("feeder-1"), 0);
+    }
+
+    #[test]
+    fn assembly_outcomes_are_counted_and_only_emitted_frames_kept() {
+        let registry = MetricsRegistry::new();
+        let gauges = Gauges::register(&registry).unwrap();
+
+        let frames = settle(
+            &gauges,
+            "test",
+            vec![
+                Outcome::Late,
+                Outcome::Dropped {
+                    missing: vec!["feeder".to_string()],
+                },
+                Outcome::Late,
+            ],
+        );
+
+        assert!(frames.is_empty());
+        let count = |outcome: &str| gauges.assembly.with_label_values(&["test", outcome]).get();
+        assert_eq!(count("late"), 2);
+        assert_eq!(count("dropped"), 1);
+        assert_eq!(count("complete"), 0);
     }
 }
@@ -0,0 +1,163 @@
+//! What the stream entries of a message carry. Calculations feed everything else; raw
+//! waveforms are summarized here, per stream, phase and waveform. Every entry is counted by
+//! its data product, so a publisher sending something this exporter ignores shows up in
+//! `data_products_total`. A product added to the message after this exporter was built decodes
+//! as none at all, and is counted as `unknown`.
+
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec, Opts};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, PhaseWaveform, Waveform,
+};
+
+/// What stream entries carry, and the summaries of raw waveforms.
+pub struct Gauges {
+    pub products: IntCounterVec,
+    pub sample_rate: GaugeVec,
+    pub samples: GaugeVec,
+    pub rms: GaugeVec,
+    pub peak: GaugeVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let products = IntCounterVec::new(
+            Opts::new(
+                "data_products_total",
+                "Stream entries received, by data product: calculations, fft, waveform, or unknown for one this exporter doesn't recognise",
+            ),
+            &["device", "product"],
+        )?;
+        registry.register(Box::new(products.clone()))?;
+        let sample_rate = GaugeVec::new(
+            Opts::new(
+                "waveform_sample_rate_hertz",
+                "Sample rate of the latest raw waveform of the stream",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(sample_rate.clone()))?;
+        let samples = GaugeVec::new(
+            Opts::new(
+                "waveform_samples",
+                "Samples in the latest raw waveform of the stream and phase",
+            ),
+            &["device", "stream", "phase", "waveform"],
+        )?;
+        registry.register(Box::new(samples.clone()))?;
+        let rms = GaugeVec::new(
+            Opts::new(
+                "waveform_rms",
+                "Root mean square of the latest raw waveform, in volts or amps",
+            ),
+            &["device", "stream", "phase", "waveform"],
+        )?;
+        registry.register(Box::new(rms.clone()))?;
+        let peak = GaugeVec::new(
+            Opts::new(
+                "waveform_peak",
+                "Largest absolute sample of the latest raw waveform, in volts or amps",
+            ),
+            &["device", "stream", "phase", "waveform"],
+        )?;
+        registry.register(Box::new(peak.clone()))?;
+        Ok(Self {
+            products,
+            sample_rate,
+            samples,
+            rms,
+            peak,
+        })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 5] {
+        [
+            &self.products,
+            &self.sample_rate,
+            &self.samples,
+            &self.rms,
+            &self.peak,
+        ]
+    }
+}
+
+/// Counts a stream entry from `device` by what it carries.
+pub fn count(gauges: &Gauges, device: &str, product: Option<&DataProduct>) {
+    let product = match product {
+        Some(DataProduct::Calculations(_)) => "calculations",
+        Some(DataProduct::Fft(_)) => "fft",
+        Some(DataProduct::Waveform(_)) => "waveform",
+        None => "unknown",
+    };
+    let counter = gauges.products.with_label_values(&[device, product]);
+    counter.inc();
+    if product == "unknown" && counter.get() == 1 {
+        log::warn!(
+            "Message with a data product this exporter doesn't recognise: device={device} \
+             (further ones are only counted in data_products_total)"
+        );
+    }
+}
+
+/// Root mean square and largest absolute value of `samples`. None when there are none, or
+/// one isn't a number.
+fn summary(samples: &[f32]) -> Option<(f64, f64)> {
+    if samples.is_empty() {
+        return None;
+    }
+    let (squares, peak) = samples.iter().fold((0.0, 0.0), |(squares, peak), &sample| {
+        let sample = sample as f64;
+        (squares + sample * sample, f64::max(peak, sample.abs()))
+    });
+    let rms = (squares / samples.len() as f64).sqrt();
+    (rms.is_finite() && peak.is_finite()).then_some((rms, peak))
+}
+
+/// Exports what `waveform` has; phases and waveforms it leaves out keep their previous values.
+pub fn record(gauges: &Gauges, device: &str, stream: &str, waveform: &Waveform) {
+    if let Some(rate) = waveform.sample_rate_hz {
+        gauges
+            .sample_rate
+            .with_label_values(&[device, stream])
+            .set(rate as f64);
+    }
+    let phases = [&waveform.phase_a, &waveform.phase_b, &waveform.phase_c];
+    for (phase, samples) in ["a", "b", "c"].into_iter().zip(phases) {
+        let Some(PhaseWaveform {
+            voltage_v,
+            current_a,
+        }) = samples
+        else {
+            continue;
+        };
+        for (waveform, samples) in [("voltage", voltage_v), ("current", current_a)] {
+            let Some((rms, peak)) = summary(samples) else {
+                continue;
+            };
+            let labels = [device, stream, phase, waveform];
+            gauges
+                .samples
+                .with_label_values(&labels)
+                .set(samples.len() as f64);
+            gauges.rms.with_label_values(&labels).set(rms);
+            gauges.peak.with_label_values(&labels).set(peak);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_is_the_rms_and_the_largest_absolute_sample() {
+        assert_eq!(
+            summary(&[3.0, -4.0, 3.0, -4.0]),
+            Some((12.5f64.sqrt(), 4.0))
+        );
+        assert_eq!(summary(&[-2.0]), Some((2.0, 2.0)));
+        assert_eq!(summary(&[]), None);
+        assert_eq!(summary(&[1.0, f32::NAN]), None);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, Opts};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeCalculations, CompositeTwoPhaseCalculations,
+};
+
+/// Counts what the deadbands hold back.
+pub struct Counters {
+    pub suppressed: IntCounterVec,
+}
+
+impl Counters {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let suppressed = IntCounterVec::new(
+            Opts::new(
+                "deadband_suppressed_total",
+                "Messages held back because no value moved outside the stream's deadband",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(suppressed.clone()))?;
+        Ok(Self { suppressed })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 1] {
+        [&self.suppressed]
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Width {
+    Absolute(f64),
+    /// Percent of the last forwarded value
+    Percent(f64),
+}
+
+impl Width {
+    fn exceeded(self, last: f64, value: f64) -> bool {
+        let change = (value - last).abs();
+        match self {
+            Width::Absolute(limit) => change > limit,
+            Width::Percent(percent) => change > last.abs() * percent / 100.0,
+        }
+    }
+}
+
+/// Parses a deadband width: `0.5` is absolute, `2%` is relative to the last forwarded value.
+pub fn parse_width(value: &str) -> Result<Width, String> {
+    let (number, percent) = match value.strip_suffix('%') {
+        Some(number) => (number, true),
+        None => (value, false),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid deadband '{value}', expected e.g. 0.5 or 2%"))?;
+    if number < 0.0 {
+        return Err(format!("deadband '{value}' must not be negative"));
+    }
+    Ok(if percent {
+        Width::Percent(number)
+    } else {
+        Width::Absolute(number)
+    })
+}
+
+/// Parses `--deadband STREAM=WIDTH`
+pub fn parse_deadband(value: &str) -> Result<(String, Width), String> {
+    let (stream, width) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected STREAM=WIDTH, got '{value}'"))?;
+    Ok((stream.to_string(), parse_width(width)?))
+}
+
+#[derive(Clone, Debug)]
+pub struct DeadbandConfig {
+    pub default: Option<Width>,
+    pub per_stream: HashMap<String, Width>,
+    pub keepalive: Duration,
+}
+
+struct Forwarded {
+    at: Instant,
+    values: Vec<f64>,
+}
+
+/// Report-by-exception filter: a stream's message is only passed on when some value has moved
+/// outside its deadband since the last message that was, or when the keepalive is due.
+pub struct Deadband {
+    config: DeadbandConfig,
+    last: HashMap<String, Forwarded>,
+}
+
+fn phase_values(values: &mut Vec<f64>, phase: Option<CompositeCalculations>) {
+    let Some(phase) = phase else {
+        return;
+    };
+    let voltage = phase.voltage_waveform_calculations_v.unwrap_or_default();
+    let current = phase.current_waveform_calculations_a.unwrap_or_default();
+    let power = phase.power_calculations.unwrap_or_default();
+    values.extend(
+        [
+            voltage.rms(),
+            voltage.dc_offset(),
+            current.rms(),
+            current.dc_offset(),
+            power.real_power_w(),
+            power.apparent_power_va(),
+            power.reactive_power_var(),
+            power.power_factor(),
+        ]
+        .map(f64::from),
+    );
+}
+
+impl Deadband {
+    pub fn new(config: DeadbandConfig) -> Self {
+        Self {
+            config,
+            last: HashMap::new(),
+        }
+    }
+
+    pub fn pass(
+        &mut self,
+        counters: &Counters,
+        device: &str,
+        stream: &str,
+        calcs: &CompositeTwoPhaseCalculations,
+    ) -> bool {
+        let Some(width) = self
+            .config
+            .per_stream
+            .get(stream)
+            .copied()
+            .or(self.config.default)
+        else {
+            return true;
+        };
+
+        let mut values = Vec::with_capacity(16);
+        phase_values(&mut values, calcs.phase_a);
+        phase_values(&mut values, calcs.phase_b);
+        phase_values(&mut values, calcs.phase_c);
+
+        let now = Instant::now();
+        let forward = match self.last.get(stream) {
+            None => true,
+            Some(last) if last.values.len() != values.len() => true,
+            Some(last) if now.duration_since(last.at) >= self.config.keepalive => true,
+            Some(last) => last
+                .values
+                .iter()
+                .zip(&values)
+                .any(|(last, value)| width.exceeded(*last, *value)),
+        };
+
+        if forward {
+            self.last
+                .insert(stream.to_string(), Forwarded { at: now, values });
+        } else {
+            counters
+                .suppressed
+                .with_label_values(&[device, stream])
+                .inc();
+        }
+        forward
+    }
+
+    /// The next message from `stream` is passed on whatever it holds, e.g. after it went stale.
+    pub fn forget(&mut self, stream: &str) {
+        self.last.remove(stream);
+    }
+}
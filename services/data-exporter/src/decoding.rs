@@ -0,0 +1,74 @@
+use karman_types::Stream;
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, Opts};
+
+/// Counts fields publishers leave out. Every field is optional on the wire, so a missing one
+/// only leaves its gauges where they were instead of taking the exporter down.
+pub struct Counters {
+    pub missing_fields: IntCounterVec,
+    pub incomplete: IntCounterVec,
+}
+
+impl Counters {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let missing_fields = IntCounterVec::new(
+            Opts::new(
+                "missing_fields_total",
+                "Required fields left out of a message, which then leave their gauges unchanged",
+            ),
+            &["device", "field"],
+        )?;
+        registry.register(Box::new(missing_fields.clone()))?;
+        let incomplete = IntCounterVec::new(
+            Opts::new(
+                "incomplete_calculations_total",
+                "Streams in a message that were missing fields (partial) or were unusable (skipped)",
+            ),
+            &["device", "outcome"],
+        )?;
+        registry.register(Box::new(incomplete.clone()))?;
+        Ok(Self {
+            missing_fields,
+            incomplete,
+        })
+    }
+
+    pub fn collectors(&self) -> [&dyn Collector; 2] {
+        [&self.missing_fields, &self.incomplete]
+    }
+
+    /// Counts the fields `stream` from `device` was missing, and the stream as partial if
+    /// there were any.
+    pub fn count(&self, device: &str, stream: &Stream) {
+        if stream.is_complete() {
+            return;
+        }
+        self.incomplete
+            .with_label_values(&[device, "partial"])
+            .inc();
+        for field in &stream.missing {
+            let counter = self.missing_fields.with_label_values(&[device, field]);
+            counter.inc();
+            // Every message from a publisher that never sends the field would otherwise log
+            if counter.get() == 1 {
+                log::warn!(
+                    "Message without a required field: device={device} stream={} \
+                     field={field} (further ones are only counted in missing_fields_total)",
+                    stream.name
+                );
+            }
+        }
+    }
+
+    /// Counts a stream entry without a name or data product.
+    pub fn skipped(&self, device: &str, reason: &str) {
+        let counter = self.incomplete.with_label_values(&[device, "skipped"]);
+        counter.inc();
+        if counter.get() == 1 {
+            log::warn!(
+                "Skipping a stream in a message: device={device} reason={reason:?} \
+                 (further ones are only counted in incomplete_calculations_total)"
+            );
+        }
+    }
+}
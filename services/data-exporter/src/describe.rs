@@ -0,0 +1,80 @@
+//! `/schema`: the metric catalog, for integrators building dashboards and alerts without
+//! reading the source. It is built from the registered collectors, so every metric is listed
+//! before it has any series. The stored document itself is described by data-db's `/schema`.
+
+use prometheus::core::Collector;
+use prometheus::proto::MetricType;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::data_product_listener::Gauges;
+use crate::metric_filter::metric_filter;
+use crate::TENANT;
+
+#[derive(Serialize)]
+struct MetricDescription {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    help: String,
+    labels: Vec<String>,
+}
+
+/// Describes every metric family `collectors` export and --metrics-include/--metrics-exclude
+/// let through, sorted by name, whether or not it has any series yet.
+fn describe_metrics(collectors: &[&dyn Collector]) -> Vec<MetricDescription> {
+    let mut metrics = Vec::new();
+    for collector in collectors {
+        let families = collector.collect();
+        for desc in collector.desc() {
+            // Never served, see metric_filter
+            if !metric_filter().serves_name(&desc.fq_name) {
+                continue;
+            }
+            let kind = families
+                .iter()
+                .find(|family| family.get_name() == desc.fq_name)
+                .map_or("untyped", |family| match family.get_field_type() {
+                    MetricType::COUNTER => "counter",
+                    MetricType::GAUGE => "gauge",
+                    MetricType::SUMMARY => "summary",
+                    MetricType::HISTOGRAM => "histogram",
+                    MetricType::UNTYPED => "untyped",
+                });
+            let mut labels = desc.variable_labels.clone();
+            // Added to every series on the way out, see with_tenant_label
+            if TENANT.get().is_some() {
+                labels.push("tenant".to_string());
+            }
+            metrics.push(MetricDescription {
+                name: desc.fq_name.clone(),
+                kind,
+                help: desc.help.clone(),
+                labels,
+            });
+        }
+    }
+    metrics.sort_by(|a, b| a.name.cmp(&b.name));
+    metrics
+}
+
+#[derive(Serialize)]
+struct Description {
+    metrics: Vec<MetricDescription>,
+}
+
+/// The catalog of what this process exports. The sample counters and harmonics are only
+/// listed when `--sample-counters` and `--harmonics` are on, as only then do they have series.
+pub fn describe(gauges: &Gauges, sample_counters: bool, harmonics: bool) -> Value {
+    let mut collectors = gauges.collectors();
+    if sample_counters {
+        collectors.extend(gauges.sample_counters.collectors());
+    }
+    if harmonics {
+        collectors.extend(gauges.harmonics.collectors());
+    }
+    serde_json::to_value(Description {
+        metrics: describe_metrics(&collectors),
+    })
+    .expect("A metric description is always valid JSON")
+}
@@ -0,0 +1,190 @@
+//! Energy: real and reactive power integrated over time into kWh and kvarh counters per stream
+//! and phase, so the energy of any interval is one `increase()` away.
+//!
+//! Each pair of consecutive samples of a phase adds the trapezoid between them, timed by their
+//! provenance timestamps; samples without one, or not after the previous one, are left out.
+//! Counters only go up, so energy flowing the other way (negative power, e.g. a site exporting
+//! solar) is counted under `direction="export"`, and a trapezoid whose ends have opposite signs
+//! is split where it crosses zero.
+//!
+//! Samples further apart than --energy-max-gap (an outage, a restart of the publisher, a
+//! stream going quiet) are bridged as --energy-gap-policy says, and the gap is counted in
+//! energy_gap_seconds_total. The counters start from zero with the exporter; `increase()`
+//! takes that in its stride like any counter reset.
+
+use std::{collections::HashMap, time::Duration};
+
+use clap::ValueEnum;
+use prometheus::{core::Collector, CounterVec, Opts};
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeCalculations;
+
+const SECONDS_PER_HOUR: f64 = 3600.0;
+
+/// How the energy between two samples more than --energy-max-gap apart is counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum GapPolicy {
+    /// Count nothing for the gap; integration starts again from the next sample
+    #[default]
+    Skip,
+    /// Assume the power before the gap held until the next sample
+    Hold,
+    /// Interpolate linearly across the gap, as between any two samples
+    Interpolate,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EnergyConfig {
+    pub max_gap: Duration,
+    pub gap_policy: GapPolicy,
+}
+
+pub struct Counters {
+    energy: CounterVec,
+    reactive_energy: CounterVec,
+    gap_seconds: CounterVec,
+}
+
+impl Counters {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let energy = CounterVec::new(
+            Opts::new(
+                "energy_kwh_total",
+                "Real energy integrated from real power, by direction: import or export",
+            ),
+            &["device", "stream", "phase", "direction"],
+        )?;
+        registry.register(Box::new(energy.clone()))?;
+        let reactive_energy = CounterVec::new(
+            Opts::new(
+                "reactive_energy_kvarh_total",
+                "Reactive energy integrated from reactive power, by direction: import or export",
+            ),
+            &["device", "stream", "phase", "direction"],
+        )?;
+        registry.register(Box::new(reactive_energy.clone()))?;
+        let gap_seconds = CounterVec::new(
+            Opts::new(
+                "energy_gap_seconds_total",
+                "Time between samples further apart than --energy-max-gap, bridged per \
+                 --energy-gap-policy",
+            ),
+            &["device", "stream", "phase"],
+        )?;
+        registry.register(Box::new(gap_seconds.clone()))?;
+        Ok(Self {
+            energy,
+            reactive_energy,
+            gap_seconds,
+        })
+    }
+
+    pub fn collectors(&self) -> [&dyn Collector; 3] {
+        [&self.energy, &self.reactive_energy, &self.gap_seconds]
+    }
+}
+
+/// A phase's previous sample: Unix time, and real and reactive power where sent.
+#[derive(Clone, Copy)]
+struct Sample {
+    time: f64,
+    real_power: Option<f64>,
+    reactive_power: Option<f64>,
+}
+
+/// The previous sample of every stream and phase, to integrate the next one against.
+pub struct EnergyMeters {
+    config: EnergyConfig,
+    last: HashMap<(String, &'static str), Sample>,
+}
+
+impl EnergyMeters {
+    pub fn new(config: EnergyConfig) -> Self {
+        Self {
+            config,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Adds the energy since the phase's previous sample.
+    pub fn record(
+        &mut self,
+        counters: &Counters,
+        device: &str,
+        stream: &str,
+        phase: &'static str,
+        calcs: &CompositeCalculations,
+    ) {
+        let Some(time) = calcs.provenance.and_then(|provenance| provenance.utc_time) else {
+            return;
+        };
+        let power = calcs.power_calculations.unwrap_or_default();
+        let sample = Sample {
+            time: time.seconds as f64 + time.nanos as f64 / 1e9,
+            real_power: power.real_power_w.map(f64::from),
+            reactive_power: power.reactive_power_var.map(f64::from),
+        };
+
+        let key = (stream.to_string(), phase);
+        let Some(previous) = self.last.get(&key).copied() else {
+            self.last.insert(key, sample);
+            return;
+        };
+        let elapsed = sample.time - previous.time;
+        if elapsed <= 0.0 {
+            return;
+        }
+        self.last.insert(key, sample);
+
+        let labels = [device, stream, phase];
+        let mut policy = GapPolicy::Interpolate;
+        if elapsed > self.config.max_gap.as_secs_f64() {
+            counters
+                .gap_seconds
+                .with_label_values(&labels)
+                .inc_by(elapsed);
+            policy = self.config.gap_policy;
+        }
+        for (counter, from, to) in [
+            (&counters.energy, previous.real_power, sample.real_power),
+            (
+                &counters.reactive_energy,
+                previous.reactive_power,
+                sample.reactive_power,
+            ),
+        ] {
+            let (Some(from), Some(to)) = (from, to) else {
+                continue;
+            };
+            let (import, export) = match policy {
+                GapPolicy::Skip => continue,
+                GapPolicy::Hold => integrate(from, from, elapsed),
+                GapPolicy::Interpolate => integrate(from, to, elapsed),
+            };
+            for (direction, energy) in [("import", import), ("export", export)] {
+                if energy > 0.0 {
+                    counter
+                        .with_label_values(&[device, stream, phase, direction])
+                        .inc_by(energy);
+                }
+            }
+        }
+    }
+}
+
+/// The energy (kWh from watts, kvarh from var) of power going linearly from `from` to `to`
+/// over `seconds`, split into the part above zero and the part below it.
+fn integrate(from: f64, to: f64, seconds: f64) -> (f64, f64) {
+    let kilo_hours = seconds / SECONDS_PER_HOUR / 1000.0;
+    if from.signum() == to.signum() || from == 0.0 || to == 0.0 {
+        let area = (from + to) / 2.0 * kilo_hours;
+        return (area.max(0.0), (-area).max(0.0));
+    }
+    // Split at the zero crossing, each side a triangle
+    let crossing = from / (from - to);
+    let first = from / 2.0 * kilo_hours * crossing;
+    let second = to / 2.0 * kilo_hours * (1.0 - crossing);
+    (
+        first.max(0.0) + second.max(0.0),
+        (-first).max(0.0) + (-second).max(0.0),
+    )
+}
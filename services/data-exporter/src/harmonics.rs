@@ -0,0 +1,101 @@
+//! Harmonic content of each stream and phase, for publishers that send it next to their
+//! calculations. Only exported with `--harmonics`, since older publishers never do and the
+//! per-order series add up quickly.
+//!
+//! Every harmonic up to `--max-harmonic-order` is exported as a percent of the fundamental,
+//! and the total harmonic distortion is worked out from all of them, however many were sent.
+//! It is labelled apart from the `thd_*` window gauges, which carry the publisher's own figure.
+
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, Opts};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{Harmonics, PhaseHarmonics};
+
+/// Harmonic magnitudes and the distortion worked out from them.
+pub struct Gauges {
+    pub magnitude: GaugeVec,
+    pub thd: GaugeVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let magnitude = GaugeVec::new(
+            Opts::new(
+                "harmonic_magnitude_percent",
+                "Magnitude of one harmonic of the waveform, as a percent of the fundamental",
+            ),
+            &["device", "stream", "phase", "waveform", "order"],
+        )?;
+        registry.register(Box::new(magnitude.clone()))?;
+        let thd = GaugeVec::new(
+            Opts::new(
+                "harmonic_thd_percent",
+                "Total harmonic distortion of the waveform from its harmonic magnitudes, as a percent of the fundamental",
+            ),
+            &["device", "stream", "phase", "waveform"],
+        )?;
+        registry.register(Box::new(thd.clone()))?;
+        Ok(Self { magnitude, thd })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 2] {
+        [&self.magnitude, &self.thd]
+    }
+}
+
+/// Root sum square of `magnitudes`, each a percent of the fundamental. None when there are
+/// none to go by, or one isn't a number.
+fn thd(magnitudes: &[f32]) -> Option<f64> {
+    if magnitudes.is_empty() {
+        return None;
+    }
+    let thd = magnitudes
+        .iter()
+        .map(|&magnitude| (magnitude as f64).powi(2))
+        .sum::<f64>()
+        .sqrt();
+    thd.is_finite().then_some(thd)
+}
+
+/// Exports what `harmonics` has; phases and waveforms it leaves out keep their previous values.
+pub fn record(gauges: &Gauges, device: &str, stream: &str, harmonics: &Harmonics, max_order: u32) {
+    let phases = [&harmonics.phase_a, &harmonics.phase_b, &harmonics.phase_c];
+    for (phase, harmonics) in ["a", "b", "c"].into_iter().zip(phases) {
+        let Some(PhaseHarmonics {
+            voltage_percent,
+            current_percent,
+        }) = harmonics
+        else {
+            continue;
+        };
+        for (waveform, magnitudes) in [("voltage", voltage_percent), ("current", current_percent)] {
+            let Some(thd) = thd(magnitudes) else {
+                continue;
+            };
+            gauges
+                .thd
+                .with_label_values(&[device, stream, phase, waveform])
+                .set(thd);
+            // The first magnitude is the 2nd harmonic
+            for (order, &magnitude) in (2..=max_order).zip(magnitudes) {
+                gauges
+                    .magnitude
+                    .with_label_values(&[device, stream, phase, waveform, &order.to_string()])
+                    .set(magnitude as f64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thd_is_the_root_sum_square_of_the_harmonics() {
+        assert_eq!(thd(&[3.0, 4.0]), Some(5.0));
+        assert_eq!(thd(&[2.5]), Some(2.5));
+        assert_eq!(thd(&[]), None);
+        assert_eq!(thd(&[1.0, f32::NAN]), None);
+    }
+}
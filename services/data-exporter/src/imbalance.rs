@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, Opts};
+
+/// Phase unbalance, the thresholds it is alerted on, and what it means for motors.
+pub struct Gauges {
+    pub unbalance: GaugeVec,
+    pub threshold: GaugeVec,
+    pub derating: GaugeVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let unbalance = GaugeVec::new(
+            Opts::new(
+                "phase_unbalance_percent",
+                "NEMA MG-1 unbalance: largest deviation from the phase average, as a percent of the average",
+            ),
+            &["device", "stream", "quantity"],
+        )?;
+        registry.register(Box::new(unbalance.clone()))?;
+        let threshold = GaugeVec::new(
+            Opts::new(
+                "phase_unbalance_threshold_percent",
+                "Configured unbalance alert threshold for the stream",
+            ),
+            &["device", "stream", "quantity", "severity"],
+        )?;
+        registry.register(Box::new(threshold.clone()))?;
+        let derating = GaugeVec::new(
+            Opts::new(
+                "motor_derating_factor",
+                "NEMA MG-1 motor derating factor for the current voltage unbalance",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(derating.clone()))?;
+        Ok(Self {
+            unbalance,
+            threshold,
+            derating,
+        })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 3] {
+        [&self.unbalance, &self.threshold, &self.derating]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Quantity {
+    Voltage,
+    Current,
+}
+
+impl Quantity {
+    fn label(self) -> &'static str {
+        match self {
+            Quantity::Voltage => "voltage",
+            Quantity::Current => "current",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    pub warning: f64,
+    pub critical: f64,
+}
+
+/// Parses `--unbalance-threshold STREAM:QUANTITY=WARN,CRIT`, e.g. `threephase/motor-1:voltage=0.5,2`
+pub fn parse_threshold(value: &str) -> Result<(String, Quantity, Thresholds), String> {
+    let format_error = || format!("expected STREAM:QUANTITY=WARN,CRIT, got '{value}'");
+    let (target, limits) = value.rsplit_once('=').ok_or_else(format_error)?;
+    let (stream, quantity) = target.rsplit_once(':').ok_or_else(format_error)?;
+    let quantity = match quantity {
+        "voltage" => Quantity::Voltage,
+        "current" => Quantity::Current,
+        other => {
+            return Err(format!(
+                "unknown quantity '{other}', expected voltage or current"
+            ))
+        }
+    };
+    let (warning, critical) = limits.split_once(',').ok_or_else(format_error)?;
+    let parse = |limit: &str| {
+        limit
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("invalid threshold '{limit}'"))
+    };
+
+    Ok((
+        stream.to_string(),
+        quantity,
+        Thresholds {
+            warning: parse(warning)?,
+            critical: parse(critical)?,
+        },
+    ))
+}
+
+/// Default thresholds plus per-asset overrides.
+#[derive(Clone, Debug)]
+pub struct UnbalanceThresholds {
+    pub voltage: Thresholds,
+    pub current: Thresholds,
+    pub per_stream: HashMap<(String, Quantity), Thresholds>,
+}
+
+impl UnbalanceThresholds {
+    fn for_stream(&self, stream: &str, quantity: Quantity) -> Thresholds {
+        self.per_stream
+            .get(&(stream.to_string(), quantity))
+            .copied()
+            .unwrap_or(match quantity {
+                Quantity::Voltage => self.voltage,
+                Quantity::Current => self.current,
+            })
+    }
+}
+
+/// NEMA MG-1 Figure 14-1, interpolated. Operation above 5% unbalance is not recommended,
+/// so the curve is held at its last point rather than extrapolated.
+fn derating_factor(voltage_unbalance: f64) -> f64 {
+    const CURVE: [(f64, f64); 6] = [
+        (0.0, 1.0),
+        (1.0, 1.0),
+        (2.0, 0.95),
+        (3.0, 0.88),
+        (4.0, 0.82),
+        (5.0, 0.75),
+    ];
+
+    for pair in CURVE.windows(2) {
+        let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+        if voltage_unbalance <= x1 {
+            return y0 + (y1 - y0) * (voltage_unbalance - x0).max(0.0) / (x1 - x0);
+        }
+    }
+    CURVE[CURVE.len() - 1].1
+}
+
+/// Publishes unbalance from per-phase window averages.
+pub fn update(
+    gauges: &Gauges,
+    device: &str,
+    stream: &str,
+    voltages: &[f64],
+    currents: &[f64],
+    thresholds: &UnbalanceThresholds,
+) {
+    for (quantity, values) in [(Quantity::Voltage, voltages), (Quantity::Current, currents)] {
+        let Some(unbalance) = power_calc::imbalance_percent(values) else {
+            continue;
+        };
+        gauges
+            .unbalance
+            .with_label_values(&[device, stream, quantity.label()])
+            .set(unbalance);
+
+        let limits = thresholds.for_stream(stream, quantity);
+        gauges
+            .threshold
+            .with_label_values(&[device, stream, quantity.label(), "warning"])
+            .set(limits.warning);
+        gauges
+            .threshold
+            .with_label_values(&[device, stream, quantity.label(), "critical"])
+            .set(limits.critical);
+
+        if quantity == Quantity::Voltage {
+            gauges
+                .derating
+                .with_label_values(&[device, stream])
+                .set(derating_factor(unbalance));
+        }
+    }
+}
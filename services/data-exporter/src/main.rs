@@ -1,83 +1,788 @@
+use std::path::PathBuf;
+use std::sync::OnceLock;
 use std::time::Duration;
 
 use axum::{
-    http::{HeaderMap, StatusCode},
-    routing::get,
-    Router,
+    extract::Path,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    routing::{delete, get},
+    Json, Router,
 };
 use clap::Parser;
-use prometheus::{Encoder, TextEncoder};
+use frame_assembly::{AssemblyConfig, TimeoutPolicy};
+use health::{Health, HealthArgs};
+use http_auth::{Auth, AuthSettings};
+use late_data::{LatePolicy, LatenessConfig};
+use logging::LogArgs;
+use prometheus::{
+    proto::{LabelPair, MetricFamily},
+    Encoder, TextEncoder,
+};
+use service_config::{tenant_topic, Shared};
+use shutdown::Shutdown;
+use site_total::{MissingMembers, SiteTotalConfig};
+use stream_registry::{Registry, RegistrySettings};
+use zmq_ingest::{HwmConfig, OverflowPolicy, ReaderThreadArgs};
 
-use crate::data_product_listener::listen;
+use crate::alerts::Alerts;
+use crate::bootstrap::BootstrapConfig;
+use crate::completeness::{parse_expected_rate, parse_rate, parse_window_seconds, ExpectedRates};
+use crate::data_product_listener::{listen, parse_source, Gauges, Handles};
+use crate::deadband::{parse_deadband, parse_width, DeadbandConfig, Width};
+use crate::energy::{EnergyConfig, GapPolicy};
+use crate::imbalance::{parse_threshold, Quantity, Thresholds, UnbalanceThresholds};
+use crate::maintenance::Maintenance;
+use crate::metric_filter::{
+    metric_filter, parse_pattern, set_metric_filter, MetricFilter, MetricPattern,
+};
+use crate::metric_names::{set_metric_naming, MetricNaming};
+use crate::openmetrics::{set_exemplars, Format, OPENMETRICS_CONTENT_TYPE, TEXT_CONTENT_TYPE};
+use crate::remote_write::RemoteWriteConfig;
+use crate::staleness::{StaleAction, StalenessConfig};
+use crate::stream_labels::{parse_rewrite, LabelConfig, Rewrite, StreamLabels};
+use crate::streams::Streams;
+use crate::voltage_bands::MeasurementPoint;
 
+mod alerts;
+mod bootstrap;
+mod completeness;
 mod data_product_listener;
+mod data_products;
+mod deadband;
+mod decoding;
+mod describe;
+mod energy;
+mod harmonics;
+mod imbalance;
+mod maintenance;
+mod metric_filter;
+mod metric_names;
+mod openmetrics;
+mod remote_write;
+mod sample_counters;
+mod staleness;
+mod stream_labels;
+mod stream_power;
+mod streams;
+mod time_sync;
+mod voltage_bands;
+
+/// How the shared sections of a --config file map onto data-exporter's flags.
+const SHARED_SETTINGS: &[Shared] = &[
+    Shared {
+        section: "zmq",
+        key: "endpoint",
+        setting: "source",
+    },
+    Shared {
+        section: "zmq",
+        key: "topic",
+        setting: "zmq-subscription",
+    },
+    Shared {
+        section: "database",
+        key: "url",
+        setting: "bootstrap-database-url",
+    },
+    Shared {
+        section: "prometheus",
+        key: "port",
+        setting: "prometheus-port",
+    },
+];
 
 #[derive(Clone, Debug, Parser)]
 struct Args {
-    /// The ip and port of the zmq source
+    /// TOML or YAML file of settings shared with the rest of the pipeline, plus the
+    /// exporter's own flags under [exporter]. Flags given on the command line take precedence.
     #[arg(long)]
-    pub source: String,
-    /// The prometheus port
+    pub config: Option<PathBuf>,
+    /// A zmq source: its ip and port, or a full endpoint such as ipc:///run/karman/feed for a
+    /// publisher on the same host. Repeatable or comma-separated, for one exporter per site;
+    /// each source is read by its own listener.
+    #[arg(long, required = true, value_delimiter = ',', value_parser = parse_source)]
+    pub source: Vec<String>,
+    /// The port serving /metrics, /healthz and /readyz
     #[arg(long)]
     pub prometheus_port: u16,
-    // The topic we're subscribing to
+    #[command(flatten)]
+    pub health: HealthArgs,
+    #[command(flatten)]
+    pub log: LogArgs,
+    /// A topic subscribed to on every --source. Repeatable or comma-separated; each source and
+    /// topic is read by its own listener.
+    #[arg(long, required = true, value_delimiter = ',')]
+    pub zmq_subscription: Vec<String>,
+    /// Messages read ahead of the metric updates before --zmq-overflow-policy applies (0 for
+    /// no limit), per subscription. Drops are counted in zmq_dropped_messages_total, which
+    /// like the other receive queue metrics is summed over every subscription.
+    #[arg(long, default_value_t = 1000)]
+    pub zmq_rcvhwm: usize,
+    /// Which message to discard when the receive queue is full
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropNewest)]
+    pub zmq_overflow_policy: OverflowPolicy,
+    /// Received frames queued for the metric updates, per subscription; frames arriving while
+    /// it is full are dropped and counted in frame_channel_dropped_total
+    #[arg(
+        long,
+        default_value_t = 1000,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub frame_channel_capacity: usize,
+    #[command(flatten)]
+    pub reader_thread: ReaderThreadArgs,
+    /// Register legacy metric names, unit-suffixed names (`_watts`, `_volts`, ...) or both
+    #[arg(long, value_enum, default_value_t = MetricNaming::Legacy)]
+    pub metric_names: MetricNaming,
+    /// Only serve metrics whose name matches this glob, e.g. rms_voltage_*, or series whose
+    /// stream does, as stream=GLOB. Repeatable; with none given everything is served.
+    /// Measurement gauges left out aren't kept at all; other metrics are kept and only dropped
+    /// from what is served.
+    #[arg(long, value_parser = parse_pattern)]
+    pub metrics_include: Vec<MetricPattern>,
+    /// Don't serve metrics whose name matches this glob, or series whose stream does, as
+    /// stream=GLOB. Repeatable; wins over --metrics-include. Excluded measurement gauges aren't
+    /// kept at all; other metrics are kept and only dropped from what is served.
+    #[arg(long, value_parser = parse_pattern)]
+    pub metrics_exclude: Vec<MetricPattern>,
+    /// Also export a running `_sum` and `_count` of every sample per measurement, so
+    /// recording rules can average over any range instead of the fixed window
+    #[arg(long)]
+    pub sample_counters: bool,
+    /// Export the harmonic magnitudes and THD of streams whose publisher sends harmonic
+    /// content; older publishers don't, so it is off by default
+    #[arg(long)]
+    pub harmonics: bool,
+    /// Highest harmonic exported on its own with --harmonics. THD still counts every one sent.
+    #[arg(long, default_value_t = 25, value_parser = clap::value_parser!(u32).range(2..))]
+    pub max_harmonic_order: u32,
+    /// Value of the `device` label on every metric, also served at /metrics/{device}.
+    /// Defaults to --source; with several sources, each one's series are labelled with it.
+    #[arg(long)]
+    pub device: Option<String>,
+    /// Seconds of samples the peak, trough and average gauges and the completeness ratios
+    /// cover, whatever rate the samples arrive at
+    #[arg(
+        long,
+        default_value_t = completeness::DEFAULT_WINDOW_SECONDS,
+        value_parser = parse_window_seconds
+    )]
+    pub window_seconds: f64,
+    /// Expected message rate for a stream, as STREAM=HZ (e.g. threephase/main=60).
+    /// Drives its completeness gauges. Repeatable.
+    #[arg(long = "expected-rate", value_parser = parse_expected_rate)]
+    pub expected_rates: Vec<(String, f64)>,
+    /// Expected message rate for streams without an --expected-rate entry
+    #[arg(long, default_value_t = 60.0, value_parser = parse_rate)]
+    pub default_expected_rate: f64,
+    /// Completeness ratio below which a stream is flagged as underdelivering
+    #[arg(long, default_value_t = 0.9)]
+    pub underdelivery_threshold: f64,
+    /// A stream's phase counts as stale once no message has carried it for this long, and
+    /// --stale-action is applied to its gauges. seconds_since_last_sample is exported either way.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub stale_after: Option<Duration>,
+    /// What to do with a stale phase's window gauges; its next sample exports them again
+    #[arg(long, value_enum, default_value_t = StaleAction::Keep)]
+    pub stale_action: StaleAction,
+    /// Rewrite stream names into their `stream` label, as REGEX=REPLACEMENT (`$1` for a
+    /// capture group), e.g. `^threephase/=` to drop the prefix. Repeatable; applied in order.
+    #[arg(long = "stream-label-rewrite", value_parser = parse_rewrite)]
+    pub stream_label_rewrites: Vec<Rewrite>,
+    /// After the rewrites, replace each run of characters other than letters, digits and
+    /// `_./:-` in a stream label with `_`
+    #[arg(long)]
+    pub sanitize_stream_labels: bool,
+    /// A stream counts towards active_streams while it has reported within this long
+    #[arg(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    pub active_stream_window: Duration,
+    /// Samples of a phase further apart than this are a gap in its energy counters
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub energy_max_gap: Duration,
+    /// How the energy counters bridge a gap: count nothing for it, hold the power from
+    /// before it, or interpolate across it
+    #[arg(long, value_enum, default_value_t = GapPolicy::Skip)]
+    pub energy_gap_policy: GapPolicy,
+    /// Nominal rms voltage the ANSI C84.1 bands are scaled to
+    #[arg(long, default_value_t = 120.0)]
+    pub nominal_voltage: f64,
+    /// Whether voltage is measured at the service entrance or at the point of use
+    #[arg(long, value_enum, default_value_t = MeasurementPoint::Service)]
+    pub voltage_measurement_point: MeasurementPoint,
+    /// Rolling window for the voltage band gauges (repeatable)
+    #[arg(
+        long = "voltage-band-window",
+        value_parser = humantime::parse_duration,
+        default_values = ["10m", "1h", "24h"]
+    )]
+    pub voltage_band_windows: Vec<Duration>,
+    /// Voltage unbalance (%) that raises a warning; NEMA MG-1 derating starts at 1%
+    #[arg(long, default_value_t = 1.0)]
+    pub voltage_unbalance_warning: f64,
+    /// Voltage unbalance (%) that is critical; NEMA MG-1 advises against running motors above 5%
+    #[arg(long, default_value_t = 5.0)]
+    pub voltage_unbalance_critical: f64,
+    #[arg(long, default_value_t = 10.0)]
+    pub current_unbalance_warning: f64,
+    #[arg(long, default_value_t = 20.0)]
+    pub current_unbalance_critical: f64,
+    /// Per-asset override as STREAM:QUANTITY=WARN,CRIT, QUANTITY being voltage or current
+    /// (e.g. threephase/motor-1:voltage=0.5,2). Repeatable.
+    #[arg(long = "unbalance-threshold", value_parser = parse_threshold)]
+    pub unbalance_thresholds: Vec<(String, Quantity, Thresholds)>,
+    /// Only update a stream's gauges when a value moves by more than this, as STREAM=WIDTH
+    /// with WIDTH absolute (0.5) or relative to the last update (2%). Repeatable.
+    #[arg(long = "deadband", value_parser = parse_deadband)]
+    pub deadbands: Vec<(String, Width)>,
+    /// Deadband for streams without a --deadband entry; off unless set
+    #[arg(long, value_parser = parse_width)]
+    pub default_deadband: Option<Width>,
+    /// Update a deadbanded stream at least this often, even if nothing moved
+    #[arg(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    pub deadband_keepalive: Duration,
+    /// TOML file of threshold alert rules, each firing a webhook and setting alert_active
+    /// while e.g. rms voltage stays under 0.95 pu for 5s. See alerts.rs for the format.
+    #[arg(long)]
+    pub alert_rules: Option<PathBuf>,
+    /// Timestamps count as untrusted when the publisher's clock reports an offset or error
+    /// bound larger than this, or is not locked to its time source
+    #[arg(long, default_value = "1ms", value_parser = humantime::parse_duration)]
+    pub max_clock_offset: Duration,
+    /// JSON array of maintenance windows (`device`, `stream`, `start`, `end`, `reason`) during
+    /// which alerts and data-quality stats pause. Re-read when it changes; more can be added
+    /// with POST /maintenance.
+    #[arg(long)]
+    pub maintenance_windows: Option<PathBuf>,
+    /// data-db's Postgres; when set, the windows are pre-filled from its recent rows at
+    /// startup so peaks, averages and voltage bands are meaningful straight away
+    #[arg(long, env = "BOOTSTRAP_DATABASE_URL", hide_env_values = true)]
+    pub bootstrap_database_url: Option<String>,
+    /// How much history to load; defaults to the longest --voltage-band-window
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub bootstrap_window: Option<Duration>,
+    /// The `device` data-db writes rows and registers streams under
+    #[arg(long, default_value = "bibimbap")]
+    pub bootstrap_device: String,
+    /// Reassemble frames whose streams or phases arrive in separate messages, waiting up to
+    /// this long after the first fragment. Off unless set.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub assembly_window: Option<Duration>,
+    /// What to do with a frame still incomplete when its assembly window ends
+    #[arg(long, value_enum, default_value_t = TimeoutPolicy::EmitPartial)]
+    pub assembly_timeout_policy: TimeoutPolicy,
+    /// A stream every assembled frame should have (repeatable); defaults to every stream seen
+    #[arg(long = "assembly-stream")]
+    pub assembly_streams: Vec<String>,
+    /// What to do with a stream's calculations when their provenance timestamp is older than
+    /// one already seen for it. Accepted ones only go into the --sample-counters totals and
+    /// completeness; either way they're counted in late_calculations_total.
+    #[arg(long, value_enum, default_value_t = LatePolicy::Correct)]
+    pub late_data_policy: LatePolicy,
+    /// With --late-data-policy correct: how late calculations may be and still be accepted
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    pub lateness_window: Duration,
+    /// Export a virtual stream with the real, reactive and apparent power of the
+    /// --site-total-member streams summed per phase (of every stream when none are given)
+    #[arg(long)]
+    pub site_total: bool,
+    #[arg(long, default_value = "site/total")]
+    pub site_total_stream: String,
+    /// A feeder stream summed into the site total (repeatable)
+    #[arg(long = "site-total-member")]
+    pub site_total_members: Vec<String>,
+    /// What to do with the site total of a frame missing a --site-total-member. Missing
+    /// members are counted in site_total_missing_members_total either way.
+    #[arg(long, value_enum, default_value_t = MissingMembers::Partial)]
+    pub site_total_missing: MissingMembers,
+    /// API keys (`name:role:key` per line) required to scrape; metrics are open without one
+    #[arg(long)]
+    pub api_keys_file: Option<PathBuf>,
+    /// HS256 secret for JWT bearer tokens, as an alternative to API keys
+    #[arg(long, env = "JWT_SECRET", hide_env_values = true)]
+    pub jwt_secret: Option<String>,
+    /// Customer site this exporter serves: added as a `tenant` label on every series, only
+    /// credentials for it (or unscoped ones) may scrape, and the subscription must start
+    /// with `<tenant>/` (the default when empty)
+    #[arg(long, env = "TENANT")]
+    pub tenant: Option<String>,
+    /// Postgres holding the `streams` registry (normally data-db's); every new stream is
+    /// recorded there the first time it is seen
+    #[arg(long, env = "STREAM_REGISTRY_DATABASE_URL", hide_env_values = true)]
+    pub stream_registry_database_url: Option<String>,
+    /// POST a JSON description of every new stream to this http:// URL. With a registry
+    /// database, only for streams data-db didn't register first.
+    #[arg(long)]
+    pub stream_webhook: Option<String>,
+    /// Also push every metric to this http:// Prometheus remote_write endpoint, e.g.
+    /// http://prometheus:9090/api/v1/write, for sites that can't be scraped
     #[arg(long)]
-    pub zmq_subscription: String,
+    pub remote_write_url: Option<String>,
+    #[arg(long, default_value = "15s", value_parser = humantime::parse_duration)]
+    pub remote_write_interval: Duration,
+    /// Basic auth user for --remote-write-url
+    #[arg(long)]
+    pub remote_write_username: Option<String>,
+    #[arg(long, env = "REMOTE_WRITE_PASSWORD", hide_env_values = true)]
+    pub remote_write_password: Option<String>,
+    /// Retries for a push that failed on the network, with a 5xx or with a 429, as long as
+    /// the next push isn't due
+    #[arg(long, default_value_t = 3)]
+    pub remote_write_max_retries: u32,
+    /// Wait before the first retry, doubled on every one
+    #[arg(long, default_value = "500ms", value_parser = humantime::parse_duration)]
+    pub remote_write_retry_backoff: Duration,
+    /// Don't serve /metrics and /metrics/{device}, only push to --remote-write-url
+    #[arg(long, requires = "remote_write_url")]
+    pub no_metrics_endpoint: bool,
+}
+
+/// One topic on one source, read by its own listener.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subscription {
+    pub source: String,
+    pub topic: String,
+    /// Value of the `device` label on the series it exports
+    pub device: String,
 }
 
-async fn metrics_handler() -> (StatusCode, HeaderMap, String) {
-    let encoder = TextEncoder::new();
-    let metric_families = prometheus::gather();
-    let mut buffer = vec![];
-    
-    if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
-        log::error!("Failed to encode metrics: {:?}", e);
-        return (StatusCode::INTERNAL_SERVER_ERROR, HeaderMap::new(), String::new());
+impl Args {
+    /// Every topic on every source.
+    pub fn subscriptions(&self) -> anyhow::Result<Vec<Subscription>> {
+        if self.device.is_some() && self.source.len() > 1 {
+            anyhow::bail!(
+                "--device names a single --source; with several, each is labelled with its own"
+            );
+        }
+        Ok(self
+            .source
+            .iter()
+            .flat_map(|source| {
+                self.zmq_subscription.iter().map(move |topic| Subscription {
+                    source: source.clone(),
+                    topic: topic.clone(),
+                    device: self.device.clone().unwrap_or_else(|| source.clone()),
+                })
+            })
+            .collect())
     }
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Content-Type",
-        "text/plain; version=0.0.4; charset=utf-8"
-            .parse()
-            .unwrap(),
-    );
-
-    let body = match String::from_utf8(buffer) {
-        Ok(s) => s,
-        Err(e) => {
-            log::error!("Failed to convert metrics to UTF8: {:?}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, HeaderMap::new(), String::new());
+    pub fn unbalance_thresholds(&self) -> UnbalanceThresholds {
+        UnbalanceThresholds {
+            voltage: Thresholds {
+                warning: self.voltage_unbalance_warning,
+                critical: self.voltage_unbalance_critical,
+            },
+            current: Thresholds {
+                warning: self.current_unbalance_warning,
+                critical: self.current_unbalance_critical,
+            },
+            per_stream: self
+                .unbalance_thresholds
+                .iter()
+                .map(|(stream, quantity, limits)| ((stream.clone(), *quantity), *limits))
+                .collect(),
+        }
+    }
+
+    /// The highest harmonic order to export, if harmonics are exported at all.
+    pub fn harmonics(&self) -> Option<u32> {
+        self.harmonics.then_some(self.max_harmonic_order)
+    }
+
+    pub fn staleness(&self) -> StalenessConfig {
+        StalenessConfig {
+            after: self.stale_after,
+            action: self.stale_action,
+        }
+    }
+
+    pub fn stream_labels(&self) -> LabelConfig {
+        LabelConfig {
+            rewrites: self.stream_label_rewrites.clone(),
+            sanitize: self.sanitize_stream_labels,
+            active_window: self.active_stream_window,
+        }
+    }
+
+    pub fn energy(&self) -> EnergyConfig {
+        EnergyConfig {
+            max_gap: self.energy_max_gap,
+            gap_policy: self.energy_gap_policy,
+        }
+    }
+
+    pub fn deadband(&self) -> DeadbandConfig {
+        DeadbandConfig {
+            default: self.default_deadband,
+            per_stream: self.deadbands.iter().cloned().collect(),
+            keepalive: self.deadband_keepalive,
+        }
+    }
+
+    pub fn bootstrap(&self) -> Option<BootstrapConfig> {
+        let window = self.bootstrap_window.unwrap_or_else(|| {
+            let longest = self.voltage_band_windows.iter().max().copied();
+            longest
+                .unwrap_or_default()
+                .max(self.window())
+        });
+        Some(BootstrapConfig {
+            database_url: self.bootstrap_database_url.clone()?,
+            device: self.bootstrap_device.clone(),
+            tenant: self.tenant.clone(),
+            window,
+        })
+    }
+
+    pub fn remote_write(&self) -> Option<RemoteWriteConfig> {
+        Some(RemoteWriteConfig {
+            url: self.remote_write_url.clone()?,
+            interval: self.remote_write_interval,
+            basic_auth: self
+                .remote_write_username
+                .clone()
+                .map(|user| (user, self.remote_write_password.clone())),
+            max_retries: self.remote_write_max_retries,
+            retry_backoff: self.remote_write_retry_backoff,
+        })
+    }
+
+    pub fn stream_registry(&self) -> RegistrySettings {
+        RegistrySettings {
+            database_url: self.stream_registry_database_url.clone(),
+            webhook_url: self.stream_webhook.clone(),
+            device: self.bootstrap_device.clone(),
+            tenant: self.tenant.clone(),
+        }
+    }
+
+    pub fn hwm(&self) -> HwmConfig {
+        HwmConfig {
+            hwm: self.zmq_rcvhwm,
+            on_full: self.zmq_overflow_policy,
+        }
+    }
+
+    pub fn assembly(&self) -> Option<AssemblyConfig> {
+        Some(AssemblyConfig {
+            window: self.assembly_window?,
+            on_timeout: self.assembly_timeout_policy,
+            expected_streams: self.assembly_streams.iter().cloned().collect(),
+        })
+    }
+
+    pub fn lateness(&self) -> LatenessConfig {
+        LatenessConfig {
+            policy: self.late_data_policy,
+            window: self.lateness_window,
+        }
+    }
+
+    pub fn site_total(&self) -> Option<SiteTotalConfig> {
+        self.site_total.then(|| SiteTotalConfig {
+            stream: self.site_total_stream.clone(),
+            members: self.site_total_members.iter().cloned().collect(),
+            on_missing: self.site_total_missing,
+        })
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs_f64(self.window_seconds)
+    }
+
+    pub fn expected_rates(&self) -> ExpectedRates {
+        ExpectedRates {
+            default_hz: self.default_expected_rate,
+            per_stream: self.expected_rates.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Set once at startup from --tenant.
+static TENANT: OnceLock<String> = OnceLock::new();
+
+/// A tenant scraping through a shared Prometheus can then only be matched on its own series.
+fn with_tenant_label(mut metric_families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+    let Some(tenant) = TENANT.get() else {
+        return metric_families;
+    };
+    for family in &mut metric_families {
+        for metric in family.mut_metric().iter_mut() {
+            let mut label = LabelPair::default();
+            label.set_name("tenant".to_string());
+            label.set_value(tenant.clone());
+            metric.mut_label().push(label);
+        }
+    }
+    metric_families
+}
+
+/// OpenMetrics, exemplars included, for scrapers that prefer it; the classic text otherwise
+fn requested_format(headers: &HeaderMap) -> Format {
+    Format::negotiate(
+        headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok()),
+    )
+}
+
+async fn metrics_handler(headers: HeaderMap) -> (StatusCode, HeaderMap, String) {
+    encode_metrics(prometheus::gather(), requested_format(&headers))
+}
+
+/// Only the series labelled with this device, so each device can be scraped on its own interval
+async fn device_metrics_handler(
+    Path(device): Path<String>,
+    headers: HeaderMap,
+) -> (StatusCode, HeaderMap, String) {
+    let metric_families: Vec<MetricFamily> = prometheus::gather()
+        .into_iter()
+        .filter_map(|mut family| {
+            let metrics: Vec<_> = family
+                .take_metric()
+                .into_iter()
+                .filter(|metric| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .any(|label| label.get_name() == "device" && label.get_value() == device)
+                })
+                .collect();
+            if metrics.is_empty() {
+                return None;
+            }
+            family.set_metric(metrics.into());
+            Some(family)
+        })
+        .collect();
+
+    if metric_families.is_empty() {
+        return (StatusCode::NOT_FOUND, HeaderMap::new(), format!("Unknown device: {device}\n"));
+    }
+
+    encode_metrics(metric_families, requested_format(&headers))
+}
+
+/// What is exported of `metric_families`, on /metrics and to --remote-write-url alike.
+fn exported_metrics(metric_families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+    with_tenant_label(metric_filter().apply(metric_families))
+}
+
+fn encode_metrics(
+    metric_families: Vec<MetricFamily>,
+    format: Format,
+) -> (StatusCode, HeaderMap, String) {
+    let metric_families = exported_metrics(metric_families);
+
+    let (body, content_type) = match format {
+        Format::OpenMetrics => (openmetrics::encode(&metric_families), OPENMETRICS_CONTENT_TYPE),
+        Format::Text => {
+            let mut buffer = vec![];
+            if let Err(e) = TextEncoder::new().encode(&metric_families, &mut buffer) {
+                log::error!("Failed to encode metrics: {:?}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, HeaderMap::new(), String::new());
+            }
+            match String::from_utf8(buffer) {
+                Ok(s) => (s, TEXT_CONTENT_TYPE),
+                Err(e) => {
+                    log::error!("Failed to convert metrics to UTF8: {:?}", e);
+                    return (StatusCode::INTERNAL_SERVER_ERROR, HeaderMap::new(), String::new());
+                }
+            }
         }
     };
 
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+
     log::debug!("Serving {} bytes of metrics", body.len());
     (StatusCode::OK, headers, body)
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-    let args = Args::parse();
+    let mut args: Args = service_config::parse("exporter", SHARED_SETTINGS);
+    logging::init(&args.log, "error");
+    let shutdown = Shutdown::install().expect("Could not install signal handlers");
+    set_metric_naming(args.metric_names);
+    set_metric_filter(MetricFilter {
+        include: args.metrics_include.clone(),
+        exclude: args.metrics_exclude.clone(),
+    });
+    args.zmq_subscription = match args
+        .zmq_subscription
+        .iter()
+        .map(|topic| tenant_topic(args.tenant.as_deref(), topic))
+        .collect::<anyhow::Result<_>>()
+    {
+        Ok(topics) => topics,
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(2);
+        }
+    };
+    let subscriptions = match args.subscriptions() {
+        Ok(subscriptions) => subscriptions,
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(2);
+        }
+    };
+    if let Some(tenant) = &args.tenant {
+        TENANT.set(tenant.clone()).ok();
+    }
     let prom_binding_addr = format!("0.0.0.0:{}", args.prometheus_port);
 
+    let auth = Auth::load(
+        "data-exporter",
+        &AuthSettings {
+            api_keys_file: args.api_keys_file.clone(),
+            jwt_secret: args.jwt_secret.clone(),
+            audit_database_url: None,
+            tenant: args.tenant.clone(),
+        },
+    )
+    .await
+    .expect("Could not load API keys");
+
+    let maintenance = Maintenance::load(args.maintenance_windows.clone())
+        .expect("Could not load maintenance windows");
+    tokio::spawn(maintenance.clone().watch(Duration::from_secs(10)));
+    let (alerts, notifier) = Alerts::load(args.alert_rules.as_deref(), args.nominal_voltage)
+        .expect("Could not load alert rules");
+
+    let registry = Registry::start("data-exporter", &args.stream_registry())
+        .await
+        .expect("Could not start the stream registry");
+
+    let streams = Streams::default();
+    let labels = StreamLabels::new(args.stream_labels());
+
+    // Every listener task borrows them for as long as the process runs
+    let gauges: &'static Gauges = Box::leak(Box::new(
+        Gauges::register(prometheus::default_registry()).expect("Unable to register gauges"),
+    ));
+    set_exemplars(&gauges.latency_exemplars);
+    // Nothing is registered after startup, so the catalog is built once
+    let description = describe::describe(gauges, args.sample_counters, args.harmonics);
+
+    // Stored rows don't say which publisher they came from, so they can't be split between
+    // several listeners
+    let bootstrap = match args.bootstrap() {
+        Some(_) if subscriptions.len() > 1 => {
+            log::warn!("Not bootstrapping: stored rows can't be told apart between subscriptions");
+            None
+        }
+        bootstrap => bootstrap,
+    };
+
+    let health = Health::new(args.health);
+    let sources: Vec<_> = subscriptions
+        .iter()
+        .map(|subscription| {
+            health.source(format!("{}/{}", subscription.source, subscription.topic))
+        })
+        .collect();
+
+    if let Some(config) = args.remote_write() {
+        tokio::spawn(remote_write::run(config, &gauges.remote_write, shutdown.clone()));
+    }
+    if let Some(notifier) = notifier {
+        tokio::spawn(notifier.run(&gauges.alerts, shutdown.clone()));
+    }
+    let scrape = if args.no_metrics_endpoint {
+        Router::new()
+    } else {
+        Router::new()
+            .route("/metrics", get(metrics_handler))
+            .route("/metrics/:device", get(device_metrics_handler))
+    };
+
     // Start metrics server
-    let app = Router::new().route("/metrics", get(metrics_handler));
+    let app = auth.protect(
+        scrape
+            .route("/schema", get(move || async move { Json(description) }))
+            .route(
+                "/maintenance",
+                get(maintenance::list_handler).post(maintenance::add_handler),
+            )
+            .route("/maintenance/:id", delete(maintenance::delete_handler))
+            .with_state(maintenance.clone())
+            .merge(
+                Router::new()
+                    .route("/streams", get(streams::list_handler))
+                    .route("/streams/:name/latest", get(streams::latest_handler))
+                    .with_state(streams.clone()),
+            )
+            .merge(
+                Router::new()
+                    .route("/stream-labels", get(stream_labels::list_handler))
+                    .with_state(labels.clone()),
+            ),
+    )
+    // Probes don't carry credentials
+    .merge(health.router());
     let listener = tokio::net::TcpListener::bind(&prom_binding_addr)
         .await
         .expect("Could not bind prometheus server");
     log::info!("data-exporter: Prometheus metrics server listening on {}", prom_binding_addr);
 
-    tokio::spawn(async move {
+    let server_shutdown = shutdown.requested();
+    let server = tokio::spawn(async move {
         axum::serve(listener, app)
+            .with_graceful_shutdown(server_shutdown)
             .await
-            .expect("Metrics server failed");
     });
 
-    loop {
-        if let Err(err) = listen(args.clone()).await {
-            log::error!("Loop exited unexpectedly:{err:#?}, trying again.");
-            tokio::time::sleep(Duration::from_secs(5)).await;
-        }
+    let handles = Handles {
+        maintenance,
+        alerts,
+        registry,
+        streams,
+        labels,
+    };
+    let mut listeners = tokio::task::JoinSet::new();
+    for (subscription, source) in subscriptions.into_iter().zip(sources) {
+        let args = args.clone();
+        let bootstrap = bootstrap.clone();
+        let handles = handles.clone();
+        let shutdown = shutdown.clone();
+        listeners.spawn(async move {
+            loop {
+                let Err(err) = listen(
+                    &args,
+                    &subscription,
+                    bootstrap.as_ref(),
+                    handles.clone(),
+                    gauges,
+                    &source,
+                    &shutdown,
+                )
+                .await
+                else {
+                    break;
+                };
+                source.disconnected();
+                log::error!(
+                    "Loop for {} topic '{}' exited unexpectedly:{err:#?}, trying again.",
+                    subscription.source,
+                    subscription.topic
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                    _ = shutdown.requested() => break,
+                }
+            }
+        });
     }
+    while let Some(listener) = listeners.join_next().await {
+        listener.expect("Listener panicked");
+    }
+
+    // Lets a scrape in progress finish
+    server
+        .await
+        .expect("Metrics server panicked")
+        .expect("Metrics server failed");
+    log::info!("Shut down cleanly");
 }
@@ -0,0 +1,266 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, Opts};
+use serde::{Deserialize, Serialize};
+
+/// Which streams are in maintenance.
+pub struct Gauges {
+    pub active: GaugeVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let active = GaugeVec::new(
+            Opts::new(
+                "maintenance_active",
+                "1 while a maintenance window covers the stream; alerts and data-quality stats pause",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(active.clone()))?;
+        Ok(Self { active })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 1] {
+        [&self.active]
+    }
+}
+
+/// A planned outage or switching operation. Leaving `device` or `stream` out covers all of them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Window {
+    pub device: Option<String>,
+    pub stream: Option<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl Window {
+    fn validate(&self) -> Result<()> {
+        if self.end <= self.start {
+            return Err(anyhow!(
+                "window ends ({}) before it starts ({})",
+                self.end,
+                self.start
+            ));
+        }
+        Ok(())
+    }
+
+    fn covers(&self, device: &str, stream: &str, now: DateTime<Utc>) -> bool {
+        self.start <= now
+            && now < self.end
+            && self.device.as_deref().is_none_or(|d| d == device)
+            && self.stream.as_deref().is_none_or(|s| s == stream)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    /// From --maintenance-windows; replaced whenever the file changes
+    Config,
+    /// Added through the API; kept in memory until it ends or is deleted
+    Api,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    pub id: u64,
+    pub origin: Origin,
+    pub active: bool,
+    #[serde(flatten)]
+    pub window: Window,
+}
+
+#[derive(Default)]
+struct Windows {
+    file: Option<PathBuf>,
+    file_modified: Option<SystemTime>,
+    configured: Vec<(u64, Window)>,
+    scheduled: Vec<(u64, Window)>,
+    next_id: u64,
+}
+
+impl Windows {
+    fn assign_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+/// Maintenance windows shared by the listener and the HTTP API.
+#[derive(Clone, Default)]
+pub struct Maintenance {
+    state: Arc<RwLock<Windows>>,
+}
+
+fn read_file(path: &PathBuf) -> Result<Vec<Window>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Could not read maintenance windows {}", path.display()))?;
+    let windows: Vec<Window> = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid maintenance windows in {}", path.display()))?;
+    for window in &windows {
+        window
+            .validate()
+            .with_context(|| format!("Invalid maintenance window in {}", path.display()))?;
+    }
+    Ok(windows)
+}
+
+impl Maintenance {
+    /// Loads the windows in `file` (a JSON array of windows), if given.
+    pub fn load(file: Option<PathBuf>) -> Result<Self> {
+        let maintenance = Self::default();
+        maintenance.state.write().unwrap().file = file;
+        maintenance.reload_if_changed()?;
+        Ok(maintenance)
+    }
+
+    /// Re-reads the windows file when its modification time changes, e.g. after a ConfigMap
+    /// update. On error the previous windows stay in place.
+    pub fn reload_if_changed(&self) -> Result<()> {
+        let Some(path) = self.state.read().unwrap().file.clone() else {
+            return Ok(());
+        };
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Could not stat maintenance windows {}", path.display()))?;
+        if self.state.read().unwrap().file_modified == Some(modified) {
+            return Ok(());
+        }
+
+        let windows = read_file(&path)?;
+        let mut state = self.state.write().unwrap();
+        let configured = windows
+            .into_iter()
+            .map(|window| (state.assign_id(), window))
+            .collect::<Vec<_>>();
+        log::info!(
+            "Loaded {} maintenance windows from {}",
+            configured.len(),
+            path.display()
+        );
+        state.configured = configured;
+        state.file_modified = Some(modified);
+        Ok(())
+    }
+
+    /// Keeps the windows file in sync; errors are logged and retried on the next tick.
+    pub async fn watch(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(err) = self.reload_if_changed() {
+                log::error!("{err:#}");
+            }
+        }
+    }
+
+    pub fn is_active(&self, device: &str, stream: &str) -> bool {
+        let now = Utc::now();
+        let state = self.state.read().unwrap();
+        state
+            .configured
+            .iter()
+            .chain(&state.scheduled)
+            .any(|(_, window)| window.covers(device, stream, now))
+    }
+
+    /// Like `is_active`, and exports the answer as `maintenance_active`.
+    pub fn check(&self, gauges: &Gauges, device: &str, stream: &str) -> bool {
+        let active = self.is_active(device, stream);
+        gauges
+            .active
+            .with_label_values(&[device, stream])
+            .set(if active { 1.0 } else { 0.0 });
+        active
+    }
+
+    fn entries(&self) -> Vec<Entry> {
+        let now = Utc::now();
+        let mut state = self.state.write().unwrap();
+        state.scheduled.retain(|(_, window)| window.end > now);
+
+        let entry = |origin: Origin, (id, window): &(u64, Window)| Entry {
+            id: *id,
+            origin,
+            active: window.start <= now && now < window.end,
+            window: window.clone(),
+        };
+        state
+            .configured
+            .iter()
+            .map(|w| entry(Origin::Config, w))
+            .chain(state.scheduled.iter().map(|w| entry(Origin::Api, w)))
+            .collect()
+    }
+
+    fn schedule(&self, window: Window) -> Result<Entry> {
+        window.validate()?;
+        let mut state = self.state.write().unwrap();
+        let id = state.assign_id();
+        state.scheduled.push((id, window.clone()));
+        log::info!("Scheduled maintenance window {id}: {window:?}");
+        let now = Utc::now();
+        Ok(Entry {
+            id,
+            origin: Origin::Api,
+            active: window.start <= now && now < window.end,
+            window,
+        })
+    }
+
+    fn cancel(&self, id: u64) -> bool {
+        let mut state = self.state.write().unwrap();
+        let before = state.scheduled.len();
+        state.scheduled.retain(|(existing, _)| *existing != id);
+        state.scheduled.len() != before
+    }
+}
+
+/// `GET /maintenance`: every window from the file, and those from the API that haven't ended.
+pub async fn list_handler(State(maintenance): State<Maintenance>) -> Json<Vec<Entry>> {
+    Json(maintenance.entries())
+}
+
+/// `POST /maintenance`: schedules a window until it ends or the exporter restarts.
+pub async fn add_handler(
+    State(maintenance): State<Maintenance>,
+    Json(window): Json<Window>,
+) -> Result<(StatusCode, Json<Entry>), (StatusCode, String)> {
+    maintenance
+        .schedule(window)
+        .map(|entry| (StatusCode::CREATED, Json(entry)))
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}\n")))
+}
+
+/// `DELETE /maintenance/{id}`: cancels a window added through the API. Windows from the file
+/// can only be removed by editing it.
+pub async fn delete_handler(
+    State(maintenance): State<Maintenance>,
+    Path(id): Path<u64>,
+) -> StatusCode {
+    if maintenance.cancel(id) {
+        log::info!("Cancelled maintenance window {id}");
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
@@ -0,0 +1,201 @@
+//! --metrics-include and --metrics-exclude: which series are served, so a deployment that only
+//! graphs a few gauges doesn't have Prometheus ingest the rest. A pattern is a glob (`*` for
+//! any run of characters, `?` for one) on metric names, e.g. `rms_voltage_*`, or on the stream
+//! label with a `stream=` prefix, e.g. `stream=feeder-*`.
+//!
+//! A series is served when its name matches an include pattern, if any are given, and its
+//! stream an include stream pattern, if any are given, and neither matches an exclude pattern.
+//! Series without a stream label are only filtered by name.
+//!
+//! The per-stream measurement gauges (each quantity's window and distribution, and the stream
+//! totals) are never registered under a name that is left out, nor given series for a stream
+//! that is, so what's filtered out of them costs nothing. Every other metric is still kept and
+//! only filtered from what /metrics, /metrics/{device} and the /schema catalog serve.
+
+use std::sync::OnceLock;
+
+use prometheus::proto::MetricFamily;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetricPattern {
+    Name(String),
+    Stream(String),
+}
+
+impl MetricPattern {
+    fn name(&self) -> Option<&str> {
+        match self {
+            MetricPattern::Name(glob) => Some(glob),
+            MetricPattern::Stream(_) => None,
+        }
+    }
+
+    fn stream(&self) -> Option<&str> {
+        match self {
+            MetricPattern::Stream(glob) => Some(glob),
+            MetricPattern::Name(_) => None,
+        }
+    }
+}
+
+/// Parses a --metrics-include or --metrics-exclude pattern.
+pub fn parse_pattern(value: &str) -> Result<MetricPattern, String> {
+    let pattern = match value.strip_prefix("stream=") {
+        Some(stream) => MetricPattern::Stream(stream.to_string()),
+        None => MetricPattern::Name(value.to_string()),
+    };
+    match &pattern {
+        MetricPattern::Name(glob) | MetricPattern::Stream(glob) if glob.is_empty() => {
+            Err(format!("empty pattern '{value}'"))
+        }
+        _ => Ok(pattern),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MetricFilter {
+    pub include: Vec<MetricPattern>,
+    pub exclude: Vec<MetricPattern>,
+}
+
+static METRIC_FILTER: OnceLock<MetricFilter> = OnceLock::new();
+
+/// Must be called before the gauges are registered; later calls are ignored.
+pub fn set_metric_filter(filter: MetricFilter) {
+    if METRIC_FILTER.set(filter).is_err() {
+        log::warn!("Metric filter already initialised, ignoring it");
+    }
+}
+
+pub fn metric_filter() -> &'static MetricFilter {
+    METRIC_FILTER.get_or_init(MetricFilter::default)
+}
+
+impl MetricFilter {
+    /// Whether any series of the metric called `name` can be served.
+    pub fn serves_name(&self, name: &str) -> bool {
+        admits(
+            self.include.iter().filter_map(MetricPattern::name),
+            self.exclude.iter().filter_map(MetricPattern::name),
+            name,
+        )
+    }
+
+    /// Whether the series of the stream called `stream` can be served.
+    pub fn serves_stream(&self, stream: &str) -> bool {
+        admits(
+            self.include.iter().filter_map(MetricPattern::stream),
+            self.exclude.iter().filter_map(MetricPattern::stream),
+            stream,
+        )
+    }
+
+    /// The families and series to serve; families left without series are dropped.
+    pub fn apply(&self, metric_families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return metric_families;
+        }
+        metric_families
+            .into_iter()
+            .filter(|family| self.serves_name(family.get_name()))
+            .filter_map(|mut family| {
+                let metrics: Vec<_> = family
+                    .take_metric()
+                    .into_iter()
+                    .filter(|metric| {
+                        metric
+                            .get_label()
+                            .iter()
+                            .find(|label| label.get_name() == "stream")
+                            .is_none_or(|label| self.serves_stream(label.get_value()))
+                    })
+                    .collect();
+                if metrics.is_empty() {
+                    return None;
+                }
+                family.set_metric(metrics.into());
+                Some(family)
+            })
+            .collect()
+    }
+}
+
+/// Whether `value` matches one of `include`, or there are none, and none of `exclude`.
+fn admits<'a>(
+    include: impl Iterator<Item = &'a str>,
+    mut exclude: impl Iterator<Item = &'a str>,
+    value: &str,
+) -> bool {
+    let mut include = include.peekable();
+    (include.peek().is_none() || include.any(|glob| glob_match(glob, value)))
+        && !exclude.any(|glob| glob_match(glob, value))
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters and `?` any one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has taken so far
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` take one more character and try again from there
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_any_run_or_one_character() {
+        assert!(glob_match("rms_voltage_*", "rms_voltage_latest"));
+        assert!(glob_match("*_latest", "rms_voltage_latest"));
+        assert!(glob_match("thd_?oltage*", "thd_voltage_peak"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("rms_voltage_*", "rms_current_latest"));
+        assert!(!glob_match("rms_voltage", "rms_voltage_latest"));
+    }
+
+    #[test]
+    fn includes_narrow_and_excludes_win() {
+        let filter = MetricFilter {
+            include: vec![
+                parse_pattern("rms_*").unwrap(),
+                parse_pattern("stream=feeder-*").unwrap(),
+            ],
+            exclude: vec![
+                parse_pattern("*_distribution*").unwrap(),
+                parse_pattern("stream=feeder-9").unwrap(),
+            ],
+        };
+        assert!(filter.serves_name("rms_voltage_latest"));
+        assert!(!filter.serves_name("real_power_latest"));
+        assert!(!filter.serves_name("rms_voltage_distribution_p99"));
+        assert!(filter.serves_stream("feeder-1"));
+        assert!(!filter.serves_stream("feeder-9"));
+        assert!(!filter.serves_stream("site/total"));
+
+        assert!(MetricFilter::default().serves_name("anything"));
+        assert!(parse_pattern("stream=").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use prometheus::core::Collector;
+use prometheus::{Gauge, GaugeVec, Opts, Registry};
+
+use crate::metric_filter::{metric_filter, MetricFilter};
+
+/// Which metric names get registered. `both` exists for the transition period
+/// so dashboards can move to the unit-suffixed names before the legacy ones go away.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MetricNaming {
+    /// Current names, e.g. `rms_voltage_latest`
+    #[default]
+    Legacy,
+    /// Prometheus convention names with a unit suffix, e.g. `rms_voltage_latest_volts`
+    Suffixed,
+    /// Register both sets and keep them in sync
+    Both,
+}
+
+static METRIC_NAMING: OnceLock<MetricNaming> = OnceLock::new();
+
+/// Must be called before the first gauge is touched; later calls are ignored.
+pub fn set_metric_naming(naming: MetricNaming) {
+    if METRIC_NAMING.set(naming).is_err() {
+        log::warn!("Metric naming already initialised, ignoring {:?}", naming);
+    }
+}
+
+pub fn metric_naming() -> MetricNaming {
+    *METRIC_NAMING.get_or_init(MetricNaming::default)
+}
+
+/// A gauge vec registered under its legacy name, its unit-suffixed name, or both. Names that
+/// --metrics-include and --metrics-exclude leave out aren't registered at all, and no series
+/// is created for a stream they leave out.
+pub struct UnitGaugeVec {
+    legacy: Option<GaugeVec>,
+    suffixed: Option<GaugeVec>,
+    filter: &'static MetricFilter,
+    /// Position of the stream label, if it has one
+    stream: Option<usize>,
+}
+
+impl UnitGaugeVec {
+    pub fn register(
+        registry: &Registry,
+        name: &str,
+        unit: &str,
+        description: &str,
+        labels: &[&str],
+    ) -> prometheus::Result<Self> {
+        Self::register_filtered(registry, metric_filter(), name, unit, description, labels)
+    }
+
+    fn register_filtered(
+        registry: &Registry,
+        filter: &'static MetricFilter,
+        name: &str,
+        unit: &str,
+        description: &str,
+        labels: &[&str],
+    ) -> prometheus::Result<Self> {
+        let naming = metric_naming();
+        let register = |name: &str| register_served(registry, filter, name, description, labels);
+        Ok(Self {
+            legacy: match naming {
+                MetricNaming::Suffixed => None,
+                _ => register(name)?,
+            },
+            suffixed: match naming {
+                MetricNaming::Legacy => None,
+                _ => register(&format!("{name}_{unit}"))?,
+            },
+            filter,
+            stream: labels.iter().position(|label| *label == "stream"),
+        })
+    }
+
+    /// Registered under `name` whatever --metric-names says, for gauges with their unit in
+    /// their name already.
+    pub fn register_as_is(
+        registry: &Registry,
+        name: &str,
+        description: &str,
+        labels: &[&str],
+    ) -> prometheus::Result<Self> {
+        let filter = metric_filter();
+        Ok(Self {
+            legacy: register_served(registry, filter, name, description, labels)?,
+            suffixed: None,
+            filter,
+            stream: labels.iter().position(|label| *label == "stream"),
+        })
+    }
+
+    /// Whichever of the two names are registered.
+    pub fn collectors(&self) -> impl Iterator<Item = &dyn Collector> {
+        [&self.legacy, &self.suffixed]
+            .into_iter()
+            .flatten()
+            .map(|gauge| gauge as &dyn Collector)
+    }
+
+    /// Stops exporting the series, under whichever names it has.
+    pub fn remove_label_values(&self, labels: &[&str]) {
+        for gauge in [&self.legacy, &self.suffixed].into_iter().flatten() {
+            // Fails only for a series that was never set
+            let _ = gauge.remove_label_values(labels);
+        }
+    }
+
+    /// The series, or one that drops whatever it's set to if its stream isn't served.
+    pub fn with_label_values(&self, labels: &[&str]) -> UnitGauge {
+        let served = self
+            .stream
+            .is_none_or(|stream| self.filter.serves_stream(labels[stream]));
+        UnitGauge {
+            legacy: self
+                .legacy
+                .as_ref()
+                .filter(|_| served)
+                .map(|g| g.with_label_values(labels)),
+            suffixed: self
+                .suffixed
+                .as_ref()
+                .filter(|_| served)
+                .map(|g| g.with_label_values(labels)),
+        }
+    }
+}
+
+/// Registers the gauge vec `name`, unless `filter` leaves it out.
+fn register_served(
+    registry: &Registry,
+    filter: &MetricFilter,
+    name: &str,
+    description: &str,
+    labels: &[&str],
+) -> prometheus::Result<Option<GaugeVec>> {
+    if !filter.serves_name(name) {
+        return Ok(None);
+    }
+    let gauge = GaugeVec::new(Opts::new(name, description), labels)?;
+    registry.register(Box::new(gauge.clone()))?;
+    Ok(Some(gauge))
+}
+
+pub struct UnitGauge {
+    legacy: Option<Gauge>,
+    suffixed: Option<Gauge>,
+}
+
+impl UnitGauge {
+    pub fn set(&self, value: f64) {
+        if let Some(gauge) = &self.legacy {
+            gauge.set(value);
+        }
+        if let Some(gauge) = &self.suffixed {
+            gauge.set(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric_filter::parse_pattern;
+
+    #[test]
+    fn filtered_out_names_and_streams_are_never_registered_or_set() {
+        let registry = Registry::new();
+        let filter = Box::leak(Box::new(MetricFilter {
+            include: vec![],
+            exclude: vec![
+                parse_pattern("*_peak").unwrap(),
+                parse_pattern("stream=feeder-9").unwrap(),
+            ],
+        }));
+        let labels = &["device", "stream", "phase"];
+        let register = |name| {
+            UnitGaugeVec::register_filtered(&registry, filter, name, "volts", "RMS voltage", labels)
+                .unwrap()
+        };
+        let (latest, peak) = (register("rms_voltage_latest"), register("rms_voltage_peak"));
+        assert_eq!(peak.collectors().count(), 0);
+
+        for stream in ["feeder-1", "feeder-9"] {
+            latest.with_label_values(&["dev", stream, "a"]).set(120.0);
+            peak.with_label_values(&["dev", stream, "a"]).set(121.0);
+        }
+        let families = registry.gather();
+        let names: Vec<_> = families.iter().map(|family| family.get_name()).collect();
+        assert_eq!(names, ["rms_voltage_latest"]);
+        let streams: Vec<_> = families[0]
+            .get_metric()
+            .iter()
+            .flat_map(|metric| metric.get_label())
+            .filter(|label| label.get_name() == "stream")
+            .map(|label| label.get_value())
+            .collect();
+        assert_eq!(streams, ["feeder-1"]);
+    }
+}
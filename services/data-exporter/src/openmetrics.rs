@@ -0,0 +1,367 @@
+//! The OpenMetrics exposition format, served by /metrics and /metrics/{device} to scrapers
+//! that ask for it in their Accept header (Prometheus does unless told otherwise); anything
+//! else still gets the classic text format. The prometheus crate only writes the latter, so the
+//! OpenMetrics text is written here from the same metric families.
+//!
+//! OpenMetrics also carries exemplars: message_latency_seconds buckets name the frame that last
+//! landed in them, by its provenance sequence number, stamped with its provenance timestamp,
+//! so a slow bucket in Grafana leads to the frame and its traces.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    OpenMetrics,
+}
+
+impl Format {
+    /// The format an Accept header prefers. OpenMetrics wins ties, plain text is the default.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let Some(accept) = accept else {
+            return Self::Text;
+        };
+        let (mut openmetrics, mut text) = (0.0f32, 0.0f32);
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match media_type.as_str() {
+                "application/openmetrics-text" => openmetrics = openmetrics.max(quality),
+                "text/plain" | "text/*" | "*/*" => text = text.max(quality),
+                _ => {}
+            }
+        }
+        if openmetrics > 0.0 && openmetrics >= text {
+            Self::OpenMetrics
+        } else {
+            Self::Text
+        }
+    }
+}
+
+/// The frame behind an observation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    /// Provenance sequence number, when the publisher sent one
+    pub sequence: Option<u64>,
+    pub value: f64,
+    /// Provenance timestamp
+    pub time: SystemTime,
+}
+
+/// The latest exemplar of each bucket of one histogram, by device.
+pub struct Exemplars {
+    metric: &'static str,
+    /// Upper bounds of the histogram's buckets, without +Inf
+    bounds: Vec<f64>,
+    latest: Mutex<HashMap<String, Vec<Option<Exemplar>>>>,
+}
+
+impl Exemplars {
+    pub fn new(metric: &'static str, bounds: Vec<f64>) -> Self {
+        Self {
+            metric,
+            bounds,
+            latest: Mutex::default(),
+        }
+    }
+
+    /// Keeps `exemplar` as the one of the bucket its value falls in.
+    pub fn record(&self, device: &str, exemplar: Exemplar) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| exemplar.value <= *bound)
+            .unwrap_or(self.bounds.len());
+        let mut latest = self.latest.lock().expect("exemplars poisoned");
+        let buckets = latest
+            .entry(device.to_string())
+            .or_insert_with(|| vec![None; self.bounds.len() + 1]);
+        buckets[bucket] = Some(exemplar);
+    }
+
+    /// The exemplar of a bucket, by its upper bound.
+    fn get(&self, device: &str, upper_bound: f64) -> Option<Exemplar> {
+        let bucket = match self.bounds.iter().position(|bound| *bound == upper_bound) {
+            Some(bucket) => bucket,
+            None if upper_bound == f64::INFINITY => self.bounds.len(),
+            None => return None,
+        };
+        let latest = self.latest.lock().expect("exemplars poisoned");
+        latest.get(device)?.get(bucket)?.clone()
+    }
+}
+
+static EXEMPLARS: OnceLock<&'static Exemplars> = OnceLock::new();
+
+/// The exemplars written out with their histogram. Set once at startup.
+pub fn set_exemplars(exemplars: &'static Exemplars) {
+    EXEMPLARS.set(exemplars).ok();
+}
+
+/// The families in the OpenMetrics text format, with the exemplars of `set_exemplars`.
+pub fn encode(metric_families: &[MetricFamily]) -> String {
+    encode_with(metric_families, EXEMPLARS.get().copied())
+}
+
+fn encode_with(metric_families: &[MetricFamily], exemplars: Option<&Exemplars>) -> String {
+    let mut out = String::new();
+    for family in metric_families {
+        let name = family.get_name();
+        let exemplars = exemplars.filter(|exemplars| exemplars.metric == name);
+        // OpenMetrics names counters without their _total, which only samples carry; one
+        // registered without it can only be served as unknown
+        let (family_name, kind) = match family.get_field_type() {
+            MetricType::COUNTER => match name.strip_suffix("_total") {
+                Some(stripped) => (stripped, "counter"),
+                None => (name, "unknown"),
+            },
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(out, "# TYPE {family_name} {kind}");
+        let _ = writeln!(out, "# HELP {family_name} {}", escape(family.get_help()));
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            let device = labels
+                .iter()
+                .find(|label| label.get_name() == "device")
+                .map(|label| label.get_value());
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    sample(&mut out, name, metric, metric.get_counter().get_value())
+                }
+                MetricType::GAUGE => sample(&mut out, name, metric, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => {
+                    sample(&mut out, name, metric, metric.get_untyped().get_value())
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut buckets: Vec<(f64, u64)> = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                        .collect();
+                    // The prometheus crate leaves out the +Inf bucket, which OpenMetrics requires
+                    if buckets
+                        .last()
+                        .is_none_or(|(bound, _)| *bound != f64::INFINITY)
+                    {
+                        buckets.push((f64::INFINITY, histogram.get_sample_count()));
+                    }
+                    let bucket_name = format!("{name}_bucket");
+                    for (bound, count) in buckets {
+                        let le = ("le", number(bound));
+                        line(&mut out, &bucket_name, metric, Some(le), &count.to_string());
+                        let exemplar = exemplars
+                            .zip(device)
+                            .and_then(|(exemplars, device)| exemplars.get(device, bound));
+                        if let Some(exemplar) = exemplar {
+                            with_exemplar(&mut out, &exemplar);
+                        }
+                        out.push('\n');
+                    }
+                    count_and_sum(
+                        &mut out,
+                        name,
+                        metric,
+                        histogram.get_sample_count(),
+                        histogram.get_sample_sum(),
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let label = ("quantile", number(quantile.get_quantile()));
+                        line(
+                            &mut out,
+                            name,
+                            metric,
+                            Some(label),
+                            &number(quantile.get_value()),
+                        );
+                        out.push('\n');
+                    }
+                    count_and_sum(
+                        &mut out,
+                        name,
+                        metric,
+                        summary.get_sample_count(),
+                        summary.get_sample_sum(),
+                    );
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn sample(out: &mut String, name: &str, metric: &Metric, value: f64) {
+    line(out, name, metric, None, &number(value));
+    out.push('\n');
+}
+
+fn count_and_sum(out: &mut String, name: &str, metric: &Metric, count: u64, sum: f64) {
+    line(
+        out,
+        &format!("{name}_count"),
+        metric,
+        None,
+        &count.to_string(),
+    );
+    out.push('\n');
+    sample(out, &format!("{name}_sum"), metric, sum);
+}
+
+/// A sample without its line end, so an exemplar can follow.
+fn line(out: &mut String, name: &str, metric: &Metric, extra: Option<(&str, String)>, value: &str) {
+    out.push_str(name);
+    let labels: Vec<(&str, &str)> = metric
+        .get_label()
+        .iter()
+        .map(|label| (label.get_name(), label.get_value()))
+        .chain(extra.as_ref().map(|(name, value)| (*name, value.as_str())))
+        .collect();
+    write_labels(out, &labels);
+    let _ = write!(out, " {value}");
+}
+
+fn with_exemplar(out: &mut String, exemplar: &Exemplar) {
+    let sequence = exemplar.sequence.map(|sequence| sequence.to_string());
+    let labels: Vec<(&str, &str)> = sequence
+        .as_deref()
+        .map(|sequence| ("sequence", sequence))
+        .into_iter()
+        .collect();
+    out.push_str(" #");
+    if labels.is_empty() {
+        out.push_str(" {}");
+    } else {
+        out.push(' ');
+        write_labels(out, &labels);
+    }
+    let _ = write!(out, " {}", number(exemplar.value));
+    if let Ok(since_epoch) = exemplar.time.duration_since(UNIX_EPOCH) {
+        let _ = write!(out, " {}", since_epoch.as_secs_f64());
+    }
+}
+
+fn write_labels(out: &mut String, labels: &[(&str, &str)]) {
+    if labels.is_empty() {
+        return;
+    }
+    out.push('{');
+    for (i, (name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{name}=\"{}\"", escape_label(value));
+    }
+    out.push('}');
+}
+
+fn number(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', r"\\").replace('\n', r"\n")
+}
+
+fn escape_label(text: &str) -> String {
+    escape(text).replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+
+    use super::*;
+
+    #[test]
+    fn accept_headers_pick_the_preferred_format() {
+        let prometheus = "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+        assert_eq!(Format::negotiate(Some(prometheus)), Format::OpenMetrics);
+        assert_eq!(Format::negotiate(None), Format::Text);
+        assert_eq!(Format::negotiate(Some("*/*")), Format::Text);
+        assert_eq!(
+            Format::negotiate(Some("text/plain, application/openmetrics-text;q=0.5")),
+            Format::Text
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/openmetrics-text;q=0")),
+            Format::Text
+        );
+    }
+
+    #[test]
+    fn histograms_carry_the_latest_exemplar_of_each_bucket() {
+        let registry = Registry::new();
+        let latency = HistogramVec::new(
+            HistogramOpts::new("message_latency_seconds", "Latency").buckets(vec![0.1, 1.0]),
+            &["device"],
+        )
+        .unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        let frames =
+            IntCounterVec::new(Opts::new("frames_total", "Frames \"seen\""), &["device"]).unwrap();
+        registry.register(Box::new(frames.clone())).unwrap();
+
+        let exemplars = Exemplars::new("message_latency_seconds", vec![0.1, 1.0]);
+        let at = UNIX_EPOCH + Duration::from_millis(1_000_500);
+        for (sequence, value) in [(7, 0.03125), (8, 0.5), (9, 0.0625)] {
+            latency.with_label_values(&["feeder"]).observe(value);
+            exemplars.record(
+                "feeder",
+                Exemplar {
+                    sequence: Some(sequence),
+                    value,
+                    time: at,
+                },
+            );
+        }
+        frames.with_label_values(&["feeder"]).inc_by(3);
+
+        let text = encode_with(&registry.gather(), Some(&exemplars));
+        assert_eq!(
+            text,
+            "# TYPE frames counter\n\
+             # HELP frames Frames \"seen\"\n\
+             frames_total{device=\"feeder\"} 3\n\
+             # TYPE message_latency_seconds histogram\n\
+             # HELP message_latency_seconds Latency\n\
+             message_latency_seconds_bucket{device=\"feeder\",le=\"0.1\"} 2 # {sequence=\"9\"} 0.0625 1000.5\n\
+             message_latency_seconds_bucket{device=\"feeder\",le=\"1\"} 3 # {sequence=\"8\"} 0.5 1000.5\n\
+             message_latency_seconds_bucket{device=\"feeder\",le=\"+Inf\"} 3\n\
+             message_latency_seconds_count{device=\"feeder\"} 3\n\
+             message_latency_seconds_sum{device=\"feeder\"} 0.59375\n\
+             # EOF\n"
+        );
+    }
+}
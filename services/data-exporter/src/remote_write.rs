@@ -0,0 +1,433 @@
+//! Pushing the metrics to a Prometheus remote_write endpoint (--remote-write-url), for sites
+//! behind NAT that can't be scraped. Every --remote-write-interval the exporter sends what
+//! /metrics would serve, after --metrics-include/--metrics-exclude and with the tenant label,
+//! as one snappy-compressed WriteRequest stamped with the time of the push. It runs alongside
+//! /metrics, or instead of it with --no-metrics-endpoint.
+//!
+//! A push that fails on the network, with a 5xx or with a 429 is retried up to
+//! --remote-write-max-retries times, waiting from --remote-write-retry-backoff and doubling,
+//! but never past when the next push is due. Any other response means the endpoint refused
+//! the samples, and retrying wouldn't help. Only the latest values matter, so a push given
+//! up on is counted and not sent again.
+
+use std::time::Duration;
+
+use prometheus::core::Collector;
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::{IntCounter, IntCounterVec, Opts};
+use shutdown::Shutdown;
+
+/// What became of the pushes, and how many samples got through.
+pub struct Counters {
+    pub pushes: IntCounterVec,
+    pub retries: IntCounter,
+    pub samples: IntCounter,
+}
+
+impl Counters {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let pushes = IntCounterVec::new(
+            Opts::new(
+                "remote_write_pushes_total",
+                "Pushes to --remote-write-url: sent, refused by the endpoint, or failed after every retry",
+            ),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(pushes.clone()))?;
+        let retries = IntCounter::new(
+            "remote_write_retries_total",
+            "Pushes to --remote-write-url sent again after a network error, 5xx or 429",
+        )?;
+        registry.register(Box::new(retries.clone()))?;
+        let samples = IntCounter::new(
+            "remote_write_samples_total",
+            "Samples in the pushes --remote-write-url accepted",
+        )?;
+        registry.register(Box::new(samples.clone()))?;
+        Ok(Self {
+            pushes,
+            retries,
+            samples,
+        })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 3] {
+        [&self.pushes, &self.retries, &self.samples]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RemoteWriteConfig {
+    pub url: String,
+    pub interval: Duration,
+    /// User name and password for basic auth
+    pub basic_auth: Option<(String, Option<String>)>,
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+}
+
+/// The remote_write protocol's messages, as in Prometheus' prompb/types.proto.
+mod prompb {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WriteRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub timeseries: Vec<TimeSeries>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TimeSeries {
+        /// Sorted by name, `__name__` included
+        #[prost(message, repeated, tag = "1")]
+        pub labels: Vec<Label>,
+        #[prost(message, repeated, tag = "2")]
+        pub samples: Vec<Sample>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Label {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Sample {
+        #[prost(double, tag = "1")]
+        pub value: f64,
+        /// Milliseconds since the Unix epoch
+        #[prost(int64, tag = "2")]
+        pub timestamp: i64,
+    }
+}
+
+use prompb::{Label, Sample, TimeSeries, WriteRequest};
+
+/// One series of `name` with `labels` and, for histogram buckets and summary quantiles, one
+/// more label.
+fn series(
+    name: String,
+    labels: &[LabelPair],
+    extra: Option<(&str, String)>,
+    value: f64,
+    timestamp: i64,
+) -> TimeSeries {
+    let mut labels: Vec<_> = labels
+        .iter()
+        .map(|label| Label {
+            name: label.get_name().to_string(),
+            value: label.get_value().to_string(),
+        })
+        .chain(extra.map(|(name, value)| Label {
+            name: name.to_string(),
+            value,
+        }))
+        .chain([Label {
+            name: "__name__".to_string(),
+            value: name,
+        }])
+        .collect();
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    TimeSeries {
+        labels,
+        samples: vec![Sample { value, timestamp }],
+    }
+}
+
+/// `le` and `quantile` values as the text format writes them.
+fn bound(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// The series of `families` as remote_write sends them, every sample at `timestamp` (in
+/// milliseconds) unless the metric carries its own. Histograms and summaries become their
+/// `_bucket`/quantile, `_sum` and `_count` series, as they do when scraped.
+fn time_series(families: &[MetricFamily], timestamp: i64) -> Vec<TimeSeries> {
+    let mut all = Vec::new();
+    for family in families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            let at = match metric.get_timestamp_ms() {
+                0 => timestamp,
+                at => at,
+            };
+            let labels = metric.get_label();
+            match family.get_field_type() {
+                MetricType::COUNTER => all.push(series(
+                    name.to_string(),
+                    labels,
+                    None,
+                    metric.get_counter().get_value(),
+                    at,
+                )),
+                MetricType::GAUGE => all.push(series(
+                    name.to_string(),
+                    labels,
+                    None,
+                    metric.get_gauge().get_value(),
+                    at,
+                )),
+                MetricType::UNTYPED => all.push(series(
+                    name.to_string(),
+                    labels,
+                    None,
+                    metric.get_untyped().get_value(),
+                    at,
+                )),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut buckets: Vec<_> = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                        .collect();
+                    if buckets.last().is_none_or(|(le, _)| *le != f64::INFINITY) {
+                        buckets.push((f64::INFINITY, histogram.get_sample_count()));
+                    }
+                    for (le, count) in buckets {
+                        all.push(series(
+                            format!("{name}_bucket"),
+                            labels,
+                            Some(("le", bound(le))),
+                            count as f64,
+                            at,
+                        ));
+                    }
+                    all.push(series(
+                        format!("{name}_sum"),
+                        labels,
+                        None,
+                        histogram.get_sample_sum(),
+                        at,
+                    ));
+                    all.push(series(
+                        format!("{name}_count"),
+                        labels,
+                        None,
+                        histogram.get_sample_count() as f64,
+                        at,
+                    ));
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        all.push(series(
+                            name.to_string(),
+                            labels,
+                            Some(("quantile", bound(quantile.get_quantile()))),
+                            quantile.get_value(),
+                            at,
+                        ));
+                    }
+                    all.push(series(
+                        format!("{name}_sum"),
+                        labels,
+                        None,
+                        summary.get_sample_sum(),
+                        at,
+                    ));
+                    all.push(series(
+                        format!("{name}_count"),
+                        labels,
+                        None,
+                        summary.get_sample_count() as f64,
+                        at,
+                    ));
+                }
+            }
+        }
+    }
+    all
+}
+
+/// A snappy-compressed WriteRequest, and how many samples it holds.
+fn encode(families: &[MetricFamily], timestamp: i64) -> (Vec<u8>, usize) {
+    use prost::Message;
+
+    let request = WriteRequest {
+        timeseries: time_series(families, timestamp),
+    };
+    let samples = request.timeseries.len();
+    let body = snap::raw::Encoder::new()
+        .compress_vec(&request.encode_to_vec())
+        .expect("snappy takes any input up to 4GiB");
+    (body, samples)
+}
+
+enum Failure {
+    /// Worth sending again
+    Transient(String),
+    Refused(String),
+}
+
+async fn push(
+    client: &reqwest::Client,
+    config: &RemoteWriteConfig,
+    body: Vec<u8>,
+) -> Result<(), Failure> {
+    let mut request = client
+        .post(&config.url)
+        .header("Content-Encoding", "snappy")
+        .header("Content-Type", "application/x-protobuf")
+        .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+        .body(body);
+    if let Some((user, password)) = &config.basic_auth {
+        request = request.basic_auth(user, password.as_ref());
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|err| Failure::Transient(err.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let reason = format!("{status}: {}", response.text().await.unwrap_or_default());
+    if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        Err(Failure::Transient(reason))
+    } else {
+        Err(Failure::Refused(reason))
+    }
+}
+
+/// Pushes the metrics every interval until shutdown is requested.
+pub async fn run(config: RemoteWriteConfig, counters: &Counters, shutdown: Shutdown) {
+    let client = match reqwest::Client::builder().timeout(config.interval).build() {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("Not pushing to {}: {err}", config.url);
+            return;
+        }
+    };
+    log::info!(
+        "Pushing metrics to {} every {}",
+        config.url,
+        humantime::format_duration(config.interval)
+    );
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.requested() => return,
+        }
+        let families = crate::exported_metrics(prometheus::gather());
+        let (body, samples) = encode(&families, chrono::Utc::now().timestamp_millis());
+
+        let due = tokio::time::Instant::now() + config.interval;
+        let mut backoff = config.retry_backoff;
+        let mut retries = 0;
+        loop {
+            let failure = match push(&client, &config, body.clone()).await {
+                Ok(()) => {
+                    counters.pushes.with_label_values(&["sent"]).inc();
+                    counters.samples.inc_by(samples as u64);
+                    break;
+                }
+                Err(failure) => failure,
+            };
+            match failure {
+                Failure::Transient(reason)
+                    if retries < config.max_retries
+                        && tokio::time::Instant::now() + backoff < due =>
+                {
+                    log::debug!("Push to {} failed, retrying: {reason}", config.url);
+                    counters.retries.inc();
+                    retries += 1;
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = shutdown.requested() => return,
+                    }
+                    backoff *= 2;
+                }
+                Failure::Transient(reason) => {
+                    log::warn!("Gave up pushing to {}: {reason}", config.url);
+                    counters.pushes.with_label_values(&["failed"]).inc();
+                    break;
+                }
+                Failure::Refused(reason) => {
+                    log::warn!("{} refused a push: {reason}", config.url);
+                    counters.pushes.with_label_values(&["refused"]).inc();
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{Histogram, HistogramOpts, IntGaugeVec, Opts, Registry};
+    use prost::Message;
+
+    use super::*;
+
+    fn labels(series: &TimeSeries) -> Vec<(&str, &str)> {
+        series
+            .labels
+            .iter()
+            .map(|label| (label.name.as_str(), label.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn families_become_sorted_series_with_histogram_buckets() {
+        let registry = Registry::new();
+        let gauge = IntGaugeVec::new(Opts::new("active_streams", "help"), &["device"]).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        gauge.with_label_values(&["lab"]).set(3);
+        let histogram = Histogram::with_opts(
+            HistogramOpts::new("latency_seconds", "help").buckets(vec![0.1, 1.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        histogram.observe(0.5);
+
+        let (body, samples) = encode(&registry.gather(), 1_700_000_000_000);
+        let decoded = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let request = WriteRequest::decode(decoded.as_slice()).unwrap();
+        assert_eq!(samples, request.timeseries.len());
+
+        let gauge = &request.timeseries[0];
+        assert_eq!(
+            labels(gauge),
+            [("__name__", "active_streams"), ("device", "lab")]
+        );
+        assert_eq!(
+            gauge.samples,
+            [Sample {
+                value: 3.0,
+                timestamp: 1_700_000_000_000
+            }]
+        );
+
+        let histogram: Vec<_> = request.timeseries[1..]
+            .iter()
+            .map(|series| (labels(series), series.samples[0].value))
+            .collect();
+        assert_eq!(
+            histogram,
+            [
+                (
+                    vec![("__name__", "latency_seconds_bucket"), ("le", "0.1")],
+                    0.0
+                ),
+                (
+                    vec![("__name__", "latency_seconds_bucket"), ("le", "1")],
+                    1.0
+                ),
+                (
+                    vec![("__name__", "latency_seconds_bucket"), ("le", "+Inf")],
+                    1.0
+                ),
+                (vec![("__name__", "latency_seconds_sum")], 0.5),
+                (vec![("__name__", "latency_seconds_count")], 1.0),
+            ]
+        );
+    }
+}
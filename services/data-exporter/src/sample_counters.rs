@@ -0,0 +1,297 @@
+//! Running totals of every sample, for Prometheus-side math over arbitrary ranges. Each
+//! quantity is exposed as a summary without quantiles, i.e. just `<name>_sum` and
+//! `<name>_count`, so a recording rule can average over any range with
+//! `increase(rms_voltage_sum[1h]) / increase(rms_voltage_count[1h])`.
+//!
+//! Unlike the windowed gauges these see every message, deadbanded or not. Sums of signed
+//! quantities (reactive power, DC offsets) can go down, which summaries allow.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{LabelPair, Metric, MetricFamily, MetricType, Summary},
+};
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeTwoPhaseCalculations;
+
+use crate::metric_names::{metric_naming, MetricNaming};
+
+const LABELS: [&str; 3] = ["device", "stream", "phase"];
+
+/// A summary of every quantity, per phase, and of the three-phase sums. Only exported with
+/// --sample-counters.
+pub struct Counters {
+    real_power: SampleSummaryVec,
+    active_power: SampleSummaryVec,
+    apparent_power: SampleSummaryVec,
+    reactive_power: SampleSummaryVec,
+    power_factor: SampleSummaryVec,
+    rms_voltage: SampleSummaryVec,
+    dc_offset_voltage: SampleSummaryVec,
+    rms_current: SampleSummaryVec,
+    dc_offset_current: SampleSummaryVec,
+    crest_factor_voltage: SampleSummaryVec,
+    thd_voltage: SampleSummaryVec,
+    crest_factor_current: SampleSummaryVec,
+    thd_current: SampleSummaryVec,
+    real_power_three_phase: SampleSummaryVec,
+    reactive_power_three_phase: SampleSummaryVec,
+}
+
+impl Counters {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let summary =
+            |name, unit, description| SampleSummaryVec::register(registry, name, unit, description);
+        Ok(Self {
+            real_power: summary("real_power", "watts", "Every real power sample")?,
+            active_power: summary("active_power", "watts", "Every active power sample")?,
+            apparent_power: summary(
+                "apparent_power",
+                "volt_amperes",
+                "Every apparent power sample",
+            )?,
+            reactive_power: summary(
+                "reactive_power",
+                "volt_amperes_reactive",
+                "Every reactive power sample",
+            )?,
+            power_factor: summary("power_factor", "ratio", "Every power factor sample")?,
+            rms_voltage: summary("rms_voltage", "volts", "Every rms voltage sample")?,
+            dc_offset_voltage: summary(
+                "dc_offset_voltage",
+                "volts",
+                "Every voltage dc offset sample",
+            )?,
+            rms_current: summary("rms_current", "amperes", "Every rms current sample")?,
+            dc_offset_current: summary(
+                "dc_offset_current",
+                "amperes",
+                "Every current dc offset sample",
+            )?,
+            crest_factor_voltage: summary(
+                "crest_factor_voltage",
+                "ratio",
+                "Every voltage crest factor sample",
+            )?,
+            thd_voltage: summary("thd_voltage", "percent", "Every voltage THD sample")?,
+            crest_factor_current: summary(
+                "crest_factor_current",
+                "ratio",
+                "Every current crest factor sample",
+            )?,
+            thd_current: summary("thd_current", "percent", "Every current THD sample")?,
+            real_power_three_phase: summary(
+                "real_power_three_phase",
+                "watts",
+                "Every three-phase real power sum",
+            )?,
+            reactive_power_three_phase: summary(
+                "reactive_power_three_phase",
+                "volt_amperes_reactive",
+                "Every three-phase reactive power sum",
+            )?,
+        })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 15] {
+        [
+            &self.real_power,
+            &self.active_power,
+            &self.apparent_power,
+            &self.reactive_power,
+            &self.power_factor,
+            &self.rms_voltage,
+            &self.dc_offset_voltage,
+            &self.rms_current,
+            &self.dc_offset_current,
+            &self.crest_factor_voltage,
+            &self.thd_voltage,
+            &self.crest_factor_current,
+            &self.thd_current,
+            &self.real_power_three_phase,
+            &self.reactive_power_three_phase,
+        ]
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Totals {
+    sum: f64,
+    count: u64,
+}
+
+/// Summaries without quantiles, labelled by device, stream and phase, under the legacy name,
+/// the unit-suffixed name or both, following --metric-names.
+#[derive(Clone)]
+struct SampleSummaryVec {
+    descs: Arc<Vec<Desc>>,
+    series: Arc<Mutex<HashMap<[String; 3], Totals>>>,
+}
+
+impl SampleSummaryVec {
+    fn register(
+        registry: &prometheus::Registry,
+        name: &str,
+        unit: &str,
+        description: &str,
+    ) -> prometheus::Result<Self> {
+        let naming = metric_naming();
+        let names = [
+            (naming != MetricNaming::Suffixed).then(|| name.to_string()),
+            (naming != MetricNaming::Legacy).then(|| format!("{name}_{unit}")),
+        ];
+        let descs = names
+            .into_iter()
+            .flatten()
+            .map(|name| {
+                Desc::new(
+                    name,
+                    description.to_string(),
+                    LABELS.map(String::from).to_vec(),
+                    HashMap::new(),
+                )
+            })
+            .collect::<prometheus::Result<_>>()?;
+
+        let summary = Self {
+            descs: Arc::new(descs),
+            series: Arc::default(),
+        };
+        registry.register(Box::new(summary.clone()))?;
+        Ok(summary)
+    }
+
+    fn observe(&self, labels: [&str; 3], value: f64) {
+        let mut series = self.series.lock().unwrap();
+        let totals = series.entry(labels.map(String::from)).or_default();
+        totals.sum += value;
+        totals.count += 1;
+    }
+}
+
+impl Collector for SampleSummaryVec {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let series = self.series.lock().unwrap();
+        let metrics: Vec<Metric> = series
+            .iter()
+            .map(|(labels, totals)| {
+                let mut summary = Summary::default();
+                summary.set_sample_sum(totals.sum);
+                summary.set_sample_count(totals.count);
+
+                let pairs: Vec<LabelPair> = LABELS
+                    .iter()
+                    .zip(labels)
+                    .map(|(name, value)| {
+                        let mut pair = LabelPair::default();
+                        pair.set_name(name.to_string());
+                        pair.set_value(value.clone());
+                        pair
+                    })
+                    .collect();
+
+                let mut metric = Metric::default();
+                metric.set_label(pairs.into());
+                metric.set_summary(summary);
+                metric
+            })
+            .collect();
+
+        self.descs
+            .iter()
+            .map(|desc| {
+                let mut family = MetricFamily::default();
+                family.set_name(desc.fq_name.clone());
+                family.set_help(desc.help.clone());
+                family.set_field_type(MetricType::SUMMARY);
+                family.set_metric(metrics.clone().into());
+                family
+            })
+            .collect()
+    }
+}
+
+/// Adds every quantity of each phase of one stream's message.
+pub fn record(
+    counters: &Counters,
+    device: &str,
+    stream: &str,
+    calcs: &CompositeTwoPhaseCalculations,
+) {
+    for (phase, calcs) in [
+        ("a", calcs.phase_a),
+        ("b", calcs.phase_b),
+        ("c", calcs.phase_c),
+    ] {
+        let Some(calcs) = calcs else {
+            continue;
+        };
+        let labels = [device, stream, phase];
+
+        if let Some(power) = calcs.power_calculations {
+            counters
+                .real_power
+                .observe(labels, power.real_power_w() as f64);
+            counters
+                .active_power
+                .observe(labels, power.real_power_w() as f64);
+            counters
+                .apparent_power
+                .observe(labels, power.apparent_power_va() as f64);
+            counters
+                .reactive_power
+                .observe(labels, power.reactive_power_var() as f64);
+            counters
+                .power_factor
+                .observe(labels, power.power_factor() as f64);
+        }
+        if let Some(voltage) = calcs.voltage_waveform_calculations_v {
+            counters.rms_voltage.observe(labels, voltage.rms() as f64);
+            counters
+                .dc_offset_voltage
+                .observe(labels, voltage.dc_offset() as f64);
+            if let Some(crest_factor) = voltage.crest_factor {
+                counters
+                    .crest_factor_voltage
+                    .observe(labels, crest_factor as f64);
+            }
+            if let Some(thd) = voltage.thd_percent {
+                counters.thd_voltage.observe(labels, thd as f64);
+            }
+        }
+        if let Some(current) = calcs.current_waveform_calculations_a {
+            counters.rms_current.observe(labels, current.rms() as f64);
+            counters
+                .dc_offset_current
+                .observe(labels, current.dc_offset() as f64);
+            if let Some(crest_factor) = current.crest_factor {
+                counters
+                    .crest_factor_current
+                    .observe(labels, crest_factor as f64);
+            }
+            if let Some(thd) = current.thd_percent {
+                counters.thd_current.observe(labels, thd as f64);
+            }
+        }
+    }
+}
+
+/// Adds one message's three-phase sums, as (real, reactive) for phases a and b, and c when a
+/// three-phase meter sent it.
+pub fn record_three_phase(counters: &Counters, device: &str, stream: &str, sums: &[(f32, f32)]) {
+    for (phase, &(real, reactive)) in ["a", "b", "c"].into_iter().zip(sums) {
+        let labels = [device, stream, phase];
+        counters.real_power_three_phase.observe(labels, real as f64);
+        counters
+            .reactive_power_three_phase
+            .observe(labels, reactive as f64);
+    }
+}
@@ -0,0 +1,132 @@
+//! How long ago each phase of each stream last reported, and which have been quiet for longer
+//! than --stale-after. Their per-phase gauges are then kept, reset or dropped following
+//! --stale-action, until the stream reports again.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, Opts};
+
+use crate::maintenance::Maintenance;
+
+/// How long each phase of each stream has been quiet.
+pub struct Gauges {
+    pub since_last_sample: GaugeVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let since_last_sample = GaugeVec::new(
+            Opts::new(
+                "seconds_since_last_sample",
+                "Seconds since the last message with this phase of the stream",
+            ),
+            &["device", "stream", "phase"],
+        )?;
+        registry.register(Box::new(since_last_sample.clone()))?;
+        Ok(Self { since_last_sample })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 1] {
+        [&self.since_last_sample]
+    }
+}
+
+/// What happens to a stream's per-phase gauges once it has been quiet for --stale-after.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum StaleAction {
+    /// Leave them at their last values; only seconds_since_last_sample shows the gap
+    #[default]
+    Keep,
+    /// Set them to NaN, so dashboards show a gap instead of a flat line
+    Reset,
+    /// Stop exporting them until the stream reports again
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StalenessConfig {
+    pub after: Option<Duration>,
+    pub action: StaleAction,
+}
+
+struct LastSeen {
+    at: Instant,
+    stale: bool,
+}
+
+/// When each stream's phases were last seen, and which have gone quiet for too long.
+pub struct StalenessTracker {
+    config: StalenessConfig,
+    last_seen: HashMap<(String, &'static str), LastSeen>,
+}
+
+impl StalenessTracker {
+    pub fn new(config: StalenessConfig) -> Self {
+        Self {
+            config,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    pub fn action(&self) -> StaleAction {
+        self.config.action
+    }
+
+    pub fn record(&mut self, stream: &str, phase: &'static str) {
+        let now = Instant::now();
+        let last = self
+            .last_seen
+            .entry((stream.to_string(), phase))
+            .or_insert(LastSeen {
+                at: now,
+                stale: false,
+            });
+        if last.stale {
+            log::info!("Stream {stream} phase {phase} is reporting again");
+        }
+        last.at = now;
+        last.stale = false;
+    }
+
+    /// Exports how long ago every stream and phase was seen, and returns those that have just
+    /// gone stale and need --stale-action applied. Streams under maintenance are expected to
+    /// be quiet, so they are only timed.
+    pub fn update(
+        &mut self,
+        gauges: &Gauges,
+        device: &str,
+        maintenance: &Maintenance,
+    ) -> Vec<(String, &'static str)> {
+        let now = Instant::now();
+        let mut stale = Vec::new();
+        for ((stream, phase), last) in self.last_seen.iter_mut() {
+            let quiet = now.duration_since(last.at);
+            gauges
+                .since_last_sample
+                .with_label_values(&[device, stream, phase])
+                .set(quiet.as_secs_f64());
+
+            let Some(after) = self.config.after else {
+                continue;
+            };
+            if last.stale || quiet < after || maintenance.is_active(device, stream) {
+                continue;
+            }
+            last.stale = true;
+            log::warn!(
+                "Stream {stream} phase {phase} has not reported for {:.0} s",
+                quiet.as_secs_f64()
+            );
+            if self.config.action != StaleAction::Keep {
+                stale.push((stream.clone(), *phase));
+            }
+        }
+        stale
+    }
+}
@@ -0,0 +1,268 @@
+//! Stream names arrive as whatever the publisher called them, e.g. `threephase/feeder 1 (east)`,
+//! and become the `stream` label of every series. --stream-label-rewrite rules rewrite them
+//! first, in order; with --sanitize-stream-labels, each run of characters other than letters,
+//! digits and `_./:-` left after them becomes a single `_`, giving `threephase/feeder_1_east`.
+//!
+//! Everything after decoding sees the label rather than the name: the gauges, /streams, and
+//! the flags and maintenance windows naming streams. The stream registry is the exception, so
+//! it keeps the names data-db registers. GET /stream-labels lists every stream seen, with the
+//! label it was given.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{extract::State, Json};
+use chrono::{DateTime, Utc};
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, IntGaugeVec, Opts};
+use regex::Regex;
+use serde::Serialize;
+
+/// How many streams each device has, and has had.
+pub struct Gauges {
+    pub active: IntGaugeVec,
+    pub discovered: IntCounterVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let active = IntGaugeVec::new(
+            Opts::new(
+                "active_streams",
+                "Streams that have reported within --active-stream-window",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(active.clone()))?;
+        let discovered = IntCounterVec::new(
+            Opts::new(
+                "streams_discovered_total",
+                "Streams seen for the first time since startup",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(discovered.clone()))?;
+        Ok(Self { active, discovered })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 2] {
+        [&self.active, &self.discovered]
+    }
+}
+
+/// One --stream-label-rewrite rule.
+#[derive(Clone, Debug)]
+pub struct Rewrite {
+    pattern: Regex,
+    replacement: String,
+}
+
+/// `REGEX=REPLACEMENT`; the pattern may contain `=` itself, the replacement can't.
+pub fn parse_rewrite(value: &str) -> Result<Rewrite, String> {
+    let (pattern, replacement) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected REGEX=REPLACEMENT, got '{value}'"))?;
+    Ok(Rewrite {
+        pattern: Regex::new(pattern).map_err(|err| err.to_string())?,
+        replacement: replacement.to_string(),
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct LabelConfig {
+    pub rewrites: Vec<Rewrite>,
+    pub sanitize: bool,
+    pub active_window: Duration,
+}
+
+impl Default for LabelConfig {
+    fn default() -> Self {
+        Self {
+            rewrites: Vec::new(),
+            sanitize: false,
+            active_window: Duration::from_secs(60),
+        }
+    }
+}
+
+impl LabelConfig {
+    /// The label for a stream called `name`.
+    pub fn label(&self, name: &str) -> String {
+        let mut label = name.to_string();
+        for rewrite in &self.rewrites {
+            label = rewrite
+                .pattern
+                .replace_all(&label, rewrite.replacement.as_str())
+                .into_owned();
+        }
+        if self.sanitize {
+            label = sanitize(&label);
+        }
+        label
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    let mut label = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || "_./:-".contains(c) {
+            label.push(c);
+        } else if !label.ends_with('_') {
+            label.push('_');
+        }
+    }
+    match label.trim_matches('_') {
+        "" => "_".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+struct Seen {
+    label: String,
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+/// An entry of `GET /stream-labels`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Mapping {
+    pub device: String,
+    /// As the publisher named it
+    pub stream: String,
+    pub label: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Reported within --active-stream-window
+    pub active: bool,
+}
+
+/// Every stream seen and its label, shared by the listeners and the HTTP API.
+#[derive(Clone, Default)]
+pub struct StreamLabels {
+    config: Arc<LabelConfig>,
+    /// By device, then stream name
+    seen: Arc<RwLock<HashMap<String, HashMap<String, Seen>>>>,
+}
+
+impl StreamLabels {
+    pub fn new(config: LabelConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            seen: Arc::default(),
+        }
+    }
+
+    /// The label of a stream called `name`, without recording it as seen.
+    pub fn label(&self, name: &str) -> String {
+        self.config.label(name)
+    }
+
+    /// The label of stream `name` from `device`, which has just reported.
+    pub fn seen(&self, gauges: &Gauges, device: &str, name: &str) -> String {
+        let now = Utc::now();
+        let mut seen = self.seen.write().unwrap();
+        let streams = seen.entry(device.to_string()).or_default();
+        if let Some(stream) = streams.get_mut(name) {
+            stream.last_seen = now;
+            return stream.label.clone();
+        }
+
+        let label = self.config.label(name);
+        if let Some((other, _)) = streams.iter().find(|(_, seen)| seen.label == label) {
+            log::warn!("Streams '{other}' and '{name}' from {device} are both labelled '{label}'");
+        }
+        log::info!("Discovered stream '{name}' from {device}, labelled '{label}'");
+        gauges.discovered.with_label_values(&[device]).inc();
+        streams.insert(
+            name.to_string(),
+            Seen {
+                label: label.clone(),
+                first_seen: now,
+                last_seen: now,
+            },
+        );
+        label
+    }
+
+    fn is_active(&self, seen: &Seen, now: DateTime<Utc>) -> bool {
+        (now - seen.last_seen)
+            .to_std()
+            .map_or(true, |quiet| quiet < self.config.active_window)
+    }
+
+    /// Exports how many of `device`'s streams are active.
+    pub fn update(&self, gauges: &Gauges, device: &str) {
+        let now = Utc::now();
+        let seen = self.seen.read().unwrap();
+        let active = seen.get(device).map_or(0, |streams| {
+            streams
+                .values()
+                .filter(|stream| self.is_active(stream, now))
+                .count()
+        });
+        gauges
+            .active
+            .with_label_values(&[device])
+            .set(active as i64);
+    }
+
+    pub fn mappings(&self) -> Vec<Mapping> {
+        let now = Utc::now();
+        let seen = self.seen.read().unwrap();
+        let mut mappings: Vec<_> = seen
+            .iter()
+            .flat_map(|(device, streams)| {
+                streams.iter().map(move |(stream, seen)| Mapping {
+                    device: device.clone(),
+                    stream: stream.clone(),
+                    label: seen.label.clone(),
+                    first_seen: seen.first_seen,
+                    last_seen: seen.last_seen,
+                    active: self.is_active(seen, now),
+                })
+            })
+            .collect();
+        mappings.sort_by(|a, b| (&a.device, &a.stream).cmp(&(&b.device, &b.stream)));
+        mappings
+    }
+}
+
+/// `GET /stream-labels`: every stream seen since startup, and the label its series carry.
+pub async fn list_handler(State(labels): State<StreamLabels>) -> Json<Vec<Mapping>> {
+    Json(labels.mappings())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_apply_in_order_before_sanitizing() {
+        let config = LabelConfig {
+            rewrites: vec![
+                parse_rewrite("^threephase/=").unwrap(),
+                parse_rewrite(r"\(([a-z]+)\)=$1-side").unwrap(),
+            ],
+            sanitize: true,
+            ..LabelConfig::default()
+        };
+        assert_eq!(
+            config.label("threephase/feeder 1 (east)"),
+            "feeder_1_east-side"
+        );
+        assert_eq!(config.label("site/total"), "site/total");
+        assert_eq!(
+            LabelConfig::default().label("feeder 1 (east)"),
+            "feeder 1 (east)"
+        );
+        assert_eq!(
+            sanitize("threephase/feeder 1 (east)"),
+            "threephase/feeder_1_east"
+        );
+        assert_eq!(sanitize("(?)"), "_");
+    }
+}
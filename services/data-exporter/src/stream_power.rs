@@ -0,0 +1,82 @@
+//! Each stream's totals over its own phases, from power-calc, as of its latest message: real,
+//! reactive and apparent power, power factor and estimated neutral current. Unlike the
+//! `*_three_phase_*` gauges, which sum one phase over every stream in a message, these never
+//! mix streams. Voltage and current imbalance are exported as phase_unbalance_percent.
+
+use prometheus::core::Collector;
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeTwoPhaseCalculations;
+
+use crate::metric_names::UnitGaugeVec;
+
+const LABELS: &[&str] = &["device", "stream"];
+
+pub struct Gauges {
+    real_power: UnitGaugeVec,
+    reactive_power: UnitGaugeVec,
+    apparent_power: UnitGaugeVec,
+    power_factor: UnitGaugeVec,
+    neutral_current: UnitGaugeVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let gauge = |name: &str, unit: &str, help: &str| {
+            UnitGaugeVec::register(registry, name, unit, help, LABELS)
+        };
+        Ok(Self {
+            real_power: gauge(
+                "stream_real_power",
+                "watts",
+                "Real power of the stream, summed over its phases",
+            )?,
+            reactive_power: gauge(
+                "stream_reactive_power",
+                "volt_amperes_reactive",
+                "Reactive power of the stream, summed over its phases",
+            )?,
+            apparent_power: gauge(
+                "stream_apparent_power",
+                "volt_amperes",
+                "Apparent power of the stream, from its total real and reactive power",
+            )?,
+            power_factor: gauge(
+                "stream_power_factor",
+                "ratio",
+                "Power factor of the stream over all its phases; negative while exporting",
+            )?,
+            neutral_current: gauge(
+                "neutral_current_estimate",
+                "amperes",
+                "Neutral current estimated from the stream's rms phase currents",
+            )?,
+        })
+    }
+
+    pub fn collectors(&self) -> impl Iterator<Item = &dyn Collector> {
+        [
+            &self.real_power,
+            &self.reactive_power,
+            &self.apparent_power,
+            &self.power_factor,
+            &self.neutral_current,
+        ]
+        .into_iter()
+        .flat_map(UnitGaugeVec::collectors)
+    }
+
+    /// Exports what can be derived from `calcs`; the others keep their previous values.
+    pub fn update(&self, device: &str, stream: &str, calcs: &CompositeTwoPhaseCalculations) {
+        let summary = power_calc::summarize(&power_calc::phases(calcs));
+        for (gauge, value) in [
+            (&self.real_power, summary.real_power),
+            (&self.reactive_power, summary.reactive_power),
+            (&self.apparent_power, summary.apparent_power),
+            (&self.power_factor, summary.power_factor),
+            (&self.neutral_current, summary.neutral_current),
+        ] {
+            if let Some(value) = value {
+                gauge.with_label_values(&[device, stream]).set(value);
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use karman_types::PhaseMeasurements;
+use protobuf_rs::utilidata::karman::bibimbap::v1::Provenance;
+use serde::{Deserialize, Serialize};
+
+/// One phase of a stream's latest calculations, null where the publisher left a value out.
+#[derive(Clone, Debug, Serialize)]
+pub struct Phase {
+    /// Provenance timestamp
+    pub time: Option<DateTime<Utc>>,
+    pub sequence_number: Option<u64>,
+    pub rms_voltage: Option<f64>,
+    pub dc_offset_voltage: Option<f64>,
+    pub rms_current: Option<f64>,
+    pub dc_offset_current: Option<f64>,
+    pub real_power: Option<f64>,
+    pub apparent_power: Option<f64>,
+    pub reactive_power: Option<f64>,
+    pub power_factor: Option<f64>,
+    pub crest_factor_voltage: Option<f64>,
+    pub thd_voltage: Option<f64>,
+    pub crest_factor_current: Option<f64>,
+    pub thd_current: Option<f64>,
+}
+
+fn provenance_time(provenance: &Provenance) -> Option<DateTime<Utc>> {
+    let time = provenance.utc_time?;
+    DateTime::from_timestamp(time.seconds, time.nanos.try_into().ok()?)
+}
+
+impl From<PhaseMeasurements> for Phase {
+    fn from(values: PhaseMeasurements) -> Self {
+        let known = |value: f64| (!value.is_nan()).then_some(value);
+        Self {
+            time: values.provenance.as_ref().and_then(provenance_time),
+            sequence_number: values
+                .provenance
+                .and_then(|provenance| provenance.generic_sequence_number),
+            rms_voltage: known(values.rms_voltage),
+            dc_offset_voltage: known(values.dc_offset_voltage),
+            rms_current: known(values.rms_current),
+            dc_offset_current: known(values.dc_offset_current),
+            real_power: known(values.real_power),
+            apparent_power: known(values.apparent_power),
+            reactive_power: known(values.reactive_power),
+            power_factor: known(values.power_factor),
+            crest_factor_voltage: values.crest_factor_voltage,
+            thd_voltage: values.thd_voltage,
+            crest_factor_current: values.crest_factor_current,
+            thd_current: values.thd_current,
+        }
+    }
+}
+
+/// The most recent in-order calculations of one stream, as the windows received them.
+#[derive(Clone, Debug, Serialize)]
+pub struct Latest {
+    pub device: String,
+    pub stream: String,
+    pub received: DateTime<Utc>,
+    /// By phase label; only the phases the message carried
+    pub phases: BTreeMap<&'static str, Phase>,
+}
+
+/// An entry of `GET /streams`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Summary {
+    pub device: String,
+    pub stream: String,
+    pub phases: Vec<&'static str>,
+    pub received: DateTime<Utc>,
+}
+
+/// The latest calculations of every stream, shared by the listeners and the HTTP API.
+#[derive(Clone, Default)]
+pub struct Streams {
+    /// By device, then stream
+    state: Arc<RwLock<HashMap<String, HashMap<String, Latest>>>>,
+}
+
+impl Streams {
+    pub fn record(&self, device: &str, stream: &str, phases: BTreeMap<&'static str, Phase>) {
+        let latest = Latest {
+            device: device.to_string(),
+            stream: stream.to_string(),
+            received: Utc::now(),
+            phases,
+        };
+        let mut state = self.state.write().unwrap();
+        state
+            .entry(device.to_string())
+            .or_default()
+            .insert(stream.to_string(), latest);
+    }
+
+    pub fn summaries(&self) -> Vec<Summary> {
+        let state = self.state.read().unwrap();
+        let mut summaries: Vec<_> = state
+            .values()
+            .flat_map(HashMap::values)
+            .map(|latest| Summary {
+                device: latest.device.clone(),
+                stream: latest.stream.clone(),
+                phases: latest.phases.keys().copied().collect(),
+                received: latest.received,
+            })
+            .collect();
+        summaries.sort_by(|a, b| (&a.device, &a.stream).cmp(&(&b.device, &b.stream)));
+        summaries
+    }
+
+    /// Every device's latest calculations for `stream`.
+    pub fn latest(&self, stream: &str) -> Vec<Latest> {
+        let state = self.state.read().unwrap();
+        let mut latest: Vec<_> = state
+            .values()
+            .filter_map(|streams| streams.get(stream).cloned())
+            .collect();
+        latest.sort_by(|a, b| a.device.cmp(&b.device));
+        latest
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LatestQuery {
+    device: Option<String>,
+}
+
+/// `GET /streams`: every stream seen since startup, with its phases and when it last arrived.
+pub async fn list_handler(State(streams): State<Streams>) -> Json<Vec<Summary>> {
+    Json(streams.summaries())
+}
+
+/// `GET /streams/{name}/latest`: the stream's most recent calculations. Names with a slash
+/// are percent-encoded (`threephase%2Fmain`). A stream sent by several devices needs
+/// `?device=`.
+pub async fn latest_handler(
+    State(streams): State<Streams>,
+    Path(name): Path<String>,
+    Query(query): Query<LatestQuery>,
+) -> Result<Json<Latest>, (StatusCode, String)> {
+    let mut latest = streams.latest(&name);
+    if let Some(device) = &query.device {
+        latest.retain(|latest| &latest.device == device);
+    }
+    match latest.len() {
+        0 => Err((StatusCode::NOT_FOUND, format!("Unknown stream: {name}\n"))),
+        1 => Ok(Json(latest.remove(0))),
+        _ => {
+            let devices: Vec<_> = latest.iter().map(|latest| latest.device.as_str()).collect();
+            Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Stream {name} comes from several devices, pick one with ?device=: {}\n",
+                    devices.join(", ")
+                ),
+            ))
+        }
+    }
+}
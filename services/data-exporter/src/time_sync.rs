@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use karman_types::time_sync::{is_trusted, source_label, SOURCES};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec, Opts};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, TimeSync,
+};
+
+/// The publishers' clock status, and the frames stamped while it couldn't be trusted.
+pub struct Gauges {
+    pub locked: GaugeVec,
+    pub offset: GaugeVec,
+    pub max_error: GaugeVec,
+    pub trusted: GaugeVec,
+    pub untrusted_frames: IntCounterVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let locked = GaugeVec::new(
+            Opts::new(
+                "time_sync_locked",
+                "1 while the publisher's clock reports being locked to its time source",
+            ),
+            &["device", "stream", "source"],
+        )?;
+        registry.register(Box::new(locked.clone()))?;
+        let offset = GaugeVec::new(
+            Opts::new(
+                "time_sync_offset_seconds",
+                "Reported offset of the publisher's clock from its time source",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(offset.clone()))?;
+        let max_error = GaugeVec::new(
+            Opts::new(
+                "time_sync_max_error_seconds",
+                "Reported worst-case error of the publisher's timestamps",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(max_error.clone()))?;
+        let trusted = GaugeVec::new(
+            Opts::new(
+                "timestamps_trusted",
+                "1 while the stream's clock is locked and within --max-clock-offset, 0 otherwise",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(trusted.clone()))?;
+        let untrusted_frames = IntCounterVec::new(
+            Opts::new(
+                "untrusted_timestamp_frames_total",
+                "Frames whose timestamps were taken while the clock was unlocked or out of tolerance",
+            ),
+            &["device", "stream"],
+        )?;
+        registry.register(Box::new(untrusted_frames.clone()))?;
+        Ok(Self {
+            locked,
+            offset,
+            max_error,
+            trusted,
+            untrusted_frames,
+        })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 5] {
+        [
+            &self.locked,
+            &self.offset,
+            &self.max_error,
+            &self.trusted,
+            &self.untrusted_frames,
+        ]
+    }
+}
+
+/// The clock status attached to a data product, if the publisher sent one.
+pub fn of(product: &DataProduct) -> Option<TimeSync> {
+    let provenance = match product {
+        DataProduct::Calculations(calcs) => [calcs.phase_a, calcs.phase_b, calcs.phase_c]
+            .into_iter()
+            .flatten()
+            .find_map(|phase| phase.provenance.filter(|p| p.time_sync.is_some())),
+        DataProduct::Fft(fft) => fft.provenance,
+        DataProduct::Waveform(waveform) => waveform.provenance,
+    };
+    provenance?.time_sync
+}
+
+pub fn record(gauges: &Gauges, device: &str, stream: &str, sync: &TimeSync, max_offset: Duration) {
+    for source in SOURCES {
+        // Only the current source reads 1, so a switch from GPS to NTP shows as a step
+        let locked = source == sync.source() && sync.locked();
+        gauges
+            .locked
+            .with_label_values(&[device, stream, source_label(source)])
+            .set(if locked { 1.0 } else { 0.0 });
+    }
+    if let Some(offset) = sync.offset_ns {
+        gauges
+            .offset
+            .with_label_values(&[device, stream])
+            .set(offset as f64 / 1e9);
+    }
+    if let Some(error) = sync.max_error_ns {
+        gauges
+            .max_error
+            .with_label_values(&[device, stream])
+            .set(error as f64 / 1e9);
+    }
+
+    let trusted = is_trusted(sync, max_offset);
+    gauges
+        .trusted
+        .with_label_values(&[device, stream])
+        .set(if trusted { 1.0 } else { 0.0 });
+    if !trusted {
+        gauges
+            .untrusted_frames
+            .with_label_values(&[device, stream])
+            .inc();
+    }
+}
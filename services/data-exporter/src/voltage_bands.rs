@@ -0,0 +1,237 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, Opts};
+
+/// How long each stream and phase spent in each voltage range.
+pub struct Gauges {
+    pub band_ratio: GaugeVec,
+}
+
+impl Gauges {
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        let band_ratio = GaugeVec::new(
+            Opts::new(
+                "voltage_band_time_ratio",
+                "Share of samples over the window with rms voltage under, inside or over the ANSI C84.1 range",
+            ),
+            &["device", "stream", "phase", "range", "position", "window"],
+        )?;
+        registry.register(Box::new(band_ratio.clone()))?;
+        Ok(Self { band_ratio })
+    }
+
+    /// For the metric catalog served on /schema.
+    pub fn collectors(&self) -> [&dyn Collector; 1] {
+        [&self.band_ratio]
+    }
+}
+
+/// Which ANSI C84.1 limits apply, depending on where the voltage is measured.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum MeasurementPoint {
+    /// At the service entrance (Range A 95-105%, Range B 91.7-105.8%)
+    Service,
+    /// At the point of use, e.g. a PDU (Range A 90-104.2%, Range B 86.7-105.8%)
+    Utilization,
+}
+
+impl MeasurementPoint {
+    /// (min, max) per unit of nominal, for Range A and Range B. These are the 120 V base
+    /// figures from the standard's Table 1 (e.g. 114-126 V and 110-127 V at the service).
+    fn limits(self) -> [(f64, f64); 2] {
+        match self {
+            MeasurementPoint::Service => [
+                (114.0 / 120.0, 126.0 / 120.0),
+                (110.0 / 120.0, 127.0 / 120.0),
+            ],
+            MeasurementPoint::Utilization => [
+                (108.0 / 120.0, 125.0 / 120.0),
+                (104.0 / 120.0, 127.0 / 120.0),
+            ],
+        }
+    }
+}
+
+const RANGES: [&str; 2] = ["a", "b"];
+const POSITIONS: [&str; 3] = ["under", "inside", "over"];
+
+/// Sample counts per range and position.
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    samples: u64,
+    positions: [[u64; 3]; 2],
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.samples += other.samples;
+        for (range, positions) in self.positions.iter_mut().enumerate() {
+            for (position, count) in positions.iter_mut().enumerate() {
+                *count += other.positions[range][position];
+            }
+        }
+    }
+
+    fn subtract(&mut self, other: &Counts) {
+        self.samples -= other.samples;
+        for (range, positions) in self.positions.iter_mut().enumerate() {
+            for (position, count) in positions.iter_mut().enumerate() {
+                *count -= other.positions[range][position];
+            }
+        }
+    }
+}
+
+/// Per-second counts for one stream and phase, with a running total per window so long
+/// windows (a day at 60 Hz) cost the same to report as short ones.
+struct Series {
+    seconds: VecDeque<(u64, Counts)>,
+    /// Index into `seconds` (offset by `popped`) of the oldest second each window still covers
+    window_starts: Vec<usize>,
+    window_totals: Vec<Counts>,
+    popped: usize,
+}
+
+impl Series {
+    fn new(windows: usize) -> Self {
+        Self {
+            seconds: VecDeque::new(),
+            window_starts: vec![0; windows],
+            window_totals: vec![Counts::default(); windows],
+            popped: 0,
+        }
+    }
+
+    fn record(&mut self, second: u64, sample: &Counts) {
+        match self.seconds.back_mut() {
+            Some((last, counts)) if *last == second => counts.add(sample),
+            _ => self.seconds.push_back((second, *sample)),
+        }
+        for total in self.window_totals.iter_mut() {
+            total.add(sample);
+        }
+    }
+
+    fn expire(&mut self, now: u64, windows: &[Duration]) {
+        for (index, window) in windows.iter().enumerate() {
+            let cutoff = now.saturating_sub(window.as_secs());
+            while let Some((second, counts)) =
+                self.seconds.get(self.window_starts[index] - self.popped)
+            {
+                if *second > cutoff {
+                    break;
+                }
+                self.window_totals[index].subtract(counts);
+                self.window_starts[index] += 1;
+            }
+        }
+
+        // Everything behind the slowest window has been subtracted from every total
+        let oldest = self.window_starts.iter().min().copied().unwrap_or_default();
+        while self.popped < oldest {
+            self.seconds.pop_front();
+            self.popped += 1;
+        }
+    }
+}
+
+/// Tracks how long each stream's rms voltage spends inside and outside the ANSI C84.1
+/// Range A and Range B bands over rolling windows.
+pub struct VoltageBands {
+    nominal: f64,
+    limits: [(f64, f64); 2],
+    windows: Vec<Duration>,
+    started: Instant,
+    series: HashMap<(String, &'static str), Series>,
+}
+
+impl VoltageBands {
+    pub fn new(nominal: f64, point: MeasurementPoint, windows: Vec<Duration>) -> Self {
+        // Seconds count from a window's length ago, so `record_at` can place bootstrapped
+        // history in any window
+        let longest = windows.iter().max().copied().unwrap_or_default();
+        let now = Instant::now();
+        Self {
+            nominal,
+            limits: point.limits(),
+            windows,
+            started: now.checked_sub(longest).unwrap_or(now),
+            series: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, stream: &str, phase: &'static str, rms_voltage: f64) {
+        self.record_at(stream, phase, rms_voltage, Duration::ZERO);
+    }
+
+    /// Records a sample taken `age` ago. Samples must arrive oldest first.
+    pub fn record_at(
+        &mut self,
+        stream: &str,
+        phase: &'static str,
+        rms_voltage: f64,
+        age: Duration,
+    ) {
+        if self.windows.is_empty() || !rms_voltage.is_finite() {
+            return;
+        }
+
+        let per_unit = rms_voltage / self.nominal;
+        let mut sample = Counts {
+            samples: 1,
+            ..Default::default()
+        };
+        for (range, (min, max)) in self.limits.iter().enumerate() {
+            let position = if per_unit < *min {
+                0
+            } else if per_unit > *max {
+                2
+            } else {
+                1
+            };
+            sample.positions[range][position] = 1;
+        }
+
+        let second = self.started.elapsed().saturating_sub(age).as_secs();
+        let windows = self.windows.len();
+        self.series
+            .entry((stream.to_string(), phase))
+            .or_insert_with(|| Series::new(windows))
+            .record(second, &sample);
+    }
+
+    pub fn update(&mut self, gauges: &Gauges, device: &str) {
+        let now = self.started.elapsed().as_secs();
+
+        for ((stream, phase), series) in self.series.iter_mut() {
+            series.expire(now, &self.windows);
+
+            for (window, totals) in self.windows.iter().zip(series.window_totals.iter()) {
+                if totals.samples == 0 {
+                    continue;
+                }
+                let window = humantime::format_duration(*window).to_string();
+                for (range, range_name) in RANGES.iter().enumerate() {
+                    for (position, position_name) in POSITIONS.iter().enumerate() {
+                        gauges
+                            .band_ratio
+                            .with_label_values(&[
+                                device,
+                                stream,
+                                phase,
+                                range_name,
+                                position_name,
+                                &window,
+                            ])
+                            .set(totals.positions[range][position] as f64 / totals.samples as f64);
+                    }
+                }
+            }
+        }
+    }
+}
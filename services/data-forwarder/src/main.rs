@@ -0,0 +1,273 @@
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+use health::{Health, HealthArgs, Source};
+use logging::LogArgs;
+use rumqttc::QoS;
+use service_config::Shared;
+use shutdown::Shutdown;
+use tracing::Instrument;
+use zmq_ingest::{
+    Backoff, HwmConfig, OverflowPolicy, ReaderThreadArgs, ReconnectArgs, SubscriberConfig,
+    SubscriberStream,
+};
+
+use crate::metrics::{FORWARDED, MQTT_CONNECTED, ZMQ_RECEIVE};
+use crate::mqtt::{MqttConfig, Publisher, TlsConfig};
+use crate::payload::{Encoder, PayloadFormat, TopicTemplate};
+
+mod metrics;
+mod mqtt;
+mod payload;
+
+/// How the shared sections of a --config file map onto data-forwarder's flags.
+const SHARED_SETTINGS: &[Shared] = &[
+    Shared {
+        section: "zmq",
+        key: "endpoint",
+        setting: "zmq-endpoint",
+    },
+    Shared {
+        section: "zmq",
+        key: "topic",
+        setting: "zmq-topic",
+    },
+    Shared {
+        section: "prometheus",
+        key: "port",
+        setting: "prometheus-port",
+    },
+];
+
+/// Subscribes to the calculation feed and republishes each stream's calculations to an MQTT
+/// broker, for SCADA systems that don't speak ZeroMQ.
+#[derive(Parser)]
+struct Args {
+    /// TOML or YAML file of settings shared with the rest of the pipeline, plus
+    /// data-forwarder's own flags under [forwarder]. Flags given on the command line take
+    /// precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// e.g. tcp://data-replay:5557, or ipc://PATH for a publisher on the same host
+    #[arg(long, value_parser = zmq_ingest::parse_endpoint)]
+    zmq_endpoint: String,
+    #[arg(long, default_value = "")]
+    zmq_topic: String,
+    /// Messages read ahead of the broker before --zmq-overflow-policy applies (0 for no
+    /// limit). Drops are counted in data_forwarder_zmq_dropped_messages_total.
+    #[arg(long, default_value_t = 1000)]
+    zmq_rcvhwm: usize,
+    /// Which message to discard when the receive queue is full
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropNewest)]
+    zmq_overflow_policy: OverflowPolicy,
+    #[command(flatten)]
+    reader_thread: ReaderThreadArgs,
+    #[command(flatten)]
+    reconnect: ReconnectArgs,
+    #[arg(long)]
+    mqtt_host: String,
+    #[arg(long, default_value_t = 1883)]
+    mqtt_port: u16,
+    /// Unique per broker: a second client with the same id disconnects the first
+    #[arg(long, default_value = "data-forwarder")]
+    mqtt_client_id: String,
+    #[arg(long, requires = "mqtt_password")]
+    mqtt_username: Option<String>,
+    #[arg(long, env = "MQTT_PASSWORD", hide_env_values = true)]
+    mqtt_password: Option<String>,
+    /// Topic each stream is published under; {device} and {stream} are replaced with the
+    /// stream's device (--device when it names none) and calculation name
+    #[arg(long, default_value = "karman/{device}/{stream}")]
+    mqtt_topic: String,
+    /// 0 (at most once), 1 (at least once) or 2 (exactly once)
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    mqtt_qos: u8,
+    /// Have the broker keep each topic's last message for new subscribers
+    #[arg(long)]
+    mqtt_retain: bool,
+    #[arg(long, value_enum, default_value_t = PayloadFormat::Json)]
+    payload: PayloadFormat,
+    /// Connect with TLS, checking the broker against --mqtt-ca-file or the system's roots
+    #[arg(long)]
+    mqtt_tls: bool,
+    /// PEM bundle of the CAs the broker's certificate may chain to
+    #[arg(long, requires = "mqtt_tls")]
+    mqtt_ca_file: Option<PathBuf>,
+    /// PEM certificate to authenticate to the broker with, with --mqtt-client-key
+    #[arg(long, requires_all = ["mqtt_client_key", "mqtt_ca_file"])]
+    mqtt_client_cert: Option<PathBuf>,
+    #[arg(long, requires = "mqtt_client_cert")]
+    mqtt_client_key: Option<PathBuf>,
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    mqtt_keep_alive: Duration,
+    /// Messages held for the broker while it is slow or away. Once this many are waiting the
+    /// subscription is not read, and --zmq-overflow-policy decides what gets lost.
+    #[arg(long, default_value_t = 1000)]
+    mqtt_queue: usize,
+    /// Longest wait before the first attempt to reconnect to the broker, doubled after every
+    /// failed attempt
+    #[arg(long, default_value = "500ms", value_parser = humantime::parse_duration)]
+    mqtt_reconnect_backoff: Duration,
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    mqtt_reconnect_max_backoff: Duration,
+    /// How long to wait on shutdown for the broker to take what is still queued
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    mqtt_drain_timeout: Duration,
+    /// Device for streams whose publisher doesn't name one
+    #[arg(long, default_value = "bibimbap")]
+    device: String,
+    /// Only forward this stream (repeatable); every stream when none are given
+    #[arg(long = "stream")]
+    streams: Vec<String>,
+    /// Serve /metrics, /healthz and /readyz on this port
+    #[arg(long)]
+    prometheus_port: Option<u16>,
+    #[command(flatten)]
+    health: HealthArgs,
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+impl Args {
+    fn subscriber(&self) -> SubscriberConfig {
+        SubscriberConfig {
+            endpoint: self.zmq_endpoint.clone(),
+            topic: self.zmq_topic.clone(),
+            hwm: HwmConfig {
+                hwm: self.zmq_rcvhwm,
+                on_full: self.zmq_overflow_policy,
+            },
+            reader_thread: self.reader_thread.reader_thread(),
+        }
+    }
+
+    fn mqtt(&self) -> MqttConfig {
+        MqttConfig {
+            host: self.mqtt_host.clone(),
+            port: self.mqtt_port,
+            client_id: self.mqtt_client_id.clone(),
+            credentials: self.mqtt_username.clone().zip(self.mqtt_password.clone()),
+            tls: self.mqtt_tls.then(|| TlsConfig {
+                ca_file: self.mqtt_ca_file.clone(),
+                client_auth: self
+                    .mqtt_client_cert
+                    .clone()
+                    .zip(self.mqtt_client_key.clone()),
+            }),
+            keep_alive: self.mqtt_keep_alive,
+            qos: match self.mqtt_qos {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => QoS::ExactlyOnce,
+            },
+            retain: self.mqtt_retain,
+            queue: self.mqtt_queue,
+            backoff: Backoff {
+                initial: self.mqtt_reconnect_backoff,
+                max: self.mqtt_reconnect_max_backoff,
+            },
+        }
+    }
+
+    fn encoder(&self) -> Result<Encoder> {
+        Ok(Encoder {
+            format: self.payload,
+            topic: TopicTemplate::parse(&self.mqtt_topic).context("Invalid --mqtt-topic")?,
+            device: self.device.clone(),
+            streams: self.streams.iter().cloned().collect::<HashSet<_>>(),
+        })
+    }
+}
+
+/// Forwards until shutdown is requested, then gives the broker what is still queued.
+async fn run(args: Args, health: Source, shutdown: &Shutdown) -> Result<()> {
+    let encoder = args.encoder()?;
+    let publisher = Publisher::start(args.mqtt())?;
+
+    // Connecting waits for the publisher to come up
+    let mut subscription = tokio::select! {
+        subscription = SubscriberStream::connect(args.subscriber(), &ZMQ_RECEIVE) => {
+            subscription.context("Could not subscribe")?
+        }
+        _ = shutdown.requested() => {
+            publisher.close(args.mqtt_drain_timeout).await;
+            return Ok(());
+        }
+    };
+    health.connected();
+    let backoff = args.reconnect.backoff();
+
+    'forwarding: loop {
+        let frame = tokio::select! {
+            frame = subscription.next() => frame,
+            _ = shutdown.requested() => break,
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(err) => {
+                log::error!("Subscription failed: {err}");
+                health.disconnected();
+                if !subscription.reconnect_with_backoff(backoff, shutdown).await {
+                    break;
+                }
+                health.connected();
+                continue;
+            }
+        };
+        health.message();
+
+        let span = frame.span;
+        let messages = span.in_scope(|| {
+            tracing::info_span!("transform").in_scope(|| encoder.messages(frame.joined))
+        });
+        for message in messages {
+            let sink = tracing::info_span!(parent: &span, "sink", stream = message.stream.as_str());
+            let published = tokio::select! {
+                published = publisher.publish(message.topic, message.payload).instrument(sink) => published,
+                _ = shutdown.requested() => break 'forwarding,
+            };
+            if !published {
+                subscription.close().await;
+                bail!("The MQTT client stopped");
+            }
+            FORWARDED.with_label_values(&[&message.stream]).inc();
+        }
+    }
+
+    subscription.close().await;
+    publisher.close(args.mqtt_drain_timeout).await;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Args = service_config::parse("forwarder", SHARED_SETTINGS);
+    logging::init(&args.log, "error");
+
+    let shutdown = match Shutdown::install() {
+        Ok(shutdown) => shutdown,
+        Err(err) => {
+            log::error!("Could not install signal handlers: {err:#}");
+            std::process::exit(1);
+        }
+    };
+
+    let health = Health::new(args.health);
+    let zmq = health.source("zmq");
+    health.check("mqtt", || async {
+        match MQTT_CONNECTED.get() {
+            1 => Ok(()),
+            _ => Err("not connected to the broker"),
+        }
+    });
+    if let Some(port) = args.prometheus_port {
+        tokio::spawn(metrics::serve(port, health));
+    }
+
+    if let Err(err) = run(args, zmq, &shutdown).await {
+        log::error!("{err:#}");
+        std::process::exit(255);
+    }
+    log::info!("Shut down cleanly");
+}
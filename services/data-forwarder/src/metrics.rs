@@ -0,0 +1,96 @@
+use std::sync::LazyLock;
+
+use axum::{
+    Router,
+    http::{HeaderMap, StatusCode},
+    routing::get,
+};
+use health::Health;
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, TextEncoder};
+use zmq_ingest::ReceiveMetrics;
+
+pub static FORWARDED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "data_forwarder_messages_forwarded_total",
+        "Stream messages handed to the MQTT client, by stream",
+        &["stream"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static PUBLISH_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_forwarder_publish_errors_total",
+        "Stream messages the MQTT client refused because it had stopped"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static MQTT_CONNECTED: LazyLock<IntGauge> = LazyLock::new(|| {
+    prometheus::register_int_gauge!(
+        "data_forwarder_mqtt_connected",
+        "1 while connected to the MQTT broker"
+    )
+    .expect("Unable to register gauge")
+});
+
+pub static MQTT_CONNECTION_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_forwarder_mqtt_connection_errors_total",
+        "Times the connection to the MQTT broker failed or could not be made"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static ZMQ_RECEIVE: LazyLock<ReceiveMetrics> = LazyLock::new(|| {
+    ReceiveMetrics::register("data_forwarder_").expect("Unable to register receive queue metrics")
+});
+
+async fn metrics_handler() -> (StatusCode, HeaderMap, String) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {:?}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            HeaderMap::new(),
+            String::new(),
+        );
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "Content-Type",
+        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+    );
+
+    match String::from_utf8(buffer) {
+        Ok(body) => (StatusCode::OK, headers, body),
+        Err(e) => {
+            log::error!("Failed to convert metrics to UTF8: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                String::new(),
+            )
+        }
+    }
+}
+
+pub async fn serve(port: u16, health: Health) {
+    let addr = format!("0.0.0.0:{}", port);
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .merge(health.router());
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .expect("Could not bind prometheus server");
+    log::info!(
+        "data-forwarder: Prometheus metrics server listening on {}",
+        addr
+    );
+
+    axum::serve(listener, app)
+        .await
+        .expect("Metrics server failed");
+}
@@ -0,0 +1,156 @@
+//! The connection to the MQTT broker. rumqttc queues what is published and sends it from its
+//! event loop, which runs on a task of its own and dials the broker again, after
+//! `Backoff::delay`, whenever the connection is lost. Messages at QoS 1 and 2 that the broker
+//! had not acknowledged are sent again once it is back; while it is away, up to `queue`
+//! messages wait for it, after which `publish` waits too and the ZeroMQ receive queue takes
+//! the overflow.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rumqttc::{
+    AsyncClient, ConnectionError, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS,
+    TlsConfiguration, Transport,
+};
+use tokio::task::JoinHandle;
+use zmq_ingest::Backoff;
+
+use crate::metrics::{MQTT_CONNECTED, MQTT_CONNECTION_ERRORS, PUBLISH_ERRORS};
+
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    pub tls: Option<TlsConfig>,
+    pub keep_alive: Duration,
+    pub qos: QoS,
+    pub retain: bool,
+    /// Messages waiting to be sent before `publish` waits
+    pub queue: usize,
+    pub backoff: Backoff,
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// PEM bundle the broker's certificate must chain to; the system's roots otherwise
+    pub ca_file: Option<PathBuf>,
+    /// PEM certificate and key to authenticate with
+    pub client_auth: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsConfig {
+    fn transport(&self) -> Result<Transport> {
+        let Some(ca_file) = &self.ca_file else {
+            if self.client_auth.is_some() {
+                anyhow::bail!("a client certificate needs --mqtt-ca-file too");
+            }
+            return Ok(Transport::tls_with_default_config());
+        };
+        let read = |path: &PathBuf| {
+            std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))
+        };
+        let client_auth = match &self.client_auth {
+            Some((cert, key)) => Some((read(cert)?, read(key)?)),
+            None => None,
+        };
+        Ok(Transport::tls_with_config(TlsConfiguration::Simple {
+            ca: read(ca_file)?,
+            alpn: None,
+            client_auth,
+        }))
+    }
+}
+
+pub struct Publisher {
+    client: AsyncClient,
+    qos: QoS,
+    retain: bool,
+    events: JoinHandle<()>,
+}
+
+impl Publisher {
+    /// Starts the event loop, which connects in the background.
+    pub fn start(config: MqttConfig) -> Result<Self> {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options
+            .set_keep_alive(config.keep_alive)
+            .set_request_channel_capacity(config.queue.max(1));
+        if let Some((username, password)) = &config.credentials {
+            options.set_credentials(username, password);
+        }
+        if let Some(tls) = &config.tls {
+            options.set_transport(tls.transport()?);
+        }
+        let (client, events) = AsyncClient::new(options, config.queue.max(1));
+        log::info!(
+            "Publishing to MQTT broker {}:{} as {}",
+            config.host,
+            config.port,
+            config.client_id
+        );
+        Ok(Self {
+            client,
+            qos: config.qos,
+            retain: config.retain,
+            events: tokio::spawn(drive(events, config.backoff)),
+        })
+    }
+
+    /// Queues a message, waiting while the queue is full. False once the client has stopped.
+    pub async fn publish(&self, topic: String, payload: Vec<u8>) -> bool {
+        match self
+            .client
+            .publish(topic, self.qos, self.retain, payload)
+            .await
+        {
+            Ok(()) => true,
+            Err(err) => {
+                PUBLISH_ERRORS.inc();
+                log::error!("Could not publish: {err}");
+                false
+            }
+        }
+    }
+
+    /// Disconnects once what is queued has been sent, giving up after `timeout`.
+    pub async fn close(self, timeout: Duration) {
+        if let Err(err) = self.client.disconnect().await {
+            log::warn!("Could not disconnect from the MQTT broker: {err}");
+        }
+        let mut events = self.events;
+        if tokio::time::timeout(timeout, &mut events).await.is_err() {
+            log::warn!("MQTT broker did not take the last messages within {timeout:?}");
+            events.abort();
+        }
+    }
+}
+
+/// Runs the event loop until the client disconnects.
+async fn drive(mut events: EventLoop, backoff: Backoff) {
+    let mut failures = 0;
+    loop {
+        match events.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                log::info!("Connected to the MQTT broker");
+                MQTT_CONNECTED.set(1);
+                failures = 0;
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(ConnectionError::RequestsDone) => {
+                MQTT_CONNECTED.set(0);
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                MQTT_CONNECTED.set(0);
+                MQTT_CONNECTION_ERRORS.inc();
+                let delay = backoff.delay(failures);
+                log::warn!("MQTT connection failed, reconnecting in {delay:?}: {err}");
+                failures = failures.saturating_add(1);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
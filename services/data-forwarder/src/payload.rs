@@ -0,0 +1,289 @@
+//! What is published for each stream of a frame, and under which MQTT topic.
+//!
+//! Every stream's calculations become a message of their own, so SCADA points can subscribe to
+//! one feeder (`karman/+/threephase/karman1`) or one device (`karman/feeder-3/#`). The JSON
+//! payload has the same names data-db stores in its `data` column, with the values the
+//! publisher left out omitted; the protobuf payload is a CompositeJoinedCalculations holding
+//! only that stream, so consumers of the ZeroMQ feed can decode it unchanged.
+
+use std::collections::HashSet;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, SecondsFormat};
+use prost::Message;
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeCalculations, CompositeJoinedCalculations, CompositeTwoPhaseCalculations,
+    composite_joined_calculations_wrapper::DataProduct,
+};
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PayloadFormat {
+    /// One JSON object per stream, with the calculations of each phase
+    #[default]
+    Json,
+    /// A CompositeJoinedCalculations with only the stream, as on the ZeroMQ feed
+    Protobuf,
+}
+
+/// An MQTT topic with `{device}` and `{stream}` filled in per message.
+#[derive(Clone, Debug)]
+pub struct TopicTemplate(String);
+
+impl TopicTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        if template.is_empty() {
+            bail!("the MQTT topic can't be empty");
+        }
+        if template.contains(['+', '#']) {
+            bail!("'{template}' has a wildcard, which can't be published to");
+        }
+        Ok(Self(template.to_string()))
+    }
+
+    /// Wildcards in the names would make the topic unpublishable, so they become `_`.
+    pub fn render(&self, device: &str, stream: &str) -> String {
+        let clean = |name: &str| name.replace(['+', '#'], "_");
+        self.0
+            .replace("{device}", &clean(device))
+            .replace("{stream}", &clean(stream))
+    }
+}
+
+pub struct Encoder {
+    pub format: PayloadFormat,
+    pub topic: TopicTemplate,
+    /// Device for streams that don't name one
+    pub device: String,
+    /// Only these streams are forwarded; all of them when empty
+    pub streams: HashSet<String>,
+}
+
+/// One stream's message.
+pub struct Outgoing {
+    pub stream: String,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+impl Encoder {
+    /// A message for every stream of the frame with calculations (FFTs are not forwarded).
+    pub fn messages(&self, joined: CompositeJoinedCalculations) -> Vec<Outgoing> {
+        joined
+            .calculations
+            .into_iter()
+            .filter_map(|wrapper| {
+                let stream = wrapper.calculation_name.clone()?;
+                let Some(DataProduct::Calculations(calcs)) = &wrapper.data_product else {
+                    return None;
+                };
+                if !self.streams.is_empty() && !self.streams.contains(&stream) {
+                    return None;
+                }
+                let device = wrapper.device_id.as_deref().unwrap_or(&self.device);
+                let topic = self.topic.render(device, &stream);
+                let payload = match self.format {
+                    PayloadFormat::Json => {
+                        serde_json::to_vec(&StreamMessage::new(device, &stream, calcs))
+                            .expect("Could not serialize")
+                    }
+                    PayloadFormat::Protobuf => CompositeJoinedCalculations {
+                        calculations: vec![wrapper],
+                    }
+                    .encode_to_vec(),
+                };
+                Some(Outgoing {
+                    stream,
+                    topic,
+                    payload,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct StreamMessage<'a> {
+    device: &'a str,
+    stream: &'a str,
+    /// When phase A was measured, by the publisher's clock (RFC 3339)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase_a: Option<Phase>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase_b: Option<Phase>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase_c: Option<Phase>,
+}
+
+impl<'a> StreamMessage<'a> {
+    fn new(device: &'a str, stream: &'a str, calcs: &CompositeTwoPhaseCalculations) -> Self {
+        let provenance = [calcs.phase_a, calcs.phase_b, calcs.phase_c]
+            .iter()
+            .flatten()
+            .find_map(|phase| phase.provenance);
+        let time = provenance
+            .and_then(|provenance| provenance.utc_time)
+            .and_then(|time| DateTime::from_timestamp(time.seconds, time.nanos.try_into().ok()?))
+            .map(|time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true));
+        Self {
+            device,
+            stream,
+            time,
+            sequence: provenance.and_then(|provenance| provenance.generic_sequence_number),
+            phase_a: calcs.phase_a.as_ref().map(Phase::from),
+            phase_b: calcs.phase_b.as_ref().map(Phase::from),
+            phase_c: calcs.phase_c.as_ref().map(Phase::from),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Phase {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rms_voltage: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dc_offset_voltage: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crest_factor_voltage: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thd_voltage: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rms_current: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dc_offset_current: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crest_factor_current: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thd_current: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    real_power: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    apparent_power: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reactive_power: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power_factor: Option<f32>,
+}
+
+impl From<&CompositeCalculations> for Phase {
+    fn from(calcs: &CompositeCalculations) -> Self {
+        let voltage = calcs.voltage_waveform_calculations_v.unwrap_or_default();
+        let current = calcs.current_waveform_calculations_a.unwrap_or_default();
+        let power = calcs.power_calculations.unwrap_or_default();
+        Self {
+            rms_voltage: voltage.rms,
+            dc_offset_voltage: voltage.dc_offset,
+            crest_factor_voltage: voltage.crest_factor,
+            thd_voltage: voltage.thd_percent,
+            rms_current: current.rms,
+            dc_offset_current: current.dc_offset,
+            crest_factor_current: current.crest_factor,
+            thd_current: current.thd_percent,
+            real_power: power.real_power_w,
+            apparent_power: power.apparent_power_va,
+            reactive_power: power.reactive_power_var,
+            power_factor: power.power_factor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf_rs::utilidata::karman::bibimbap::v1::{
+        CompositeJoinedCalculationsWrapper, Fft, PowerCalculations, WaveformCalculations,
+    };
+
+    use super::*;
+
+    fn stream(name: &str, device: Option<&str>) -> CompositeJoinedCalculationsWrapper {
+        let phase = CompositeCalculations {
+            voltage_waveform_calculations_v: Some(WaveformCalculations {
+                rms: Some(240.0),
+                ..Default::default()
+            }),
+            power_calculations: Some(PowerCalculations {
+                real_power_w: Some(1200.0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        CompositeJoinedCalculationsWrapper {
+            calculation_name: Some(name.to_string()),
+            data_product: Some(DataProduct::Calculations(CompositeTwoPhaseCalculations {
+                phase_a: Some(phase),
+                phase_b: Some(phase),
+                phase_c: None,
+            })),
+            device_id: device.map(str::to_string),
+            harmonics: None,
+        }
+    }
+
+    fn encoder(format: PayloadFormat, streams: &[&str]) -> Encoder {
+        Encoder {
+            format,
+            topic: TopicTemplate::parse("karman/{device}/{stream}").unwrap(),
+            device: "bibimbap".to_string(),
+            streams: streams.iter().map(|stream| stream.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn topics_name_the_device_and_stream() {
+        let template = TopicTemplate::parse("karman/{device}/{stream}").unwrap();
+        assert_eq!(
+            template.render("feeder-3", "threephase/karman1"),
+            "karman/feeder-3/threephase/karman1"
+        );
+        assert_eq!(template.render("a+b", "c#"), "karman/a_b/c_");
+        assert!(TopicTemplate::parse("karman/+/{stream}").is_err());
+        assert!(TopicTemplate::parse("").is_err());
+    }
+
+    #[test]
+    fn each_stream_is_a_message_of_its_own() {
+        let joined = CompositeJoinedCalculations {
+            calculations: vec![
+                stream("karman1", Some("feeder-3")),
+                stream("karman2", None),
+                CompositeJoinedCalculationsWrapper {
+                    calculation_name: Some("fft".to_string()),
+                    data_product: Some(DataProduct::Fft(Fft::default())),
+                    device_id: None,
+                    harmonics: None,
+                },
+            ],
+        };
+        let messages = encoder(PayloadFormat::Json, &[]).messages(joined);
+        let topics: Vec<_> = messages
+            .iter()
+            .map(|message| message.topic.as_str())
+            .collect();
+        assert_eq!(
+            topics,
+            ["karman/feeder-3/karman1", "karman/bibimbap/karman2"]
+        );
+
+        let json: serde_json::Value = serde_json::from_slice(&messages[0].payload).unwrap();
+        assert_eq!(json["device"], "feeder-3");
+        assert_eq!(json["phase_a"]["rms_voltage"], 240.0);
+        assert_eq!(json["phase_b"]["real_power"], 1200.0);
+        assert!(json["phase_a"].get("rms_current").is_none());
+        assert!(json.get("phase_c").is_none());
+    }
+
+    #[test]
+    fn protobuf_payloads_carry_only_their_stream() {
+        let joined = CompositeJoinedCalculations {
+            calculations: vec![stream("karman1", None), stream("karman2", None)],
+        };
+        let messages = encoder(PayloadFormat::Protobuf, &["karman2"]).messages(joined);
+        assert_eq!(messages.len(), 1);
+        let decoded = CompositeJoinedCalculations::decode(&messages[0].payload[..]).unwrap();
+        assert_eq!(decoded.calculations, vec![stream("karman2", None)]);
+    }
+}
@@ -0,0 +1,92 @@
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+
+/// A simulated device clock. Publishing still follows the host clock, but the
+/// provenance timestamps stamped onto each frame run fast/slow by `drift_ppm`
+/// and jump by the configured step changes, the way a badly disciplined meter
+/// clock would.
+pub struct DeviceClock {
+    drift_ppm: f64,
+    steps: Vec<ClockStep>,
+}
+
+/// At frame `frame`, the clock jumps by `offset_ms` (cumulative with earlier steps).
+struct ClockStep {
+    frame: usize,
+    offset_ms: i64,
+}
+
+impl DeviceClock {
+    /// CLOCK_DRIFT_PPM: frequency error in parts per million (e.g. `50` or `-20`).
+    /// CLOCK_STEPS: comma separated `frame:offset_ms` pairs, e.g. `600:250,1800:-1000`.
+    pub fn from_env() -> Result<Self> {
+        let drift_ppm = match env::var("CLOCK_DRIFT_PPM") {
+            Ok(value) => value.parse().context("Invalid CLOCK_DRIFT_PPM")?,
+            Err(_) => 0.0,
+        };
+        let steps = match env::var("CLOCK_STEPS") {
+            Ok(value) => parse_steps(&value)?,
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Self { drift_ppm, steps })
+    }
+
+    pub fn is_ideal(&self) -> bool {
+        self.drift_ppm == 0.0 && self.steps.is_empty()
+    }
+
+    pub fn describe(&self) -> String {
+        let steps = self
+            .steps
+            .iter()
+            .map(|step| format!("{}ms@frame{}", step.offset_ms, step.frame))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("drift {} ppm, steps [{}]", self.drift_ppm, steps)
+    }
+
+    /// The timestamp the simulated device would report for `frame`, published
+    /// `elapsed` after `start` on the host clock.
+    pub fn timestamp(&self, start: SystemTime, elapsed: Duration, frame: usize) -> prost_types::Timestamp {
+        let start_ns = start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i128;
+
+        let drifted_ns = (elapsed.as_nanos() as f64 * (1.0 + self.drift_ppm / 1_000_000.0)) as i128;
+
+        let step_ns: i128 = self
+            .steps
+            .iter()
+            .filter(|step| step.frame <= frame)
+            .map(|step| step.offset_ms as i128 * 1_000_000)
+            .sum();
+
+        let total_ns = start_ns + drifted_ns + step_ns;
+        prost_types::Timestamp {
+            seconds: total_ns.div_euclid(1_000_000_000) as i64,
+            nanos: total_ns.rem_euclid(1_000_000_000) as i32,
+        }
+    }
+}
+
+fn parse_steps(value: &str) -> Result<Vec<ClockStep>> {
+    let mut steps = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (frame, offset_ms) = entry
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid CLOCK_STEPS entry '{}', expected frame:offset_ms", entry))?;
+        steps.push(ClockStep {
+            frame: frame
+                .parse()
+                .with_context(|| format!("Invalid frame in CLOCK_STEPS entry '{}'", entry))?,
+            offset_ms: offset_ms
+                .parse()
+                .with_context(|| format!("Invalid offset in CLOCK_STEPS entry '{}'", entry))?,
+        });
+    }
+    Ok(steps)
+}
@@ -0,0 +1,150 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::{extract::State, http::StatusCode, routing::get, Extension, Json, Router};
+use http_auth::{Auth, Principal};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::dataset::{self, DatasetSummary};
+
+/// Progress of the replay currently running, shared with the control API.
+#[derive(Default)]
+pub struct ReplayStatus {
+    pub dataset: Mutex<String>,
+    pub frames_total: AtomicUsize,
+    pub frames_published: AtomicUsize,
+    /// Passes through the selected frames finished, which keeps rising with LOOP
+    pub laps: AtomicUsize,
+}
+
+#[derive(Clone)]
+pub struct ControlState {
+    pub datasets_dir: Option<PathBuf>,
+    pub selection: watch::Sender<PathBuf>,
+    pub status: Arc<ReplayStatus>,
+    pub auth: Auth,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    dataset: String,
+    frames_total: usize,
+    frames_published: usize,
+    laps: usize,
+}
+
+#[derive(Deserialize)]
+struct SelectRequest {
+    /// File name as listed by /datasets
+    dataset: String,
+}
+
+type ApiError = (StatusCode, String);
+
+async fn list_datasets(
+    State(state): State<ControlState>,
+) -> Result<Json<Vec<DatasetSummary>>, ApiError> {
+    let dir = state.datasets_dir.ok_or((
+        StatusCode::NOT_FOUND,
+        "No datasets directory configured (set DATASETS_DIR)".to_string(),
+    ))?;
+
+    // Summaries parse every file in full, keep that off the runtime threads
+    let summaries = tokio::task::spawn_blocking(move || {
+        dataset_paths(&dir)
+            .into_iter()
+            .filter_map(|path| match dataset::summarize(&path) {
+                Ok(summary) => Some(summary),
+                Err(err) => {
+                    log::warn!("Skipping unreadable dataset {}: {err:#}", path.display());
+                    None
+                }
+            })
+            .collect()
+    })
+    .await
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    Ok(Json(summaries))
+}
+
+async fn replay_status(State(state): State<ControlState>) -> Json<StatusResponse> {
+    Json(StatusResponse {
+        dataset: state.status.dataset.lock().unwrap().clone(),
+        frames_total: state.status.frames_total.load(Ordering::Relaxed),
+        frames_published: state.status.frames_published.load(Ordering::Relaxed),
+        laps: state.status.laps.load(Ordering::Relaxed),
+    })
+}
+
+async fn select_dataset(
+    State(state): State<ControlState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<SelectRequest>,
+) -> Result<StatusCode, ApiError> {
+    let dir = state.datasets_dir.ok_or((
+        StatusCode::NOT_FOUND,
+        "No datasets directory configured (set DATASETS_DIR)".to_string(),
+    ))?;
+
+    // Only accept names from the catalog so the API can't be pointed at arbitrary paths
+    let path = dataset_paths(&dir)
+        .into_iter()
+        .find(|path| {
+            path.file_name()
+                .is_some_and(|name| name == request.dataset.as_str())
+        })
+        .ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Unknown dataset: {}", request.dataset),
+        ))?;
+
+    log::info!("Control API selected dataset {}", path.display());
+    state.selection.send_replace(path);
+    state
+        .auth
+        .audit(
+            &principal,
+            "select_dataset",
+            serde_json::json!({ "dataset": request.dataset }),
+        )
+        .await;
+    Ok(StatusCode::ACCEPTED)
+}
+
+fn dataset_paths(dir: &PathBuf) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::error!("Could not read datasets directory {}: {err}", dir.display());
+            return Vec::new();
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| dataset::is_dataset(path))
+        .collect();
+    paths.sort();
+    paths
+}
+
+pub async fn serve(port: u16, state: ControlState) {
+    let addr = format!("0.0.0.0:{}", port);
+    let app = state.auth.protect(
+        Router::new()
+            .route("/datasets", get(list_datasets))
+            .route("/replay", get(replay_status).post(select_dataset)),
+    );
+    let app = app.with_state(state);
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .expect("Could not bind control API");
+    log::info!("data-replay: control API listening on {}", addr);
+
+    axum::serve(listener, app)
+        .await
+        .expect("Control API failed");
+}
@@ -0,0 +1,594 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DataType, FloatType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::SerializedFileReader;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::record::{Field, Row};
+use parquet::schema::parser::parse_message_type;
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, time_sync::Source, CompositeCalculations,
+    CompositeJoinedCalculations, CompositeJoinedCalculationsWrapper, CompositeTwoPhaseCalculations,
+    PowerCalculations, Provenance, TimeSync, WaveformCalculations,
+};
+use serde::{Deserialize, Serialize};
+
+/// One stream and phase at one timestamp. CSV, JSON-lines and Parquet datasets all have these
+/// columns, by name.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DatasetRow {
+    time: i64, // Milliseconds since epoch
+    stream_name: String,
+    phase: String,
+    rms_voltage: f32,
+    dc_offset_voltage: f32,
+    rms_current: f32,
+    dc_offset_current: f32,
+    real_power: f32,
+    apparent_power: f32,
+    reactive_power: f32,
+    power_factor: f32,
+    sequence_number: Option<u64>,
+    /// Optional clock status columns, replayed as Provenance.time_sync when present
+    time_source: Option<String>,
+    clock_locked: Option<bool>,
+    clock_offset_ns: Option<i64>,
+    clock_max_error_ns: Option<u64>,
+    /// Optional extended waveform statistics
+    crest_factor_voltage: Option<f32>,
+    thd_percent_voltage: Option<f32>,
+    crest_factor_current: Option<f32>,
+    thd_percent_current: Option<f32>,
+}
+
+/// What the catalog reports about a dataset file without loading it for replay.
+#[derive(Debug, Serialize)]
+pub struct DatasetSummary {
+    pub name: String,
+    pub rows: u64,
+    pub frames: u64,
+    /// First and last row time, milliseconds since epoch
+    pub start_ms: Option<i64>,
+    pub end_ms: Option<i64>,
+    pub duration_seconds: f64,
+    pub streams: BTreeSet<String>,
+}
+
+/// One timestamp's rows, ready to publish.
+pub struct Frame {
+    /// Row time, milliseconds since epoch
+    pub time_ms: i64,
+    pub joined: CompositeJoinedCalculations,
+}
+
+/// How a dataset file is encoded, going by its extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Csv,
+    JsonLines,
+    Parquet,
+}
+
+impl Format {
+    fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "csv" => Some(Self::Csv),
+            "jsonl" | "ndjson" => Some(Self::JsonLines),
+            "parquet" => Some(Self::Parquet),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `path` is in a format data-replay reads.
+pub fn is_dataset(path: &Path) -> bool {
+    Format::of(path).is_some()
+}
+
+type Rows = Box<dyn Iterator<Item = Result<DatasetRow>>>;
+
+/// The rows of a dataset file, in file order. Anything that isn't JSON-lines or Parquet is read
+/// as CSV, as before other formats were supported.
+fn read_rows(path: &Path) -> Result<Rows> {
+    let file = File::open(path).context("Could not open dataset file")?;
+    match Format::of(path).unwrap_or(Format::Csv) {
+        Format::Csv => Ok(Box::new(
+            csv::Reader::from_reader(file)
+                .into_deserialize()
+                .map(|row| row.context("Failed to parse CSV row")),
+        )),
+        Format::JsonLines => Ok(Box::new(
+            BufReader::new(file)
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+                .map(|(number, line)| {
+                    let line = line.context("Could not read dataset file")?;
+                    serde_json::from_str(&line)
+                        .with_context(|| format!("Failed to parse JSON line {}", number + 1))
+                }),
+        )),
+        Format::Parquet => {
+            let reader = SerializedFileReader::new(file).context("Could not read Parquet file")?;
+            Ok(Box::new(reader.into_iter().map(|row| {
+                parquet_row(row.context("Failed to read Parquet row")?)
+            })))
+        }
+    }
+}
+
+/// Maps a Parquet row onto the dataset columns by name. Floats keep their stored precision,
+/// where a CSV export would have rounded them to text.
+fn parquet_row(row: Row) -> Result<DatasetRow> {
+    let mut object = serde_json::Map::new();
+    for (name, field) in row.get_column_iter() {
+        let value = match field {
+            // Otherwise these come out as date strings; rows carry milliseconds since epoch
+            Field::TimestampMillis(ms) => (*ms).into(),
+            Field::TimestampMicros(us) => us.div_euclid(1000).into(),
+            field => field.to_json_value(),
+        };
+        object.insert(name.clone(), value);
+    }
+    serde_json::from_value(object.into()).context("Failed to parse Parquet row")
+}
+
+pub fn load_frames(path: &Path) -> Result<Vec<Frame>> {
+    log::info!("Reading dataset from: {}", path.display());
+
+    // Group rows by timestamp into frames (each timestamp has 6 rows: 3 streams × 2 phases, or
+    // 9 from three-phase meters)
+    let mut frames = Vec::new();
+    // Ordered by stream name so a frame encodes the same way on every run
+    let mut current_frame: BTreeMap<String, [Option<DatasetRow>; 3]> = BTreeMap::new();
+    let mut last_timestamp: Option<i64> = None;
+    let mut frame_count = 0u64;
+
+    for row in read_rows(path)? {
+        let row = row?;
+
+        // New timestamp = new frame (timestamps are in milliseconds)
+        if let Some(last) =
+            last_timestamp.filter(|last| row.time != *last && !current_frame.is_empty())
+        {
+            frames.push(Frame {
+                time_ms: last,
+                joined: build_frame(&current_frame, frame_count)?,
+            });
+            frame_count += 1;
+            current_frame.clear();
+        }
+        last_timestamp = Some(row.time);
+
+        // Group the phases of each stream
+        let entry = current_frame
+            .entry(row.stream_name.clone())
+            .or_insert([None, None, None]);
+        match row.phase.as_str() {
+            "phase_a" => entry[0] = Some(row),
+            "phase_b" => entry[1] = Some(row),
+            "phase_c" => entry[2] = Some(row),
+            _ => log::warn!("Unknown phase: {}", row.phase),
+        }
+    }
+
+    // Last frame
+    if let Some(last) = last_timestamp.filter(|_| !current_frame.is_empty()) {
+        frames.push(Frame {
+            time_ms: last,
+            joined: build_frame(&current_frame, frame_count)?,
+        });
+    }
+
+    log::info!("Loaded {} frames from {}", frames.len(), path.display());
+    Ok(frames)
+}
+
+pub fn summarize(path: &Path) -> Result<DatasetSummary> {
+    let mut rows = 0;
+    let mut frames = 0;
+    let mut start_ms: Option<i64> = None;
+    let mut end_ms: Option<i64> = None;
+    let mut last_timestamp = None;
+    let mut streams = BTreeSet::new();
+
+    for row in read_rows(path)? {
+        let row = row?;
+        rows += 1;
+        if last_timestamp != Some(row.time) {
+            frames += 1;
+            last_timestamp = Some(row.time);
+        }
+        start_ms = Some(start_ms.map_or(row.time, |start| start.min(row.time)));
+        end_ms = Some(end_ms.map_or(row.time, |end| end.max(row.time)));
+        streams.insert(row.stream_name);
+    }
+
+    let duration_seconds = match (start_ms, end_ms) {
+        (Some(start), Some(end)) => (end - start) as f64 / 1000.0,
+        _ => 0.0,
+    };
+
+    Ok(DatasetSummary {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        rows,
+        frames,
+        start_ms,
+        end_ms,
+        duration_seconds,
+        streams,
+    })
+}
+
+/// How far sequence numbers advance over `frames`: one past the highest minus the lowest. A
+/// looped replay adds this once per lap, so the numbers keep rising where the last lap ended.
+pub fn sequence_span(frames: &[Frame]) -> u64 {
+    let sequences = frames
+        .iter()
+        .flat_map(|frame| frame.joined.calculations.iter())
+        .filter_map(|calc| match &calc.data_product {
+            Some(DataProduct::Calculations(two_phase)) => Some(two_phase),
+            _ => None,
+        })
+        .flat_map(|calcs| [&calcs.phase_a, &calcs.phase_b, &calcs.phase_c])
+        .flatten()
+        .filter_map(|phase| phase.provenance.as_ref()?.generic_sequence_number);
+
+    let (min, max) = sequences.fold((u64::MAX, 0), |(min, max), sequence| {
+        (min.min(sequence), max.max(sequence))
+    });
+    if min > max {
+        return 0;
+    }
+    max - min + 1
+}
+
+fn build_frame(
+    frame_data: &BTreeMap<String, [Option<DatasetRow>; 3]>,
+    sequence: u64,
+) -> Result<CompositeJoinedCalculations> {
+    let mut calculations = Vec::new();
+
+    for (stream_name, [phase_a, phase_b, phase_c]) in frame_data.iter() {
+        let Some(row_a) = phase_a else { continue };
+
+        // If phase_b is missing from the dataset, duplicate phase_a to satisfy protobuf structure.
+        // Dashboards only display data fromphase_a. To reduce CSV file size by ~50%, we only
+        // export phase_a and duplicate it here. Downstream services (data-exporter and
+        // data-db) expect both fields and use .unwrap(), so we populate both.
+        let row_b = phase_b.as_ref().unwrap_or(row_a);
+
+        let calc_name = format!("threephase/{}", stream_name);
+
+        let composite = CompositeTwoPhaseCalculations {
+            phase_a: Some(build_composite(row_a, sequence)),
+            phase_b: Some(build_composite(row_b, sequence)),
+            // Only three-phase meters have one; it isn't made up like phase_b
+            phase_c: phase_c
+                .as_ref()
+                .map(|row_c| build_composite(row_c, sequence)),
+        };
+
+        calculations.push(CompositeJoinedCalculationsWrapper {
+            calculation_name: Some(calc_name),
+            data_product: Some(DataProduct::Calculations(composite)),
+            device_id: None,
+            harmonics: None,
+        });
+    }
+
+    Ok(CompositeJoinedCalculations { calculations })
+}
+
+/// A frame's rows, as `load_frames` reads them back: one per stream and phase, under the
+/// frame's earliest provenance time, or `received_ms` if it carries none. Stream names lose
+/// the `threephase/` that replaying puts back; FFTs are left out, and values the publisher
+/// left out are NaN.
+pub fn frame_rows(joined: &CompositeJoinedCalculations, received_ms: i64) -> Vec<DatasetRow> {
+    let phases = |calcs: &CompositeTwoPhaseCalculations| {
+        [
+            ("phase_a", calcs.phase_a),
+            ("phase_b", calcs.phase_b),
+            ("phase_c", calcs.phase_c),
+        ]
+        .into_iter()
+        .filter_map(|(phase, calcs)| Some((phase, calcs?)))
+    };
+    let streams: Vec<_> = joined
+        .calculations
+        .iter()
+        .filter_map(|wrapper| match &wrapper.data_product {
+            Some(DataProduct::Calculations(calcs)) => {
+                Some((wrapper.calculation_name.as_deref()?, calcs))
+            }
+            _ => None,
+        })
+        .collect();
+    let time = streams
+        .iter()
+        .flat_map(|(_, calcs)| phases(calcs))
+        .filter_map(|(_, phase)| phase.provenance?.utc_time)
+        .filter_map(|time| {
+            chrono::DateTime::from_timestamp(time.seconds, time.nanos.try_into().ok()?)
+        })
+        .map(|time| time.timestamp_millis())
+        .min()
+        .unwrap_or(received_ms);
+
+    let mut rows = Vec::new();
+    for (name, calcs) in streams {
+        let stream_name = name.strip_prefix("threephase/").unwrap_or(name);
+        for (phase, calcs) in phases(calcs) {
+            let voltage = calcs.voltage_waveform_calculations_v.unwrap_or_default();
+            let current = calcs.current_waveform_calculations_a.unwrap_or_default();
+            let power = calcs.power_calculations.unwrap_or_default();
+            let provenance = calcs.provenance.unwrap_or_default();
+            let clock = provenance.time_sync.unwrap_or_default();
+            rows.push(DatasetRow {
+                time,
+                stream_name: stream_name.to_string(),
+                phase: phase.to_string(),
+                rms_voltage: voltage.rms.unwrap_or(f32::NAN),
+                dc_offset_voltage: voltage.dc_offset.unwrap_or(f32::NAN),
+                rms_current: current.rms.unwrap_or(f32::NAN),
+                dc_offset_current: current.dc_offset.unwrap_or(f32::NAN),
+                real_power: power.real_power_w.unwrap_or(f32::NAN),
+                apparent_power: power.apparent_power_va.unwrap_or(f32::NAN),
+                reactive_power: power.reactive_power_var.unwrap_or(f32::NAN),
+                power_factor: power.power_factor.unwrap_or(f32::NAN),
+                sequence_number: provenance.generic_sequence_number,
+                time_source: clock.source.and_then(|source| {
+                    match Source::try_from(source).ok()? {
+                        Source::FreeRunning => Some("free_running".to_string()),
+                        Source::Ntp => Some("ntp".to_string()),
+                        Source::Ptp => Some("ptp".to_string()),
+                        Source::Gps => Some("gps".to_string()),
+                        Source::Unspecified => None,
+                    }
+                }),
+                clock_locked: clock.locked,
+                clock_offset_ns: clock.offset_ns,
+                clock_max_error_ns: clock.max_error_ns,
+                crest_factor_voltage: voltage.crest_factor,
+                thd_percent_voltage: voltage.thd_percent,
+                crest_factor_current: current.crest_factor,
+                thd_percent_current: current.thd_percent,
+            });
+        }
+    }
+    rows
+}
+
+/// Writes rows to a new dataset file, as CSV or, going by the extension, Parquet.
+pub enum DatasetWriter {
+    Csv(csv::Writer<File>),
+    /// Rows are buffered into row groups of `PARQUET_ROW_GROUP` rows
+    Parquet(SerializedFileWriter<File>, Vec<DatasetRow>),
+}
+
+const PARQUET_ROW_GROUP: usize = 60_000;
+
+/// The Parquet columns, in `DatasetRow` order. All are optional so `columns` can treat them
+/// alike, but the ones `DatasetRow` requires are always written.
+const PARQUET_SCHEMA: &str = "
+    message dataset {
+        OPTIONAL INT64 time (TIMESTAMP(MILLIS, true));
+        OPTIONAL BYTE_ARRAY stream_name (UTF8);
+        OPTIONAL BYTE_ARRAY phase (UTF8);
+        OPTIONAL FLOAT rms_voltage;
+        OPTIONAL FLOAT dc_offset_voltage;
+        OPTIONAL FLOAT rms_current;
+        OPTIONAL FLOAT dc_offset_current;
+        OPTIONAL FLOAT real_power;
+        OPTIONAL FLOAT apparent_power;
+        OPTIONAL FLOAT reactive_power;
+        OPTIONAL FLOAT power_factor;
+        OPTIONAL INT64 sequence_number (INTEGER(64, false));
+        OPTIONAL BYTE_ARRAY time_source (UTF8);
+        OPTIONAL BOOLEAN clock_locked;
+        OPTIONAL INT64 clock_offset_ns;
+        OPTIONAL INT64 clock_max_error_ns (INTEGER(64, false));
+        OPTIONAL FLOAT crest_factor_voltage;
+        OPTIONAL FLOAT thd_percent_voltage;
+        OPTIONAL FLOAT crest_factor_current;
+        OPTIONAL FLOAT thd_percent_current;
+    }
+";
+
+/// One Parquet column of a row group.
+enum Column {
+    Long(Vec<Option<i64>>),
+    Float(Vec<Option<f32>>),
+    Bool(Vec<Option<bool>>),
+    Text(Vec<Option<String>>),
+}
+
+impl DatasetWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let format = Format::of(path).unwrap_or(Format::Csv);
+        if format == Format::JsonLines {
+            bail!("Datasets are recorded as CSV or Parquet, not JSON-lines");
+        }
+        let file = File::create(path)
+            .with_context(|| format!("Could not create dataset file {}", path.display()))?;
+        if format == Format::Csv {
+            return Ok(Self::Csv(csv::Writer::from_writer(file)));
+        }
+        let schema =
+            Arc::new(parse_message_type(PARQUET_SCHEMA).context("Invalid Parquet schema")?);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build();
+        let writer = SerializedFileWriter::new(file, schema, Arc::new(properties))
+            .context("Could not start the Parquet file")?;
+        Ok(Self::Parquet(writer, Vec::with_capacity(PARQUET_ROW_GROUP)))
+    }
+
+    pub fn write(&mut self, rows: Vec<DatasetRow>) -> Result<()> {
+        match self {
+            Self::Csv(writer) => {
+                for row in rows {
+                    writer.serialize(row).context("Could not write CSV row")?;
+                }
+            }
+            Self::Parquet(writer, buffered) => {
+                buffered.extend(rows);
+                if buffered.len() >= PARQUET_ROW_GROUP {
+                    write_row_group(writer, std::mem::take(buffered))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes what is buffered and, for Parquet, the footer the file can't be read without.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Self::Csv(mut writer) => writer.flush().context("Could not write CSV file"),
+            Self::Parquet(mut writer, buffered) => {
+                if !buffered.is_empty() {
+                    write_row_group(&mut writer, buffered)?;
+                }
+                writer
+                    .close()
+                    .context("Could not finish the Parquet file")?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn write_row_group(writer: &mut SerializedFileWriter<File>, rows: Vec<DatasetRow>) -> Result<()> {
+    let mut group = writer
+        .next_row_group()
+        .context("Could not start a Parquet row group")?;
+    let mut columns = columns(rows).into_iter();
+    while let Some(mut column) = group.next_column()? {
+        match columns.next().context("More Parquet columns than values")? {
+            Column::Long(values) => write_column::<Int64Type>(&mut column, values)?,
+            Column::Float(values) => write_column::<FloatType>(&mut column, values)?,
+            Column::Bool(values) => write_column::<BoolType>(&mut column, values)?,
+            Column::Text(values) => write_column::<ByteArrayType>(
+                &mut column,
+                values
+                    .into_iter()
+                    .map(|value| value.map(|text| ByteArray::from(text.into_bytes())))
+                    .collect(),
+            )?,
+        }
+        column.close()?;
+    }
+    group
+        .close()
+        .context("Could not write a Parquet row group")?;
+    Ok(())
+}
+
+fn write_column<T: DataType>(
+    column: &mut SerializedColumnWriter<'_>,
+    values: Vec<Option<T::T>>,
+) -> Result<()> {
+    let levels: Vec<i16> = values.iter().map(|value| value.is_some().into()).collect();
+    let present: Vec<T::T> = values.into_iter().flatten().collect();
+    column
+        .typed::<T>()
+        .write_batch(&present, Some(&levels), None)?;
+    Ok(())
+}
+
+/// `rows` split into columns, in the order of `PARQUET_SCHEMA`.
+fn columns(rows: Vec<DatasetRow>) -> Vec<Column> {
+    let long =
+        |value: fn(&DatasetRow) -> Option<i64>| Column::Long(rows.iter().map(value).collect());
+    let float =
+        |value: fn(&DatasetRow) -> Option<f32>| Column::Float(rows.iter().map(value).collect());
+    let text =
+        |value: fn(&DatasetRow) -> Option<String>| Column::Text(rows.iter().map(value).collect());
+    vec![
+        long(|row| Some(row.time)),
+        text(|row| Some(row.stream_name.clone())),
+        text(|row| Some(row.phase.clone())),
+        float(|row| Some(row.rms_voltage)),
+        float(|row| Some(row.dc_offset_voltage)),
+        float(|row| Some(row.rms_current)),
+        float(|row| Some(row.dc_offset_current)),
+        float(|row| Some(row.real_power)),
+        float(|row| Some(row.apparent_power)),
+        float(|row| Some(row.reactive_power)),
+        float(|row| Some(row.power_factor)),
+        long(|row| row.sequence_number.map(|sequence| sequence as i64)),
+        text(|row| row.time_source.clone()),
+        Column::Bool(rows.iter().map(|row| row.clock_locked).collect()),
+        long(|row| row.clock_offset_ns),
+        long(|row| row.clock_max_error_ns.map(|error| error as i64)),
+        float(|row| row.crest_factor_voltage),
+        float(|row| row.thd_percent_voltage),
+        float(|row| row.crest_factor_current),
+        float(|row| row.thd_percent_current),
+    ]
+}
+
+fn time_sync(row: &DatasetRow) -> Option<TimeSync> {
+    if row.time_source.is_none()
+        && row.clock_locked.is_none()
+        && row.clock_offset_ns.is_none()
+        && row.clock_max_error_ns.is_none()
+    {
+        return None;
+    }
+
+    let source = match row.time_source.as_deref() {
+        Some("free_running") => Source::FreeRunning,
+        Some("ntp") => Source::Ntp,
+        Some("ptp") => Source::Ptp,
+        Some("gps") => Source::Gps,
+        _ => Source::Unspecified,
+    };
+    Some(TimeSync {
+        source: Some(source as i32),
+        locked: row.clock_locked,
+        offset_ns: row.clock_offset_ns,
+        max_error_ns: row.clock_max_error_ns,
+    })
+}
+
+fn build_composite(row: &DatasetRow, sequence: u64) -> CompositeCalculations {
+    CompositeCalculations {
+        provenance: Some(Provenance {
+            utc_time: Some(prost_types::Timestamp {
+                seconds: 0, // Will be overwritten at publish time
+                nanos: 0,
+            }),
+            generic_sequence_number: row.sequence_number.or(Some(sequence)),
+            time_sync: time_sync(row),
+        }),
+        voltage_waveform_calculations_v: Some(WaveformCalculations {
+            rms: Some(row.rms_voltage),
+            dc_offset: Some(row.dc_offset_voltage),
+            crest_factor: row.crest_factor_voltage,
+            thd_percent: row.thd_percent_voltage,
+        }),
+        current_waveform_calculations_a: Some(WaveformCalculations {
+            rms: Some(row.rms_current),
+            dc_offset: Some(row.dc_offset_current),
+            crest_factor: row.crest_factor_current,
+            thd_percent: row.thd_percent_current,
+        }),
+        power_calculations: Some(PowerCalculations {
+            real_power_w: Some(row.real_power),
+            apparent_power_va: Some(row.apparent_power),
+            reactive_power_var: Some(row.reactive_power),
+            power_factor: Some(row.power_factor),
+        }),
+    }
+}
@@ -0,0 +1,250 @@
+//! One replay standing in for several devices: a JSON file of `{"devices": [...]}`, each
+//! e.g.
+//!
+//! ```json
+//! {"file": "/datasets/feeder-1.csv", "topic": "site1/meter-1"}
+//! {"file": "/datasets/feeder-2.parquet", "topic": "site1/meter-2", "rate_hz": 30, "start_offset_seconds": 2.5}
+//! {"file": "/datasets/feeder-3.csv", "topic": "site2/meter-1", "pub": "tcp://0.0.0.0:5558"}
+//! ```
+//!
+//! Every device publishes its dataset from its own pass, `start_offset_seconds` after the
+//! others start, at its own `rate_hz` (RATE_HZ by default). They share the PUB socket unless
+//! they name their own `pub`. Everything else (TENANT, PACING, LOOP, START_FRAME and the other
+//! schedule, clock, perturbation and scenario settings) applies to each device alike.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::{join_all, try_join_all};
+use serde::Deserialize;
+use shutdown::Shutdown;
+use tokio::sync::mpsc;
+use zeromq::{PubSocket, Socket, SocketSend};
+
+use crate::control::ReplayStatus;
+use crate::dataset::{self, Frame};
+use crate::manifest::PassRecord;
+use crate::schedule::Schedule;
+use crate::startup::Startup;
+use crate::{Outlet, PublishOptions};
+
+/// Messages waiting for a shared socket at once, per socket.
+const QUEUE: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DevicesFile {
+    devices: Vec<Device>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Device {
+    file: PathBuf,
+    topic: String,
+    rate_hz: Option<f64>,
+    #[serde(default)]
+    start_offset_seconds: f64,
+    #[serde(rename = "pub")]
+    pub_addr: Option<String>,
+}
+
+/// DEVICES: path to the devices file, instead of FILE.
+pub fn requested() -> Option<PathBuf> {
+    env::var("DEVICES")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+fn read_file(path: &Path) -> Result<Vec<Device>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Could not read DEVICES {}", path.display()))?;
+    let file: DevicesFile = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid DEVICES in {}", path.display()))?;
+    if file.devices.is_empty() {
+        bail!("DEVICES {} lists no devices", path.display());
+    }
+    for device in &file.devices {
+        if device
+            .rate_hz
+            .is_some_and(|rate| !(rate.is_finite() && rate > 0.0))
+        {
+            bail!("{}: rate_hz must be positive", device.topic);
+        }
+        if !(device.start_offset_seconds.is_finite() && device.start_offset_seconds >= 0.0) {
+            bail!(
+                "{}: start_offset_seconds must not be negative",
+                device.topic
+            );
+        }
+        if let Some(pub_addr) = &device.pub_addr {
+            zmq_ingest::parse_endpoint(pub_addr)
+                .with_context(|| format!("{}: invalid pub", device.topic))?;
+        }
+    }
+    Ok(file.devices)
+}
+
+/// Publishes every device in `path` until their passes end or shutdown is requested.
+/// `options` carries the settings they share; devices on `default_pub` use that socket.
+pub async fn run(
+    path: &Path,
+    default_pub: &str,
+    tenant: Option<&str>,
+    startup: Startup,
+    schedule: &Schedule,
+    options: &PublishOptions<'_>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let devices = read_file(path)?;
+    // Loaded up front, so a bad file stops the replay before anything is published
+    let mut loaded = Vec::new();
+    for device in devices {
+        let topic = service_config::tenant_topic(tenant, &device.topic)?;
+        let frames = dataset::load_frames(&device.file)
+            .with_context(|| format!("Could not load {}", device.file.display()))?;
+        let selection = schedule
+            .frames(frames.len())
+            .with_context(|| format!("In {}", device.file.display()))?;
+        let period = match device.rate_hz {
+            Some(rate_hz) => Duration::from_secs_f64(1.0 / rate_hz),
+            None => options.period,
+        };
+        let pub_addr = device
+            .pub_addr
+            .clone()
+            .unwrap_or_else(|| default_pub.to_string());
+        loaded.push((device, topic, frames, selection, period, pub_addr));
+    }
+
+    let mut sockets = BTreeMap::new();
+    let mut monitors = Vec::new();
+    for (.., pub_addr) in &loaded {
+        if sockets.contains_key(pub_addr) {
+            continue;
+        }
+        let mut socket = PubSocket::new();
+        monitors.push(startup.monitor(&mut socket));
+        socket
+            .bind(pub_addr)
+            .await
+            .with_context(|| format!("Could not bind to ZeroMQ socket {pub_addr}"))?;
+        log::info!("Publisher bound to {pub_addr}");
+        sockets.insert(pub_addr.clone(), socket);
+    }
+    log::info!(
+        "Waiting for subscribers ({}) before publishing {} devices...",
+        startup.describe(),
+        loaded.len()
+    );
+    tokio::select! {
+        _ = join_all(monitors.into_iter().map(|monitor| startup.wait(monitor))) => {}
+        _ = shutdown.requested() => return close_all(sockets.into_values()).await,
+    }
+    log::info!("Pacing: {}", options.pacing.describe());
+    log::info!("Schedule: {}", schedule.describe());
+
+    let mut queues = BTreeMap::new();
+    let mut writers = Vec::new();
+    for (pub_addr, socket) in sockets {
+        let (sender, receiver) = mpsc::channel(QUEUE);
+        queues.insert(pub_addr, sender);
+        writers.push(write(socket, receiver));
+    }
+
+    let passes = loaded
+        .iter()
+        .map(|(device, topic, frames, selection, period, pub_addr)| {
+            let outlet = Outlet::Queue(queues[pub_addr].clone());
+            let options = PublishOptions {
+                topic,
+                period: *period,
+                ..*options
+            };
+            let frames = &frames[selection.clone()];
+            let offset = Duration::from_secs_f64(device.start_offset_seconds);
+            pass(outlet, frames, selection.start, options, offset)
+        })
+        .collect::<Vec<_>>();
+    // The writers finish once every pass has dropped its queue
+    drop(queues);
+
+    let passes = async {
+        tokio::select! {
+            result = try_join_all(passes) => result.map(|_| ()),
+            _ = crate::pass_limit(schedule.max_duration) => {
+                log::info!("Stopped at MAX_DURATION_SECONDS");
+                Ok(())
+            }
+            _ = shutdown.requested() => Ok(()),
+        }
+    };
+    let (result, sockets) = tokio::join!(passes, join_all(writers));
+    close_all(sockets).await?;
+    result
+}
+
+/// One device's pass, starting `offset` from now.
+async fn pass(
+    outlet: Outlet<'_>,
+    frames: &[Frame],
+    first: usize,
+    options: PublishOptions<'_>,
+    offset: Duration,
+) -> Result<()> {
+    tokio::time::sleep(offset).await;
+    log::info!(
+        "Publishing {} frames at {:.1} Hz with topic '{}'...",
+        frames.len(),
+        1.0 / options.period.as_secs_f64(),
+        options.topic
+    );
+    let mut record = PassRecord::new();
+    crate::publish(
+        outlet,
+        frames,
+        first,
+        &options,
+        &ReplayStatus::default(),
+        &mut record,
+    )
+    .await?;
+    log::info!(
+        "Finished publishing {} frames with topic '{}'",
+        record.frames_published,
+        options.topic
+    );
+    Ok(())
+}
+
+/// Sends what the passes sharing `socket` queue, until they have all finished.
+async fn write(mut socket: PubSocket, mut queue: mpsc::Receiver<Vec<u8>>) -> PubSocket {
+    while let Some(message) = queue.recv().await {
+        if let Err(err) = socket.send(message.into()).await {
+            log::error!("Failed to send message: {err}");
+        }
+    }
+    socket
+}
+
+async fn close_all(sockets: impl IntoIterator<Item = PubSocket>) -> Result<()> {
+    for socket in sockets {
+        crate::close(socket).await?;
+    }
+    Ok(())
+}
+
+/// The single-dataset settings that don't apply with DEVICES.
+pub fn check_env() -> Result<()> {
+    for setting in ["FILE", "CONTROL_PORT", "MANIFEST"] {
+        if env::var_os(setting).is_some() {
+            return Err(anyhow!("{setting} doesn't apply with DEVICES"));
+        }
+    }
+    Ok(())
+}
@@ -1,202 +1,475 @@
-use anyhow::{Context, Result};
-use chrono::{DateTime, Utc};
+use anyhow::{anyhow, Context, Result};
 use prost::Message;
-use protobuf_rs::utilidata::karman::bibimbap::v1::{
-    composite_joined_calculations_wrapper::DataProduct, CompositeCalculations,
-    CompositeJoinedCalculations, CompositeJoinedCalculationsWrapper, CompositeTwoPhaseCalculations,
-    PowerCalculations, Provenance, WaveformCalculations,
-};
-use serde::Deserialize;
-use std::collections::HashMap;
+use protobuf_rs::utilidata::karman::bibimbap::v1::composite_joined_calculations_wrapper::DataProduct;
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeJoinedCalculations;
 use std::env;
-use std::fs::File;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use zeromq::{Socket, SocketSend};
-
-#[derive(Debug, Deserialize)]
-struct CsvRow {
-    time: i64,  // Milliseconds since epoch
-    stream_name: String,
-    phase: String,
-    rms_voltage: f32,
-    dc_offset_voltage: f32,
-    rms_current: f32,
-    dc_offset_current: f32,
-    real_power: f32,
-    apparent_power: f32,
-    reactive_power: f32,
-    power_factor: f32,
-    sequence_number: Option<u64>,
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use http_auth::{Auth, AuthSettings};
+use logging::LogArgs;
+use service_config::{tenant_topic, ConfigFile, Shared};
+use shutdown::Shutdown;
+use tokio::sync::{mpsc, watch};
+use zeromq::{PubSocket, Socket, SocketSend};
+
+use crate::clock::DeviceClock;
+use crate::control::{ControlState, ReplayStatus};
+use crate::dataset::Frame;
+use crate::manifest::{Manifest, PassRecord};
+use crate::pacing::{Pacer, Pacing};
+use crate::perturb::Perturbation;
+use crate::scenario::{Injected, Scenario};
+use crate::schedule::Schedule;
+use crate::startup::Startup;
+
+mod clock;
+mod control;
+mod dataset;
+mod devices;
+mod manifest;
+mod pacing;
+mod perturb;
+mod record;
+mod scenario;
+mod schedule;
+mod startup;
+mod synthetic;
+
+/// How the shared sections of a --config file map onto data-replay's environment. The
+/// shared endpoint is where the others connect, not an address to bind, so PUB is left out.
+const SHARED_SETTINGS: &[Shared] = &[Shared {
+    section: "zmq",
+    key: "topic",
+    setting: "TOPIC",
+}];
+
+/// Every environment variable data-replay reads, and so may be set under [replay].
+const SETTINGS: &[&str] = &[
+    "API_KEYS_FILE",
+    "AUDIT_DATABASE_URL",
+    "CLOCK_DRIFT_PPM",
+    "CLOCK_STEPS",
+    "CONTROL_PORT",
+    "DATASETS_DIR",
+    "DEVICES",
+    "DROP_PROBABILITY",
+    "END_FRAME",
+    "FILE",
+    "JITTER_MS",
+    "JWT_SECRET",
+    "LOG_FORMAT",
+    "LOG_LEVEL",
+    "LOOP",
+    "MANIFEST",
+    "MAX_DURATION_SECONDS",
+    "NOISE_PCT",
+    "OUTPUT",
+    "PACING",
+    "PACING_ALIGN_MS",
+    "PACING_SPEED",
+    "PUB",
+    "RATE_HZ",
+    "RECORD_DURATION_SECONDS",
+    "RECORD_FRAMES",
+    "SCENARIO",
+    "SEED",
+    "STARTUP_DELAY_SECONDS",
+    "START_FRAME",
+    "SUB",
+    "SYNTHETIC",
+    "SYNTHETIC_CYCLE_SECONDS",
+    "SYNTHETIC_LOAD_KW",
+    "SYNTHETIC_LOAD_SHAPE",
+    "SYNTHETIC_NOMINAL_VOLTAGE",
+    "SYNTHETIC_POWER_FACTOR",
+    "SYNTHETIC_STREAMS",
+    "SYNTHETIC_THREE_PHASE",
+    "TENANT",
+    "TOPIC",
+    "WAIT_FOR_SUBSCRIBERS",
+];
+
+/// Reads `--config PATH` into the environment before the runtime starts any threads;
+/// variables already set win over the file. `data-replay record` captures a live feed to a
+/// dataset instead of publishing one, and `data-replay --synthetic` publishes generated streams
+/// instead of a dataset's. With DEVICES, several datasets are published at once.
+fn main() -> Result<()> {
+    let args: Vec<_> = env::args_os().collect();
+    if let Some(config) = ConfigFile::from_args(&args)? {
+        config
+            .apply_to_env("replay", SHARED_SETTINGS, SETTINGS)
+            .with_context(|| format!("In {}", config.path().display()))?;
+    }
+    let mode = if args.get(1).is_some_and(|command| command == "record") {
+        Mode::Record
+    } else if synthetic::requested(&args)? {
+        Mode::Synthetic
+    } else {
+        Mode::Replay
+    };
+    run(mode)
+}
+
+enum Mode {
+    Replay,
+    Record,
+    Synthetic,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
+async fn run(mode: Mode) -> Result<()> {
+    let log = LogArgs::from_env().map_err(|err| anyhow!(err))?;
+    logging::init(&log, "error");
+    let shutdown = Shutdown::install().context("Could not install signal handlers")?;
+    if let Mode::Record = mode {
+        let tenant = env::var("TENANT").ok().filter(|tenant| !tenant.is_empty());
+        let topic = tenant_topic(tenant.as_deref(), &env::var("TOPIC").unwrap_or_default())?;
+        return record::run(topic, &shutdown).await;
+    }
 
     let file_path = env::var("FILE").unwrap_or_else(|_| "/datasets/sample1-b200-no-powercap.csv".to_string());
+    // tcp://HOST:PORT, or ipc://PATH for subscribers on the same host
     let pub_addr = env::var("PUB").unwrap_or_else(|_| "tcp://0.0.0.0:5557".to_string());
+    zmq_ingest::parse_endpoint(&pub_addr).context("Invalid PUB")?;
     let rate_hz: f64 = env::var("RATE_HZ")
         .unwrap_or_else(|_| "60".to_string())
         .parse()
         .context("Invalid RATE_HZ")?;
-    let topic = env::var("TOPIC").unwrap_or_default();
+    // Customer site this replay stands in for; its frames go out under `<tenant>/`
+    let tenant = env::var("TENANT").ok().filter(|tenant| !tenant.is_empty());
+    let topic = tenant_topic(tenant.as_deref(), &env::var("TOPIC").unwrap_or_default())?;
+    let clock = DeviceClock::from_env()?;
+    let perturbation = Perturbation::from_env()?;
+    let scenario = Scenario::from_env()?;
+    let pacing = Pacing::from_env()?;
+    let schedule = Schedule::from_env()?;
+    let startup = Startup::from_env()?;
+    // Optional JSON manifest of each pass, rewritten when the pass ends
+    let manifest_path = env::var("MANIFEST").ok().map(PathBuf::from);
+    // Optional control API: dataset catalog and switching datasets at runtime
+    let datasets_dir = env::var("DATASETS_DIR").ok().map(PathBuf::from);
+    let control_port: Option<u16> = env::var("CONTROL_PORT")
+        .ok()
+        .map(|port| port.parse())
+        .transpose()
+        .context("Invalid CONTROL_PORT")?;
+    let period = Duration::from_secs_f64(1.0 / rate_hz);
 
-    log::info!("Reading dataset from: {}", file_path);
-    
-    // Read and parse CSV
-    let file = File::open(&file_path).context("Could not open dataset file")?;
-    let mut rdr = csv::Reader::from_reader(file);
-    
-    // Group rows by timestamp into frames (each timestamp has 6 rows: 3 streams × 2 phases)
-    let mut frames = Vec::new();
-    let mut current_frame: HashMap<String, (Option<CsvRow>, Option<CsvRow>)> = HashMap::new();
-    let mut last_timestamp: Option<i64> = None;
-    let mut frame_count = 0u64;
-    
-    for result in rdr.deserialize() {
-        let row: CsvRow = result.context("Failed to parse CSV row")?;
-        
-        // New timestamp = new frame (timestamps are in milliseconds)
-        if let Some(last) = last_timestamp {
-            if row.time != last {
-                if !current_frame.is_empty() {
-                    frames.push(build_frame(&current_frame, frame_count)?);
-                    frame_count += 1;
-                    current_frame.clear();
-                }
-            }
-        }
-        last_timestamp = Some(row.time);
-        
-        // Group phase A and B for each stream
-        let entry = current_frame.entry(row.stream_name.clone()).or_insert((None, None));
-        match row.phase.as_str() {
-            "phase_a" => entry.0 = Some(row),
-            "phase_b" => entry.1 = Some(row),
-            _ => log::warn!("Unknown phase: {}", row.phase),
-        }
+    if let Mode::Synthetic = mode {
+        let options = PublishOptions {
+            topic: &topic,
+            period,
+            pacing,
+            looping: true,
+            clock: &clock,
+            perturbation: &perturbation,
+            scenario: &scenario,
+        };
+        return synthetic::run(&pub_addr, startup, &options, schedule.max_duration, &shutdown).await;
     }
-    
-    // Last frame
-    if !current_frame.is_empty() {
-        frames.push(build_frame(&current_frame, frame_count)?);
+    if let Some(path) = devices::requested() {
+        devices::check_env()?;
+        let options = PublishOptions {
+            topic: &topic,
+            period,
+            pacing,
+            looping: schedule.looping,
+            clock: &clock,
+            perturbation: &perturbation,
+            scenario: &scenario,
+        };
+        return devices::run(&path, &pub_addr, tenant.as_deref(), startup, &schedule, &options, &shutdown).await;
+    }
+
+    let mut frames = dataset::load_frames(file_path.as_ref())?;
+    schedule.frames(frames.len())?;
+    let mut dataset_path = PathBuf::from(&file_path);
+
+    let (selection_tx, mut selection_rx) = watch::channel(PathBuf::from(&file_path));
+    let status = Arc::new(ReplayStatus::default());
+    *status.dataset.lock().unwrap() = file_path.clone();
+
+    if let Some(port) = control_port {
+        // Reads are open unless keys are configured; selecting a dataset always needs admin
+        let auth = Auth::load(
+            "data-replay",
+            &AuthSettings {
+                api_keys_file: env::var("API_KEYS_FILE").ok().map(PathBuf::from),
+                jwt_secret: env::var("JWT_SECRET").ok(),
+                audit_database_url: env::var("AUDIT_DATABASE_URL").ok(),
+                tenant: tenant.clone(),
+            },
+        )
+        .await?;
+        tokio::spawn(control::serve(
+            port,
+            ControlState {
+                datasets_dir,
+                selection: selection_tx.clone(),
+                status: status.clone(),
+                auth,
+            },
+        ));
     }
-    
-    log::info!("Loaded {} frames from {}", frames.len(), file_path);
     
     // Setup ZeroMQ publisher
     let mut socket = zeromq::PubSocket::new();
+    let monitor = startup.monitor(&mut socket);
     socket.bind(&pub_addr).await.context("Could not bind to ZeroMQ socket")?;
     
-    log::info!("Publisher bound to {}, waiting 75 seconds for subscribers...", pub_addr);
-    tokio::time::sleep(Duration::from_secs(75)).await;
-    
-    log::info!("Publishing {} frames at {} Hz with topic '{}'...", frames.len(), rate_hz, topic);
-    
-    let period = Duration::from_secs_f64(1.0 / rate_hz);
-    let start_time = SystemTime::now();
+    log::info!("Publisher bound to {}, waiting for subscribers ({})...", pub_addr, startup.describe());
+    tokio::select! {
+        _ = startup.wait(monitor) => {}
+        _ = shutdown.requested() => return close(socket).await,
+    }
     
-    for (idx, frame) in frames.iter().enumerate() {
-        // Rewrite timestamps to NOW + offset for live dashboards
-        let offset = period * idx as u32;
-        let current_time = start_time + offset;
-        let timestamp = current_time.duration_since(UNIX_EPOCH).unwrap();
-        
-        let mut frame_with_time = frame.clone();
-        for calc in frame_with_time.calculations.iter_mut() {
-            if let Some(DataProduct::Calculations(ref mut two_phase)) = calc.data_product {
-                if let Some(ref mut phase_a) = two_phase.phase_a {
-                    if let Some(ref mut prov) = phase_a.provenance {
-                        prov.utc_time = Some(prost_types::Timestamp {
-                            seconds: timestamp.as_secs() as i64,
-                            nanos: timestamp.subsec_nanos() as i32,
-                        });
-                    }
-                }
-                if let Some(ref mut phase_b) = two_phase.phase_b {
-                    if let Some(ref mut prov) = phase_b.provenance {
-                        prov.utc_time = Some(prost_types::Timestamp {
-                            seconds: timestamp.as_secs() as i64,
-                            nanos: timestamp.subsec_nanos() as i32,
-                        });
+    if !clock.is_ideal() {
+        log::info!("Simulating device clock error: {}", clock.describe());
+    }
+    if perturbation.is_none() {
+        log::info!("Seed {}", perturbation.seed);
+    } else {
+        log::info!("Perturbing replay: {:?}", perturbation);
+    }
+    if !scenario.is_none() {
+        log::info!("Injecting faults: {}", scenario.describe());
+    }
+    log::info!("Pacing: {}", pacing.describe());
+    log::info!("Schedule: {}", schedule.describe());
+    let options = PublishOptions {
+        topic: &topic,
+        period,
+        pacing,
+        looping: schedule.looping,
+        clock: &clock,
+        perturbation: &perturbation,
+        scenario: &scenario,
+    };
+
+    loop {
+        // A dataset selected later may be too short for START_FRAME; it then publishes nothing
+        let selection = schedule.frames(frames.len()).unwrap_or_else(|err| {
+            log::error!("{err:#}");
+            0..0
+        });
+        let selected = &frames[selection.clone()];
+        log::info!("Publishing {} frames at {} Hz with topic '{}'...", selected.len(), rate_hz, topic);
+        status.frames_total.store(selected.len(), Ordering::Relaxed);
+        status.frames_published.store(0, Ordering::Relaxed);
+        status.laps.store(0, Ordering::Relaxed);
+
+        let mut record = PassRecord::new();
+        // Finished: it published everything it was going to, or ran out of time
+        let (finished, completed) = tokio::select! {
+            result = publish(Outlet::Socket(&mut socket), selected, selection.start, &options, &status, &mut record) => {
+                result?;
+                (true, true)
+            }
+            _ = pass_limit(schedule.max_duration) => (true, false),
+            _ = selection_rx.changed() => (false, false),
+            _ = shutdown.requested() => (false, false),
+        };
+
+        if let Some(path) = &manifest_path {
+            let manifest = Manifest {
+                dataset: dataset_path.display().to_string(),
+                dataset_sha256: manifest::file_sha256(&dataset_path)?,
+                topic: topic.clone(),
+                rate_hz,
+                clock: clock.describe(),
+                perturbation: perturbation.clone(),
+                scenario: scenario.path(),
+                pacing: pacing.describe(),
+                schedule: schedule.describe(),
+                started_at: chrono::DateTime::<chrono::Utc>::from(record.started).to_rfc3339(),
+                completed,
+                frames_total: selected.len(),
+                laps: record.laps,
+                frames_published: record.frames_published,
+                frames_sha256: record.frames_sha256(),
+                frames_dropped: record.frames_dropped,
+                dropped_frames: record.dropped_frames,
+            };
+            if let Err(err) = manifest::write(path, &manifest) {
+                log::error!("{err:#}");
+            }
+        }
+
+        if shutdown.is_requested() {
+            log::info!("Stopped after {} of {} frames", record.frames_published, selected.len());
+            return close(socket).await;
+        }
+        if finished {
+            if completed {
+                log::info!("Finished publishing {} frames.", selected.len());
+            } else {
+                log::info!("Stopped after {} frames, at MAX_DURATION_SECONDS", record.frames_published);
+            }
+            // Idle until another dataset is selected (forever, without a control API)
+            tokio::select! {
+                changed = selection_rx.changed() => {
+                    if changed.is_err() {
+                        return close(socket).await;
                     }
                 }
+                _ = shutdown.requested() => return close(socket).await,
             }
+        } else {
+            log::info!("Dataset changed, stopping current replay");
+        }
+
+        let path = selection_rx.borrow_and_update().clone();
+        match dataset::load_frames(&path) {
+            Ok(loaded) => {
+                frames = loaded;
+                *status.dataset.lock().unwrap() = path.display().to_string();
+                dataset_path = path;
+            }
+            Err(err) => log::error!("Could not load dataset {}: {err:#}", path.display()),
         }
-        
-        // Encode and publish
-        let mut buf = Vec::new();
-        frame_with_time.encode(&mut buf).context("Failed to encode frame")?;
-        
-        let mut message = topic.as_bytes().to_vec();
-        message.extend_from_slice(&buf);
-        
-        socket.send(message.into()).await.context("Failed to send message")?;
-        tokio::time::sleep(period).await;
     }
-    
-    log::info!("Finished publishing {} frames.", frames.len());
-    
-    // Keep container alive
-    loop {
-        tokio::time::sleep(Duration::from_secs(60)).await;
+}
+
+/// Unbinds the publisher so subscribers see the connection go away.
+async fn close(socket: PubSocket) -> Result<()> {
+    for err in socket.close().await {
+        log::warn!("Error closing publisher: {err}");
     }
+    Ok(())
 }
 
-fn build_frame(
-    frame_data: &HashMap<String, (Option<CsvRow>, Option<CsvRow>)>,
-    sequence: u64,
-) -> Result<CompositeJoinedCalculations> {
-    let mut calculations = Vec::new();
-    
-    for (stream_name, (phase_a, phase_b)) in frame_data.iter() {
-        let Some(row_a) = phase_a else { continue };
-        
-        // If phase_b is missing from CSV, duplicate phase_a to satisfy protobuf structure.
-        // Dashboards only display data fromphase_a. To reduce CSV file size by ~50%, we only
-        // export phase_a and duplicate it here. Downstream services (data-exporter and
-        // data-db) expect both fields and use .unwrap(), so we populate both.
-        let row_b = phase_b.as_ref().unwrap_or(row_a);
-        
-        let calc_name = format!("threephase/{}", stream_name);
-        
-        let composite = CompositeTwoPhaseCalculations {
-            phase_a: Some(build_composite(row_a, sequence)),
-            phase_b: Some(build_composite(row_b, sequence)),
-        };
-        
-        calculations.push(CompositeJoinedCalculationsWrapper {
-            calculation_name: Some(calc_name),
-            data_product: Some(DataProduct::Calculations(composite)),
-        });
+/// Resolves once a pass has run for MAX_DURATION_SECONDS, never without one.
+async fn pass_limit(max_duration: Option<Duration>) {
+    match max_duration {
+        Some(max) => tokio::time::sleep(max).await,
+        None => std::future::pending().await,
     }
-    
-    Ok(CompositeJoinedCalculations { calculations })
 }
 
-fn build_composite(row: &CsvRow, sequence: u64) -> CompositeCalculations {
-    CompositeCalculations {
-        provenance: Some(Provenance {
-            utc_time: Some(prost_types::Timestamp {
-                seconds: 0, // Will be overwritten at publish time
-                nanos: 0,
-            }),
-            generic_sequence_number: row.sequence_number.or(Some(sequence)),
-        }),
-        voltage_waveform_calculations_v: Some(WaveformCalculations {
-            rms: Some(row.rms_voltage),
-            dc_offset: Some(row.dc_offset_voltage),
-        }),
-        current_waveform_calculations_a: Some(WaveformCalculations {
-            rms: Some(row.rms_current),
-            dc_offset: Some(row.dc_offset_current),
-        }),
-        power_calculations: Some(PowerCalculations {
-            real_power_w: Some(row.real_power),
-            apparent_power_va: Some(row.apparent_power),
-            reactive_power_var: Some(row.reactive_power),
-            power_factor: Some(row.power_factor),
-        }),
+struct PublishOptions<'a> {
+    topic: &'a str,
+    period: Duration,
+    pacing: Pacing,
+    looping: bool,
+    clock: &'a DeviceClock,
+    perturbation: &'a Perturbation,
+    scenario: &'a Scenario,
+}
+
+/// Where a pass sends its messages: straight to its PUB socket, or to the queue of one that
+/// several devices share.
+enum Outlet<'a> {
+    Socket(&'a mut PubSocket),
+    Queue(mpsc::Sender<Vec<u8>>),
+}
+
+impl Outlet<'_> {
+    async fn send(&mut self, message: Vec<u8>) -> Result<()> {
+        match self {
+            Outlet::Socket(socket) => socket.send(message.into()).await.context("Failed to send message"),
+            Outlet::Queue(queue) => queue.send(message).await.map_err(|_| anyhow!("The publisher has stopped")),
+        }
+    }
+}
+
+/// Sends `frame` under `topic`, or what the scenario puts in its place.
+async fn send_frame(
+    outlet: &mut Outlet<'_>,
+    topic: &str,
+    frame: &CompositeJoinedCalculations,
+    injected: &Injected<'_>,
+) -> Result<()> {
+    let mut message = topic.as_bytes().to_vec();
+    match injected.undecodable_payload() {
+        Some(payload) => message.extend_from_slice(&payload),
+        None => frame.encode(&mut message).context("Failed to encode frame")?,
+    }
+    if injected.duplicate {
+        outlet.send(message.clone()).await?;
     }
+    outlet.send(message).await
 }
 
+/// Publishes `frames`, which start at frame `first` of the dataset, once or (looping) until
+/// cancelled. Frames are timed from the start of the pass, so timestamps keep rising from one
+/// lap to the next, and each lap adds the selection's sequence span to sequence numbers.
+async fn publish(
+    mut outlet: Outlet<'_>,
+    frames: &[Frame],
+    first: usize,
+    options: &PublishOptions<'_>,
+    status: &ReplayStatus,
+    record: &mut PassRecord,
+) -> Result<()> {
+    let PublishOptions { topic, period, pacing, looping, clock, perturbation, scenario } = *options;
+    let times: Vec<i64> = frames.iter().map(|frame| frame.time_ms).collect();
+    let lap = pacing.lap(&times, period);
+    let mut pacer = Pacer::start(pacing).await;
+    record.started = pacer.started();
+    let start_time = record.started;
+    let mut rng = perturbation.rng();
+    let mut scenario_rng = scenario.rng(perturbation.seed);
+    let sequence_span = dataset::sequence_span(frames);
+    // Frames published or dropped so far in the pass, over every lap
+    let mut position = 0usize;
+    let mut lap_start = Duration::ZERO;
+
+    loop {
+        let sequence_offset = sequence_span * record.laps as u64;
+        record.laps += 1;
+
+        for (offset, frame) in frames.iter().enumerate() {
+            let idx = first + offset;
+            let elapsed = lap_start + lap.offsets[offset];
+            let next_due = lap_start + lap.offsets.get(offset + 1).copied().unwrap_or(lap.length);
+            let injected = scenario.at(position);
+            let mut frame_with_time = frame.joined.clone();
+            perturbation.apply_noise(&mut frame_with_time, &mut rng);
+            injected.apply(&mut frame_with_time);
+            let dropped = perturbation.drop_frame(&mut rng) || injected.drop;
+            let jitter = perturbation.jitter(&mut rng) + injected.jitter(&mut scenario_rng);
+            if dropped {
+                record.dropped(idx);
+                pacer.next(next_due, jitter).await;
+                position += 1;
+                continue;
+            }
+            // Checksummed before the timestamps below, which follow the wall clock
+            let payload = injected.undecodable_payload();
+            record.published(idx, &payload.unwrap_or_else(|| frame_with_time.encode_to_vec()));
+
+            // Rewrite timestamps to NOW + offset for live dashboards (as seen by the simulated device clock)
+            let timestamp = clock.timestamp(start_time, elapsed, position);
+
+            for calc in frame_with_time.calculations.iter_mut() {
+                if let Some(DataProduct::Calculations(ref mut two_phase)) = calc.data_product {
+                    let phases = [
+                        two_phase.phase_a.as_mut(),
+                        two_phase.phase_b.as_mut(),
+                        two_phase.phase_c.as_mut(),
+                    ];
+                    for phase in phases.into_iter().flatten() {
+                        if let Some(ref mut prov) = phase.provenance {
+                            prov.utc_time = Some(timestamp);
+                            prov.generic_sequence_number =
+                                prov.generic_sequence_number.map(|sequence| sequence + sequence_offset);
+                        }
+                    }
+                }
+            }
+
+            send_frame(&mut outlet, topic, &frame_with_time, &injected).await?;
+            status.frames_published.fetch_add(1, Ordering::Relaxed);
+            pacer.next(next_due, jitter).await;
+            position += 1;
+        }
+
+        lap_start += lap.length;
+        status.laps.fetch_add(1, Ordering::Relaxed);
+        if !looping || frames.is_empty() {
+            return Ok(());
+        }
+    }
+}
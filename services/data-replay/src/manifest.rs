@@ -0,0 +1,113 @@
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::perturb::Perturbation;
+
+/// Dropped frames a manifest lists; the rest are only counted.
+const DROPPED_FRAMES_LISTED: usize = 1000;
+
+/// What one pass over a dataset published, written to MANIFEST when the pass ends so a run can
+/// be reproduced from it (same dataset checksum, seed and options give the same frames).
+#[derive(Serialize)]
+pub struct Manifest {
+    pub dataset: String,
+    /// SHA-256 of the dataset file
+    pub dataset_sha256: String,
+    pub topic: String,
+    pub rate_hz: f64,
+    pub clock: String,
+    pub perturbation: Perturbation,
+    /// SCENARIO file of injected faults, which count towards frames_dropped and frames_sha256
+    pub scenario: Option<String>,
+    /// Free-running, or aligned to the wall clock
+    pub pacing: String,
+    /// Frames selected, looping, time limit
+    pub schedule: String,
+    /// Publish start, RFC 3339; frame timestamps are derived from it
+    pub started_at: String,
+    /// False when the pass was cut short by a dataset switch or MAX_DURATION_SECONDS, and
+    /// always for a looping pass
+    pub completed: bool,
+    /// Frames selected from the dataset, once through
+    pub frames_total: usize,
+    /// Times the selection was started, more than one with LOOP
+    pub laps: usize,
+    pub frames_published: usize,
+    /// Frames skipped by DROP_PROBABILITY, on every lap
+    pub frames_dropped: usize,
+    /// The first of them, up to 1000
+    pub dropped_frames: Vec<DroppedFrame>,
+    /// SHA-256 over every published frame's index and payload, with provenance timestamps
+    /// zeroed since those follow the wall clock
+    pub frames_sha256: String,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct DroppedFrame {
+    /// Counting from 0
+    pub lap: usize,
+    /// Into the dataset's frames
+    pub index: usize,
+}
+
+/// Accumulates a pass's manifest while frames are published.
+pub struct PassRecord {
+    /// When publishing started; frame timestamps count from here
+    pub started: SystemTime,
+    hasher: Sha256,
+    pub frames_published: usize,
+    pub frames_dropped: usize,
+    pub dropped_frames: Vec<DroppedFrame>,
+    pub laps: usize,
+}
+
+impl PassRecord {
+    pub fn new() -> Self {
+        Self {
+            started: SystemTime::now(),
+            hasher: Sha256::new(),
+            frames_published: 0,
+            frames_dropped: 0,
+            dropped_frames: Vec::new(),
+            laps: 0,
+        }
+    }
+
+    pub fn published(&mut self, index: usize, payload: &[u8]) {
+        self.hasher.update((index as u64).to_le_bytes());
+        self.hasher.update((payload.len() as u64).to_le_bytes());
+        self.hasher.update(payload);
+        self.frames_published += 1;
+    }
+
+    /// Counts a dropped frame of the current lap, listing it if there is room.
+    pub fn dropped(&mut self, index: usize) {
+        self.frames_dropped += 1;
+        if self.dropped_frames.len() < DROPPED_FRAMES_LISTED {
+            self.dropped_frames.push(DroppedFrame {
+                lap: self.laps.saturating_sub(1),
+                index,
+            });
+        }
+    }
+
+    pub fn frames_sha256(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+pub fn file_sha256(path: &Path) -> Result<String> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("Could not read {} for its checksum", path.display()))?;
+    Ok(format!("{:x}", Sha256::digest(bytes)))
+}
+
+pub fn write(path: &Path, manifest: &Manifest) -> Result<()> {
+    let json = serde_json::to_string_pretty(manifest).context("Could not encode manifest")?;
+    fs::write(path, json).with_context(|| format!("Could not write manifest {}", path.display()))
+}
@@ -0,0 +1,171 @@
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Longest single sleep while waiting for a wall-clock slot, so a step of the host clock
+/// (an NTP correction, PTP locking) is noticed before the next frame is due.
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+/// How frames are spaced out.
+#[derive(Clone, Copy, Debug)]
+pub enum Pacing {
+    /// Sleep one period (plus jitter) after every frame. Scheduling delays accumulate, so two
+    /// instances drift apart over a long pass.
+    FreeRunning,
+    /// Publish frame `i` at `start + i * period` on the host's wall clock, with `start` on a
+    /// multiple of `align` since the Unix epoch. Instances on hosts disciplined by NTP or PTP
+    /// then emit the same frame at the same instant, to within the clocks' agreement.
+    WallClock { align: Duration },
+    /// Space frames as far apart as their rows' timestamps are, divided by `speed`, so gaps and
+    /// changes of sampling rate in the capture replay as they happened. Frames are scheduled
+    /// from the start of the pass like wall-clock pacing, without aligning the start.
+    Original { speed: f64 },
+}
+
+/// When each frame of a lap over the selected frames is due, from the start of the lap.
+pub struct Lap {
+    pub offsets: Vec<Duration>,
+    /// From the start of the lap to the start of the next
+    pub length: Duration,
+}
+
+impl Pacing {
+    /// PACING: `free` (the default) or `wall-clock`.
+    /// PACING_ALIGN_MS: with wall-clock pacing, passes start on a multiple of this many
+    /// milliseconds since the epoch, e.g. `1000` for the top of a second (the default).
+    /// PACING_SPEED: with original pacing, how much faster than captured to replay, e.g. `2`
+    /// for twice as fast or `0.5` for half speed (the default is `1`).
+    pub fn from_env() -> Result<Self> {
+        let mode = env::var("PACING").unwrap_or_default();
+        match mode.as_str() {
+            "" | "free" => Ok(Self::FreeRunning),
+            "original" => {
+                let speed: f64 = match env::var("PACING_SPEED") {
+                    Ok(value) => value.parse().context("Invalid PACING_SPEED")?,
+                    Err(_) => 1.0,
+                };
+                if !(speed.is_finite() && speed > 0.0) {
+                    return Err(anyhow!("PACING_SPEED must be positive"));
+                }
+                Ok(Self::Original { speed })
+            }
+            "wall-clock" => {
+                let align_ms: u64 = match env::var("PACING_ALIGN_MS") {
+                    Ok(value) => value.parse().context("Invalid PACING_ALIGN_MS")?,
+                    Err(_) => 1000,
+                };
+                if align_ms == 0 {
+                    return Err(anyhow!("PACING_ALIGN_MS must be positive"));
+                }
+                Ok(Self::WallClock {
+                    align: Duration::from_millis(align_ms),
+                })
+            }
+            other => Err(anyhow!(
+                "Invalid PACING '{}', expected free, wall-clock or original",
+                other
+            )),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::FreeRunning => "free-running".to_string(),
+            Self::WallClock { align } => format!("wall-clock, aligned to {}ms", align.as_millis()),
+            Self::Original { speed } => format!("original timing at {}x", speed),
+        }
+    }
+
+    /// Schedules frames with row times `times_ms`: one `period` apart, or with original pacing,
+    /// as far apart as their times. A lap ends one `period` after its last frame either way.
+    pub fn lap(&self, times_ms: &[i64], period: Duration) -> Lap {
+        let offsets: Vec<Duration> = match self {
+            Self::Original { speed } => {
+                let first = times_ms.first().copied().unwrap_or_default();
+                // Rows out of order are published at once rather than back in time
+                let mut latest = 0;
+                times_ms
+                    .iter()
+                    .map(|&time| {
+                        latest = latest.max(time - first);
+                        Duration::from_secs_f64(latest as f64 / 1000.0 / speed)
+                    })
+                    .collect()
+            }
+            _ => (0..times_ms.len()).map(|index| period * index as u32).collect(),
+        };
+        let length = offsets.last().map_or(Duration::ZERO, |last| *last + period);
+        Lap { offsets, length }
+    }
+}
+
+/// Times one pass.
+pub struct Pacer {
+    pacing: Pacing,
+    started: SystemTime,
+    /// When the frame just published was due, from `started`
+    due: Duration,
+}
+
+impl Pacer {
+    /// Waits for the next alignment boundary under wall-clock pacing; frame 0 is due on return.
+    pub async fn start(pacing: Pacing) -> Self {
+        let started = match pacing {
+            Pacing::FreeRunning | Pacing::Original { .. } => SystemTime::now(),
+            Pacing::WallClock { align } => {
+                let boundary = next_boundary(SystemTime::now(), align);
+                wait_until(boundary).await;
+                boundary
+            }
+        };
+        Self {
+            pacing,
+            started,
+            due: Duration::ZERO,
+        }
+    }
+
+    /// When frame 0 was (or, for wall-clock pacing, was due to be) published.
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Waits until the next frame is due, `due` after the start of the pass. `jitter_secs` moves
+    /// that one frame without shifting the ones after it under wall-clock and original pacing.
+    pub async fn next(&mut self, due: Duration, jitter_secs: f64) {
+        let gap = due.saturating_sub(self.due);
+        self.due = due;
+        match self.pacing {
+            Pacing::FreeRunning => {
+                let sleep = (gap.as_secs_f64() + jitter_secs).max(0.0);
+                tokio::time::sleep(Duration::from_secs_f64(sleep)).await;
+            }
+            Pacing::WallClock { .. } | Pacing::Original { .. } => {
+                let due = due.as_secs_f64() + jitter_secs;
+                let due = self.started + Duration::from_secs_f64(due.max(0.0));
+                // Behind schedule (a stall, or the clock stepped forward) the frame goes out at
+                // once, and the following ones catch up to their slots
+                wait_until(due).await;
+            }
+        }
+    }
+}
+
+fn next_boundary(now: SystemTime, align: Duration) -> SystemTime {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let align = align.as_nanos();
+    let boundary = since_epoch.div_ceil(align) * align;
+    UNIX_EPOCH + Duration::from_nanos(boundary as u64)
+}
+
+/// Sleeps until the wall clock reads `target`, re-reading it as it goes rather than trusting a
+/// monotonic timer, which doesn't follow corrections to the wall clock.
+async fn wait_until(target: SystemTime) {
+    while let Ok(remaining) = target.duration_since(SystemTime::now()) {
+        if remaining.is_zero() {
+            break;
+        }
+        tokio::time::sleep(remaining.min(MAX_SLEEP)).await;
+    }
+}
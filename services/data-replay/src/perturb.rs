@@ -0,0 +1,144 @@
+use std::env;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, CompositeCalculations,
+    CompositeJoinedCalculations,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Normal};
+use serde::Serialize;
+
+/// Randomized degradation of the replayed stream. Every random choice comes from one RNG
+/// seeded with `seed` at the start of each pass and drawn in frame order, so the same seed,
+/// dataset and options publish exactly the same frames.
+#[derive(Clone, Debug, Serialize)]
+pub struct Perturbation {
+    pub seed: u64,
+    /// Standard deviation of gaussian noise added to each measurement, as a percent of it
+    pub noise_pct: f64,
+    /// Chance that a frame is skipped instead of published
+    pub drop_probability: f64,
+    /// Publish times are moved by up to this much either way
+    #[serde(rename = "jitter_ms", serialize_with = "as_millis")]
+    pub jitter: Duration,
+}
+
+fn as_millis<S: serde::Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(value.as_secs_f64() * 1000.0)
+}
+
+impl Perturbation {
+    /// SEED: u64 seed for all randomized behaviour; a random one is picked (and logged) if unset.
+    /// NOISE_PCT: gaussian noise on every measurement, e.g. `0.5`.
+    /// DROP_PROBABILITY: chance of skipping each frame, from 0 to 1.
+    /// JITTER_MS: maximum publish time jitter in milliseconds.
+    pub fn from_env() -> Result<Self> {
+        let seed = match env::var("SEED") {
+            Ok(value) => value.parse().context("Invalid SEED")?,
+            Err(_) => rand::random(),
+        };
+        let noise_pct: f64 = match env::var("NOISE_PCT") {
+            Ok(value) => value.parse().context("Invalid NOISE_PCT")?,
+            Err(_) => 0.0,
+        };
+        let drop_probability: f64 = match env::var("DROP_PROBABILITY") {
+            Ok(value) => value.parse().context("Invalid DROP_PROBABILITY")?,
+            Err(_) => 0.0,
+        };
+        let jitter_ms: f64 = match env::var("JITTER_MS") {
+            Ok(value) => value.parse().context("Invalid JITTER_MS")?,
+            Err(_) => 0.0,
+        };
+
+        if !(0.0..=1.0).contains(&drop_probability) {
+            return Err(anyhow!("DROP_PROBABILITY must be between 0 and 1"));
+        }
+        if noise_pct < 0.0 || jitter_ms < 0.0 {
+            return Err(anyhow!("NOISE_PCT and JITTER_MS must not be negative"));
+        }
+
+        Ok(Self {
+            seed,
+            noise_pct,
+            drop_probability,
+            jitter: Duration::from_secs_f64(jitter_ms / 1000.0),
+        })
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.noise_pct == 0.0 && self.drop_probability == 0.0 && self.jitter.is_zero()
+    }
+
+    pub fn rng(&self) -> ChaCha8Rng {
+        ChaCha8Rng::seed_from_u64(self.seed)
+    }
+
+    /// Decides whether to drop the next frame. Draws even when drops are off, so turning
+    /// them on doesn't shift the noise applied to the frames that remain.
+    pub fn drop_frame(&self, rng: &mut ChaCha8Rng) -> bool {
+        rng.gen::<f64>() < self.drop_probability
+    }
+
+    /// Offset for the next publish time, in [-jitter, +jitter].
+    pub fn jitter(&self, rng: &mut ChaCha8Rng) -> f64 {
+        let unit: f64 = rng.gen_range(-1.0..=1.0);
+        unit * self.jitter.as_secs_f64()
+    }
+
+    pub fn apply_noise(&self, frame: &mut CompositeJoinedCalculations, rng: &mut ChaCha8Rng) {
+        if self.noise_pct == 0.0 {
+            return;
+        }
+
+        for calc in frame.calculations.iter_mut() {
+            let Some(DataProduct::Calculations(two_phase)) = calc.data_product.as_mut() else {
+                continue;
+            };
+            for phase in [
+                two_phase.phase_a.as_mut(),
+                two_phase.phase_b.as_mut(),
+                two_phase.phase_c.as_mut(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                self.noise_phase(phase, rng);
+            }
+        }
+    }
+
+    fn noise_phase(&self, phase: &mut CompositeCalculations, rng: &mut ChaCha8Rng) {
+        let mut noisy = |value: &mut Option<f32>| {
+            if let Some(value) = value {
+                let sigma = (value.abs() as f64 * self.noise_pct / 100.0).max(f64::MIN_POSITIVE);
+                let normal = Normal::new(0.0, sigma).expect("sigma is positive and finite");
+                *value += normal.sample(rng) as f32;
+            }
+        };
+
+        if let Some(voltage) = phase.voltage_waveform_calculations_v.as_mut() {
+            noisy(&mut voltage.rms);
+            noisy(&mut voltage.dc_offset);
+            noisy(&mut voltage.crest_factor);
+            noisy(&mut voltage.thd_percent);
+        }
+        if let Some(current) = phase.current_waveform_calculations_a.as_mut() {
+            noisy(&mut current.rms);
+            noisy(&mut current.dc_offset);
+            noisy(&mut current.crest_factor);
+            noisy(&mut current.thd_percent);
+        }
+        if let Some(power) = phase.power_calculations.as_mut() {
+            noisy(&mut power.real_power_w);
+            noisy(&mut power.apparent_power_va);
+            noisy(&mut power.reactive_power_var);
+            noisy(&mut power.power_factor);
+            if let Some(power_factor) = power.power_factor.as_mut() {
+                *power_factor = power_factor.clamp(-1.0, 1.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! `data-replay record`: subscribes to a live feed and writes what arrives to a dataset file
+//! that data-replay can publish again, for new datasets without a separate capture script.
+//!
+//! Reads its own environment: SUB, the publisher to connect to (e.g. tcp://karman:5557, or
+//! ipc://PATH on the same host), TOPIC (and TENANT) as for replay, and OUTPUT, the file to write (Parquet for a `.parquet`
+//! name, CSV otherwise). Recording stops after RECORD_FRAMES frames or RECORD_DURATION_SECONDS,
+//! whichever comes first, or on SIGINT/SIGTERM; the file is complete in every case.
+//!
+//! Rows keep the frames' own provenance time (the time they were received when a frame has
+//! none), sequence numbers and clock status, so a replay with PACING=original reproduces the
+//! capture's timing.
+
+use std::env;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use shutdown::Shutdown;
+use zmq_ingest::{
+    parse_endpoint, HwmConfig, OverflowPolicy, ReaderThread, ReceiveMetrics, SubscriberConfig,
+    SubscriberStream,
+};
+
+use crate::dataset::{self, DatasetWriter};
+
+static ZMQ_RECEIVE: LazyLock<ReceiveMetrics> = LazyLock::new(|| {
+    ReceiveMetrics::register("data_replay_record_")
+        .expect("Unable to register receive queue metrics")
+});
+
+/// Frames read ahead of the file before new ones are dropped
+const RECEIVE_HWM: usize = 10_000;
+
+struct Recording {
+    subscriber: SubscriberConfig,
+    output: PathBuf,
+    max_frames: Option<u64>,
+    max_duration: Option<Duration>,
+}
+
+impl Recording {
+    fn from_env(topic: String) -> Result<Self> {
+        let endpoint = env::var("SUB")
+            .map_err(|_| anyhow!("Recording needs SUB, the publisher to subscribe to"))?;
+        parse_endpoint(&endpoint).context("Invalid SUB")?;
+        let output = env::var("OUTPUT")
+            .map_err(|_| anyhow!("Recording needs OUTPUT, the dataset file to write"))?;
+        let max_frames = env::var("RECORD_FRAMES")
+            .ok()
+            .map(|frames| frames.parse::<u64>())
+            .transpose()
+            .context("Invalid RECORD_FRAMES")?;
+        let max_duration = env::var("RECORD_DURATION_SECONDS")
+            .ok()
+            .map(|seconds| seconds.parse::<f64>())
+            .transpose()
+            .context("Invalid RECORD_DURATION_SECONDS")?;
+        if max_frames == Some(0) {
+            return Err(anyhow!("RECORD_FRAMES must be positive"));
+        }
+        if max_duration.is_some_and(|seconds| seconds <= 0.0) {
+            return Err(anyhow!("RECORD_DURATION_SECONDS must be positive"));
+        }
+        Ok(Self {
+            subscriber: SubscriberConfig {
+                endpoint,
+                topic,
+                hwm: HwmConfig {
+                    hwm: RECEIVE_HWM,
+                    on_full: OverflowPolicy::DropNewest,
+                },
+                reader_thread: ReaderThread::default(),
+            },
+            output: PathBuf::from(output),
+            max_frames,
+            max_duration: max_duration.map(Duration::from_secs_f64),
+        })
+    }
+
+    fn describe(&self) -> String {
+        match (self.max_frames, self.max_duration) {
+            (Some(frames), Some(duration)) => format!("{frames} frames or {duration:?}"),
+            (Some(frames), None) => format!("{frames} frames"),
+            (None, Some(duration)) => format!("{duration:?}"),
+            (None, None) => "until stopped".to_string(),
+        }
+    }
+}
+
+/// Records until a limit is reached or shutdown is requested.
+pub async fn run(topic: String, shutdown: &Shutdown) -> Result<()> {
+    let recording = Recording::from_env(topic)?;
+    let mut writer = DatasetWriter::create(&recording.output)?;
+
+    // Connecting waits for the publisher to come up
+    let mut subscription = tokio::select! {
+        subscription = SubscriberStream::connect(recording.subscriber.clone(), &ZMQ_RECEIVE) => {
+            subscription.context("Could not subscribe")?
+        }
+        _ = shutdown.requested() => return writer.finish(),
+    };
+    log::info!(
+        "Recording to {} ({})",
+        recording.output.display(),
+        recording.describe()
+    );
+
+    let limit = crate::pass_limit(recording.max_duration);
+    tokio::pin!(limit);
+    let mut frames = 0u64;
+    let mut rows = 0usize;
+    let result = loop {
+        if recording.max_frames.is_some_and(|max| frames >= max) {
+            break Ok(());
+        }
+        let frame = tokio::select! {
+            frame = subscription.next() => frame,
+            _ = &mut limit => break Ok(()),
+            _ = shutdown.requested() => break Ok(()),
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(err) => break Err(anyhow!("Subscription failed: {err}")),
+        };
+        let received_ms = frame
+            .received
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let frame_rows = dataset::frame_rows(&frame.joined, received_ms);
+        if frame_rows.is_empty() {
+            continue;
+        }
+        rows += frame_rows.len();
+        frames += 1;
+        if let Err(err) = writer.write(frame_rows) {
+            break Err(err);
+        }
+    };
+
+    subscription.close().await;
+    // Whatever stopped the recording, what was recorded is kept
+    writer.finish()?;
+    log::info!(
+        "Recorded {frames} frames ({rows} rows) to {}, dropped {} while writing",
+        recording.output.display(),
+        ZMQ_RECEIVE.dropped.get()
+    );
+    result
+}
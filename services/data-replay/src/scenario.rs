@@ -0,0 +1,248 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, CompositeJoinedCalculations,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Deserialize;
+
+/// Faults injected on a schedule, to exercise subscribers' error paths: a JSON file of
+/// `{"faults": [...]}`, each fault e.g.
+///
+/// ```json
+/// {"fault": "drop", "at": 600, "frames": 60}
+/// {"fault": "duplicate_sequence", "at": 1000, "every": 1000}
+/// {"fault": "omit_phase_b", "at": 1800, "frames": 120, "streams": ["threephase/feeder-1"]}
+/// {"fault": "undecodable", "at": 2400, "frames": 5, "bytes": 64}
+/// {"fault": "jitter", "at": 3000, "frames": 600, "jitter_ms": 50}
+/// ```
+///
+/// `at` counts frames from the start of the pass, published or dropped and over every lap,
+/// like CLOCK_STEPS. A fault lasts `frames` frames (1 by default), and with `every`, starts
+/// again that many frames after each start. Faults may overlap.
+#[derive(Debug, Default)]
+pub struct Scenario {
+    path: Option<PathBuf>,
+    faults: Vec<Fault>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScenarioFile {
+    faults: Vec<Fault>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Fault {
+    #[serde(flatten)]
+    kind: FaultKind,
+    at: usize,
+    #[serde(default = "one")]
+    frames: usize,
+    every: Option<usize>,
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "fault", rename_all = "snake_case")]
+enum FaultKind {
+    /// The frame isn't published
+    Drop,
+    /// The frame is published twice, the copy repeating its sequence numbers
+    DuplicateSequence,
+    /// The listed streams (every stream when none are) go out without phase B
+    OmitPhaseB {
+        #[serde(default)]
+        streams: Vec<String>,
+    },
+    /// `bytes` bytes (at least 11) no protobuf decoder accepts go out under the topic
+    /// instead of the frame
+    Undecodable {
+        #[serde(default = "undecodable_bytes")]
+        bytes: usize,
+    },
+    /// The frame is published up to `jitter_ms` early or late, on top of JITTER_MS
+    Jitter { jitter_ms: f64 },
+}
+
+fn undecodable_bytes() -> usize {
+    32
+}
+
+/// What to do to one frame.
+#[derive(Debug, Default)]
+pub struct Injected<'a> {
+    pub drop: bool,
+    pub duplicate: bool,
+    /// Streams to strip phase B from; every stream when empty
+    omit_phase_b: Option<Vec<&'a str>>,
+    /// Publish this many undecodable bytes instead of the frame
+    undecodable: Option<usize>,
+    /// Largest extra publish time offset, either way
+    jitter: Duration,
+}
+
+impl Fault {
+    fn active(&self, position: usize) -> bool {
+        let Some(since) = position.checked_sub(self.at) else {
+            return false;
+        };
+        match self.every {
+            Some(every) => since % every < self.frames,
+            None => since < self.frames,
+        }
+    }
+
+    /// Whether a window of the fault opens at `position`.
+    fn starts(&self, position: usize) -> bool {
+        let Some(since) = position.checked_sub(self.at) else {
+            return false;
+        };
+        match self.every {
+            Some(every) => since % every == 0,
+            None => since == 0,
+        }
+    }
+}
+
+impl Scenario {
+    /// SCENARIO: path to a JSON scenario file; no faults are injected without one.
+    pub fn from_env() -> Result<Self> {
+        let Ok(path) = env::var("SCENARIO") else {
+            return Ok(Self::default());
+        };
+        let path = PathBuf::from(path);
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read scenario {}", path.display()))?;
+        let file: ScenarioFile = serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid scenario {}", path.display()))?;
+        for fault in &file.faults {
+            if fault.frames == 0 {
+                return Err(anyhow!(
+                    "{:?} in {} lasts no frames",
+                    fault.kind,
+                    path.display()
+                ));
+            }
+            if fault.every == Some(0) {
+                return Err(anyhow!(
+                    "{:?} in {} repeats every 0 frames",
+                    fault.kind,
+                    path.display()
+                ));
+            }
+            if let FaultKind::Jitter { jitter_ms } = fault.kind {
+                if !(jitter_ms.is_finite() && jitter_ms >= 0.0) {
+                    return Err(anyhow!(
+                        "jitter_ms in {} must not be negative",
+                        path.display()
+                    ));
+                }
+            }
+        }
+        Ok(Self {
+            path: Some(path),
+            faults: file.faults,
+        })
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.faults.is_empty()
+    }
+
+    /// The scenario file, for the manifest.
+    pub fn path(&self) -> Option<String> {
+        self.path.as_ref().map(|path| path.display().to_string())
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.path {
+            Some(path) => format!("{} faults from {}", self.faults.len(), path.display()),
+            None => "none".to_string(),
+        }
+    }
+
+    /// For the scenario's random choices: seeded with SEED like the perturbation's, on a
+    /// stream of its own, so adding a scenario leaves the noise and drops as they were.
+    pub fn rng(&self, seed: u64) -> ChaCha8Rng {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_stream(1);
+        rng
+    }
+
+    /// The faults that apply to the frame at `position` in the pass. Each fault is logged as
+    /// it starts, so subscribers' logs can be lined up with it.
+    pub fn at(&self, position: usize) -> Injected<'_> {
+        let mut injected = Injected::default();
+        for fault in self.faults.iter().filter(|fault| fault.active(position)) {
+            if fault.starts(position) {
+                log::info!(
+                    "Injecting {:?} for {} frames at frame {position}",
+                    fault.kind,
+                    fault.frames
+                );
+            }
+            match &fault.kind {
+                FaultKind::Drop => injected.drop = true,
+                FaultKind::DuplicateSequence => injected.duplicate = true,
+                FaultKind::OmitPhaseB { streams } => match &mut injected.omit_phase_b {
+                    // Already every stream
+                    Some(omitted) if omitted.is_empty() => {}
+                    Some(omitted) if !streams.is_empty() => {
+                        omitted.extend(streams.iter().map(String::as_str))
+                    }
+                    omitted => *omitted = Some(streams.iter().map(String::as_str).collect()),
+                },
+                FaultKind::Undecodable { bytes } => {
+                    injected.undecodable = Some(injected.undecodable.unwrap_or(0).max(*bytes));
+                }
+                FaultKind::Jitter { jitter_ms } => {
+                    injected.jitter = injected
+                        .jitter
+                        .max(Duration::from_secs_f64(jitter_ms / 1000.0));
+                }
+            }
+        }
+        injected
+    }
+}
+
+impl Injected<'_> {
+    /// Strips phase B as the scenario says.
+    pub fn apply(&self, frame: &mut CompositeJoinedCalculations) {
+        let Some(streams) = &self.omit_phase_b else {
+            return;
+        };
+        for calc in frame.calculations.iter_mut() {
+            let name = calc.calculation_name.as_deref().unwrap_or_default();
+            if !streams.is_empty() && !streams.contains(&name) {
+                continue;
+            }
+            if let Some(DataProduct::Calculations(two_phase)) = calc.data_product.as_mut() {
+                two_phase.phase_b = None;
+            }
+        }
+    }
+
+    /// Offset for the frame's publish time on top of JITTER_MS, drawn from `Scenario::rng`.
+    pub fn jitter(&self, rng: &mut ChaCha8Rng) -> f64 {
+        if self.jitter.is_zero() {
+            return 0.0;
+        }
+        let unit: f64 = rng.gen_range(-1.0..=1.0);
+        unit * self.jitter.as_secs_f64()
+    }
+
+    /// What goes out in place of an undecodable frame: eleven or more 0xff bytes, a varint
+    /// longer than protobuf allows, so not even the first field's key can be read.
+    pub fn undecodable_payload(&self) -> Option<Vec<u8>> {
+        self.undecodable.map(|bytes| vec![0xff; bytes.max(11)])
+    }
+}
@@ -0,0 +1,86 @@
+use std::env;
+use std::ops::Range;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+/// Which part of the dataset a pass publishes, how often, and for how long.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    /// Start over from the first selected frame after the last one, until stopped
+    pub looping: bool,
+    start_frame: usize,
+    end_frame: Option<usize>,
+    /// Longest a pass publishes for
+    pub max_duration: Option<Duration>,
+}
+
+impl Schedule {
+    /// LOOP: `true` (or `1`) replays the selection continuously instead of idling after it.
+    /// START_FRAME / END_FRAME: publish only frames `START_FRAME..END_FRAME` of the dataset,
+    /// counted like CLOCK_STEPS and the manifest's frame indexes (one frame per timestamp).
+    /// MAX_DURATION_SECONDS: stop a pass this long after it starts, looping or not.
+    pub fn from_env() -> Result<Self> {
+        let looping = match env::var("LOOP").unwrap_or_default().as_str() {
+            "" | "false" | "0" => false,
+            "true" | "1" => true,
+            other => return Err(anyhow!("Invalid LOOP '{}', expected true or false", other)),
+        };
+        let start_frame: usize = match env::var("START_FRAME") {
+            Ok(value) => value.parse().context("Invalid START_FRAME")?,
+            Err(_) => 0,
+        };
+        let end_frame: Option<usize> = env::var("END_FRAME")
+            .ok()
+            .map(|value| value.parse())
+            .transpose()
+            .context("Invalid END_FRAME")?;
+        let max_duration = env::var("MAX_DURATION_SECONDS")
+            .ok()
+            .map(|value| value.parse::<f64>())
+            .transpose()
+            .context("Invalid MAX_DURATION_SECONDS")?;
+
+        if end_frame.is_some_and(|end| end <= start_frame) {
+            return Err(anyhow!("END_FRAME must be after START_FRAME"));
+        }
+        if max_duration.is_some_and(|seconds| seconds <= 0.0) {
+            return Err(anyhow!("MAX_DURATION_SECONDS must be positive"));
+        }
+
+        Ok(Self {
+            looping,
+            start_frame,
+            end_frame,
+            max_duration: max_duration.map(Duration::from_secs_f64),
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        let end = self
+            .end_frame
+            .map_or("end".to_string(), |end| end.to_string());
+        let mut description = format!("frames {}..{}", self.start_frame, end);
+        if self.looping {
+            description.push_str(", looping");
+        }
+        if let Some(max) = self.max_duration {
+            description.push_str(&format!(", at most {:.0} s", max.as_secs_f64()));
+        }
+        description
+    }
+
+    /// The frames of a `len`-frame dataset a pass publishes. END_FRAME past the end of the
+    /// dataset stops at its last frame, since datasets selected later may be shorter.
+    pub fn frames(&self, len: usize) -> Result<Range<usize>> {
+        if self.start_frame >= len {
+            return Err(anyhow!(
+                "START_FRAME {} is past the dataset's {} frames",
+                self.start_frame,
+                len
+            ));
+        }
+        let end = self.end_frame.map_or(len, |end| end.min(len));
+        Ok(self.start_frame..end)
+    }
+}
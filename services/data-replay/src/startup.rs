@@ -0,0 +1,120 @@
+use std::env;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use zeromq::{PubSocket, Socket, SocketEvent};
+
+/// How long subscribers get, after the last one the replay waits for has connected, to send
+/// their subscriptions. The publisher drops frames for a peer it has no subscription from yet,
+/// and the socket doesn't report subscriptions, only connections.
+const SUBSCRIBE_GRACE: Duration = Duration::from_millis(500);
+
+/// When the first pass starts after the publisher is bound.
+#[derive(Clone, Copy, Debug)]
+pub enum Startup {
+    /// After a fixed delay, for subscribers that take a while to come up.
+    Delay(Duration),
+    /// As soon as `subscribers` peers have connected, or after `timeout` with fewer.
+    Subscribers {
+        subscribers: usize,
+        timeout: Duration,
+    },
+}
+
+impl Startup {
+    /// STARTUP_DELAY_SECONDS: how long to wait before publishing (75 by default), or with
+    /// WAIT_FOR_SUBSCRIBERS, the longest to wait for them.
+    /// WAIT_FOR_SUBSCRIBERS: start as soon as this many subscribers have connected.
+    pub fn from_env() -> Result<Self> {
+        let delay_seconds: f64 = match env::var("STARTUP_DELAY_SECONDS") {
+            Ok(value) => value.parse().context("Invalid STARTUP_DELAY_SECONDS")?,
+            Err(_) => 75.0,
+        };
+        if delay_seconds < 0.0 {
+            return Err(anyhow!("STARTUP_DELAY_SECONDS must not be negative"));
+        }
+        let delay = Duration::from_secs_f64(delay_seconds);
+
+        match env::var("WAIT_FOR_SUBSCRIBERS") {
+            Ok(value) => {
+                let subscribers: usize = value.parse().context("Invalid WAIT_FOR_SUBSCRIBERS")?;
+                if subscribers == 0 {
+                    return Err(anyhow!("WAIT_FOR_SUBSCRIBERS must be positive"));
+                }
+                Ok(Self::Subscribers {
+                    subscribers,
+                    timeout: delay,
+                })
+            }
+            Err(_) => Ok(Self::Delay(delay)),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Delay(delay) => format!("{:.0} seconds", delay.as_secs_f64()),
+            Self::Subscribers {
+                subscribers,
+                timeout,
+            } => format!(
+                "{} subscribers, at most {:.0} seconds",
+                subscribers,
+                timeout.as_secs_f64()
+            ),
+        }
+    }
+
+    /// Starts watching for subscribers; call before binding so none are missed.
+    pub fn monitor(&self, socket: &mut PubSocket) -> Option<mpsc::Receiver<SocketEvent>> {
+        match self {
+            Self::Delay(_) => None,
+            Self::Subscribers { .. } => Some(socket.monitor()),
+        }
+    }
+
+    /// Waits until the first pass is due, with the receiver from `monitor`.
+    pub async fn wait(&self, monitor: Option<mpsc::Receiver<SocketEvent>>) {
+        let (subscribers, timeout, mut events) = match (*self, monitor) {
+            (
+                Self::Subscribers {
+                    subscribers,
+                    timeout,
+                },
+                Some(events),
+            ) => (subscribers, timeout, events),
+            (Self::Delay(delay), _) | (Self::Subscribers { timeout: delay, .. }, None) => {
+                tokio::time::sleep(delay).await;
+                return;
+            }
+        };
+
+        let mut connected = 0;
+        let waited = tokio::time::timeout(timeout, async {
+            while connected < subscribers {
+                match events.next().await {
+                    Some(SocketEvent::Accepted(endpoint, _)) => {
+                        connected += 1;
+                        log::info!(
+                            "Subscriber {connected} of {subscribers} connected from {endpoint}"
+                        );
+                    }
+                    Some(SocketEvent::Disconnected(_)) => connected = connected.saturating_sub(1),
+                    Some(_) => {}
+                    // The socket went away; nobody else will connect
+                    None => return,
+                }
+            }
+            tokio::time::sleep(SUBSCRIBE_GRACE).await;
+        })
+        .await;
+
+        if waited.is_err() {
+            log::warn!(
+                "Only {connected} of {subscribers} subscribers connected within {:.0} seconds, publishing anyway",
+                timeout.as_secs_f64()
+            );
+        }
+    }
+}
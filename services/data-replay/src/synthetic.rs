@@ -0,0 +1,362 @@
+//! `data-replay --synthetic` (or SYNTHETIC=true): publishes made-up streams instead of a
+//! dataset, a signal generator for load tests and demos that need no capture on disk. Each
+//! stream draws a load that follows the configured shape, with the voltage sagging under it,
+//! and frames are computed as they fall due, so nothing grows with the number of streams or
+//! how long it runs.
+//!
+//! RATE_HZ, PUB, TOPIC, TENANT, PACING, the clock and perturbation settings (NOISE_PCT is the
+//! generator's noise), SCENARIO, STARTUP_DELAY_SECONDS, WAIT_FOR_SUBSCRIBERS and
+//! MAX_DURATION_SECONDS apply as they do to a dataset. The dataset's own settings (FILE, LOOP, START_FRAME,
+//! END_FRAME, MANIFEST, CONTROL_PORT) don't: it publishes until stopped or out of time.
+
+use std::env;
+use std::f64::consts::{PI, SQRT_2};
+use std::ffi::OsString;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, CompositeCalculations,
+    CompositeJoinedCalculations, CompositeJoinedCalculationsWrapper, CompositeTwoPhaseCalculations,
+    PowerCalculations, Provenance, WaveformCalculations,
+};
+use shutdown::Shutdown;
+use zeromq::{PubSocket, Socket};
+
+use crate::pacing::Pacer;
+use crate::startup::Startup;
+use crate::{Outlet, PublishOptions};
+
+/// How far the voltage sags at full load, as a fraction of nominal
+const SAG_AT_FULL_LOAD: f64 = 0.03;
+/// Share of each phase's load, so the phases of a stream don't read the same
+const PHASE_BALANCE: [f64; 3] = [1.05, 0.95, 1.0];
+/// Streams lag one another by up to this fraction of a cycle, two hours of a daily one
+const STAGGER: f64 = 1.0 / 12.0;
+
+/// How each stream's load varies over a cycle, as a fraction of SYNTHETIC_LOAD_KW.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadShape {
+    /// Full load throughout
+    Flat,
+    /// Between 20% and full load
+    Sine,
+    /// A household's day squeezed into the cycle: a low base, a morning peak and a larger
+    /// evening one
+    Daily,
+}
+
+impl LoadShape {
+    /// At `position` through the cycle, from 0 to 1.
+    fn load(&self, position: f64) -> f64 {
+        match self {
+            Self::Flat => 1.0,
+            Self::Sine => 0.6 + 0.4 * (2.0 * PI * position).sin(),
+            Self::Daily => {
+                let hour = position * 24.0;
+                let peak = |at: f64, width: f64| (-((hour - at) / width).powi(2)).exp();
+                (0.3 + 0.3 * peak(7.5, 1.5) + 0.7 * peak(19.0, 2.5)).min(1.0)
+            }
+        }
+    }
+}
+
+/// What the generator publishes.
+#[derive(Clone, Debug)]
+pub struct Synthetic {
+    streams: usize,
+    nominal_voltage: f64,
+    load_kw: f64,
+    power_factor: f64,
+    shape: LoadShape,
+    /// One cycle of `shape`
+    cycle: Duration,
+    three_phase: bool,
+}
+
+/// `--synthetic` on the command line, or SYNTHETIC set to `true` (or `1`).
+pub fn requested(args: &[OsString]) -> Result<bool> {
+    if args.iter().skip(1).any(|arg| arg == "--synthetic") {
+        return Ok(true);
+    }
+    match env::var("SYNTHETIC").unwrap_or_default().as_str() {
+        "" | "false" | "0" => Ok(false),
+        "true" | "1" => Ok(true),
+        other => Err(anyhow!(
+            "Invalid SYNTHETIC '{}', expected true or false",
+            other
+        )),
+    }
+}
+
+impl Synthetic {
+    /// SYNTHETIC_STREAMS: how many streams each frame carries (3 by default), named
+    /// `threephase/synthetic-1` and on.
+    /// SYNTHETIC_NOMINAL_VOLTAGE: each phase's voltage at no load (240 by default).
+    /// SYNTHETIC_LOAD_KW: each stream's real power at full load, over all its phases (5 by
+    /// default).
+    /// SYNTHETIC_POWER_FACTOR: from 0 (exclusive) to 1 (0.95 by default).
+    /// SYNTHETIC_LOAD_SHAPE: `flat`, `sine` (the default) or `daily`.
+    /// SYNTHETIC_CYCLE_SECONDS: how long one cycle of the load shape takes (60 by default).
+    /// SYNTHETIC_THREE_PHASE: `true` (or `1`) gives every stream a phase C.
+    pub fn from_env() -> Result<Self> {
+        let streams: usize = match env::var("SYNTHETIC_STREAMS") {
+            Ok(value) => value.parse().context("Invalid SYNTHETIC_STREAMS")?,
+            Err(_) => 3,
+        };
+        let nominal_voltage: f64 = match env::var("SYNTHETIC_NOMINAL_VOLTAGE") {
+            Ok(value) => value.parse().context("Invalid SYNTHETIC_NOMINAL_VOLTAGE")?,
+            Err(_) => 240.0,
+        };
+        let load_kw: f64 = match env::var("SYNTHETIC_LOAD_KW") {
+            Ok(value) => value.parse().context("Invalid SYNTHETIC_LOAD_KW")?,
+            Err(_) => 5.0,
+        };
+        let power_factor: f64 = match env::var("SYNTHETIC_POWER_FACTOR") {
+            Ok(value) => value.parse().context("Invalid SYNTHETIC_POWER_FACTOR")?,
+            Err(_) => 0.95,
+        };
+        let shape = match env::var("SYNTHETIC_LOAD_SHAPE")
+            .unwrap_or_default()
+            .as_str()
+        {
+            "flat" => LoadShape::Flat,
+            "" | "sine" => LoadShape::Sine,
+            "daily" => LoadShape::Daily,
+            other => {
+                return Err(anyhow!(
+                    "Invalid SYNTHETIC_LOAD_SHAPE '{}', expected flat, sine or daily",
+                    other
+                ))
+            }
+        };
+        let cycle_seconds: f64 = match env::var("SYNTHETIC_CYCLE_SECONDS") {
+            Ok(value) => value.parse().context("Invalid SYNTHETIC_CYCLE_SECONDS")?,
+            Err(_) => 60.0,
+        };
+        let three_phase = match env::var("SYNTHETIC_THREE_PHASE")
+            .unwrap_or_default()
+            .as_str()
+        {
+            "" | "false" | "0" => false,
+            "true" | "1" => true,
+            other => {
+                return Err(anyhow!(
+                    "Invalid SYNTHETIC_THREE_PHASE '{}', expected true or false",
+                    other
+                ))
+            }
+        };
+
+        if streams == 0 {
+            return Err(anyhow!("SYNTHETIC_STREAMS must be positive"));
+        }
+        if !(nominal_voltage.is_finite() && nominal_voltage > 0.0) {
+            return Err(anyhow!("SYNTHETIC_NOMINAL_VOLTAGE must be positive"));
+        }
+        if !(load_kw.is_finite() && load_kw >= 0.0) {
+            return Err(anyhow!("SYNTHETIC_LOAD_KW must not be negative"));
+        }
+        if !(power_factor > 0.0 && power_factor <= 1.0) {
+            return Err(anyhow!(
+                "SYNTHETIC_POWER_FACTOR must be above 0 and at most 1"
+            ));
+        }
+        if !(cycle_seconds.is_finite() && cycle_seconds > 0.0) {
+            return Err(anyhow!("SYNTHETIC_CYCLE_SECONDS must be positive"));
+        }
+
+        Ok(Self {
+            streams,
+            nominal_voltage,
+            load_kw,
+            power_factor,
+            shape,
+            cycle: Duration::from_secs_f64(cycle_seconds),
+            three_phase,
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {} streams at {} V, {} kW {:?} load over {:.0} s, power factor {}",
+            self.streams,
+            if self.three_phase {
+                "three-phase"
+            } else {
+                "two-phase"
+            },
+            self.nominal_voltage,
+            self.load_kw,
+            self.shape,
+            self.cycle.as_secs_f64(),
+            self.power_factor
+        )
+    }
+
+    /// Frame `index`, due `elapsed` into the run and stamped `timestamp`.
+    fn frame(
+        &self,
+        index: u64,
+        elapsed: Duration,
+        timestamp: prost_types::Timestamp,
+    ) -> CompositeJoinedCalculations {
+        let cycles = elapsed.as_secs_f64() / self.cycle.as_secs_f64();
+        let phases = if self.three_phase { 3.0 } else { 2.0 };
+        let calculations = (0..self.streams)
+            .map(|stream| {
+                let lag = STAGGER * stream as f64 / self.streams as f64;
+                let load = self.shape.load((cycles - lag).rem_euclid(1.0));
+                let phase = |balance: f64| {
+                    let load = load * balance;
+                    let provenance = Provenance {
+                        utc_time: Some(timestamp),
+                        generic_sequence_number: Some(index),
+                        time_sync: None,
+                    };
+                    self.phase(load, phases, provenance)
+                };
+                let composite = CompositeTwoPhaseCalculations {
+                    phase_a: Some(phase(PHASE_BALANCE[0])),
+                    phase_b: Some(phase(PHASE_BALANCE[1])),
+                    phase_c: self.three_phase.then(|| phase(PHASE_BALANCE[2])),
+                };
+                CompositeJoinedCalculationsWrapper {
+                    calculation_name: Some(format!("threephase/synthetic-{}", stream + 1)),
+                    data_product: Some(DataProduct::Calculations(composite)),
+                    device_id: None,
+                    harmonics: None,
+                }
+            })
+            .collect();
+        CompositeJoinedCalculations { calculations }
+    }
+
+    /// One of `phases` phases carrying `load` of the stream's full load.
+    fn phase(&self, load: f64, phases: f64, provenance: Provenance) -> CompositeCalculations {
+        let voltage = self.nominal_voltage * (1.0 - SAG_AT_FULL_LOAD * load);
+        let real = self.load_kw * 1000.0 * load / phases;
+        let apparent = real / self.power_factor;
+        let reactive = apparent * (1.0 - self.power_factor.powi(2)).sqrt();
+        // Light loads are mostly electronics, whose current is the more distorted
+        let current_thd = 4.0 + 8.0 * (1.0 - load).max(0.0);
+        CompositeCalculations {
+            provenance: Some(provenance),
+            voltage_waveform_calculations_v: Some(WaveformCalculations {
+                rms: Some(voltage as f32),
+                dc_offset: Some(0.0),
+                crest_factor: Some(SQRT_2 as f32),
+                thd_percent: Some(2.0),
+            }),
+            current_waveform_calculations_a: Some(WaveformCalculations {
+                rms: Some((apparent / voltage) as f32),
+                dc_offset: Some(0.0),
+                crest_factor: Some((SQRT_2 * (1.0 + current_thd / 100.0)) as f32),
+                thd_percent: Some(current_thd as f32),
+            }),
+            power_calculations: Some(PowerCalculations {
+                real_power_w: Some(real as f32),
+                apparent_power_va: Some(apparent as f32),
+                reactive_power_var: Some(reactive as f32),
+                power_factor: Some(self.power_factor as f32),
+            }),
+        }
+    }
+}
+
+/// Binds `pub_addr` and generates frames until MAX_DURATION_SECONDS or shutdown.
+pub async fn run(
+    pub_addr: &str,
+    startup: Startup,
+    options: &PublishOptions<'_>,
+    max_duration: Option<Duration>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let synthetic = Synthetic::from_env()?;
+
+    let mut socket = PubSocket::new();
+    let monitor = startup.monitor(&mut socket);
+    socket
+        .bind(pub_addr)
+        .await
+        .context("Could not bind to ZeroMQ socket")?;
+    log::info!(
+        "Publisher bound to {}, waiting for subscribers ({})...",
+        pub_addr,
+        startup.describe()
+    );
+    tokio::select! {
+        _ = startup.wait(monitor) => {}
+        _ = shutdown.requested() => return crate::close(socket).await,
+    }
+
+    if !options.clock.is_ideal() {
+        log::info!(
+            "Simulating device clock error: {}",
+            options.clock.describe()
+        );
+    }
+    log::info!(
+        "Seed {}, perturbation {:?}",
+        options.perturbation.seed,
+        options.perturbation
+    );
+    log::info!("Pacing: {}", options.pacing.describe());
+    log::info!(
+        "Generating {} at {:.1} Hz with topic '{}'",
+        synthetic.describe(),
+        1.0 / options.period.as_secs_f64(),
+        options.topic
+    );
+
+    let mut published = 0u64;
+    let result = tokio::select! {
+        result = publish(Outlet::Socket(&mut socket), &synthetic, options, &mut published) => result,
+        _ = crate::pass_limit(max_duration) => Ok(()),
+        _ = shutdown.requested() => Ok(()),
+    };
+    log::info!("Stopped after {published} frames");
+    crate::close(socket).await?;
+    result
+}
+
+/// Publishes frame after frame, one period apart, until cancelled.
+async fn publish(
+    mut outlet: Outlet<'_>,
+    synthetic: &Synthetic,
+    options: &PublishOptions<'_>,
+    published: &mut u64,
+) -> Result<()> {
+    let PublishOptions {
+        topic,
+        period,
+        pacing,
+        clock,
+        perturbation,
+        scenario,
+        ..
+    } = *options;
+    let mut pacer = Pacer::start(pacing).await;
+    let start_time = pacer.started();
+    let mut rng = perturbation.rng();
+    let mut scenario_rng = scenario.rng(perturbation.seed);
+
+    let mut index = 0u64;
+    loop {
+        let elapsed = period.mul_f64(index as f64);
+        let next_due = period.mul_f64((index + 1) as f64);
+        let timestamp = clock.timestamp(start_time, elapsed, index as usize);
+        let injected = scenario.at(index as usize);
+        let mut frame = synthetic.frame(index, elapsed, timestamp);
+        perturbation.apply_noise(&mut frame, &mut rng);
+        injected.apply(&mut frame);
+        let dropped = perturbation.drop_frame(&mut rng) || injected.drop;
+        let jitter = perturbation.jitter(&mut rng) + injected.jitter(&mut scenario_rng);
+        if !dropped {
+            crate::send_frame(&mut outlet, topic, &frame, &injected).await?;
+            *published += 1;
+        }
+        pacer.next(next_due, jitter).await;
+        index += 1;
+    }
+}
@@ -0,0 +1,196 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use health::{Health, HealthArgs, Source};
+use logging::LogArgs;
+use protobuf_rs::utilidata::karman::bibimbap::v1::calculation_stream_server::CalculationStreamServer;
+use service_config::Shared;
+use shutdown::Shutdown;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+use zmq_ingest::{
+    Backoff, HwmConfig, OverflowPolicy, ReaderThreadArgs, ReconnectArgs, SubscriberConfig,
+    SubscriberStream,
+};
+
+use crate::metrics::ZMQ_RECEIVE;
+use crate::relay::{Feed, Relay};
+
+mod metrics;
+mod relay;
+
+/// How the shared sections of a --config file map onto data-stream's flags.
+const SHARED_SETTINGS: &[Shared] = &[
+    Shared {
+        section: "zmq",
+        key: "endpoint",
+        setting: "zmq-endpoint",
+    },
+    Shared {
+        section: "zmq",
+        key: "topic",
+        setting: "zmq-topic",
+    },
+    Shared {
+        section: "prometheus",
+        key: "port",
+        setting: "prometheus-port",
+    },
+];
+
+/// Subscribes to the calculation feed and relays it over gRPC
+/// (utilidata.karman.bibimbap.v1.CalculationStream), for tools that can't speak ZeroMQ or
+/// reach the publisher.
+#[derive(Parser)]
+struct Args {
+    /// TOML or YAML file of settings shared with the rest of the pipeline, plus data-stream's
+    /// own flags under [stream]. Flags given on the command line take precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// e.g. tcp://data-replay:5557, or ipc://PATH for a publisher on the same host
+    #[arg(long, value_parser = zmq_ingest::parse_endpoint)]
+    zmq_endpoint: String,
+    #[arg(long, default_value = "")]
+    zmq_topic: String,
+    /// Messages read ahead of the subscribers before --zmq-overflow-policy applies (0 for no
+    /// limit). Drops are counted in data_stream_zmq_dropped_messages_total.
+    #[arg(long, default_value_t = 1000)]
+    zmq_rcvhwm: usize,
+    /// Which message to discard when the receive queue is full
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropNewest)]
+    zmq_overflow_policy: OverflowPolicy,
+    #[command(flatten)]
+    reader_thread: ReaderThreadArgs,
+    #[command(flatten)]
+    reconnect: ReconnectArgs,
+    #[arg(long, default_value_t = 50051)]
+    grpc_port: u16,
+    /// Frames a subscriber may fall behind before it misses some. Missed frames are counted in
+    /// data_stream_frames_lagged_total.
+    #[arg(long, default_value_t = 256)]
+    client_buffer: usize,
+    /// Device for streams whose publisher doesn't name one, as matched by device filters
+    #[arg(long, default_value = "bibimbap")]
+    device: String,
+    /// Serve /metrics, /healthz and /readyz on this port
+    #[arg(long)]
+    prometheus_port: Option<u16>,
+    #[command(flatten)]
+    health: HealthArgs,
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+impl Args {
+    fn subscriber(&self) -> SubscriberConfig {
+        SubscriberConfig {
+            endpoint: self.zmq_endpoint.clone(),
+            topic: self.zmq_topic.clone(),
+            hwm: HwmConfig {
+                hwm: self.zmq_rcvhwm,
+                on_full: self.zmq_overflow_policy,
+            },
+            reader_thread: self.reader_thread.reader_thread(),
+        }
+    }
+}
+
+/// Serves subscribers until shutdown is requested, which ends every subscription.
+async fn run(args: Args, health: Source, shutdown: &Shutdown) -> Result<()> {
+    let (feed, _) = broadcast::channel(args.client_buffer.max(1));
+    let relay = Relay::new(feed.clone(), args.device.clone(), shutdown.clone());
+    let address = SocketAddr::from(([0, 0, 0, 0], args.grpc_port));
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Could not bind the gRPC server to {address}"))?;
+    log::info!("data-stream: gRPC server listening on {address}");
+    let server = tokio::spawn(
+        Server::builder()
+            .add_service(CalculationStreamServer::new(relay))
+            .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown.requested()),
+    );
+
+    // Connecting waits for the publisher to come up
+    let subscription = tokio::select! {
+        subscription = SubscriberStream::connect(args.subscriber(), &ZMQ_RECEIVE) => {
+            Some(subscription.context("Could not subscribe")?)
+        }
+        _ = shutdown.requested() => None,
+    };
+    if let Some(mut subscription) = subscription {
+        relay_feed(
+            &mut subscription,
+            &feed,
+            args.reconnect.backoff(),
+            &health,
+            shutdown,
+        )
+        .await;
+        subscription.close().await;
+    }
+
+    server
+        .await
+        .context("The gRPC server panicked")?
+        .context("The gRPC server failed")
+}
+
+/// Puts every frame on the feed until shutdown is requested.
+async fn relay_feed(
+    subscription: &mut SubscriberStream,
+    feed: &Feed,
+    backoff: Backoff,
+    health: &Source,
+    shutdown: &Shutdown,
+) {
+    health.connected();
+    loop {
+        let frame = tokio::select! {
+            frame = subscription.next() => frame,
+            _ = shutdown.requested() => return,
+        };
+        match frame {
+            // Only fails while nobody is subscribed
+            Ok(frame) => {
+                health.message();
+                _ = feed.send(Arc::new(frame.joined));
+            }
+            Err(err) => {
+                log::error!("Subscription failed: {err}");
+                health.disconnected();
+                if !subscription.reconnect_with_backoff(backoff, shutdown).await {
+                    return;
+                }
+                health.connected();
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Args = service_config::parse("stream", SHARED_SETTINGS);
+    logging::init(&args.log, "error");
+
+    let shutdown = match Shutdown::install() {
+        Ok(shutdown) => shutdown,
+        Err(err) => {
+            log::error!("Could not install signal handlers: {err:#}");
+            std::process::exit(1);
+        }
+    };
+
+    let health = Health::new(args.health);
+    let zmq = health.source("zmq");
+    if let Some(port) = args.prometheus_port {
+        tokio::spawn(metrics::serve(port, health));
+    }
+
+    if let Err(err) = run(args, zmq, &shutdown).await {
+        log::error!("{err:#}");
+        std::process::exit(255);
+    }
+    log::info!("Shut down cleanly");
+}
@@ -0,0 +1,87 @@
+use std::sync::LazyLock;
+
+use axum::{
+    Router,
+    http::{HeaderMap, StatusCode},
+    routing::get,
+};
+use health::Health;
+use prometheus::{Encoder, IntCounter, IntGauge, TextEncoder};
+use zmq_ingest::ReceiveMetrics;
+
+pub static CLIENTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    prometheus::register_int_gauge!(
+        "data_stream_clients",
+        "Subscriptions currently being streamed to"
+    )
+    .expect("Unable to register gauge")
+});
+
+pub static FRAMES_SENT: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_stream_frames_sent_total",
+        "Frames handed to subscribers, after each subscription's filter"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static FRAMES_LAGGED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "data_stream_frames_lagged_total",
+        "Frames subscribers missed because they fell more than --client-buffer frames behind"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static ZMQ_RECEIVE: LazyLock<ReceiveMetrics> = LazyLock::new(|| {
+    ReceiveMetrics::register("data_stream_").expect("Unable to register receive queue metrics")
+});
+
+async fn metrics_handler() -> (StatusCode, HeaderMap, String) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {:?}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            HeaderMap::new(),
+            String::new(),
+        );
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "Content-Type",
+        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+    );
+
+    match String::from_utf8(buffer) {
+        Ok(body) => (StatusCode::OK, headers, body),
+        Err(e) => {
+            log::error!("Failed to convert metrics to UTF8: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                String::new(),
+            )
+        }
+    }
+}
+
+pub async fn serve(port: u16, health: Health) {
+    let addr = format!("0.0.0.0:{}", port);
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .merge(health.router());
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .expect("Could not bind prometheus server");
+    log::info!(
+        "data-stream: Prometheus metrics server listening on {}",
+        addr
+    );
+
+    axum::serve(listener, app)
+        .await
+        .expect("Metrics server failed");
+}
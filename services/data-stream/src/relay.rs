@@ -0,0 +1,213 @@
+//! The CalculationStream service. Every subscription gets a receiver on the feed's broadcast
+//! channel and a task of its own that narrows each frame to the streams its filter selects.
+//! The channel keeps the last --client-buffer frames: a subscriber further behind than that
+//! skips to the oldest frame still there, counting what it missed, so one slow client holds
+//! up neither the feed nor the other subscribers.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeJoinedCalculations, StreamFilter, calculation_stream_server::CalculationStream,
+};
+use shutdown::Shutdown;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use crate::metrics::{CLIENTS, FRAMES_LAGGED, FRAMES_SENT};
+
+/// Frames as they come off the subscription, for every subscriber to take a copy of.
+pub type Feed = broadcast::Sender<Arc<CompositeJoinedCalculations>>;
+
+pub struct Relay {
+    feed: Feed,
+    /// Device for streams that don't name one
+    device: String,
+    shutdown: Shutdown,
+}
+
+impl Relay {
+    pub fn new(feed: Feed, device: String, shutdown: Shutdown) -> Self {
+        Self {
+            feed,
+            device,
+            shutdown,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl CalculationStream for Relay {
+    type SubscribeCalculationsStream = ReceiverStream<Result<CompositeJoinedCalculations, Status>>;
+
+    async fn subscribe_calculations(
+        &self,
+        request: Request<StreamFilter>,
+    ) -> Result<Response<Self::SubscribeCalculationsStream>, Status> {
+        let peer = request
+            .remote_addr()
+            .map_or_else(|| "unknown peer".to_string(), |addr| addr.to_string());
+        let filter = Filter::new(request.into_inner(), &self.device);
+        log::info!("{peer} subscribed to {filter}");
+
+        let (client, frames) = mpsc::channel(1);
+        tokio::spawn(relay(
+            self.feed.subscribe(),
+            filter,
+            client,
+            self.shutdown.clone(),
+            peer,
+        ));
+        Ok(Response::new(ReceiverStream::new(frames)))
+    }
+}
+
+/// Hands one subscriber its frames until it goes away, the feed ends or shutdown is requested.
+async fn relay(
+    mut feed: broadcast::Receiver<Arc<CompositeJoinedCalculations>>,
+    filter: Filter,
+    client: mpsc::Sender<Result<CompositeJoinedCalculations, Status>>,
+    shutdown: Shutdown,
+    peer: String,
+) {
+    CLIENTS.inc();
+    loop {
+        let frame = tokio::select! {
+            frame = feed.recv() => frame,
+            _ = client.closed() => break,
+            _ = shutdown.requested() => break,
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(RecvError::Lagged(missed)) => {
+                FRAMES_LAGGED.inc_by(missed);
+                log::warn!("{peer} fell behind and missed {missed} frames");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let Some(frame) = filter.apply(&frame) else {
+            continue;
+        };
+        let sent = tokio::select! {
+            sent = client.send(Ok(frame)) => sent.is_ok(),
+            _ = shutdown.requested() => false,
+        };
+        if !sent {
+            break;
+        }
+        FRAMES_SENT.inc();
+    }
+    CLIENTS.dec();
+    log::info!("{peer} unsubscribed");
+}
+
+/// Which streams of a frame a subscriber asked for.
+struct Filter {
+    /// Every stream when empty
+    streams: HashSet<String>,
+    /// Every device when empty
+    devices: HashSet<String>,
+    default_device: String,
+}
+
+impl Filter {
+    fn new(request: StreamFilter, default_device: &str) -> Self {
+        Self {
+            streams: request.calculation_names.into_iter().collect(),
+            devices: request.device_ids.into_iter().collect(),
+            default_device: default_device.to_string(),
+        }
+    }
+
+    /// The frame with only the selected streams; None when that leaves nothing.
+    fn apply(&self, frame: &CompositeJoinedCalculations) -> Option<CompositeJoinedCalculations> {
+        let calculations: Vec<_> = frame
+            .calculations
+            .iter()
+            .filter(|wrapper| {
+                let stream = wrapper.calculation_name.as_deref().unwrap_or_default();
+                let device = wrapper.device_id.as_deref().unwrap_or(&self.default_device);
+                (self.streams.is_empty() || self.streams.contains(stream))
+                    && (self.devices.is_empty() || self.devices.contains(device))
+            })
+            .cloned()
+            .collect();
+        (!calculations.is_empty()).then_some(CompositeJoinedCalculations { calculations })
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |names: &HashSet<String>| {
+            if names.is_empty() {
+                "all".to_string()
+            } else {
+                let mut names: Vec<_> = names.iter().map(String::as_str).collect();
+                names.sort_unstable();
+                names.join(", ")
+            }
+        };
+        write!(
+            f,
+            "streams: {}; devices: {}",
+            list(&self.streams),
+            list(&self.devices)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeJoinedCalculationsWrapper;
+
+    use super::*;
+
+    fn stream(name: &str, device: Option<&str>) -> CompositeJoinedCalculationsWrapper {
+        CompositeJoinedCalculationsWrapper {
+            calculation_name: Some(name.to_string()),
+            data_product: None,
+            device_id: device.map(str::to_string),
+            harmonics: None,
+        }
+    }
+
+    fn filter(streams: &[&str], devices: &[&str]) -> Filter {
+        let request = StreamFilter {
+            calculation_names: streams.iter().map(|name| name.to_string()).collect(),
+            device_ids: devices.iter().map(|name| name.to_string()).collect(),
+        };
+        Filter::new(request, "bibimbap")
+    }
+
+    fn frame() -> CompositeJoinedCalculations {
+        CompositeJoinedCalculations {
+            calculations: vec![
+                stream("karman1", Some("feeder-3")),
+                stream("karman2", None),
+                stream("karman3", Some("feeder-4")),
+            ],
+        }
+    }
+
+    #[test]
+    fn an_empty_filter_passes_every_stream() {
+        assert_eq!(filter(&[], &[]).apply(&frame()), Some(frame()));
+    }
+
+    #[test]
+    fn streams_and_devices_narrow_the_frame() {
+        let narrowed = filter(&["karman1", "karman2"], &["bibimbap"]).apply(&frame());
+        assert_eq!(
+            narrowed.unwrap().calculations,
+            vec![stream("karman2", None)]
+        );
+
+        let narrowed = filter(&[], &["feeder-3", "feeder-4"]).apply(&frame());
+        assert_eq!(narrowed.unwrap().calculations.len(), 2);
+
+        assert_eq!(filter(&["karman9"], &[]).apply(&frame()), None);
+    }
+}
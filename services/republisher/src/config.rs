@@ -0,0 +1,84 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+use zmq_ingest::parse_endpoint;
+
+use crate::transform::Derived;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    pub endpoint: String,
+    #[serde(default)]
+    pub topic: String,
+}
+
+/// One downstream PUB socket and what it carries.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Output {
+    pub name: String,
+    /// Bind the socket here so consumers can connect to it
+    pub bind: Option<String>,
+    /// Or connect it to a subscriber (or proxy) that binds
+    pub connect: Option<String>,
+    /// Topic to publish under; the upstream topic when omitted
+    pub topic: Option<String>,
+    /// Streams to pass on; every stream when omitted
+    pub streams: Option<HashSet<String>>,
+    /// Stream renames, applied after filtering
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+}
+
+impl Output {
+    /// Whether frames have to be decoded for this output, or can be forwarded byte for byte.
+    pub fn rewrites_frames(&self) -> bool {
+        self.streams.is_some() || !self.rename.is_empty()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub source: Source,
+    #[serde(rename = "output")]
+    pub outputs: Vec<Output>,
+    /// Streams computed from the incoming ones and added to every frame
+    #[serde(default)]
+    pub derived: Vec<Derived>,
+}
+
+pub fn load(path: &Path) -> Result<Config> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    let config: Config =
+        toml::from_str(&contents).with_context(|| format!("Invalid config {}", path.display()))?;
+
+    if config.outputs.is_empty() {
+        return Err(anyhow!("No [[output]] entries in {}", path.display()));
+    }
+    parse_endpoint(&config.source.endpoint).context("Invalid [source] endpoint")?;
+    let mut names = HashSet::new();
+    for output in &config.outputs {
+        if output.bind.is_some() == output.connect.is_some() {
+            return Err(anyhow!(
+                "{}: set exactly one of bind or connect",
+                output.name
+            ));
+        }
+        for endpoint in output.bind.iter().chain(&output.connect) {
+            parse_endpoint(endpoint)
+                .with_context(|| format!("{}: invalid endpoint", output.name))?;
+        }
+        if !names.insert(&output.name) {
+            return Err(anyhow!("{}: output names must be unique", output.name));
+        }
+    }
+
+    Ok(config)
+}
@@ -0,0 +1,234 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use health::{Health, HealthArgs};
+use logging::LogArgs;
+use prost::Message;
+use protobuf_rs::utilidata::karman::bibimbap::v1::CompositeJoinedCalculations;
+use tracing::{Instrument, Span};
+use zeromq::{PubSocket, Socket, SocketRecv, SocketSend, SubSocket, ZmqMessage};
+
+use crate::config::{Config, Output};
+use crate::metrics::{FILTERED, PUBLISHED, RECEIVED, REJECTED, SEND_ERRORS, UNDECODABLE};
+use crate::transform::Transforms;
+
+mod config;
+mod metrics;
+mod transform;
+
+/// Subscribes to one publisher and republishes its frames to several downstream sockets, each
+/// with its own topic, stream filter and stream renames, optionally adding derived streams.
+#[derive(Parser)]
+struct Args {
+    /// TOML file with a [source] table, one [[output]] table per downstream and any
+    /// [[derived]] streams
+    #[arg(long)]
+    config: PathBuf,
+    /// Serve /metrics, /healthz and /readyz on this port
+    #[arg(long)]
+    prometheus_port: Option<u16>,
+    #[command(flatten)]
+    health: HealthArgs,
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+struct Downstream {
+    output: Output,
+    topic: Vec<u8>,
+    socket: PubSocket,
+}
+
+impl Downstream {
+    async fn open(output: Output, source_topic: &str) -> Result<Self> {
+        let mut socket = PubSocket::new();
+        if let Some(endpoint) = &output.bind {
+            socket
+                .bind(endpoint)
+                .await
+                .with_context(|| format!("{}: could not bind {endpoint}", output.name))?;
+            log::info!("{}: bound {endpoint}", output.name);
+        }
+        if let Some(endpoint) = &output.connect {
+            socket
+                .connect(endpoint)
+                .await
+                .with_context(|| format!("{}: could not connect to {endpoint}", output.name))?;
+            log::info!("{}: connected to {endpoint}", output.name);
+        }
+        let topic = output
+            .topic
+            .as_deref()
+            .unwrap_or(source_topic)
+            .as_bytes()
+            .to_vec();
+        Ok(Self {
+            output,
+            topic,
+            socket,
+        })
+    }
+
+    /// The payload this output publishes, or None when its filter leaves nothing.
+    fn rewrite(&self, joined: &CompositeJoinedCalculations) -> Option<Vec<u8>> {
+        let calculations: Vec<_> = joined
+            .calculations
+            .iter()
+            .filter(|calculation| {
+                self.output
+                    .streams
+                    .as_ref()
+                    .is_none_or(|streams| streams.contains(calculation.calculation_name()))
+            })
+            .cloned()
+            .map(|mut calculation| {
+                if let Some(renamed) = self.output.rename.get(calculation.calculation_name()) {
+                    calculation.calculation_name = Some(renamed.clone());
+                }
+                calculation
+            })
+            .collect();
+        if calculations.is_empty() {
+            return None;
+        }
+        Some(CompositeJoinedCalculations { calculations }.encode_to_vec())
+    }
+
+    /// The span publishing a frame to this output goes in.
+    fn sink_span(&self, frame: &Span) -> Span {
+        tracing::info_span!(parent: frame, "sink", output = self.output.name.as_str())
+    }
+
+    async fn publish(&mut self, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(self.topic.len() + payload.len());
+        frame.extend_from_slice(&self.topic);
+        frame.extend_from_slice(payload);
+
+        match self.socket.send(ZmqMessage::from(frame)).await {
+            Ok(()) => PUBLISHED.with_label_values(&[&self.output.name]).inc(),
+            Err(err) => {
+                SEND_ERRORS.with_label_values(&[&self.output.name]).inc();
+                log::warn!("{}: could not send: {err}", self.output.name);
+            }
+        }
+    }
+}
+
+/// Decodes a frame in a `decode` span under `frame`, naming `frame` after it.
+fn decode(payload: &[u8], frame: &Span) -> Option<CompositeJoinedCalculations> {
+    let joined = tracing::info_span!(parent: frame, "decode")
+        .in_scope(|| CompositeJoinedCalculations::decode(payload))
+        .inspect_err(|err| {
+            UNDECODABLE.inc();
+            log::error!("Could not decode incoming message: {err:#?}");
+        })
+        .ok()?;
+    logging::record_frame(frame, &joined);
+    Some(joined)
+}
+
+async fn run(args: Args) -> Result<()> {
+    let Config {
+        source,
+        outputs,
+        derived,
+    } = config::load(&args.config)?;
+    let transforms = Transforms::compile(&derived)
+        .with_context(|| format!("Invalid [[derived]] in {}", args.config.display()))?;
+
+    let health = Health::new(args.health);
+    let zmq = health.source("zmq");
+    if let Some(port) = args.prometheus_port {
+        tokio::spawn(metrics::serve(port, health));
+    }
+
+    let mut downstreams = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        downstreams.push(Downstream::open(output, &source.topic).await?);
+    }
+
+    let mut subscription = SubSocket::new();
+    subscription
+        .connect(&source.endpoint)
+        .await
+        .with_context(|| format!("Could not connect to {}", source.endpoint))?;
+    subscription
+        .subscribe(&source.topic)
+        .await
+        .context("Could not subscribe")?;
+    zmq.connected();
+    log::info!(
+        "Republishing '{}' from {} to {} outputs, with {} derived streams",
+        source.topic,
+        source.endpoint,
+        downstreams.len(),
+        derived.len()
+    );
+
+    loop {
+        let incoming = subscription
+            .recv()
+            .await
+            .inspect_err(|_| zmq.disconnected())
+            .context("Unable to receive message")?;
+        zmq.message();
+        let payload = match zmq_ingest::split_topic(&incoming, source.topic.as_bytes()) {
+            Ok(payload) => payload,
+            Err(err) => {
+                REJECTED.with_label_values(&[err.label()]).inc();
+                log::error!("Skipping message: {err}");
+                continue;
+            }
+        };
+        RECEIVED.inc();
+
+        // Decoded at most once, and only if there are derived streams or some output filters
+        // or renames
+        let mut decoded: Option<Option<CompositeJoinedCalculations>> = None;
+        // Named once the frame is decoded, if it is
+        let frame = logging::undecoded_frame_span();
+        let transformed;
+        let payload = if transforms.is_empty() {
+            payload
+        } else {
+            let Some(mut joined) = decode(payload, &frame) else {
+                continue;
+            };
+            frame.in_scope(|| {
+                tracing::info_span!("transform").in_scope(|| transforms.apply(&mut joined))
+            });
+            transformed = joined.encode_to_vec();
+            decoded = Some(Some(joined));
+            &transformed[..]
+        };
+        for downstream in downstreams.iter_mut() {
+            if !downstream.output.rewrites_frames() {
+                let sink = downstream.sink_span(&frame);
+                downstream.publish(payload).instrument(sink).await;
+                continue;
+            }
+
+            let joined = decoded.get_or_insert_with(|| decode(payload, &frame));
+            let Some(joined) = joined else {
+                continue;
+            };
+            let sink = downstream.sink_span(&frame);
+            match downstream.rewrite(joined) {
+                Some(rewritten) => downstream.publish(&rewritten).instrument(sink).await,
+                None => FILTERED.with_label_values(&[&downstream.output.name]).inc(),
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    logging::init(&args.log, "error");
+
+    if let Err(err) = run(args).await {
+        log::error!("{err:#}");
+        std::process::exit(1);
+    }
+}
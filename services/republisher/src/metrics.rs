@@ -0,0 +1,119 @@
+use std::sync::LazyLock;
+
+use axum::{
+    Router,
+    http::{HeaderMap, StatusCode},
+    routing::get,
+};
+use health::Health;
+use prometheus::{Encoder, IntCounter, IntCounterVec, TextEncoder};
+
+pub static RECEIVED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "republisher_frames_received_total",
+        "Frames received from the source"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static REJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "republisher_messages_rejected_total",
+        "Messages skipped because they did not start with the source topic (topic_mismatch) or were not a topic and a payload (frameless)",
+        &["reason"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static UNDECODABLE: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "republisher_frames_undecodable_total",
+        "Frames that derived streams or outputs with filters or renames could not decode, and so skipped"
+    )
+    .expect("Unable to register counter")
+});
+
+pub static PUBLISHED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "republisher_frames_published_total",
+        "Frames published to an output",
+        &["output"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static FILTERED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "republisher_frames_filtered_total",
+        "Frames not published to an output because none of their streams passed its filter",
+        &["output"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static SEND_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "republisher_send_errors_total",
+        "Frames an output's socket failed to send",
+        &["output"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub static DERIVED_SKIPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "republisher_derived_skipped_total",
+        "Frames published without a derived stream, because an input was missing or the result not a finite number",
+        &["stream"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+async fn metrics_handler() -> (StatusCode, HeaderMap, String) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {:?}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            HeaderMap::new(),
+            String::new(),
+        );
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "Content-Type",
+        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+    );
+
+    match String::from_utf8(buffer) {
+        Ok(body) => (StatusCode::OK, headers, body),
+        Err(e) => {
+            log::error!("Failed to convert metrics to UTF8: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                String::new(),
+            )
+        }
+    }
+}
+
+pub async fn serve(port: u16, health: Health) {
+    let addr = format!("0.0.0.0:{}", port);
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .merge(health.router());
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .expect("Could not bind prometheus server");
+    log::info!(
+        "republisher: Prometheus metrics server listening on {}",
+        addr
+    );
+
+    axum::serve(listener, app)
+        .await
+        .expect("Metrics server failed");
+}
@@ -0,0 +1,490 @@
+//! Derived streams: each `[[derived]]` table defines a new stream whose quantities are
+//! expressions over the incoming ones, evaluated separately for phases a and b, e.g.
+//!
+//! ```toml
+//! [[derived]]
+//! name = "site/net"
+//! inputs = { feeder1 = "threephase/karman1", feeder2 = "threephase/karman2", solar = "threephase/karman3" }
+//! real = "feeder1.real + feeder2.real - solar.real"
+//! ```
+//!
+//! Expressions have numbers, `+ - * /`, parentheses, `abs`, `sqrt`, `min` and `max`, and
+//! references `stream.quantity`, where `stream` is an alias from `inputs` or a stream name
+//! made of letters, digits and underscores. A derived stream can use the ones defined before
+//! it. Frames missing an input, or where a result isn't a finite number, go out without it.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result, anyhow, bail};
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeCalculations, CompositeJoinedCalculations, CompositeJoinedCalculationsWrapper,
+    CompositeTwoPhaseCalculations, PowerCalculations, WaveformCalculations,
+    composite_joined_calculations_wrapper::DataProduct,
+};
+use serde::Deserialize;
+
+use crate::metrics::DERIVED_SKIPPED;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Derived {
+    pub name: String,
+    /// Short names for input streams, usable in the expressions
+    #[serde(default)]
+    pub inputs: HashMap<String, String>,
+    /// Real power (W)
+    pub real: Option<String>,
+    /// Reactive power (VAr)
+    pub reactive: Option<String>,
+    /// Apparent power (VA)
+    pub apparent: Option<String>,
+    pub power_factor: Option<String>,
+    /// RMS voltage (V)
+    pub voltage: Option<String>,
+    /// RMS current (A)
+    pub current: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Quantity {
+    Real,
+    Reactive,
+    Apparent,
+    PowerFactor,
+    Voltage,
+    Current,
+}
+
+impl Quantity {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "real" => Self::Real,
+            "reactive" => Self::Reactive,
+            "apparent" => Self::Apparent,
+            "power_factor" | "pf" => Self::PowerFactor,
+            "voltage" => Self::Voltage,
+            "current" => Self::Current,
+            other => bail!(
+                "unknown quantity '{other}', expected real, reactive, apparent, power_factor, \
+                 voltage or current"
+            ),
+        })
+    }
+
+    fn of(self, calcs: &CompositeCalculations) -> Option<f64> {
+        let power = calcs.power_calculations;
+        let value = match self {
+            Self::Real => power?.real_power_w,
+            Self::Reactive => power?.reactive_power_var,
+            Self::Apparent => power?.apparent_power_va,
+            Self::PowerFactor => power?.power_factor,
+            Self::Voltage => calcs.voltage_waveform_calculations_v?.rms,
+            Self::Current => calcs.current_waveform_calculations_a?.rms,
+        };
+        value.map(f64::from)
+    }
+
+    fn set(self, calcs: &mut CompositeCalculations, value: f32) {
+        let power = calcs.power_calculations.get_or_insert_default();
+        match self {
+            Self::Real => power.real_power_w = Some(value),
+            Self::Reactive => power.reactive_power_var = Some(value),
+            Self::Apparent => power.apparent_power_va = Some(value),
+            Self::PowerFactor => power.power_factor = Some(value),
+            Self::Voltage => {
+                calcs
+                    .voltage_waveform_calculations_v
+                    .get_or_insert_default()
+                    .rms = Some(value)
+            }
+            Self::Current => {
+                calcs
+                    .current_waveform_calculations_a
+                    .get_or_insert_default()
+                    .rms = Some(value)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Func {
+    Abs,
+    Sqrt,
+    Min,
+    Max,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(f64),
+    Input { stream: String, quantity: Quantity },
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, phase: &dyn Fn(&str) -> Option<CompositeCalculations>) -> Option<f64> {
+        Some(match self {
+            Self::Number(value) => *value,
+            Self::Input { stream, quantity } => quantity.of(&phase(stream)?)?,
+            Self::Neg(inner) => -inner.eval(phase)?,
+            Self::Binary(op, left, right) => {
+                let (left, right) = (left.eval(phase)?, right.eval(phase)?);
+                match op {
+                    Op::Add => left + right,
+                    Op::Sub => left - right,
+                    Op::Mul => left * right,
+                    Op::Div => left / right,
+                }
+            }
+            Self::Call(func, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(phase))
+                    .collect::<Option<Vec<f64>>>()?;
+                match func {
+                    Func::Abs => args[0].abs(),
+                    Func::Sqrt => args[0].sqrt(),
+                    Func::Min => args.into_iter().fold(f64::INFINITY, f64::min),
+                    Func::Max => args.into_iter().fold(f64::NEG_INFINITY, f64::max),
+                }
+            }
+        })
+    }
+
+    fn streams<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Self::Number(_) => {}
+            Self::Input { stream, .. } => out.push(stream),
+            Self::Neg(inner) => inner.streams(out),
+            Self::Binary(_, left, right) => {
+                left.streams(out);
+                right.streams(out);
+            }
+            Self::Call(_, args) => args.iter().for_each(|arg| arg.streams(out)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Symbol(char),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Number(value) => write!(f, "'{value}'"),
+            Self::Ident(name) => write!(f, "'{name}'"),
+            Self::Symbol(symbol) => write!(f, "'{symbol}'"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let number = &source[start..end];
+            tokens.push(Token::Number(
+                number
+                    .parse()
+                    .map_err(|_| anyhow!("invalid number '{number}'"))?,
+            ));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Ident(source[start..end].to_string()));
+        } else if "+-*/().,".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            bail!("unexpected '{c}'");
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the usual precedence: `+ -`, then `* /`, then unary minus.
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    inputs: &'a HashMap<String, String>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<()> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(anyhow!("expected '{symbol}'"))
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        let mut expr = self.product()?;
+        loop {
+            let op = if self.eat('+') {
+                Op::Add
+            } else if self.eat('-') {
+                Op::Sub
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                Op::Mul
+            } else if self.eat('/') {
+                Op::Div
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Symbol('(')) => {
+                let expr = self.sum()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) if self.eat('(') => self.call(&name),
+            Some(Token::Ident(name)) => {
+                self.expect('.')
+                    .with_context(|| format!("after '{name}', as in {name}.real"))?;
+                let Some(Token::Ident(quantity)) = self.next() else {
+                    bail!("expected a quantity after '{name}.'");
+                };
+                let stream = self.inputs.get(&name).cloned().unwrap_or(name);
+                Ok(Expr::Input {
+                    stream,
+                    quantity: Quantity::parse(&quantity)?,
+                })
+            }
+            Some(token) => Err(anyhow!("unexpected {token}")),
+            None => Err(anyhow!("unexpected end of expression")),
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<Expr> {
+        let (func, arity) = match name {
+            "abs" => (Func::Abs, Some(1)),
+            "sqrt" => (Func::Sqrt, Some(1)),
+            "min" => (Func::Min, None),
+            "max" => (Func::Max, None),
+            other => bail!("unknown function '{other}'"),
+        };
+        let mut args = vec![self.sum()?];
+        while self.eat(',') {
+            args.push(self.sum()?);
+        }
+        self.expect(')')?;
+        if arity.is_some_and(|arity| args.len() != arity) {
+            bail!("{name} takes one argument");
+        }
+        Ok(Expr::Call(func, args))
+    }
+}
+
+fn parse(source: &str, inputs: &HashMap<String, String>) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+        inputs,
+    };
+    let expr = parser.sum()?;
+    if let Some(token) = parser.peek() {
+        bail!("unexpected {token}");
+    }
+    Ok(expr)
+}
+
+struct Transform {
+    name: String,
+    quantities: Vec<(Quantity, Expr)>,
+    /// Where the provenance (time, sequence) comes from
+    first_input: Option<String>,
+}
+
+impl Transform {
+    fn compile(derived: &Derived) -> Result<Self> {
+        let mut quantities = Vec::new();
+        for (quantity, source) in [
+            (Quantity::Real, &derived.real),
+            (Quantity::Reactive, &derived.reactive),
+            (Quantity::Apparent, &derived.apparent),
+            (Quantity::PowerFactor, &derived.power_factor),
+            (Quantity::Voltage, &derived.voltage),
+            (Quantity::Current, &derived.current),
+        ] {
+            let Some(source) = source else {
+                continue;
+            };
+            let expr = parse(source, &derived.inputs)
+                .with_context(|| format!("{}: invalid expression '{source}'", derived.name))?;
+            quantities.push((quantity, expr));
+        }
+        if quantities.is_empty() {
+            bail!("{}: defines no quantities", derived.name);
+        }
+
+        let mut streams = Vec::new();
+        for (_, expr) in &quantities {
+            expr.streams(&mut streams);
+        }
+        Ok(Self {
+            name: derived.name.clone(),
+            first_input: streams.first().map(|stream| stream.to_string()),
+            quantities,
+        })
+    }
+
+    fn phase(
+        &self,
+        streams: &HashMap<String, CompositeTwoPhaseCalculations>,
+        pick: fn(&CompositeTwoPhaseCalculations) -> Option<CompositeCalculations>,
+    ) -> Option<CompositeCalculations> {
+        let lookup = |stream: &str| streams.get(stream).and_then(pick);
+        let mut calcs = CompositeCalculations {
+            provenance: self
+                .first_input
+                .as_deref()
+                .and_then(lookup)
+                .and_then(|calcs| calcs.provenance),
+            voltage_waveform_calculations_v: Some(WaveformCalculations::default()),
+            current_waveform_calculations_a: Some(WaveformCalculations::default()),
+            power_calculations: Some(PowerCalculations::default()),
+        };
+        for (quantity, expr) in &self.quantities {
+            let value = expr.eval(&lookup).filter(|value| value.is_finite())?;
+            quantity.set(&mut calcs, value as f32);
+        }
+        Some(calcs)
+    }
+}
+
+/// The compiled `[[derived]]` tables, applied in order.
+pub struct Transforms(Vec<Transform>);
+
+impl Transforms {
+    pub fn compile(derived: &[Derived]) -> Result<Self> {
+        let mut names = HashSet::new();
+        for derived in derived {
+            if !names.insert(&derived.name) {
+                bail!("{}: derived stream names must be unique", derived.name);
+            }
+        }
+        Ok(Self(
+            derived
+                .iter()
+                .map(Transform::compile)
+                .collect::<Result<_>>()?,
+        ))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Appends the derived streams to a frame.
+    pub fn apply(&self, joined: &mut CompositeJoinedCalculations) {
+        let mut streams: HashMap<String, CompositeTwoPhaseCalculations> = joined
+            .calculations
+            .iter()
+            .filter_map(|wrapper| match &wrapper.data_product {
+                Some(DataProduct::Calculations(calcs)) => {
+                    Some((wrapper.calculation_name().to_string(), *calcs))
+                }
+                _ => None,
+            })
+            .collect();
+
+        for transform in &self.0 {
+            let phase_a = transform.phase(&streams, |calcs| calcs.phase_a);
+            let phase_b = transform.phase(&streams, |calcs| calcs.phase_b);
+            let (Some(phase_a), Some(phase_b)) = (phase_a, phase_b) else {
+                DERIVED_SKIPPED.with_label_values(&[&transform.name]).inc();
+                continue;
+            };
+            let calcs = CompositeTwoPhaseCalculations {
+                phase_a: Some(phase_a),
+                phase_b: Some(phase_b),
+                // Derived only when every input it reads came from a three-phase meter
+                phase_c: transform.phase(&streams, |calcs| calcs.phase_c),
+            };
+            streams.insert(transform.name.clone(), calcs);
+            joined
+                .calculations
+                .push(CompositeJoinedCalculationsWrapper {
+                    calculation_name: Some(transform.name.clone()),
+                    data_product: Some(DataProduct::Calculations(calcs)),
+                    device_id: None,
+                    harmonics: None,
+                });
+        }
+    }
+}
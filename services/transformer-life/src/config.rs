@@ -0,0 +1,105 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+
+/// Cooling class, which sets the IEEE C57.91 oil (n) and winding (m) exponents.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cooling {
+    #[default]
+    Onan,
+    Onaf,
+    Ofaf,
+    Odaf,
+}
+
+impl Cooling {
+    /// (n, m) from IEEE C57.91 Table 4
+    pub fn exponents(self) -> (f64, f64) {
+        match self {
+            Cooling::Onan => (0.8, 0.8),
+            Cooling::Onaf | Cooling::Ofaf => (0.9, 0.8),
+            Cooling::Odaf => (1.0, 1.0),
+        }
+    }
+}
+
+/// Nameplate and thermal data for one transformer. Defaults are typical for a 65 °C rise
+/// distribution transformer; use the manufacturer's test report where available.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transformer {
+    pub name: String,
+    pub rated_kva: f64,
+    /// Streams whose apparent power (phase A + phase B) is this transformer's load
+    pub streams: Vec<String>,
+    #[serde(default = "default_ambient")]
+    pub ambient_c: f64,
+    #[serde(default)]
+    pub cooling: Cooling,
+    /// Top-oil rise over ambient at rated load
+    #[serde(default = "default_top_oil_rise")]
+    pub rated_top_oil_rise_c: f64,
+    /// Winding hottest-spot rise over top oil at rated load
+    #[serde(default = "default_hot_spot_rise")]
+    pub rated_hot_spot_rise_c: f64,
+    /// Load losses at rated load divided by no-load losses
+    #[serde(default = "default_loss_ratio")]
+    pub load_loss_ratio: f64,
+    #[serde(default = "default_oil_time_constant")]
+    pub top_oil_time_constant_hours: f64,
+    #[serde(default = "default_winding_time_constant")]
+    pub winding_time_constant_minutes: f64,
+}
+
+fn default_ambient() -> f64 {
+    30.0
+}
+
+fn default_top_oil_rise() -> f64 {
+    55.0
+}
+
+fn default_hot_spot_rise() -> f64 {
+    25.0
+}
+
+fn default_loss_ratio() -> f64 {
+    5.0
+}
+
+fn default_oil_time_constant() -> f64 {
+    3.0
+}
+
+fn default_winding_time_constant() -> f64 {
+    5.0
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    #[serde(rename = "transformer")]
+    transformers: Vec<Transformer>,
+}
+
+pub fn load(path: &Path) -> Result<Vec<Transformer>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    let config: Config =
+        toml::from_str(&contents).with_context(|| format!("Invalid config {}", path.display()))?;
+
+    for transformer in &config.transformers {
+        if transformer.rated_kva <= 0.0 {
+            return Err(anyhow!("{}: rated_kva must be positive", transformer.name));
+        }
+        if transformer.streams.is_empty() {
+            return Err(anyhow!("{}: no streams configured", transformer.name));
+        }
+    }
+    if config.transformers.is_empty() {
+        return Err(anyhow!("No [[transformer]] entries in {}", path.display()));
+    }
+
+    Ok(config.transformers)
+}
@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Pool, Postgres, QueryBuilder};
+
+use crate::thermal::Reading;
+
+const TABLE: &str = "CREATE TABLE IF NOT EXISTS transformer_life (
+  time                   TIMESTAMPTZ      NOT NULL,
+  transformer            TEXT             NOT NULL,
+  loading_ratio          DOUBLE PRECISION NOT NULL,
+  top_oil_c              DOUBLE PRECISION NOT NULL,
+  hot_spot_c             DOUBLE PRECISION NOT NULL,
+  aging_factor           DOUBLE PRECISION NOT NULL,
+  equivalent_aging_hours DOUBLE PRECISION NOT NULL,
+  loss_of_life_percent   DOUBLE PRECISION NOT NULL
+)";
+
+const INDEX: &str = "CREATE INDEX IF NOT EXISTS transformer_life_transformer_time_idx
+  ON transformer_life (transformer, time DESC)";
+
+pub async fn prepare(pool: &Pool<Postgres>) -> Result<()> {
+    pool.execute(TABLE)
+        .await
+        .context("Could not create transformer_life table")?;
+    pool.execute(INDEX)
+        .await
+        .context("Could not create transformer_life index")?;
+    Ok(())
+}
+
+/// Aging accumulated by earlier runs, so loss of life stays cumulative across restarts.
+pub async fn last_aging_hours(pool: &Pool<Postgres>, transformer: &str) -> Result<f64> {
+    let hours: Option<f64> = sqlx::query_scalar(
+        "SELECT equivalent_aging_hours FROM transformer_life WHERE transformer = $1 ORDER BY time DESC LIMIT 1",
+    )
+    .bind(transformer)
+    .fetch_optional(pool)
+    .await
+    .context("Could not read previous aging")?;
+    Ok(hours.unwrap_or_default())
+}
+
+pub async fn insert(
+    pool: &Pool<Postgres>,
+    time: DateTime<Utc>,
+    rows: &[(String, Reading)],
+) -> Result<()> {
+    let mut builder = QueryBuilder::<Postgres>::new(
+        "INSERT INTO transformer_life (time, transformer, loading_ratio, top_oil_c, hot_spot_c, \
+         aging_factor, equivalent_aging_hours, loss_of_life_percent) ",
+    );
+    builder.push_values(rows, |mut b, (transformer, reading)| {
+        b.push_bind(time)
+            .push_bind(transformer)
+            .push_bind(reading.loading_ratio)
+            .push_bind(reading.top_oil_c)
+            .push_bind(reading.hot_spot_c)
+            .push_bind(reading.aging_factor)
+            .push_bind(reading.equivalent_aging_hours)
+            .push_bind(reading.loss_of_life_percent());
+    });
+    builder
+        .build()
+        .execute(pool)
+        .await
+        .context("Could not insert transformer rows")?;
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use health::{Health, HealthArgs};
+use logging::LogArgs;
+use prost::Message;
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    CompositeJoinedCalculations, composite_joined_calculations_wrapper::DataProduct,
+};
+use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
+use tokio::time::Instant;
+use zeromq::{Socket, SocketRecv, SubSocket};
+
+use crate::metrics::REJECTED;
+use crate::thermal::{Reading, ThermalModel};
+
+mod config;
+mod db;
+mod metrics;
+mod thermal;
+
+/// Estimates transformer loading, hottest-spot temperature and insulation loss of life
+/// (IEEE C57.91) from the apparent power of the streams feeding each transformer.
+#[derive(Parser, Clone)]
+struct Args {
+    /// TOML file with one [[transformer]] table per transformer
+    #[arg(long)]
+    config: PathBuf,
+    /// tcp://HOST:PORT, or ipc://PATH for a publisher on the same host
+    #[arg(long, value_parser = zmq_ingest::parse_endpoint)]
+    zmq_endpoint: String,
+    #[arg(long, default_value = "")]
+    zmq_topic: String,
+    /// Serves /metrics, /healthz and /readyz
+    #[arg(long)]
+    prometheus_port: u16,
+    #[command(flatten)]
+    health: HealthArgs,
+    /// Write a row per transformer to the transformer_life table, and resume cumulative
+    /// aging from it on startup
+    #[arg(long)]
+    connection_string: Option<String>,
+    /// How often the thermal model is advanced
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    step_interval: Duration,
+    /// How often rows are written
+    #[arg(long, default_value = "1m", value_parser = humantime::parse_duration)]
+    row_interval: Duration,
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+/// A transformer's model plus the load seen since the last step.
+struct Tracked {
+    model: ThermalModel,
+    va_sum: f64,
+    samples: u64,
+    last_kva: f64,
+    latest: Option<Reading>,
+}
+
+impl Tracked {
+    fn add_frame(&mut self, joined: &CompositeJoinedCalculations) {
+        let mut va = 0.0;
+        let mut found = false;
+        for calculation in &joined.calculations {
+            if !self
+                .model
+                .config()
+                .streams
+                .iter()
+                .any(|stream| stream == calculation.calculation_name())
+            {
+                continue;
+            }
+            let Some(DataProduct::Calculations(calcs)) = &calculation.data_product else {
+                continue;
+            };
+            for phase in [calcs.phase_a, calcs.phase_b, calcs.phase_c]
+                .into_iter()
+                .flatten()
+            {
+                if let Some(power) = phase.power_calculations {
+                    va += power.apparent_power_va() as f64;
+                    found = true;
+                }
+            }
+        }
+        if found {
+            self.va_sum += va;
+            self.samples += 1;
+        }
+    }
+
+    fn step(&mut self, hours: f64) {
+        // Hold the last known load through gaps in the stream rather than letting it cool
+        if self.samples > 0 {
+            self.last_kva = self.va_sum / self.samples as f64 / 1000.0;
+        }
+        self.va_sum = 0.0;
+        self.samples = 0;
+
+        let reading = self.model.step(self.last_kva, hours);
+        metrics::set(&self.model.config().name, &reading);
+        self.latest = Some(reading);
+    }
+}
+
+async fn prepare_subscribe(args: &Args) -> Result<SubSocket> {
+    let mut subsocket = SubSocket::new();
+    subsocket
+        .connect(&args.zmq_endpoint)
+        .await
+        .context("Could not connect to socket")?;
+    subsocket
+        .subscribe(&args.zmq_topic)
+        .await
+        .context("Could not subscribe")?;
+    log::info!(
+        "Subscribed to {} topic '{}'",
+        args.zmq_endpoint,
+        args.zmq_topic
+    );
+    Ok(subsocket)
+}
+
+async fn write_rows(pool: &Pool<Postgres>, tracked: &[Tracked]) {
+    let rows: Vec<_> = tracked
+        .iter()
+        .filter_map(|t| Some((t.model.config().name.clone(), t.latest.clone()?)))
+        .collect();
+    if rows.is_empty() {
+        return;
+    }
+    if let Err(err) = db::insert(pool, chrono::Utc::now(), &rows).await {
+        log::error!("{err:#}");
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    let transformers = config::load(&args.config)?;
+
+    let pool = match &args.connection_string {
+        Some(connection_string) => {
+            let pool = PgPoolOptions::new()
+                .max_connections(2)
+                .connect(connection_string)
+                .await
+                .context("Could not connect to database")?;
+            db::prepare(&pool).await?;
+            Some(pool)
+        }
+        None => None,
+    };
+
+    let mut tracked = Vec::new();
+    for transformer in transformers {
+        let aging = match &pool {
+            Some(pool) => db::last_aging_hours(pool, &transformer.name).await?,
+            None => 0.0,
+        };
+        log::info!(
+            "Tracking {} ({} kVA, streams {:?}), resuming at {aging:.1} equivalent aging hours",
+            transformer.name,
+            transformer.rated_kva,
+            transformer.streams
+        );
+        tracked.push(Tracked {
+            model: ThermalModel::new(transformer, aging),
+            va_sum: 0.0,
+            samples: 0,
+            last_kva: 0.0,
+            latest: None,
+        });
+    }
+
+    let health = Health::new(args.health);
+    let zmq = health.source("zmq");
+    if let Some(pool) = pool.clone() {
+        health.check("database", move || {
+            let pool = pool.clone();
+            async move { sqlx::query("SELECT 1").execute(&pool).await.map(|_| ()) }
+        });
+    }
+    tokio::spawn(metrics::serve(args.prometheus_port, health));
+
+    let mut subscription = prepare_subscribe(&args).await?;
+    zmq.connected();
+    let mut step_timer = tokio::time::interval(args.step_interval);
+    let mut row_timer = tokio::time::interval(args.row_interval);
+    let mut last_step = Instant::now();
+
+    loop {
+        tokio::select! {
+            incoming = subscription.recv() => {
+                let incoming = incoming
+                    .inspect_err(|_| zmq.disconnected())
+                    .context("Unable to receive message")?;
+                zmq.message();
+                let buf = match zmq_ingest::split_topic(&incoming, args.zmq_topic.as_bytes()) {
+                    Ok(buf) => buf,
+                    Err(err) => {
+                        REJECTED.with_label_values(&[err.label()]).inc();
+                        log::error!("Skipping message: {err}");
+                        continue;
+                    }
+                };
+                match CompositeJoinedCalculations::decode(buf) {
+                    Ok(joined) => tracked.iter_mut().for_each(|t| t.add_frame(&joined)),
+                    Err(err) => {
+                        REJECTED.with_label_values(&["undecodable"]).inc();
+                        log::error!("Could not decode incoming message: {err:#?}");
+                    }
+                }
+            }
+            _ = step_timer.tick() => {
+                let hours = last_step.elapsed().as_secs_f64() / 3600.0;
+                last_step = Instant::now();
+                for t in tracked.iter_mut() {
+                    t.step(hours);
+                }
+            }
+            _ = row_timer.tick() => {
+                if let Some(pool) = &pool {
+                    write_rows(pool, &tracked).await;
+                }
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    logging::init(&args.log, "error");
+
+    if let Err(err) = run(args).await {
+        log::error!("{err:#}");
+        std::process::exit(1);
+    }
+}
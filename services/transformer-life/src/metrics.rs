@@ -0,0 +1,131 @@
+use std::sync::LazyLock;
+
+use axum::{
+    Router,
+    http::{HeaderMap, StatusCode},
+    routing::get,
+};
+use health::Health;
+use prometheus::{Encoder, GaugeVec, IntCounterVec, TextEncoder};
+
+use crate::thermal::Reading;
+
+macro_rules! transformer_gauge {
+    ($VAR:ident, $name:literal, $description:literal) => {
+        static $VAR: LazyLock<GaugeVec> = LazyLock::new(|| {
+            prometheus::register_gauge_vec!($name, $description, &["transformer"])
+                .expect("Unable to register gauge vec")
+        });
+    };
+}
+
+transformer_gauge!(
+    LOADING,
+    "transformer_loading_ratio",
+    "Apparent power over nameplate rating"
+);
+transformer_gauge!(
+    TOP_OIL,
+    "transformer_top_oil_celsius",
+    "Estimated top-oil temperature"
+);
+transformer_gauge!(
+    HOT_SPOT,
+    "transformer_hot_spot_celsius",
+    "Estimated winding hottest-spot temperature (IEEE C57.91)"
+);
+transformer_gauge!(
+    AGING_FACTOR,
+    "transformer_aging_acceleration_factor",
+    "Insulation aging rate relative to a 110 C hottest spot"
+);
+transformer_gauge!(
+    AGING_HOURS,
+    "transformer_equivalent_aging_hours",
+    "Cumulative equivalent aging at the reference temperature"
+);
+transformer_gauge!(
+    LOSS_OF_LIFE,
+    "transformer_loss_of_life_percent",
+    "Cumulative insulation loss of life against a 180000 h normal life"
+);
+
+pub static REJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "transformer_life_messages_rejected_total",
+        "Messages skipped because they did not start with the subscribed topic (topic_mismatch), were not a topic and a payload (frameless) or did not decode (undecodable)",
+        &["reason"]
+    )
+    .expect("Unable to register counter vec")
+});
+
+pub fn set(transformer: &str, reading: &Reading) {
+    LOADING
+        .with_label_values(&[transformer])
+        .set(reading.loading_ratio);
+    TOP_OIL
+        .with_label_values(&[transformer])
+        .set(reading.top_oil_c);
+    HOT_SPOT
+        .with_label_values(&[transformer])
+        .set(reading.hot_spot_c);
+    AGING_FACTOR
+        .with_label_values(&[transformer])
+        .set(reading.aging_factor);
+    AGING_HOURS
+        .with_label_values(&[transformer])
+        .set(reading.equivalent_aging_hours);
+    LOSS_OF_LIFE
+        .with_label_values(&[transformer])
+        .set(reading.loss_of_life_percent());
+}
+
+async fn metrics_handler() -> (StatusCode, HeaderMap, String) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {:?}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            HeaderMap::new(),
+            String::new(),
+        );
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "Content-Type",
+        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+    );
+
+    match String::from_utf8(buffer) {
+        Ok(body) => (StatusCode::OK, headers, body),
+        Err(e) => {
+            log::error!("Failed to convert metrics to UTF8: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                String::new(),
+            )
+        }
+    }
+}
+
+pub async fn serve(port: u16, health: Health) {
+    let addr = format!("0.0.0.0:{}", port);
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .merge(health.router());
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .expect("Could not bind prometheus server");
+    log::info!(
+        "transformer-life: Prometheus metrics server listening on {}",
+        addr
+    );
+
+    axum::serve(listener, app)
+        .await
+        .expect("Metrics server failed");
+}
@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres, QueryBuilder};
+
+/// Rows the device already has for `stream` in the given range, so re-running an import
+/// doesn't silently duplicate history.
+pub async fn existing_rows(
+    pool: &Pool<Postgres>,
+    device: &str,
+    tenant: Option<&str>,
+    stream: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<i64> {
+    sqlx::query_scalar(
+        "SELECT count(*) FROM bibimbap WHERE device = $1 AND tenant IS NOT DISTINCT FROM $2 \
+         AND time BETWEEN $3 AND $4 AND data ? $5",
+    )
+    .bind(device)
+    .bind(tenant)
+    .bind(start)
+    .bind(end)
+    .bind(stream)
+    .fetch_one(pool)
+    .await
+    .context("Could not check for existing rows")
+}
+
+/// Writes all rows in one transaction, so a failed import leaves nothing behind.
+pub async fn insert(
+    pool: &Pool<Postgres>,
+    device: &str,
+    tenant: Option<&str>,
+    rows: &[(DateTime<Utc>, serde_json::Value)],
+    batch_size: usize,
+) -> Result<()> {
+    let mut tx = pool.begin().await.context("Could not start transaction")?;
+
+    for (written, chunk) in rows.chunks(batch_size.max(1)).enumerate() {
+        let mut builder =
+            QueryBuilder::<Postgres>::new("INSERT INTO bibimbap (time, device, tenant, data) ");
+        builder.push_values(chunk, |mut b, (time, data)| {
+            b.push_bind(time)
+                .push_bind(device)
+                .push_bind(tenant)
+                .push_bind(data);
+        });
+        builder
+            .build()
+            .execute(&mut *tx)
+            .await
+            .context("Could not insert rows")?;
+        log::debug!(
+            "Inserted {} of {} rows",
+            (written * batch_size.max(1) + chunk.len()),
+            rows.len()
+        );
+    }
+
+    tx.commit().await.context("Could not commit import")?;
+    Ok(())
+}
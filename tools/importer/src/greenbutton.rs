@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use chrono::DateTime;
+use quick_xml::{Reader, events::Event};
+
+use crate::readings::{Bucket, Field, Readings};
+
+/// ESPI flowDirection for energy received from the customer (exported to the grid)
+const FLOW_REVERSE: u32 = 19;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct ReadingType {
+    power_of_ten_multiplier: i32,
+    uom: u32,
+    flow_direction: u32,
+}
+
+impl ReadingType {
+    /// The field a reading maps to, and whether it is energy over the interval (Wh and
+    /// friends) that has to be turned into average power.
+    fn field(&self) -> Result<(Field, bool)> {
+        Ok(match self.uom {
+            72 => (Field::RealPower, true),
+            73 => (Field::ReactivePower, true),
+            71 => (Field::ApparentPower, true),
+            38 => (Field::RealPower, false),
+            63 => (Field::ReactivePower, false),
+            61 => (Field::ApparentPower, false),
+            29 => (Field::RmsVoltage, false),
+            5 => (Field::RmsCurrent, false),
+            65 => (Field::PowerFactor, false),
+            uom => return Err(anyhow!("Unsupported GreenButton unit of measure {uom}")),
+        })
+    }
+}
+
+#[derive(Default)]
+struct IntervalReading {
+    start: Option<i64>,
+    duration: Option<i64>,
+    value: Option<f64>,
+}
+
+/// Reads a GreenButton (ESPI) XML download into `readings`. Each IntervalBlock uses the
+/// ReadingType that precedes it in the document, which is how utility "Download My Data"
+/// files are laid out. Values are stored under the `total` bucket. Returns the number of
+/// interval readings read.
+pub fn read(path: &Path, readings: &mut Readings) -> Result<usize> {
+    let mut reader = Reader::from_file(path).context("Could not open GreenButton file")?;
+    reader.config_mut().trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut elements: Vec<String> = Vec::new();
+    let mut pending_type = ReadingType::default();
+    let mut reading_type: Option<ReadingType> = None;
+    let mut interval = IntervalReading::default();
+    let mut count = 0;
+
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .with_context(|| format!("Invalid XML at byte {}", reader.buffer_position()))?;
+        match event {
+            Event::Start(element) => {
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+                match name.as_str() {
+                    "ReadingType" => pending_type = ReadingType::default(),
+                    "IntervalReading" => interval = IntervalReading::default(),
+                    _ => {}
+                }
+                elements.push(name);
+            }
+            Event::Text(text) => {
+                let text = text.unescape().context("Invalid XML text")?;
+                let text = text.trim();
+                let within = |ancestor: &str| elements.iter().any(|name| name == ancestor);
+                let Some(current) = elements.last() else {
+                    continue;
+                };
+
+                if within("ReadingType") {
+                    match current.as_str() {
+                        "powerOfTenMultiplier" => {
+                            pending_type.power_of_ten_multiplier = parse(text, current)?
+                        }
+                        "uom" => pending_type.uom = parse(text, current)?,
+                        "flowDirection" => pending_type.flow_direction = parse(text, current)?,
+                        _ => {}
+                    }
+                } else if within("IntervalReading") {
+                    match current.as_str() {
+                        "start" if within("timePeriod") => {
+                            interval.start = Some(parse(text, current)?)
+                        }
+                        "duration" if within("timePeriod") => {
+                            interval.duration = Some(parse(text, current)?)
+                        }
+                        "value" => interval.value = Some(parse(text, current)?),
+                        _ => {}
+                    }
+                }
+            }
+            Event::End(_) => match elements.pop().as_deref() {
+                Some("ReadingType") => reading_type = Some(pending_type),
+                Some("IntervalReading") => {
+                    let reading_type = reading_type
+                        .ok_or_else(|| anyhow!("IntervalReading found before any ReadingType"))?;
+                    if add_interval(&interval, &reading_type, readings)? {
+                        count += 1;
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(count)
+}
+
+fn add_interval(
+    interval: &IntervalReading,
+    reading_type: &ReadingType,
+    readings: &mut Readings,
+) -> Result<bool> {
+    let (Some(start), Some(value)) = (interval.start, interval.value) else {
+        log::warn!("Skipping IntervalReading without a start time or value");
+        return Ok(false);
+    };
+    let time = DateTime::from_timestamp(start, 0)
+        .ok_or_else(|| anyhow!("Interval start {start} is out of range"))?;
+
+    let (field, is_energy) = reading_type.field()?;
+    let mut value = value * 10f64.powi(reading_type.power_of_ten_multiplier);
+    if is_energy {
+        let hours = match interval.duration {
+            Some(seconds) if seconds > 0 => seconds as f64 / 3600.0,
+            _ => {
+                log::warn!("Skipping energy reading at {time} without an interval duration");
+                return Ok(false);
+            }
+        };
+        value /= hours;
+    }
+    if reading_type.flow_direction == FLOW_REVERSE && field.is_directional() {
+        value = -value;
+    }
+
+    let channel = format!("flow direction {}", reading_type.flow_direction);
+    readings.add(time, Bucket::Total, field, &channel, value);
+    Ok(true)
+}
+
+fn parse<T: std::str::FromStr>(text: &str, element: &str) -> Result<T> {
+    text.parse()
+        .map_err(|_| anyhow!("Invalid <{element}> value '{text}'"))
+}
@@ -0,0 +1,169 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, anyhow};
+use clap::{Parser, ValueEnum};
+use logging::LogArgs;
+use sqlx::postgres::PgPoolOptions;
+
+use crate::meter_csv::{Column, Layout, parse_column};
+use crate::readings::Readings;
+
+mod db;
+mod greenbutton;
+mod meter_csv;
+mod readings;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    /// Landis+Gyr load profile CSV
+    LandisGyr,
+    /// Schneider ION data log CSV
+    Ion,
+    /// GreenButton (ESPI) XML
+    Greenbutton,
+    /// Any CSV, mapped entirely with --time-column, --time-format and --column
+    Csv,
+}
+
+/// Imports historical meter exports into the bibimbap table, so history from before the
+/// module was installed can be queried next to live data.
+#[derive(Parser)]
+struct Args {
+    /// Export files to import
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    #[arg(long, value_enum)]
+    format: Format,
+    /// Postgres connection string, as passed to data-db
+    #[arg(long, required_unless_present = "dry_run")]
+    connection_string: Option<String>,
+    /// Value of the `device` column
+    #[arg(long, default_value = "bibimbap")]
+    device: String,
+    /// Customer site the rows belong to, as data-db's --tenant
+    #[arg(long)]
+    tenant: Option<String>,
+    /// Stream name the readings are stored under in the `data` column
+    #[arg(long, default_value = "threephase/meter")]
+    stream: String,
+    /// IANA timezone of CSV timestamps (GreenButton timestamps are always UTC)
+    #[arg(long, default_value = "UTC")]
+    timezone: chrono_tz::Tz,
+    /// CSV: column(s) holding the timestamp, replacing the format's preset (repeatable)
+    #[arg(long)]
+    time_column: Vec<String>,
+    /// CSV: chrono format for the timestamp, replacing the format's preset (repeatable)
+    #[arg(long)]
+    time_format: Vec<String>,
+    /// CSV: map a column as HEADER=BUCKET.FIELD[*SCALE], e.g. "kW a=phase_a.real_power*1000".
+    /// BUCKET is phase_a, phase_b or total. Replaces a preset column with the same header.
+    #[arg(long = "column", value_parser = parse_column)]
+    columns: Vec<(String, Column)>,
+    /// Rows per INSERT statement
+    #[arg(long, default_value_t = 1000)]
+    batch_size: usize,
+    /// Import even if the device already has rows for this stream in the file's time range
+    #[arg(long)]
+    allow_overlap: bool,
+    /// Parse and report, but don't write anything
+    #[arg(long)]
+    dry_run: bool,
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+impl Args {
+    fn layout(&self) -> Option<Layout> {
+        let preset = match self.format {
+            Format::LandisGyr => Layout::landis_gyr(),
+            Format::Ion => Layout::ion(),
+            Format::Csv => Layout::custom(),
+            Format::Greenbutton => return None,
+        };
+        Some(preset.with_overrides(&self.time_column, &self.time_format, &self.columns))
+    }
+}
+
+fn read_files(args: &Args) -> Result<Readings> {
+    let layout = args.layout();
+    let mut readings = Readings::default();
+
+    for path in &args.files {
+        let count = match &layout {
+            Some(layout) => meter_csv::read(path, layout, args.timezone, &mut readings),
+            None => greenbutton::read(path, &mut readings),
+        }
+        .with_context(|| format!("Could not import {}", path.display()))?;
+        log::info!("Read {count} readings from {}", path.display());
+    }
+    if readings.duplicates() > 0 {
+        log::warn!(
+            "{} values were read more than once (overlapping exports?); the last one read is kept",
+            readings.duplicates()
+        );
+    }
+
+    Ok(readings)
+}
+
+async fn run(args: Args) -> Result<()> {
+    let readings = read_files(&args)?;
+    let Some((start, end)) = readings.time_range() else {
+        return Err(anyhow!("No readings found"));
+    };
+    log::info!(
+        "{} rows for device '{}', stream '{}', from {start} to {end}",
+        readings.len(),
+        args.device,
+        args.stream
+    );
+
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(args.connection_string.as_deref().unwrap_or_default())
+        .await
+        .context("Could not connect to database")?;
+
+    let existing = db::existing_rows(
+        &pool,
+        &args.device,
+        args.tenant.as_deref(),
+        &args.stream,
+        start,
+        end,
+    )
+    .await?;
+    if existing > 0 && !args.allow_overlap {
+        return Err(anyhow!(
+            "{existing} rows already exist for this device and stream between {start} and {end}; \
+             pass --allow-overlap to import anyway"
+        ));
+    }
+
+    let rows = readings.into_rows(&args.stream);
+    db::insert(
+        &pool,
+        &args.device,
+        args.tenant.as_deref(),
+        &rows,
+        args.batch_size,
+    )
+    .await?;
+    log::info!("Imported {} rows", rows.len());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    logging::init(&args.log, "info");
+
+    if let Err(err) = run(args).await {
+        log::error!("{err:#}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, LocalResult, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::readings::{Bucket, Field, Readings};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Column {
+    pub bucket: Bucket,
+    pub field: Field,
+    /// Multiplier into data-db's units (W, VAr, VA, V, A, PF as a ratio)
+    pub scale: f64,
+}
+
+/// Parses `--column HEADER=BUCKET.FIELD[*SCALE]`, e.g. `kW a=phase_a.real_power*1000`
+pub fn parse_column(value: &str) -> Result<(String, Column), String> {
+    let (header, target) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected HEADER=BUCKET.FIELD[*SCALE], got '{value}'"))?;
+    let (target, scale) = match target.split_once('*') {
+        Some((target, scale)) => (
+            target,
+            scale
+                .parse()
+                .map_err(|_| format!("invalid scale '{scale}'"))?,
+        ),
+        None => (target, 1.0),
+    };
+    let (bucket, field) = target
+        .split_once('.')
+        .ok_or_else(|| format!("expected BUCKET.FIELD, got '{target}'"))?;
+    let bucket = Bucket::parse(bucket).ok_or_else(|| format!("unknown bucket '{bucket}'"))?;
+    let field = Field::parse(field).ok_or_else(|| format!("unknown field '{field}'"))?;
+
+    Ok((
+        header.trim().to_string(),
+        Column {
+            bucket,
+            field,
+            scale,
+        },
+    ))
+}
+
+/// Which columns hold the timestamp and values. Headers are matched case-insensitively.
+pub struct Layout {
+    /// Joined with a space before parsing, for exports that split date and time
+    pub time_columns: Vec<String>,
+    pub time_formats: Vec<String>,
+    pub columns: Vec<(String, Column)>,
+}
+
+fn column(header: &str, bucket: Bucket, field: Field, scale: f64) -> (String, Column) {
+    (
+        header.to_string(),
+        Column {
+            bucket,
+            field,
+            scale,
+        },
+    )
+}
+
+impl Layout {
+    /// Load profile exports from Landis+Gyr MAP tools (E650/E850 and similar).
+    pub fn landis_gyr() -> Self {
+        use Bucket::*;
+        use Field::*;
+        Self {
+            time_columns: vec!["Date".to_string(), "Time".to_string()],
+            time_formats: vec![
+                "%d.%m.%Y %H:%M:%S".to_string(),
+                "%d.%m.%Y %H:%M".to_string(),
+                "%Y-%m-%d %H:%M:%S".to_string(),
+            ],
+            columns: vec![
+                column("+P [kW]", Total, RealPower, 1000.0),
+                column("-P [kW]", Total, RealPower, -1000.0),
+                column("+Q [kvar]", Total, ReactivePower, 1000.0),
+                column("-Q [kvar]", Total, ReactivePower, -1000.0),
+                column("S [kVA]", Total, ApparentPower, 1000.0),
+                column("PF", Total, PowerFactor, 1.0),
+                column("U L1 [V]", PhaseA, RmsVoltage, 1.0),
+                column("U L2 [V]", PhaseB, RmsVoltage, 1.0),
+                column("I L1 [A]", PhaseA, RmsCurrent, 1.0),
+                column("I L2 [A]", PhaseB, RmsCurrent, 1.0),
+                column("P L1 [kW]", PhaseA, RealPower, 1000.0),
+                column("P L2 [kW]", PhaseB, RealPower, 1000.0),
+                column("Q L1 [kvar]", PhaseA, ReactivePower, 1000.0),
+                column("Q L2 [kvar]", PhaseB, ReactivePower, 1000.0),
+            ],
+        }
+    }
+
+    /// Data log exports from Schneider ION meters (ION Setup / Power Monitoring Expert).
+    pub fn ion() -> Self {
+        use Bucket::*;
+        use Field::*;
+        Self {
+            time_columns: vec!["Timestamp".to_string()],
+            time_formats: vec![
+                "%Y-%m-%d %H:%M:%S".to_string(),
+                "%Y-%m-%d %H:%M:%S%.f".to_string(),
+                "%m/%d/%Y %H:%M:%S".to_string(),
+                "%m/%d/%Y %I:%M:%S %p".to_string(),
+            ],
+            columns: vec![
+                column("Vln a", PhaseA, RmsVoltage, 1.0),
+                column("Vln b", PhaseB, RmsVoltage, 1.0),
+                column("I a", PhaseA, RmsCurrent, 1.0),
+                column("I b", PhaseB, RmsCurrent, 1.0),
+                column("kW a", PhaseA, RealPower, 1000.0),
+                column("kW b", PhaseB, RealPower, 1000.0),
+                column("kW tot", Total, RealPower, 1000.0),
+                column("kVAR a", PhaseA, ReactivePower, 1000.0),
+                column("kVAR b", PhaseB, ReactivePower, 1000.0),
+                column("kVAR tot", Total, ReactivePower, 1000.0),
+                column("kVA a", PhaseA, ApparentPower, 1000.0),
+                column("kVA b", PhaseB, ApparentPower, 1000.0),
+                column("kVA tot", Total, ApparentPower, 1000.0),
+                // ION reports signed power factor in percent
+                column("PF sign a", PhaseA, PowerFactor, 0.01),
+                column("PF sign b", PhaseB, PowerFactor, 0.01),
+                column("PF sign tot", Total, PowerFactor, 0.01),
+            ],
+        }
+    }
+
+    /// No presets; everything comes from --time-column, --time-format and --column.
+    pub fn custom() -> Self {
+        Self {
+            time_columns: Vec::new(),
+            time_formats: Vec::new(),
+            columns: Vec::new(),
+        }
+    }
+
+    /// Applies command line overrides. A --column with the same header as a preset replaces it.
+    pub fn with_overrides(
+        mut self,
+        time_columns: &[String],
+        time_formats: &[String],
+        columns: &[(String, Column)],
+    ) -> Self {
+        if !time_columns.is_empty() {
+            self.time_columns = time_columns.to_vec();
+        }
+        if !time_formats.is_empty() {
+            self.time_formats = time_formats.to_vec();
+        }
+        for (header, column) in columns {
+            self.columns
+                .retain(|(existing, _)| !existing.eq_ignore_ascii_case(header));
+            self.columns.push((header.clone(), *column));
+        }
+        self
+    }
+}
+
+/// Reads one export into `readings`, interpreting its local timestamps in `timezone`.
+/// Returns the number of rows read.
+pub fn read(path: &Path, layout: &Layout, timezone: Tz, readings: &mut Readings) -> Result<usize> {
+    if layout.time_columns.is_empty() || layout.time_formats.is_empty() {
+        return Err(anyhow!("No time column or time format configured"));
+    }
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(sniff_delimiter(path)?)
+        .flexible(true)
+        .from_path(path)
+        .context("Could not open export file")?;
+    let headers = rdr.headers().context("Could not read header row")?.clone();
+    let position = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header.trim().eq_ignore_ascii_case(name))
+    };
+
+    let time_indices = layout
+        .time_columns
+        .iter()
+        .map(|name| position(name).ok_or_else(|| anyhow!("Missing time column '{name}'")))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut value_indices = Vec::new();
+    for (header, column) in &layout.columns {
+        match position(header) {
+            Some(index) => value_indices.push((index, header, *column)),
+            None => log::debug!("Column '{header}' not present in {}", path.display()),
+        }
+    }
+    if value_indices.is_empty() {
+        return Err(anyhow!(
+            "None of the mapped columns are present (headers: {:?})",
+            headers.iter().collect::<Vec<_>>()
+        ));
+    }
+    log::info!(
+        "{}: importing columns {:?}",
+        path.display(),
+        value_indices
+            .iter()
+            .map(|(_, header, _)| header.as_str())
+            .collect::<Vec<_>>()
+    );
+
+    let mut rows = 0;
+    let mut repeated = HashSet::new();
+    for (line, record) in rdr.records().enumerate() {
+        let record = record.with_context(|| format!("Failed to parse row {}", line + 2))?;
+
+        let stamp = time_indices
+            .iter()
+            .map(|index| record.get(*index).unwrap_or_default().trim())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let time = match parse_time(&stamp, &layout.time_formats, timezone, &mut repeated) {
+            Ok(time) => time,
+            Err(err) => {
+                log::warn!("Skipping row {}: {err}", line + 2);
+                continue;
+            }
+        };
+
+        for (index, header, column) in &value_indices {
+            let Some(value) = record.get(*index).and_then(parse_number) else {
+                log::debug!("Row {}: no numeric value for '{header}'", line + 2);
+                continue;
+            };
+            readings.add(
+                time,
+                column.bucket,
+                column.field,
+                header,
+                value * column.scale,
+            );
+        }
+        rows += 1;
+    }
+
+    Ok(rows)
+}
+
+/// European exports are usually `;`-separated; pick whichever separator the header uses more.
+fn sniff_delimiter(path: &Path) -> Result<u8> {
+    let mut header = String::new();
+    BufReader::new(File::open(path).context("Could not open export file")?)
+        .read_line(&mut header)
+        .context("Could not read header row")?;
+    let count = |delimiter| header.matches(delimiter).count();
+    Ok(if count(';') > count(',') { b';' } else { b',' })
+}
+
+/// `repeated` holds the local times of the hour clocks go back over that were already read,
+/// so their second occurrence is taken as the later instant, as rows of an export run in
+/// time order.
+fn parse_time(
+    stamp: &str,
+    formats: &[String],
+    timezone: Tz,
+    repeated: &mut HashSet<NaiveDateTime>,
+) -> Result<DateTime<Utc>> {
+    let naive = formats
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(stamp, format).ok())
+        .ok_or_else(|| anyhow!("Timestamp '{stamp}' matches none of {formats:?}"))?;
+
+    match timezone.from_local_datetime(&naive) {
+        LocalResult::Single(time) => Ok(time.with_timezone(&Utc)),
+        // The repeated hour when clocks go back; exports rarely say which one they mean
+        LocalResult::Ambiguous(earliest, latest) => {
+            if repeated.insert(naive) {
+                log::debug!("Ambiguous local time '{stamp}', using the earlier instant");
+                Ok(earliest.with_timezone(&Utc))
+            } else {
+                log::debug!("Ambiguous local time '{stamp}' seen again, using the later instant");
+                Ok(latest.with_timezone(&Utc))
+            }
+        }
+        LocalResult::None => Err(anyhow!("Timestamp '{stamp}' does not exist in {timezone}")),
+    }
+}
+
+/// Accepts decimal commas; returns None for blanks and meter status markers like `---`.
+fn parse_number(cell: &str) -> Option<f64> {
+    let cell = cell.trim();
+    if cell.is_empty() {
+        return None;
+    }
+    if !cell.contains('.') && cell.matches(',').count() == 1 {
+        return cell.replace(',', ".").parse().ok();
+    }
+    cell.parse().ok()
+}
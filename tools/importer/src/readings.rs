@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+
+/// Where a value lands inside a stream's JSON object. `phase_a`/`phase_b` match what data-db
+/// writes for live calculations; `total` holds whole-meter quantities (most utility exports
+/// only have those) so they aren't mistaken for a single phase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bucket {
+    PhaseA,
+    PhaseB,
+    Total,
+}
+
+impl Bucket {
+    pub fn key(self) -> &'static str {
+        match self {
+            Bucket::PhaseA => "phase_a",
+            Bucket::PhaseB => "phase_b",
+            Bucket::Total => "total",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [Bucket::PhaseA, Bucket::PhaseB, Bucket::Total]
+            .into_iter()
+            .find(|bucket| bucket.key() == value)
+    }
+}
+
+/// The subset of data-db's per-phase fields that meter exports can provide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Field {
+    RmsVoltage,
+    RmsCurrent,
+    RealPower,
+    ReactivePower,
+    ApparentPower,
+    PowerFactor,
+}
+
+impl Field {
+    pub fn key(self) -> &'static str {
+        match self {
+            Field::RmsVoltage => "rms_voltage",
+            Field::RmsCurrent => "rms_current",
+            Field::RealPower => "real_power",
+            Field::ReactivePower => "reactive_power",
+            Field::ApparentPower => "apparent_power",
+            Field::PowerFactor => "power_factor",
+        }
+    }
+
+    /// Power that flows one way or the other, which exports split into delivered and received
+    /// channels.
+    pub fn is_directional(self) -> bool {
+        matches!(
+            self,
+            Field::RealPower | Field::ReactivePower | Field::ApparentPower
+        )
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [
+            Field::RmsVoltage,
+            Field::RmsCurrent,
+            Field::RealPower,
+            Field::ReactivePower,
+            Field::ApparentPower,
+            Field::PowerFactor,
+        ]
+        .into_iter()
+        .find(|field| field.key() == value)
+    }
+}
+
+/// A field's value by the channel it was read from, e.g. `+P [kW]` and `-P [kW]`.
+type Channels = BTreeMap<String, f64>;
+
+/// Values read from one or more export files, grouped by timestamp.
+#[derive(Default)]
+pub struct Readings {
+    rows: BTreeMap<DateTime<Utc>, BTreeMap<Bucket, BTreeMap<Field, Channels>>>,
+    duplicates: usize,
+}
+
+impl Readings {
+    /// Adds `value`, read from `channel` (the column or flow direction it came from). Power
+    /// read from different channels for the same timestamp, bucket and field is added up:
+    /// that is how delivered and received channels (`+P`/`-P`, GreenButton flow directions)
+    /// net out. Anything read twice otherwise, as from overlapping exports, replaces what was
+    /// read before, and is counted in `duplicates`.
+    pub fn add(
+        &mut self,
+        time: DateTime<Utc>,
+        bucket: Bucket,
+        field: Field,
+        channel: &str,
+        value: f64,
+    ) {
+        let channels = self
+            .rows
+            .entry(time)
+            .or_default()
+            .entry(bucket)
+            .or_default()
+            .entry(field)
+            .or_default();
+        let replaced = if field.is_directional() {
+            channels.insert(channel.to_string(), value).is_some()
+        } else {
+            let replaced = !channels.is_empty();
+            *channels = Channels::from([(channel.to_string(), value)]);
+            replaced
+        };
+        if replaced {
+            log::debug!("{} {} at {time} read again", bucket.key(), field.key());
+            self.duplicates += 1;
+        }
+    }
+
+    /// Values that replaced one already read.
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn time_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let first = self.rows.keys().next()?;
+        let last = self.rows.keys().next_back()?;
+        Some((*first, *last))
+    }
+
+    /// One row per timestamp, shaped like data-db's `data` column: `{stream: {bucket: {field: value}}}`.
+    pub fn into_rows(self, stream: &str) -> Vec<(DateTime<Utc>, serde_json::Value)> {
+        self.rows
+            .into_iter()
+            .map(|(time, buckets)| {
+                let buckets: serde_json::Map<String, serde_json::Value> = buckets
+                    .into_iter()
+                    .map(|(bucket, fields)| {
+                        let fields = fields
+                            .into_iter()
+                            .map(|(field, channels)| {
+                                let value: f64 = channels.values().sum();
+                                (field.key().to_string(), value.into())
+                            })
+                            .collect::<serde_json::Map<_, _>>();
+                        (bucket.key().to_string(), fields.into())
+                    })
+                    .collect();
+                (time, serde_json::json!({ stream: buckets }))
+            })
+            .collect()
+    }
+}
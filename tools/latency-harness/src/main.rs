@@ -0,0 +1,131 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use logging::LogArgs;
+use tokio::sync::watch;
+
+use crate::probe::Publisher;
+use crate::report::{Report, SinkReport};
+
+mod observe;
+mod probe;
+mod report;
+
+/// Measures end-to-end latency through the exporter and data-db.
+///
+/// Stands in for data-replay: point the services under test at --bind (the same way they
+/// would be pointed at data-replay), then the harness publishes numbered probe frames and
+/// times when each one becomes visible at every sink.
+#[derive(Parser)]
+struct Args {
+    /// Endpoint the probe publisher binds to
+    #[arg(long, default_value = "tcp://0.0.0.0:5557")]
+    bind: String,
+    /// Topic prefix, matching the subscribers' configuration
+    #[arg(long, default_value = "")]
+    topic: String,
+    /// Number of probes to send
+    #[arg(long, default_value_t = 600)]
+    count: u64,
+    #[arg(long, default_value_t = 60.0)]
+    rate_hz: f64,
+    /// Wait this long after binding for subscribers to connect before probing
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    warmup: Duration,
+    /// Keep polling sinks this long after the last probe
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    drain: Duration,
+    /// How often sinks are polled; this bounds the measurement resolution
+    #[arg(long, default_value = "5ms", value_parser = humantime::parse_duration)]
+    poll_interval: Duration,
+    /// Exporter metrics URL, e.g. http://exporter:9105/metrics
+    #[arg(long)]
+    exporter_url: Option<String>,
+    /// API key for an exporter running with --api-keys-file
+    #[arg(long)]
+    exporter_api_key: Option<String>,
+    /// data-db's connection string
+    #[arg(long)]
+    connection_string: Option<String>,
+    /// Also write the report as JSON
+    #[arg(long)]
+    report: Option<PathBuf>,
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+async fn run(args: Args) -> Result<()> {
+    if args.exporter_url.is_none() && args.connection_string.is_none() {
+        return Err(anyhow!(
+            "Nothing to measure: pass --exporter-url and/or --connection-string"
+        ));
+    }
+
+    let mut publisher = Publisher::bind(&args.bind, &args.topic).await?;
+    log::info!("Waiting {:?} for subscribers...", args.warmup);
+    tokio::time::sleep(args.warmup).await;
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let exporter = args.exporter_url.clone().map(|url| {
+        tokio::spawn(observe::exporter(
+            url,
+            args.exporter_api_key.clone(),
+            args.poll_interval,
+            stop_rx.clone(),
+        ))
+    });
+    let data_db = args.connection_string.clone().map(|connection_string| {
+        tokio::spawn(observe::data_db(
+            connection_string,
+            chrono::Utc::now(),
+            args.poll_interval.max(Duration::from_millis(50)),
+            stop_rx.clone(),
+        ))
+    });
+
+    log::info!("Sending {} probes at {} Hz", args.count, args.rate_hz);
+    let sent_at = publisher
+        .send_probes(args.count, Duration::from_secs_f64(1.0 / args.rate_hz))
+        .await?;
+    tokio::time::sleep(args.drain).await;
+    stop_tx.send_replace(true);
+
+    let mut sinks = Vec::new();
+    if let Some(task) = exporter {
+        let seen = task.await??;
+        sinks.push(SinkReport::new("exporter", &sent_at, &seen));
+    }
+    if let Some(task) = data_db {
+        let seen = task.await??;
+        sinks.push(SinkReport::new("data-db ingest", &sent_at, &seen.ingested));
+        sinks.push(SinkReport::new("data-db visible", &sent_at, &seen.visible));
+    }
+
+    let report = Report {
+        probes_sent: sent_at.len(),
+        rate_hz: args.rate_hz,
+        poll_interval_ms: args.poll_interval.as_secs_f64() * 1000.0,
+        sinks,
+    };
+    report.print();
+
+    if let Some(path) = &args.report {
+        let json = serde_json::to_string_pretty(&report).context("Could not encode report")?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    logging::init(&args.log, "info");
+
+    if let Err(err) = run(args).await {
+        log::error!("{err:#}");
+        std::process::exit(1);
+    }
+}
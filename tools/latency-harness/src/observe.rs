@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgPoolOptions, Row};
+use tokio::sync::watch;
+
+use crate::probe::PROBE_STREAM;
+
+/// When each probe sequence was first observed at a sink.
+pub type Observations = HashMap<u64, SystemTime>;
+
+/// Polls the exporter's metrics and records when each sequence number first shows up in
+/// `real_power_latest` for the probe stream. Resolution is the poll interval.
+pub async fn exporter(
+    url: String,
+    api_key: Option<String>,
+    interval: Duration,
+    mut stop: watch::Receiver<bool>,
+) -> Result<Observations> {
+    let client = reqwest::Client::new();
+    let mut seen = Observations::new();
+    let mut highest = 0;
+    let mut ticker = tokio::time::interval(interval);
+
+    while !*stop.borrow() {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stop.changed() => break,
+        }
+
+        let mut request = client.get(&url);
+        if let Some(key) = &api_key {
+            request = request.header("X-Api-Key", key);
+        }
+        let body = match request.send().await {
+            Ok(response) => response.text().await.unwrap_or_default(),
+            Err(err) => {
+                log::warn!("Exporter poll failed: {err}");
+                continue;
+            }
+        };
+        let now = SystemTime::now();
+
+        let Some(latest) = latest_probe(&body) else {
+            continue;
+        };
+        // The gauge only shows the newest probe; everything up to it has arrived by now
+        for sequence in highest + 1..=latest {
+            seen.insert(sequence, now);
+        }
+        highest = highest.max(latest);
+    }
+
+    Ok(seen)
+}
+
+fn latest_probe(metrics: &str) -> Option<u64> {
+    let stream_label = format!("stream=\"{PROBE_STREAM}\"");
+    metrics
+        .lines()
+        .filter(|line| line.starts_with("real_power_latest"))
+        .filter(|line| line.contains(&stream_label) && line.contains("phase=\"a\""))
+        .filter_map(|line| line.rsplit(' ').next()?.parse::<f64>().ok())
+        .map(|value| value as u64)
+        .max()
+}
+
+/// Sink-side timing from data-db: when the row became visible to a query, and the `time`
+/// data-db stamped on it when the frame arrived.
+#[derive(Default)]
+pub struct DbObservations {
+    pub visible: Observations,
+    pub ingested: Observations,
+}
+
+pub async fn data_db(
+    connection_string: String,
+    since: DateTime<Utc>,
+    interval: Duration,
+    mut stop: watch::Receiver<bool>,
+) -> Result<DbObservations> {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&connection_string)
+        .await
+        .context("Could not connect to database")?;
+
+    let mut seen = DbObservations::default();
+    let mut since = since;
+    let mut ticker = tokio::time::interval(interval);
+    // Rows written in either --json-schema-version layout
+    let query = format!(
+        "SELECT b.time, (s.value->'phase_a'->>'real_power')::float8 AS sequence \
+         FROM bibimbap b {} WHERE s.key = $1 AND b.time >= $2 ORDER BY b.time",
+        stored_document::streams_lateral("b")
+    );
+
+    while !*stop.borrow() {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stop.changed() => break,
+        }
+
+        let rows = sqlx::query(&query)
+            .bind(PROBE_STREAM)
+            .bind(since)
+            .fetch_all(&pool)
+            .await;
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(err) => {
+                log::warn!("data-db poll failed: {err}");
+                continue;
+            }
+        };
+        let now = SystemTime::now();
+
+        for row in rows {
+            let time: DateTime<Utc> = row.get("time");
+            let Some(sequence) = row.get::<Option<f64>, _>("sequence") else {
+                continue;
+            };
+            let sequence = sequence as u64;
+            seen.visible.entry(sequence).or_insert(now);
+            seen.ingested.entry(sequence).or_insert(time.into());
+            since = since.max(time);
+        }
+    }
+
+    Ok(seen)
+}
@@ -0,0 +1,106 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use prost::Message;
+use protobuf_rs::utilidata::karman::bibimbap::v1::{
+    composite_joined_calculations_wrapper::DataProduct, CompositeCalculations,
+    CompositeJoinedCalculations, CompositeJoinedCalculationsWrapper, CompositeTwoPhaseCalculations,
+    PowerCalculations, Provenance, WaveformCalculations,
+};
+use zeromq::{PubSocket, Socket, SocketSend};
+
+/// Stream name probe frames are published under
+pub const PROBE_STREAM: &str = "probe/latency";
+
+/// Probe frames carry their sequence number as real power, because that survives every sink
+/// unchanged: the exporter's `real_power_latest` gauge and data-db's JSON both keep it exactly
+/// (sequence numbers stay well inside f32's integer range).
+fn probe_frame(sequence: u64, sent: SystemTime) -> CompositeJoinedCalculations {
+    let since_epoch = sent
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let phase = CompositeCalculations {
+        provenance: Some(Provenance {
+            utc_time: Some(prost_types::Timestamp {
+                seconds: since_epoch.as_secs() as i64,
+                nanos: since_epoch.subsec_nanos() as i32,
+            }),
+            generic_sequence_number: Some(sequence),
+            time_sync: None,
+        }),
+        voltage_waveform_calculations_v: Some(WaveformCalculations {
+            rms: Some(120.0),
+            dc_offset: Some(0.0),
+            crest_factor: None,
+            thd_percent: None,
+        }),
+        current_waveform_calculations_a: Some(WaveformCalculations {
+            rms: Some(0.0),
+            dc_offset: Some(0.0),
+            crest_factor: None,
+            thd_percent: None,
+        }),
+        power_calculations: Some(PowerCalculations {
+            real_power_w: Some(sequence as f32),
+            apparent_power_va: Some(0.0),
+            reactive_power_var: Some(0.0),
+            power_factor: Some(1.0),
+        }),
+    };
+
+    CompositeJoinedCalculations {
+        calculations: vec![CompositeJoinedCalculationsWrapper {
+            calculation_name: Some(PROBE_STREAM.to_string()),
+            data_product: Some(DataProduct::Calculations(CompositeTwoPhaseCalculations {
+                phase_a: Some(phase),
+                phase_b: Some(phase),
+                phase_c: None,
+            })),
+            device_id: None,
+            harmonics: None,
+        }],
+    }
+}
+
+pub struct Publisher {
+    socket: PubSocket,
+    topic: String,
+}
+
+impl Publisher {
+    pub async fn bind(endpoint: &str, topic: &str) -> Result<Self> {
+        let mut socket = PubSocket::new();
+        socket
+            .bind(endpoint)
+            .await
+            .context("Could not bind publisher")?;
+        log::info!("Probe publisher bound to {endpoint}");
+        Ok(Self {
+            socket,
+            topic: topic.to_string(),
+        })
+    }
+
+    /// Sends probes 1..=count at a fixed rate and returns when each was sent (index 0 is
+    /// sequence 1).
+    pub async fn send_probes(&mut self, count: u64, period: Duration) -> Result<Vec<SystemTime>> {
+        let mut sent_at = Vec::with_capacity(count as usize);
+        let mut ticker = tokio::time::interval(period);
+
+        for sequence in 1..=count {
+            ticker.tick().await;
+            let sent = SystemTime::now();
+            let mut message = self.topic.as_bytes().to_vec();
+            probe_frame(sequence, sent)
+                .encode(&mut message)
+                .context("Failed to encode probe")?;
+            self.socket
+                .send(message.into())
+                .await
+                .context("Failed to send probe")?;
+            sent_at.push(sent);
+        }
+
+        Ok(sent_at)
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::observe::Observations;
+
+#[derive(Serialize)]
+pub struct SinkReport {
+    pub sink: String,
+    pub received: usize,
+    pub lost: usize,
+    pub min_ms: Option<f64>,
+    pub mean_ms: Option<f64>,
+    pub p50_ms: Option<f64>,
+    pub p90_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub probes_sent: usize,
+    pub rate_hz: f64,
+    pub poll_interval_ms: f64,
+    pub sinks: Vec<SinkReport>,
+}
+
+impl SinkReport {
+    pub fn new(sink: &str, sent_at: &[SystemTime], seen: &Observations) -> Self {
+        let mut latencies: Vec<f64> = sent_at
+            .iter()
+            .enumerate()
+            .filter_map(|(index, sent)| {
+                let observed = seen.get(&(index as u64 + 1))?;
+                // Clocks on different hosts can put an observation slightly before the send
+                Some(match observed.duration_since(*sent) {
+                    Ok(latency) => latency.as_secs_f64() * 1000.0,
+                    Err(early) => -early.duration().as_secs_f64() * 1000.0,
+                })
+            })
+            .collect();
+        latencies.sort_by(f64::total_cmp);
+
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0) * latencies.len() as f64).ceil() as usize;
+            latencies.get(rank.saturating_sub(1)).copied()
+        };
+
+        Self {
+            sink: sink.to_string(),
+            received: latencies.len(),
+            lost: sent_at.len() - latencies.len(),
+            min_ms: latencies.first().copied(),
+            mean_ms: (!latencies.is_empty())
+                .then(|| latencies.iter().sum::<f64>() / latencies.len() as f64),
+            p50_ms: percentile(50.0),
+            p90_ms: percentile(90.0),
+            p99_ms: percentile(99.0),
+            max_ms: latencies.last().copied(),
+        }
+    }
+}
+
+impl Report {
+    pub fn print(&self) {
+        let format = |value: Option<f64>| value.map_or("-".to_string(), |v| format!("{v:.2}"));
+
+        println!(
+            "{} probes at {} Hz (sink poll interval {} ms)",
+            self.probes_sent, self.rate_hz, self.poll_interval_ms
+        );
+        println!(
+            "{:<16} {:>8} {:>6} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            "sink", "received", "lost", "min ms", "mean ms", "p50 ms", "p90 ms", "p99 ms", "max ms"
+        );
+        for sink in &self.sinks {
+            println!(
+                "{:<16} {:>8} {:>6} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}",
+                sink.sink,
+                sink.received,
+                sink.lost,
+                format(sink.min_ms),
+                format(sink.mean_ms),
+                format(sink.p50_ms),
+                format(sink.p90_ms),
+                format(sink.p99_ms),
+                format(sink.max_ms)
+            );
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{Parser, ValueEnum};
+use logging::LogArgs;
+use sqlx::postgres::PgPoolOptions;
+
+use crate::query::{Aggregation, Field, Phase, Query, Table};
+
+mod query;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    /// Aligned columns for reading in a terminal
+    Table,
+    Csv,
+}
+
+/// Prints one stream's measurements from the bibimbap table, raw or aggregated, for quick
+/// checks in the field.
+#[derive(Parser)]
+struct Args {
+    /// Postgres connection string, as passed to data-db
+    #[arg(long, env = "PAM_QUERY_CONNECTION_STRING")]
+    connection_string: String,
+    /// Stream (calculation name), e.g. threephase/karman1
+    #[arg(long)]
+    stream: String,
+    /// Value to print (repeatable)
+    #[arg(long = "field", value_enum, default_value = "real_power")]
+    fields: Vec<Field>,
+    #[arg(long, value_enum, default_value_t = Phase::All)]
+    phase: Phase,
+    /// Start of the range: a time such as 2026-10-16T08:00:00Z, "2026-10-16 08:00" or
+    /// 2026-10-16 (UTC), or how long ago, e.g. 15m
+    #[arg(long, default_value = "1h", value_parser = parse_time)]
+    from: DateTime<Utc>,
+    /// End of the range, in the same forms as --from
+    #[arg(long, default_value = "now", value_parser = parse_time)]
+    to: DateTime<Utc>,
+    #[arg(long, value_enum, default_value_t = Aggregation::Raw)]
+    aggregation: Aggregation,
+    /// With --aggregation: bucket width, e.g. 1m; one bucket for the whole range without it
+    #[arg(long, value_parser = humantime::parse_duration)]
+    bucket: Option<Duration>,
+    /// Value of the `device` column
+    #[arg(long, default_value = "bibimbap")]
+    device: String,
+    /// Customer site, as data-db's --tenant
+    #[arg(long)]
+    tenant: Option<String>,
+    /// Rows (or buckets) printed at most
+    #[arg(long, default_value_t = 10_000)]
+    limit: i64,
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+/// An absolute time, `now`, or a duration back from now.
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    if value == "now" {
+        return Ok(Utc::now());
+    }
+    // Seconds, or the whole time of day, may be left out
+    for suffix in ["", ":00", " 00:00:00"] {
+        if let Ok(time) = humantime::parse_rfc3339_weak(&format!("{value}{suffix}")) {
+            return Ok(time.into());
+        }
+    }
+    let ago = humantime::parse_duration(value).map_err(|_| {
+        anyhow!("expected a time like 2026-10-16T08:00:00Z, or a duration like 15m")
+    })?;
+    Ok((SystemTime::now() - ago).into())
+}
+
+fn print_csv(table: &Table, aggregated: bool) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(std::io::stdout().lock());
+    let mut header = vec!["time".to_string()];
+    if aggregated {
+        header.push("samples".to_string());
+    }
+    header.extend(table.columns.iter().cloned());
+    writer.write_record(&header)?;
+
+    for record in &table.records {
+        let mut fields = vec![record.time.to_rfc3339_opts(SecondsFormat::Millis, true)];
+        fields.extend(record.samples.map(|samples| samples.to_string()));
+        fields.extend(
+            record
+                .values
+                .iter()
+                .map(|value| value.map(|value| value.to_string()).unwrap_or_default()),
+        );
+        writer.write_record(&fields)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn print_table(table: &Table, aggregated: bool) -> Result<()> {
+    let mut header = vec!["time".to_string()];
+    if aggregated {
+        header.push("samples".to_string());
+    }
+    header.extend(table.columns.iter().cloned());
+
+    let lines: Vec<Vec<String>> = table
+        .records
+        .iter()
+        .map(|record| {
+            let mut line = vec![record.time.format("%Y-%m-%d %H:%M:%S%.3f").to_string()];
+            line.extend(record.samples.map(|samples| samples.to_string()));
+            line.extend(
+                record
+                    .values
+                    .iter()
+                    .map(|value| value.map_or("-".to_string(), |value| format!("{value:.3}"))),
+            );
+            line
+        })
+        .collect();
+
+    let mut widths: Vec<usize> = header.iter().map(String::len).collect();
+    for line in &lines {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut out = std::io::stdout().lock();
+    // Times read left to right, numbers line up on the right
+    let print = |out: &mut dyn Write, line: &[String]| -> std::io::Result<()> {
+        for (i, (cell, width)) in line.iter().zip(&widths).enumerate() {
+            match i {
+                0 => write!(out, "{cell:<width$}")?,
+                _ => write!(out, "  {cell:>width$}")?,
+            }
+        }
+        writeln!(out)
+    };
+    print(&mut out, &header)?;
+    for line in &lines {
+        print(&mut out, line)?;
+    }
+    Ok(())
+}
+
+async fn run(args: Args) -> Result<()> {
+    if args.bucket.is_some() && args.aggregation == Aggregation::Raw {
+        return Err(anyhow!("--bucket needs --aggregation avg, min or max"));
+    }
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&args.connection_string)
+        .await
+        .context("Could not connect to database")?;
+
+    let query = Query {
+        device: args.device,
+        tenant: args.tenant,
+        stream: args.stream,
+        fields: args.fields,
+        phase: args.phase,
+        from: args.from,
+        to: args.to,
+        aggregation: args.aggregation,
+        bucket: args.bucket,
+        limit: args.limit,
+    };
+    let table = query.run(&pool).await?;
+    if table.records.is_empty() {
+        log::warn!(
+            "No rows for stream '{}' between {} and {} (full-rate rows older than data-db's \
+             --full-rate-retention only remain in the rollup tables)",
+            query.stream,
+            query.from,
+            query.to
+        );
+    } else if table.records.len() as i64 == query.limit {
+        log::warn!("Stopped at --limit {} rows", query.limit);
+    }
+
+    let aggregated = query.aggregation != Aggregation::Raw;
+    match args.format {
+        Format::Table => print_table(&table, aggregated),
+        Format::Csv => print_csv(&table, aggregated),
+    }
+}
+
+/// Output piped into `head` and the like stops being read; that's not worth an error.
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let io = match cause.downcast_ref::<csv::Error>() {
+            Some(err) => match err.kind() {
+                csv::ErrorKind::Io(io) => Some(io),
+                _ => None,
+            },
+            None => cause.downcast_ref::<std::io::Error>(),
+        };
+        io.is_some_and(|io| io.kind() == std::io::ErrorKind::BrokenPipe)
+    })
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    logging::init(&args.log, "info");
+
+    if let Err(err) = run(args).await {
+        if is_broken_pipe(&err) {
+            return;
+        }
+        log::error!("{err:#}");
+        std::process::exit(1);
+    }
+}
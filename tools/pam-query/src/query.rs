@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use sqlx::{Pool, Postgres, QueryBuilder, Row};
+
+/// Per-phase values data-db stores under each stream in the `data` column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum Field {
+    RmsVoltage,
+    RmsCurrent,
+    DcOffsetVoltage,
+    DcOffsetCurrent,
+    RealPower,
+    ReactivePower,
+    ApparentPower,
+    PowerFactor,
+    ThreePhaseRealPower,
+    ThreePhaseReactivePower,
+    CrestFactorVoltage,
+    ThdVoltage,
+    CrestFactorCurrent,
+    ThdCurrent,
+}
+
+impl Field {
+    /// The key in the `data` column, which is also how it's spelled on the command line
+    fn key(self) -> &'static str {
+        match self {
+            Self::RmsVoltage => "rms_voltage",
+            Self::RmsCurrent => "rms_current",
+            Self::DcOffsetVoltage => "dc_offset_voltage",
+            Self::DcOffsetCurrent => "dc_offset_current",
+            Self::RealPower => "real_power",
+            Self::ReactivePower => "reactive_power",
+            Self::ApparentPower => "apparent_power",
+            Self::PowerFactor => "power_factor",
+            Self::ThreePhaseRealPower => "three_phase_real_power",
+            Self::ThreePhaseReactivePower => "three_phase_reactive_power",
+            Self::CrestFactorVoltage => "crest_factor_voltage",
+            Self::ThdVoltage => "thd_voltage",
+            Self::CrestFactorCurrent => "crest_factor_current",
+            Self::ThdCurrent => "thd_current",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Phase {
+    A,
+    B,
+    C,
+    /// Every phase; C is empty for meters without one
+    #[value(alias = "both")]
+    All,
+}
+
+impl Phase {
+    fn names(self) -> &'static [&'static str] {
+        match self {
+            Self::A => &["a"],
+            Self::B => &["b"],
+            Self::C => &["c"],
+            Self::All => &["a", "b", "c"],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Aggregation {
+    /// Every stored row
+    Raw,
+    Avg,
+    Min,
+    Max,
+}
+
+impl Aggregation {
+    fn function(self) -> Option<&'static str> {
+        match self {
+            Self::Raw => None,
+            Self::Avg => Some("avg"),
+            Self::Min => Some("min"),
+            Self::Max => Some("max"),
+        }
+    }
+}
+
+pub struct Query {
+    pub device: String,
+    pub tenant: Option<String>,
+    pub stream: String,
+    pub fields: Vec<Field>,
+    pub phase: Phase,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub aggregation: Aggregation,
+    /// Bucket width for aggregations; one bucket for the whole range when unset
+    pub bucket: Option<Duration>,
+    pub limit: i64,
+}
+
+pub struct Record {
+    /// The row's time, or the start of its bucket
+    pub time: DateTime<Utc>,
+    /// Rows in the bucket, when aggregating
+    pub samples: Option<i64>,
+    pub values: Vec<Option<f64>>,
+}
+
+pub struct Table {
+    /// Names of `Record::values`, e.g. `real_power_a`
+    pub columns: Vec<String>,
+    pub records: Vec<Record>,
+}
+
+impl Query {
+    pub async fn run(&self, pool: &Pool<Postgres>) -> Result<Table> {
+        if self.from >= self.to {
+            bail!("--from ({}) must be before --to ({})", self.from, self.to);
+        }
+        let function = self.aggregation.function();
+
+        let mut builder = QueryBuilder::<Postgres>::new("SELECT ");
+        match function {
+            Some(_) => {
+                let bucket = match self.bucket {
+                    Some(bucket) => bucket,
+                    None => (self.to - self.from).to_std()?,
+                };
+                builder
+                    .push("date_bin(make_interval(secs => ")
+                    .push_bind(bucket.as_secs_f64())
+                    .push("), time, ")
+                    .push_bind(self.from)
+                    .push(") AS bucket, count(*)");
+            }
+            None => {
+                builder.push("time");
+            }
+        }
+
+        let mut columns = Vec::new();
+        for field in &self.fields {
+            for phase in self.phase.names() {
+                builder.push(", ");
+                if let Some(function) = function {
+                    builder.push(function).push("(");
+                }
+                builder.push(format!(
+                    "(s.value -> 'phase_{phase}' ->> '{}')::float8",
+                    field.key()
+                ));
+                if function.is_some() {
+                    builder.push(")");
+                }
+                columns.push(format!("{}_{phase}", field.key()));
+            }
+        }
+
+        // Rows written in either --json-schema-version layout
+        builder
+            .push(" FROM bibimbap b ")
+            .push(stored_document::streams_lateral("b"))
+            .push(" WHERE s.key = ")
+            .push_bind(&self.stream)
+            .push(" AND device = ")
+            .push_bind(&self.device)
+            .push(" AND tenant IS NOT DISTINCT FROM ")
+            .push_bind(&self.tenant)
+            .push(" AND time >= ")
+            .push_bind(self.from)
+            .push(" AND time < ")
+            .push_bind(self.to);
+        if function.is_some() {
+            builder.push(" GROUP BY 1");
+        }
+        builder.push(" ORDER BY 1 LIMIT ").push_bind(self.limit);
+
+        let rows = builder
+            .build()
+            .fetch_all(pool)
+            .await
+            .context("Could not query bibimbap")?;
+
+        let first_value = if function.is_some() { 2 } else { 1 };
+        let mut records = Vec::with_capacity(rows.len());
+        for row in rows {
+            records.push(Record {
+                time: row.try_get(0)?,
+                samples: function.map(|_| row.try_get(1)).transpose()?,
+                values: (first_value..first_value + columns.len())
+                    .map(|i| row.try_get(i))
+                    .collect::<Result<_, _>>()?,
+            });
+        }
+        Ok(Table { columns, records })
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Target {
+    DataDb,
+    Exporter,
+    /// The database data-db writes to, restarted with --db-restart-command
+    Database,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stop {
+    /// SIGTERM, as for a rolling update
+    Graceful,
+    /// SIGKILL, as for a crash or an OOM kill
+    Kill,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Fault {
+    pub target: Target,
+    /// How a service is stopped; the database is always restarted by its command
+    pub stop: Stop,
+    /// How long the target stays down before it is started again
+    #[serde(rename = "downtime_s", serialize_with = "as_secs")]
+    pub downtime: Duration,
+}
+
+fn as_secs<S: serde::Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(value.as_secs_f64())
+}
+
+/// Picks when the next fault happens and what it is. Seeded, so a failing soak can be rerun
+/// with the same faults at the same offsets.
+pub struct Schedule {
+    rng: ChaCha8Rng,
+    interval: Duration,
+    max_downtime: Duration,
+    targets: Vec<Target>,
+}
+
+impl Schedule {
+    pub fn new(seed: u64, interval: Duration, max_downtime: Duration, database: bool) -> Self {
+        let mut targets = vec![Target::DataDb, Target::Exporter];
+        if database {
+            targets.push(Target::Database);
+        }
+        Self {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            interval,
+            max_downtime,
+            targets,
+        }
+    }
+
+    /// Time until the next fault: between half and one and a half of the mean interval.
+    pub fn wait(&mut self) -> Duration {
+        self.interval.mul_f64(self.rng.gen_range(0.5..1.5))
+    }
+
+    pub fn fault(&mut self) -> Fault {
+        let target = self.targets[self.rng.gen_range(0..self.targets.len())];
+        let stop = if self.rng.gen_bool(0.5) {
+            Stop::Graceful
+        } else {
+            Stop::Kill
+        };
+        Fault {
+            target,
+            stop,
+            downtime: self.max_downtime.mul_f64(self.rng.gen_range(0.0..=1.0)),
+        }
+    }
+}
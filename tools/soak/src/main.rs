@@ -0,0 +1,301 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use logging::LogArgs;
+
+use crate::chaos::{Fault, Schedule, Stop, Target};
+use crate::pipeline::{Consumer, Replay};
+use crate::process::Component;
+use crate::report::{ComponentReport, ConsumerReport, FaultRecord, Report};
+
+mod chaos;
+mod observe;
+mod pipeline;
+mod process;
+mod report;
+
+/// Soaks the whole pipeline under injected faults.
+///
+/// Runs data-replay, data-db and the exporter as child processes for --duration, randomly
+/// stopping or killing data-db and the exporter and restarting the database meanwhile, then
+/// checks that nothing panicked or exited uncleanly and that every frame a consumer didn't
+/// receive was published while it was down. data-replay is configured through its usual
+/// environment (FILE, PUB, RATE_HZ, ...), inherited from the soak's.
+#[derive(Parser)]
+struct Args {
+    #[arg(long)]
+    replay_bin: PathBuf,
+    #[arg(long)]
+    data_db_bin: PathBuf,
+    /// Argument for data-db, e.g. --data-db-arg=--prometheus-port=9100; repeat for each
+    #[arg(long, allow_hyphen_values = true)]
+    data_db_arg: Vec<String>,
+    #[arg(long)]
+    exporter_bin: PathBuf,
+    /// Argument for the exporter; repeat for each
+    #[arg(long, allow_hyphen_values = true)]
+    exporter_arg: Vec<String>,
+    /// How long faults are injected for
+    #[arg(long, default_value = "4h", value_parser = humantime::parse_duration)]
+    duration: Duration,
+    /// Seeds the fault schedule, and data-replay's SEED (plus the pass number)
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Passed to data-replay as DROP_PROBABILITY
+    #[arg(long, default_value_t = 0.0)]
+    drop_probability: f64,
+    /// Mean time between faults
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    fault_interval: Duration,
+    /// Longest a faulted component stays down
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    max_downtime: Duration,
+    /// No faults this long after starting, while data-replay waits for subscribers
+    #[arg(long, default_value = "2m", value_parser = humantime::parse_duration)]
+    warmup: Duration,
+    /// Time the consumers get to catch up after the last frame is published
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    drain: Duration,
+    /// How often components are checked on and their metrics scraped
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    poll_interval: Duration,
+    /// Shell command that stops the database; with --db-start-command, makes it a fault target
+    #[arg(long, requires = "db_start_command")]
+    db_stop_command: Option<String>,
+    #[arg(long, requires = "db_stop_command")]
+    db_start_command: Option<String>,
+    /// data-replay's control port, which the soak reads its progress from
+    #[arg(long, default_value_t = 9107)]
+    replay_control_port: u16,
+    /// Where data-replay writes each pass's manifest
+    #[arg(long, default_value = "soak-pass.json")]
+    manifest: PathBuf,
+    /// data-db's metrics URL, e.g. http://127.0.0.1:9100/metrics
+    #[arg(long)]
+    data_db_metrics_url: String,
+    /// The exporter's metrics URL, e.g. http://127.0.0.1:9105/metrics
+    #[arg(long)]
+    exporter_metrics_url: String,
+    /// API key for an exporter running with --api-keys-file
+    #[arg(long)]
+    exporter_api_key: Option<String>,
+    /// data-db's connection string, to check its rows were stored
+    #[arg(long)]
+    connection_string: Option<String>,
+    /// `device` data-db writes rows under
+    #[arg(long, default_value = "bibimbap")]
+    device: String,
+    /// Share of published frames a consumer may lose unexplained, for the frames in flight
+    /// around each outage
+    #[arg(long, default_value_t = 0.001)]
+    tolerance: f64,
+    /// Also write the report as JSON
+    #[arg(long)]
+    report: Option<PathBuf>,
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+/// Checks on every component, restarting any that exited, and scrapes the consumers.
+async fn poll(
+    client: &reqwest::Client,
+    replay: &mut Replay,
+    consumers: [&mut Consumer; 2],
+) -> Result<()> {
+    replay.poll(client).await?;
+    for consumer in consumers {
+        if consumer.component.exited() {
+            log::error!(
+                "{} exited unexpectedly, restarting it",
+                consumer.component.name
+            );
+            consumer.went_down(replay.published());
+            consumer.component.start()?;
+        }
+        if consumer.scrape(client).await {
+            consumer.check_outage(replay.published());
+        }
+    }
+    Ok(())
+}
+
+async fn inject(
+    fault: Fault,
+    args: &Args,
+    client: &reqwest::Client,
+    replay: &mut Replay,
+    consumer: Option<&mut Consumer>,
+) -> Result<()> {
+    let Some(consumer) = consumer else {
+        let (Some(stop), Some(start)) = (&args.db_stop_command, &args.db_start_command) else {
+            return Ok(());
+        };
+        process::run_shell(stop).await?;
+        tokio::time::sleep(fault.downtime).await;
+        return process::run_shell(start).await;
+    };
+
+    // Counted up to the moment it goes down, so what it misses is what's published after
+    replay.poll(client).await?;
+    consumer.scrape(client).await;
+    consumer.went_down(replay.published());
+    match fault.stop {
+        Stop::Graceful => consumer.component.stop().await,
+        Stop::Kill => consumer.component.kill().await,
+    }
+    tokio::time::sleep(fault.downtime).await;
+    consumer.component.start()
+}
+
+async fn run(args: Args) -> Result<()> {
+    let client = reqwest::Client::new();
+    let started_at = chrono::Utc::now();
+
+    let mut replay = Replay::new(
+        Component::new("data-replay", args.replay_bin.clone(), Vec::new()),
+        args.replay_control_port,
+        args.manifest.clone(),
+        args.seed,
+    );
+    if args.drop_probability > 0.0 {
+        replay
+            .component
+            .set_env("DROP_PROBABILITY", args.drop_probability);
+    }
+    let mut data_db = Consumer::new(
+        Component::new(
+            "data-db",
+            args.data_db_bin.clone(),
+            args.data_db_arg.clone(),
+        ),
+        args.data_db_metrics_url.clone(),
+        None,
+        "data_db_",
+    );
+    let mut exporter = Consumer::new(
+        Component::new(
+            "data-exporter",
+            args.exporter_bin.clone(),
+            args.exporter_arg.clone(),
+        ),
+        args.exporter_metrics_url.clone(),
+        args.exporter_api_key.clone(),
+        "",
+    );
+    data_db.component.start()?;
+    exporter.component.start()?;
+    replay.start_pass()?;
+
+    let mut schedule = Schedule::new(
+        args.seed,
+        args.fault_interval,
+        args.max_downtime,
+        args.db_stop_command.is_some(),
+    );
+    let start = Instant::now();
+    let mut next_fault = start + args.warmup + schedule.wait();
+    let mut faults = Vec::new();
+    let mut ticker = tokio::time::interval(args.poll_interval);
+    log::info!("Soaking for {:?} with seed {}", args.duration, args.seed);
+
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
+    while start.elapsed() < args.duration {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = &mut interrupted => {
+                log::warn!("Interrupted, checking what ran so far");
+                break;
+            }
+        }
+        poll(&client, &mut replay, [&mut data_db, &mut exporter]).await?;
+        if Instant::now() < next_fault {
+            continue;
+        }
+
+        let fault = schedule.fault();
+        log::info!("Injecting {fault:?}");
+        let at_s = start.elapsed().as_secs_f64();
+        let consumer = match fault.target {
+            Target::DataDb => Some(&mut data_db),
+            Target::Exporter => Some(&mut exporter),
+            Target::Database => None,
+        };
+        let error = inject(fault, &args, &client, &mut replay, consumer)
+            .await
+            .err()
+            .map(|err| format!("{err:#}"));
+        if let Some(err) = &error {
+            log::error!("Could not inject {fault:?}: {err}");
+        }
+        faults.push(FaultRecord { at_s, fault, error });
+        next_fault = Instant::now() + schedule.wait();
+    }
+    let duration_s = start.elapsed().as_secs_f64();
+
+    replay.finish(&client).await?;
+    log::info!(
+        "Published {} frames, draining for {:?}",
+        replay.published(),
+        args.drain
+    );
+    tokio::time::sleep(args.drain).await;
+    for consumer in [&mut data_db, &mut exporter] {
+        consumer.scrape(&client).await;
+        consumer.end_outage(replay.published());
+        consumer.component.stop().await;
+    }
+
+    let stored_rows = match &args.connection_string {
+        Some(connection_string) => {
+            Some(observe::stored_rows(connection_string, &args.device, started_at).await?)
+        }
+        None => None,
+    };
+    let mut report = Report {
+        seed: args.seed,
+        duration_s,
+        passes: replay.passes,
+        frames_total: replay.frames_total,
+        frames_published: replay.frames_published,
+        frames_dropped: replay.frames_dropped,
+        faults,
+        components: [&replay.component, &data_db.component, &exporter.component]
+            .into_iter()
+            .map(ComponentReport::new)
+            .collect(),
+        consumers: [&data_db, &exporter]
+            .into_iter()
+            .map(|consumer| ConsumerReport::new(consumer, replay.frames_published))
+            .collect(),
+        rows_written: data_db.rows_written.total(),
+        failed_rows: data_db.failed_rows.total(),
+        stored_rows,
+        failures: Vec::new(),
+    };
+    report.check(&replay, args.tolerance);
+    report.print();
+
+    if let Some(path) = &args.report {
+        let json = serde_json::to_string_pretty(&report).context("Could not encode report")?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+    }
+    if !report.failures.is_empty() {
+        return Err(anyhow!("Soak failed: {}", report.failures.join("; ")));
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    logging::init(&args.log, "info");
+
+    if let Err(err) = run(args).await {
+        log::error!("{err:#}");
+        std::process::exit(1);
+    }
+}
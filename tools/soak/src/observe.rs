@@ -0,0 +1,113 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{postgres::PgPoolOptions, Row};
+
+/// Sum of every series of counter `name` in a Prometheus text exposition.
+pub fn counter(metrics: &str, name: &str) -> f64 {
+    metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            if series.split('{').next()? != name {
+                return None;
+            }
+            value.parse::<f64>().ok()
+        })
+        .sum()
+}
+
+/// A counter followed across the restarts of the process exporting it, which start it at 0.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tally {
+    /// Totals of the earlier runs
+    before: f64,
+    /// Last value scraped from the current run
+    current: f64,
+}
+
+impl Tally {
+    pub fn observe(&mut self, value: f64) {
+        self.current = value;
+    }
+
+    /// The process is being restarted; what it counted so far is kept.
+    pub fn restarted(&mut self) {
+        self.before += self.current;
+        self.current = 0.0;
+    }
+
+    pub fn current(&self) -> f64 {
+        self.current
+    }
+
+    pub fn total(&self) -> u64 {
+        (self.before + self.current) as u64
+    }
+}
+
+pub async fn scrape(client: &reqwest::Client, url: &str, api_key: Option<&str>) -> Result<String> {
+    let mut request = client.get(url);
+    if let Some(key) = api_key {
+        request = request.header("X-Api-Key", key);
+    }
+    let response = request.send().await?.error_for_status()?;
+    Ok(response.text().await?)
+}
+
+#[derive(Deserialize)]
+struct ReplayStatus {
+    frames_published: u64,
+}
+
+/// Frames published so far in data-replay's current pass, from its control API.
+pub async fn replay_published(client: &reqwest::Client, control_url: &str) -> Result<u64> {
+    let body = scrape(client, &format!("{control_url}/replay"), None).await?;
+    let status: ReplayStatus =
+        serde_json::from_str(&body).context("Unexpected /replay response")?;
+    Ok(status.frames_published)
+}
+
+/// The parts of a data-replay pass manifest the soak accounts with.
+#[derive(Deserialize)]
+pub struct PassManifest {
+    pub completed: bool,
+    pub frames_total: u64,
+    pub frames_published: u64,
+    pub frames_dropped: u64,
+}
+
+/// The manifest of the pass that just ended, or None while it is still running.
+pub fn pass_manifest(path: &Path) -> Result<Option<PassManifest>> {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Could not read {}", path.display())),
+    };
+    serde_json::from_str(&json)
+        .map(Some)
+        .with_context(|| format!("Could not parse manifest {}", path.display()))
+}
+
+/// Rows data-db stored for `device` since the soak started.
+pub async fn stored_rows(
+    connection_string: &str,
+    device: &str,
+    since: DateTime<Utc>,
+) -> Result<u64> {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(connection_string)
+        .await
+        .context("Could not connect to the database")?;
+    let row = sqlx::query("SELECT count(*) AS rows FROM bibimbap WHERE device = $1 AND time >= $2")
+        .bind(device)
+        .bind(since)
+        .fetch_one(&pool)
+        .await
+        .context("Could not count stored rows")?;
+    Ok(row.get::<i64, _>("rows") as u64)
+}
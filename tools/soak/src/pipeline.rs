@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::observe::{self, Tally};
+use crate::process::Component;
+
+/// data-replay, run one pass per process: once a pass's manifest shows up it is stopped and
+/// started again with the next seed, so the soak publishes for as long as it runs.
+pub struct Replay {
+    pub component: Component,
+    control_url: String,
+    manifest: PathBuf,
+    seed: u64,
+    pub passes: u64,
+    /// Of the passes that ended
+    pub frames_total: u64,
+    pub frames_published: u64,
+    pub frames_dropped: u64,
+    /// Passes whose manifest doesn't add up: frames neither published nor dropped
+    pub unaccounted: Vec<String>,
+    /// Published so far in the running pass
+    current: u64,
+}
+
+impl Replay {
+    pub fn new(mut component: Component, control_port: u16, manifest: PathBuf, seed: u64) -> Self {
+        component.set_env("CONTROL_PORT", control_port);
+        component.set_env("MANIFEST", manifest.display());
+        Self {
+            component,
+            control_url: format!("http://127.0.0.1:{control_port}"),
+            manifest,
+            seed,
+            passes: 0,
+            frames_total: 0,
+            frames_published: 0,
+            frames_dropped: 0,
+            unaccounted: Vec::new(),
+            current: 0,
+        }
+    }
+
+    pub fn start_pass(&mut self) -> Result<()> {
+        if self.manifest.exists() {
+            std::fs::remove_file(&self.manifest)?;
+        }
+        self.component
+            .set_env("SEED", self.seed.wrapping_add(self.passes));
+        self.current = 0;
+        self.component.start()
+    }
+
+    /// Frames published since the soak started.
+    pub fn published(&self) -> u64 {
+        self.frames_published + self.current
+    }
+
+    /// Accounts for the pass that just ended, from its manifest, or from the last count seen if
+    /// it never wrote one.
+    fn end_pass(&mut self) -> Result<()> {
+        match observe::pass_manifest(&self.manifest)? {
+            Some(pass) => {
+                let dropped = pass.frames_dropped;
+                if pass.completed && pass.frames_published + dropped != pass.frames_total {
+                    self.unaccounted.push(format!(
+                        "pass {} published {} and dropped {dropped} of {} frames",
+                        self.passes, pass.frames_published, pass.frames_total
+                    ));
+                }
+                self.frames_total += pass.frames_total;
+                self.frames_published += pass.frames_published;
+                self.frames_dropped += dropped;
+            }
+            None => self.frames_published += self.current,
+        }
+        self.current = 0;
+        self.passes += 1;
+        Ok(())
+    }
+
+    /// Catches up with the running pass, and starts the next one when it has ended.
+    pub async fn poll(&mut self, client: &reqwest::Client) -> Result<()> {
+        if self.component.exited() {
+            log::error!("data-replay exited unexpectedly, starting another pass");
+            self.end_pass()?;
+            return self.start_pass();
+        }
+        if observe::pass_manifest(&self.manifest)?.is_some() {
+            self.component.stop().await;
+            self.end_pass()?;
+            log::info!(
+                "Pass {} done, {} frames published so far",
+                self.passes,
+                self.published()
+            );
+            return self.start_pass();
+        }
+        // Unreachable while it waits for subscribers, before the pass starts
+        if let Ok(published) = observe::replay_published(client, &self.control_url).await {
+            self.current = published;
+        }
+        Ok(())
+    }
+
+    /// Stops publishing and accounts for the pass cut short.
+    pub async fn finish(&mut self, client: &reqwest::Client) -> Result<()> {
+        self.poll(client).await?;
+        self.component.stop().await;
+        self.end_pass()
+    }
+}
+
+/// data-db or the exporter, with its receive counters followed across restarts.
+pub struct Consumer {
+    pub component: Component,
+    metrics_url: String,
+    api_key: Option<String>,
+    /// Prefix of its receive-queue metric names
+    prefix: &'static str,
+    pub received: Tally,
+    pub dropped: Tally,
+    pub rejected: Tally,
+    pub rows_written: Tally,
+    pub failed_rows: Tally,
+    /// Frames published when it went down, while it hasn't received anything since
+    outage_start: Option<u64>,
+    pub outages: usize,
+    /// Frames published during its outages, which it can't have received
+    pub missed: u64,
+}
+
+impl Consumer {
+    pub fn new(
+        component: Component,
+        metrics_url: String,
+        api_key: Option<String>,
+        prefix: &'static str,
+    ) -> Self {
+        Self {
+            component,
+            metrics_url,
+            api_key,
+            prefix,
+            received: Tally::default(),
+            dropped: Tally::default(),
+            rejected: Tally::default(),
+            rows_written: Tally::default(),
+            failed_rows: Tally::default(),
+            outage_start: None,
+            outages: 0,
+            missed: 0,
+        }
+    }
+
+    /// Reads its counters. Returns whether it answered.
+    pub async fn scrape(&mut self, client: &reqwest::Client) -> bool {
+        let metrics =
+            match observe::scrape(client, &self.metrics_url, self.api_key.as_deref()).await {
+                Ok(metrics) => metrics,
+                Err(err) => {
+                    log::debug!("Could not scrape {}: {err}", self.component.name);
+                    return false;
+                }
+            };
+        let prefix = self.prefix;
+        let counter = |name: &str| observe::counter(&metrics, &format!("{prefix}{name}"));
+        self.received
+            .observe(counter("zmq_received_messages_total"));
+        self.dropped.observe(counter("zmq_dropped_messages_total"));
+        self.rejected
+            .observe(counter("zmq_rejected_messages_total"));
+        self.rows_written.observe(counter("rows_written_total"));
+        self.failed_rows
+            .observe(counter("insert_failed_rows_total"));
+        true
+    }
+
+    /// It is about to be stopped, or has exited; `published` is the replay's count as of its
+    /// last scrape.
+    pub fn went_down(&mut self, published: u64) {
+        for tally in [
+            &mut self.received,
+            &mut self.dropped,
+            &mut self.rejected,
+            &mut self.rows_written,
+            &mut self.failed_rows,
+        ] {
+            tally.restarted();
+        }
+        // A second outage before it received anything extends the first
+        if self.outage_start.is_none() {
+            self.outage_start = Some(published);
+            self.outages += 1;
+        }
+    }
+
+    /// Ends the outage once it is receiving again.
+    pub fn check_outage(&mut self, published: u64) {
+        if self.received.current() > 0.0 {
+            self.end_outage(published);
+        }
+    }
+
+    pub fn end_outage(&mut self, published: u64) {
+        if let Some(start) = self.outage_start.take() {
+            self.missed += published.saturating_sub(start);
+        }
+    }
+}
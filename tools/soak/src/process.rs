@@ -0,0 +1,154 @@
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+
+/// How long a component gets to exit after SIGTERM before it is killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exit code of a Rust program whose main thread panicked.
+const PANIC_EXIT_CODE: i32 = 101;
+
+/// One service under test, run as a child process and restarted as the soak needs.
+pub struct Component {
+    pub name: &'static str,
+    program: PathBuf,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    child: Option<Child>,
+    /// "panicked at" lines seen on its stderr, over every run
+    panics: Arc<AtomicUsize>,
+    pub starts: usize,
+    /// Exits nobody asked for, and stops that didn't exit cleanly
+    pub failed_exits: Vec<String>,
+}
+
+impl Component {
+    pub fn new(name: &'static str, program: PathBuf, args: Vec<String>) -> Self {
+        Self {
+            name,
+            program,
+            args,
+            env: Vec::new(),
+            child: None,
+            panics: Arc::new(AtomicUsize::new(0)),
+            starts: 0,
+            failed_exits: Vec::new(),
+        }
+    }
+
+    /// Sets an environment variable for the next start.
+    pub fn set_env(&mut self, key: &str, value: impl ToString) {
+        self.env.retain(|(existing, _)| existing != key);
+        self.env.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn panics(&self) -> usize {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .envs(self.env.iter().cloned())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| {
+                format!("Could not start {} ({})", self.name, self.program.display())
+            })?;
+
+        // Passed through with the component's name in front, watching for panics on the way
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let (name, panics) = (self.name, self.panics.clone());
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.contains("panicked at") {
+                    panics.fetch_add(1, Ordering::Relaxed);
+                }
+                eprintln!("[{name}] {line}");
+            }
+        });
+
+        self.child = Some(child);
+        self.starts += 1;
+        Ok(())
+    }
+
+    /// Whether it has exited on its own since the last check. The exit is recorded as a failure.
+    pub fn exited(&mut self) -> bool {
+        let Some(child) = &mut self.child else {
+            return false;
+        };
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                self.child = None;
+                self.failed_exits
+                    .push(format!("exited unexpectedly: {}", describe(status)));
+                true
+            }
+            Ok(None) => false,
+            Err(err) => {
+                log::warn!("Could not check on {}: {err}", self.name);
+                false
+            }
+        }
+    }
+
+    /// Stops it with SIGTERM, as an orchestrator would; it has to exit cleanly.
+    pub async fn stop(&mut self) {
+        let Some(mut child) = self.child.take() else {
+            return;
+        };
+        if let Some(pid) = child.id() {
+            // SAFETY: kill(2) has no memory safety requirements; pid is our own child
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        }
+        match tokio::time::timeout(STOP_TIMEOUT, child.wait()).await {
+            Ok(Ok(status)) if status.success() => {}
+            Ok(Ok(status)) => self
+                .failed_exits
+                .push(format!("stopped uncleanly: {}", describe(status))),
+            Ok(Err(err)) => log::warn!("Could not wait for {}: {err}", self.name),
+            Err(_) => {
+                self.failed_exits
+                    .push(format!("still running {STOP_TIMEOUT:?} after SIGTERM"));
+                let _ = child.kill().await;
+            }
+        }
+    }
+
+    /// Kills it with SIGKILL, as a crash or an OOM kill would.
+    pub async fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            if let Err(err) = child.kill().await {
+                log::warn!("Could not kill {}: {err}", self.name);
+            }
+        }
+    }
+}
+
+fn describe(status: ExitStatus) -> String {
+    match status.code() {
+        Some(PANIC_EXIT_CODE) => format!("{status} (panic)"),
+        _ => status.to_string(),
+    }
+}
+
+/// Runs a command that stops or starts the database, through `sh -c`.
+pub async fn run_shell(command: &str) -> Result<()> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .await
+        .with_context(|| format!("Could not run '{command}'"))?;
+    anyhow::ensure!(status.success(), "'{command}' failed: {status}");
+    Ok(())
+}
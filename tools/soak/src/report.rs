@@ -0,0 +1,190 @@
+use serde::Serialize;
+
+use crate::chaos::Fault;
+use crate::pipeline::{Consumer, Replay};
+use crate::process::Component;
+
+#[derive(Serialize)]
+pub struct FaultRecord {
+    /// Seconds into the soak
+    pub at_s: f64,
+    #[serde(flatten)]
+    pub fault: Fault,
+    /// Why it couldn't be injected, e.g. the database command failed
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ComponentReport {
+    pub name: String,
+    pub starts: usize,
+    pub panics: usize,
+    pub failed_exits: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ConsumerReport {
+    pub name: String,
+    pub received: u64,
+    /// Counted by the consumer itself: dropped at its receive high-water mark
+    pub dropped: u64,
+    /// Counted by the consumer itself: not a decodable frame
+    pub rejected: u64,
+    pub outages: usize,
+    /// Published while it was down
+    pub missed: u64,
+    /// Published, but neither received nor missed during an outage
+    pub unexplained: i64,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub seed: u64,
+    pub duration_s: f64,
+    pub passes: u64,
+    pub frames_total: u64,
+    pub frames_published: u64,
+    /// Skipped by data-replay's DROP_PROBABILITY
+    pub frames_dropped: u64,
+    pub faults: Vec<FaultRecord>,
+    pub components: Vec<ComponentReport>,
+    pub consumers: Vec<ConsumerReport>,
+    pub rows_written: u64,
+    pub failed_rows: u64,
+    /// Rows found in the database at the end, with --connection-string
+    pub stored_rows: Option<u64>,
+    /// Why the soak failed; empty when it passed
+    pub failures: Vec<String>,
+}
+
+impl ComponentReport {
+    pub fn new(component: &Component) -> Self {
+        Self {
+            name: component.name.to_string(),
+            starts: component.starts,
+            panics: component.panics(),
+            failed_exits: component.failed_exits.clone(),
+        }
+    }
+}
+
+impl ConsumerReport {
+    pub fn new(consumer: &Consumer, published: u64) -> Self {
+        let received = consumer.received.total();
+        Self {
+            name: consumer.component.name.to_string(),
+            received,
+            dropped: consumer.dropped.total(),
+            rejected: consumer.rejected.total(),
+            outages: consumer.outages,
+            missed: consumer.missed,
+            unexplained: published as i64 - received as i64 - consumer.missed as i64,
+        }
+    }
+}
+
+impl Report {
+    /// Fills in `failures`: panics and unclean exits anywhere, frames lost that no injected
+    /// fault accounts for, and rows data-db gave up on or reported written but didn't store.
+    pub fn check(&mut self, replay: &Replay, tolerance: f64) {
+        for component in &self.components {
+            if component.panics > 0 {
+                self.failures.push(format!(
+                    "{} panicked {} times",
+                    component.name, component.panics
+                ));
+            }
+            for exit in &component.failed_exits {
+                self.failures.push(format!("{} {exit}", component.name));
+            }
+        }
+        self.failures.extend(replay.unaccounted.iter().cloned());
+
+        let allowed = (self.frames_published as f64 * tolerance).ceil() as i64;
+        for consumer in &self.consumers {
+            if consumer.received > self.frames_published {
+                self.failures.push(format!(
+                    "{} received {} frames but only {} were published",
+                    consumer.name, consumer.received, self.frames_published
+                ));
+            }
+            if consumer.unexplained > allowed {
+                self.failures.push(format!(
+                    "{} lost {} frames that no fault accounts for (allowed {allowed})",
+                    consumer.name, consumer.unexplained
+                ));
+            }
+        }
+
+        if self.failed_rows > 0 {
+            self.failures
+                .push(format!("data-db gave up on {} rows", self.failed_rows));
+        }
+        if let Some(stored) = self.stored_rows {
+            if stored < self.rows_written {
+                self.failures.push(format!(
+                    "data-db reported {} rows written but the database has {stored}",
+                    self.rows_written
+                ));
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Soaked for {:.0} s (seed {}): {} passes, {} of {} frames published, {} dropped by replay",
+            self.duration_s,
+            self.seed,
+            self.passes,
+            self.frames_published,
+            self.frames_total,
+            self.frames_dropped
+        );
+        println!("{} faults injected", self.faults.len());
+        println!(
+            "{:<14} {:>7} {:>7} {:>13}",
+            "component", "starts", "panics", "failed exits"
+        );
+        for component in &self.components {
+            println!(
+                "{:<14} {:>7} {:>7} {:>13}",
+                component.name,
+                component.starts,
+                component.panics,
+                component.failed_exits.len()
+            );
+        }
+        println!(
+            "{:<14} {:>10} {:>8} {:>8} {:>8} {:>10} {:>12}",
+            "consumer", "received", "dropped", "rejected", "outages", "missed", "unexplained"
+        );
+        for consumer in &self.consumers {
+            println!(
+                "{:<14} {:>10} {:>8} {:>8} {:>8} {:>10} {:>12}",
+                consumer.name,
+                consumer.received,
+                consumer.dropped,
+                consumer.rejected,
+                consumer.outages,
+                consumer.missed,
+                consumer.unexplained
+            );
+        }
+        let stored = self
+            .stored_rows
+            .map_or("-".to_string(), |rows| rows.to_string());
+        println!(
+            "data-db rows: {} written, {} failed, {stored} stored",
+            self.rows_written, self.failed_rows
+        );
+
+        if self.failures.is_empty() {
+            println!("PASSED");
+        } else {
+            println!("FAILED");
+            for failure in &self.failures {
+                println!("  {failure}");
+            }
+        }
+    }
+}